@@ -10,6 +10,19 @@ CREATE TABLE events
     PRIMARY KEY (aggregate_type, aggregate_id, sequence)
 );
 
+CREATE TABLE events_archive
+(
+    aggregate_type text                         NOT NULL,
+    aggregate_id   text                         NOT NULL,
+    sequence       bigint CHECK (sequence >= 0) NOT NULL,
+    event_type     text                         NOT NULL,
+    event_version  text                         NOT NULL,
+    payload        json                         NOT NULL,
+    metadata       json                         NOT NULL,
+    archived_at    timestamptz                  NOT NULL,
+    PRIMARY KEY (aggregate_type, aggregate_id, sequence)
+);
+
 CREATE TABLE snapshots
 (
     aggregate_type   text                                 NOT NULL,
@@ -38,6 +51,14 @@ CREATE TABLE transfer_query
     PRIMARY KEY (view_id)
 );
 
+CREATE TABLE batch_transfer_query
+(
+    view_id text                        NOT NULL,
+    version           bigint CHECK (version >= 0) NOT NULL,
+    payload           json                        NOT NULL,
+    PRIMARY KEY (view_id)
+);
+
 
 CREATE TABLE order_query
 (
@@ -47,3 +68,446 @@ CREATE TABLE order_query
     PRIMARY KEY (view_id)
 );
 
+
+CREATE TABLE rfq_query
+(
+    view_id text                        NOT NULL,
+    version           bigint CHECK (version >= 0) NOT NULL,
+    payload           json                        NOT NULL,
+    PRIMARY KEY (view_id)
+);
+
+CREATE TABLE auction_query
+(
+    view_id text                        NOT NULL,
+    version           bigint CHECK (version >= 0) NOT NULL,
+    payload           json                        NOT NULL,
+    PRIMARY KEY (view_id)
+);
+
+CREATE TABLE standing_order_query
+(
+    view_id text                        NOT NULL,
+    version           bigint CHECK (version >= 0) NOT NULL,
+    payload           json                        NOT NULL,
+    PRIMARY KEY (view_id)
+);
+
+CREATE TABLE command_inbox
+(
+    batch_id     text    NOT NULL,
+    position     integer NOT NULL,
+    command_id   text    NOT NULL,
+    command      json    NOT NULL,
+    metadata     json    NOT NULL,
+    not_before   bigint  NOT NULL,
+    depends_on   text[]  NOT NULL,
+    status       text    NOT NULL,
+    error        text,
+    submitted_at bigint  NOT NULL,
+    claimed_at   bigint,
+    executed_at  bigint,
+    PRIMARY KEY (batch_id, command_id)
+);
+
+CREATE INDEX command_inbox_unfinished ON command_inbox (status, not_before) WHERE status IN ('Pending', 'Running');
+
+CREATE TABLE payout_report
+(
+    batch_id   text        NOT NULL,
+    report     text        NOT NULL,
+    created_at timestamptz NOT NULL,
+    PRIMARY KEY (batch_id)
+);
+
+CREATE TABLE asset_query
+(
+    view_id text                        NOT NULL,
+    version           bigint CHECK (version >= 0) NOT NULL,
+    payload           json                        NOT NULL,
+    PRIMARY KEY (view_id)
+);
+
+CREATE TABLE approval_query
+(
+    view_id text                        NOT NULL,
+    version           bigint CHECK (version >= 0) NOT NULL,
+    payload           json                        NOT NULL,
+    PRIMARY KEY (view_id)
+);
+
+CREATE TABLE txid_registry
+(
+    txid       text        NOT NULL,
+    owner      text        NOT NULL,
+    created_at timestamptz NOT NULL,
+    PRIMARY KEY (txid)
+);
+
+CREATE TABLE account_txids
+(
+    account_id text   NOT NULL,
+    txid       text   NOT NULL,
+    timestamp  bigint NOT NULL,
+    PRIMARY KEY (account_id, txid)
+);
+
+CREATE INDEX account_txids_by_timestamp ON account_txids (account_id, timestamp);
+
+CREATE TABLE idempotency_key
+(
+    idempotency_key text        NOT NULL,
+    fingerprint     text        NOT NULL,
+    status          smallint,
+    headers         jsonb,
+    body            bytea,
+    created_at      timestamptz NOT NULL,
+    PRIMARY KEY (idempotency_key)
+);
+
+CREATE TABLE event_outbox
+(
+    id             bigserial   NOT NULL,
+    aggregate_type text        NOT NULL,
+    aggregate_id   text        NOT NULL,
+    sequence       bigint      NOT NULL,
+    event_type     text        NOT NULL,
+    event_version  text        NOT NULL,
+    payload        jsonb       NOT NULL,
+    metadata       jsonb       NOT NULL,
+    txid           xid8        NOT NULL DEFAULT pg_current_xact_id(),
+    created_at     timestamptz NOT NULL,
+    PRIMARY KEY (id),
+    UNIQUE (aggregate_type, aggregate_id, sequence)
+);
+
+CREATE TABLE outbox_offset
+(
+    publisher  text        NOT NULL,
+    last_id    bigint      NOT NULL,
+    updated_at timestamptz NOT NULL,
+    PRIMARY KEY (publisher)
+);
+
+CREATE TABLE preferences_query
+(
+    view_id text                        NOT NULL,
+    version           bigint CHECK (version >= 0) NOT NULL,
+    payload           json                        NOT NULL,
+    PRIMARY KEY (view_id)
+);
+
+CREATE TABLE alert_state
+(
+    account_id text        NOT NULL,
+    alert_id   text        NOT NULL,
+    rule       jsonb       NOT NULL,
+    triggered  boolean     NOT NULL,
+    updated_at timestamptz NOT NULL,
+    PRIMARY KEY (account_id, alert_id)
+);
+
+-- Fees charged and not credited to their collection account yet, written in the
+-- transaction charging them, see `crate::account::fees`
+CREATE TABLE pending_fee
+(
+    account_id text   NOT NULL,
+    txid       text   NOT NULL,
+    timestamp  bigint NOT NULL,
+    to_account text   NOT NULL,
+    asset      text   NOT NULL,
+    amount     bigint NOT NULL,
+    PRIMARY KEY (account_id, txid)
+);
+
+CREATE INDEX pending_fee_timestamp ON pending_fee (timestamp);
+
+CREATE TABLE ledger_entries
+(
+    account_id    text   NOT NULL,
+    sequence      bigint NOT NULL,
+    txid          text   NOT NULL,
+    timestamp     bigint NOT NULL,
+    detail        jsonb  NOT NULL,
+    balance_after jsonb,
+    origin        jsonb,
+    category      text,
+    metadata      jsonb,
+    -- The business day the transaction is booked on, NULL while business days are not kept
+    value_date    date,
+    -- The display name of the counterparty, see `CounterpartyNames`
+    counterparty_name text,
+    -- The category and metadata values, for `search_ledger`
+    memo          tsvector GENERATED ALWAYS AS (
+        to_tsvector('simple', coalesce(category, '')) || jsonb_to_tsvector('simple', coalesce(metadata, '{}'), '["string"]')
+    ) STORED,
+    PRIMARY KEY (account_id, sequence)
+);
+
+CREATE INDEX ledger_entries_timestamp ON ledger_entries (account_id, timestamp);
+CREATE INDEX ledger_entries_txid ON ledger_entries (account_id, txid);
+CREATE INDEX ledger_entries_category ON ledger_entries (account_id, category, sequence) WHERE category IS NOT NULL;
+CREATE INDEX ledger_entries_value_date ON ledger_entries (value_date) WHERE value_date IS NOT NULL;
+CREATE INDEX ledger_entries_memo ON ledger_entries USING gin (memo);
+
+CREATE TABLE recorded_request
+(
+    id             bigserial   NOT NULL,
+    correlation_id text        NOT NULL,
+    method         text        NOT NULL,
+    path           text        NOT NULL,
+    headers        jsonb       NOT NULL,
+    body           bytea       NOT NULL,
+    status         smallint    NOT NULL,
+    recorded_at    timestamptz NOT NULL,
+    PRIMARY KEY (id)
+);
+
+CREATE TABLE webhook_subscription
+(
+    subscription_id text        NOT NULL,
+    url             text        NOT NULL,
+    event_types     jsonb       NOT NULL,
+    created_at      timestamptz NOT NULL,
+    PRIMARY KEY (subscription_id)
+);
+
+CREATE TABLE open_orders
+(
+    order_id    text    NOT NULL,
+    seller      text    NOT NULL,
+    sell_asset  text    NOT NULL,
+    buy_asset   text    NOT NULL,
+    sell_amount bigint  NOT NULL,
+    buy_amount  bigint  NOT NULL,
+    -- buy_amount / sell_amount, the lower the better for a buyer on either side
+    price       numeric NOT NULL,
+    placed      boolean NOT NULL,
+    timestamp   bigint  NOT NULL,
+    PRIMARY KEY (order_id)
+);
+
+CREATE INDEX open_orders_pair ON open_orders (sell_asset, buy_asset, price);
+
+-- Accounts asked to be closed, until `crate::account::closure` finalizes or aborts the closure.
+CREATE TABLE account_closure
+(
+    account_id  text   NOT NULL,
+    finalize_at bigint NOT NULL,
+    PRIMARY KEY (account_id)
+);
+
+CREATE TABLE trading_halt
+(
+    base      text   NOT NULL,
+    quote     text   NOT NULL,
+    reason    text   NOT NULL,
+    halted_at bigint NOT NULL,
+    -- NULL while the halt lasts until lifted
+    resume_at bigint
+);
+
+-- One halt per pair, whichever direction it was halted in.
+CREATE UNIQUE INDEX trading_halt_market ON trading_halt (LEAST(base, quote), GREATEST(base, quote));
+
+-- The rates pairs settled at, one row per trade. Assets are stored in alphabetical
+-- order, so both sides of a trade land on the same row.
+CREATE TABLE rates
+(
+    asset_a   text   NOT NULL,
+    asset_b   text   NOT NULL,
+    txid      text   NOT NULL,
+    amount_a  bigint NOT NULL,
+    amount_b  bigint NOT NULL,
+    timestamp bigint NOT NULL,
+    PRIMARY KEY (asset_a, asset_b, txid)
+);
+
+CREATE INDEX rates_history ON rates (asset_a, asset_b, timestamp);
+
+CREATE TABLE account_stats_query
+(
+    view_id text                        NOT NULL,
+    version           bigint CHECK (version >= 0) NOT NULL,
+    payload           json                        NOT NULL,
+    PRIMARY KEY (view_id)
+);
+
+-- Movements between accounts in double entry, one row per movement with the debit leg
+-- posted by the sender and the credit leg posted by the receiver, see `JournalQuery`.
+CREATE TABLE journal
+(
+    txid            text   NOT NULL,
+    asset           text   NOT NULL,
+    debit_account   text   NOT NULL,
+    credit_account  text   NOT NULL,
+    -- NULL until the account posts its leg
+    debit_sequence  bigint,
+    debit_amount    bigint,
+    credit_sequence bigint,
+    credit_amount   bigint,
+    timestamp       bigint NOT NULL,
+    PRIMARY KEY (txid, asset, debit_account, credit_account)
+);
+
+CREATE INDEX journal_asset ON journal (asset);
+
+-- Credit limits of accounts allowed to overdraw an asset, see `OverdraftLimits`.
+CREATE TABLE overdraft_limit
+(
+    account_id   text        NOT NULL,
+    asset        text        NOT NULL,
+    credit_limit bigint      NOT NULL,
+    updated_at   timestamptz NOT NULL,
+    PRIMARY KEY (account_id, asset)
+);
+
+-- Progress of the bulk replays and the operator's pause of them, see `ReplayThrottle`.
+CREATE TABLE replay_jobs
+(
+    name       text        NOT NULL,
+    priority   text        NOT NULL,
+    state      text        NOT NULL,
+    rows       bigint      NOT NULL,
+    paused     boolean     NOT NULL,
+    started_at timestamptz NOT NULL,
+    updated_at timestamptz NOT NULL,
+    PRIMARY KEY (name)
+);
+
+-- Asset migrations and how far they got through the accounts, see `AssetMigrations`.
+CREATE TABLE asset_migration
+(
+    from_asset  text   NOT NULL,
+    to_asset    text   NOT NULL,
+    numerator   bigint NOT NULL,
+    denominator bigint NOT NULL,
+    status      text   NOT NULL,
+    initiator   text   NOT NULL,
+    started_at  bigint NOT NULL,
+    finished_at bigint,
+    -- The last account converted, in id order
+    cursor      text   NOT NULL,
+    scanned     bigint NOT NULL,
+    converted   bigint NOT NULL,
+    skipped     bigint NOT NULL,
+    failed      bigint NOT NULL,
+    failures    jsonb  NOT NULL,
+    error       text,
+    PRIMARY KEY (from_asset)
+);
+
+-- The display names accounts show their counterparties, see `CounterpartyNames`.
+CREATE TABLE counterparty_name
+(
+    account_id   text   NOT NULL,
+    display_name text   NOT NULL,
+    updated_at   bigint NOT NULL,
+    PRIMARY KEY (account_id)
+);
+
+-- Undos of the saga steps on accounts while the steps run, see `CompensationJournal`.
+CREATE TABLE compensations
+(
+    id         bigserial NOT NULL,
+    saga       text      NOT NULL,
+    saga_id    text      NOT NULL,
+    account_id text      NOT NULL,
+    command    jsonb     NOT NULL,
+    attempts   integer   NOT NULL,
+    -- Of the last recovery attempt
+    error      text,
+    created_at bigint    NOT NULL,
+    -- Of the saga when the step ran
+    sequence   bigint    NOT NULL,
+    -- Whether the saga kept the step, to stand once its next event commits
+    kept       boolean   NOT NULL,
+    PRIMARY KEY (id)
+);
+CREATE INDEX compensations_created_at ON compensations (created_at);
+CREATE INDEX compensations_saga ON compensations (saga, saga_id);
+
+-- Saga steps and compensations that failed on an account, see `DeadLetters`.
+CREATE TABLE dead_letter
+(
+    id          bigserial NOT NULL,
+    saga        text      NOT NULL,
+    step        text      NOT NULL,
+    saga_id     text      NOT NULL,
+    account_id  text      NOT NULL,
+    -- The account command, executed again on a retry
+    command     jsonb     NOT NULL,
+    error       text      NOT NULL,
+    status      text      NOT NULL,
+    attempts    integer   NOT NULL,
+    created_at  bigint    NOT NULL,
+    updated_at  bigint    NOT NULL,
+    resolved_by text,
+    PRIMARY KEY (id)
+);
+CREATE INDEX dead_letter_pending ON dead_letter (id) WHERE status = 'Pending';
+
+-- Business days, see `BusinessDays`. Postings are value dated to the open one; a closed
+-- day takes none and holds its checkpoint once taken.
+CREATE TABLE business_day
+(
+    date       date    NOT NULL,
+    closed     boolean NOT NULL,
+    opened_at  bigint  NOT NULL,
+    closed_at  bigint,
+    checkpoint jsonb,
+    PRIMARY KEY (date)
+);
+
+CREATE UNIQUE INDEX business_day_open ON business_day (closed) WHERE NOT closed;
+
+-- Duplicate transactions rejected, reversals and compensations of each account per hour,
+-- see `Signals`. `bucket` is the unix time the hour starts at.
+CREATE TABLE account_signals
+(
+    account_id text   NOT NULL,
+    bucket     bigint NOT NULL,
+    kind       text   NOT NULL,
+    count      bigint NOT NULL,
+    PRIMARY KEY (account_id, bucket, kind)
+);
+CREATE INDEX account_signals_bucket ON account_signals (bucket);
+
+-- Who executed which command, see `AuditLogQuery`: the request metadata of each command
+-- with the sequences of the events it resulted in.
+CREATE TABLE audit_log
+(
+    id             bigserial NOT NULL,
+    aggregate_type text      NOT NULL,
+    aggregate_id   text      NOT NULL,
+    command        text,
+    first_sequence bigint    NOT NULL,
+    last_sequence  bigint    NOT NULL,
+    events         text[]    NOT NULL,
+    metadata       jsonb     NOT NULL,
+    recorded_at    bigint    NOT NULL,
+    PRIMARY KEY (id),
+    UNIQUE (aggregate_type, aggregate_id, first_sequence)
+);
+CREATE INDEX audit_log_aggregate ON audit_log (aggregate_id, first_sequence);
+
+-- Events committed per account and UTC day and the bytes of their payloads, see `Quotas`.
+-- `day` is the unix time the day starts at.
+CREATE TABLE account_usage
+(
+    account_id text   NOT NULL,
+    day        bigint NOT NULL,
+    events     bigint NOT NULL,
+    bytes      bigint NOT NULL,
+    PRIMARY KEY (account_id, day)
+);
+CREATE INDEX account_usage_day ON account_usage (day);
+
+-- How far the sandbox clock was fast-forwarded, see `crate::util::clock`. A single row,
+-- kept across sandbox resets.
+CREATE TABLE sandbox_clock
+(
+    id          boolean NOT NULL DEFAULT true CHECK (id),
+    offset_secs bigint  NOT NULL,
+    PRIMARY KEY (id)
+);
@@ -0,0 +1,63 @@
+-- The embedded event store, see `crate::sqlite`. Applied on connect, so every statement
+-- must be safe to run again.
+CREATE TABLE IF NOT EXISTS events
+(
+    aggregate_type text                          NOT NULL,
+    aggregate_id   text                          NOT NULL,
+    sequence       integer CHECK (sequence >= 0) NOT NULL,
+    event_type     text                          NOT NULL,
+    event_version  text                          NOT NULL,
+    payload        text                          NOT NULL,
+    metadata       text                          NOT NULL,
+    PRIMARY KEY (aggregate_type, aggregate_id, sequence)
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS snapshots
+(
+    aggregate_type   text                                  NOT NULL,
+    aggregate_id     text                                  NOT NULL,
+    last_sequence    integer CHECK (last_sequence >= 0)    NOT NULL,
+    current_snapshot integer CHECK (current_snapshot >= 0) NOT NULL,
+    payload          text                                  NOT NULL,
+    PRIMARY KEY (aggregate_type, aggregate_id, last_sequence)
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS asset_query
+(
+    view_id text                         NOT NULL,
+    version integer CHECK (version >= 0) NOT NULL,
+    payload text                         NOT NULL,
+    PRIMARY KEY (view_id)
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS account_query
+(
+    view_id text                         NOT NULL,
+    version integer CHECK (version >= 0) NOT NULL,
+    payload text                         NOT NULL,
+    PRIMARY KEY (view_id)
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS account_stats_query
+(
+    view_id text                         NOT NULL,
+    version integer CHECK (version >= 0) NOT NULL,
+    payload text                         NOT NULL,
+    PRIMARY KEY (view_id)
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS transfer_query
+(
+    view_id text                         NOT NULL,
+    version integer CHECK (version >= 0) NOT NULL,
+    payload text                         NOT NULL,
+    PRIMARY KEY (view_id)
+) STRICT;
+
+CREATE TABLE IF NOT EXISTS order_query
+(
+    view_id text                         NOT NULL,
+    version integer CHECK (version >= 0) NOT NULL,
+    payload text                         NOT NULL,
+    PRIMARY KEY (view_id)
+) STRICT;
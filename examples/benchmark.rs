@@ -54,16 +54,16 @@ async fn main() {
 
 
 async fn create_account(client: &Client, account_id: &str) -> Result<(), reqwest::Error> {
-    let url = format!("http://localhost:3030/account/{}", account_id);
+    let url = format!("http://localhost:3030/admin/account/{}", account_id);
     let body = json!({
-        "Lifecycle": {
-            "Open": {
-                "account_id": account_id
-            }
+        "Open": {
+            "account_id": account_id
         }
     });
+    let api_key = std::env::var("ADMIN_API_KEY").unwrap_or_default();
     let response = client
         .post(&url)
+        .header("X-Api-Key", api_key)
         .json(&body)
         .send()
         .await?;
@@ -84,7 +84,7 @@ async fn deposit_money(client: &Client, account_id: &str, asset: &str, amount: u
                     }
                 },
                 "timestamp": now,
-                "txid": txid
+                "txid": txid.hex()
             }
     });
 
@@ -161,7 +161,7 @@ async fn place_order(client: &Client,
     let body = json!({
         "Open": {
             "config": {
-                "order_id": txid,
+                "order_id": txid.hex(),
                 "seller": seller,
                 "sell_asset": sell_asset,
                 "sell_amount": sell_amount,
@@ -0,0 +1,97 @@
+use std::collections::BTreeSet;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use crate::account::queries::AccountView;
+use crate::config::AccrualConfig;
+
+const DAY_SECS: u64 = 86_400;
+const YEAR_DAYS: u128 = 365;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AccrualParams {
+    // Unix seconds, only whole days until then accrue.
+    pub until: u64,
+}
+
+// What the funds of an account in one asset accrue over `days`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Accrual {
+    pub asset: String,
+    // Available and locked, the funds the account owns.
+    pub balance: u64,
+    pub days: u64,
+    pub interest: u64,
+    pub fees: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct AccrualPreview {
+    pub account_id: String,
+    pub from: u64,
+    pub until: u64,
+    // One per asset with a schedule that the account holds.
+    pub accruals: Vec<Accrual>,
+}
+
+// Accrues a balance over whole days under the schedule of its asset: simple interest,
+// rounded down, and the daily fee for every day the asset is held. Posting accruals is to
+// go through the same calculation, so that a preview matches what gets posted.
+pub fn accrue(config: &AccrualConfig, asset: &str, balance: u64, days: u64) -> Accrual {
+    let bps = config.interest_bps.get(asset).copied().unwrap_or(0);
+    let interest = balance as u128 * bps as u128 * days as u128 / (10_000 * YEAR_DAYS);
+    let daily_fee = if balance > 0 { config.daily_fee.get(asset).copied().unwrap_or(0) } else { 0 };
+    Accrual {
+        asset: asset.to_string(),
+        balance,
+        days,
+        interest: interest.min(u64::MAX as u128) as u64,
+        fees: daily_fee.saturating_mul(days),
+    }
+}
+
+// Previews what the account accrues from `now` until `until` at its current balances,
+// posting nothing.
+pub fn preview_accruals(config: &AccrualConfig, account_id: &str, view: &AccountView, now: u64, until: u64) -> AccrualPreview {
+    let days = until.saturating_sub(now) / DAY_SECS;
+    let assets: BTreeSet<&String> = config.interest_bps.keys().chain(config.daily_fee.keys()).collect();
+    let accruals = assets
+        .into_iter()
+        .map(|asset| accrue(config, asset, view.owned_balance(asset), days))
+        .filter(|accrual| accrual.balance > 0)
+        .collect();
+    AccrualPreview { account_id: account_id.to_string(), from: now, until, accruals }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use crate::account::accruals::{accrue, Accrual};
+    use crate::config::AccrualConfig;
+
+    #[test]
+    fn test_accrue() {
+        let config = AccrualConfig {
+            interest_bps: HashMap::from([("USDT".to_string(), 365)]),
+            daily_fee: HashMap::from([("USDT".to_string(), 2), ("BTC".to_string(), 1)]),
+        };
+        // 3.65% a year is 1 bps a day.
+        assert_eq!(accrue(&config, "USDT", 1_000_000, 30), Accrual {
+            asset: "USDT".to_string(),
+            balance: 1_000_000,
+            days: 30,
+            interest: 3_000,
+            fees: 60,
+        });
+        // Interest rounds down, and fees are only owed by holders.
+        assert_eq!(accrue(&config, "USDT", 99, 1).interest, 0);
+        assert_eq!(accrue(&config, "BTC", 0, 30).fees, 0);
+        assert_eq!(accrue(&config, "ETH", 1_000, 30), Accrual {
+            asset: "ETH".to_string(),
+            balance: 1_000,
+            days: 30,
+            interest: 0,
+            fees: 0,
+        });
+    }
+}
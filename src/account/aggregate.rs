@@ -6,30 +6,57 @@ use async_trait::async_trait;
 use cqrs_es::Aggregate;
 use serde::{Deserialize, Serialize};
 
-use super::events::{AccountError, AccountEvent};
-use crate::services::BankAccountServices;
+use super::events::{AccountError, AccountEvent, EarmarkPurpose};
+use crate::asset::types::ConversionRatio;
+use crate::config::FeeOperation;
+use crate::services::{AssetValidationError, BankAccountServices};
+use crate::statemachine::{StateMachine, Transition};
 use crate::util::types::ByteArray32;
 use super::commands::{TransactionCommand, LifecycleCommand, AccountCommand};
 use super::events::{LifecycleEvent, TransactionEvent};
 
-const DEFAULT_TTL: u64 = 30 * 24 * 60 * 60;
+#[derive(Clone, Serialize, Deserialize)]
+struct EarmarkedFunds {
+    asset: String,
+    amount: u64,
+    purpose: EarmarkPurpose,
+}
 
-#[derive(Serialize, Deserialize, Default)]
+#[derive(Clone, Serialize, Deserialize, Default)]
 struct ProcessedTransactions {
     ttl: u64,
     txids: BTreeMap<String, u64>,
     timeseries: VecDeque<(u64, ByteArray32)>,
+    // The most txids remembered at once, the oldest ones past it are evicted.
+    #[serde(default)]
+    max_txids: Option<u64>,
 }
 
 impl ProcessedTransactions {
-    fn new(ttl: u64) -> Self {
+    fn new(ttl: u64, max_txids: Option<u64>) -> Self {
         Self {
             ttl,
             txids: BTreeMap::new(),
             timeseries: VecDeque::new(),
+            max_txids,
         }
     }
 
+    fn len(&self) -> usize {
+        self.txids.len()
+    }
+
+    fn oldest(&self, count: usize) -> Vec<ByteArray32> {
+        self.timeseries.iter().take(count).map(|(_, txid)| *txid).collect()
+    }
+
+    fn evict(&mut self, txids: &[ByteArray32]) {
+        for txid in txids {
+            self.txids.remove(&txid.hex());
+        }
+        self.timeseries.retain(|(_, txid)| !txids.contains(txid));
+    }
+
     fn get_timestamp(&self, txid: &ByteArray32) -> Option<u64> {
         self.txids.get(&txid.hex()).copied()
     }
@@ -63,13 +90,13 @@ impl ProcessedTransactions {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 struct ReservedFunds {
     asset: String,
     amount: u64,
 }
 
-#[derive(Serialize, Deserialize, Default)]
+#[derive(Clone, Serialize, Deserialize, Default)]
 pub enum Account {
     #[default]
     Uninitialized,
@@ -79,20 +106,165 @@ pub enum Account {
     Disabled {
         state: BankAccountState,
     },
+    // Withdrawal-only until the closure is finalized or aborted, see `LifecycleCommand::RequestClose`.
+    CloseRequested {
+        state: BankAccountState,
+        closure: PendingClosure,
+    },
     Closed,
 }
 
-#[derive(Serialize, Deserialize, Default)]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PendingClosure {
+    finalize_at: u64,
+    // Whether funds were deposited or credited since the request, which aborts it.
+    credited: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default)]
 pub struct BankAccountState {
     account_id: String,
     assets: BTreeMap<String, u64>,
     reserving: BTreeMap<String, ReservedFunds>,
     processed_transactions: ProcessedTransactions,
+    // Named holds, with what is left of each.
+    #[serde(default)]
+    holds: BTreeMap<String, ReservedFunds>,
+    // What the account owes on its credit line, by asset. Drawn funds are part of `assets`.
+    #[serde(default)]
+    overdrawn: BTreeMap<String, u64>,
+    // Funds set aside by name, deposits from unverified sources under `quarantine_earmark`.
+    // Not part of `assets`, nor of the locked balance.
+    #[serde(default)]
+    earmarked: BTreeMap<String, EarmarkedFunds>,
+}
+
+// Fees are recorded under their own txid, derived from the one of the charged transaction.
+pub fn fee_txid(txid: &ByteArray32) -> ByteArray32 {
+    ByteArray32::derive("fee", &txid.hex())
+}
+
+// Funds moved from a hold into a lock leave the hold under a txid of their own, the lock
+// keeps the txid of the command for the settlement.
+pub fn held_txid(txid: &ByteArray32) -> ByteArray32 {
+    ByteArray32::derive("held", &txid.hex())
+}
+
+// Quarantined deposits are earmarked under their txid, released by `ReleaseQuarantine`.
+const QUARANTINE_EARMARK: &str = "quarantine-";
+
+pub fn quarantine_earmark(txid: &ByteArray32) -> String {
+    format!("{}{}", QUARANTINE_EARMARK, txid.hex())
+}
+
+impl BankAccountServices {
+    // How long an account opened now keeps its txids in its state: the recent ones only
+    // when the others are in the dedup store.
+    fn remembered_secs(&self, account_id: &str) -> u64 {
+        let window = self.duplicate_detection.window_secs(account_id);
+        match self.dedup_store {
+            Some(_) => window.min(self.duplicate_detection.recent_secs),
+            None => window,
+        }
+    }
+
+    // When the account processed the txid: remembered by the account, or else within the
+    // window in the dedup store.
+    async fn processed_at(&self, state: &BankAccountState, txid: &ByteArray32, timestamp: u64) -> Result<Option<u64>, AccountError> {
+        if let Some(processed) = state.processed_transactions.get_timestamp(txid) {
+            return Ok(Some(processed));
+        }
+        let Some(store) = &self.dedup_store else {
+            return Ok(None);
+        };
+        let since = timestamp.saturating_sub(self.duplicate_detection.window_secs(&state.account_id));
+        store.processed_at(&state.account_id, txid, since).await.map_err(AccountError::DuplicateCheckFailed)
+    }
+
+    // Withdrawals are cleared with the ATM network and locks with check validation, both
+    // before the balance is looked at.
+    async fn clear_withdrawal(&self, state: &BankAccountState, amount: u64) -> Result<(), AccountError> {
+        self.services.atm_withdrawal(&state.account_id, amount as f64).await.map_err(|_| AccountError::AtmRuleViolation)
+    }
+
+    async fn validate_check(&self, state: &BankAccountState, txid: &ByteArray32) -> Result<(), AccountError> {
+        self.services.validate_check(&state.account_id, &txid.hex()).await.map_err(|_| AccountError::CheckInvalid)
+    }
+
+    async fn validate_asset(&self, asset: &str) -> Result<(), AccountError> {
+        self.services.validate_asset(asset).await.map_err(|e| match e {
+            AssetValidationError::NotRegistered => AccountError::AssetNotRegistered(asset.to_string()),
+            AssetValidationError::Disabled => AccountError::AssetDisabled(asset.to_string()),
+        })
+    }
+
+    // Covers what a withdrawal or debit takes beyond the balance on the credit line of
+    // the account, as long as its overdraft limit leaves room for it.
+    async fn draw_overdraft(
+        &self,
+        state: &BankAccountState,
+        closing: bool,
+        txid: ByteArray32,
+        timestamp: u64,
+        asset: &str,
+        due: u64,
+    ) -> Result<Option<AccountEvent>, AccountError> {
+        let Some(shortfall) = due.checked_sub(state.available(asset)).filter(|shortfall| *shortfall > 0) else {
+            return Ok(None);
+        };
+        // Owing on the credit line would abort the closure, closing accounts pay from
+        // their balance only.
+        if closing {
+            return Err(AccountError::AccountClosing);
+        }
+        let limit = self.overdraft.credit_limit(&state.account_id, asset).await;
+        if state.overdrawn(asset).saturating_add(shortfall) > limit {
+            return Err(AccountError::InsufficientFunds);
+        }
+        Ok(Some(AccountEvent::overdraft_used(txid, timestamp, asset.to_string(), shortfall, limit)))
+    }
 }
 
 impl BankAccountState {
     fn is_empty(&self) -> bool {
-        self.assets.is_empty() && self.reserving.is_empty()
+        // Withdrawing a balance to nothing leaves it at zero.
+        self.assets.values().all(|amount| *amount == 0)
+            && self.reserving.is_empty()
+            && self.holds.is_empty()
+            && self.overdrawn.is_empty()
+            && self.earmarked.is_empty()
+    }
+
+    // Emptied balances keep their entry, they count as not held.
+    fn holds_asset(&self, asset: &str) -> bool {
+        self.assets.get(asset).is_some_and(|amount| *amount > 0)
+            || self.overdrawn.get(asset).is_some_and(|amount| *amount > 0)
+            || self.reserving.values().chain(self.holds.values()).any(|funds| funds.asset == asset)
+            || self.earmarked.values().any(|funds| funds.asset == asset)
+    }
+
+    // Moves everything held in `from` over to `to` at `ratio`, None when an amount would
+    // no longer fit.
+    fn convert(&mut self, from: &str, to: &str, ratio: ConversionRatio) -> Option<()> {
+        for balances in [&mut self.assets, &mut self.overdrawn] {
+            if let Some(amount) = balances.remove(from) {
+                let balance = balances.entry(to.to_string()).or_insert(0);
+                *balance = balance.checked_add(ratio.convert(amount)?)?;
+            }
+        }
+        for funds in self.reserving.values_mut().chain(self.holds.values_mut()) {
+            if funds.asset == from {
+                funds.asset = to.to_string();
+                funds.amount = ratio.convert(funds.amount)?;
+            }
+        }
+        for funds in self.earmarked.values_mut() {
+            if funds.asset == from {
+                funds.asset = to.to_string();
+                funds.amount = ratio.convert(funds.amount)?;
+            }
+        }
+        Some(())
     }
 
     fn save_txid(&mut self, txid: ByteArray32, timestamp: u64) {
@@ -101,37 +273,213 @@ impl BankAccountState {
             .expect("txid already exists");
     }
 
+    // A reversed txid may be known to the dedup store only, see `BankAccountServices::processed_at`.
     fn remove_txid(&mut self, txid: &ByteArray32) {
-        self.processed_transactions
-            .remove(txid)
-            .expect("txid does not exist");
+        self.processed_transactions.remove(txid);
+    }
+
+    // Past its cap, an account forgets its oldest txids along with the transaction that
+    // overflows it.
+    fn evict_overflow(&self, mut events: Vec<AccountEvent>) -> Vec<AccountEvent> {
+        let Some(max) = self.processed_transactions.max_txids else {
+            return events;
+        };
+        if (self.processed_transactions.len().saturating_add(events.len()) as u64) <= max {
+            return events;
+        }
+        let mut preview = Account::InService { state: self.clone() };
+        for event in &events {
+            preview.apply(event.clone());
+        }
+        let Account::InService { state } = preview else {
+            unreachable!("account should be in service");
+        };
+        let overflow = (state.processed_transactions.len() as u64).saturating_sub(max) as usize;
+        if overflow > 0 {
+            events.push(AccountEvent::txids_evicted(state.processed_transactions.oldest(overflow)));
+        }
+        events
+    }
+
+    fn available(&self, asset: &str) -> u64 {
+        self.assets.get(asset).copied().unwrap_or(0)
+    }
+
+    fn overdrawn(&self, asset: &str) -> u64 {
+        self.overdrawn.get(asset).copied().unwrap_or(0)
+    }
+
+    // Funds a deposit, credit or reversed debit brings in pay back the credit line first.
+    fn repay_overdraft(&self, txid: ByteArray32, timestamp: u64, asset: &str, amount: u64) -> Option<AccountEvent> {
+        let repaid = self.overdrawn(asset).min(amount);
+        (repaid > 0).then(|| AccountEvent::overdraft_repaid(txid, timestamp, asset.to_string(), repaid))
+    }
+
+    fn locked(&self, asset: &str) -> u64 {
+        self.reserving
+            .values()
+            .chain(self.holds.values())
+            .filter(|reserved| reserved.asset == asset)
+            .map(|reserved| reserved.amount)
+            .sum()
+    }
+
+    // Checks that `amount` can be captured or released from the hold, and returns its
+    // asset and what the hold keeps afterwards.
+    fn take_from_hold(&self, hold: &str, amount: u64) -> Result<(String, u64), AccountError> {
+        let Some(held) = self.holds.get(hold) else {
+            return Err(AccountError::HoldNotFound(hold.to_string()));
+        };
+        if amount == 0 {
+            return Err(AccountError::InvalidTransaction);
+        }
+        let remaining = held.amount.checked_sub(amount).ok_or(AccountError::HoldExceeded(held.amount))?;
+        Ok((held.asset.clone(), remaining))
+    }
+
+    fn set_hold(&mut self, hold: String, asset: String, remaining: u64) {
+        if remaining == 0 {
+            self.holds.remove(&hold);
+        } else {
+            self.holds.insert(hold, ReservedFunds { asset, amount: remaining });
+        }
+    }
+
+    // Stamps a freshly raised transaction event with the balances it will leave behind,
+    // mirroring what `apply` does so views never have to recompute them.
+    fn attach_balance_after(&self, event: AccountEvent) -> AccountEvent {
+        let AccountEvent::Transaction { txid, event: transaction, .. } = &event else {
+            return event;
+        };
+        match transaction {
+            TransactionEvent::Deposited { asset, amount }
+            | TransactionEvent::Credited { asset, amount, .. }
+            | TransactionEvent::DebitReversed { asset, amount, .. }
+            | TransactionEvent::OverdraftUsed { asset, amount, .. }
+            | TransactionEvent::QuarantineReleased { asset, amount }
+            | TransactionEvent::EarmarkReleased { asset, amount, .. } => {
+                let (available, locked) = (self.available(asset) + amount, self.locked(asset));
+                let asset = asset.clone();
+                event.with_balance_after(asset, available, locked)
+            }
+            TransactionEvent::Withdrew { asset, amount }
+            | TransactionEvent::Debited { asset, amount, .. }
+            | TransactionEvent::CreditReversed { asset, amount, .. }
+            | TransactionEvent::FeeCharged { asset, amount, .. }
+            | TransactionEvent::OverdraftRepaid { asset, amount }
+            | TransactionEvent::FundsEarmarked { asset, amount, .. } => {
+                let (available, locked) = (self.available(asset).saturating_sub(*amount), self.locked(asset));
+                let asset = asset.clone();
+                event.with_balance_after(asset, available, locked)
+            }
+            TransactionEvent::FundsLocked { asset, amount } | TransactionEvent::HoldPlaced { asset, amount, .. } => {
+                let (available, locked) = (self.available(asset).saturating_sub(*amount), self.locked(asset) + amount);
+                let asset = asset.clone();
+                event.with_balance_after(asset, available, locked)
+            }
+            TransactionEvent::FundsUnlocked { asset, amount } | TransactionEvent::HoldReleased { asset, amount, .. } => {
+                let (available, locked) = (self.available(asset) + amount, self.locked(asset).saturating_sub(*amount));
+                let asset = asset.clone();
+                event.with_balance_after(asset, available, locked)
+            }
+            TransactionEvent::HoldCaptured { asset, amount, .. } => {
+                let (available, locked) = (self.available(asset), self.locked(asset).saturating_sub(*amount));
+                let asset = asset.clone();
+                event.with_balance_after(asset, available, locked)
+            }
+            TransactionEvent::DepositQuarantined { asset, .. } => {
+                let (available, locked) = (self.available(asset), self.locked(asset));
+                let asset = asset.clone();
+                event.with_balance_after(asset, available, locked)
+            }
+            // Nothing is left in the old asset.
+            TransactionEvent::AssetConverted { from_asset, to_asset, ratio, .. } => {
+                let mut converted = self.clone();
+                converted.convert(from_asset, to_asset, *ratio);
+                let (available, locked) = (converted.available(to_asset), converted.locked(to_asset));
+                let asset = to_asset.clone();
+                event.with_balance_after(asset, available, locked)
+            }
+            TransactionEvent::Settled { send_asset, receive_asset, receive_amount, .. } => {
+                let released = self.reserving.get(&txid.hex()).map(|r| r.amount).unwrap_or(0);
+                let send = (send_asset.clone(), self.available(send_asset), self.locked(send_asset).saturating_sub(released));
+                // When both legs are in the same asset the second snapshot supersedes the first.
+                let receive = if receive_asset == send_asset {
+                    (send.0.clone(), send.1 + receive_amount, send.2)
+                } else {
+                    (receive_asset.clone(), self.available(receive_asset) + receive_amount, self.locked(receive_asset))
+                };
+                event
+                    .with_balance_after(send.0, send.1, send.2)
+                    .with_balance_after(receive.0, receive.1, receive.2)
+            }
+        }
     }
 }
 
-#[async_trait]
-impl Aggregate for Account {
-    type Command = AccountCommand;
-    type Event = AccountEvent;
-    type Error = AccountError;
-    type Services = BankAccountServices;
+impl Account {
+    // The available balance of an open account and whether it is in service, for
+    // answering balance checks straight from the aggregate.
+    pub fn available_balance(&self, asset: &str) -> Option<(u64, bool)> {
+        match self {
+            Account::InService { state } => Some((state.available(asset), true)),
+            Account::Disabled { state } | Account::CloseRequested { state, .. } => Some((state.available(asset), false)),
+            Account::Uninitialized | Account::Closed => None,
+        }
+    }
 
-    // This identifier should be unique to the system.
-    fn aggregate_type() -> String {
-        "account".to_string()
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            Account::InService { state } | Account::Disabled { state } | Account::CloseRequested { state, .. } => Some(&state.account_id),
+            Account::Uninitialized | Account::Closed => None,
+        }
     }
 
     // The aggregate logic goes here. Note that this will be the _bulk_ of a CQRS system
     // so expect to use helper functions elsewhere to keep the code clean.
-    async fn handle(
+    pub(crate) async fn decide(
         &self,
-        command: Self::Command,
-        _services: &Self::Services,
-    ) -> Result<Vec<Self::Event>, Self::Error> {
+        command: AccountCommand,
+        services: &BankAccountServices,
+    ) -> Result<Vec<AccountEvent>, AccountError> {
         match command {
+            AccountCommand::Batch(commands) => {
+                let policy = match self.id() {
+                    Some(account_id) => services.command_policy.policy(account_id),
+                    None => services.command_policy.defaults(),
+                };
+                policy.check_batch(commands.len()).map_err(|err| AccountError::PolicyViolation(err.to_string()))?;
+                // Each command sees the events of the ones before it, a rejected one is
+                // skipped so the rest are still checked.
+                let mut preview = self.clone();
+                let (mut events, mut rejected) = (vec![], vec![]);
+                for (index, command) in commands.into_iter().enumerate() {
+                    if let AccountCommand::Batch(_) = command {
+                        rejected.push((index, AccountError::InvalidTransaction));
+                        continue;
+                    }
+                    match Box::pin(preview.decide(command, services)).await {
+                        Ok(raised) => {
+                            for event in &raised {
+                                preview.apply(event.clone());
+                            }
+                            events.extend(raised);
+                        }
+                        Err(e) => rejected.push((index, e)),
+                    }
+                }
+                if rejected.is_empty() {
+                    Ok(events)
+                } else {
+                    Err(AccountError::BatchRejected(rejected))
+                }
+            }
             AccountCommand::Lifecycle(command) => match command {
                 LifecycleCommand::Open { account_id } => match self {
                     Account::Uninitialized | Account::Closed => {
-                        Ok(vec![AccountEvent::account_opened(account_id)])
+                        let window = services.remembered_secs(&account_id);
+                        let max_txids = services.duplicate_detection.max_txids(&account_id);
+                        Ok(vec![AccountEvent::account_opened_with_window(account_id, window, max_txids)])
                     }
                     _ => Err(AccountError::AccountAlreadyExists),
                 },
@@ -153,6 +501,7 @@ impl Aggregate for Account {
                     Account::Uninitialized | Account::Closed => {
                         Err(AccountError::AccountNotFound)
                     }
+                    Account::CloseRequested { .. } => Err(AccountError::AccountClosing),
                     Account::InService { state } => {
                         if state.is_empty() {
                             Ok(vec![AccountEvent::account_closed()])
@@ -168,41 +517,101 @@ impl Aggregate for Account {
                         }
                     }
                 },
+                LifecycleCommand::SetTags { tags } => match self {
+                    Account::InService { .. } | Account::Disabled { .. } | Account::CloseRequested { .. } => {
+                        Ok(vec![AccountEvent::account_tagged(tags)])
+                    }
+                    Account::Uninitialized | Account::Closed => Err(AccountError::AccountNotFound),
+                },
+                LifecycleCommand::RequestClose { timestamp } => match self {
+                    Account::InService { .. } => {
+                        let finalize_at = timestamp.saturating_add(services.closure_grace_secs);
+                        Ok(vec![AccountEvent::close_requested(timestamp, finalize_at)])
+                    }
+                    Account::CloseRequested { .. } => Err(AccountError::AccountClosing),
+                    Account::Disabled { .. } => Err(AccountError::AccountNotInService),
+                    Account::Uninitialized | Account::Closed => Err(AccountError::AccountNotFound),
+                },
+                LifecycleCommand::FinalizeClose { timestamp } => match self {
+                    Account::CloseRequested { closure, .. } if timestamp < closure.finalize_at => {
+                        Err(AccountError::ClosureNotDue(closure.finalize_at))
+                    }
+                    Account::CloseRequested { closure, .. } if closure.credited => {
+                        Ok(vec![AccountEvent::closure_aborted("credited during the grace period".to_string())])
+                    }
+                    Account::CloseRequested { state, .. } if !state.is_empty() => {
+                        Ok(vec![AccountEvent::closure_aborted("funds left on the account".to_string())])
+                    }
+                    Account::CloseRequested { .. } => Ok(vec![AccountEvent::account_closed()]),
+                    Account::Uninitialized | Account::Closed => Err(AccountError::AccountNotFound),
+                    Account::InService { .. } | Account::Disabled { .. } => Err(AccountError::InvalidTransaction),
+                },
             },
             AccountCommand::Transaction {
                 txid,
                 timestamp,
                 command,
+                labels,
             } => match self {
                 Account::Uninitialized | Account::Closed => {
                     Err(AccountError::AccountNotFound)
                 }
-                Account::Disabled { .. } => Err(AccountError::AccountNotInService),
-                Account::InService { state } => {
-                    match command {
-                        TransactionCommand::Deposit { asset, amount } => {
+                Account::Disabled { .. } if !matches!(command, TransactionCommand::ConvertAsset { .. }) => {
+                    Err(AccountError::AccountNotInService)
+                }
+                Account::CloseRequested { .. } if !command.allowed_while_closing() => {
+                    Err(AccountError::AccountClosing)
+                }
+                // Balances of disabled accounts migrate all the same.
+                Account::InService { state } | Account::Disabled { state } | Account::CloseRequested { state, .. } => {
+                    services.command_policy
+                        .policy(&state.account_id)
+                        .check_transaction(&command, &labels, |asset| state.available(asset))
+                        .map_err(|err| AccountError::PolicyViolation(err.to_string()))?;
+                    let mut value_date = services.value_date(&command)?;
+                    let closing = matches!(self, Account::CloseRequested { .. });
+                    let events = match command {
+                        TransactionCommand::Deposit { asset, amount, source, value_date: backdated } => {
                             if let Some(timestamp) =
-                                state.processed_transactions.get_timestamp(&txid)
+                                services.processed_at(state, &txid, timestamp).await?
                             {
                                 return Err(AccountError::DuplicateTransaction(timestamp));
                             }
-                            Ok(vec![AccountEvent::deposited(
-                                txid, timestamp, asset, amount,
-                            )])
+                            services.validate_asset(&asset).await?;
+                            if let Some(date) = backdated {
+                                value_date = Some(services.check_value_date(date)?);
+                            }
+                            if services.quarantine.quarantines(source.as_deref()) {
+                                Ok(vec![AccountEvent::deposit_quarantined(txid, timestamp, asset, amount, source.unwrap_or_default())])
+                            } else {
+                                let repaid = state.repay_overdraft(txid, timestamp, &asset, amount);
+                                Ok([AccountEvent::deposited(txid, timestamp, asset, amount)]
+                                    .into_iter()
+                                    .chain(repaid)
+                                    .collect())
+                            }
                         }
-                        TransactionCommand::Withdraw { asset, amount } => {
+                        TransactionCommand::Withdraw { asset, amount, value_date: backdated } => {
                             if let Some(timestamp) =
-                                state.processed_transactions.get_timestamp(&txid)
+                                services.processed_at(state, &txid, timestamp).await?
                             {
                                 return Err(AccountError::DuplicateTransaction(timestamp));
                             }
-                            if state.assets.get(&asset).unwrap_or(&0) < &amount {
-                                return Err(AccountError::InsufficientFunds);
+                            services.validate_asset(&asset).await?;
+                            services.clear_withdrawal(state, amount).await?;
+                            if let Some(date) = backdated {
+                                value_date = Some(services.check_value_date(date)?);
                             }
+                            let fee = services.fees.fee(&state.account_id, FeeOperation::Withdraw, &asset, amount);
+                            let due = amount.saturating_add(fee.as_ref().map_or(0, |(_, fee)| *fee));
+                            let drawn = services.draw_overdraft(state, closing, txid, timestamp, &asset, due).await?;
 
-                            Ok(vec![AccountEvent::withdrew(
-                                txid, timestamp, asset, amount,
-                            )])
+                            let mut events: Vec<_> = drawn.into_iter().collect();
+                            events.push(AccountEvent::withdrew(txid, timestamp, asset.clone(), amount));
+                            if let Some((collector, fee)) = fee {
+                                events.push(AccountEvent::fee_charged(fee_txid(&txid), timestamp, collector, asset, fee));
+                            }
+                            Ok(events)
                         }
                         TransactionCommand::Credit {
                             from_account,
@@ -210,17 +619,15 @@ impl Aggregate for Account {
                             amount,
                         } => {
                             if let Some(timestamp) =
-                                state.processed_transactions.get_timestamp(&txid)
+                                services.processed_at(state, &txid, timestamp).await?
                             {
                                 return Err(AccountError::DuplicateTransaction(timestamp));
                             }
-                            Ok(vec![AccountEvent::credited(
-                                txid,
-                                timestamp,
-                                from_account,
-                                asset,
-                                amount,
-                            )])
+                            let repaid = state.repay_overdraft(txid, timestamp, &asset, amount);
+                            Ok([AccountEvent::credited(txid, timestamp, from_account, asset, amount)]
+                                .into_iter()
+                                .chain(repaid)
+                                .collect())
                         }
                         TransactionCommand::ReverseCredit {
                             from_account,
@@ -228,17 +635,22 @@ impl Aggregate for Account {
                             amount,
                         } => {
                             if let Some(timestamp) =
-                                state.processed_transactions.get_timestamp(&txid)
+                                services.processed_at(state, &txid, timestamp).await?
                             {
-                                return Ok(vec![AccountEvent::credit_reversed(
+                                // The credited funds may have been spent since.
+                                if state.assets.get(&asset).unwrap_or(&0) < &amount {
+                                    return Err(AccountError::InsufficientFunds);
+                                }
+                                Ok(vec![AccountEvent::credit_reversed(
                                     txid,
                                     timestamp,
                                     from_account,
                                     asset,
                                     amount,
-                                )]);
+                                )])
+                            } else {
+                                Err(AccountError::TransactionNotFound)
                             }
-                            Err(AccountError::TransactionNotFound)
                         }
                         TransactionCommand::ReverseDebit {
                             to_account,
@@ -246,13 +658,16 @@ impl Aggregate for Account {
                             amount,
                         } => {
                             if let Some(timestamp) =
-                                state.processed_transactions.get_timestamp(&txid)
+                                services.processed_at(state, &txid, timestamp).await?
                             {
-                                return Ok(vec![AccountEvent::debit_reversed(
-                                    txid, timestamp, to_account, asset, amount,
-                                )]);
+                                let repaid = state.repay_overdraft(txid, timestamp, &asset, amount);
+                                Ok([AccountEvent::debit_reversed(txid, timestamp, to_account, asset, amount)]
+                                    .into_iter()
+                                    .chain(repaid)
+                                    .collect())
+                            } else {
+                                Err(AccountError::TransactionNotFound)
                             }
-                            Err(AccountError::TransactionNotFound)
                         }
                         TransactionCommand::Debit {
                             to_account,
@@ -260,17 +675,15 @@ impl Aggregate for Account {
                             amount,
                         } => {
                             if let Some(timestamp) =
-                                state.processed_transactions.get_timestamp(&txid)
+                                services.processed_at(state, &txid, timestamp).await?
                             {
                                 return Err(AccountError::DuplicateTransaction(timestamp));
                             }
-                            if state.assets.get(&asset).unwrap_or(&0) < &amount {
-                                return Err(AccountError::InsufficientFunds);
-                            }
-
-                            Ok(vec![AccountEvent::debited(
-                                txid, timestamp, to_account, asset, amount,
-                            )])
+                            let drawn = services.draw_overdraft(state, closing, txid, timestamp, &asset, amount).await?;
+                            Ok(drawn
+                                .into_iter()
+                                .chain([AccountEvent::debited(txid, timestamp, to_account, asset, amount)])
+                                .collect())
                         }
                         TransactionCommand::LockFunds {
                             asset,
@@ -279,6 +692,8 @@ impl Aggregate for Account {
                             if state.reserving.contains_key(&txid.hex()) {
                                 return Err(AccountError::DuplicateLock);
                             }
+                            services.validate_asset(&asset).await?;
+                            services.validate_check(state, &txid).await?;
                             if state.assets.get(&asset).unwrap_or(&0) < &amount {
                                 return Err(AccountError::InsufficientFunds);
                             }
@@ -287,6 +702,16 @@ impl Aggregate for Account {
                                 txid, timestamp, asset, amount,
                             )])
                         }
+                        TransactionCommand::LockHeld { hold, amount } => {
+                            if state.reserving.contains_key(&txid.hex()) {
+                                return Err(AccountError::DuplicateLock);
+                            }
+                            let (asset, remaining) = state.take_from_hold(&hold, amount)?;
+                            Ok(vec![
+                                AccountEvent::hold_released(held_txid(&txid), timestamp, hold, asset.clone(), amount, remaining),
+                                AccountEvent::funds_locked(txid, timestamp, asset, amount),
+                            ])
+                        }
                         TransactionCommand::UnlockFunds => {
                             if let Some(locked) = state.reserving.get(&txid.hex()) {
                                 Ok(vec![AccountEvent::funds_unlocked(
@@ -296,11 +721,60 @@ impl Aggregate for Account {
                                 Err(AccountError::LockNotFound)
                             }
                         }
+                        TransactionCommand::ReleaseQuarantine => {
+                            let Some(held) = state.earmarked.get(&quarantine_earmark(&txid)) else {
+                                return Err(AccountError::QuarantineNotFound);
+                            };
+                            let repaid = state.repay_overdraft(txid, timestamp, &held.asset, held.amount);
+                            Ok([AccountEvent::quarantine_released(txid, timestamp, held.asset.clone(), held.amount)]
+                                .into_iter()
+                                .chain(repaid)
+                                .collect())
+                        }
+                        TransactionCommand::Earmark { earmark, asset, amount, purpose } => {
+                            if let Some(timestamp) =
+                                services.processed_at(state, &txid, timestamp).await?
+                            {
+                                return Err(AccountError::DuplicateTransaction(timestamp));
+                            }
+                            if amount == 0 {
+                                return Err(AccountError::InvalidTransaction);
+                            }
+                            if earmark.starts_with(QUARANTINE_EARMARK) {
+                                return Err(AccountError::ReservedEarmark(earmark));
+                            }
+                            if state.earmarked.contains_key(&earmark) {
+                                return Err(AccountError::DuplicateEarmark(earmark));
+                            }
+                            if state.available(&asset) < amount {
+                                return Err(AccountError::InsufficientFunds);
+                            }
+                            Ok(vec![AccountEvent::funds_earmarked(txid, timestamp, earmark, asset, amount, purpose)])
+                        }
+                        TransactionCommand::ReleaseEarmark { earmark } => {
+                            if let Some(timestamp) =
+                                services.processed_at(state, &txid, timestamp).await?
+                            {
+                                return Err(AccountError::DuplicateTransaction(timestamp));
+                            }
+                            if earmark.starts_with(QUARANTINE_EARMARK) {
+                                return Err(AccountError::ReservedEarmark(earmark));
+                            }
+                            let Some(earmarked) = state.earmarked.get(&earmark) else {
+                                return Err(AccountError::EarmarkNotFound(earmark));
+                            };
+                            let (asset, amount) = (earmarked.asset.clone(), earmarked.amount);
+                            let repaid = state.repay_overdraft(txid, timestamp, &asset, amount);
+                            Ok([AccountEvent::earmark_released(txid, timestamp, earmark, asset, amount)]
+                                .into_iter()
+                                .chain(repaid)
+                                .collect())
+                        }
                         TransactionCommand::Settle {
                             to_account, receive_asset, receive_amount,
                         } => {
                             if let Some(timestamp) =
-                                state.processed_transactions.get_timestamp(&txid)
+                                services.processed_at(state, &txid, timestamp).await?
                             {
                                 return Err(AccountError::DuplicateTransaction(timestamp));
                             }
@@ -308,32 +782,142 @@ impl Aggregate for Account {
                             let Some(locked) = state.reserving.get(&txid.hex()) else {
                                 return Err(AccountError::LockNotFound)
                             };
-                            Ok(vec![AccountEvent::settlement(
+                            // Never more than what is received, so a settlement cannot fail on its fee.
+                            let fee = services.fees.fee(&state.account_id, FeeOperation::Settle, &receive_asset, receive_amount);
+                            let mut events = vec![AccountEvent::settlement(
                                 txid,
                                 timestamp,
                                 to_account,
                                 locked.asset.clone(),
                                 locked.amount,
-                                receive_asset,
+                                receive_asset.clone(),
                                 receive_amount
-                            )])
+                            )];
+                            if let Some((collector, fee)) = fee {
+                                events.push(AccountEvent::fee_charged(
+                                    fee_txid(&txid), timestamp, collector, receive_asset, fee.min(receive_amount),
+                                ));
+                            }
+                            Ok(events)
                         }
-                    }
+                        TransactionCommand::PlaceHold { hold, asset, amount } => {
+                            if let Some(timestamp) =
+                                services.processed_at(state, &txid, timestamp).await?
+                            {
+                                return Err(AccountError::DuplicateTransaction(timestamp));
+                            }
+                            if amount == 0 {
+                                return Err(AccountError::InvalidTransaction);
+                            }
+                            if state.holds.contains_key(&hold) {
+                                return Err(AccountError::DuplicateHold(hold));
+                            }
+                            services.validate_asset(&asset).await?;
+                            if state.available(&asset) < amount {
+                                return Err(AccountError::InsufficientFunds);
+                            }
+                            Ok(vec![AccountEvent::hold_placed(txid, timestamp, hold, asset, amount)])
+                        }
+                        TransactionCommand::CaptureHold { hold, to_account, amount } => {
+                            if let Some(timestamp) = services.processed_at(state, &txid, timestamp).await? {
+                                return Err(AccountError::DuplicateTransaction(timestamp));
+                            }
+                            let (asset, remaining) = state.take_from_hold(&hold, amount)?;
+                            Ok(vec![AccountEvent::hold_captured(txid, timestamp, hold, to_account, asset, amount, remaining)])
+                        }
+                        TransactionCommand::ReleaseHold { hold, amount } => {
+                            if let Some(timestamp) = services.processed_at(state, &txid, timestamp).await? {
+                                return Err(AccountError::DuplicateTransaction(timestamp));
+                            }
+                            let (asset, remaining) = state.take_from_hold(&hold, amount)?;
+                            Ok(vec![AccountEvent::hold_released(txid, timestamp, hold, asset, amount, remaining)])
+                        }
+                        TransactionCommand::ConvertAsset { from_asset, to_asset, ratio } => {
+                            if from_asset == to_asset || !ratio.is_valid() {
+                                return Err(AccountError::InvalidTransaction);
+                            }
+                            if !state.holds_asset(&from_asset) {
+                                return Err(AccountError::AssetNotHeld(from_asset));
+                            }
+                            match services.services.asset_migration(&from_asset).await {
+                                Some(migration) if migration.to == to_asset && migration.ratio == ratio => {}
+                                _ => return Err(AccountError::MigrationMismatch(from_asset)),
+                            }
+                            if state.clone().convert(&from_asset, &to_asset, ratio).is_none() {
+                                return Err(AccountError::InvalidTransaction);
+                            }
+                            let from_amount = state.available(&from_asset);
+                            let to_amount = ratio.convert(from_amount).expect("converted balance should fit");
+                            Ok(vec![AccountEvent::asset_converted(txid, timestamp, from_asset, from_amount, to_asset, to_amount, ratio)])
+                        }
+                    }?;
+                    let label = |event: AccountEvent| event.with_labels(labels.clone()).with_value_date(value_date);
+                    let events = if events.len() == 1 {
+                        events
+                            .into_iter()
+                            .map(|event| label(state.attach_balance_after(event)))
+                            .collect()
+                    } else {
+                        // Events raised together, such as a transaction and its fee or overdraft,
+                        // each carry the balances the ones before them leave behind.
+                        let mut preview = Account::InService { state: state.clone() };
+                        events
+                            .into_iter()
+                            .map(|event| {
+                                let Account::InService { state } = &preview else {
+                                    unreachable!("account should be in service");
+                                };
+                                let event = state.attach_balance_after(event);
+                                preview.apply(event.clone());
+                                label(event)
+                            })
+                            .collect()
+                    };
+                    Ok(state.evict_overflow(events))
                 }
             },
         }
     }
+}
+
+#[async_trait]
+impl Aggregate for Account {
+    type Command = AccountCommand;
+    type Event = AccountEvent;
+    type Error = AccountError;
+    type Services = BankAccountServices;
+
+    // This identifier should be unique to the system.
+    fn aggregate_type() -> String {
+        "account".to_string()
+    }
+
+    // The logic is `Account::decide`, unless the account is routed to the implementation
+    // under rollout, see `crate::account::canary`.
+    async fn handle(
+        &self,
+        command: Self::Command,
+        services: &Self::Services,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        match &services.canary {
+            Some(canary) => canary.handle(self, command, services).await,
+            None => self.decide(command, services).await,
+        }
+    }
 
     fn apply(&mut self, event: Self::Event) {
         match event {
             AccountEvent::Lifecycle(account_event) => match account_event {
-                LifecycleEvent::Opened { account_id } => {
+                LifecycleEvent::Opened { account_id, duplicate_window_secs, max_txids } => {
                     *self = Account::InService {
                         state: BankAccountState {
                             account_id,
                             assets: BTreeMap::new(),
                             reserving: BTreeMap::new(),
-                            processed_transactions: ProcessedTransactions::new(DEFAULT_TTL),
+                            processed_transactions: ProcessedTransactions::new(duplicate_window_secs, max_txids),
+                            holds: BTreeMap::new(),
+                            overdrawn: BTreeMap::new(),
+                            earmarked: BTreeMap::new(),
                         },
                     };
                 }
@@ -356,14 +940,46 @@ impl Aggregate for Account {
                 LifecycleEvent::Closed => {
                     *self = Account::Closed;
                 }
+                // Tags are only read from the view.
+                LifecycleEvent::Tagged { .. } => {}
+                LifecycleEvent::CloseRequested { finalize_at, .. } => {
+                    let Account::InService { state } = self else {
+                        unreachable!("account should be in service");
+                    };
+                    let mut temp = BankAccountState::default();
+                    mem::swap(state, &mut temp);
+                    *self = Account::CloseRequested { state: temp, closure: PendingClosure { finalize_at, credited: false } };
+                }
+                LifecycleEvent::ClosureAborted { .. } => {
+                    let Account::CloseRequested { state, .. } = self else {
+                        unreachable!("account should be closing");
+                    };
+                    let mut temp = BankAccountState::default();
+                    mem::swap(state, &mut temp);
+                    *self = Account::InService { state: temp };
+                }
+                LifecycleEvent::TxidsEvicted { txids } => {
+                    let (Account::InService { state } | Account::Disabled { state } | Account::CloseRequested { state, .. }) = self else {
+                        unreachable!("account should be open");
+                    };
+                    state.processed_transactions.evict(&txids);
+                }
             },
             AccountEvent::Transaction {
                 timestamp,
                 txid,
                 event,
+                ..
             } => {
-                let Account::InService { ref mut state } = self else {
-                    unreachable!("account should be in service");
+                if let Account::CloseRequested { closure, .. } = self {
+                    closure.credited |= matches!(
+                        event,
+                        TransactionEvent::Deposited { .. } | TransactionEvent::DepositQuarantined { .. } | TransactionEvent::Credited { .. }
+                    );
+                }
+                // Only conversions reach disabled accounts.
+                let (Account::InService { state } | Account::Disabled { state } | Account::CloseRequested { state, .. }) = self else {
+                    unreachable!("account should be open");
                 };
 
                 match event {
@@ -436,6 +1052,90 @@ impl Aggregate for Account {
                             .checked_add(reserved.amount)
                             .expect("balance should not overflow");
                     }
+                    TransactionEvent::FeeCharged { asset, amount, .. } => {
+                        state.save_txid(txid, timestamp);
+                        let balance = state.assets.entry(asset.to_owned()).or_insert(0);
+                        *balance = balance
+                            .checked_sub(amount)
+                            .expect("balance should not be negative");
+                    }
+                    TransactionEvent::HoldPlaced { hold, asset, amount } => {
+                        state.save_txid(txid, timestamp);
+                        let balance = state.assets.entry(asset.to_owned()).or_insert(0);
+                        *balance = balance
+                            .checked_sub(amount)
+                            .expect("balance should not be negative");
+                        state.holds.insert(hold, ReservedFunds { asset, amount });
+                    }
+                    TransactionEvent::HoldCaptured { hold, asset, remaining, .. } => {
+                        state.save_txid(txid, timestamp);
+                        state.set_hold(hold, asset, remaining);
+                    }
+                    TransactionEvent::HoldReleased { hold, asset, amount, remaining } => {
+                        state.save_txid(txid, timestamp);
+                        let balance = state.assets.entry(asset.to_owned()).or_insert(0);
+                        *balance = balance
+                            .checked_add(amount)
+                            .expect("balance should not overflow");
+                        state.set_hold(hold, asset, remaining);
+                    }
+                    // Part of the transaction under the same txid, which records it.
+                    TransactionEvent::OverdraftUsed { asset, amount, .. } => {
+                        let balance = state.assets.entry(asset.to_owned()).or_insert(0);
+                        *balance = balance
+                            .checked_add(amount)
+                            .expect("balance should not overflow");
+                        *state.overdrawn.entry(asset).or_insert(0) += amount;
+                    }
+                    TransactionEvent::OverdraftRepaid { asset, amount } => {
+                        let balance = state.assets.entry(asset.to_owned()).or_insert(0);
+                        *balance = balance
+                            .checked_sub(amount)
+                            .expect("balance should not be negative");
+                        let owed = state.overdrawn.get_mut(&asset).expect("asset not found in overdrawn");
+                        *owed = owed.checked_sub(amount).expect("repaid more than owed");
+                        if *owed == 0 {
+                            state.overdrawn.remove(&asset);
+                        }
+                    }
+                    TransactionEvent::DepositQuarantined { asset, amount, .. } => {
+                        state.save_txid(txid, timestamp);
+                        let funds = EarmarkedFunds { asset, amount, purpose: EarmarkPurpose::Quarantine };
+                        state.earmarked.insert(quarantine_earmark(&txid), funds);
+                    }
+                    // The deposit recorded the txid when it was quarantined.
+                    TransactionEvent::QuarantineReleased { .. } => {
+                        let released = state
+                            .earmarked
+                            .remove(&quarantine_earmark(&txid))
+                            .expect("txid not found in earmarked");
+                        let balance = state.assets.entry(released.asset).or_insert(0);
+                        *balance = balance
+                            .checked_add(released.amount)
+                            .expect("balance should not overflow");
+                    }
+                    TransactionEvent::FundsEarmarked { earmark, asset, amount, purpose } => {
+                        state.save_txid(txid, timestamp);
+                        let balance = state.assets.entry(asset.to_owned()).or_insert(0);
+                        *balance = balance
+                            .checked_sub(amount)
+                            .expect("balance should not be negative");
+                        state.earmarked.insert(earmark, EarmarkedFunds { asset, amount, purpose });
+                    }
+                    TransactionEvent::EarmarkReleased { earmark, asset, amount } => {
+                        state.save_txid(txid, timestamp);
+                        state.earmarked.remove(&earmark).expect("earmark not found");
+                        let balance = state.assets.entry(asset).or_insert(0);
+                        *balance = balance
+                            .checked_add(amount)
+                            .expect("balance should not overflow");
+                    }
+                    // The txid of the migration is not recorded, the account may convert again.
+                    TransactionEvent::AssetConverted { from_asset, to_asset, ratio, .. } => {
+                        state
+                            .convert(&from_asset, &to_asset, ratio)
+                            .expect("converted balances should fit");
+                    }
                     TransactionEvent::Settled { receive_asset, receive_amount, .. } => {
                         state.save_txid(txid, timestamp);
                         state
@@ -454,20 +1154,74 @@ impl Aggregate for Account {
     }
 }
 
+impl StateMachine for Account {
+    const TRANSITIONS: &'static [Transition] = &[
+        Transition { from: "Uninitialized", command: "Open", guard: None, events: &["Opened"], to: "InService" },
+        Transition { from: "Closed", command: "Open", guard: None, events: &["Opened"], to: "InService" },
+        Transition { from: "InService", command: "Disable", guard: None, events: &["Disabled"], to: "Disabled" },
+        Transition { from: "Disabled", command: "Enable", guard: None, events: &["Enabled"], to: "InService" },
+        Transition { from: "InService", command: "Close", guard: Some("no balance"), events: &["Closed"], to: "Closed" },
+        Transition { from: "Disabled", command: "Close", guard: Some("no balance"), events: &["Closed"], to: "Closed" },
+        Transition { from: "InService", command: "SetTags", guard: None, events: &["Tagged"], to: "InService" },
+        Transition { from: "Disabled", command: "SetTags", guard: None, events: &["Tagged"], to: "Disabled" },
+        Transition { from: "InService", command: "RequestClose", guard: None, events: &["CloseRequested"], to: "CloseRequested" },
+        Transition { from: "CloseRequested", command: "FinalizeClose", guard: Some("due, no credits, no balance"), events: &["Closed"], to: "Closed" },
+        Transition { from: "CloseRequested", command: "FinalizeClose", guard: Some("due, credits or balance"), events: &["ClosureAborted"], to: "InService" },
+        Transition { from: "CloseRequested", command: "Withdraw", guard: None, events: &["Withdrew"], to: "CloseRequested" },
+        Transition { from: "CloseRequested", command: "Debit", guard: None, events: &["Debited"], to: "CloseRequested" },
+        Transition { from: "CloseRequested", command: "Deposit", guard: Some("aborts the closure"), events: &["Deposited"], to: "CloseRequested" },
+        Transition { from: "CloseRequested", command: "Credit", guard: Some("aborts the closure"), events: &["Credited"], to: "CloseRequested" },
+        Transition { from: "InService", command: "Deposit", guard: Some("not overdrawn"), events: &["Deposited"], to: "InService" },
+        Transition { from: "InService", command: "Deposit", guard: Some("overdrawn"), events: &["Deposited", "OverdraftRepaid"], to: "InService" },
+        Transition { from: "InService", command: "Deposit", guard: Some("unverified source"), events: &["DepositQuarantined"], to: "InService" },
+        Transition { from: "InService", command: "ReleaseQuarantine", guard: Some("not overdrawn"), events: &["QuarantineReleased"], to: "InService" },
+        Transition { from: "InService", command: "ReleaseQuarantine", guard: Some("overdrawn"), events: &["QuarantineReleased", "OverdraftRepaid"], to: "InService" },
+        Transition { from: "InService", command: "Withdraw", guard: Some("no fee"), events: &["Withdrew"], to: "InService" },
+        Transition { from: "InService", command: "Withdraw", guard: Some("fee due"), events: &["Withdrew", "FeeCharged"], to: "InService" },
+        Transition { from: "InService", command: "Withdraw", guard: Some("overdraft, no fee"), events: &["OverdraftUsed", "Withdrew"], to: "InService" },
+        Transition { from: "InService", command: "Withdraw", guard: Some("overdraft, fee due"), events: &["OverdraftUsed", "Withdrew", "FeeCharged"], to: "InService" },
+        Transition { from: "InService", command: "Debit", guard: Some("within balance"), events: &["Debited"], to: "InService" },
+        Transition { from: "InService", command: "Debit", guard: Some("overdraft"), events: &["OverdraftUsed", "Debited"], to: "InService" },
+        Transition { from: "InService", command: "ReverseDebit", guard: Some("not overdrawn"), events: &["DebitReversed"], to: "InService" },
+        Transition { from: "InService", command: "ReverseDebit", guard: Some("overdrawn"), events: &["DebitReversed", "OverdraftRepaid"], to: "InService" },
+        Transition { from: "InService", command: "Credit", guard: Some("not overdrawn"), events: &["Credited"], to: "InService" },
+        Transition { from: "InService", command: "Credit", guard: Some("overdrawn"), events: &["Credited", "OverdraftRepaid"], to: "InService" },
+        Transition { from: "InService", command: "ReverseCredit", guard: None, events: &["CreditReversed"], to: "InService" },
+        Transition { from: "InService", command: "LockFunds", guard: None, events: &["FundsLocked"], to: "InService" },
+        Transition { from: "InService", command: "UnlockFunds", guard: None, events: &["FundsUnlocked"], to: "InService" },
+        Transition { from: "InService", command: "Settle", guard: Some("no fee"), events: &["Settled"], to: "InService" },
+        Transition { from: "InService", command: "Settle", guard: Some("fee due"), events: &["Settled", "FeeCharged"], to: "InService" },
+        Transition { from: "InService", command: "PlaceHold", guard: None, events: &["HoldPlaced"], to: "InService" },
+        Transition { from: "InService", command: "CaptureHold", guard: None, events: &["HoldCaptured"], to: "InService" },
+        Transition { from: "InService", command: "ReleaseHold", guard: None, events: &["HoldReleased"], to: "InService" },
+        Transition { from: "InService", command: "LockHeld", guard: None, events: &["HoldReleased", "FundsLocked"], to: "InService" },
+        Transition { from: "InService", command: "Earmark", guard: None, events: &["FundsEarmarked"], to: "InService" },
+        Transition { from: "InService", command: "ReleaseEarmark", guard: Some("not overdrawn"), events: &["EarmarkReleased"], to: "InService" },
+        Transition { from: "InService", command: "ReleaseEarmark", guard: Some("overdrawn"), events: &["EarmarkReleased", "OverdraftRepaid"], to: "InService" },
+        Transition { from: "InService", command: "ConvertAsset", guard: Some("asset held"), events: &["AssetConverted"], to: "InService" },
+        Transition { from: "Disabled", command: "ConvertAsset", guard: Some("asset held"), events: &["AssetConverted"], to: "Disabled" },
+    ];
+}
+
 // The aggregate tests are the most important part of a CQRS system.
 // The simplicity and flexibility of these tests are a good part of what
 // makes an event sourced system so friendly to changing business requirements.
 #[cfg(test)]
 mod aggregate_tests {
     use async_trait::async_trait;
-    use std::sync::Mutex;
+    use std::collections::{BTreeSet, HashMap};
+    use std::sync::{Arc, Mutex};
 
     use cqrs_es::test::TestFramework;
 
-    use crate::account::aggregate::Account;
+    use crate::account::aggregate::{fee_txid, held_txid, quarantine_earmark, Account};
     use crate::account::commands::{AccountCommand, TransactionCommand};
-    use crate::account::events::AccountEvent;
-    use crate::services::{AtmError, BankAccountApi, BankAccountServices, CheckingError};
+    use crate::account::events::{AccountError, AccountEvent, EarmarkPurpose, TransactionLabels, DEFAULT_TTL};
+    use crate::asset::aggregate::AssetMigration;
+    use crate::asset::types::ConversionRatio;
+    use crate::business_day::PostingDate;
+    use crate::config::{CommandPolicyConfig, DuplicateDetectionConfig, FeeConfig, FeeRate, QuarantineConfig};
+    use crate::services::{AssetValidationError, AtmError, BankAccountApi, BankAccountServices, CheckingError, DedupStore, OverdraftPolicy};
     use crate::util::types::ByteArray32;
 
     // A test framework that will apply our events and command
@@ -476,36 +1230,117 @@ mod aggregate_tests {
 
     #[test]
     fn test_deposit_money() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
         let expected =
-            AccountEvent::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 1000);
+            AccountEvent::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 1000)
+                .with_balance_after("Satoshi", 1000, 0);
         let command =
             AccountCommand::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 1000);
 
         let services = BankAccountServices::new(Box::new(MockBankAccountServices::default()));
         // Obtain a new test framework
         AccountTestFramework::with(services)
-            // In a test case with no previous events
-            .given_no_previous_events()
+            // In a test case with a freshly opened account
+            .given(vec![opened])
             // Wnen we fire this command
             .when(command)
             // then we expect these results
             .then_expect_events(vec![expected]);
     }
 
+    #[test]
+    fn test_deposit_value_dated() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
+        let expected = AccountEvent::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 1000)
+            .with_balance_after("Satoshi", 1000, 0)
+            .with_value_date(Some("2026-10-16".parse().unwrap()));
+        let command = AccountCommand::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 1000);
+
+        let posting_date = PostingDate::new("2026-10-16".parse().unwrap(), u64::MAX);
+        let services = BankAccountServices::new(Box::new(MockBankAccountServices::default())).with_posting_date(Some(posting_date));
+        AccountTestFramework::with(services)
+            .given(vec![opened.clone()])
+            .when(command.clone())
+            .then_expect_events(vec![expected]);
+
+        // Past the cut-off the day is frozen, until the next one is open.
+        let posting_date = PostingDate::new("2026-10-16".parse().unwrap(), 0);
+        let services = BankAccountServices::new(Box::new(MockBankAccountServices::default())).with_posting_date(Some(posting_date));
+        AccountTestFramework::with(services)
+            .given(vec![opened])
+            .when(command)
+            .then_expect_error_message("Business day 2026-10-16 is closed");
+    }
+
+    #[test]
+    fn test_transfer_unwinds_on_frozen_day() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
+        let funded = AccountEvent::deposited(ByteArray32([0; 32]), 1, "BTC".to_string(), 100);
+        let debited = AccountEvent::debited(ByteArray32([1; 32]), 2, "ACCT-0002".to_string(), "BTC".to_string(), 10);
+        let posting_date = PostingDate::new("2026-10-16".parse().unwrap(), 0);
+        let services = || BankAccountServices::new(Box::new(MockBankAccountServices::default())).with_posting_date(Some(posting_date.clone()));
+
+        // New transactions wait for the next day, reversing the debit of a transfer does not.
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), funded.clone(), debited.clone()])
+            .when(AccountCommand::debit(ByteArray32([2; 32]), 3, "ACCT-0002".to_string(), "BTC".to_string(), 10))
+            .then_expect_error_message("Business day 2026-10-16 is closed");
+        AccountTestFramework::with(services())
+            .given(vec![opened, funded, debited])
+            .when(AccountCommand::reverse_debit(ByteArray32([1; 32]), 3, "ACCT-0002".to_string(), "BTC".to_string(), 10))
+            .then_expect_events(vec![
+                AccountEvent::debit_reversed(ByteArray32([1; 32]), 2, "ACCT-0002".to_string(), "BTC".to_string(), 10)
+                    .with_balance_after("BTC", 100, 0)
+                    .with_value_date(Some("2026-10-16".parse().unwrap())),
+            ]);
+    }
+
+    #[test]
+    fn test_deposit_backdated() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
+        let expected = AccountEvent::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 1000)
+            .with_balance_after("Satoshi", 1000, 0)
+            .with_value_date(Some("2026-10-13".parse().unwrap()));
+        let command = AccountCommand::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 1000)
+            .with_value_date("2026-10-13".parse().unwrap());
+        let services = || {
+            let posting_date = PostingDate::new("2026-10-16".parse().unwrap(), u64::MAX);
+            BankAccountServices::new(Box::new(MockBankAccountServices::default()))
+                .with_posting_date(Some(posting_date))
+                .with_max_backdate_days(3)
+        };
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone()])
+            .when(command)
+            .then_expect_events(vec![expected]);
+
+        // Neither before the window nor after the posting date.
+        for date in ["2026-10-12", "2026-10-17"] {
+            let command = AccountCommand::withdrew(ByteArray32([1; 32]), 0, "Satoshi".to_string(), 10)
+                .with_value_date(date.parse().unwrap());
+            AccountTestFramework::with(services())
+                .given(vec![opened.clone()])
+                .when(command)
+                .then_expect_error_message(&format!("Value date {} is outside of the backdating window", date));
+        }
+    }
+
     #[test]
     fn test_deposit_money_with_balance() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
         let previous =
             AccountEvent::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 1000);
 
         let expected =
-            AccountEvent::deposited(ByteArray32([1; 32]), 1, "Satoshi".to_string(), 1000);
+            AccountEvent::deposited(ByteArray32([1; 32]), 1, "Satoshi".to_string(), 200)
+                .with_balance_after("Satoshi", 1200, 0);
         let command =
             AccountCommand::deposited(ByteArray32([1; 32]), 1, "Satoshi".to_string(), 200);
         let services = BankAccountServices::new(Box::new(MockBankAccountServices::default()));
 
         AccountTestFramework::with(services)
-            // Given this previously applied event
-            .given(vec![previous])
+            // Given these previously applied events
+            .given(vec![opened, previous])
             // When we fire this command
             .when(command)
             // Then we expect this resultant event
@@ -514,23 +1349,26 @@ mod aggregate_tests {
 
     #[test]
     fn test_withdraw_money() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
         let previous =
             AccountEvent::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 200);
         let expected =
-            AccountEvent::withdrew(ByteArray32([1; 32]), 1, "Satoshi".to_string(), 100);
+            AccountEvent::withdrew(ByteArray32([1; 32]), 1, "Satoshi".to_string(), 100)
+                .with_balance_after("Satoshi", 100, 0);
         let services = MockBankAccountServices::default();
         services.set_atm_withdrawal_response(Ok(()));
         let command =
             AccountCommand::withdrew(ByteArray32([1; 32]), 1, "Satoshi".to_string(), 100);
 
         AccountTestFramework::with(BankAccountServices::new(Box::new(services)))
-            .given(vec![previous])
+            .given(vec![opened, previous])
             .when(command)
             .then_expect_events(vec![expected]);
     }
 
     #[test]
     fn test_withdraw_money_client_error() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
         let previous =
             AccountEvent::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 200);
         let services = MockBankAccountServices::default();
@@ -541,31 +1379,86 @@ mod aggregate_tests {
             command: TransactionCommand::Withdraw {
                 asset: "Satoshi".to_string(),
                 amount: 100,
+                value_date: None,
             },
+            labels: TransactionLabels::default(),
         };
 
         let services = BankAccountServices::new(Box::new(services));
         AccountTestFramework::with(services)
-            .given(vec![previous])
+            .given(vec![opened, previous])
             .when(command)
             .then_expect_error_message("atm rule violation");
     }
 
+    #[test]
+    fn test_withdraw_money_duplicate_transaction() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
+        let previous =
+            AccountEvent::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 200);
+        let command = AccountCommand::Transaction {
+            txid: ByteArray32([0; 32]),
+            timestamp: 1,
+            command: TransactionCommand::Withdraw {
+                asset: "Satoshi".to_string(),
+                amount: 100,
+                value_date: None,
+            },
+            labels: TransactionLabels::default(),
+        };
+
+        let services = BankAccountServices::new(Box::new(MockBankAccountServices::default()));
+        AccountTestFramework::with(services)
+            .given(vec![opened, previous])
+            .when(command)
+            .then_expect_error_message(
+                "duplicate transaction, this transaction has already been processed at 0",
+            );
+    }
+
     #[test]
     fn test_withdraw_money_funds_not_available() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
         let command =
             AccountCommand::withdrew(ByteArray32([1; 32]), 0, "Satoshi".to_string(), 200);
 
         let services = BankAccountServices::new(Box::new(MockBankAccountServices::default()));
         AccountTestFramework::with(services)
-            .given_no_previous_events()
+            .given(vec![opened])
             .when(command)
             // Here we expect an error rather than any events
-            .then_expect_error_message("funds not available")
+            .then_expect_error_message("Insufficient funds")
+    }
+
+    #[test]
+    fn test_transaction_on_unopened_account() {
+        let command =
+            AccountCommand::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 1000);
+
+        let services = BankAccountServices::new(Box::new(MockBankAccountServices::default()));
+        AccountTestFramework::with(services)
+            .given_no_previous_events()
+            .when(command)
+            .then_expect_error_message("Account not found")
+    }
+
+    #[test]
+    fn test_deposit_unregistered_asset() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
+        let command =
+            AccountCommand::deposited(ByteArray32([0; 32]), 0, "BTc".to_string(), 1000);
+
+        let services = MockBankAccountServices::default();
+        services.set_unregistered_asset("BTc");
+        AccountTestFramework::with(BankAccountServices::new(Box::new(services)))
+            .given(vec![opened])
+            .when(command)
+            .then_expect_error_message("Asset BTc is not registered")
     }
 
     #[test]
     fn test_lock_funds() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
         let previous =
             AccountEvent::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 200);
         let expected = AccountEvent::funds_locked(
@@ -573,7 +1466,8 @@ mod aggregate_tests {
             1,
             "Satoshi".to_string(),
             100,
-        );
+        )
+        .with_balance_after("Satoshi", 100, 100);
         let services = MockBankAccountServices::default();
         services.set_validate_check_response(Ok(()));
         let services = BankAccountServices::new(Box::new(services));
@@ -586,13 +1480,14 @@ mod aggregate_tests {
         );
 
         AccountTestFramework::with(services)
-            .given(vec![previous])
+            .given(vec![opened, previous])
             .when(command)
             .then_expect_events(vec![expected]);
     }
 
     #[test]
     fn test_lock_funds_insufficient_funds() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
         let previous =
             AccountEvent::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 200);
         let services = MockBankAccountServices::default();
@@ -606,26 +1501,667 @@ mod aggregate_tests {
         );
 
         AccountTestFramework::with(services)
-            .given(vec![previous])
+            .given(vec![opened, previous])
             .when(command)
             .then_expect_error_message("check invalid");
     }
 
+    #[test]
+    fn test_lock_funds_exceeding_balance() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
+        let previous =
+            AccountEvent::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 200);
+        let services = BankAccountServices::new(Box::new(MockBankAccountServices::default()));
+        let command = AccountCommand::lock_funds(
+            ByteArray32([1; 32]),
+            1,
+            "Satoshi".to_string(),
+            300,
+        );
+
+        AccountTestFramework::with(services)
+            .given(vec![opened, previous])
+            .when(command)
+            .then_expect_error_message("Insufficient funds");
+    }
+
     #[test]
     fn test_unlock_funds_not_found() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
         let command =
-            AccountCommand::unlock_funds(ByteArray32([0; 32]));
+            AccountCommand::unlock_funds(ByteArray32([0; 32]), 0);
+
+        let services = BankAccountServices::new(Box::new(MockBankAccountServices::default()));
+        AccountTestFramework::with(services)
+            .given(vec![opened])
+            .when(command)
+            .then_expect_error_message(
+                "Lock not found, please check the transaction id and make sure it not expired",
+            )
+    }
+
+    #[test]
+    fn test_hold_partial_capture_and_release() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
+        let deposited = AccountEvent::deposited(ByteArray32([0; 32]), 0, "USD".to_string(), 500);
+        let placed = AccountEvent::hold_placed(ByteArray32([1; 32]), 1, "payment-1".to_string(), "USD".to_string(), 300);
+        let services = || {
+            let services = MockBankAccountServices::default();
+            services.set_validate_check_response(Ok(()));
+            BankAccountServices::new(Box::new(services))
+        };
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone()])
+            .when(AccountCommand::place_hold(ByteArray32([1; 32]), 1, "payment-1".to_string(), "USD".to_string(), 300))
+            .then_expect_events(vec![placed.clone().with_balance_after("USD", 200, 300)]);
+        // A second hold on the same funds is independent of the first.
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), placed.clone()])
+            .when(AccountCommand::place_hold(ByteArray32([2; 32]), 2, "payment-2".to_string(), "USD".to_string(), 200))
+            .then_expect_events(vec![
+                AccountEvent::hold_placed(ByteArray32([2; 32]), 2, "payment-2".to_string(), "USD".to_string(), 200)
+                    .with_balance_after("USD", 0, 500),
+            ]);
+
+        let captured = AccountEvent::hold_captured(ByteArray32([3; 32]), 3, "payment-1".to_string(), "MERCHANT".to_string(), "USD".to_string(), 120, 180);
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), placed.clone()])
+            .when(AccountCommand::capture_hold(ByteArray32([3; 32]), 3, "payment-1".to_string(), "MERCHANT".to_string(), 120))
+            .then_expect_events(vec![captured.clone().with_balance_after("USD", 200, 180)]);
+        // Releasing the rest ends the hold.
+        let released = AccountEvent::hold_released(ByteArray32([4; 32]), 4, "payment-1".to_string(), "USD".to_string(), 180, 0);
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), placed.clone(), captured.clone()])
+            .when(AccountCommand::release_hold(ByteArray32([4; 32]), 4, "payment-1".to_string(), 180))
+            .then_expect_events(vec![released.clone().with_balance_after("USD", 380, 0)]);
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), placed.clone(), captured.clone(), released])
+            .when(AccountCommand::capture_hold(ByteArray32([5; 32]), 5, "payment-1".to_string(), "MERCHANT".to_string(), 1))
+            .then_expect_error_message("Hold payment-1 not found");
+
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), placed.clone(), captured.clone()])
+            .when(AccountCommand::capture_hold(ByteArray32([5; 32]), 5, "payment-1".to_string(), "MERCHANT".to_string(), 181))
+            .then_expect_error_message("Amount exceeds the 180 left on the hold");
+        // A capture sent twice is only applied once.
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), placed.clone(), captured])
+            .when(AccountCommand::capture_hold(ByteArray32([3; 32]), 3, "payment-1".to_string(), "MERCHANT".to_string(), 120))
+            .then_expect_error_message("duplicate transaction, this transaction has already been processed at 3");
+        AccountTestFramework::with(services())
+            .given(vec![opened, deposited, placed])
+            .when(AccountCommand::place_hold(ByteArray32([6; 32]), 6, "payment-1".to_string(), "USD".to_string(), 1))
+            .then_expect_error_message("Hold payment-1 already exists");
+    }
+
+    #[test]
+    fn test_lock_held_funds() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
+        let deposited = AccountEvent::deposited(ByteArray32([0; 32]), 0, "USD".to_string(), 500);
+        let placed = AccountEvent::hold_placed(ByteArray32([1; 32]), 1, "bid-1".to_string(), "USD".to_string(), 300);
+        let services = || BankAccountServices::new(Box::new(MockBankAccountServices::default()));
+        let lock = ByteArray32([7; 32]);
+        let released = AccountEvent::hold_released(held_txid(&lock), 2, "bid-1".to_string(), "USD".to_string(), 120, 180);
+        let locked = AccountEvent::funds_locked(lock, 2, "USD".to_string(), 120);
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), placed.clone()])
+            .when(AccountCommand::lock_held(lock, 2, "bid-1".to_string(), 120))
+            .then_expect_events(vec![
+                released.clone().with_balance_after("USD", 320, 180),
+                locked.clone().with_balance_after("USD", 200, 300),
+            ]);
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), placed.clone()])
+            .when(AccountCommand::lock_held(lock, 2, "bid-1".to_string(), 301))
+            .then_expect_error_message("Amount exceeds the 300 left on the hold");
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), placed.clone(), released.clone(), locked.clone()])
+            .when(AccountCommand::lock_held(lock, 3, "bid-1".to_string(), 10))
+            .then_expect_error_message("Duplicate lock, this lock has already been processed");
+        // The lock settles under the txid of the command.
+        let settled = AccountTestFramework::with(services())
+            .given(vec![opened, deposited, placed, released, locked])
+            .when(AccountCommand::settle(lock, 3, "ACCT-0002".to_string(), "BTC".to_string(), 1))
+            .inspect_result();
+        assert!(settled.is_ok());
+    }
+
+    #[test]
+    fn test_tag_account() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
+        let tags: BTreeSet<String> = ["vip".to_string()].into_iter().collect();
+        let services = || BankAccountServices::new(Box::new(MockBankAccountServices::default()));
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), AccountEvent::account_disabled()])
+            .when(AccountCommand::tags_set(tags.clone()))
+            .then_expect_events(vec![AccountEvent::account_tagged(tags.clone())]);
+
+        AccountTestFramework::with(services())
+            .given(vec![opened, AccountEvent::account_closed()])
+            .when(AccountCommand::tags_set(tags))
+            .then_expect_error_message("Account not found");
+    }
+
+    #[test]
+    fn test_two_phase_closure() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
+        let deposited = AccountEvent::deposited(ByteArray32([1; 32]), 1, "USD".to_string(), 100);
+        let services = || BankAccountServices::new(Box::new(MockBankAccountServices::default())).with_closure_grace_secs(1000);
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone()])
+            .when(AccountCommand::close_requested(10))
+            .then_expect_events(vec![AccountEvent::close_requested(10, 1010)]);
+
+        // Withdrawal-only for the grace period.
+        let requested = AccountEvent::close_requested(10, 1010);
+        let given = vec![opened.clone(), deposited.clone(), requested.clone()];
+        AccountTestFramework::with(services())
+            .given(given.clone())
+            .when(AccountCommand::withdrew(ByteArray32([2; 32]), 20, "USD".to_string(), 100))
+            .then_expect_events(vec![AccountEvent::withdrew(ByteArray32([2; 32]), 20, "USD".to_string(), 100).with_balance_after("USD", 0, 0)]);
+        AccountTestFramework::with(services())
+            .given(given.clone())
+            .when(AccountCommand::Transaction { txid: ByteArray32([2; 32]), timestamp: 20, command: TransactionCommand::LockFunds { asset: "USD".to_string(), amount: 10 }, labels: TransactionLabels::default() })
+            .then_expect_error_message("Account is being closed");
+        AccountTestFramework::with(services())
+            .given(given.clone())
+            .when(AccountCommand::close_finalized(1009))
+            .then_expect_error_message("Closure of the account is not due before 1010");
+
+        // Drained, it closes; with funds left or credited since, it goes back in service.
+        let withdrew = AccountEvent::withdrew(ByteArray32([2; 32]), 20, "USD".to_string(), 100);
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), requested.clone(), withdrew.clone()])
+            .when(AccountCommand::close_finalized(1010))
+            .then_expect_events(vec![AccountEvent::account_closed()]);
+        AccountTestFramework::with(services())
+            .given(given)
+            .when(AccountCommand::close_finalized(1010))
+            .then_expect_events(vec![AccountEvent::closure_aborted("funds left on the account".to_string())]);
+        let refunded = AccountEvent::deposited(ByteArray32([3; 32]), 30, "USD".to_string(), 5);
+        let withdrew_again = AccountEvent::withdrew(ByteArray32([4; 32]), 40, "USD".to_string(), 5);
+        AccountTestFramework::with(services())
+            .given(vec![opened, deposited, requested, withdrew, refunded, withdrew_again])
+            .when(AccountCommand::close_finalized(1010))
+            .then_expect_events(vec![AccountEvent::closure_aborted("credited during the grace period".to_string())]);
+    }
+
+    #[test]
+    fn test_open_records_duplicate_window() {
+        let duplicate_detection = DuplicateDetectionConfig {
+            window_secs: 600,
+            tenants: [("ACME-".to_string(), 10)].into_iter().collect(),
+            max_txids: Some(1000),
+            ..DuplicateDetectionConfig::default()
+        };
+        let services = BankAccountServices::new(Box::new(MockBankAccountServices::default()))
+            .with_duplicate_detection(duplicate_detection);
+        AccountTestFramework::with(services)
+            .given_no_previous_events()
+            .when(AccountCommand::account_opened("ACME-0001".to_string()))
+            .then_expect_events(vec![AccountEvent::account_opened_with_window("ACME-0001".to_string(), 10, Some(1000))]);
+    }
+
+    #[test]
+    fn test_txid_reusable_after_duplicate_window() {
+        let opened = AccountEvent::account_opened_with_window("ACME-0001".to_string(), 10, None);
+        let first = AccountEvent::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 100);
+        // Recording a later txid forgets the ones older than the window.
+        let later = AccountEvent::deposited(ByteArray32([1; 32]), 20, "Satoshi".to_string(), 100);
+        let expected = AccountEvent::deposited(ByteArray32([0; 32]), 21, "Satoshi".to_string(), 100)
+            .with_balance_after("Satoshi", 300, 0);
 
         let services = BankAccountServices::new(Box::new(MockBankAccountServices::default()));
         AccountTestFramework::with(services)
+            .given(vec![opened, first, later])
+            .when(AccountCommand::deposited(ByteArray32([0; 32]), 21, "Satoshi".to_string(), 100))
+            .then_expect_events(vec![expected]);
+    }
+
+    struct StoredTxids(Vec<(ByteArray32, u64)>);
+
+    #[async_trait]
+    impl DedupStore for StoredTxids {
+        async fn processed_at(&self, _account_id: &str, txid: &ByteArray32, since: u64) -> Result<Option<u64>, String> {
+            Ok(self.0.iter().find(|(stored, timestamp)| stored == txid && *timestamp >= since).map(|(_, timestamp)| *timestamp))
+        }
+    }
+
+    #[test]
+    fn test_txids_in_dedup_store() {
+        let (debit, deposit) = (ByteArray32([1; 32]), ByteArray32([2; 32]));
+        let duplicate_detection = DuplicateDetectionConfig { window_secs: 1000, recent_secs: 10, ..DuplicateDetectionConfig::default() };
+        let services = || {
+            BankAccountServices::new(Box::new(MockBankAccountServices::default()))
+                .with_duplicate_detection(duplicate_detection.clone())
+                .with_dedup_store(Some(Arc::new(StoredTxids(vec![(debit, 5), (deposit, 6)]))))
+        };
+        let opened = AccountEvent::account_opened_with_window("ACCT-0001".to_string(), 10, None);
+        let funded = AccountEvent::deposited(ByteArray32([0; 32]), 1, "BTC".to_string(), 100);
+
+        // The account only keeps the recent txids.
+        AccountTestFramework::with(services())
             .given_no_previous_events()
+            .when(AccountCommand::account_opened("ACCT-0001".to_string()))
+            .then_expect_events(vec![opened.clone()]);
+        // Older ones are found in the store within the window, and no longer past it.
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), funded.clone()])
+            .when(AccountCommand::deposited(deposit, 500, "BTC".to_string(), 1))
+            .then_expect_error_message("duplicate transaction, this transaction has already been processed at 6");
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), funded.clone()])
+            .when(AccountCommand::deposited(deposit, 1007, "BTC".to_string(), 1))
+            .then_expect_events(vec![AccountEvent::deposited(deposit, 1007, "BTC".to_string(), 1).with_balance_after("BTC", 101, 0)]);
+        // A reversal finds the transaction it reverses there too.
+        AccountTestFramework::with(services())
+            .given(vec![opened, funded])
+            .when(AccountCommand::reverse_debit(debit, 500, "ACCT-0002".to_string(), "BTC".to_string(), 10))
+            .then_expect_events(vec![
+                AccountEvent::debit_reversed(debit, 5, "ACCT-0002".to_string(), "BTC".to_string(), 10).with_balance_after("BTC", 110, 0),
+            ]);
+    }
+
+    #[test]
+    fn test_oldest_txids_evicted_past_cap() {
+        let opened = AccountEvent::account_opened_with_window("ACCT-0001".to_string(), DEFAULT_TTL, Some(2));
+        let first = AccountEvent::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 100);
+        let second = AccountEvent::deposited(ByteArray32([1; 32]), 1, "Satoshi".to_string(), 100);
+        let third = AccountEvent::deposited(ByteArray32([2; 32]), 2, "Satoshi".to_string(), 100)
+            .with_balance_after("Satoshi", 300, 0);
+        let evicted = AccountEvent::txids_evicted(vec![ByteArray32([0; 32])]);
+
+        let services = || BankAccountServices::new(Box::new(MockBankAccountServices::default()));
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), first.clone(), second.clone()])
+            .when(AccountCommand::deposited(ByteArray32([2; 32]), 2, "Satoshi".to_string(), 100))
+            .then_expect_events(vec![third.clone(), evicted.clone()]);
+        // Once evicted, the txid is no longer a duplicate.
+        let resent = AccountEvent::deposited(ByteArray32([0; 32]), 3, "Satoshi".to_string(), 100)
+            .with_balance_after("Satoshi", 400, 0);
+        AccountTestFramework::with(services())
+            .given(vec![opened, first, second, third, evicted])
+            .when(AccountCommand::deposited(ByteArray32([0; 32]), 3, "Satoshi".to_string(), 100))
+            .then_expect_events(vec![resent, AccountEvent::txids_evicted(vec![ByteArray32([1; 32])])]);
+    }
+
+    #[test]
+    fn test_withdraw_charges_fee() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
+        let previous = AccountEvent::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 200);
+        let fees = FeeConfig {
+            collection_account: "FEES".to_string(),
+            withdraw: [("Satoshi".to_string(), FeeRate { flat: 5, bps: 0 })].into_iter().collect(),
+            ..Default::default()
+        };
+        let services = || BankAccountServices::new(Box::new(MockBankAccountServices::default())).with_fees(fees.clone());
+        let expected = vec![
+            AccountEvent::withdrew(ByteArray32([1; 32]), 1, "Satoshi".to_string(), 100)
+                .with_balance_after("Satoshi", 100, 0),
+            AccountEvent::fee_charged(fee_txid(&ByteArray32([1; 32])), 1, "FEES".to_string(), "Satoshi".to_string(), 5)
+                .with_balance_after("Satoshi", 95, 0),
+        ];
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), previous.clone()])
+            .when(AccountCommand::withdrew(ByteArray32([1; 32]), 1, "Satoshi".to_string(), 100))
+            .then_expect_events(expected);
+
+        // The fee has to be covered as well.
+        AccountTestFramework::with(services())
+            .given(vec![opened, previous])
+            .when(AccountCommand::withdrew(ByteArray32([1; 32]), 1, "Satoshi".to_string(), 198))
+            .then_expect_error_message("Insufficient funds");
+    }
+
+    #[test]
+    fn test_labels_carried_to_every_event() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
+        let previous = AccountEvent::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 200);
+        let fees = FeeConfig {
+            collection_account: "FEES".to_string(),
+            withdraw: [("Satoshi".to_string(), FeeRate { flat: 5, bps: 0 })].into_iter().collect(),
+            ..Default::default()
+        };
+        let labels = TransactionLabels {
+            category: Some("payroll".to_string()),
+            metadata: [("invoice".to_string(), "INV-42".to_string())].into_iter().collect(),
+        };
+        AccountTestFramework::with(BankAccountServices::new(Box::new(MockBankAccountServices::default())).with_fees(fees))
+            .given(vec![opened, previous])
+            .when(AccountCommand::withdrew(ByteArray32([1; 32]), 1, "Satoshi".to_string(), 100).with_labels(labels.clone()))
+            .then_expect_events(vec![
+                AccountEvent::withdrew(ByteArray32([1; 32]), 1, "Satoshi".to_string(), 100)
+                    .with_balance_after("Satoshi", 100, 0)
+                    .with_labels(labels.clone()),
+                AccountEvent::fee_charged(fee_txid(&ByteArray32([1; 32])), 1, "FEES".to_string(), "Satoshi".to_string(), 5)
+                    .with_balance_after("Satoshi", 95, 0)
+                    .with_labels(labels),
+            ]);
+    }
+
+    #[test]
+    fn test_settle_reports_balances_of_both_assets() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
+        let deposited =
+            AccountEvent::deposited(ByteArray32([0; 32]), 0, "Satoshi".to_string(), 200);
+        let locked =
+            AccountEvent::funds_locked(ByteArray32([1; 32]), 1, "Satoshi".to_string(), 150);
+        let expected = AccountEvent::settlement(
+            ByteArray32([1; 32]),
+            1,
+            "ACCT-0002".to_string(),
+            "Satoshi".to_string(),
+            150,
+            "Wei".to_string(),
+            3000,
+        )
+        .with_balance_after("Satoshi", 50, 0)
+        .with_balance_after("Wei", 3000, 0);
+        let command = AccountCommand::Transaction {
+            txid: ByteArray32([1; 32]),
+            timestamp: 1,
+            command: TransactionCommand::Settle {
+                to_account: "ACCT-0002".to_string(),
+                receive_asset: "Wei".to_string(),
+                receive_amount: 3000,
+            },
+            labels: TransactionLabels::default(),
+        };
+
+        let services = BankAccountServices::new(Box::new(MockBankAccountServices::default()));
+        AccountTestFramework::with(services)
+            .given(vec![opened, deposited, locked])
             .when(command)
-            .then_expect_error_message("funds not available")
+            .then_expect_events(vec![expected]);
+    }
+
+    #[test]
+    fn test_overdraft_up_to_credit_limit() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
+        let deposited = AccountEvent::deposited(ByteArray32([0; 32]), 0, "USD".to_string(), 50);
+        let services = || BankAccountServices::new(Box::new(MockBankAccountServices::default()))
+            .with_overdraft(Box::new(FixedOverdraft(100)));
+        let drawn = AccountEvent::overdraft_used(ByteArray32([1; 32]), 1, "USD".to_string(), 70, 100);
+        let withdrew = AccountEvent::withdrew(ByteArray32([1; 32]), 1, "USD".to_string(), 120);
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone()])
+            .when(AccountCommand::withdrew(ByteArray32([1; 32]), 1, "USD".to_string(), 120))
+            .then_expect_events(vec![
+                drawn.clone().with_balance_after("USD", 120, 0),
+                withdrew.clone().with_balance_after("USD", 0, 0),
+            ]);
+
+        // Only 30 of the limit is left.
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), drawn.clone(), withdrew.clone()])
+            .when(AccountCommand::debit(ByteArray32([2; 32]), 2, "ACCT-0002".to_string(), "USD".to_string(), 31))
+            .then_expect_error_message("Insufficient funds");
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), drawn.clone(), withdrew.clone()])
+            .when(AccountCommand::debit(ByteArray32([2; 32]), 2, "ACCT-0002".to_string(), "USD".to_string(), 30))
+            .then_expect_events(vec![
+                AccountEvent::overdraft_used(ByteArray32([2; 32]), 2, "USD".to_string(), 30, 100).with_balance_after("USD", 30, 0),
+                AccountEvent::debited(ByteArray32([2; 32]), 2, "ACCT-0002".to_string(), "USD".to_string(), 30).with_balance_after("USD", 0, 0),
+            ]);
+
+        // Incoming funds pay back what is owed first.
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), drawn.clone(), withdrew.clone()])
+            .when(AccountCommand::deposited(ByteArray32([3; 32]), 3, "USD".to_string(), 100))
+            .then_expect_events(vec![
+                AccountEvent::deposited(ByteArray32([3; 32]), 3, "USD".to_string(), 100).with_balance_after("USD", 100, 0),
+                AccountEvent::overdraft_repaid(ByteArray32([3; 32]), 3, "USD".to_string(), 70).with_balance_after("USD", 30, 0),
+            ]);
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), drawn.clone(), withdrew.clone()])
+            .when(AccountCommand::credit(ByteArray32([3; 32]), 3, "ACCT-0002".to_string(), "USD".to_string(), 20))
+            .then_expect_events(vec![
+                AccountEvent::credited(ByteArray32([3; 32]), 3, "ACCT-0002".to_string(), "USD".to_string(), 20).with_balance_after("USD", 20, 0),
+                AccountEvent::overdraft_repaid(ByteArray32([3; 32]), 3, "USD".to_string(), 20).with_balance_after("USD", 0, 0),
+            ]);
+
+        // Closing accounts pay from their balance only.
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), AccountEvent::close_requested(1, 1001)])
+            .when(AccountCommand::withdrew(ByteArray32([1; 32]), 2, "USD".to_string(), 51))
+            .then_expect_error_message("Account is being closed");
+
+        // Without a credit limit the balance cannot go below zero.
+        AccountTestFramework::with(BankAccountServices::new(Box::new(MockBankAccountServices::default())))
+            .given(vec![opened, deposited])
+            .when(AccountCommand::withdrew(ByteArray32([1; 32]), 1, "USD".to_string(), 51))
+            .then_expect_error_message("Insufficient funds");
+    }
+
+    #[test]
+    fn test_quarantine_unverified_deposits() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
+        let quarantine = QuarantineConfig { enabled: true, verified_sources: BTreeSet::from(["custody".to_string()]) };
+        let services = || BankAccountServices::new(Box::new(MockBankAccountServices::default()))
+            .with_quarantine(quarantine.clone());
+        let txid = ByteArray32([1; 32]);
+        let quarantined = AccountEvent::deposit_quarantined(txid, 1, "USD".to_string(), 100, "unknown-bank".to_string());
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone()])
+            .when(AccountCommand::deposited_from(txid, 1, "USD".to_string(), 100, "unknown-bank".to_string()))
+            .then_expect_events(vec![quarantined.clone().with_balance_after("USD", 0, 0)]);
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone()])
+            .when(AccountCommand::deposited_from(txid, 1, "USD".to_string(), 100, "custody".to_string()))
+            .then_expect_events(vec![AccountEvent::deposited(txid, 1, "USD".to_string(), 100).with_balance_after("USD", 100, 0)]);
+        // Naming no source does not get around the check.
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone()])
+            .when(AccountCommand::deposited(txid, 1, "USD".to_string(), 100))
+            .then_expect_events(vec![
+                AccountEvent::deposit_quarantined(txid, 1, "USD".to_string(), 100, String::new()).with_balance_after("USD", 0, 0),
+            ]);
+
+        // Held funds cannot be spent, nor can the account be closed over them.
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), quarantined.clone()])
+            .when(AccountCommand::withdrew(ByteArray32([2; 32]), 2, "USD".to_string(), 1))
+            .then_expect_error_message("Insufficient funds");
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), quarantined.clone()])
+            .when(AccountCommand::account_closed())
+            .then_expect_error_message("Account is not empty");
+        // The deposit is earmarked under its txid, out of reach of the earmark commands.
+        let earmark = quarantine_earmark(&txid);
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), quarantined.clone()])
+            .when(AccountCommand::release_earmark(ByteArray32([2; 32]), 2, earmark.clone()))
+            .then_expect_error_message(&format!("Earmark {} is reserved for a quarantined deposit", earmark));
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), AccountEvent::deposited(ByteArray32([2; 32]), 1, "USD".to_string(), 100)])
+            .when(AccountCommand::earmark(ByteArray32([3; 32]), 2, earmark.clone(), "USD".to_string(), 10, EarmarkPurpose::Quarantine))
+            .then_expect_error_message(&format!("Earmark {} is reserved for a quarantined deposit", earmark));
+
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), quarantined.clone()])
+            .when(AccountCommand::release_quarantine(txid, 2))
+            .then_expect_events(vec![
+                AccountEvent::quarantine_released(txid, 2, "USD".to_string(), 100).with_balance_after("USD", 100, 0),
+            ]);
+        let released = AccountEvent::quarantine_released(txid, 2, "USD".to_string(), 100);
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), quarantined.clone(), released])
+            .when(AccountCommand::release_quarantine(txid, 3))
+            .then_expect_error_message("No quarantined deposit under this transaction id");
+        AccountTestFramework::with(services())
+            .given(vec![opened, quarantined])
+            .when(AccountCommand::deposited_from(txid, 3, "USD".to_string(), 100, "unknown-bank".to_string()))
+            .then_expect_error_message("duplicate transaction, this transaction has already been processed at 1");
+    }
+
+    #[test]
+    fn test_command_policy() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
+        let deposited = AccountEvent::deposited(ByteArray32([1; 32]), 1, "USD".to_string(), 100);
+        let policy = CommandPolicyConfig { max_amounts: HashMap::from([("withdraw".to_string(), 50)]), max_batch_len: 2, ..CommandPolicyConfig::default() };
+        let services = || BankAccountServices::new(Box::new(MockBankAccountServices::default()))
+            .with_command_policy(policy.clone());
+        let withdraw = |id: u8, amount| AccountCommand::withdrew(ByteArray32([id; 32]), 2, "USD".to_string(), amount);
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone()])
+            .when(withdraw(2, 50))
+            .then_expect_events(vec![AccountEvent::withdrew(ByteArray32([2; 32]), 2, "USD".to_string(), 50).with_balance_after("USD", 50, 0)]);
+        // Whichever route or saga sent it.
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone()])
+            .when(withdraw(2, 60))
+            .then_expect_error_message("Policy violation: Amount 60 of withdraw exceeds the limit of 50");
+        AccountTestFramework::with(services())
+            .given(vec![opened, deposited])
+            .when(AccountCommand::Batch(vec![withdraw(2, 1), withdraw(3, 1), withdraw(4, 1)]))
+            .then_expect_error_message("Policy violation: Batch of 3 exceeds the limit of 2");
+    }
+
+    #[test]
+    fn test_earmarked_funds_cannot_be_spent() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
+        let deposited = AccountEvent::deposited(ByteArray32([1; 32]), 1, "USD".to_string(), 100);
+        let services = || BankAccountServices::new(Box::new(MockBankAccountServices::default()));
+        let (earmark, release) = (ByteArray32([2; 32]), ByteArray32([3; 32]));
+        let earmarked = AccountEvent::funds_earmarked(earmark, 2, "aml-1".to_string(), "USD".to_string(), 60, EarmarkPurpose::Compliance);
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone()])
+            .when(AccountCommand::earmark(earmark, 2, "aml-1".to_string(), "USD".to_string(), 60, EarmarkPurpose::Compliance))
+            .then_expect_events(vec![earmarked.clone().with_balance_after("USD", 40, 0)]);
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone()])
+            .when(AccountCommand::earmark(earmark, 2, "aml-1".to_string(), "USD".to_string(), 160, EarmarkPurpose::Compliance))
+            .then_expect_error_message("Insufficient funds");
+
+        // Earmarked funds can be neither spent nor locked.
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), earmarked.clone()])
+            .when(AccountCommand::withdrew(ByteArray32([4; 32]), 3, "USD".to_string(), 50))
+            .then_expect_error_message("Insufficient funds");
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), earmarked.clone()])
+            .when(AccountCommand::lock_funds(ByteArray32([4; 32]), 3, "USD".to_string(), 50))
+            .then_expect_error_message("Insufficient funds");
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), earmarked.clone()])
+            .when(AccountCommand::earmark(ByteArray32([4; 32]), 3, "aml-1".to_string(), "USD".to_string(), 10, EarmarkPurpose::TermDeposit))
+            .then_expect_error_message("Earmark aml-1 already exists");
+
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), earmarked.clone()])
+            .when(AccountCommand::release_earmark(release, 3, "aml-1".to_string()))
+            .then_expect_events(vec![
+                AccountEvent::earmark_released(release, 3, "aml-1".to_string(), "USD".to_string(), 60).with_balance_after("USD", 100, 0),
+            ]);
+        let released = AccountEvent::earmark_released(release, 3, "aml-1".to_string(), "USD".to_string(), 60);
+        AccountTestFramework::with(services())
+            .given(vec![opened, deposited, earmarked, released])
+            .when(AccountCommand::release_earmark(ByteArray32([4; 32]), 4, "aml-1".to_string()))
+            .then_expect_error_message("Earmark aml-1 not found");
+    }
+
+    #[test]
+    fn test_convert_asset() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
+        let deposited = AccountEvent::deposited(ByteArray32([1; 32]), 1, "OLD".to_string(), 1500);
+        let locked = AccountEvent::funds_locked(ByteArray32([2; 32]), 2, "OLD".to_string(), 500);
+        let ratio = ConversionRatio { numerator: 2, denominator: 1 };
+        let services = || {
+            let services = MockBankAccountServices::default();
+            services.set_migration("OLD", "NEW", ratio);
+            BankAccountServices::new(Box::new(services))
+        };
+        let migration = ByteArray32([3; 32]);
+        let convert = |from: &str| AccountCommand::convert_asset(migration, 3, from.to_string(), "NEW".to_string(), ratio);
+        // Locked funds convert along, the snapshot covers the new asset.
+        let converted = AccountEvent::asset_converted(migration, 3, "OLD".to_string(), 1000, "NEW".to_string(), 2000, ratio);
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), locked.clone()])
+            .when(convert("OLD"))
+            .then_expect_events(vec![converted.clone().with_balance_after("NEW", 2000, 1000)]);
+        // Disabled accounts convert all the same.
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone(), AccountEvent::account_disabled()])
+            .when(convert("OLD"))
+            .then_expect_events(vec![
+                AccountEvent::asset_converted(migration, 3, "OLD".to_string(), 1500, "NEW".to_string(), 3000, ratio).with_balance_after("NEW", 3000, 0),
+            ]);
+
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone()])
+            .when(convert("EUR"))
+            .then_expect_error_message("Asset EUR is not held by the account");
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone()])
+            .when(convert("NEW"))
+            .then_expect_error_message("Invalid transaction");
+        // Only along the migration recorded on the asset.
+        let other = ConversionRatio { numerator: 3, denominator: 1 };
+        AccountTestFramework::with(services())
+            .given(vec![opened.clone(), deposited.clone()])
+            .when(AccountCommand::convert_asset(migration, 3, "OLD".to_string(), "NEW".to_string(), other))
+            .then_expect_error_message("Asset OLD is not migrated into this asset at this ratio");
+        AccountTestFramework::with(BankAccountServices::new(Box::new(MockBankAccountServices::default())))
+            .given(vec![opened.clone(), deposited.clone()])
+            .when(convert("OLD"))
+            .then_expect_error_message("Asset OLD is not migrated into this asset at this ratio");
+        // The txid is not spent, funds arriving late are converted under it again.
+        let late = AccountEvent::deposited(ByteArray32([4; 32]), 4, "OLD".to_string(), 10);
+        AccountTestFramework::with(services())
+            .given(vec![opened, deposited, locked, converted, late])
+            .when(convert("OLD"))
+            .then_expect_events(vec![
+                AccountEvent::asset_converted(migration, 3, "OLD".to_string(), 10, "NEW".to_string(), 20, ratio).with_balance_after("NEW", 2020, 1000),
+            ]);
+    }
+
+    #[test]
+    fn test_batch_runs_commands_in_order() {
+        let opened = AccountEvent::account_opened("ACCT-0001".to_string());
+        let (deposit, withdrawal) = (ByteArray32([1; 32]), ByteArray32([2; 32]));
+        let commands = vec![
+            AccountCommand::deposited(deposit, 1, "USD".to_string(), 100),
+            AccountCommand::withdrew(withdrawal, 2, "USD".to_string(), 30),
+        ];
+        AccountTestFramework::with(BankAccountServices::new(Box::new(MockBankAccountServices::default())))
+            .given(vec![opened.clone()])
+            .when(AccountCommand::Batch(commands.clone()))
+            .then_expect_events(vec![
+                AccountEvent::deposited(deposit, 1, "USD".to_string(), 100).with_balance_after("USD", 100, 0),
+                AccountEvent::withdrew(withdrawal, 2, "USD".to_string(), 30).with_balance_after("USD", 70, 0),
+            ]);
+
+        // Later commands are checked against the ones before them, skipping those rejected.
+        let mut commands = commands;
+        commands.insert(1, AccountCommand::withdrew(ByteArray32([3; 32]), 2, "USD".to_string(), 150));
+        commands.push(AccountCommand::Batch(vec![]));
+        let result = AccountTestFramework::with(BankAccountServices::new(Box::new(MockBankAccountServices::default())))
+            .given(vec![opened])
+            .when(AccountCommand::Batch(commands))
+            .inspect_result();
+        match result {
+            Err(AccountError::BatchRejected(rejected)) => assert_eq!(
+                rejected.iter().map(|(index, err)| (*index, err.to_string())).collect::<Vec<_>>(),
+                vec![(1, "Insufficient funds".to_string()), (3, AccountError::InvalidTransaction.to_string())],
+            ),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    struct FixedOverdraft(u64);
+
+    #[async_trait]
+    impl OverdraftPolicy for FixedOverdraft {
+        async fn credit_limit(&self, _account_id: &str, _asset: &str) -> u64 {
+            self.0
+        }
     }
 
     pub struct MockBankAccountServices {
         atm_withdrawal_response: Mutex<Option<Result<(), AtmError>>>,
         validate_check_response: Mutex<Option<Result<(), CheckingError>>>,
+        unregistered_assets: Mutex<Vec<String>>,
+        migrations: Mutex<Vec<(String, AssetMigration)>>,
     }
 
     impl Default for MockBankAccountServices {
@@ -633,6 +2169,8 @@ mod aggregate_tests {
             Self {
                 atm_withdrawal_response: Mutex::new(None),
                 validate_check_response: Mutex::new(None),
+                unregistered_assets: Mutex::new(vec![]),
+                migrations: Mutex::new(vec![]),
             }
         }
     }
@@ -644,12 +2182,19 @@ mod aggregate_tests {
         fn set_validate_check_response(&self, response: Result<(), CheckingError>) {
             *self.validate_check_response.lock().unwrap() = Some(response);
         }
+        fn set_unregistered_asset(&self, asset: &str) {
+            self.unregistered_assets.lock().unwrap().push(asset.to_string());
+        }
+        fn set_migration(&self, asset: &str, to: &str, ratio: ConversionRatio) {
+            let migration = AssetMigration { to: to.to_string(), ratio, completed: false };
+            self.migrations.lock().unwrap().push((asset.to_string(), migration));
+        }
     }
 
     #[async_trait]
     impl BankAccountApi for MockBankAccountServices {
         async fn atm_withdrawal(&self, _atm_id: &str, _amount: f64) -> Result<(), AtmError> {
-            self.atm_withdrawal_response.lock().unwrap().take().unwrap()
+            self.atm_withdrawal_response.lock().unwrap().take().unwrap_or(Ok(()))
         }
 
         async fn validate_check(
@@ -657,7 +2202,18 @@ mod aggregate_tests {
             _account_id: &str,
             _check_number: &str,
         ) -> Result<(), CheckingError> {
-            self.validate_check_response.lock().unwrap().take().unwrap()
+            self.validate_check_response.lock().unwrap().take().unwrap_or(Ok(()))
+        }
+
+        async fn validate_asset(&self, asset: &str) -> Result<(), AssetValidationError> {
+            if self.unregistered_assets.lock().unwrap().iter().any(|a| a == asset) {
+                return Err(AssetValidationError::NotRegistered);
+            }
+            Ok(())
+        }
+
+        async fn asset_migration(&self, asset: &str) -> Option<AssetMigration> {
+            self.migrations.lock().unwrap().iter().find(|(a, _)| a == asset).map(|(_, migration)| migration.clone())
         }
     }
 }
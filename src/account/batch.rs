@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use cqrs_es::AggregateError;
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::account::aggregate::Account;
+use crate::account::commands::AccountCommand;
+use crate::account::events::AccountError;
+use crate::metrics::ErrorMetrics;
+use crate::sealing::SealedCqrs;
+use crate::signals::Signals;
+
+// Commands a single request may carry unless configured otherwise, see
+// `crate::config::CommandPolicyConfig`.
+pub const MAX_BATCH_SIZE: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct CommandOutcome {
+    // Position of the command in the request.
+    pub index: usize,
+    pub applied: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl CommandOutcome {
+    pub fn applied(index: usize) -> Self {
+        CommandOutcome { index, applied: true, error: None }
+    }
+
+    pub fn rejected(index: usize, error: impl ToString) -> Self {
+        CommandOutcome { index, applied: false, error: Some(error.to_string()) }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BatchOutcome {
+    pub applied: usize,
+    pub rejected: usize,
+    // One per command, in the order they were sent.
+    pub results: Vec<CommandOutcome>,
+}
+
+impl BatchOutcome {
+    pub fn new(mut results: Vec<CommandOutcome>) -> Self {
+        results.sort_by_key(|outcome| outcome.index);
+        let applied = results.iter().filter(|outcome| outcome.applied).count();
+        BatchOutcome { applied, rejected: results.len() - applied, results }
+    }
+}
+
+// Executes the commands of one account in order, each with its position in the request.
+// They run as one `AccountCommand::Batch`, so the account is loaded once and their events
+// are committed together. The commands the account rejects are reported and the rest run
+// again without them, which only fails anew when the account changed in between.
+pub async fn execute_batch(
+    cqrs: &SealedCqrs<Account>,
+    error_metrics: &ErrorMetrics,
+    signals: &Signals,
+    account_id: &str,
+    mut commands: Vec<(usize, AccountCommand)>,
+    metadata: HashMap<String, String>,
+) -> Vec<CommandOutcome> {
+    let mut outcomes = Vec::with_capacity(commands.len());
+    while !commands.is_empty() {
+        let batch = AccountCommand::Batch(commands.iter().map(|(_, command)| command.clone()).collect());
+        match cqrs.execute_with_metadata(account_id, batch, metadata.clone()).await {
+            Ok(()) => {
+                outcomes.extend(commands.iter().map(|(index, _)| CommandOutcome::applied(*index)));
+                break;
+            }
+            Err(AggregateError::UserError(AccountError::BatchRejected(rejected))) => {
+                // Positions in this run, in ascending order.
+                for (position, error) in rejected.into_iter().rev() {
+                    let (index, _) = commands.remove(position);
+                    outcomes.push(CommandOutcome::rejected(index, &error));
+                    let error = AggregateError::UserError(error);
+                    error_metrics.record::<Account>(&error);
+                    signals.rejected(account_id, &error).await;
+                }
+            }
+            Err(err) => {
+                error_metrics.record::<Account>(&err);
+                signals.rejected(account_id, &err).await;
+                tracing::error!("Error: {:#?}\n", err);
+                outcomes.extend(commands.iter().map(|(index, _)| CommandOutcome::rejected(*index, &err)));
+                break;
+            }
+        }
+    }
+    outcomes
+}
@@ -0,0 +1,434 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use cqrs_es::persist::ViewRepository;
+use cqrs_es::AggregateError;
+use futures::StreamExt;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use sqlx::{Pool, Postgres, Row};
+use crate::account::aggregate::Account;
+use crate::account::commands::AccountCommand;
+use crate::account::events::AccountError;
+use crate::account::queries::AccountView;
+use crate::config::BulkConfig;
+use crate::metrics::ErrorMetrics;
+use crate::sealing::{SealedCqrs, SealedViewRepository};
+use crate::util::clock;
+use crate::util::metadata::{CORRELATION_ID_KEY, INITIATOR_KEY};
+
+// Failures kept in full per job, the rest are only counted.
+const MAX_FAILURES: usize = 100;
+const MAX_FINISHED_JOBS: usize = 100;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum BulkError {
+    #[error("The filter needs account ids, a tenant or a tag")]
+    EmptyFilter,
+    #[error("Bulk job {0} is still running")]
+    JobRunning(String),
+    #[error("Bulk job {0} not found")]
+    NotFound(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum BulkAction {
+    Disable,
+    Enable,
+}
+
+impl BulkAction {
+    fn command(self) -> AccountCommand {
+        match self {
+            BulkAction::Disable => AccountCommand::account_disabled(),
+            BulkAction::Enable => AccountCommand::account_enabled(),
+        }
+    }
+
+    // Accounts already in the target state refuse the command, they are skipped
+    // rather than failed so a job can be rerun after an interruption.
+    fn is_noop(self, err: &AggregateError<AccountError>) -> bool {
+        matches!(
+            (self, err),
+            (BulkAction::Disable, AggregateError::UserError(AccountError::AccountNotInService))
+                | (BulkAction::Enable, AggregateError::UserError(AccountError::AccountNotDisabled))
+        )
+    }
+}
+
+// Selects the open accounts matching every given criterion. One of them is required,
+// so a job never sweeps all accounts by accident.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountFilter {
+    pub account_ids: Vec<String>,
+    // Account id prefix, the way tenants are told apart elsewhere.
+    pub tenant: Option<String>,
+    pub tag: Option<String>,
+}
+
+impl AccountFilter {
+    fn validate(&self) -> Result<(), BulkError> {
+        let tenant = self.tenant.as_deref().unwrap_or_default();
+        match self.account_ids.is_empty() && tenant.is_empty() && self.tag.is_none() {
+            true => Err(BulkError::EmptyFilter),
+            false => Ok(()),
+        }
+    }
+
+    fn matches(&self, account_id: &str, view: &AccountView) -> bool {
+        view.is_open()
+            && self.tenant.as_ref().is_none_or(|tenant| account_id.starts_with(tenant.as_str()))
+            && self.tag.as_ref().is_none_or(|tag| view.has_tag(tag))
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BulkRequest {
+    pub action: BulkAction,
+    pub filter: AccountFilter,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+pub enum JobStatus {
+    Running,
+    Completed,
+    Cancelled,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct BulkFailure {
+    pub account_id: String,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BulkJob {
+    pub job_id: String,
+    pub action: BulkAction,
+    pub filter: AccountFilter,
+    pub initiator: String,
+    pub status: JobStatus,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    // Accounts looked at so far, and how many of them matched the filter.
+    pub scanned: u64,
+    pub matched: u64,
+    pub succeeded: u64,
+    // Matched, but already in the target state.
+    pub skipped: u64,
+    pub failed: u64,
+    pub failures: Vec<BulkFailure>,
+    // Why the job stopped early, when it failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+enum Outcome {
+    Cancelled,
+    NotMatched,
+    Succeeded,
+    Skipped,
+    Failed(BulkFailure),
+}
+
+struct Entry {
+    job: BulkJob,
+    cancel: Arc<AtomicBool>,
+}
+
+// Disables or enables a filtered set of accounts in a background job, for incident
+// response. One job runs at a time, at most `BulkConfig::concurrency` commands at once,
+// and its progress is kept in memory until `MAX_FINISHED_JOBS` later jobs finished.
+// Every command carries the initiator of the job and the job id as correlation id.
+#[derive(Clone)]
+pub struct BulkOperations {
+    account_cqrs: Arc<SealedCqrs<Account>>,
+    account_query: Arc<SealedViewRepository<AccountView, Account>>,
+    pool: Pool<Postgres>,
+    error_metrics: ErrorMetrics,
+    config: BulkConfig,
+    jobs: Arc<Mutex<BTreeMap<String, Entry>>>,
+}
+
+impl BulkOperations {
+    pub fn new(
+        account_cqrs: Arc<SealedCqrs<Account>>,
+        account_query: Arc<SealedViewRepository<AccountView, Account>>,
+        pool: Pool<Postgres>,
+        error_metrics: ErrorMetrics,
+        config: BulkConfig,
+    ) -> Self {
+        BulkOperations { account_cqrs, account_query, pool, error_metrics, config, jobs: Default::default() }
+    }
+
+    pub fn start(&self, request: BulkRequest, mut metadata: HashMap<String, String>) -> Result<BulkJob, BulkError> {
+        request.filter.validate()?;
+        let mut jobs = self.jobs.lock().expect("bulk jobs poisoned");
+        if let Some(running) = jobs.values().find(|entry| entry.job.status == JobStatus::Running) {
+            return Err(BulkError::JobRunning(running.job.job_id.clone()));
+        }
+        while jobs.len() >= MAX_FINISHED_JOBS {
+            let oldest = jobs.values().min_by_key(|entry| entry.job.started_at).map(|entry| entry.job.job_id.clone());
+            jobs.remove(&oldest.expect("jobs are not empty"));
+        }
+        let job_id = hex::encode(rand::thread_rng().gen::<[u8; 8]>());
+        metadata.insert(CORRELATION_ID_KEY.to_string(), job_id.clone());
+        let job = BulkJob {
+            job_id: job_id.clone(),
+            action: request.action,
+            filter: request.filter.clone(),
+            initiator: metadata.get(INITIATOR_KEY).cloned().unwrap_or_default(),
+            status: JobStatus::Running,
+            started_at: clock::now(),
+            finished_at: None,
+            scanned: 0,
+            matched: 0,
+            succeeded: 0,
+            skipped: 0,
+            failed: 0,
+            failures: vec![],
+            error: None,
+        };
+        let cancel = Arc::new(AtomicBool::new(false));
+        jobs.insert(job_id.clone(), Entry { job: job.clone(), cancel: cancel.clone() });
+        tracing::info!("Bulk job {} started by {}: {:?} {:?}", job_id, job.initiator, job.action, job.filter);
+        let operations = self.clone();
+        tokio::spawn(async move { operations.run(job_id, request, metadata, cancel).await });
+        Ok(job)
+    }
+
+    pub fn job(&self, job_id: &str) -> Option<BulkJob> {
+        self.jobs.lock().expect("bulk jobs poisoned").get(job_id).map(|entry| entry.job.clone())
+    }
+
+    // Newest first.
+    pub fn jobs(&self) -> Vec<BulkJob> {
+        let mut jobs: Vec<BulkJob> = self.jobs.lock().expect("bulk jobs poisoned").values().map(|entry| entry.job.clone()).collect();
+        jobs.sort_by_key(|job| std::cmp::Reverse(job.started_at));
+        jobs
+    }
+
+    // Commands already sent still complete; the job reports `Cancelled` once they did.
+    pub fn cancel(&self, job_id: &str) -> Result<BulkJob, BulkError> {
+        let jobs = self.jobs.lock().expect("bulk jobs poisoned");
+        let entry = jobs.get(job_id).ok_or_else(|| BulkError::NotFound(job_id.to_string()))?;
+        entry.cancel.store(true, Ordering::Relaxed);
+        Ok(entry.job.clone())
+    }
+
+    async fn run(&self, job_id: String, request: BulkRequest, metadata: HashMap<String, String>, cancel: Arc<AtomicBool>) {
+        let result = self.process(&job_id, &request, &metadata, &cancel).await;
+        let job = self.update(&job_id, |job| {
+            job.finished_at = Some(clock::now());
+            job.status = match &result {
+                Ok(()) if cancel.load(Ordering::Relaxed) => JobStatus::Cancelled,
+                Ok(()) => JobStatus::Completed,
+                Err(err) => {
+                    job.error = Some(err.to_string());
+                    JobStatus::Failed
+                }
+            };
+        });
+        tracing::info!(
+            "Bulk job {} {:?}: {} matched, {} succeeded, {} skipped, {} failed",
+            job_id, job.status, job.matched, job.succeeded, job.skipped, job.failed,
+        );
+    }
+
+    async fn process(
+        &self,
+        job_id: &str,
+        request: &BulkRequest,
+        metadata: &HashMap<String, String>,
+        cancel: &AtomicBool,
+    ) -> Result<(), sqlx::Error> {
+        let page_size = self.config.page_size.max(1);
+        let mut after = String::new();
+        loop {
+            if cancel.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            let page = self.candidates(&request.filter, &after, page_size).await?;
+            let Some(last) = page.last().cloned() else {
+                return Ok(());
+            };
+            let full = page.len() == page_size;
+            let outcomes: Vec<Outcome> = futures::stream::iter(page)
+                .map(|account_id| async move { self.apply(&account_id, request, metadata, cancel).await })
+                .buffer_unordered(self.config.concurrency.max(1))
+                .collect()
+                .await;
+            let job = self.update(job_id, |job| {
+                for outcome in outcomes {
+                    match outcome {
+                        Outcome::Cancelled => continue,
+                        Outcome::NotMatched => {}
+                        Outcome::Succeeded => job.succeeded += 1,
+                        Outcome::Skipped => job.skipped += 1,
+                        Outcome::Failed(failure) => {
+                            job.failed += 1;
+                            if job.failures.len() < MAX_FAILURES {
+                                job.failures.push(failure);
+                            }
+                        }
+                    }
+                    job.scanned += 1;
+                }
+                job.matched = job.succeeded + job.skipped + job.failed;
+            });
+            tracing::info!("Bulk job {}: {} scanned, {} matched", job_id, job.scanned, job.matched);
+            if !full {
+                return Ok(());
+            }
+            after = last;
+        }
+    }
+
+    // The next account ids after `after`, in id order.
+    async fn candidates(&self, filter: &AccountFilter, after: &str, page_size: usize) -> Result<Vec<String>, sqlx::Error> {
+        if !filter.account_ids.is_empty() {
+            let mut ids: Vec<String> = filter.account_ids.iter().filter(|id| id.as_str() > after).cloned().collect();
+            ids.sort();
+            ids.dedup();
+            ids.truncate(page_size);
+            return Ok(ids);
+        }
+        sqlx::query("SELECT view_id FROM account_query WHERE view_id > $1 AND starts_with(view_id, $2) ORDER BY view_id LIMIT $3")
+            .bind(after)
+            .bind(filter.tenant.as_deref().unwrap_or_default())
+            .bind(page_size as i64)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|row| row.try_get("view_id"))
+            .collect()
+    }
+
+    async fn apply(&self, account_id: &str, request: &BulkRequest, metadata: &HashMap<String, String>, cancel: &AtomicBool) -> Outcome {
+        if cancel.load(Ordering::Relaxed) {
+            return Outcome::Cancelled;
+        }
+        let failed = |error: String| Outcome::Failed(BulkFailure { account_id: account_id.to_string(), error });
+        match self.account_query.load(account_id).await {
+            Ok(Some(view)) if request.filter.matches(account_id, &view) => {}
+            Ok(_) => return Outcome::NotMatched,
+            Err(err) => return failed(err.to_string()),
+        }
+        match self.account_cqrs.execute_with_metadata(account_id, request.action.command(), metadata.clone()).await {
+            Ok(()) => Outcome::Succeeded,
+            Err(err) if request.action.is_noop(&err) => Outcome::Skipped,
+            Err(err) => {
+                self.error_metrics.record::<Account>(&err);
+                failed(err.to_string())
+            }
+        }
+    }
+
+    fn update(&self, job_id: &str, f: impl FnOnce(&mut BulkJob)) -> BulkJob {
+        let mut jobs = self.jobs.lock().expect("bulk jobs poisoned");
+        let entry = jobs.get_mut(job_id).expect("running jobs are never pruned");
+        f(&mut entry.job);
+        entry.job.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeSet, HashMap};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use cqrs_es::persist::ViewRepository;
+    use crate::account::bulk::{AccountFilter, BulkAction, BulkError, BulkJob, BulkOperations, BulkRequest, JobStatus};
+    use crate::account::commands::AccountCommand;
+    use crate::account::queries::AccountQuery;
+    use crate::aggregate_cache::AggregateCache;
+    use crate::config::{AggregateCacheConfig, BulkConfig, ErrorAlertConfig, ShardConfig};
+    use crate::metrics::{AggregateCacheMetrics, ErrorMetrics};
+    use crate::commit_hooks::HookedEventRepository;
+    use crate::sealing::{sealed_snapshot_cqrs, SealedViewRepository};
+    use crate::services::{BankAccountServices, HappyPathBankAccountServices};
+    use crate::util::metadata::INITIATOR_KEY;
+    use crate::util::test_db::{delete_prefixed, random_suffix, test_pool};
+
+    async fn wait(operations: &BulkOperations, job: BulkJob) -> BulkJob {
+        for _ in 0..100 {
+            let job = operations.job(&job.job_id).unwrap();
+            if job.status != JobStatus::Running {
+                return job;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("bulk job {} did not finish", job.job_id);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_disable_and_enable() {
+        let pool = test_pool();
+        let account_query = Arc::new(SealedViewRepository::new("account_query", pool.clone(), None));
+        let account_cqrs = Arc::new(sealed_snapshot_cqrs(
+            HookedEventRepository::new(pool.clone(), vec![]),
+            vec![Box::new(AccountQuery::new(account_query.clone()))],
+            100,
+            BankAccountServices::new(Box::new(HappyPathBankAccountServices)),
+            None,
+            AggregateCache::new(&AggregateCacheConfig::default(), AggregateCacheMetrics::default()),
+            &ShardConfig::default(),
+        ));
+        let tenant = format!("BULK-{}-", random_suffix());
+        let ids: Vec<String> = (1..=3).map(|n| format!("{}{}", tenant, n)).collect();
+        for (n, id) in ids.iter().enumerate() {
+            account_cqrs.execute(id, AccountCommand::account_opened(id.clone())).await.unwrap();
+            if n < 2 {
+                let tags: BTreeSet<String> = ["incident".to_string()].into_iter().collect();
+                account_cqrs.execute(id, AccountCommand::tags_set(tags)).await.unwrap();
+            }
+        }
+        let config = BulkConfig { concurrency: 2, page_size: 1 };
+        let operations = BulkOperations::new(account_cqrs, account_query.clone(), pool.clone(), ErrorMetrics::new(ErrorAlertConfig::default()), config);
+        let metadata = HashMap::from([(INITIATOR_KEY.to_string(), "api-key:0".to_string())]);
+        let request = |action, filter| BulkRequest { action, filter };
+
+        let empty = operations.start(request(BulkAction::Disable, AccountFilter::default()), metadata.clone());
+        assert_eq!(empty.unwrap_err(), BulkError::EmptyFilter);
+
+        let tagged = AccountFilter { tenant: Some(tenant.clone()), tag: Some("incident".to_string()), ..Default::default() };
+        let job = operations.start(request(BulkAction::Disable, tagged.clone()), metadata.clone()).unwrap();
+        let job = wait(&operations, job).await;
+        assert_eq!(job.status, JobStatus::Completed);
+        assert_eq!((job.scanned, job.matched, job.succeeded, job.skipped, job.failed), (3, 2, 2, 0, 0));
+        assert_eq!(job.initiator, "api-key:0");
+        let disabled = |id: &str| {
+            let account_query = account_query.clone();
+            let id = id.to_string();
+            async move { serde_json::to_value(account_query.load(&id).await.unwrap().unwrap()).unwrap()["is_disabled"].clone() }
+        };
+        assert_eq!(disabled(&ids[0]).await, true);
+        assert_eq!(disabled(&ids[2]).await, false);
+
+        // Rerunning skips the accounts already disabled.
+        let job = operations.start(request(BulkAction::Disable, tagged), metadata.clone()).unwrap();
+        let job = wait(&operations, job).await;
+        assert_eq!((job.matched, job.succeeded, job.skipped), (2, 0, 2));
+
+        let listed = AccountFilter { account_ids: vec![ids[2].clone(), ids[0].clone(), format!("{}9", tenant)], ..Default::default() };
+        let job = operations.start(request(BulkAction::Enable, listed), metadata).unwrap();
+        let job = wait(&operations, job).await;
+        assert_eq!((job.scanned, job.matched, job.succeeded, job.skipped), (3, 2, 1, 1));
+        assert_eq!(disabled(&ids[0]).await, false);
+        assert_eq!(operations.jobs().len(), 3);
+        assert_eq!(operations.cancel("unknown").unwrap_err(), BulkError::NotFound("unknown".to_string()));
+
+        for table in ["events", "snapshots"] {
+            sqlx::query(&format!("DELETE FROM {} WHERE aggregate_type = 'account' AND starts_with(aggregate_id, $1)", table))
+                .bind(&tenant)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        delete_prefixed(&pool, "account_query", "view_id", &tenant).await;
+    }
+}
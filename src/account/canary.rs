@@ -0,0 +1,181 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use prometheus::{IntCounterVec, Opts, Registry};
+use crate::account::aggregate::Account;
+use crate::account::commands::{AccountCommand, LifecycleCommand};
+use crate::account::events::{AccountError, AccountEvent};
+use crate::config::{CanaryConfig, CanaryMode};
+use crate::services::BankAccountServices;
+use crate::util::types::ByteArray32;
+
+// How an account decides on a command, the stable logic or one being rolled out. The
+// services it is handed are read only, so running two of them on the same command has
+// no effect outside of the events they return.
+#[async_trait]
+pub trait AccountLogic: Send + Sync {
+    // Labels the implementation in logs and metrics.
+    fn version(&self) -> &'static str;
+
+    async fn handle(&self, account: &Account, command: AccountCommand, services: &BankAccountServices) -> Result<Vec<AccountEvent>, AccountError>;
+}
+
+// `Account::decide`, what every account runs outside of the canary.
+pub struct StableLogic;
+
+#[async_trait]
+impl AccountLogic for StableLogic {
+    fn version(&self) -> &'static str {
+        "stable"
+    }
+
+    async fn handle(&self, account: &Account, command: AccountCommand, services: &BankAccountServices) -> Result<Vec<AccountEvent>, AccountError> {
+        account.decide(command, services).await
+    }
+}
+
+// The implementation under rollout. A risky change to `Account::decide` goes into an
+// `AccountLogic` of its own and is returned here, until then the canary runs the stable
+// logic against itself.
+pub fn next_logic() -> Arc<dyn AccountLogic> {
+    Arc::new(StableLogic)
+}
+
+// Commands by the implementation and how the canary treated them: `live` when the next
+// implementation decided, `match` or `mismatch` when it shadowed the stable one.
+#[derive(Clone)]
+pub struct CanaryMetrics {
+    commands: IntCounterVec,
+}
+
+impl Default for CanaryMetrics {
+    fn default() -> Self {
+        let commands = IntCounterVec::new(
+            Opts::new("account_canary_commands_total", "Account commands routed to the canary"),
+            &["version", "outcome"],
+        ).expect("invalid canary counter");
+        CanaryMetrics { commands }
+    }
+}
+
+impl CanaryMetrics {
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.commands.clone()))
+    }
+
+    fn observe(&self, version: &str, outcome: &str) {
+        self.commands.with_label_values(&[version, outcome]).inc();
+    }
+}
+
+// Routes `percent` of the accounts, always the same ones, to the next implementation.
+// In shadow mode the stable logic still decides and the next one runs alongside it,
+// its differing outcomes are logged; in live mode the next one decides alone.
+#[derive(Clone)]
+pub struct Canary {
+    next: Arc<dyn AccountLogic>,
+    percent: u8,
+    mode: CanaryMode,
+    metrics: CanaryMetrics,
+}
+
+impl Canary {
+    // None while no account is routed.
+    pub fn new(config: &CanaryConfig, next: Arc<dyn AccountLogic>, metrics: CanaryMetrics) -> Option<Self> {
+        (config.percent > 0).then(|| Canary { next, percent: config.percent.min(100), mode: config.mode, metrics })
+    }
+
+    // Stable across nodes and restarts, so an account sees one implementation throughout.
+    pub fn routes(&self, account_id: &str) -> bool {
+        let hash = ByteArray32::derive("canary", account_id);
+        u16::from_be_bytes([hash.0[0], hash.0[1]]) % 100 < self.percent as u16
+    }
+
+    pub async fn handle(&self, account: &Account, command: AccountCommand, services: &BankAccountServices) -> Result<Vec<AccountEvent>, AccountError> {
+        // Accounts not opened yet are known by the command opening them.
+        let account_id = match (account.id(), &command) {
+            (Some(account_id), _) => Some(account_id.to_string()),
+            (None, AccountCommand::Lifecycle(LifecycleCommand::Open { account_id })) => Some(account_id.clone()),
+            (None, _) => None,
+        };
+        let Some(account_id) = account_id.filter(|account_id| self.routes(account_id)) else {
+            return account.decide(command, services).await;
+        };
+        let version = self.next.version();
+        if self.mode == CanaryMode::Live {
+            self.metrics.observe(version, "live");
+            return self.next.handle(account, command, services).await;
+        }
+        let stable = account.decide(command.clone(), services).await;
+        let next = self.next.handle(account, command, services).await;
+        if same_outcome(&stable, &next) {
+            self.metrics.observe(version, "match");
+        } else {
+            self.metrics.observe(version, "mismatch");
+            tracing::warn!("Canary {} differs from the stable logic on {}: stable {:?}, next {:?}", version, account_id, stable, next);
+        }
+        stable
+    }
+}
+
+// Errors are told apart by what they report, they carry no equality of their own.
+fn same_outcome(stable: &Result<Vec<AccountEvent>, AccountError>, next: &Result<Vec<AccountEvent>, AccountError>) -> bool {
+    match (stable, next) {
+        (Ok(stable), Ok(next)) => stable == next,
+        (Err(stable), Err(next)) => format!("{:?}", stable) == format!("{:?}", next),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use async_trait::async_trait;
+    use crate::account::aggregate::Account;
+    use crate::account::canary::{AccountLogic, Canary, CanaryMetrics, StableLogic};
+    use crate::account::commands::AccountCommand;
+    use crate::account::events::{AccountError, AccountEvent};
+    use crate::config::{CanaryConfig, CanaryMode};
+    use crate::services::{BankAccountServices, HappyPathBankAccountServices};
+
+    // Rejects every command.
+    struct Broken;
+
+    #[async_trait]
+    impl AccountLogic for Broken {
+        fn version(&self) -> &'static str {
+            "broken"
+        }
+
+        async fn handle(&self, _account: &Account, _command: AccountCommand, _services: &BankAccountServices) -> Result<Vec<AccountEvent>, AccountError> {
+            Err(AccountError::InvalidTransaction)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_canary_routing() {
+        let services = BankAccountServices::new(Box::new(HappyPathBankAccountServices));
+        let config = |percent, mode| CanaryConfig { percent, mode };
+        assert!(Canary::new(&config(0, CanaryMode::Shadow), Arc::new(Broken), CanaryMetrics::default()).is_none());
+
+        let all = Canary::new(&config(100, CanaryMode::Shadow), Arc::new(Broken), CanaryMetrics::default()).unwrap();
+        let half = Canary::new(&config(50, CanaryMode::Shadow), Arc::new(StableLogic), CanaryMetrics::default()).unwrap();
+        let ids: Vec<String> = (0..1000).map(|i| format!("ACCT-{:04}", i)).collect();
+        let routed = ids.iter().filter(|id| half.routes(id)).count();
+        assert!((400..600).contains(&routed), "{} of 1000 routed", routed);
+        assert!(ids.iter().all(|id| all.routes(id)));
+        assert_eq!(ids.iter().filter(|id| half.routes(id)).count(), routed);
+
+        // In shadow mode the stable logic decides, the difference is only counted.
+        let open = AccountCommand::account_opened("ACCT-0001".to_string());
+        let events = all.handle(&Account::Uninitialized, open.clone(), &services).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(all.metrics.commands.with_label_values(&["broken", "mismatch"]).get(), 1);
+
+        let live = Canary::new(&config(100, CanaryMode::Live), Arc::new(Broken), CanaryMetrics::default()).unwrap();
+        assert!(matches!(live.handle(&Account::Uninitialized, open.clone(), &services).await, Err(AccountError::InvalidTransaction)));
+
+        let same = Canary::new(&config(100, CanaryMode::Shadow), Arc::new(StableLogic), CanaryMetrics::default()).unwrap();
+        same.handle(&Account::Uninitialized, open, &services).await.unwrap();
+        assert_eq!(same.metrics.commands.with_label_values(&["stable", "match"]).get(), 1);
+    }
+}
@@ -0,0 +1,97 @@
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use cqrs_es::persist::{PersistenceError, SerializedEvent};
+use cqrs_es::{Aggregate, AggregateError};
+use sqlx::{Pool, Postgres, Row, Transaction};
+use crate::background::{Background, Shutdown};
+use crate::account::aggregate::Account;
+use crate::account::commands::AccountCommand;
+use crate::account::events::{AccountError, AccountEvent, LifecycleEvent};
+use crate::commit_hooks::{persistence_error, CommitHook};
+use crate::config::AccountClosureConfig;
+use crate::sealing::SealedCqrs;
+use crate::util::clock;
+
+// Keeps `account_closure` in step with the accounts being closed, a row from the
+// request until the closure is finalized or aborted, written in the transaction of the
+// event, see `crate::commit_hooks`, so no closure goes unswept. The account views may
+// be sealed, so the sweep cannot look into them.
+pub struct ClosureHook;
+
+#[async_trait]
+impl CommitHook for ClosureHook {
+    async fn append(&self, tx: &mut Transaction<'_, Postgres>, events: &[SerializedEvent]) -> Result<(), PersistenceError> {
+        for event in events.iter().filter(|event| event.aggregate_type == Account::aggregate_type()) {
+            let payload = serde_json::from_value(event.payload.clone()).map_err(|e| PersistenceError::DeserializationError(Box::new(e)))?;
+            let query = match payload {
+                AccountEvent::Lifecycle(LifecycleEvent::CloseRequested { finalize_at, .. }) => {
+                    sqlx::query("INSERT INTO account_closure (account_id, finalize_at) VALUES ($1, $2) ON CONFLICT (account_id) DO UPDATE SET finalize_at = $2")
+                        .bind(&event.aggregate_id)
+                        .bind(finalize_at as i64)
+                }
+                AccountEvent::Lifecycle(LifecycleEvent::ClosureAborted { .. } | LifecycleEvent::Closed) => {
+                    sqlx::query("DELETE FROM account_closure WHERE account_id = $1").bind(&event.aggregate_id)
+                }
+                _ => continue,
+            };
+            query.execute(&mut **tx).await.map_err(persistence_error)?;
+        }
+        Ok(())
+    }
+}
+
+// Finalizes the closure of accounts whose grace period ran out: the account decides
+// whether it closes or goes back in service, see `LifecycleCommand::FinalizeClose`.
+// A row left behind by an account no longer closing is dropped.
+#[derive(Clone)]
+pub struct AccountClosures {
+    account_cqrs: Arc<SealedCqrs<Account>>,
+    pool: Pool<Postgres>,
+    config: AccountClosureConfig,
+}
+
+impl AccountClosures {
+    pub fn new(account_cqrs: Arc<SealedCqrs<Account>>, pool: Pool<Postgres>, config: AccountClosureConfig) -> Self {
+        AccountClosures { account_cqrs, pool, config }
+    }
+
+    pub fn spawn(self, background: &Background) {
+        background.spawn(|shutdown| async move { self.run(shutdown).await });
+    }
+
+    async fn run(&self, mut shutdown: Shutdown) {
+        loop {
+            if let Err(e) = self.sweep().await {
+                tracing::error!("Failed to sweep account closures: {}", e);
+            }
+            if !shutdown.pause(Duration::from_secs(self.config.sweep_interval_secs.max(1))).await {
+                break;
+            }
+        }
+    }
+
+    async fn sweep(&self) -> Result<(), sqlx::Error> {
+        let now = clock::now();
+        for account_id in due_closures(&self.pool, now).await? {
+            match self.account_cqrs.execute(&account_id, AccountCommand::close_finalized(now)).await {
+                Ok(()) => tracing::info!("Finalized the closure of account {}", account_id),
+                Err(AggregateError::UserError(AccountError::InvalidTransaction | AccountError::AccountNotFound)) => {
+                    sqlx::query("DELETE FROM account_closure WHERE account_id = $1").bind(&account_id).execute(&self.pool).await?;
+                }
+                Err(e) => tracing::error!("Failed to finalize the closure of account {}: {}", account_id, e),
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn due_closures(pool: &Pool<Postgres>, now: u64) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query("SELECT account_id FROM account_closure WHERE finalize_at <= $1")
+        .bind(now as i64)
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| row.try_get("account_id"))
+        .collect()
+}
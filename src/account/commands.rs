@@ -1,33 +1,66 @@
+use std::collections::BTreeSet;
+use chrono::NaiveDate;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::account::events::{EarmarkPurpose, TransactionLabels};
+use crate::asset::types::ConversionRatio;
 use crate::util::types::ByteArray32;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub enum AccountCommand {
     Lifecycle(LifecycleCommand),
     Transaction {
         timestamp: u64,
         txid: ByteArray32,
         command: TransactionCommand,
+        #[serde(flatten)]
+        labels: TransactionLabels,
     },
+    // Commands handled in order against the account as loaded once, and committed
+    // together. When any is rejected nothing is committed and the error names each
+    // rejected command by its position, see `crate::account::batch`.
+    #[schema(no_recursion)]
+    Batch(Vec<AccountCommand>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
 pub enum LifecycleCommand {
     Open { account_id: String },
     Disable,
     Enable,
     Close,
+    // Replaces the tags of the account, which select it for bulk operations.
+    SetTags { tags: BTreeSet<String> },
+    // Starts closing an account in service that may still hold funds: for the grace
+    // period it only lets funds out, see `TransactionCommand::allowed_while_closing`.
+    RequestClose { timestamp: u64 },
+    // Sent by `crate::account::closure` once the grace period ran out. Closes the account
+    // if it is empty and took no credits since the request, puts it back in service otherwise.
+    FinalizeClose { timestamp: u64 },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub enum TransactionCommand {
     Deposit {
         asset: String,
         amount: u64,
+        // The external party the funds come from, checked against the verified sources
+        // when quarantine is enabled. Without one the deposit is held as unverified.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        source: Option<String>,
+        // Books the deposit on an earlier business day than the one it is posted on, within
+        // the backdating window. Only taken from operators, see `value_dated_handler`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[schema(value_type = Option<String>, format = Date)]
+        value_date: Option<NaiveDate>,
     },
     Withdraw {
         asset: String,
         amount: u64,
+        // As for deposits.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[schema(value_type = Option<String>, format = Date)]
+        value_date: Option<NaiveDate>,
     },
     Debit {
         to_account: String,
@@ -59,6 +92,84 @@ pub enum TransactionCommand {
         receive_asset: String,
         receive_amount: u64,
     },
+    // Holds reserve funds under a name of the caller's choosing, for authorize/capture
+    // flows. Unlike locks they are captured or released in parts, each part under a txid
+    // of its own, and an account may carry any number of them.
+    PlaceHold {
+        hold: String,
+        asset: String,
+        amount: u64,
+    },
+    // Takes part of a hold out of the account, the caller credits `to_account`.
+    CaptureHold {
+        hold: String,
+        to_account: String,
+        amount: u64,
+    },
+    // Returns part of a hold to the available balance.
+    ReleaseHold {
+        hold: String,
+        amount: u64,
+    },
+    // Moves part of a hold into a lock under the txid, for a saga to settle funds held
+    // for it without them ever being spendable in between.
+    LockHeld {
+        hold: String,
+        amount: u64,
+    },
+    // Lets a quarantined deposit, sent under the txid of the deposit, into the balance
+    // once it has been checked.
+    ReleaseQuarantine,
+    // Sets funds of the balance aside under a name, for compliance holds, term deposits
+    // or funds quarantined after they reached the balance. They stay on the account but
+    // cannot be spent until the earmark is released, as a whole. Quarantined deposits are
+    // earmarked by the deposit itself, see `crate::account::aggregate::quarantine_earmark`.
+    Earmark {
+        earmark: String,
+        asset: String,
+        amount: u64,
+        purpose: EarmarkPurpose,
+    },
+    ReleaseEarmark {
+        earmark: String,
+    },
+    // Converts everything the account holds in `from_asset` into `to_asset`, its balance,
+    // locks, holds, quarantined and earmarked funds and what it owes alike. Sent by the
+    // asset migration to open and disabled accounts, see `crate::asset::migration`.
+    ConvertAsset {
+        from_asset: String,
+        to_asset: String,
+        ratio: ConversionRatio,
+    },
+}
+
+// Body of the admin route earmarking funds, which names the earmark in its path.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct EarmarkRequest {
+    pub asset: String,
+    pub amount: u64,
+    pub purpose: EarmarkPurpose,
+}
+
+impl TransactionCommand {
+    // What an account asked to be closed still takes: withdrawals, debits and the
+    // settling of what was under way. Credits are not turned away, they abort the closure
+    // instead. New locks, holds and earmarks would hold funds past the grace period.
+    pub fn allowed_while_closing(&self) -> bool {
+        !matches!(self, TransactionCommand::LockFunds { .. } | TransactionCommand::PlaceHold { .. } | TransactionCommand::Earmark { .. })
+    }
+
+    // What a saga runs to unwind or settle what it started. Taken on the day frozen at its
+    // cut-off too, a saga turned away there would burn its retries with the funds locked.
+    pub fn finishes_saga(&self) -> bool {
+        matches!(
+            self,
+            TransactionCommand::UnlockFunds
+                | TransactionCommand::ReverseDebit { .. }
+                | TransactionCommand::ReverseCredit { .. }
+                | TransactionCommand::Settle { .. }
+        )
+    }
 }
 
 impl AccountCommand {
@@ -78,11 +189,33 @@ impl AccountCommand {
         AccountCommand::Lifecycle(LifecycleCommand::Close)
     }
 
+    pub fn tags_set(tags: BTreeSet<String>) -> Self {
+        AccountCommand::Lifecycle(LifecycleCommand::SetTags { tags })
+    }
+
+    pub fn close_requested(timestamp: u64) -> Self {
+        AccountCommand::Lifecycle(LifecycleCommand::RequestClose { timestamp })
+    }
+
+    pub fn close_finalized(timestamp: u64) -> Self {
+        AccountCommand::Lifecycle(LifecycleCommand::FinalizeClose { timestamp })
+    }
+
     pub fn deposited(txid: ByteArray32, timestamp: u64, asset: String, amount: u64) -> Self {
         AccountCommand::Transaction {
             timestamp,
             txid,
-            command: TransactionCommand::Deposit { asset, amount },
+            command: TransactionCommand::Deposit { asset, amount, source: None, value_date: None },
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn deposited_from(txid: ByteArray32, timestamp: u64, asset: String, amount: u64, source: String) -> Self {
+        AccountCommand::Transaction {
+            timestamp,
+            txid,
+            command: TransactionCommand::Deposit { asset, amount, source: Some(source), value_date: None },
+            labels: TransactionLabels::default(),
         }
     }
 
@@ -90,7 +223,8 @@ impl AccountCommand {
         AccountCommand::Transaction {
             timestamp,
             txid,
-            command: TransactionCommand::Withdraw { asset, amount },
+            command: TransactionCommand::Withdraw { asset, amount, value_date: None },
+            labels: TransactionLabels::default(),
         }
     }
 
@@ -109,6 +243,7 @@ impl AccountCommand {
                 asset,
                 amount,
             },
+            labels: TransactionLabels::default(),
         }
     }
 
@@ -127,6 +262,7 @@ impl AccountCommand {
                 asset,
                 amount,
             },
+            labels: TransactionLabels::default(),
         }
     }
 
@@ -145,6 +281,7 @@ impl AccountCommand {
                 asset,
                 amount,
             },
+            labels: TransactionLabels::default(),
         }
     }
 
@@ -163,6 +300,7 @@ impl AccountCommand {
                 asset,
                 amount,
             },
+            labels: TransactionLabels::default(),
         }
     }
 
@@ -179,29 +317,135 @@ impl AccountCommand {
                 asset,
                 amount,
             },
+            labels: TransactionLabels::default(),
         }
     }
 
-    pub fn unlock_funds(txid: ByteArray32) -> Self {
+    pub fn unlock_funds(txid: ByteArray32, timestamp: u64) -> Self {
         AccountCommand::Transaction {
-            timestamp: 0,
+            timestamp,
             txid,
             command: TransactionCommand::UnlockFunds,
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn release_quarantine(txid: ByteArray32, timestamp: u64) -> Self {
+        AccountCommand::Transaction {
+            timestamp,
+            txid,
+            command: TransactionCommand::ReleaseQuarantine,
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn place_hold(txid: ByteArray32, timestamp: u64, hold: String, asset: String, amount: u64) -> Self {
+        AccountCommand::Transaction {
+            timestamp,
+            txid,
+            command: TransactionCommand::PlaceHold { hold, asset, amount },
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn capture_hold(txid: ByteArray32, timestamp: u64, hold: String, to_account: String, amount: u64) -> Self {
+        AccountCommand::Transaction {
+            timestamp,
+            txid,
+            command: TransactionCommand::CaptureHold { hold, to_account, amount },
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn lock_held(txid: ByteArray32, timestamp: u64, hold: String, amount: u64) -> Self {
+        AccountCommand::Transaction {
+            timestamp,
+            txid,
+            command: TransactionCommand::LockHeld { hold, amount },
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn release_hold(txid: ByteArray32, timestamp: u64, hold: String, amount: u64) -> Self {
+        AccountCommand::Transaction {
+            timestamp,
+            txid,
+            command: TransactionCommand::ReleaseHold { hold, amount },
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn earmark(txid: ByteArray32, timestamp: u64, earmark: String, asset: String, amount: u64, purpose: EarmarkPurpose) -> Self {
+        AccountCommand::Transaction {
+            timestamp,
+            txid,
+            command: TransactionCommand::Earmark { earmark, asset, amount, purpose },
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn release_earmark(txid: ByteArray32, timestamp: u64, earmark: String) -> Self {
+        AccountCommand::Transaction {
+            timestamp,
+            txid,
+            command: TransactionCommand::ReleaseEarmark { earmark },
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn convert_asset(txid: ByteArray32, timestamp: u64, from_asset: String, to_asset: String, ratio: ConversionRatio) -> Self {
+        AccountCommand::Transaction {
+            timestamp,
+            txid,
+            command: TransactionCommand::ConvertAsset { from_asset, to_asset, ratio },
+            labels: TransactionLabels::default(),
         }
     }
 
     pub fn settle(txid: ByteArray32,
+                  timestamp: u64,
                   to_account: String,
                   receive_asset: String,
                   receive_amount: u64) -> Self {
         AccountCommand::Transaction {
-            timestamp: 0,
+            timestamp,
             txid,
             command: TransactionCommand::Settle {
                 to_account,
                 receive_asset,
                 receive_amount
             },
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn with_labels(mut self, transaction_labels: TransactionLabels) -> Self {
+        if let AccountCommand::Transaction { labels, .. } = &mut self {
+            *labels = transaction_labels;
+        }
+        self
+    }
+
+    // Only deposits and withdrawals are value dated, other commands are left as they are.
+    pub fn with_value_date(mut self, date: NaiveDate) -> Self {
+        if let AccountCommand::Transaction {
+            command: TransactionCommand::Deposit { value_date, .. } | TransactionCommand::Withdraw { value_date, .. },
+            ..
+        } = &mut self {
+            *value_date = Some(date);
+        }
+        self
+    }
+
+    // The value date the command asks for, the first one in a batch.
+    pub fn value_date(&self) -> Option<NaiveDate> {
+        match self {
+            AccountCommand::Transaction {
+                command: TransactionCommand::Deposit { value_date, .. } | TransactionCommand::Withdraw { value_date, .. },
+                ..
+            } => *value_date,
+            AccountCommand::Transaction { .. } | AccountCommand::Lifecycle(_) => None,
+            AccountCommand::Batch(commands) => commands.iter().find_map(AccountCommand::value_date),
         }
     }
 }
@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use cqrs_es::{EventEnvelope, Query};
+use sqlx::{Pool, Postgres, Row};
+use crate::preferences::aggregate::Preferences;
+use crate::preferences::events::PreferencesEvent;
+use crate::util::clock;
+
+// Names are set on one node and read on all of them, so another node's rename shows
+// after at most this long.
+const CACHE_TTL_SECS: u64 = 60;
+// Past this many accounts the cache starts over rather than tracking their use.
+const CACHE_CAPACITY: usize = 10_000;
+
+struct Cached {
+    display_name: Option<String>,
+    fetched_at: u64,
+}
+
+// The display names accounts set in their preferences, kept in `counterparty_name` for
+// the ledger to show next to the counterparty ids. Ledger entries take the name their
+// counterparty has when they are booked; those booked before it had one take it when a
+// statement first shows them, see `load_statement`. Lookups go through a cache that also
+// remembers the accounts without a name, most counterparties never set one.
+#[derive(Clone)]
+pub struct CounterpartyNames {
+    pool: Pool<Postgres>,
+    cache: Arc<Mutex<HashMap<String, Cached>>>,
+}
+
+impl CounterpartyNames {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        CounterpartyNames { pool, cache: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    // The names of those of the accounts that have one, looking up the ones not cached
+    // in a single query.
+    pub async fn resolve(&self, account_ids: &[&str]) -> Result<HashMap<String, String>, sqlx::Error> {
+        let now = clock::now();
+        let mut names = HashMap::new();
+        let mut missing = vec![];
+        {
+            let cache = self.cache.lock().unwrap();
+            for account_id in account_ids {
+                match cache.get(*account_id) {
+                    Some(cached) if now < cached.fetched_at + CACHE_TTL_SECS => {
+                        if let Some(display_name) = &cached.display_name {
+                            names.insert(account_id.to_string(), display_name.clone());
+                        }
+                    }
+                    _ => missing.push(account_id.to_string()),
+                }
+            }
+        }
+        if missing.is_empty() {
+            return Ok(names);
+        }
+        missing.sort();
+        missing.dedup();
+        let rows = sqlx::query("SELECT account_id, display_name FROM counterparty_name WHERE account_id = ANY($1)")
+            .bind(&missing)
+            .fetch_all(&self.pool)
+            .await?;
+        let mut found = HashMap::with_capacity(rows.len());
+        for row in rows {
+            found.insert(row.try_get::<String, _>("account_id")?, row.try_get::<String, _>("display_name")?);
+        }
+        for account_id in missing {
+            let display_name = found.remove(&account_id);
+            self.remember(&account_id, display_name.clone());
+            if let Some(display_name) = display_name {
+                names.insert(account_id, display_name);
+            }
+        }
+        Ok(names)
+    }
+
+    fn remember(&self, account_id: &str, display_name: Option<String>) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= CACHE_CAPACITY && !cache.contains_key(account_id) {
+            cache.clear();
+        }
+        cache.insert(account_id.to_string(), Cached { display_name, fetched_at: clock::now() });
+    }
+
+    async fn write(&self, account_id: &str, display_name: &Option<String>) -> Result<(), sqlx::Error> {
+        match display_name {
+            Some(display_name) => {
+                sqlx::query(
+                    "INSERT INTO counterparty_name (account_id, display_name, updated_at) VALUES ($1, $2, $3)
+                     ON CONFLICT (account_id) DO UPDATE SET display_name = $2, updated_at = $3",
+                )
+                    .bind(account_id)
+                    .bind(display_name)
+                    .bind(clock::now() as i64)
+                    .execute(&self.pool)
+                    .await?;
+            }
+            None => {
+                sqlx::query("DELETE FROM counterparty_name WHERE account_id = $1")
+                    .bind(account_id)
+                    .execute(&self.pool)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Query<Preferences> for CounterpartyNames {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<Preferences>]) {
+        let display_name = events.iter().rev().find_map(|event| match &event.payload {
+            PreferencesEvent::DisplayNameSet { display_name } => Some(display_name),
+            _ => None,
+        });
+        let Some(display_name) = display_name else {
+            return;
+        };
+        match self.write(aggregate_id, display_name).await {
+            Ok(()) => self.remember(aggregate_id, display_name.clone()),
+            Err(e) => tracing::error!("Failed to write the display name of {}: {}", aggregate_id, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use cqrs_es::{EventEnvelope, Query};
+    use rand::random;
+    use crate::account::aggregate::Account;
+    use crate::account::counterparty::CounterpartyNames;
+    use crate::account::events::AccountEvent;
+    use crate::account::ledger::{load_statement, write_ledger_entries, StatementFilter};
+    use crate::preferences::aggregate::Preferences;
+    use crate::preferences::events::PreferencesEvent;
+    use crate::util::test_db::{delete_rows, random_suffix, test_pool};
+    use crate::util::types::ByteArray32;
+
+    #[tokio::test]
+    async fn test_counterparty_names() {
+        let pool = test_pool();
+        let suffix = random_suffix();
+        let (account_id, payer, payee) = (format!("NAMED-{}", suffix), format!("PAYER-{}", suffix), format!("PAYEE-{}", suffix));
+        let names = CounterpartyNames::new(pool.clone());
+        let set_name = |account_id: &str, display_name: &str| vec![EventEnvelope::<Preferences> {
+            aggregate_id: account_id.to_string(),
+            sequence: 1,
+            payload: PreferencesEvent::DisplayNameSet { display_name: Some(display_name.to_string()) },
+            metadata: HashMap::new(),
+        }];
+        let envelope = |sequence, payload| EventEnvelope::<Account> {
+            aggregate_id: account_id.clone(),
+            sequence,
+            payload,
+            metadata: HashMap::new(),
+        };
+
+        // Booked before the payee named itself, and after the payer did.
+        write_ledger_entries(&pool, &[envelope(1, AccountEvent::debited(ByteArray32(random()), 10, payee.clone(), "USD".to_string(), 5))]).await.unwrap();
+        names.dispatch(&payer, &set_name(&payer, "Acme Payroll")).await;
+        write_ledger_entries(&pool, &[envelope(2, AccountEvent::credited(ByteArray32(random()), 20, payer.clone(), "USD".to_string(), 7))]).await.unwrap();
+        let stored = || sqlx::query_scalar::<_, Option<String>>("SELECT counterparty_name FROM ledger_entries WHERE account_id = $1 ORDER BY sequence")
+            .bind(&account_id)
+            .fetch_all(&pool);
+        assert_eq!(stored().await.unwrap(), vec![None, Some("Acme Payroll".to_string())]);
+
+        // Shown, and backfilled, once the payee has a name too.
+        names.dispatch(&payee, &set_name(&payee, "Corner Shop")).await;
+        let page = load_statement(&pool, &names, &account_id, &StatementFilter::default()).await.unwrap();
+        let shown: Vec<_> = page.entries.iter().map(|entry| entry.counterparty_name.as_deref()).collect();
+        assert_eq!(shown, vec![Some("Acme Payroll"), Some("Corner Shop")]);
+        assert_eq!(stored().await.unwrap(), vec![Some("Corner Shop".to_string()), Some("Acme Payroll".to_string())]);
+        assert!(names.resolve(&[&account_id]).await.unwrap().is_empty());
+
+        delete_rows(&pool, "ledger_entries", "account_id", &account_id).await;
+        sqlx::query("DELETE FROM counterparty_name WHERE account_id = ANY($1)").bind(vec![payer, payee]).execute(&pool).await.unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+use cqrs_es::persist::{PersistenceError, ViewRepository};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use crate::account::aggregate::Account;
+use crate::account::queries::AccountView;
+use crate::aggregate_cache::AggregateCache;
+
+const DEFAULT_MAX_STALENESS_MS: u64 = 1_000;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CoverageParams {
+    pub asset: String,
+    pub amount: u64,
+    // How old an answer from the aggregate cache may be, 1 second by default. Older ones
+    // are read from the view instead.
+    pub max_staleness_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum CoverageSource {
+    // The aggregate as this node last committed it, no storage involved.
+    Cache,
+    // The account view, read from storage.
+    View,
+}
+
+// Whether an account can cover an amount from its available balance, not counting its
+// credit line.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Coverage {
+    pub account_id: String,
+    pub asset: String,
+    pub amount: u64,
+    pub available: u64,
+    // False for disabled accounts, whatever their balance.
+    pub covers: bool,
+    pub source: CoverageSource,
+    // Of the last event the answer reflects.
+    pub sequence: usize,
+    // How long ago the balance was known to match the event store: since the commit that
+    // left it in the cache, 0 for the view. Commands of other nodes since then are not
+    // reflected, unless the cache heard of them through cache invalidation and dropped it.
+    pub staleness_ms: u64,
+}
+
+// Answers a balance check from the aggregate cache when it holds the account and it is
+// recent enough, without touching storage, and from the view otherwise. Accounts busy
+// with a command are read from the view rather than waited for.
+pub async fn check_coverage(
+    cache: &AggregateCache<Account>,
+    views: &impl ViewRepository<AccountView, Account>,
+    account_id: &str,
+    params: &CoverageParams,
+) -> Result<Option<Coverage>, PersistenceError> {
+    let max_staleness_ms = params.max_staleness_ms.unwrap_or(DEFAULT_MAX_STALENESS_MS);
+    let coverage = |available: u64, in_service: bool, source, sequence, staleness_ms| Coverage {
+        account_id: account_id.to_string(),
+        asset: params.asset.clone(),
+        amount: params.amount,
+        available,
+        covers: in_service && available >= params.amount,
+        source,
+        sequence,
+        staleness_ms,
+    };
+    let cached = cache.peek(account_id, |account| account.available_balance(&params.asset));
+    match cached {
+        Some((balance, sequence, age)) if (age.as_millis() as u64) <= max_staleness_ms => {
+            return Ok(balance.map(|(available, in_service)| coverage(available, in_service, CoverageSource::Cache, sequence, age.as_millis() as u64)));
+        }
+        _ => {}
+    }
+    let view = views.load(account_id).await?;
+    Ok(view
+        .filter(AccountView::is_open)
+        .map(|view| coverage(view.available_balance(&params.asset), !view.is_disabled(), CoverageSource::View, view.sequence(), 0)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use cqrs_es::persist::{GenericQuery, PersistedEventStore};
+    use cqrs_es::CqrsFramework;
+    use postgres_es::{PostgresEventRepository, PostgresViewRepository};
+    use rand::random;
+    use crate::account::aggregate::Account;
+    use crate::account::commands::AccountCommand;
+    use crate::account::coverage::{check_coverage, CoverageParams, CoverageSource};
+    use crate::account::queries::AccountView;
+    use crate::aggregate_cache::{AggregateCache, CachedEventStore};
+    use crate::config::AggregateCacheConfig;
+    use crate::metrics::AggregateCacheMetrics;
+    use crate::services::{BankAccountServices, HappyPathBankAccountServices};
+    use crate::util::test_db::{delete_rows, random_suffix, test_pool};
+    use crate::util::types::ByteArray32;
+
+    #[tokio::test]
+    async fn test_check_coverage() {
+        let pool = test_pool();
+        let cache = AggregateCache::<Account>::new(&AggregateCacheConfig::default(), AggregateCacheMetrics::default());
+        let views = Arc::new(PostgresViewRepository::<AccountView, Account>::new("account_query", pool.clone()));
+        let mut query = GenericQuery::new(views.clone());
+        query.use_error_handler(Box::new(|e| panic!("{}", e)));
+        let store = PersistedEventStore::new_event_store(PostgresEventRepository::new(pool.clone()));
+        let cqrs = CqrsFramework::new(
+            CachedEventStore::new(store, cache.clone(), None),
+            vec![Box::new(query)],
+            BankAccountServices::new(Box::new(HappyPathBankAccountServices)),
+        );
+        let account_id = format!("COVER-{}", random_suffix());
+        let params = |amount, max_staleness_ms| CoverageParams { asset: "BTC".to_string(), amount, max_staleness_ms };
+
+        assert!(check_coverage(&cache, &*views, &account_id, &params(1, None)).await.unwrap().is_none());
+        cqrs.execute(&account_id, AccountCommand::account_opened(account_id.clone())).await.unwrap();
+        cqrs.execute(&account_id, AccountCommand::deposited(ByteArray32(random()), 1, "BTC".to_string(), 100)).await.unwrap();
+
+        // Fresh from the commit, the cache answers.
+        let coverage = check_coverage(&cache, &*views, &account_id, &params(100, Some(60_000))).await.unwrap().unwrap();
+        assert_eq!((coverage.source, coverage.available, coverage.covers, coverage.sequence), (CoverageSource::Cache, 100, true, 2));
+        let coverage = check_coverage(&cache, &*views, &account_id, &params(101, Some(60_000))).await.unwrap().unwrap();
+        assert!(!coverage.covers);
+
+        // Too old, or no longer cached, the view does.
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        let coverage = check_coverage(&cache, &*views, &account_id, &params(100, Some(0))).await.unwrap().unwrap();
+        assert_eq!((coverage.source, coverage.available, coverage.covers, coverage.staleness_ms), (CoverageSource::View, 100, true, 0));
+        cache.clear();
+        let coverage = check_coverage(&cache, &*views, &account_id, &params(50, None)).await.unwrap().unwrap();
+        assert_eq!((coverage.source, coverage.sequence), (CoverageSource::View, 2));
+
+        delete_rows(&pool, "events", "aggregate_id", &account_id).await;
+        delete_rows(&pool, "account_query", "view_id", &account_id).await;
+    }
+}
@@ -0,0 +1,181 @@
+use async_trait::async_trait;
+use cqrs_es::persist::{PersistenceError, SerializedEvent};
+use cqrs_es::Aggregate;
+use sqlx::{Pool, Postgres, Row, Transaction};
+use crate::account::aggregate::Account;
+use crate::account::events::{AccountEvent, TransactionEvent};
+use crate::commit_hooks::{persistence_error, CommitHook};
+use crate::config::DuplicateDetectionConfig;
+use crate::services::DedupStore;
+use crate::util::types::ByteArray32;
+
+// The txids accounts processed, in the `account_txids` table instead of their state, so
+// busy accounts do not carry them in every snapshot. Claimed in the transaction the
+// events commit in, and the command fails when the account processed the txid within
+// its window already; the accounts only remember them for
+// `DuplicateDetectionConfig::recent_secs`. Reversals forget their txid like the account
+// does, and rows past the window of their account are dropped as it records new ones.
+#[derive(Clone)]
+pub struct TxidStore {
+    pool: Pool<Postgres>,
+    config: DuplicateDetectionConfig,
+}
+
+impl TxidStore {
+    pub fn new(pool: Pool<Postgres>, config: DuplicateDetectionConfig) -> Self {
+        TxidStore { pool, config }
+    }
+
+    async fn record(&self, tx: &mut Transaction<'_, Postgres>, account_id: &str, events: Vec<AccountEvent>) -> Result<(), PersistenceError> {
+        let window_secs = self.config.window_secs(account_id);
+        let mut latest = None;
+        for event in events {
+            let AccountEvent::Transaction { txid, timestamp, event, .. } = event else {
+                continue;
+            };
+            match event {
+                // The ones `Account::apply` forgets the txid of.
+                TransactionEvent::DebitReversed { .. } | TransactionEvent::CreditReversed { .. } => {
+                    sqlx::query("DELETE FROM account_txids WHERE account_id = $1 AND txid = $2")
+                        .bind(account_id)
+                        .bind(txid.hex())
+                        .execute(&mut **tx)
+                        .await
+                        .map_err(persistence_error)?;
+                },
+                // And the ones it does not record it for.
+                TransactionEvent::FundsLocked { .. }
+                | TransactionEvent::FundsUnlocked { .. }
+                | TransactionEvent::OverdraftUsed { .. }
+                | TransactionEvent::OverdraftRepaid { .. }
+                | TransactionEvent::QuarantineReleased { .. }
+                | TransactionEvent::AssetConverted { .. } => {},
+                _ => {
+                    // Only a row past the window is taken over.
+                    let claimed = sqlx::query(
+                        "INSERT INTO account_txids (account_id, txid, timestamp) VALUES ($1, $2, $3)
+                         ON CONFLICT (account_id, txid) DO UPDATE SET timestamp = excluded.timestamp
+                         WHERE account_txids.timestamp < $4",
+                    )
+                        .bind(account_id)
+                        .bind(txid.hex())
+                        .bind(timestamp as i64)
+                        .bind(timestamp.saturating_sub(window_secs) as i64)
+                        .execute(&mut **tx)
+                        .await
+                        .map_err(persistence_error)?;
+                    if claimed.rows_affected() != 1 {
+                        // Run again, the command then finds the txid processed.
+                        return Err(PersistenceError::OptimisticLockError);
+                    }
+                    latest = latest.max(Some(timestamp));
+                },
+            }
+        }
+        if let Some(latest) = latest {
+            let expired = latest.saturating_sub(window_secs);
+            sqlx::query("DELETE FROM account_txids WHERE account_id = $1 AND timestamp < $2")
+                .bind(account_id)
+                .bind(expired as i64)
+                .execute(&mut **tx)
+                .await
+                .map_err(persistence_error)?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DedupStore for TxidStore {
+    async fn processed_at(&self, account_id: &str, txid: &ByteArray32, since: u64) -> Result<Option<u64>, String> {
+        let row = sqlx::query("SELECT timestamp FROM account_txids WHERE account_id = $1 AND txid = $2 AND timestamp >= $3")
+            .bind(account_id)
+            .bind(txid.hex())
+            .bind(since as i64)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        row.map(|row| row.try_get::<i64, _>("timestamp").map(|timestamp| timestamp as u64))
+            .transpose()
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[async_trait]
+impl CommitHook for TxidStore {
+    async fn append(&self, tx: &mut Transaction<'_, Postgres>, events: &[SerializedEvent]) -> Result<(), PersistenceError> {
+        let Some(first) = events.first().filter(|event| event.aggregate_type == Account::aggregate_type()) else {
+            return Ok(());
+        };
+        let events = events
+            .iter()
+            .map(|event| serde_json::from_value(event.payload.clone()))
+            .collect::<Result<Vec<AccountEvent>, _>>()
+            .map_err(|e| PersistenceError::DeserializationError(Box::new(e)))?;
+        self.record(tx, &first.aggregate_id, events).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::persist::{PersistenceError, SerializedEvent};
+    use cqrs_es::DomainEvent;
+    use rand::random;
+    use crate::account::dedup::TxidStore;
+    use crate::account::events::AccountEvent;
+    use crate::commit_hooks::CommitHook;
+    use crate::config::DuplicateDetectionConfig;
+    use crate::services::DedupStore;
+    use crate::util::test_db::{delete_rows, random_suffix, test_pool};
+    use crate::util::types::ByteArray32;
+
+    fn serialized(account_id: &str, sequence: usize, payload: AccountEvent) -> SerializedEvent {
+        let (event_type, event_version) = (payload.event_type(), payload.event_version());
+        let payload = serde_json::to_value(payload).unwrap();
+        SerializedEvent::new(account_id.to_string(), sequence, "account".to_string(), event_type, event_version, payload, serde_json::json!({}))
+    }
+
+    #[tokio::test]
+    async fn test_txid_store() {
+        let pool = test_pool();
+        let config = DuplicateDetectionConfig { window_secs: 100, ..DuplicateDetectionConfig::default() };
+        let store = TxidStore::new(pool.clone(), config);
+        let account_id = format!("DEDUP-{}", random_suffix());
+        let (deposit, debit, later) = (ByteArray32(random()), ByteArray32(random()), ByteArray32(random()));
+        let append = |events: Vec<SerializedEvent>| {
+            let (pool, store) = (pool.clone(), store.clone());
+            async move {
+                let mut tx = pool.begin().await.unwrap();
+                store.append(&mut tx, &events).await?;
+                tx.commit().await.unwrap();
+                Ok::<_, PersistenceError>(())
+            }
+        };
+
+        append(vec![
+            serialized(&account_id, 1, AccountEvent::account_opened(account_id.clone())),
+            serialized(&account_id, 2, AccountEvent::deposited(deposit, 10, "BTC".to_string(), 100)),
+            serialized(&account_id, 3, AccountEvent::debited(debit, 20, "ACCT-0002".to_string(), "BTC".to_string(), 10)),
+        ]).await.unwrap();
+        assert_eq!(store.processed_at(&account_id, &deposit, 0).await.unwrap(), Some(10));
+        // Outside of the window asked for.
+        assert_eq!(store.processed_at(&account_id, &deposit, 11).await.unwrap(), None);
+        assert_eq!(store.processed_at("ACCT-0002", &deposit, 0).await.unwrap(), None);
+
+        // Claimed once within the window, the events of the second claim are not committed.
+        let again = append(vec![serialized(&account_id, 4, AccountEvent::deposited(deposit, 30, "BTC".to_string(), 100))]).await;
+        assert!(matches!(again, Err(PersistenceError::OptimisticLockError)));
+        assert_eq!(store.processed_at(&account_id, &deposit, 0).await.unwrap(), Some(10));
+
+        // A reversal forgets its txid, and recording past the window drops the old ones.
+        append(vec![
+            serialized(&account_id, 4, AccountEvent::debit_reversed(debit, 20, "ACCT-0002".to_string(), "BTC".to_string(), 10)),
+            serialized(&account_id, 5, AccountEvent::deposited(later, 115, "BTC".to_string(), 100)),
+        ]).await.unwrap();
+        assert_eq!(store.processed_at(&account_id, &debit, 0).await.unwrap(), None);
+        assert_eq!(store.processed_at(&account_id, &deposit, 0).await.unwrap(), None);
+        assert_eq!(store.processed_at(&account_id, &later, 0).await.unwrap(), Some(115));
+
+        delete_rows(&pool, "account_txids", "account_id", &account_id).await;
+    }
+}
@@ -1,8 +1,14 @@
+use chrono::NaiveDate;
 use cqrs_es::DomainEvent;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Debug;
 
+use crate::asset::types::ConversionRatio;
 use crate::util::types::ByteArray32;
+#[cfg(feature = "server")]
+use crate::metrics::ErrorVariant;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum AccountEvent {
@@ -11,12 +17,61 @@ pub enum AccountEvent {
         timestamp: u64,
         txid: ByteArray32,
         event: TransactionEvent,
+        // Balances of the assets touched by this transaction, as they stand once it is applied.
+        // Missing on events stored before the snapshots were recorded.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        balance_after: Option<BTreeMap<String, BalanceSnapshot>>,
+        // The business day the transaction is booked on, see `crate::business_day`. Missing
+        // while business days are not kept and on events stored before.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value_date: Option<NaiveDate>,
+        // As given on the command, every event it raises carries them.
+        #[serde(flatten)]
+        labels: TransactionLabels,
     },
 }
 
+// What a transaction is for, set by the client: a category statements can be filtered
+// by, e.g. payroll or refund, and free-form metadata such as an invoice number.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default, ToSchema)]
+pub struct TransactionLabels {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl TransactionLabels {
+    pub fn is_empty(&self) -> bool {
+        self.category.is_none() && self.metadata.is_empty()
+    }
+}
+
+// Why funds are earmarked, see `TransactionCommand::Earmark`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub enum EarmarkPurpose {
+    Quarantine,
+    TermDeposit,
+    Compliance,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default, ToSchema)]
+pub struct BalanceSnapshot {
+    pub available: u64,
+    pub locked: u64,
+}
+
 impl AccountEvent {
     pub fn account_opened(account_id: String) -> Self {
-        AccountEvent::Lifecycle(LifecycleEvent::Opened { account_id })
+        AccountEvent::account_opened_with_window(account_id, DEFAULT_TTL, None)
+    }
+
+    pub fn account_opened_with_window(account_id: String, duplicate_window_secs: u64, max_txids: Option<u64>) -> Self {
+        AccountEvent::Lifecycle(LifecycleEvent::Opened { account_id, duplicate_window_secs, max_txids })
+    }
+
+    pub fn txids_evicted(txids: Vec<ByteArray32>) -> Self {
+        AccountEvent::Lifecycle(LifecycleEvent::TxidsEvicted { txids })
     }
 
     pub fn account_disabled() -> Self {
@@ -31,11 +86,26 @@ impl AccountEvent {
         AccountEvent::Lifecycle(LifecycleEvent::Closed)
     }
 
+    pub fn account_tagged(tags: BTreeSet<String>) -> Self {
+        AccountEvent::Lifecycle(LifecycleEvent::Tagged { tags })
+    }
+
+    pub fn close_requested(requested_at: u64, finalize_at: u64) -> Self {
+        AccountEvent::Lifecycle(LifecycleEvent::CloseRequested { requested_at, finalize_at })
+    }
+
+    pub fn closure_aborted(reason: String) -> Self {
+        AccountEvent::Lifecycle(LifecycleEvent::ClosureAborted { reason })
+    }
+
     pub fn deposited(txid: ByteArray32, timestamp: u64, asset: String, amount: u64) -> Self {
         AccountEvent::Transaction {
             timestamp,
             txid,
             event: TransactionEvent::Deposited { asset, amount },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
         }
     }
 
@@ -54,6 +124,9 @@ impl AccountEvent {
                 asset,
                 amount,
             },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
         }
     }
 
@@ -72,6 +145,9 @@ impl AccountEvent {
                 asset,
                 amount,
             },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
         }
     }
 
@@ -90,6 +166,9 @@ impl AccountEvent {
                 asset,
                 amount,
             },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
         }
     }
 
@@ -108,6 +187,9 @@ impl AccountEvent {
                 asset,
                 amount,
             },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
         }
     }
 
@@ -116,6 +198,9 @@ impl AccountEvent {
             timestamp,
             txid,
             event: TransactionEvent::Withdrew { asset, amount },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
         }
     }
 
@@ -132,6 +217,9 @@ impl AccountEvent {
                 asset,
                 amount,
             },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
         }
     }
 
@@ -146,6 +234,30 @@ impl AccountEvent {
                 asset,
                 amount
             },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn fee_charged(
+        txid: ByteArray32,
+        timestamp: u64,
+        to_account: String,
+        asset: String,
+        amount: u64,
+    ) -> Self {
+        AccountEvent::Transaction {
+            timestamp,
+            txid,
+            event: TransactionEvent::FeeCharged {
+                to_account,
+                asset,
+                amount,
+            },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
         }
     }
 
@@ -168,16 +280,201 @@ impl AccountEvent {
                 receive_asset,
                 receive_amount
             },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn hold_placed(txid: ByteArray32, timestamp: u64, hold: String, asset: String, amount: u64) -> Self {
+        AccountEvent::Transaction {
+            timestamp,
+            txid,
+            event: TransactionEvent::HoldPlaced { hold, asset, amount },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn hold_captured(
+        txid: ByteArray32,
+        timestamp: u64,
+        hold: String,
+        to_account: String,
+        asset: String,
+        amount: u64,
+        remaining: u64,
+    ) -> Self {
+        AccountEvent::Transaction {
+            timestamp,
+            txid,
+            event: TransactionEvent::HoldCaptured { hold, to_account, asset, amount, remaining },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn hold_released(txid: ByteArray32, timestamp: u64, hold: String, asset: String, amount: u64, remaining: u64) -> Self {
+        AccountEvent::Transaction {
+            timestamp,
+            txid,
+            event: TransactionEvent::HoldReleased { hold, asset, amount, remaining },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn overdraft_used(txid: ByteArray32, timestamp: u64, asset: String, amount: u64, limit: u64) -> Self {
+        AccountEvent::Transaction {
+            timestamp,
+            txid,
+            event: TransactionEvent::OverdraftUsed { asset, amount, limit },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn overdraft_repaid(txid: ByteArray32, timestamp: u64, asset: String, amount: u64) -> Self {
+        AccountEvent::Transaction {
+            timestamp,
+            txid,
+            event: TransactionEvent::OverdraftRepaid { asset, amount },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn deposit_quarantined(txid: ByteArray32, timestamp: u64, asset: String, amount: u64, source: String) -> Self {
+        AccountEvent::Transaction {
+            timestamp,
+            txid,
+            event: TransactionEvent::DepositQuarantined { asset, amount, source },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
         }
     }
+
+    pub fn quarantine_released(txid: ByteArray32, timestamp: u64, asset: String, amount: u64) -> Self {
+        AccountEvent::Transaction {
+            timestamp,
+            txid,
+            event: TransactionEvent::QuarantineReleased { asset, amount },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn funds_earmarked(txid: ByteArray32, timestamp: u64, earmark: String, asset: String, amount: u64, purpose: EarmarkPurpose) -> Self {
+        AccountEvent::Transaction {
+            timestamp,
+            txid,
+            event: TransactionEvent::FundsEarmarked { earmark, asset, amount, purpose },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn earmark_released(txid: ByteArray32, timestamp: u64, earmark: String, asset: String, amount: u64) -> Self {
+        AccountEvent::Transaction {
+            timestamp,
+            txid,
+            event: TransactionEvent::EarmarkReleased { earmark, asset, amount },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn asset_converted(
+        txid: ByteArray32,
+        timestamp: u64,
+        from_asset: String,
+        from_amount: u64,
+        to_asset: String,
+        to_amount: u64,
+        ratio: ConversionRatio,
+    ) -> Self {
+        AccountEvent::Transaction {
+            timestamp,
+            txid,
+            event: TransactionEvent::AssetConverted { from_asset, from_amount, to_asset, to_amount, ratio },
+            balance_after: None,
+            value_date: None,
+            labels: TransactionLabels::default(),
+        }
+    }
+
+    pub fn with_balance_after(mut self, asset: impl Into<String>, available: u64, locked: u64) -> Self {
+        if let AccountEvent::Transaction { balance_after, .. } = &mut self {
+            balance_after
+                .get_or_insert_with(BTreeMap::new)
+                .insert(asset.into(), BalanceSnapshot { available, locked });
+        }
+        self
+    }
+
+    pub fn with_labels(mut self, transaction_labels: TransactionLabels) -> Self {
+        if let AccountEvent::Transaction { labels, .. } = &mut self {
+            *labels = transaction_labels;
+        }
+        self
+    }
+
+    pub fn with_value_date(mut self, date: Option<NaiveDate>) -> Self {
+        if let AccountEvent::Transaction { value_date, .. } = &mut self {
+            *value_date = date;
+        }
+        self
+    }
+}
+
+pub const DEFAULT_TTL: u64 = 30 * 24 * 60 * 60;
+
+fn default_ttl() -> u64 {
+    DEFAULT_TTL
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum LifecycleEvent {
-    Opened { account_id: String },
+    Opened {
+        account_id: String,
+        // Seconds a txid is remembered for duplicate detection. Recorded when the account
+        // is opened so replaying it keeps the window that was in force; accounts opened
+        // before it was configurable have the former fixed 30 days.
+        #[serde(default = "default_ttl")]
+        duplicate_window_secs: u64,
+        // How many txids it remembers at most; accounts opened before the cap have none.
+        #[serde(default)]
+        max_txids: Option<u64>,
+    },
     Disabled,
     Enabled,
     Closed,
+    Tagged {
+        tags: BTreeSet<String>,
+    },
+    // The account stays open for withdrawals until `finalize_at`.
+    CloseRequested {
+        requested_at: u64,
+        finalize_at: u64,
+    },
+    // Back in service, the closure was not finalized.
+    ClosureAborted {
+        reason: String,
+    },
+    // The oldest txids, forgotten to keep the account within its cap. Recorded rather
+    // than recomputed so replaying it forgets the same ones.
+    TxidsEvicted {
+        txids: Vec<ByteArray32>,
+    },
 }
 
 impl LifecycleEvent {
@@ -187,6 +484,10 @@ impl LifecycleEvent {
             LifecycleEvent::Disabled => "Disabled".to_string(),
             LifecycleEvent::Enabled => "Enabled".to_string(),
             LifecycleEvent::Closed => "Closed".to_string(),
+            LifecycleEvent::Tagged { .. } => "Tagged".to_string(),
+            LifecycleEvent::TxidsEvicted { .. } => "TxidsEvicted".to_string(),
+            LifecycleEvent::CloseRequested { .. } => "CloseRequested".to_string(),
+            LifecycleEvent::ClosureAborted { .. } => "ClosureAborted".to_string(),
         }
     }
 }
@@ -236,6 +537,79 @@ pub enum TransactionEvent {
         receive_asset: String,
         receive_amount: u64,
     },
+    // A fee on a withdrawal or settlement, debited here and credited to the fee
+    // collection account `to_account` by the `FeeCollector`.
+    FeeCharged {
+        to_account: String,
+        asset: String,
+        amount: u64,
+    },
+    HoldPlaced {
+        hold: String,
+        asset: String,
+        amount: u64,
+    },
+    // `remaining` is what the hold still reserves, it is gone once nothing remains.
+    HoldCaptured {
+        hold: String,
+        to_account: String,
+        asset: String,
+        amount: u64,
+        remaining: u64,
+    },
+    HoldReleased {
+        hold: String,
+        asset: String,
+        amount: u64,
+        remaining: u64,
+    },
+    // Drawn on the credit line of the account to cover what the transaction under the
+    // same txid takes beyond the balance. `limit` is the credit limit at the time.
+    OverdraftUsed {
+        asset: String,
+        amount: u64,
+        limit: u64,
+    },
+    // Paid back out of funds the transaction under the same txid brought in.
+    OverdraftRepaid {
+        asset: String,
+        amount: u64,
+    },
+    // A deposit from an unverified source, held apart from the balance until released.
+    // The source is empty when the deposit named none.
+    DepositQuarantined {
+        asset: String,
+        amount: u64,
+        source: String,
+    },
+    // Lets the deposit quarantined under the same txid into the balance.
+    QuarantineReleased {
+        asset: String,
+        amount: u64,
+    },
+    // Funds of the balance set aside, neither available nor locked until released.
+    FundsEarmarked {
+        earmark: String,
+        asset: String,
+        amount: u64,
+        purpose: EarmarkPurpose,
+    },
+    EarmarkReleased {
+        earmark: String,
+        asset: String,
+        amount: u64,
+    },
+    // Everything held in `from_asset` converted into `to_asset` at `ratio`. The amounts are
+    // those of the balance, locked, held, quarantined and earmarked funds and what is owed
+    // convert alongside. Recorded under the txid of the migration, an account converts
+    // again when funds in the old asset reach it late.
+    AssetConverted {
+        from_asset: String,
+        from_amount: u64,
+        to_asset: String,
+        to_amount: u64,
+        ratio: ConversionRatio,
+    },
 }
 
 impl TransactionEvent {
@@ -250,6 +624,17 @@ impl TransactionEvent {
             TransactionEvent::FundsLocked { .. } => "FundsLocked".to_string(),
             TransactionEvent::FundsUnlocked { .. } => "FundsUnlocked".to_string(),
             TransactionEvent::Settled { .. } => "Settled".to_string(),
+            TransactionEvent::FeeCharged { .. } => "FeeCharged".to_string(),
+            TransactionEvent::HoldPlaced { .. } => "HoldPlaced".to_string(),
+            TransactionEvent::HoldCaptured { .. } => "HoldCaptured".to_string(),
+            TransactionEvent::HoldReleased { .. } => "HoldReleased".to_string(),
+            TransactionEvent::OverdraftUsed { .. } => "OverdraftUsed".to_string(),
+            TransactionEvent::OverdraftRepaid { .. } => "OverdraftRepaid".to_string(),
+            TransactionEvent::DepositQuarantined { .. } => "DepositQuarantined".to_string(),
+            TransactionEvent::QuarantineReleased { .. } => "QuarantineReleased".to_string(),
+            TransactionEvent::FundsEarmarked { .. } => "FundsEarmarked".to_string(),
+            TransactionEvent::EarmarkReleased { .. } => "EarmarkReleased".to_string(),
+            TransactionEvent::AssetConverted { .. } => "AssetConverted".to_string(),
         }
     }
 }
@@ -260,11 +645,7 @@ impl DomainEvent for AccountEvent {
             AccountEvent::Lifecycle(account_event) => {
                 format!("Lifecycle::{}", account_event.event_name())
             }
-            AccountEvent::Transaction {
-                timestamp: _,
-                txid: _,
-                event,
-            } => format!("Transaction::{}", event.event_name()),
+            AccountEvent::Transaction { event, .. } => format!("Transaction::{}", event.event_name()),
         }
     }
 
@@ -287,6 +668,10 @@ pub enum AccountError {
     AccountNotInService,
     #[error("Account is not empty")]
     AccountNotEmpty,
+    #[error("Account is being closed")]
+    AccountClosing,
+    #[error("Closure of the account is not due before {0}")]
+    ClosureNotDue(u64),
     #[error("Lock not found, please check the transaction id and make sure it not expired")]
     LockNotFound,
     #[error("Invalid transaction")]
@@ -297,4 +682,79 @@ pub enum AccountError {
     DuplicateTransaction(u64),
     #[error("Transaction not found, please check the transaction and make sure it not expired")]
     TransactionNotFound,
+    #[error("Asset {0} is not registered")]
+    AssetNotRegistered(String),
+    #[error("Asset {0} is disabled")]
+    AssetDisabled(String),
+    #[error("Hold {0} not found")]
+    HoldNotFound(String),
+    #[error("Hold {0} already exists")]
+    DuplicateHold(String),
+    #[error("Amount exceeds the {0} left on the hold")]
+    HoldExceeded(u64),
+    #[error("No quarantined deposit under this transaction id")]
+    QuarantineNotFound,
+    #[error("Earmark {0} not found")]
+    EarmarkNotFound(String),
+    #[error("Earmark {0} already exists")]
+    DuplicateEarmark(String),
+    #[error("Earmark {0} is reserved for a quarantined deposit")]
+    ReservedEarmark(String),
+    #[error("Value date {0} is outside of the backdating window")]
+    ValueDateOutOfWindow(NaiveDate),
+    #[error("Business day {0} is closed")]
+    BusinessDayClosed(NaiveDate),
+    #[error("Asset {0} is not held by the account")]
+    AssetNotHeld(String),
+    #[error("Asset {0} is not migrated into this asset at this ratio")]
+    MigrationMismatch(String),
+    #[error("{} of the batched commands were rejected", .0.len())]
+    BatchRejected(Vec<(usize, AccountError)>),
+    #[error("Cannot tell whether the transaction was processed: {0}")]
+    DuplicateCheckFailed(String),
+    #[error("Policy violation: {0}")]
+    PolicyViolation(String),
+    #[error("atm rule violation")]
+    AtmRuleViolation,
+    #[error("check invalid")]
+    CheckInvalid,
+}
+
+#[cfg(feature = "server")]
+impl ErrorVariant for AccountError {
+    fn variant(&self) -> &'static str {
+        match self {
+            AccountError::InsufficientFunds => "InsufficientFunds",
+            AccountError::AtmRuleViolation => "AtmRuleViolation",
+            AccountError::CheckInvalid => "CheckInvalid",
+            AccountError::AccountNotFound => "AccountNotFound",
+            AccountError::AccountAlreadyExists => "AccountAlreadyExists",
+            AccountError::AccountNotDisabled => "AccountNotDisabled",
+            AccountError::AccountNotInService => "AccountNotInService",
+            AccountError::AccountNotEmpty => "AccountNotEmpty",
+            AccountError::AccountClosing => "AccountClosing",
+            AccountError::ClosureNotDue(_) => "ClosureNotDue",
+            AccountError::LockNotFound => "LockNotFound",
+            AccountError::InvalidTransaction => "InvalidTransaction",
+            AccountError::DuplicateLock => "DuplicateLock",
+            AccountError::DuplicateTransaction(_) => "DuplicateTransaction",
+            AccountError::TransactionNotFound => "TransactionNotFound",
+            AccountError::AssetNotRegistered(_) => "AssetNotRegistered",
+            AccountError::AssetDisabled(_) => "AssetDisabled",
+            AccountError::HoldNotFound(_) => "HoldNotFound",
+            AccountError::DuplicateHold(_) => "DuplicateHold",
+            AccountError::HoldExceeded(_) => "HoldExceeded",
+            AccountError::QuarantineNotFound => "QuarantineNotFound",
+            AccountError::EarmarkNotFound(_) => "EarmarkNotFound",
+            AccountError::DuplicateEarmark(_) => "DuplicateEarmark",
+            AccountError::ReservedEarmark(_) => "ReservedEarmark",
+            AccountError::ValueDateOutOfWindow(_) => "ValueDateOutOfWindow",
+            AccountError::BusinessDayClosed(_) => "BusinessDayClosed",
+            AccountError::AssetNotHeld(_) => "AssetNotHeld",
+            AccountError::MigrationMismatch(_) => "MigrationMismatch",
+            AccountError::DuplicateCheckFailed(_) => "DuplicateCheckFailed",
+            AccountError::BatchRejected(_) => "BatchRejected",
+            AccountError::PolicyViolation(_) => "PolicyViolation",
+        }
+    }
 }
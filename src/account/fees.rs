@@ -0,0 +1,215 @@
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use cqrs_es::persist::{PersistenceError, SerializedEvent};
+use cqrs_es::{Aggregate, AggregateError, EventEnvelope, Query};
+use crate::background::{Background, Shutdown};
+use crate::commit_hooks::{persistence_error, CommitHook};
+use crate::sealing::SealedCqrs;
+use sqlx::{Pool, Postgres, Row, Transaction};
+use tokio::sync::Notify;
+use crate::account::aggregate::Account;
+use crate::account::commands::AccountCommand;
+use crate::account::events::{AccountError, AccountEvent, TransactionEvent};
+use crate::util::types::ByteArray32;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+const SWEEP_BATCH: i64 = 500;
+
+// Wakes the `FeeCollector` up when fees are charged.
+pub struct FeeQuery {
+    charged: Arc<Notify>,
+}
+
+impl FeeQuery {
+    pub fn channel() -> (Self, Arc<Notify>) {
+        let charged = Arc::new(Notify::new());
+        (FeeQuery { charged: charged.clone() }, charged)
+    }
+}
+
+#[async_trait]
+impl Query<Account> for FeeQuery {
+    async fn dispatch(&self, _aggregate_id: &str, events: &[EventEnvelope<Account>]) {
+        let charged = events.iter().any(|e| matches!(
+            e.payload,
+            AccountEvent::Transaction { event: TransactionEvent::FeeCharged { .. }, .. }
+        ));
+        if charged {
+            self.charged.notify_one();
+        }
+    }
+}
+
+// Records every fee charged into `pending_fee` in the transaction charging it, see
+// `crate::commit_hooks`, so a fee is never debited without the credit it owes the
+// collection account. Writing a row again is a no-op.
+pub struct FeeHook;
+
+#[async_trait]
+impl CommitHook for FeeHook {
+    async fn append(&self, tx: &mut Transaction<'_, Postgres>, events: &[SerializedEvent]) -> Result<(), PersistenceError> {
+        for event in events.iter().filter(|event| event.aggregate_type == Account::aggregate_type()) {
+            let payload = serde_json::from_value(event.payload.clone()).map_err(|e| PersistenceError::DeserializationError(Box::new(e)))?;
+            let AccountEvent::Transaction { txid, timestamp, event: TransactionEvent::FeeCharged { to_account, asset, amount }, .. } = payload else {
+                continue;
+            };
+            sqlx::query(
+                "INSERT INTO pending_fee (account_id, txid, timestamp, to_account, asset, amount) VALUES ($1, $2, $3, $4, $5, $6)
+                 ON CONFLICT (account_id, txid) DO NOTHING",
+            )
+                .bind(&event.aggregate_id)
+                .bind(txid.hex())
+                .bind(timestamp as i64)
+                .bind(&to_account)
+                .bind(&asset)
+                .bind(amount as i64)
+                .execute(&mut **tx)
+                .await
+                .map_err(persistence_error)?;
+        }
+        Ok(())
+    }
+}
+
+// A charged fee is the debit leg of a transfer to the fee collection account; the
+// collector runs the credit leg under the same txid, so crediting twice is rejected as
+// a duplicate, and only then drops the fee from `pending_fee`. Fees charged before a
+// crash or while the collection account was not open yet stay there until credited.
+pub struct FeeCollector {
+    account_cqrs: Arc<SealedCqrs<Account>>,
+    pool: Pool<Postgres>,
+    charged: Arc<Notify>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingFee {
+    pub account_id: String,
+    pub txid: String,
+    pub timestamp: u64,
+    pub to_account: String,
+    pub asset: String,
+    pub amount: u64,
+}
+
+impl FeeCollector {
+    pub fn new(account_cqrs: Arc<SealedCqrs<Account>>, pool: Pool<Postgres>, charged: Arc<Notify>) -> Self {
+        FeeCollector { account_cqrs, pool, charged }
+    }
+
+    pub fn spawn(self, background: &Background) {
+        background.spawn(|shutdown| async move { self.run(shutdown).await });
+    }
+
+    async fn run(&self, mut shutdown: Shutdown) {
+        loop {
+            match self.sweep().await {
+                // A batch fully collected means more may be waiting.
+                Ok(collected) if collected as i64 == SWEEP_BATCH && !shutdown.is_stopping() => continue,
+                Ok(_) => {}
+                Err(e) => tracing::error!("Failed to sweep pending fees: {}", e),
+            }
+            tokio::select! {
+                _ = self.charged.notified() => {}
+                _ = tokio::time::sleep(SWEEP_INTERVAL) => {}
+                _ = shutdown.stopped() => break,
+            }
+        }
+    }
+
+    async fn sweep(&self) -> Result<usize, sqlx::Error> {
+        let mut collected = 0;
+        for fee in pending_fees(&self.pool, SWEEP_BATCH).await? {
+            let Ok(txid) = fee.txid.parse::<ByteArray32>() else {
+                tracing::error!("Fee of {} has a malformed txid {}", fee.account_id, fee.txid);
+                continue;
+            };
+            let command = AccountCommand::credit(txid, fee.timestamp, fee.account_id.clone(), fee.asset.clone(), fee.amount);
+            // Stays pending and is retried on the next sweep when it fails, e.g. while the
+            // collection account is not open.
+            match self.account_cqrs.execute(&fee.to_account, command).await {
+                Ok(_) | Err(AggregateError::UserError(AccountError::DuplicateTransaction(_))) => {
+                    collected_fee(&self.pool, &fee).await?;
+                    collected += 1;
+                }
+                Err(e) => tracing::error!("Failed to collect fee {} of {} into {}: {}", fee.txid, fee.account_id, fee.to_account, e),
+            }
+        }
+        Ok(collected)
+    }
+}
+
+// Fees without the matching credit on the collection account, oldest first.
+pub async fn pending_fees(pool: &Pool<Postgres>, limit: i64) -> Result<Vec<PendingFee>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT account_id, txid, timestamp, to_account, asset, amount FROM pending_fee
+         ORDER BY timestamp, account_id, txid
+         LIMIT $1",
+    )
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+    rows.iter()
+        .map(|row| Ok(PendingFee {
+            account_id: row.try_get("account_id")?,
+            txid: row.try_get("txid")?,
+            timestamp: row.try_get::<i64, _>("timestamp")? as u64,
+            to_account: row.try_get("to_account")?,
+            asset: row.try_get("asset")?,
+            amount: row.try_get::<i64, _>("amount")? as u64,
+        }))
+        .collect()
+}
+
+async fn collected_fee(pool: &Pool<Postgres>, fee: &PendingFee) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM pending_fee WHERE account_id = $1 AND txid = $2")
+        .bind(&fee.account_id)
+        .bind(&fee.txid)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::persist::SerializedEvent;
+    use cqrs_es::DomainEvent;
+    use rand::random;
+    use crate::account::events::AccountEvent;
+    use crate::account::fees::{collected_fee, pending_fees, FeeHook};
+    use crate::commit_hooks::CommitHook;
+    use crate::util::test_db::{random_suffix, test_pool};
+    use crate::util::types::ByteArray32;
+
+    #[tokio::test]
+    async fn test_pending_until_credited() {
+        let pool = test_pool();
+        let suffix = random_suffix();
+        let (payer, collector) = (format!("PAYER-{}", suffix), format!("FEES-{}", suffix));
+        let txid = ByteArray32(random());
+        let serialized = |sequence: usize, payload: AccountEvent| {
+            let (event_type, event_version) = (payload.event_type(), payload.event_version());
+            let payload = serde_json::to_value(payload).unwrap();
+            SerializedEvent::new(payer.clone(), sequence, "account".to_string(), event_type, event_version, payload, serde_json::json!({}))
+        };
+        let pending = || async {
+            pending_fees(&pool, 10_000).await.unwrap().into_iter().find(|fee| fee.account_id == payer)
+        };
+
+        // Only fees are recorded, once however often the hook runs.
+        let events = [
+            serialized(1, AccountEvent::withdrew(ByteArray32(random()), 0, "BTC".to_string(), 100)),
+            serialized(2, AccountEvent::fee_charged(txid, 0, collector.clone(), "BTC".to_string(), 5)),
+        ];
+        for _ in 0..2 {
+            let mut tx = pool.begin().await.unwrap();
+            FeeHook.append(&mut tx, &events).await.unwrap();
+            tx.commit().await.unwrap();
+        }
+        let fee = pending().await.unwrap();
+        assert_eq!((fee.txid.as_str(), fee.to_account.as_str(), fee.asset.as_str(), fee.amount), (txid.hex().as_str(), collector.as_str(), "BTC", 5));
+
+        collected_fee(&pool, &fee).await.unwrap();
+        assert_eq!(pending().await, None);
+    }
+}
@@ -0,0 +1,243 @@
+use async_trait::async_trait;
+use cqrs_es::{EventEnvelope, Query};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use sqlx::{Pool, Postgres, Row};
+use crate::account::aggregate::Account;
+use crate::account::events::{AccountEvent, TransactionEvent};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Debit,
+    Credit,
+}
+
+// One side of a movement between two accounts, as posted by one of them. The other
+// account posts the other side under the same txid.
+#[derive(Debug, PartialEq, Eq)]
+struct Leg<'a> {
+    side: Side,
+    debit_account: &'a str,
+    credit_account: &'a str,
+    asset: &'a str,
+    amount: u64,
+}
+
+// The legs a transaction of `account_id` posts. Funds leave the debit account and
+// reach the credit account; a reversal posts the movement back.
+fn legs<'a>(account_id: &'a str, event: &'a TransactionEvent) -> Vec<Leg<'a>> {
+    let debit = |to_account: &'a str, asset: &'a str, amount: u64| Leg { side: Side::Debit, debit_account: account_id, credit_account: to_account, asset, amount };
+    let credit = |from_account: &'a str, asset: &'a str, amount: u64| Leg { side: Side::Credit, debit_account: from_account, credit_account: account_id, asset, amount };
+    match event {
+        TransactionEvent::Debited { to_account, asset, amount }
+        | TransactionEvent::FeeCharged { to_account, asset, amount }
+        | TransactionEvent::HoldCaptured { to_account, asset, amount, .. } => vec![debit(to_account, asset, *amount)],
+        TransactionEvent::Credited { from_account, asset, amount } => vec![credit(from_account, asset, *amount)],
+        TransactionEvent::DebitReversed { to_account, asset, amount } => vec![credit(to_account, asset, *amount)],
+        TransactionEvent::CreditReversed { from_account, asset, amount } => vec![debit(from_account, asset, *amount)],
+        // Each side of a trade settles both assets, the counterparty posts the other legs.
+        TransactionEvent::Settled { to_account, send_asset, send_amount, receive_asset, receive_amount } => vec![
+            debit(to_account, send_asset, *send_amount),
+            credit(to_account, receive_asset, *receive_amount),
+        ],
+        // Deposits and withdrawals move funds in and out of the system, locks, holds,
+        // quarantine and earmarks stay on the account, and overdrafts are between the
+        // account and its credit line. Conversions change what the account holds, not where.
+        TransactionEvent::Deposited { .. }
+        | TransactionEvent::Withdrew { .. }
+        | TransactionEvent::FundsLocked { .. }
+        | TransactionEvent::FundsUnlocked { .. }
+        | TransactionEvent::HoldPlaced { .. }
+        | TransactionEvent::HoldReleased { .. }
+        | TransactionEvent::OverdraftUsed { .. }
+        | TransactionEvent::OverdraftRepaid { .. }
+        | TransactionEvent::DepositQuarantined { .. }
+        | TransactionEvent::QuarantineReleased { .. }
+        | TransactionEvent::FundsEarmarked { .. }
+        | TransactionEvent::EarmarkReleased { .. }
+        | TransactionEvent::AssetConverted { .. } => vec![],
+    }
+}
+
+// Materializes the movements between accounts into a double-entry `journal`, one row
+// per movement holding the debit leg of the sender and the credit leg of the receiver.
+// A row is complete once both accounts have posted their side; until then it is a
+// movement in flight, or a broken pair when it stays that way.
+pub struct JournalQuery {
+    pool: Pool<Postgres>,
+}
+
+impl JournalQuery {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        JournalQuery { pool }
+    }
+}
+
+#[async_trait]
+impl Query<Account> for JournalQuery {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<Account>]) {
+        if let Err(e) = write_journal_entries(&self.pool, events).await {
+            tracing::error!("Failed to write journal entries of {}: {}", aggregate_id, e);
+        }
+    }
+}
+
+// A leg already posted is kept as is, so writing the same events again is a no-op;
+// the projection rebuild relies on it to backfill history.
+pub async fn write_journal_entries(pool: &Pool<Postgres>, events: &[EventEnvelope<Account>]) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    for event in events {
+        let AccountEvent::Transaction { timestamp, txid, event: transaction, .. } = &event.payload else {
+            continue;
+        };
+        for leg in legs(&event.aggregate_id, transaction) {
+            let posted = (Some(event.sequence as i64), Some(leg.amount as i64));
+            let ((debit_sequence, debit_amount), (credit_sequence, credit_amount)) = match leg.side {
+                Side::Debit => (posted, (None, None)),
+                Side::Credit => ((None, None), posted),
+            };
+            sqlx::query(
+                "INSERT INTO journal (txid, asset, debit_account, credit_account, debit_sequence, debit_amount, credit_sequence, credit_amount, timestamp)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                 ON CONFLICT (txid, asset, debit_account, credit_account) DO UPDATE SET
+                     debit_sequence = COALESCE(journal.debit_sequence, EXCLUDED.debit_sequence),
+                     debit_amount = COALESCE(journal.debit_amount, EXCLUDED.debit_amount),
+                     credit_sequence = COALESCE(journal.credit_sequence, EXCLUDED.credit_sequence),
+                     credit_amount = COALESCE(journal.credit_amount, EXCLUDED.credit_amount),
+                     timestamp = LEAST(journal.timestamp, EXCLUDED.timestamp)",
+            )
+                .bind(txid.hex())
+                .bind(leg.asset)
+                .bind(leg.debit_account)
+                .bind(leg.credit_account)
+                .bind(debit_sequence)
+                .bind(debit_amount)
+                .bind(credit_sequence)
+                .bind(credit_amount)
+                .bind(*timestamp as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct AssetBalance {
+    pub asset: String,
+    // Totals of the legs posted so far.
+    pub debits: u64,
+    pub credits: u64,
+    // Transactions in the asset whose legs do not net out, those in flight included.
+    pub unmatched: u64,
+    pub balanced: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TrialBalance {
+    pub assets: Vec<AssetBalance>,
+    // Every asset nets to zero across all accounts.
+    pub balanced: bool,
+}
+
+// Sums both sides of the journal per asset. Funds only move between accounts here,
+// so debits equal credits unless a movement is still in flight or one side of it was
+// lost; the unmatched transactions tell which. A debit reversed before the credit
+// leaves two half rows under its txid, which net out.
+pub async fn trial_balance(pool: &Pool<Postgres>) -> Result<TrialBalance, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT asset, SUM(debits)::bigint AS debits, SUM(credits)::bigint AS credits, COUNT(*) FILTER (WHERE debits <> credits) AS unmatched
+         FROM (
+             SELECT txid, asset, COALESCE(SUM(debit_amount), 0) AS debits, COALESCE(SUM(credit_amount), 0) AS credits
+             FROM journal
+             GROUP BY txid, asset
+         ) transactions
+         GROUP BY asset
+         ORDER BY asset",
+    )
+        .fetch_all(pool)
+        .await?;
+    let mut assets = Vec::with_capacity(rows.len());
+    for row in rows {
+        let debits = row.try_get::<i64, _>("debits")? as u64;
+        let credits = row.try_get::<i64, _>("credits")? as u64;
+        assets.push(AssetBalance {
+            asset: row.try_get("asset")?,
+            debits,
+            credits,
+            unmatched: row.try_get::<i64, _>("unmatched")? as u64,
+            balanced: debits == credits,
+        });
+    }
+    let balanced = assets.iter().all(|asset| asset.balanced);
+    Ok(TrialBalance { assets, balanced })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use cqrs_es::{EventEnvelope, Query};
+    use rand::random;
+    use crate::account::aggregate::Account;
+    use crate::account::events::AccountEvent;
+    use crate::account::journal::{trial_balance, AssetBalance, JournalQuery};
+    use crate::util::test_db::{random_suffix, test_pool};
+    use crate::util::types::ByteArray32;
+
+    #[tokio::test]
+    async fn test_trial_balance() {
+        let pool = test_pool();
+        let suffix = random_suffix().to_uppercase();
+        let (usdt, btc) = (format!("USDT{}", suffix), format!("BTC{}", suffix));
+        let (alice, bob) = (format!("ALICE-{}", suffix), format!("BOB-{}", suffix));
+        let (paid, refunded, traded, pending) = (ByteArray32(random()), ByteArray32(random()), ByteArray32(random()), ByteArray32(random()));
+        let envelope = |account_id: &str, sequence, payload| EventEnvelope::<Account> {
+            aggregate_id: account_id.to_string(),
+            sequence,
+            payload,
+            metadata: HashMap::new(),
+        };
+        let alice_events = vec![
+            envelope(&alice, 1, AccountEvent::debited(paid, 10, bob.clone(), usdt.clone(), 300)),
+            // Reversed before bob was credited.
+            envelope(&alice, 2, AccountEvent::debited(refunded, 20, bob.clone(), usdt.clone(), 50)),
+            envelope(&alice, 3, AccountEvent::debit_reversed(refunded, 30, bob.clone(), usdt.clone(), 50)),
+            envelope(&alice, 4, AccountEvent::settlement(traded, 40, bob.clone(), btc.clone(), 2, usdt.clone(), 700)),
+        ];
+        let bob_events = vec![
+            envelope(&bob, 1, AccountEvent::credited(paid, 10, alice.clone(), usdt.clone(), 300)),
+            envelope(&bob, 2, AccountEvent::settlement(traded, 40, alice.clone(), usdt.clone(), 700, btc.clone(), 2)),
+        ];
+        let query = JournalQuery::new(pool.clone());
+        query.dispatch(&alice, &alice_events).await;
+        query.dispatch(&bob, &bob_events).await;
+        query.dispatch(&alice, &alice_events).await;
+
+        let of_test = |assets: Vec<AssetBalance>| assets.into_iter().filter(|asset| asset.asset.ends_with(&suffix)).collect::<Vec<_>>();
+        let balance = |asset: &str, debits, credits, unmatched| AssetBalance { asset: asset.to_string(), debits, credits, unmatched, balanced: debits == credits };
+        assert_eq!(of_test(trial_balance(&pool).await.unwrap().assets), vec![
+            balance(&btc, 2, 2, 0),
+            balance(&usdt, 1_050, 1_050, 0),
+        ]);
+
+        // In flight until bob is credited.
+        query.dispatch(&alice, &[envelope(&alice, 5, AccountEvent::debited(pending, 50, bob.clone(), usdt.clone(), 25))]).await;
+        assert_eq!(of_test(trial_balance(&pool).await.unwrap().assets), vec![
+            balance(&btc, 2, 2, 0),
+            balance(&usdt, 1_075, 1_050, 1),
+        ]);
+        query.dispatch(&bob, &[envelope(&bob, 3, AccountEvent::credited(pending, 50, alice.clone(), usdt.clone(), 25))]).await;
+        assert_eq!(of_test(trial_balance(&pool).await.unwrap().assets), vec![
+            balance(&btc, 2, 2, 0),
+            balance(&usdt, 1_075, 1_075, 0),
+        ]);
+
+        sqlx::query("DELETE FROM journal WHERE asset IN ($1, $2)")
+            .bind(&usdt)
+            .bind(&btc)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,714 @@
+use std::collections::BTreeMap;
+use async_trait::async_trait;
+use axum::body::Bytes;
+use cqrs_es::{EventEnvelope, Query};
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use serde_json::Value;
+use sqlx::postgres::PgRow;
+use sqlx::{Pool, Postgres, Row};
+use crate::account::aggregate::Account;
+use crate::account::counterparty::CounterpartyNames;
+use crate::account::events::{AccountEvent, BalanceSnapshot, TransactionEvent, TransactionLabels};
+use crate::account::queries::LedgerDetail;
+use crate::rates::{value_at, write_rate, RateError, Valuation};
+use crate::util::metadata::EventOrigin;
+
+pub const DEFAULT_PAGE_SIZE: i64 = 100;
+pub const MAX_PAGE_SIZE: i64 = 1000;
+// Entries read and held at a time while exporting.
+pub const EXPORT_PAGE_SIZE: i64 = MAX_PAGE_SIZE;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LedgerError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Malformed ledger entry {0}: {1}")]
+    Payload(String, serde_json::Error),
+    #[error("Invalid cursor: {0}")]
+    InvalidCursor(String),
+    #[error("Cannot write CSV: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("Rate error: {0}")]
+    Rate(#[from] RateError),
+    #[error("Unknown column: {0}")]
+    UnknownColumn(String),
+    #[error("Invalid search: {0:?}")]
+    InvalidSearch(String),
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct StatementEntry {
+    pub sequence: i64,
+    pub timestamp: u64,
+    // The business day the transaction is booked on, see `crate::business_day`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value_date: Option<String>,
+    pub txid: String,
+    pub detail: LedgerDetail,
+    // The display name of the counterparty, when it set one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counterparty_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balance_after: Option<BTreeMap<String, BalanceSnapshot>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<EventOrigin>,
+    #[serde(flatten)]
+    pub labels: TransactionLabels,
+    // Only with `value_in`, and absent when no rate was known at the time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub valuation: Option<Valuation>,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StatementFilter {
+    // Inclusive lower and exclusive upper bound on the transaction timestamp.
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    // `next_cursor` of the previous page.
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+    // Values every entry in this asset at the rate of its time, see `crate::rates`.
+    pub value_in: Option<String>,
+    // Only the transactions given this category.
+    pub category: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportParams {
+    // Comma separated columns of the CSV, in the order given. All of them by default,
+    // the valuation columns only with `value_in`.
+    pub columns: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchParams {
+    // Words to find in the memos, see `search_ledger`.
+    pub q: String,
+    // `next_cursor` of the previous page.
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SearchMatch {
+    #[serde(flatten)]
+    pub entry: StatementEntry,
+    // The matching memo fields with the matched words in `<mark>` tags, by field.
+    pub highlights: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SearchPage {
+    pub matches: Vec<SearchMatch>,
+    // Absent on the last page.
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct StatementPage {
+    pub entries: Vec<StatementEntry>,
+    // Absent on the last page.
+    pub next_cursor: Option<String>,
+}
+
+// One line of a CSV statement. Settlements send `asset` and receive `receive_asset`,
+// every other transaction moves `asset` only.
+#[derive(Debug)]
+pub struct StatementRow {
+    pub sequence: i64,
+    pub timestamp: u64,
+    pub txid: String,
+    pub kind: &'static str,
+    pub counterparty: Option<String>,
+    pub asset: String,
+    pub amount: u64,
+    pub receive_asset: Option<String>,
+    pub receive_amount: Option<u64>,
+    pub initiator: Option<String>,
+    pub correlation_id: Option<String>,
+    pub category: Option<String>,
+    pub counterparty_name: Option<String>,
+}
+
+impl StatementRow {
+    // Columns added later go last, so exports keep the column positions they had.
+    const HEADERS: [&'static str; 13] = [
+        "sequence", "timestamp", "txid", "type", "counterparty", "asset", "amount",
+        "receive_asset", "receive_amount", "initiator", "correlation_id", "category", "counterparty_name",
+    ];
+
+    // The fields in the order of `HEADERS`, absent ones empty.
+    fn fields(self) -> [String; 13] {
+        [
+            self.sequence.to_string(),
+            self.timestamp.to_string(),
+            self.txid,
+            self.kind.to_string(),
+            self.counterparty.unwrap_or_default(),
+            self.asset,
+            self.amount.to_string(),
+            self.receive_asset.unwrap_or_default(),
+            optional(self.receive_amount),
+            self.initiator.unwrap_or_default(),
+            self.correlation_id.unwrap_or_default(),
+            self.category.unwrap_or_default(),
+            self.counterparty_name.unwrap_or_default(),
+        ]
+    }
+}
+
+fn optional(value: Option<u64>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+// Appended to every `StatementRow` of an export with `value_in`.
+#[derive(Debug)]
+pub struct ValuationColumns {
+    pub value_asset: String,
+    pub value: Option<u64>,
+    pub rate_timestamp: Option<u64>,
+}
+
+impl ValuationColumns {
+    const HEADERS: [&'static str; 3] = ["value_asset", "value", "rate_timestamp"];
+
+    fn new(value_in: &str, valuation: Option<&Valuation>) -> Self {
+        ValuationColumns {
+            value_asset: value_in.to_string(),
+            value: valuation.map(|valuation| valuation.value),
+            rate_timestamp: valuation.and_then(|valuation| valuation.rate_timestamp),
+        }
+    }
+
+    fn fields(self) -> [String; 3] {
+        [self.value_asset, optional(self.value), optional(self.rate_timestamp)]
+    }
+}
+
+impl From<StatementEntry> for StatementRow {
+    fn from(entry: StatementEntry) -> Self {
+        let (kind, counterparty, asset, amount, receive) = match entry.detail {
+            LedgerDetail::Deposit { asset, amount } => ("Deposit", None, asset, amount, None),
+            LedgerDetail::Withdraw { asset, amount } => ("Withdraw", None, asset, amount, None),
+            LedgerDetail::Debited { to_account, asset, amount } => ("Debited", Some(to_account), asset, amount, None),
+            LedgerDetail::DebitReversed { to_account, asset, amount } => ("DebitReversed", Some(to_account), asset, amount, None),
+            LedgerDetail::Credited { from_account, asset, amount } => ("Credited", Some(from_account), asset, amount, None),
+            LedgerDetail::CreditReversed { from_account, asset, amount } => ("CreditReversed", Some(from_account), asset, amount, None),
+            LedgerDetail::Lock { asset, amount } => ("Lock", None, asset, amount, None),
+            LedgerDetail::Unlock { asset, amount } => ("Unlock", None, asset, amount, None),
+            LedgerDetail::Settlement { to_account, send_asset, send_amount, receive_asset, receive_amount } => {
+                ("Settlement", Some(to_account), send_asset, send_amount, Some((receive_asset, receive_amount)))
+            }
+            LedgerDetail::Fee { to_account, asset, amount } => ("Fee", Some(to_account), asset, amount, None),
+            LedgerDetail::Hold { asset, amount, .. } => ("Hold", None, asset, amount, None),
+            LedgerDetail::HoldCapture { to_account, asset, amount, .. } => ("HoldCapture", Some(to_account), asset, amount, None),
+            LedgerDetail::HoldRelease { asset, amount, .. } => ("HoldRelease", None, asset, amount, None),
+            LedgerDetail::Overdraft { asset, amount, .. } => ("Overdraft", None, asset, amount, None),
+            LedgerDetail::OverdraftRepayment { asset, amount } => ("OverdraftRepayment", None, asset, amount, None),
+            LedgerDetail::Quarantine { asset, amount, source } => ("Quarantine", Some(source), asset, amount, None),
+            LedgerDetail::QuarantineRelease { asset, amount } => ("QuarantineRelease", None, asset, amount, None),
+            LedgerDetail::Earmark { asset, amount, .. } => ("Earmark", None, asset, amount, None),
+            LedgerDetail::EarmarkRelease { asset, amount, .. } => ("EarmarkRelease", None, asset, amount, None),
+            LedgerDetail::AssetConversion { from_asset, from_amount, to_asset, to_amount } => {
+                ("AssetConversion", None, from_asset, from_amount, Some((to_asset, to_amount)))
+            }
+        };
+        let origin = entry.origin.unwrap_or_default();
+        let (receive_asset, receive_amount) = receive.unzip();
+        StatementRow {
+            sequence: entry.sequence,
+            timestamp: entry.timestamp,
+            txid: entry.txid,
+            kind,
+            counterparty,
+            counterparty_name: entry.counterparty_name,
+            asset,
+            amount,
+            receive_asset,
+            receive_amount,
+            initiator: origin.initiator,
+            correlation_id: origin.correlation_id,
+            category: entry.labels.category,
+        }
+    }
+}
+
+// Keeps the full transaction history of every account in `ledger_entries`, one row
+// per transaction event. `AccountView` only holds the most recent entries; this is
+// what account statements are served from.
+pub struct LedgerQuery {
+    pool: Pool<Postgres>,
+}
+
+impl LedgerQuery {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        LedgerQuery { pool }
+    }
+}
+
+#[async_trait]
+impl Query<Account> for LedgerQuery {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<Account>]) {
+        if let Err(e) = write_ledger_entries(&self.pool, events).await {
+            tracing::error!("Failed to write ledger entries of {}: {}", aggregate_id, e);
+        }
+    }
+}
+
+// Rows are keyed by (account_id, sequence), so writing the same events again is a no-op;
+// the projection rebuild relies on it to backfill history.
+pub async fn write_ledger_entries(pool: &Pool<Postgres>, events: &[EventEnvelope<Account>]) -> Result<(), LedgerError> {
+    let mut tx = pool.begin().await?;
+    for event in events {
+        let AccountEvent::Transaction { timestamp, txid, event: transaction, balance_after, value_date, labels } = &event.payload else {
+            continue;
+        };
+        let context = || format!("{}-{}", event.aggregate_id, event.sequence);
+        let detail = LedgerDetail::from(transaction);
+        let counterparty = detail.counterparty().map(str::to_string);
+        let detail = serde_json::to_value(detail).map_err(|e| LedgerError::Payload(context(), e))?;
+        let balance_after = balance_after.as_ref()
+            .map(serde_json::to_value)
+            .transpose()
+            .map_err(|e| LedgerError::Payload(context(), e))?;
+        let origin = EventOrigin::from_metadata(&event.metadata)
+            .map(|origin| serde_json::to_value(&origin))
+            .transpose()
+            .map_err(|e| LedgerError::Payload(context(), e))?;
+        let metadata = (!labels.metadata.is_empty())
+            .then(|| serde_json::to_value(&labels.metadata))
+            .transpose()
+            .map_err(|e| LedgerError::Payload(context(), e))?;
+        if let TransactionEvent::Settled { send_asset, send_amount, receive_asset, receive_amount, .. } = transaction {
+            write_rate(&mut tx, &txid.hex(), *timestamp, (send_asset, *send_amount), (receive_asset, *receive_amount)).await?;
+        }
+        // Named after the counterparty as it is called at booking.
+        sqlx::query(
+            "INSERT INTO ledger_entries (account_id, sequence, txid, timestamp, detail, balance_after, origin, category, metadata, value_date, counterparty_name)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10::date, (SELECT display_name FROM counterparty_name WHERE account_id = $11))
+             ON CONFLICT (account_id, sequence) DO NOTHING",
+        )
+            .bind(&event.aggregate_id)
+            .bind(event.sequence as i64)
+            .bind(txid.hex())
+            .bind(*timestamp as i64)
+            .bind(detail)
+            .bind(balance_after)
+            .bind(origin)
+            .bind(&labels.category)
+            .bind(metadata)
+            .bind(value_date.map(|date| date.to_string()))
+            .bind(counterparty)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+// Returns the transactions of an account newest first. The cursor is the sequence of
+// the last entry handed out, so pages stay stable while new transactions come in.
+pub async fn load_statement(pool: &Pool<Postgres>, names: &CounterpartyNames, account_id: &str, filter: &StatementFilter) -> Result<StatementPage, LedgerError> {
+    let before = parse_cursor(filter.cursor.as_deref())?;
+    let limit = filter.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let rows = sqlx::query(
+        "SELECT sequence, txid, timestamp, value_date::text AS value_date, detail, counterparty_name, balance_after, origin, category, metadata FROM ledger_entries
+         WHERE account_id = $1
+           AND ($2::bigint IS NULL OR timestamp >= $2)
+           AND ($3::bigint IS NULL OR timestamp < $3)
+           AND sequence < $4
+           AND ($6::text IS NULL OR category = $6)
+         ORDER BY sequence DESC
+         LIMIT $5",
+    )
+        .bind(account_id)
+        .bind(filter.from.map(|from| from as i64))
+        .bind(filter.to.map(|to| to as i64))
+        // A bound rather than `$4 IS NULL OR ...`, so the generic plan of the prepared
+        // statement still seeks the primary key instead of rescanning every later entry.
+        .bind(before.unwrap_or(i64::MAX))
+        .bind(limit + 1)
+        .bind(&filter.category)
+        .fetch_all(pool)
+        .await?;
+
+    let mut entries = rows.iter()
+        .map(|row| statement_entry(account_id, row))
+        .collect::<Result<Vec<_>, _>>()?;
+    name_counterparties(pool, names, account_id, &mut entries).await;
+    if let Some(value_in) = &filter.value_in {
+        let items: Vec<_> = entries.iter().map(|entry| {
+            let (asset, amount) = entry.detail.moved();
+            (asset, amount, entry.timestamp)
+        }).collect();
+        let valuations = value_at(pool, value_in, &items).await?;
+        for (entry, valuation) in entries.iter_mut().zip(valuations) {
+            entry.valuation = valuation;
+        }
+    }
+    let next_cursor = if entries.len() as i64 > limit {
+        entries.truncate(limit as usize);
+        entries.last().map(|entry| entry.sequence.to_string())
+    } else {
+        None
+    };
+    Ok(StatementPage { entries, next_cursor })
+}
+
+// Reads an entry selected with the columns of `load_statement`.
+fn statement_entry(account_id: &str, row: &PgRow) -> Result<StatementEntry, LedgerError> {
+    let sequence: i64 = row.try_get("sequence")?;
+    let context = || format!("{}-{}", account_id, sequence);
+    let detail: Value = row.try_get("detail")?;
+    let balance_after: Option<Value> = row.try_get("balance_after")?;
+    let origin: Option<Value> = row.try_get("origin")?;
+    let metadata: Option<Value> = row.try_get("metadata")?;
+    Ok(StatementEntry {
+        sequence,
+        timestamp: row.try_get::<i64, _>("timestamp")? as u64,
+        value_date: row.try_get("value_date")?,
+        txid: row.try_get("txid")?,
+        detail: serde_json::from_value(detail).map_err(|e| LedgerError::Payload(context(), e))?,
+        counterparty_name: row.try_get("counterparty_name")?,
+        balance_after: balance_after.map(serde_json::from_value).transpose().map_err(|e| LedgerError::Payload(context(), e))?,
+        origin: origin.map(serde_json::from_value).transpose().map_err(|e| LedgerError::Payload(context(), e))?,
+        labels: TransactionLabels {
+            category: row.try_get("category")?,
+            metadata: metadata.map(serde_json::from_value).transpose().map_err(|e| LedgerError::Payload(context(), e))?.unwrap_or_default(),
+        },
+        valuation: None,
+    })
+}
+
+// Searches the memos of an account, i.e. the category and metadata values of its
+// transactions, through the whole history. `q` takes web search syntax: words all have
+// to match, `"quoted phrases"` in order, `or` between alternatives and `-` before words
+// to leave out. Matches come newest first, paged like `load_statement`.
+pub async fn search_ledger(pool: &Pool<Postgres>, names: &CounterpartyNames, account_id: &str, params: &SearchParams) -> Result<SearchPage, LedgerError> {
+    let before = parse_cursor(params.cursor.as_deref())?;
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    if params.q.trim().is_empty() {
+        return Err(LedgerError::InvalidSearch(params.q.clone()));
+    }
+    // Each matching field is highlighted on its own, the category under `category` and
+    // metadata under `metadata.<key>`.
+    let rows = sqlx::query(
+        "SELECT sequence, txid, timestamp, value_date::text AS value_date, detail, counterparty_name, balance_after, origin, category, metadata,
+                (SELECT jsonb_object_agg(field, ts_headline('simple', value, search, 'StartSel=<mark>, StopSel=</mark>, HighlightAll=true'))
+                 FROM (SELECT 'category' AS field, category AS value
+                       UNION ALL SELECT 'metadata.' || key, value FROM jsonb_each_text(coalesce(metadata, '{}'))) AS fields
+                 WHERE value IS NOT NULL AND to_tsvector('simple', value) @@ search) AS highlights
+         FROM ledger_entries, websearch_to_tsquery('simple', $2) AS search
+         WHERE account_id = $1 AND memo @@ search AND sequence < $3
+         ORDER BY sequence DESC
+         LIMIT $4",
+    )
+        .bind(account_id)
+        .bind(&params.q)
+        .bind(before.unwrap_or(i64::MAX))
+        .bind(limit + 1)
+        .fetch_all(pool)
+        .await?;
+    let mut entries = rows.iter()
+        .map(|row| statement_entry(account_id, row))
+        .collect::<Result<Vec<_>, _>>()?;
+    let highlights = rows.iter()
+        .map(|row| {
+            let highlights: Option<Value> = row.try_get("highlights")?;
+            Ok(highlights.map(serde_json::from_value).transpose().map_err(|e| LedgerError::Payload(account_id.to_string(), e))?.unwrap_or_default())
+        })
+        .collect::<Result<Vec<BTreeMap<String, String>>, LedgerError>>()?;
+    name_counterparties(pool, names, account_id, &mut entries).await;
+    let mut matches: Vec<_> = entries.into_iter().zip(highlights).map(|(entry, highlights)| SearchMatch { entry, highlights }).collect();
+    let next_cursor = if matches.len() as i64 > limit {
+        matches.truncate(limit as usize);
+        matches.last().map(|found| found.entry.sequence.to_string())
+    } else {
+        None
+    };
+    Ok(SearchPage { matches, next_cursor })
+}
+
+// Names the counterparties of entries booked before they had a name, and keeps the names
+// on the entries so the next statement finds them there. Statements go out without the
+// names rather than fail on them.
+async fn name_counterparties(pool: &Pool<Postgres>, names: &CounterpartyNames, account_id: &str, entries: &mut [StatementEntry]) {
+    let unnamed: Vec<_> = entries.iter()
+        .filter(|entry| entry.counterparty_name.is_none())
+        .filter_map(|entry| entry.detail.counterparty())
+        .collect();
+    if unnamed.is_empty() {
+        return;
+    }
+    let resolved = match names.resolve(&unnamed).await {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            tracing::warn!("Failed to name the counterparties of {}: {}", account_id, e);
+            return;
+        }
+    };
+    let (mut sequences, mut backfilled) = (vec![], vec![]);
+    for entry in entries.iter_mut().filter(|entry| entry.counterparty_name.is_none()) {
+        if let Some(display_name) = entry.detail.counterparty().and_then(|counterparty| resolved.get(counterparty)) {
+            entry.counterparty_name = Some(display_name.clone());
+            sequences.push(entry.sequence);
+            backfilled.push(display_name.clone());
+        }
+    }
+    if sequences.is_empty() {
+        return;
+    }
+    let written = sqlx::query(
+        "UPDATE ledger_entries SET counterparty_name = named.display_name
+         FROM unnest($2::bigint[], $3::text[]) AS named (sequence, display_name)
+         WHERE account_id = $1 AND ledger_entries.sequence = named.sequence AND counterparty_name IS NULL",
+    )
+        .bind(account_id)
+        .bind(&sequences)
+        .bind(&backfilled)
+        .execute(pool)
+        .await;
+    if let Err(e) = written {
+        tracing::warn!("Failed to backfill the counterparty names of {}: {}", account_id, e);
+    }
+}
+
+pub(crate) fn parse_cursor(cursor: Option<&str>) -> Result<Option<i64>, LedgerError> {
+    cursor
+        .map(|cursor| cursor.parse::<i64>().map_err(|_| LedgerError::InvalidCursor(cursor.to_string())))
+        .transpose()
+}
+
+struct Export {
+    pool: Pool<Postgres>,
+    names: CounterpartyNames,
+    account_id: String,
+    filter: StatementFilter,
+    // Positions of the exported columns among all of them.
+    columns: Vec<usize>,
+    started: bool,
+    done: bool,
+}
+
+// Streams the statement of an account as CSV, newest first, one chunk per page of
+// `EXPORT_PAGE_SIZE` entries. Only the page being written is held in memory, however
+// long the history. `limit` is ignored; `cursor` resumes an export that broke off
+// after the entry it names. `columns` selects the columns, see `ExportParams`.
+pub fn export_statement(
+    pool: Pool<Postgres>,
+    names: CounterpartyNames,
+    account_id: String,
+    filter: StatementFilter,
+    columns: Option<&str>,
+) -> Result<impl Stream<Item = Result<Bytes, LedgerError>>, LedgerError> {
+    parse_cursor(filter.cursor.as_deref())?;
+    let headers = export_headers(&filter);
+    let columns = match columns {
+        None => (0..headers.len()).collect(),
+        Some(columns) => columns
+            .split(',')
+            .map(|column| headers.iter().position(|header| *header == column.trim()).ok_or_else(|| LedgerError::UnknownColumn(column.to_string())))
+            .collect::<Result<_, _>>()?,
+    };
+    let filter = StatementFilter { limit: Some(EXPORT_PAGE_SIZE), ..filter };
+    let export = Export { pool, names, account_id, filter, columns, started: false, done: false };
+    Ok(futures::stream::unfold(export, |mut export| async move {
+        if export.done {
+            return None;
+        }
+        let chunk = export.next_chunk().await;
+        // Nothing more can be read after a failed page.
+        export.done |= chunk.is_err();
+        Some((chunk, export))
+    }))
+}
+
+fn export_headers(filter: &StatementFilter) -> Vec<&'static str> {
+    let valuation = if filter.value_in.is_some() { &ValuationColumns::HEADERS[..] } else { &[] };
+    StatementRow::HEADERS.iter().chain(valuation).copied().collect()
+}
+
+impl Export {
+    async fn next_chunk(&mut self) -> Result<Bytes, LedgerError> {
+        let page = load_statement(&self.pool, &self.names, &self.account_id, &self.filter).await?;
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(vec![]);
+        let value_in = self.filter.value_in.as_deref();
+        if !self.started {
+            let headers = export_headers(&self.filter);
+            writer.write_record(self.columns.iter().map(|column| headers[*column]))?;
+            self.started = true;
+        }
+        for mut entry in page.entries {
+            let valuation = value_in.map(|value_in| ValuationColumns::new(value_in, entry.valuation.take().as_ref()).fields());
+            let fields: Vec<_> = StatementRow::from(entry).fields().into_iter().chain(valuation.into_iter().flatten()).collect();
+            writer.write_record(self.columns.iter().map(|column| &fields[*column]))?;
+        }
+        let chunk = writer.into_inner().map_err(|e| csv::Error::from(e.into_error()))?;
+        self.done = page.next_cursor.is_none();
+        self.filter.cursor = page.next_cursor;
+        Ok(Bytes::from(chunk))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use cqrs_es::{EventEnvelope, Query};
+    use rand::random;
+    use crate::account::aggregate::Account;
+    use crate::account::counterparty::CounterpartyNames;
+    use crate::account::events::{AccountEvent, TransactionLabels};
+    use crate::account::ledger::{export_statement, load_statement, search_ledger, write_ledger_entries, LedgerError, LedgerQuery, SearchParams, StatementFilter};
+    use crate::util::metadata::INITIATOR_KEY;
+    use crate::util::test_db::{delete_rows, random_suffix, test_pool};
+    use crate::util::types::ByteArray32;
+
+    #[tokio::test]
+    async fn test_statement_pages_through_history() {
+        let pool = test_pool();
+        let names = CounterpartyNames::new(pool.clone());
+        let account_id = format!("LEDGER-{}", random_suffix());
+        let mut events: Vec<EventEnvelope<Account>> = vec![
+            EventEnvelope { aggregate_id: account_id.clone(), sequence: 1, payload: AccountEvent::account_opened(account_id.clone()), metadata: HashMap::new() },
+        ];
+        for sequence in 2..=6 {
+            events.push(EventEnvelope {
+                aggregate_id: account_id.clone(),
+                sequence,
+                payload: AccountEvent::deposited(ByteArray32(random()), sequence as u64 * 10, "BTC".to_string(), 1),
+                metadata: HashMap::new(),
+            });
+        }
+        let query = LedgerQuery::new(pool.clone());
+        query.dispatch(&account_id, &events).await;
+        query.dispatch(&account_id, &events).await;
+
+        let filter = StatementFilter { limit: Some(3), ..Default::default() };
+        let first = load_statement(&pool, &names, &account_id, &filter).await.unwrap();
+        assert_eq!(first.entries.iter().map(|entry| entry.sequence).collect::<Vec<_>>(), vec![6, 5, 4]);
+        let filter = StatementFilter { limit: Some(3), cursor: first.next_cursor, ..Default::default() };
+        let second = load_statement(&pool, &names, &account_id, &filter).await.unwrap();
+        assert_eq!(second.entries.iter().map(|entry| entry.sequence).collect::<Vec<_>>(), vec![3, 2]);
+        assert_eq!(second.next_cursor, None);
+
+        let filter = StatementFilter { from: Some(30), to: Some(50), ..Default::default() };
+        let window = load_statement(&pool, &names, &account_id, &filter).await.unwrap();
+        assert_eq!(window.entries.iter().map(|entry| entry.timestamp).collect::<Vec<_>>(), vec![40, 30]);
+
+        delete_rows(&pool, "ledger_entries", "account_id", &account_id).await;
+    }
+
+    #[tokio::test]
+    async fn test_search_ledger_memos() {
+        let pool = test_pool();
+        let names = CounterpartyNames::new(pool.clone());
+        let account_id = format!("SEARCH-{}", random_suffix());
+        let labelled = |sequence: usize, category: Option<&str>, metadata: &[(&str, &str)]| EventEnvelope::<Account> {
+            aggregate_id: account_id.clone(),
+            sequence,
+            payload: AccountEvent::deposited(ByteArray32(random()), sequence as u64, "BTC".to_string(), 1).with_labels(TransactionLabels {
+                category: category.map(str::to_string),
+                metadata: metadata.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+            }),
+            metadata: HashMap::new(),
+        };
+        let events = vec![
+            labelled(1, Some("payroll"), &[("invoice", "INV-1001 March salary")]),
+            labelled(2, Some("refund"), &[("note", "Damaged parcel")]),
+            labelled(3, None, &[]),
+            labelled(4, Some("payroll"), &[("invoice", "INV-1002 April salary")]),
+        ];
+        write_ledger_entries(&pool, &events).await.unwrap();
+        let search = |q: &str, cursor: Option<String>| SearchParams { q: q.to_string(), cursor, limit: Some(1) };
+
+        let first = search_ledger(&pool, &names, &account_id, &search("salary", None)).await.unwrap();
+        assert_eq!(first.matches.iter().map(|found| found.entry.sequence).collect::<Vec<_>>(), vec![4]);
+        assert_eq!(first.matches[0].highlights.get("metadata.invoice").map(String::as_str), Some("INV-1002 April <mark>salary</mark>"));
+        let second = search_ledger(&pool, &names, &account_id, &search("salary", first.next_cursor)).await.unwrap();
+        assert_eq!(second.matches.iter().map(|found| found.entry.sequence).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(second.next_cursor, None);
+
+        // Categories match too, and words can be left out.
+        let params = SearchParams { limit: None, ..search("payroll -april", None) };
+        let page = search_ledger(&pool, &names, &account_id, &params).await.unwrap();
+        assert_eq!(page.matches.iter().map(|found| found.entry.sequence).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(page.matches[0].highlights.keys().collect::<Vec<_>>(), vec!["category"]);
+        let page = search_ledger(&pool, &names, &account_id, &search("parcel", None)).await.unwrap();
+        assert_eq!(page.matches[0].entry.labels.category.as_deref(), Some("refund"));
+        assert!(matches!(search_ledger(&pool, &names, &account_id, &search(" ", None)).await, Err(LedgerError::InvalidSearch(_))));
+
+        delete_rows(&pool, "ledger_entries", "account_id", &account_id).await;
+    }
+
+    #[tokio::test]
+    async fn test_export_statement_csv() {
+        use futures::TryStreamExt;
+
+        let pool = test_pool();
+        let names = CounterpartyNames::new(pool.clone());
+        let account_id = format!("EXPORT-{}", random_suffix());
+        let (deposit, settlement) = (ByteArray32::derive("test", "deposit"), ByteArray32::derive("test", "settlement"));
+        let events = vec![
+            EventEnvelope::<Account> {
+                aggregate_id: account_id.clone(),
+                sequence: 2,
+                payload: AccountEvent::deposited(deposit, 10, "USDT".to_string(), 500)
+                    .with_labels(TransactionLabels { category: Some("payroll".to_string()), ..Default::default() }),
+                metadata: HashMap::from([(INITIATOR_KEY.to_string(), "ops".to_string())]),
+            },
+            EventEnvelope::<Account> {
+                aggregate_id: account_id.clone(),
+                sequence: 3,
+                payload: AccountEvent::settlement(settlement, 20, "ACCT-0002".to_string(), "USDT".to_string(), 100, "BTC".to_string(), 1),
+                metadata: HashMap::new(),
+            },
+        ];
+        write_ledger_entries(&pool, &events).await.unwrap();
+        let export_columns = |cursor: Option<&str>, value_in: Option<&str>, columns: Option<&str>| {
+            let filter = StatementFilter { cursor: cursor.map(str::to_string), value_in: value_in.map(str::to_string), ..Default::default() };
+            let chunks = export_statement(pool.clone(), names.clone(), account_id.clone(), filter, columns).unwrap();
+            async move {
+                let chunks: Vec<_> = chunks.try_collect().await.unwrap();
+                String::from_utf8(chunks.concat()).unwrap()
+            }
+        };
+
+        let export_valued = |cursor, value_in| export_columns(cursor, value_in, None);
+        let export = |cursor| export_valued(cursor, None);
+        let header = "sequence,timestamp,txid,type,counterparty,asset,amount,receive_asset,receive_amount,initiator,correlation_id,category,counterparty_name";
+        let settled = format!("3,20,{},Settlement,ACCT-0002,USDT,100,BTC,1,,,,", settlement.hex());
+        let deposited = format!("2,10,{},Deposit,,USDT,500,,,ops,,payroll,", deposit.hex());
+        assert_eq!(export(None).await, format!("{}\n{}\n{}\n", header, settled, deposited));
+        // Resumes after the last entry received.
+        assert_eq!(export(Some("3")).await, format!("{}\n{}\n", header, deposited));
+        assert!(export_statement(pool.clone(), names.clone(), account_id.clone(), StatementFilter { cursor: Some("x".to_string()), ..Default::default() }, None).is_err());
+
+        // Only the columns asked for, in their order.
+        assert_eq!(export_columns(None, None, Some("amount,type,sequence")).await, "amount,type,sequence\n100,Settlement,3\n500,Deposit,2\n");
+        assert_eq!(export_columns(None, Some("BTC"), Some("sequence,value")).await, "sequence,value\n3,1\n2,\n");
+        assert!(export_statement(pool.clone(), names.clone(), account_id.clone(), StatementFilter::default(), Some("sequence,value")).is_err());
+
+        // Valued at the rate of the settlement itself, the deposit predates any BTC rate.
+        let valued = format!("{},value_asset,value,rate_timestamp\n{},BTC,1,20\n{},BTC,,\n", header, settled, deposited);
+        assert_eq!(export_valued(None, Some("BTC")).await, valued);
+        let filter = StatementFilter { value_in: Some("USDT".to_string()), ..Default::default() };
+        let page = load_statement(&pool, &names, &account_id, &filter).await.unwrap();
+        let values: Vec<_> = page.entries.iter().map(|entry| entry.valuation.as_ref().map(|valuation| valuation.value)).collect();
+        assert_eq!(values, vec![Some(100), Some(500)]);
+        let filter = StatementFilter { category: Some("payroll".to_string()), ..Default::default() };
+        let page = load_statement(&pool, &names, &account_id, &filter).await.unwrap();
+        assert_eq!(page.entries.iter().map(|entry| entry.txid.clone()).collect::<Vec<_>>(), vec![deposit.hex()]);
+
+        delete_rows(&pool, "ledger_entries", "account_id", &account_id).await;
+        sqlx::query("DELETE FROM rates WHERE txid = $1")
+            .bind(settlement.hex())
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
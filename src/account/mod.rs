@@ -1,4 +1,36 @@
+#[cfg(feature = "server")]
+pub mod accruals;
+#[cfg(feature = "server")]
 pub mod aggregate;
+#[cfg(feature = "server")]
+pub mod batch;
+#[cfg(feature = "server")]
+pub mod closure;
+#[cfg(feature = "server")]
+pub mod counterparty;
+#[cfg(feature = "server")]
+pub mod coverage;
+#[cfg(feature = "server")]
+pub mod dedup;
+#[cfg(feature = "server")]
+pub mod bulk;
+#[cfg(feature = "server")]
+pub mod canary;
 pub mod commands;
 pub mod events;
+#[cfg(feature = "server")]
+pub mod fees;
+#[cfg(feature = "server")]
+pub mod journal;
+#[cfg(feature = "server")]
+pub mod ledger;
+#[cfg(feature = "server")]
+pub mod ofx;
+#[cfg(feature = "server")]
+pub mod overdraft;
+#[cfg(feature = "server")]
 pub mod queries;
+#[cfg(feature = "server")]
+pub mod stats;
+#[cfg(feature = "server")]
+pub mod stream;
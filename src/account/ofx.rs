@@ -0,0 +1,291 @@
+use std::fmt::Write;
+use axum::body::Bytes;
+use chrono::NaiveDateTime;
+use futures::Stream;
+use serde::Deserialize;
+use utoipa::IntoParams;
+use crate::account::counterparty::CounterpartyNames;
+use crate::account::events::BalanceSnapshot;
+use crate::account::ledger::{load_statement, parse_cursor, LedgerError, StatementEntry, StatementFilter, EXPORT_PAGE_SIZE};
+use crate::account::queries::LedgerDetail;
+use crate::util::clock;
+
+// Reported as the bank of every statement.
+const BANK_ID: &str = "cqrs-account";
+
+const HEADER: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n\
+                      <?OFX OFXHEADER=\"200\" VERSION=\"220\" SECURITY=\"NONE\" OLDFILEUID=\"NONE\" NEWFILEUID=\"NONE\"?>\n";
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct OfxParams {
+    // The asset of the statement.
+    pub asset: String,
+}
+
+struct Export {
+    pool: sqlx::Pool<sqlx::Postgres>,
+    names: CounterpartyNames,
+    account_id: String,
+    filter: StatementFilter,
+    asset: String,
+    decimals: u8,
+    // Of the newest entry moving the asset, read on the first page it shows up on.
+    balance: Option<(u64, BalanceSnapshot)>,
+    started: bool,
+    done: bool,
+}
+
+// Streams the statement of an account in one asset as an OFX 2.2 bank statement, newest
+// first, one chunk per page of `EXPORT_PAGE_SIZE` entries like the CSV export. OFX
+// statements hold one currency, so the asset is named by the caller together with its
+// decimals; amounts are in whole units. The balances close the statement, as of the
+// newest entry moving the asset.
+//
+// Only what changes the balance of the account is listed: locks and holds keep funds on
+// the account and quarantined deposits are not on it until released. `cursor` resumes
+// an export that broke off, `value_in` is ignored.
+pub fn export_ofx(
+    pool: sqlx::Pool<sqlx::Postgres>,
+    names: CounterpartyNames,
+    account_id: String,
+    filter: StatementFilter,
+    asset: String,
+    decimals: u8,
+) -> Result<impl Stream<Item = Result<Bytes, LedgerError>>, LedgerError> {
+    parse_cursor(filter.cursor.as_deref())?;
+    let filter = StatementFilter { limit: Some(EXPORT_PAGE_SIZE), value_in: None, ..filter };
+    let export = Export { pool, names, account_id, filter, asset, decimals, balance: None, started: false, done: false };
+    Ok(futures::stream::unfold(export, |mut export| async move {
+        if export.done {
+            return None;
+        }
+        let chunk = export.next_chunk().await;
+        export.done |= chunk.is_err();
+        Some((chunk, export))
+    }))
+}
+
+impl Export {
+    async fn next_chunk(&mut self) -> Result<Bytes, LedgerError> {
+        let page = load_statement(&self.pool, &self.names, &self.account_id, &self.filter).await?;
+        let mut chunk = String::new();
+        if !self.started {
+            self.write_header(&mut chunk);
+            self.started = true;
+        }
+        for entry in page.entries {
+            if self.balance.is_none() {
+                let snapshot = entry.balance_after.as_ref().and_then(|balances| balances.get(&self.asset));
+                self.balance = snapshot.map(|snapshot| (entry.timestamp, snapshot.clone()));
+            }
+            self.write_transaction(&mut chunk, &entry);
+        }
+        self.done = page.next_cursor.is_none();
+        self.filter.cursor = page.next_cursor;
+        if self.done {
+            self.write_footer(&mut chunk);
+        }
+        Ok(Bytes::from(chunk))
+    }
+
+    fn write_header(&self, out: &mut String) {
+        let now = date(clock::now());
+        let start = date(self.filter.from.unwrap_or(0));
+        let end = self.filter.to.map(date).unwrap_or_else(|| now.clone());
+        out.push_str(HEADER);
+        let _ = write!(
+            out,
+            "<OFX>\n\
+             <SIGNONMSGSRSV1><SONRS><STATUS><CODE>0</CODE><SEVERITY>INFO</SEVERITY></STATUS><DTSERVER>{now}</DTSERVER><LANGUAGE>ENG</LANGUAGE></SONRS></SIGNONMSGSRSV1>\n\
+             <BANKMSGSRSV1><STMTTRNRS><TRNUID>0</TRNUID><STATUS><CODE>0</CODE><SEVERITY>INFO</SEVERITY></STATUS>\n\
+             <STMTRS><CURDEF>{asset}</CURDEF>\n\
+             <BANKACCTFROM><BANKID>{BANK_ID}</BANKID><ACCTID>{account}</ACCTID><ACCTTYPE>CHECKING</ACCTTYPE></BANKACCTFROM>\n\
+             <BANKTRANLIST><DTSTART>{start}</DTSTART><DTEND>{end}</DTEND>\n",
+            asset = escape(&self.asset),
+            account = escape(&self.account_id),
+        );
+    }
+
+    fn write_transaction(&self, out: &mut String, entry: &StatementEntry) {
+        let Some((kind, amount)) = movement(&entry.detail, &self.asset) else {
+            return;
+        };
+        // Counterparties by their name where they set one.
+        let memo = match entry.counterparty_name.as_deref().or(entry.detail.counterparty()) {
+            Some(counterparty) => format!("{} {}", kind, counterparty),
+            None => kind.to_string(),
+        };
+        let _ = writeln!(
+            out,
+            "<STMTTRN><TRNTYPE>{}</TRNTYPE><DTPOSTED>{}</DTPOSTED><TRNAMT>{}</TRNAMT><FITID>{}-{}</FITID><MEMO>{}</MEMO></STMTTRN>",
+            transaction_type(&entry.detail),
+            date(entry.timestamp),
+            units(amount, self.decimals),
+            entry.txid,
+            entry.sequence,
+            escape(&memo),
+        );
+    }
+
+    fn write_footer(&self, out: &mut String) {
+        // Nothing moved the asset, as far as the statement reaches.
+        let (timestamp, balance) = self.balance.clone().unwrap_or_else(|| (self.filter.to.unwrap_or_else(clock::now), BalanceSnapshot::default()));
+        let _ = write!(
+            out,
+            "</BANKTRANLIST>\n\
+             <LEDGERBAL><BALAMT>{}</BALAMT><DTASOF>{as_of}</DTASOF></LEDGERBAL>\n\
+             <AVAILBAL><BALAMT>{}</BALAMT><DTASOF>{as_of}</DTASOF></AVAILBAL>\n\
+             </STMTRS></STMTTRNRS></BANKMSGSRSV1>\n\
+             </OFX>\n",
+            units((balance.available + balance.locked) as i128, self.decimals),
+            units(balance.available as i128, self.decimals),
+            as_of = date(timestamp),
+        );
+    }
+}
+
+// The kind of a ledger entry and the amount of `asset` it adds to the account, negative
+// when it takes it out; `None` when it leaves the balance as is.
+fn movement(detail: &LedgerDetail, asset: &str) -> Option<(&'static str, i128)> {
+    let (kind, moved, amount, sign) = match detail {
+        LedgerDetail::Deposit { asset, amount } => ("Deposit", asset, amount, 1),
+        LedgerDetail::Withdraw { asset, amount } => ("Withdraw", asset, amount, -1),
+        LedgerDetail::Debited { asset, amount, .. } => ("Debited", asset, amount, -1),
+        LedgerDetail::DebitReversed { asset, amount, .. } => ("DebitReversed", asset, amount, 1),
+        LedgerDetail::Credited { asset, amount, .. } => ("Credited", asset, amount, 1),
+        LedgerDetail::CreditReversed { asset, amount, .. } => ("CreditReversed", asset, amount, -1),
+        LedgerDetail::Fee { asset, amount, .. } => ("Fee", asset, amount, -1),
+        LedgerDetail::HoldCapture { asset, amount, .. } => ("HoldCapture", asset, amount, -1),
+        LedgerDetail::Overdraft { asset, amount, .. } => ("Overdraft", asset, amount, 1),
+        LedgerDetail::OverdraftRepayment { asset, amount } => ("OverdraftRepayment", asset, amount, -1),
+        LedgerDetail::QuarantineRelease { asset, amount } => ("QuarantineRelease", asset, amount, 1),
+        // Earmarked funds are set apart from the balance until released.
+        LedgerDetail::Earmark { asset, amount, .. } => ("Earmark", asset, amount, -1),
+        LedgerDetail::EarmarkRelease { asset, amount, .. } => ("EarmarkRelease", asset, amount, 1),
+        LedgerDetail::Settlement { send_asset, send_amount, receive_asset, receive_amount, .. } => {
+            if send_asset != asset && receive_asset != asset {
+                return None;
+            }
+            let sent = if send_asset == asset { *send_amount as i128 } else { 0 };
+            let received = if receive_asset == asset { *receive_amount as i128 } else { 0 };
+            return Some(("Settlement", received - sent));
+        }
+        // The balance leaves the old asset and reappears in the new one.
+        LedgerDetail::AssetConversion { from_asset, from_amount, to_asset, to_amount } => {
+            return match asset {
+                asset if asset == from_asset => Some(("AssetConversion", -(*from_amount as i128))),
+                asset if asset == to_asset => Some(("AssetConversion", *to_amount as i128)),
+                _ => None,
+            };
+        }
+        LedgerDetail::Lock { .. }
+        | LedgerDetail::Unlock { .. }
+        | LedgerDetail::Hold { .. }
+        | LedgerDetail::HoldRelease { .. }
+        | LedgerDetail::Quarantine { .. } => return None,
+    };
+    (moved == asset).then_some((kind, sign * *amount as i128))
+}
+
+fn transaction_type(detail: &LedgerDetail) -> &'static str {
+    match detail {
+        LedgerDetail::Deposit { .. } | LedgerDetail::QuarantineRelease { .. } => "DEP",
+        LedgerDetail::Withdraw { .. } => "DEBIT",
+        LedgerDetail::Fee { .. } => "FEE",
+        LedgerDetail::Overdraft { .. } | LedgerDetail::EarmarkRelease { .. } => "CREDIT",
+        LedgerDetail::OverdraftRepayment { .. } | LedgerDetail::Earmark { .. } => "DEBIT",
+        LedgerDetail::AssetConversion { .. } => "OTHER",
+        _ => "XFER",
+    }
+}
+
+// OFX datetime in UTC, YYYYMMDDHHMMSS.
+fn date(timestamp: u64) -> String {
+    NaiveDateTime::from_timestamp_opt(timestamp as i64, 0).unwrap_or_default().format("%Y%m%d%H%M%S").to_string()
+}
+
+// Minor units as a decimal amount of the asset.
+fn units(amount: i128, decimals: u8) -> String {
+    let sign = if amount < 0 { "-" } else { "" };
+    let (amount, decimals) = (amount.unsigned_abs(), decimals as u32);
+    if decimals == 0 {
+        return format!("{}{}", sign, amount);
+    }
+    let scale = 10u128.pow(decimals);
+    format!("{}{}.{:0width$}", sign, amount / scale, amount % scale, width = decimals as usize)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use cqrs_es::EventEnvelope;
+    use futures::TryStreamExt;
+    use rand::random;
+    use crate::account::aggregate::Account;
+    use crate::account::counterparty::CounterpartyNames;
+    use crate::account::events::AccountEvent;
+    use crate::account::ledger::{write_ledger_entries, StatementFilter};
+    use crate::account::ofx::{export_ofx, units};
+    use crate::util::test_db::{delete_rows, random_suffix, test_pool};
+    use crate::util::types::ByteArray32;
+
+    #[test]
+    fn test_units() {
+        assert_eq!(units(1_500_000, 6), "1.500000");
+        assert_eq!(units(-25, 2), "-0.25");
+        assert_eq!(units(7, 0), "7");
+    }
+
+    #[tokio::test]
+    async fn test_export_ofx() {
+        let pool = test_pool();
+        let account_id = format!("OFX-{}", random_suffix());
+        let (deposit, lock, settlement) = (ByteArray32(random()), ByteArray32(random()), ByteArray32(random()));
+        let envelope = |sequence, payload| EventEnvelope::<Account> {
+            aggregate_id: account_id.clone(),
+            sequence,
+            payload,
+            metadata: HashMap::new(),
+        };
+        let events = vec![
+            envelope(1, AccountEvent::deposited(deposit, 10, "USDT".to_string(), 5_000_000).with_balance_after("USDT".to_string(), 5_000_000, 0)),
+            envelope(2, AccountEvent::funds_locked(lock, 20, "USDT".to_string(), 1_000_000).with_balance_after("USDT".to_string(), 4_000_000, 1_000_000)),
+            envelope(3, AccountEvent::settlement(settlement, 30, "A&B".to_string(), "USDT".to_string(), 1_000_000, "BTC".to_string(), 2)
+                .with_balance_after("USDT".to_string(), 4_000_000, 0)),
+        ];
+        write_ledger_entries(&pool, &events).await.unwrap();
+        let export = |asset: &str, decimals| {
+            let chunks = export_ofx(pool.clone(), CounterpartyNames::new(pool.clone()), account_id.clone(), StatementFilter { to: Some(86_400), ..Default::default() }, asset.to_string(), decimals).unwrap();
+            async move {
+                let chunks: Vec<_> = chunks.try_collect().await.unwrap();
+                String::from_utf8(chunks.concat()).unwrap()
+            }
+        };
+
+        let usdt = export("USDT", 6).await;
+        assert!(usdt.starts_with("<?xml"));
+        assert!(usdt.contains("<CURDEF>USDT</CURDEF>"));
+        assert!(usdt.contains(&format!("<ACCTID>{}</ACCTID>", account_id)));
+        assert!(usdt.contains("<DTSTART>19700101000000</DTSTART><DTEND>19700102000000</DTEND>"));
+        // Newest first, the lock leaves the balance as is.
+        let transactions: Vec<_> = usdt.lines().filter(|line| line.starts_with("<STMTTRN>")).collect();
+        assert_eq!(transactions, vec![
+            format!("<STMTTRN><TRNTYPE>XFER</TRNTYPE><DTPOSTED>19700101000030</DTPOSTED><TRNAMT>-1.000000</TRNAMT><FITID>{}-3</FITID><MEMO>Settlement A&amp;B</MEMO></STMTTRN>", settlement.hex()),
+            format!("<STMTTRN><TRNTYPE>DEP</TRNTYPE><DTPOSTED>19700101000010</DTPOSTED><TRNAMT>5.000000</TRNAMT><FITID>{}-1</FITID><MEMO>Deposit</MEMO></STMTTRN>", deposit.hex()),
+        ]);
+        assert!(usdt.contains("<LEDGERBAL><BALAMT>4.000000</BALAMT><DTASOF>19700101000030</DTASOF></LEDGERBAL>"));
+        assert!(usdt.ends_with("</OFX>\n"));
+
+        let btc = export("BTC", 0).await;
+        assert!(btc.contains("<TRNTYPE>XFER</TRNTYPE><DTPOSTED>19700101000030</DTPOSTED><TRNAMT>2</TRNAMT>"));
+        // The settlement carries no BTC balance.
+        assert!(btc.contains("<LEDGERBAL><BALAMT>0</BALAMT><DTASOF>19700102000000</DTASOF></LEDGERBAL>"));
+
+        delete_rows(&pool, "ledger_entries", "account_id", &account_id).await;
+    }
+}
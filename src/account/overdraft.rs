@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use sqlx::{Pool, Postgres, Row};
+use crate::services::OverdraftPolicy;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct OverdraftLimit {
+    pub asset: String,
+    // How far the balance may go below zero, 0 removes the overdraft.
+    pub limit: u64,
+}
+
+// Credit limits per account and asset, kept in `overdraft_limit` and read on every
+// withdrawal or debit that exceeds the balance. Lowering a limit below what the account
+// already owes only stops further overdrafts.
+#[derive(Clone)]
+pub struct OverdraftLimits {
+    pool: Pool<Postgres>,
+}
+
+impl OverdraftLimits {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        OverdraftLimits { pool }
+    }
+
+    pub async fn set(&self, account_id: &str, limit: &OverdraftLimit) -> Result<(), sqlx::Error> {
+        if limit.limit == 0 {
+            sqlx::query("DELETE FROM overdraft_limit WHERE account_id = $1 AND asset = $2")
+                .bind(account_id)
+                .bind(&limit.asset)
+                .execute(&self.pool)
+                .await?;
+            return Ok(());
+        }
+        sqlx::query(
+            "INSERT INTO overdraft_limit (account_id, asset, credit_limit, updated_at)
+             VALUES ($1, $2, $3, now())
+             ON CONFLICT (account_id, asset) DO UPDATE SET credit_limit = EXCLUDED.credit_limit, updated_at = EXCLUDED.updated_at",
+        )
+            .bind(account_id)
+            .bind(&limit.asset)
+            .bind(limit.limit as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn limits(&self, account_id: &str) -> Result<BTreeMap<String, u64>, sqlx::Error> {
+        let rows = sqlx::query("SELECT asset, credit_limit FROM overdraft_limit WHERE account_id = $1")
+            .bind(account_id)
+            .fetch_all(&self.pool)
+            .await?;
+        let mut limits = BTreeMap::new();
+        for row in rows {
+            limits.insert(row.try_get("asset")?, row.try_get::<i64, _>("credit_limit")? as u64);
+        }
+        Ok(limits)
+    }
+}
+
+#[async_trait]
+impl OverdraftPolicy for OverdraftLimits {
+    async fn credit_limit(&self, account_id: &str, asset: &str) -> u64 {
+        let limit = sqlx::query("SELECT credit_limit FROM overdraft_limit WHERE account_id = $1 AND asset = $2")
+            .bind(account_id)
+            .bind(asset)
+            .fetch_optional(&self.pool)
+            .await
+            .and_then(|row| row.map(|row| row.try_get::<i64, _>("credit_limit")).transpose());
+        match limit {
+            Ok(limit) => limit.unwrap_or(0) as u64,
+            Err(e) => {
+                // Fail closed, an unreadable limit must not let the balance go negative.
+                tracing::error!("Failed to load the overdraft limit of {} in {}: {}", account_id, asset, e);
+                0
+            }
+        }
+    }
+}
@@ -1,12 +1,15 @@
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 
 use async_trait::async_trait;
 use cqrs_es::persist::GenericQuery;
 use cqrs_es::{EventEnvelope, Query, View};
-use postgres_es::PostgresViewRepository;
+use crate::sealing::SealedViewRepository;
 use serde::{Deserialize, Serialize};
-use crate::account::aggregate::Account;
-use crate::account::events::{LifecycleEvent, AccountEvent, TransactionEvent};
+use utoipa::ToSchema;
+use crate::account::aggregate::{quarantine_earmark, Account};
+use crate::account::events::{LifecycleEvent, AccountEvent, BalanceSnapshot, EarmarkPurpose, TransactionEvent, TransactionLabels};
+use crate::asset::types::ConversionRatio;
+use crate::util::metadata::EventOrigin;
 
 const RECENT_LEDGER_SIZE: usize = 100;
 
@@ -27,31 +30,82 @@ impl Query<Account> for SimpleLoggingQuery {
 // Our second query, this one will be handled with Postgres `GenericQuery`
 // which will serialize and persist our view after it is updated. It also
 // provides a `load` method to deserialize the view on request.
-pub type AccountQuery = GenericQuery<
-    PostgresViewRepository<AccountView, Account>,
-    AccountView,
-    Account,
->;
+pub type AccountViewRepository = SealedViewRepository<AccountView, Account>;
+
+pub type AccountQuery = GenericQuery<AccountViewRepository, AccountView, Account>;
 
 // The view for a BankAccount query, for a standard http application this should
 // be designed to reflect the response dto that will be returned to a user.
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
 pub struct AccountView {
     account_id: Option<String>,
     is_disabled: bool,
     balance: BTreeMap<String, u64>,
     locked_balance: BTreeMap<String, u64>,
+    #[schema(value_type = Vec<LedgerEntry>)]
     recent_ledger: VecDeque<LedgerEntry>,
+    #[serde(default)]
+    tags: BTreeSet<String>,
+    // Named holds and what is left of each, their funds are part of `locked_balance`.
+    #[serde(default)]
+    holds: BTreeMap<String, HoldBalance>,
+    // Owed on the credit line by asset, the drawn funds are already spent.
+    #[serde(default)]
+    overdrawn: BTreeMap<String, u64>,
+    // Funds set aside by earmarks and deposits from unverified sources awaiting release,
+    // neither in `balance` nor in `locked_balance`.
+    #[serde(default)]
+    earmarked_balance: BTreeMap<String, u64>,
+    // The same funds by earmark, quarantined deposits under `quarantine_earmark`.
+    #[serde(default)]
+    earmarks: BTreeMap<String, EarmarkBalance>,
+    // Present while the account is being closed, see `LifecycleCommand::RequestClose`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    closing: Option<AccountClosing>,
+    // Of the last event applied, see `crate::consistency`.
+    #[serde(default)]
+    sequence: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct EarmarkBalance {
+    pub asset: String,
+    pub amount: u64,
+    pub purpose: EarmarkPurpose,
+    pub timestamp: u64,
+    // Where a quarantined deposit came from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct AccountClosing {
+    pub requested_at: u64,
+    pub finalize_at: u64,
+    // Funds came in since the request, the closure will be aborted.
+    pub credited: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct HoldBalance {
+    pub asset: String,
+    pub amount: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct LedgerEntry {
     timestamp: u64,
     txid: String,
     detail: LedgerDetail,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    balance_after: Option<BTreeMap<String, BalanceSnapshot>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    origin: Option<EventOrigin>,
+    #[serde(flatten)]
+    labels: TransactionLabels,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 #[serde(tag = "@t")]
 pub enum LedgerDetail {
     Deposit {
@@ -97,25 +151,247 @@ pub enum LedgerDetail {
         receive_asset: String,
         receive_amount: u64
     },
+    Fee {
+        to_account: String,
+        asset: String,
+        amount: u64,
+    },
+    Hold {
+        hold: String,
+        asset: String,
+        amount: u64,
+    },
+    HoldCapture {
+        hold: String,
+        to_account: String,
+        asset: String,
+        amount: u64,
+    },
+    HoldRelease {
+        hold: String,
+        asset: String,
+        amount: u64,
+    },
+    Overdraft {
+        asset: String,
+        amount: u64,
+        limit: u64,
+    },
+    OverdraftRepayment {
+        asset: String,
+        amount: u64,
+    },
+    Quarantine {
+        asset: String,
+        amount: u64,
+        source: String,
+    },
+    QuarantineRelease {
+        asset: String,
+        amount: u64,
+    },
+    Earmark {
+        earmark: String,
+        asset: String,
+        amount: u64,
+        purpose: EarmarkPurpose,
+    },
+    EarmarkRelease {
+        earmark: String,
+        asset: String,
+        amount: u64,
+    },
+    // The available balance converted by an asset migration, the rest of the account
+    // converts with it.
+    AssetConversion {
+        from_asset: String,
+        from_amount: u64,
+        to_asset: String,
+        to_amount: u64,
+    },
+}
+
+impl LedgerDetail {
+    // The asset and amount the transaction moves, the sent side of a settlement.
+    pub fn moved(&self) -> (&str, u64) {
+        match self {
+            LedgerDetail::Deposit { asset, amount }
+            | LedgerDetail::Withdraw { asset, amount }
+            | LedgerDetail::Debited { asset, amount, .. }
+            | LedgerDetail::DebitReversed { asset, amount, .. }
+            | LedgerDetail::Credited { asset, amount, .. }
+            | LedgerDetail::CreditReversed { asset, amount, .. }
+            | LedgerDetail::Lock { asset, amount }
+            | LedgerDetail::Unlock { asset, amount }
+            | LedgerDetail::Fee { asset, amount, .. }
+            | LedgerDetail::Hold { asset, amount, .. }
+            | LedgerDetail::HoldCapture { asset, amount, .. }
+            | LedgerDetail::HoldRelease { asset, amount, .. }
+            | LedgerDetail::Overdraft { asset, amount, .. }
+            | LedgerDetail::OverdraftRepayment { asset, amount }
+            | LedgerDetail::Quarantine { asset, amount, .. }
+            | LedgerDetail::QuarantineRelease { asset, amount }
+            | LedgerDetail::Earmark { asset, amount, .. }
+            | LedgerDetail::EarmarkRelease { asset, amount, .. } => (asset, *amount),
+            LedgerDetail::Settlement { send_asset, send_amount, .. } => (send_asset, *send_amount),
+            LedgerDetail::AssetConversion { from_asset, from_amount, .. } => (from_asset, *from_amount),
+        }
+    }
+
+    // The other account of the transaction, if any.
+    pub fn counterparty(&self) -> Option<&str> {
+        match self {
+            LedgerDetail::Debited { to_account, .. }
+            | LedgerDetail::DebitReversed { to_account, .. }
+            | LedgerDetail::Settlement { to_account, .. }
+            | LedgerDetail::Fee { to_account, .. }
+            | LedgerDetail::HoldCapture { to_account, .. } => Some(to_account),
+            LedgerDetail::Credited { from_account, .. } | LedgerDetail::CreditReversed { from_account, .. } => Some(from_account),
+            _ => None,
+        }
+    }
+}
+
+impl From<&TransactionEvent> for LedgerDetail {
+    fn from(event: &TransactionEvent) -> Self {
+        match event.clone() {
+            TransactionEvent::Deposited { asset, amount } => LedgerDetail::Deposit { asset, amount },
+            TransactionEvent::Withdrew { asset, amount } => LedgerDetail::Withdraw { asset, amount },
+            TransactionEvent::Debited { to_account, asset, amount } => LedgerDetail::Debited { to_account, asset, amount },
+            TransactionEvent::DebitReversed { to_account, asset, amount } => LedgerDetail::DebitReversed { to_account, asset, amount },
+            TransactionEvent::Credited { from_account, asset, amount } => LedgerDetail::Credited { from_account, asset, amount },
+            TransactionEvent::CreditReversed { from_account, asset, amount } => LedgerDetail::CreditReversed { from_account, asset, amount },
+            TransactionEvent::FundsLocked { asset, amount } => LedgerDetail::Lock { asset, amount },
+            TransactionEvent::FundsUnlocked { asset, amount } => LedgerDetail::Unlock { asset, amount },
+            TransactionEvent::Settled { to_account, send_asset, send_amount, receive_asset, receive_amount } => LedgerDetail::Settlement {
+                to_account,
+                send_asset,
+                send_amount,
+                receive_asset,
+                receive_amount,
+            },
+            TransactionEvent::FeeCharged { to_account, asset, amount } => LedgerDetail::Fee { to_account, asset, amount },
+            TransactionEvent::HoldPlaced { hold, asset, amount } => LedgerDetail::Hold { hold, asset, amount },
+            TransactionEvent::HoldCaptured { hold, to_account, asset, amount, .. } => LedgerDetail::HoldCapture { hold, to_account, asset, amount },
+            TransactionEvent::HoldReleased { hold, asset, amount, .. } => LedgerDetail::HoldRelease { hold, asset, amount },
+            TransactionEvent::OverdraftUsed { asset, amount, limit } => LedgerDetail::Overdraft { asset, amount, limit },
+            TransactionEvent::OverdraftRepaid { asset, amount } => LedgerDetail::OverdraftRepayment { asset, amount },
+            TransactionEvent::DepositQuarantined { asset, amount, source } => LedgerDetail::Quarantine { asset, amount, source },
+            TransactionEvent::QuarantineReleased { asset, amount } => LedgerDetail::QuarantineRelease { asset, amount },
+            TransactionEvent::FundsEarmarked { earmark, asset, amount, purpose } => LedgerDetail::Earmark { earmark, asset, amount, purpose },
+            TransactionEvent::EarmarkReleased { earmark, asset, amount } => LedgerDetail::EarmarkRelease { earmark, asset, amount },
+            TransactionEvent::AssetConverted { from_asset, from_amount, to_asset, to_amount, .. } => {
+                LedgerDetail::AssetConversion { from_asset, from_amount, to_asset, to_amount }
+            }
+        }
+    }
 }
 
 impl AccountView {
+    // Closed accounts keep an empty view.
+    pub fn is_open(&self) -> bool {
+        self.account_id.is_some()
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.contains(tag)
+    }
+
+    // Every balance kept per asset, by the field it is shown under.
+    pub(crate) fn balances(&self) -> [(&'static str, &BTreeMap<String, u64>); 4] {
+        [
+            ("balance", &self.balance),
+            ("locked_balance", &self.locked_balance),
+            ("overdrawn", &self.overdrawn),
+            ("earmarked_balance", &self.earmarked_balance),
+        ]
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.is_disabled
+    }
+
+    pub fn available_balance(&self, asset: &str) -> u64 {
+        self.balance.get(asset).copied().unwrap_or(0)
+    }
+
+    // Available and locked, not counting earmarked funds.
+    pub fn owned_balance(&self, asset: &str) -> u64 {
+        self.available_balance(asset).saturating_add(self.locked_balance.get(asset).copied().unwrap_or(0))
+    }
+
     fn add_ledger(&mut self, entry: LedgerEntry) {
         self.recent_ledger.push_front(entry);
         if self.recent_ledger.len() > RECENT_LEDGER_SIZE {
             self.recent_ledger.pop_back();
         }
     }
+
+    fn earmark(&mut self, earmark: String, earmarked: EarmarkBalance) {
+        *self.earmarked_balance.entry(earmarked.asset.clone()).or_insert(0) += earmarked.amount;
+        self.earmarks.insert(earmark, earmarked);
+    }
+
+    fn release_earmark(&mut self, earmark: &str, asset: &str, amount: u64) {
+        let earmarked = self.earmarked_balance.entry(asset.to_string()).or_insert(0);
+        *earmarked = earmarked.saturating_sub(amount);
+        if *earmarked == 0 {
+            self.earmarked_balance.remove(asset);
+        }
+        self.earmarks.remove(earmark);
+    }
+
+    // Moves what is shown under `from` over to `to`. The snapshot of the event corrects the
+    // balances of `to`, the rest is converted here.
+    fn convert(&mut self, from: &str, to: &str, ratio: ConversionRatio) {
+        let convert = |amount: u64| ratio.convert(amount).unwrap_or(u64::MAX);
+        for balances in [&mut self.balance, &mut self.locked_balance, &mut self.overdrawn, &mut self.earmarked_balance] {
+            if let Some(amount) = balances.remove(from) {
+                let balance = balances.entry(to.to_string()).or_insert(0);
+                *balance = balance.saturating_add(convert(amount));
+            }
+        }
+        for hold in self.holds.values_mut().filter(|hold| hold.asset == from) {
+            hold.asset = to.to_string();
+            hold.amount = convert(hold.amount);
+        }
+        for earmark in self.earmarks.values_mut().filter(|earmark| earmark.asset == from) {
+            earmark.asset = to.to_string();
+            earmark.amount = convert(earmark.amount);
+        }
+    }
+
+    // Events carrying balance snapshots are authoritative, they override whatever
+    // the incremental arithmetic above arrived at.
+    fn apply_balance_after(&mut self, balance_after: &Option<BTreeMap<String, BalanceSnapshot>>) {
+        let Some(balances) = balance_after else {
+            return;
+        };
+        for (asset, snapshot) in balances {
+            self.balance.insert(asset.clone(), snapshot.available);
+            self.locked_balance.insert(asset.clone(), snapshot.locked);
+        }
+        if let Some(entry) = self.recent_ledger.front_mut() {
+            entry.balance_after = Some(balances.clone());
+        }
+    }
 }
 
 // This updates the view with events as they are committed.
 // The logic should be minimal here, e.g., don't calculate the account balance,
 // design the events to carry the balance information instead.
+impl AccountView {
+    pub fn sequence(&self) -> usize {
+        self.sequence
+    }
+}
+
 impl View<Account> for AccountView {
     fn update(&mut self, event: &EventEnvelope<Account>) {
+        let origin = EventOrigin::from_metadata(&event.metadata);
         match &event.payload {
             AccountEvent::Lifecycle(account_event) => match account_event {
-                LifecycleEvent::Opened { account_id } => {
+                LifecycleEvent::Opened { account_id, .. } => {
                     self.account_id = Some(account_id.clone());
                 }
                 LifecycleEvent::Closed => {
@@ -127,186 +403,378 @@ impl View<Account> for AccountView {
                 LifecycleEvent::Enabled => {
                     self.is_disabled = false;
                 }
+                LifecycleEvent::Tagged { tags } => {
+                    self.tags = tags.clone();
+                }
+                LifecycleEvent::CloseRequested { requested_at, finalize_at } => {
+                    self.closing = Some(AccountClosing { requested_at: *requested_at, finalize_at: *finalize_at, credited: false });
+                }
+                LifecycleEvent::ClosureAborted { .. } => {
+                    self.closing = None;
+                }
+                LifecycleEvent::TxidsEvicted { .. } => {}
             },
             AccountEvent::Transaction {
                 timestamp,
                 txid,
                 event,
-            } => match event {
-                TransactionEvent::Deposited { asset, amount } => {
-                    self.balance
-                        .entry(asset.clone())
-                        .and_modify(|e| *e += *amount)
-                        .or_insert(*amount);
-                    self.add_ledger(LedgerEntry {
-                        timestamp: *timestamp,
-                        txid: txid.hex(),
-                        detail: LedgerDetail::Deposit {
-                            asset: asset.clone(),
-                            amount: *amount,
-                        },
-                    });
-                }
-                TransactionEvent::Withdrew { asset, amount } => {
-                    self.balance
-                        .entry(asset.clone())
-                        .and_modify(|e| *e -= *amount)
-                        .or_insert(0);
-                    self.add_ledger(LedgerEntry {
-                        timestamp: *timestamp,
-                        txid: txid.hex(),
-                        detail: LedgerDetail::Withdraw {
-                            asset: asset.clone(),
-                            amount: *amount,
-                        },
-                    });
-                }
-                TransactionEvent::Debited {
-                    to_account,
-                    asset,
-                    amount,
-                } => {
-                    self.balance
-                        .entry(asset.clone())
-                        .and_modify(|e| *e -= *amount)
-                        .or_insert(0);
-                    self.add_ledger(LedgerEntry {
-                        timestamp: *timestamp,
-                        txid: txid.hex(),
-                        detail: LedgerDetail::Debited {
-                            to_account: to_account.clone(),
-                            asset: asset.clone(),
-                            amount: *amount,
-                        },
-                    });
+                balance_after,
+                labels,
+                ..
+            } => {
+                if let Some(closing) = &mut self.closing {
+                    closing.credited |= matches!(
+                        event,
+                        TransactionEvent::Deposited { .. } | TransactionEvent::DepositQuarantined { .. } | TransactionEvent::Credited { .. }
+                    );
                 }
-                TransactionEvent::DebitReversed {
-                    to_account,
-                    asset,
-                    amount,
-                } => {
-                    self.balance
-                        .entry(asset.clone())
-                        .and_modify(|e| *e += *amount)
-                        .or_insert(*amount);
-                    self.add_ledger(LedgerEntry {
-                        timestamp: *timestamp,
-                        txid: txid.hex(),
-                        detail: LedgerDetail::DebitReversed {
-                            to_account: to_account.clone(),
+                match event {
+                    TransactionEvent::Deposited { asset, amount } => {
+                        self.balance
+                            .entry(asset.clone())
+                            .and_modify(|e| *e += *amount)
+                            .or_insert(*amount);
+                        self.add_ledger(LedgerEntry {
+                            timestamp: *timestamp,
+                            txid: txid.hex(),
+                            detail: LedgerDetail::Deposit {
+                                asset: asset.clone(),
+                                amount: *amount,
+                            },
+                            balance_after: None,
+                            origin: origin.clone(),
+                            labels: labels.clone(),
+                        });
+                    }
+                    TransactionEvent::Withdrew { asset, amount } => {
+                        self.balance
+                            .entry(asset.clone())
+                            .and_modify(|e| *e -= *amount)
+                            .or_insert(0);
+                        self.add_ledger(LedgerEntry {
+                            timestamp: *timestamp,
+                            txid: txid.hex(),
+                            detail: LedgerDetail::Withdraw {
+                                asset: asset.clone(),
+                                amount: *amount,
+                            },
+                            balance_after: None,
+                            origin: origin.clone(),
+                            labels: labels.clone(),
+                        });
+                    }
+                    TransactionEvent::Debited {
+                        to_account,
+                        asset,
+                        amount,
+                    } => {
+                        self.balance
+                            .entry(asset.clone())
+                            .and_modify(|e| *e -= *amount)
+                            .or_insert(0);
+                        self.add_ledger(LedgerEntry {
+                            timestamp: *timestamp,
+                            txid: txid.hex(),
+                            detail: LedgerDetail::Debited {
+                                to_account: to_account.clone(),
+                                asset: asset.clone(),
+                                amount: *amount,
+                            },
+                            balance_after: None,
+                            origin: origin.clone(),
+                            labels: labels.clone(),
+                        });
+                    }
+                    TransactionEvent::DebitReversed {
+                        to_account,
+                        asset,
+                        amount,
+                    } => {
+                        self.balance
+                            .entry(asset.clone())
+                            .and_modify(|e| *e += *amount)
+                            .or_insert(*amount);
+                        self.add_ledger(LedgerEntry {
+                            timestamp: *timestamp,
+                            txid: txid.hex(),
+                            detail: LedgerDetail::DebitReversed {
+                                to_account: to_account.clone(),
+                                asset: asset.clone(),
+                                amount: *amount,
+                            },
+                            balance_after: None,
+                            origin: origin.clone(),
+                            labels: labels.clone(),
+                        });
+                    }
+                    TransactionEvent::Credited {
+                        from_account,
+                        asset,
+                        amount,
+                    } => {
+                        self.balance
+                            .entry(asset.clone())
+                            .and_modify(|e| *e += amount)
+                            .or_insert(*amount);
+                        self.add_ledger(LedgerEntry {
+                            timestamp: *timestamp,
+                            txid: txid.hex(),
+                            detail: LedgerDetail::Credited {
+                                from_account: from_account.clone(),
+                                asset: asset.clone(),
+                                amount: *amount,
+                            },
+                            balance_after: None,
+                            origin: origin.clone(),
+                            labels: labels.clone(),
+                        });
+                    }
+                    TransactionEvent::CreditReversed {
+                        from_account,
+                        asset,
+                        amount,
+                    } => {
+                        self.balance
+                            .entry(asset.clone())
+                            .and_modify(|e| *e -= *amount)
+                            .or_insert(0);
+                        self.add_ledger(LedgerEntry {
+                            timestamp: *timestamp,
+                            txid: txid.hex(),
+                            detail: LedgerDetail::CreditReversed {
+                                from_account: from_account.clone(),
+                                asset: asset.clone(),
+                                amount: *amount,
+                            },
+                            balance_after: None,
+                            origin: origin.clone(),
+                            labels: labels.clone(),
+                        });
+                    }
+                    TransactionEvent::FundsLocked {
+                        asset,
+                        amount,
+                    } => {
+                        self.balance
+                            .entry(asset.clone())
+                            .and_modify(|e| *e -= *amount)
+                            .or_insert_with(|| unreachable!("asset not found due to lock, it should not happens"));
+                        self.locked_balance
+                            .entry(asset.clone())
+                            .and_modify(|e| *e += *amount)
+                            .or_insert(*amount);
+                        self.add_ledger(LedgerEntry {
+                            timestamp: *timestamp,
+                            txid: txid.hex(),
+                            detail: LedgerDetail::Lock {
+                                asset: asset.clone(),
+                                amount: *amount,
+                            },
+                            balance_after: None,
+                            origin: origin.clone(),
+                            labels: labels.clone(),
+                        });
+                    }
+                    TransactionEvent::FundsUnlocked { asset, amount } => {
+                        self.balance
+                            .entry(asset.clone())
+                            .and_modify(|e| *e += *amount)
+                            .or_insert(*amount);
+                        self.locked_balance
+                            .entry(asset.clone())
+                            .and_modify(|e| *e -= *amount)
+                            .or_insert_with(|| unreachable!("asset not exists due to unlock, it should not happens"));
+                        self.add_ledger(LedgerEntry {
+                            timestamp: *timestamp,
+                            txid: txid.hex(),
+                            detail: LedgerDetail::Unlock {
+                                asset: asset.clone(),
+                                amount: *amount,
+                            },
+                            balance_after: None,
+                            origin: origin.clone(),
+                            labels: labels.clone(),
+                        });
+                    }
+                    TransactionEvent::Settled {
+                        to_account,
+                        send_asset,
+                        send_amount,
+                        receive_asset,
+                        receive_amount,
+                    } => {
+                        self.locked_balance
+                            .entry(send_asset.clone())
+                            .and_modify(|e| {
+                                e.checked_sub(*send_amount)
+                                    .unwrap_or_else(|| panic!("account: [{}] lock {} {} in order, but {} will be withdrew!", self.account_id.to_owned().unwrap_or("???".to_string()), e, send_asset, send_amount));
+                            })
+                            .or_insert_with(|| unreachable!("locked asset not exists, it should not happens"));
+                        self.balance
+                            .entry(receive_asset.clone())
+                            .and_modify(|e| *e += *receive_amount)
+                            .or_insert(*receive_amount);
+                        self.add_ledger(LedgerEntry {
+                            timestamp: *timestamp,
+                            txid: txid.hex(),
+                            detail: LedgerDetail::Settlement {
+                                to_account: to_account.clone(),
+                                send_asset: send_asset.clone(),
+                                send_amount: *send_amount,
+                                receive_asset: receive_asset.clone(),
+                                receive_amount: *receive_amount,
+                            },
+                            balance_after: None,
+                            origin: origin.clone(),
+                            labels: labels.clone(),
+                        });
+                    }
+                    TransactionEvent::FeeCharged {
+                        to_account,
+                        asset,
+                        amount,
+                    } => {
+                        self.balance
+                            .entry(asset.clone())
+                            .and_modify(|e| *e -= *amount)
+                            .or_insert(0);
+                        self.add_ledger(LedgerEntry {
+                            timestamp: *timestamp,
+                            txid: txid.hex(),
+                            detail: LedgerDetail::Fee {
+                                to_account: to_account.clone(),
+                                asset: asset.clone(),
+                                amount: *amount,
+                            },
+                            balance_after: None,
+                            origin: origin.clone(),
+                            labels: labels.clone(),
+                        });
+                    }
+                    // Holds postdate balance snapshots, their events always carry the balances.
+                    TransactionEvent::HoldPlaced { hold, asset, amount } => {
+                        self.holds.insert(hold.clone(), HoldBalance { asset: asset.clone(), amount: *amount });
+                        self.add_ledger(LedgerEntry {
+                            timestamp: *timestamp,
+                            txid: txid.hex(),
+                            detail: LedgerDetail::from(event),
+                            balance_after: None,
+                            origin: origin.clone(),
+                            labels: labels.clone(),
+                        });
+                    }
+                    TransactionEvent::HoldCaptured { hold, asset, remaining, .. }
+                    | TransactionEvent::HoldReleased { hold, asset, remaining, .. } => {
+                        if *remaining == 0 {
+                            self.holds.remove(hold);
+                        } else {
+                            self.holds.insert(hold.clone(), HoldBalance { asset: asset.clone(), amount: *remaining });
+                        }
+                        self.add_ledger(LedgerEntry {
+                            timestamp: *timestamp,
+                            txid: txid.hex(),
+                            detail: LedgerDetail::from(event),
+                            balance_after: None,
+                            origin: origin.clone(),
+                            labels: labels.clone(),
+                        });
+                    }
+                    // Overdrafts postdate balance snapshots too.
+                    TransactionEvent::OverdraftUsed { asset, amount, .. } => {
+                        *self.overdrawn.entry(asset.clone()).or_insert(0) += *amount;
+                        self.add_ledger(LedgerEntry {
+                            timestamp: *timestamp,
+                            txid: txid.hex(),
+                            detail: LedgerDetail::from(event),
+                            balance_after: None,
+                            origin: origin.clone(),
+                            labels: labels.clone(),
+                        });
+                    }
+                    TransactionEvent::OverdraftRepaid { asset, amount } => {
+                        let owed = self.overdrawn.entry(asset.clone()).or_insert(0);
+                        *owed = owed.saturating_sub(*amount);
+                        if *owed == 0 {
+                            self.overdrawn.remove(asset);
+                        }
+                        self.add_ledger(LedgerEntry {
+                            timestamp: *timestamp,
+                            txid: txid.hex(),
+                            detail: LedgerDetail::from(event),
+                            balance_after: None,
+                            origin: origin.clone(),
+                            labels: labels.clone(),
+                        });
+                    }
+                    // And so does quarantine.
+                    TransactionEvent::DepositQuarantined { asset, amount, source } => {
+                        let deposit = EarmarkBalance {
                             asset: asset.clone(),
                             amount: *amount,
-                        },
-                    });
-                }
-                TransactionEvent::Credited {
-                    from_account,
-                    asset,
-                    amount,
-                } => {
-                    self.balance
-                        .entry(asset.clone())
-                        .and_modify(|e| *e += amount)
-                        .or_insert(*amount);
-                    self.add_ledger(LedgerEntry {
-                        timestamp: *timestamp,
-                        txid: txid.hex(),
-                        detail: LedgerDetail::Credited {
-                            from_account: from_account.clone(),
-                            asset: asset.clone(),
-                            amount: *amount,
-                        },
-                    });
-                }
-                TransactionEvent::CreditReversed {
-                    from_account,
-                    asset,
-                    amount,
-                } => {
-                    self.balance
-                        .entry(asset.clone())
-                        .and_modify(|e| *e -= *amount)
-                        .or_insert(0);
-                    self.add_ledger(LedgerEntry {
-                        timestamp: *timestamp,
-                        txid: txid.hex(),
-                        detail: LedgerDetail::CreditReversed {
-                            from_account: from_account.clone(),
-                            asset: asset.clone(),
-                            amount: *amount,
-                        },
-                    });
-                }
-                TransactionEvent::FundsLocked {
-                    asset,
-                    amount,
-                } => {
-                    self.balance
-                        .entry(asset.clone())
-                        .and_modify(|e| *e -= *amount)
-                        .or_insert_with(|| unreachable!("asset not found due to lock, it should not happens"));
-                    self.locked_balance
-                        .entry(asset.clone())
-                        .and_modify(|e| *e += *amount)
-                        .or_insert(*amount);
-                    self.add_ledger(LedgerEntry {
-                        timestamp: *timestamp,
-                        txid: txid.hex(),
-                        detail: LedgerDetail::Lock {
-                            asset: asset.clone(),
-                            amount: *amount,
-                        },
-                    });
-                }
-                TransactionEvent::FundsUnlocked { asset, amount } => {
-                    self.balance
-                        .entry(asset.clone())
-                        .and_modify(|e| *e += *amount)
-                        .or_insert(*amount);
-                    self.locked_balance
-                        .entry(asset.clone())
-                        .and_modify(|e| *e -= *amount)
-                        .or_insert_with(|| unreachable!("asset not exists due to unlock, it should not happens"));
-                    self.add_ledger(LedgerEntry {
-                        timestamp: *timestamp,
-                        txid: txid.hex(),
-                        detail: LedgerDetail::Unlock {
-                            asset: asset.clone(),
-                            amount: *amount,
-                        },
-                    });
-                }
-                TransactionEvent::Settled {
-                    to_account,
-                    send_asset,
-                    send_amount,
-                    receive_asset,
-                    receive_amount,
-                } => {
-                    self.locked_balance
-                        .entry(send_asset.clone())
-                        .and_modify(|e| {
-                            e.checked_sub(*send_amount)
-                                .unwrap_or_else(|| panic!("account: [{}] lock {} {} in order, but {} will be withdrew!", self.account_id.to_owned().unwrap_or("???".to_string()), e, send_asset, send_amount));
-                        })
-                        .or_insert_with(|| unreachable!("locked asset not exists, it should not happens"));
-                    self.balance
-                        .entry(receive_asset.clone())
-                        .and_modify(|e| *e += *receive_amount)
-                        .or_insert(*receive_amount);
-                    self.add_ledger(LedgerEntry {
-                        timestamp: *timestamp,
-                        txid: txid.hex(),
-                        detail: LedgerDetail::Settlement {
-                            to_account: to_account.clone(),
-                            send_asset: send_asset.clone(),
-                            send_amount: *send_amount,
-                            receive_asset: receive_asset.clone(),
-                            receive_amount: *receive_amount,
-                        },
-                    });
+                            purpose: EarmarkPurpose::Quarantine,
+                            timestamp: *timestamp,
+                            source: Some(source.clone()),
+                        };
+                        self.earmark(quarantine_earmark(txid), deposit);
+                        self.add_ledger(LedgerEntry {
+                            timestamp: *timestamp,
+                            txid: txid.hex(),
+                            detail: LedgerDetail::from(event),
+                            balance_after: None,
+                            origin: origin.clone(),
+                            labels: labels.clone(),
+                        });
+                    }
+                    TransactionEvent::QuarantineReleased { asset, amount } => {
+                        self.release_earmark(&quarantine_earmark(txid), asset, *amount);
+                        self.add_ledger(LedgerEntry {
+                            timestamp: *timestamp,
+                            txid: txid.hex(),
+                            detail: LedgerDetail::from(event),
+                            balance_after: None,
+                            origin: origin.clone(),
+                            labels: labels.clone(),
+                        });
+                    }
+                    // As do earmarks, the snapshot covers what leaves or returns to `balance`.
+                    TransactionEvent::FundsEarmarked { earmark, asset, amount, purpose } => {
+                        let earmarked = EarmarkBalance { asset: asset.clone(), amount: *amount, purpose: *purpose, timestamp: *timestamp, source: None };
+                        self.earmark(earmark.clone(), earmarked);
+                        self.add_ledger(LedgerEntry {
+                            timestamp: *timestamp,
+                            txid: txid.hex(),
+                            detail: LedgerDetail::from(event),
+                            balance_after: None,
+                            origin: origin.clone(),
+                            labels: labels.clone(),
+                        });
+                    }
+                    TransactionEvent::EarmarkReleased { earmark, asset, amount } => {
+                        self.release_earmark(earmark, asset, *amount);
+                        self.add_ledger(LedgerEntry {
+                            timestamp: *timestamp,
+                            txid: txid.hex(),
+                            detail: LedgerDetail::from(event),
+                            balance_after: None,
+                            origin: origin.clone(),
+                            labels: labels.clone(),
+                        });
+                    }
+                    TransactionEvent::AssetConverted { from_asset, to_asset, ratio, .. } => {
+                        self.convert(from_asset, to_asset, *ratio);
+                        self.add_ledger(LedgerEntry {
+                            timestamp: *timestamp,
+                            txid: txid.hex(),
+                            detail: LedgerDetail::from(event),
+                            balance_after: None,
+                            origin: origin.clone(),
+                            labels: labels.clone(),
+                        });
+                    }
                 }
+                self.apply_balance_after(balance_after);
             },
         }
+        self.sequence = event.sequence;
     }
 }
@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+use cqrs_es::persist::GenericQuery;
+use cqrs_es::{EventEnvelope, View};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::account::aggregate::Account;
+use crate::account::events::{AccountEvent, LifecycleEvent, TransactionEvent};
+use crate::sealing::SealedViewRepository;
+
+pub type AccountStatsRepository = SealedViewRepository<AccountStats, Account>;
+
+pub type AccountStatsQuery = GenericQuery<AccountStatsRepository, AccountStats, Account>;
+
+// Lifetime totals of an account by asset, kept up to date event by event. Unlike
+// `AccountView` it never forgets, and it survives the account being closed and
+// reopened. Reversals take back what they reverse.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct AccountStats {
+    pub account_id: String,
+    pub deposits: BTreeMap<String, u64>,
+    pub withdrawals: BTreeMap<String, u64>,
+    pub transfers_in: BTreeMap<String, u64>,
+    // Debits and captured holds.
+    pub transfers_out: BTreeMap<String, u64>,
+    // Trade volume, both legs of every settlement.
+    pub sold: BTreeMap<String, u64>,
+    pub bought: BTreeMap<String, u64>,
+    pub trades: u64,
+    pub fees_paid: BTreeMap<String, u64>,
+    pub transactions: u64,
+    pub first_activity: Option<u64>,
+    pub last_activity: Option<u64>,
+}
+
+fn add(totals: &mut BTreeMap<String, u64>, asset: &str, amount: u64) {
+    let total = totals.entry(asset.to_string()).or_insert(0);
+    *total = total.saturating_add(amount);
+}
+
+fn take_back(totals: &mut BTreeMap<String, u64>, asset: &str, amount: u64) {
+    if let Some(total) = totals.get_mut(asset) {
+        *total = total.saturating_sub(amount);
+    }
+}
+
+impl View<Account> for AccountStats {
+    fn update(&mut self, event: &EventEnvelope<Account>) {
+        match &event.payload {
+            AccountEvent::Lifecycle(LifecycleEvent::Opened { account_id, .. }) => {
+                self.account_id = account_id.clone();
+            }
+            AccountEvent::Lifecycle(_) => {}
+            AccountEvent::Transaction { timestamp, event, .. } => {
+                match event {
+                    // A quarantined deposit counts once, when it is received.
+                    TransactionEvent::Deposited { asset, amount }
+                    | TransactionEvent::DepositQuarantined { asset, amount, .. } => add(&mut self.deposits, asset, *amount),
+                    TransactionEvent::Withdrew { asset, amount } => add(&mut self.withdrawals, asset, *amount),
+                    TransactionEvent::Credited { asset, amount, .. } => add(&mut self.transfers_in, asset, *amount),
+                    TransactionEvent::CreditReversed { asset, amount, .. } => take_back(&mut self.transfers_in, asset, *amount),
+                    TransactionEvent::Debited { asset, amount, .. }
+                    | TransactionEvent::HoldCaptured { asset, amount, .. } => add(&mut self.transfers_out, asset, *amount),
+                    TransactionEvent::DebitReversed { asset, amount, .. } => take_back(&mut self.transfers_out, asset, *amount),
+                    TransactionEvent::Settled { send_asset, send_amount, receive_asset, receive_amount, .. } => {
+                        add(&mut self.sold, send_asset, *send_amount);
+                        add(&mut self.bought, receive_asset, *receive_amount);
+                        self.trades += 1;
+                    }
+                    TransactionEvent::FeeCharged { asset, amount, .. } => add(&mut self.fees_paid, asset, *amount),
+                    TransactionEvent::FundsLocked { .. }
+                    | TransactionEvent::FundsUnlocked { .. }
+                    | TransactionEvent::HoldPlaced { .. }
+                    | TransactionEvent::HoldReleased { .. }
+                    | TransactionEvent::OverdraftUsed { .. }
+                    | TransactionEvent::OverdraftRepaid { .. }
+                    | TransactionEvent::QuarantineReleased { .. }
+                    | TransactionEvent::FundsEarmarked { .. }
+                    | TransactionEvent::EarmarkReleased { .. }
+                    | TransactionEvent::AssetConverted { .. } => {}
+                }
+                self.transactions += 1;
+                self.first_activity = Some(self.first_activity.map_or(*timestamp, |first| first.min(*timestamp)));
+                self.last_activity = Some(self.last_activity.map_or(*timestamp, |last| last.max(*timestamp)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, HashMap};
+    use cqrs_es::{EventEnvelope, View};
+    use crate::account::aggregate::Account;
+    use crate::account::events::AccountEvent;
+    use crate::account::stats::AccountStats;
+    use crate::util::types::ByteArray32;
+
+    #[test]
+    fn test_lifetime_totals() {
+        let events = vec![
+            AccountEvent::account_opened("ACCT-0001".to_string()),
+            AccountEvent::deposited(ByteArray32([1; 32]), 100, "USDT".to_string(), 1_000),
+            AccountEvent::debited(ByteArray32([2; 32]), 110, "ACCT-0002".to_string(), "USDT".to_string(), 300),
+            AccountEvent::debit_reversed(ByteArray32([2; 32]), 120, "ACCT-0002".to_string(), "USDT".to_string(), 300),
+            AccountEvent::funds_locked(ByteArray32([3; 32]), 130, "USDT".to_string(), 600),
+            AccountEvent::settlement(ByteArray32([3; 32]), 140, "ACCT-0003".to_string(), "USDT".to_string(), 600, "BTC".to_string(), 2),
+            AccountEvent::fee_charged(ByteArray32([4; 32]), 140, "FEES".to_string(), "BTC".to_string(), 1),
+            AccountEvent::withdrew(ByteArray32([5; 32]), 150, "USDT".to_string(), 50),
+            AccountEvent::account_closed(),
+        ];
+        let mut stats = AccountStats::default();
+        for (sequence, payload) in events.into_iter().enumerate() {
+            stats.update(&EventEnvelope::<Account> {
+                aggregate_id: "ACCT-0001".to_string(),
+                sequence: sequence + 1,
+                payload,
+                metadata: HashMap::new(),
+            });
+        }
+        let totals = |entries: &[(&str, u64)]| entries.iter().map(|(asset, amount)| (asset.to_string(), *amount)).collect::<BTreeMap<_, _>>();
+        assert_eq!(stats, AccountStats {
+            account_id: "ACCT-0001".to_string(),
+            deposits: totals(&[("USDT", 1_000)]),
+            withdrawals: totals(&[("USDT", 50)]),
+            transfers_in: totals(&[]),
+            transfers_out: totals(&[("USDT", 0)]),
+            sold: totals(&[("USDT", 600)]),
+            bought: totals(&[("BTC", 2)]),
+            trades: 1,
+            fees_paid: totals(&[("BTC", 1)]),
+            transactions: 7,
+            first_activity: Some(100),
+            last_activity: Some(150),
+        });
+    }
+}
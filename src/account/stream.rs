@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use cqrs_es::{DomainEvent, EventEnvelope, Query};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use crate::account::aggregate::Account;
+use crate::account::events::AccountEvent;
+
+// Events buffered per account for a slow subscriber before it starts missing some.
+const STREAM_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamedEvent {
+    pub account_id: String,
+    pub sequence: usize,
+    pub event_type: String,
+    pub payload: AccountEvent,
+    pub metadata: HashMap<String, String>,
+}
+
+// Fans committed account events out to live subscribers, one broadcast channel per
+// account. Channels are created on the first subscription and dropped once the last
+// subscriber is gone, so accounts nobody watches cost nothing.
+//
+// Only events committed after subscribing are delivered; clients that need history
+// read the ledger first and then follow the stream.
+#[derive(Clone, Default)]
+pub struct AccountEventStream {
+    channels: Arc<Mutex<HashMap<String, broadcast::Sender<Arc<StreamedEvent>>>>>,
+}
+
+impl AccountEventStream {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn subscribe(&self, account_id: &str) -> broadcast::Receiver<Arc<StreamedEvent>> {
+        let mut channels = self.channels.lock().expect("account stream registry poisoned");
+        channels.retain(|_, sender| sender.receiver_count() > 0);
+        channels
+            .entry(account_id.to_string())
+            .or_insert_with(|| broadcast::channel(STREAM_CAPACITY).0)
+            .subscribe()
+    }
+}
+
+#[async_trait]
+impl Query<Account> for AccountEventStream {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<Account>]) {
+        let mut channels = self.channels.lock().expect("account stream registry poisoned");
+        let Some(sender) = channels.get(aggregate_id) else {
+            return;
+        };
+        for event in events {
+            let streamed = StreamedEvent {
+                account_id: aggregate_id.to_string(),
+                sequence: event.sequence,
+                event_type: event.payload.event_type(),
+                payload: event.payload.clone(),
+                metadata: event.metadata.clone(),
+            };
+            // Fails only when every subscriber has gone away in the meantime.
+            if sender.send(Arc::new(streamed)).is_err() {
+                channels.remove(aggregate_id);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use cqrs_es::{EventEnvelope, Query};
+    use crate::account::aggregate::Account;
+    use crate::account::events::AccountEvent;
+    use crate::account::stream::AccountEventStream;
+
+    fn envelope(account_id: &str, sequence: usize) -> EventEnvelope<Account> {
+        EventEnvelope {
+            aggregate_id: account_id.to_string(),
+            sequence,
+            payload: AccountEvent::account_disabled(),
+            metadata: HashMap::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_reaches_subscribers_of_the_account() {
+        let stream = AccountEventStream::new();
+        let mut subscriber = stream.subscribe("ACCT-0001");
+        let mut other = stream.subscribe("ACCT-0002");
+
+        stream.dispatch("ACCT-0001", &[envelope("ACCT-0001", 3)]).await;
+        let event = subscriber.recv().await.unwrap();
+        assert_eq!((event.account_id.as_str(), event.sequence, event.event_type.as_str()), ("ACCT-0001", 3, "Lifecycle::Disabled"));
+        assert!(other.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_channel_dropped_without_subscribers() {
+        let stream = AccountEventStream::new();
+        drop(stream.subscribe("ACCT-0001"));
+        stream.dispatch("ACCT-0001", &[envelope("ACCT-0001", 1)]).await;
+        assert!(stream.channels.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use cqrs_es::persist::EventStoreAggregateContext;
+use cqrs_es::{Aggregate, AggregateContext, AggregateError, EventEnvelope, EventStore};
+use prometheus::IntGauge;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::OwnedMutexGuard;
+use crate::config::AggregateCacheConfig;
+use crate::consistency::drop_expected_sequence;
+use crate::invalidation::{Invalidated, InvalidationBus};
+use crate::metrics::AggregateCacheMetrics;
+
+// An aggregate as it stands after its last committed event.
+struct Hydrated<A: Aggregate> {
+    aggregate: A,
+    current_sequence: usize,
+    current_snapshot: Option<usize>,
+    // When the commit left it here, the aggregate matched the event store then.
+    committed_at: Instant,
+}
+
+type Mailbox<A> = Arc<tokio::sync::Mutex<Option<Hydrated<A>>>>;
+
+struct Entry<A: Aggregate> {
+    mailbox: Mailbox<A>,
+    last_used: u64,
+}
+
+struct Entries<A: Aggregate> {
+    by_id: HashMap<String, Entry<A>>,
+    // Bumped on every lookup, orders the entries by their last use.
+    clock: u64,
+}
+
+struct Shared<A: Aggregate> {
+    capacity: usize,
+    entries: Mutex<Entries<A>>,
+    metrics: AggregateCacheMetrics,
+    size: IntGauge,
+}
+
+// The aggregates of one type kept hydrated between their commands, each behind a mailbox:
+// the commands to one aggregate take turns in the order they arrive, from loading the
+// aggregate until their events are committed, and each picks up the state the one before
+// left behind instead of loading it from the event store again. Nothing is trusted beyond
+// that: a command decided on a stale aggregate fails to commit on the optimistic lock of
+// the event store, which drops the aggregate so the retry loads it afresh. Another node
+// committing to the aggregate is heard of through the `InvalidationBus`, when it is on.
+pub struct AggregateCache<A: Aggregate> {
+    shared: Arc<Shared<A>>,
+}
+
+impl<A: Aggregate> Clone for AggregateCache<A> {
+    fn clone(&self) -> Self {
+        AggregateCache { shared: self.shared.clone() }
+    }
+}
+
+impl<A: Aggregate> AggregateCache<A> {
+    pub fn new(config: &AggregateCacheConfig, metrics: AggregateCacheMetrics) -> Self {
+        let shared = Shared {
+            capacity: config.capacity,
+            entries: Mutex::new(Entries { by_id: HashMap::new(), clock: 0 }),
+            size: metrics.entries(&A::aggregate_type()),
+            metrics,
+        };
+        AggregateCache { shared: Arc::new(shared) }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.shared.capacity > 0
+    }
+
+    pub fn metrics(&self) -> &AggregateCacheMetrics {
+        &self.shared.metrics
+    }
+
+    fn mailbox(&self, aggregate_id: &str) -> Mailbox<A> {
+        let mut entries = self.shared.entries.lock().expect("aggregate cache poisoned");
+        entries.clock += 1;
+        let clock = entries.clock;
+        let entry = entries
+            .by_id
+            .entry(aggregate_id.to_string())
+            .or_insert_with(|| Entry { mailbox: Arc::new(tokio::sync::Mutex::new(None)), last_used: 0 });
+        entry.last_used = clock;
+        let mailbox = entry.mailbox.clone();
+        if entries.by_id.len() > self.shared.capacity {
+            // Down to nine tenths at once, so not every new aggregate pays for the scan.
+            let target = self.shared.capacity - self.shared.capacity / 10;
+            let excess = entries.by_id.len().saturating_sub(target);
+            // Aggregates with commands on their way keep their mailbox, this one included.
+            let mut idle: Vec<(u64, String)> = entries
+                .by_id
+                .iter()
+                .filter(|(_, entry)| Arc::strong_count(&entry.mailbox) == 1)
+                .map(|(aggregate_id, entry)| (entry.last_used, aggregate_id.clone()))
+                .collect();
+            idle.sort_unstable();
+            for (_, aggregate_id) in idle.into_iter().take(excess) {
+                entries.by_id.remove(&aggregate_id);
+            }
+        }
+        self.shared.size.set(entries.by_id.len() as i64);
+        mailbox
+    }
+
+    // Reads the cached aggregate without waiting for it, along with its sequence and how
+    // long ago it was committed. None when it is not cached or a command holds it, neither
+    // is it counted as a use.
+    pub fn peek<R>(&self, aggregate_id: &str, read: impl FnOnce(&A) -> R) -> Option<(R, usize, Duration)> {
+        let mailbox = {
+            let entries = self.shared.entries.lock().expect("aggregate cache poisoned");
+            entries.by_id.get(aggregate_id)?.mailbox.clone()
+        };
+        let hydrated = mailbox.try_lock().ok()?;
+        let hydrated = hydrated.as_ref()?;
+        Some((read(&hydrated.aggregate), hydrated.current_sequence, hydrated.committed_at.elapsed()))
+    }
+
+    // Drops the aggregate unless it is at `version` already, once the command holding it
+    // is done.
+    pub fn invalidate(&self, aggregate_id: &str, version: usize)
+    where
+        A: 'static,
+    {
+        let mailbox = {
+            let entries = self.shared.entries.lock().expect("aggregate cache poisoned");
+            match entries.by_id.get(aggregate_id) {
+                Some(entry) => entry.mailbox.clone(),
+                None => return,
+            }
+        };
+        let invalidate = move |hydrated: &mut Option<Hydrated<A>>| {
+            if hydrated.as_ref().is_some_and(|hydrated| hydrated.current_sequence < version) {
+                *hydrated = None;
+            }
+        };
+        match mailbox.clone().try_lock_owned() {
+            Ok(mut hydrated) => invalidate(&mut hydrated),
+            Err(_) => {
+                tokio::spawn(async move { invalidate(&mut *mailbox.lock().await) });
+            },
+        }
+    }
+
+    // Drops every aggregate, e.g. once the event store was wiped. Commands on their way
+    // leave theirs in a mailbox nobody looks into anymore.
+    pub fn clear(&self) {
+        let mut entries = self.shared.entries.lock().expect("aggregate cache poisoned");
+        entries.by_id.clear();
+        self.shared.size.set(0);
+    }
+
+    // Follows the commits of every node until the bus is gone.
+    pub fn listen(&self, bus: &InvalidationBus)
+    where
+        A: 'static,
+    {
+        let mut invalidations = bus.subscribe();
+        let cache = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match invalidations.recv().await {
+                    Ok(Invalidated::One(invalidation)) if invalidation.aggregate_type == A::aggregate_type() => {
+                        cache.invalidate(&invalidation.aggregate_id, invalidation.version);
+                    },
+                    Ok(Invalidated::One(_)) => {},
+                    Ok(Invalidated::All) | Err(RecvError::Lagged(_)) => cache.clear(),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+}
+
+// Holds the turn of a command in the mailbox of the aggregate, from loading it until its
+// events are committed. Dropped without a commit, i.e. when the command was rejected, it
+// leaves the mailbox empty: the rejection may come from a stale aggregate, which no
+// optimistic lock catches, so the next command loads it afresh.
+pub struct CachedAggregateContext<A: Aggregate> {
+    context: EventStoreAggregateContext<A>,
+    turn: Option<OwnedMutexGuard<Option<Hydrated<A>>>>,
+}
+
+impl<A: Aggregate> AggregateContext<A> for CachedAggregateContext<A> {
+    fn aggregate(&self) -> &A {
+        &self.context.aggregate
+    }
+}
+
+// Puts an `AggregateCache` in front of an event store of `PersistedEventStore` contexts.
+pub struct CachedEventStore<ES, A: Aggregate> {
+    inner: ES,
+    cache: AggregateCache<A>,
+    // Commits crossing a snapshot write one, the aggregate is then loaded from it again
+    // rather than keeping track of its version.
+    snapshot_size: Option<usize>,
+}
+
+impl<ES, A: Aggregate> CachedEventStore<ES, A> {
+    pub fn new(inner: ES, cache: AggregateCache<A>, snapshot_size: Option<usize>) -> Self {
+        CachedEventStore { inner, cache, snapshot_size }
+    }
+}
+
+#[async_trait]
+impl<ES, A> EventStore<A> for CachedEventStore<ES, A>
+where
+    ES: EventStore<A, AC = EventStoreAggregateContext<A>>,
+    A: Aggregate + Clone,
+{
+    type AC = CachedAggregateContext<A>;
+
+    async fn load_events(&self, aggregate_id: &str) -> Result<Vec<EventEnvelope<A>>, AggregateError<A::Error>> {
+        self.inner.load_events(aggregate_id).await
+    }
+
+    async fn load_aggregate(&self, aggregate_id: &str) -> Result<Self::AC, AggregateError<A::Error>> {
+        if !self.cache.is_enabled() {
+            let context = self.inner.load_aggregate(aggregate_id).await?;
+            return Ok(CachedAggregateContext { context, turn: None });
+        }
+        let mut turn = self.cache.mailbox(aggregate_id).lock_owned().await;
+        let hydrated = turn.take();
+        self.cache.shared.metrics.loaded(&A::aggregate_type(), hydrated.is_some());
+        let context = match hydrated {
+            Some(hydrated) => EventStoreAggregateContext {
+                aggregate_id: aggregate_id.to_string(),
+                aggregate: hydrated.aggregate,
+                current_sequence: hydrated.current_sequence,
+                current_snapshot: hydrated.current_snapshot,
+            },
+            None => self.inner.load_aggregate(aggregate_id).await?,
+        };
+        Ok(CachedAggregateContext { context, turn: Some(turn) })
+    }
+
+    async fn commit(
+        &self,
+        events: Vec<A::Event>,
+        context: Self::AC,
+        metadata: HashMap<String, String>,
+    ) -> Result<Vec<EventEnvelope<A>>, AggregateError<A::Error>> {
+        let CachedAggregateContext { context: inner, turn } = context;
+        let Some(mut turn) = turn else {
+            let mut committed = self.inner.commit(events, inner, metadata).await?;
+            drop_expected_sequence(&mut committed);
+            return Ok(committed);
+        };
+        let (mut aggregate, current_sequence, current_snapshot) = (inner.aggregate.clone(), inner.current_sequence, inner.current_snapshot);
+        // On failure the aggregate stays out of the cache, the event store may be ahead of it.
+        let mut committed = self.inner.commit(events, inner, metadata).await?;
+        drop_expected_sequence(&mut committed);
+        let last_sequence = committed.last().map_or(current_sequence, |event| event.sequence);
+        let snapshot_taken = self.snapshot_size.is_some_and(|size| current_sequence / size != last_sequence / size);
+        if !snapshot_taken {
+            for event in &committed {
+                aggregate.apply(event.payload.clone());
+            }
+            *turn = Some(Hydrated { aggregate, current_sequence: last_sequence, current_snapshot, committed_at: Instant::now() });
+        }
+        Ok(committed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use cqrs_es::persist::PersistedEventStore;
+    use cqrs_es::{AggregateError, CqrsFramework};
+    use postgres_es::PostgresEventRepository;
+    use prometheus::{Registry, TextEncoder};
+    use rand::random;
+    use crate::account::aggregate::Account;
+    use crate::account::commands::AccountCommand;
+    use crate::aggregate_cache::{AggregateCache, CachedEventStore};
+    use crate::config::AggregateCacheConfig;
+    use crate::metrics::AggregateCacheMetrics;
+    use crate::services::{BankAccountServices, HappyPathBankAccountServices};
+    use crate::util::test_db::{random_suffix, test_pool};
+    use crate::util::types::ByteArray32;
+
+    #[tokio::test]
+    async fn test_aggregate_cache() {
+        let pool = test_pool();
+        let services = || BankAccountServices::new(Box::new(HappyPathBankAccountServices));
+        let registry = Registry::new();
+        let metrics = AggregateCacheMetrics::default();
+        metrics.register(&registry).unwrap();
+        let cache = AggregateCache::<Account>::new(&AggregateCacheConfig::default(), metrics);
+        let store = PersistedEventStore::new_snapshot_store(PostgresEventRepository::new(pool.clone()), 100);
+        let cached = CqrsFramework::new(CachedEventStore::new(store, cache.clone(), Some(100)), vec![], services());
+        let uncached = CqrsFramework::new(PersistedEventStore::<_, Account>::new_event_store(PostgresEventRepository::new(pool)), vec![], services());
+
+        let account_id = format!("CACHE-{}", random_suffix());
+        let deposit = |amount| AccountCommand::deposited(ByteArray32(random()), 1, "BTC".to_string(), amount);
+        let withdraw = |amount| AccountCommand::withdrew(ByteArray32(random()), 1, "BTC".to_string(), amount);
+        cached.execute(&account_id, AccountCommand::account_opened(account_id.clone())).await.unwrap();
+        cached.execute(&account_id, deposit(100)).await.unwrap();
+        // Rejected commands drop the aggregate, the next one loads it again.
+        assert!(cached.execute(&account_id, withdraw(101)).await.is_err());
+        cached.execute(&account_id, withdraw(100)).await.unwrap();
+
+        // Committed elsewhere, the cached aggregate is stale: the next command loses on the
+        // optimistic lock and its retry loads the account again.
+        uncached.execute(&account_id, deposit(50)).await.unwrap();
+        assert!(matches!(cached.execute(&account_id, deposit(1)).await, Err(AggregateError::AggregateConflict)));
+        cached.execute(&account_id, deposit(1)).await.unwrap();
+        cached.execute(&account_id, withdraw(51)).await.unwrap();
+        assert!(cached.execute(&account_id, withdraw(1)).await.is_err());
+
+        let metrics = TextEncoder::new().encode_to_string(&registry.gather()).unwrap();
+        assert!(metrics.contains("aggregate_cache_loads_total{aggregate=\"account\",result=\"hit\"} 5"), "{}", metrics);
+        assert!(metrics.contains("aggregate_cache_loads_total{aggregate=\"account\",result=\"miss\"} 3"), "{}", metrics);
+        assert!(metrics.contains("aggregate_cache_entries{aggregate=\"account\"} 1"), "{}", metrics);
+
+        // Commands to one account take turns.
+        let cached = Arc::new(cached);
+        let deposits: Vec<_> = (0..8)
+            .map(|_| {
+                let (cached, account_id) = (cached.clone(), account_id.clone());
+                tokio::spawn(async move { cached.execute(&account_id, deposit(1)).await })
+            })
+            .collect();
+        for deposit in deposits {
+            deposit.await.unwrap().unwrap();
+        }
+        cache.clear();
+        assert!(cached.execute(&account_id, withdraw(8)).await.is_ok());
+    }
+}
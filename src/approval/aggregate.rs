@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::mem::swap;
+use std::sync::Arc;
+use async_trait::async_trait;
+use cqrs_es::Aggregate;
+use postgres_es::PostgresCqrs;
+use serde::{Deserialize, Serialize};
+use crate::account::aggregate::Account;
+use crate::account::commands::{AccountCommand, LifecycleCommand};
+use crate::account::overdraft::OverdraftLimits;
+use crate::approval::commands::{ApprovalCommand, ConfigChange};
+use crate::approval::events::ApprovalEvent;
+use crate::asset::aggregate::Asset;
+use crate::asset::commands::AssetCommand;
+use crate::config::ConflictRetryConfig;
+use crate::metrics::ErrorVariant;
+use crate::retry::retry_conflicts;
+use crate::sealing::SealedCqrs;
+use crate::statemachine::{StateMachine, Transition};
+use crate::util::clock;
+use crate::util::metadata::INITIATOR_KEY;
+
+// Who proposed a change, next to its approver as the initiator of the commands applying it.
+pub const PROPOSER_KEY: &str = "proposer";
+
+// A configuration change under maker-checker: proposed by one operator, applied when a
+// different one approves it and only then recorded as approved. Either of them, or any
+// other operator, may reject it while it is pending.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub enum Approval {
+    #[default]
+    Uninitialized,
+    Pending {
+        proposer: String,
+        change: ConfigChange,
+    },
+    Approved {
+        proposer: String,
+        approver: String,
+    },
+    Rejected {
+        proposer: String,
+        approver: String,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApprovalError {
+    #[error("Invalid state: {0}")]
+    InvalidState(String),
+    #[error("Invalid change: {0}")]
+    InvalidChange(String),
+    #[error("{0} proposed the change, another operator must approve it")]
+    SameOperator(String),
+    #[error("The change could not be applied: {0}")]
+    NotApplied(String),
+}
+
+impl ErrorVariant for ApprovalError {
+    fn variant(&self) -> &'static str {
+        match self {
+            ApprovalError::InvalidState(_) => "InvalidState",
+            ApprovalError::InvalidChange(_) => "InvalidChange",
+            ApprovalError::SameOperator(_) => "SameOperator",
+            ApprovalError::NotApplied(_) => "NotApplied",
+        }
+    }
+}
+
+// Applies an approved change to the accounts, the overdraft limits or the asset registry.
+#[async_trait]
+pub trait ConfigApplier: Sync + Send {
+    async fn apply(&self, change: &ConfigChange, proposer: &str, approver: &str) -> Result<(), String>;
+}
+
+#[derive(Clone)]
+pub struct ApprovalServices {
+    applier: Arc<dyn ConfigApplier>,
+}
+
+impl ApprovalServices {
+    pub fn new(applier: Arc<dyn ConfigApplier>) -> Self {
+        ApprovalServices { applier }
+    }
+}
+
+pub struct ConfigChanges {
+    account_cqrs: Arc<SealedCqrs<Account>>,
+    asset_cqrs: Arc<PostgresCqrs<Asset>>,
+    overdraft_limits: OverdraftLimits,
+    retry: ConflictRetryConfig,
+}
+
+impl ConfigChanges {
+    pub fn new(account_cqrs: Arc<SealedCqrs<Account>>, asset_cqrs: Arc<PostgresCqrs<Asset>>, overdraft_limits: OverdraftLimits, retry: ConflictRetryConfig) -> Self {
+        ConfigChanges { account_cqrs, asset_cqrs, overdraft_limits, retry }
+    }
+}
+
+#[async_trait]
+impl ConfigApplier for ConfigChanges {
+    async fn apply(&self, change: &ConfigChange, proposer: &str, approver: &str) -> Result<(), String> {
+        let metadata = HashMap::from([
+            (INITIATOR_KEY.to_string(), approver.to_string()),
+            (PROPOSER_KEY.to_string(), proposer.to_string()),
+        ]);
+        match change {
+            ConfigChange::AccountLifecycle { account_id, command } => {
+                let execute = || self.account_cqrs.execute_with_metadata(account_id, AccountCommand::Lifecycle(command.clone()), metadata.clone());
+                retry_conflicts(&self.retry, execute).await.map_err(|e| e.to_string())
+            },
+            ConfigChange::OverdraftLimit { account_id, limit } => {
+                self.overdraft_limits.set(account_id, limit).await.map_err(|e| e.to_string())?;
+                tracing::warn!("Overdraft limit of {} in {} set to {}, proposed by {} and approved by {}", account_id, limit.asset, limit.limit, proposer, approver);
+                Ok(())
+            },
+            ConfigChange::TradingRules { symbol, quote, rules } => {
+                let command = AssetCommand::SetTradingRules { quote: quote.clone(), rules: *rules, timestamp: clock::now() };
+                let execute = || self.asset_cqrs.execute_with_metadata(symbol, command.clone(), metadata.clone());
+                retry_conflicts(&self.retry, execute).await.map_err(|e| e.to_string())
+            },
+        }
+    }
+}
+
+fn validate(change: &ConfigChange) -> Result<(), ApprovalError> {
+    match change {
+        // Opening is not a change to an account's configuration.
+        ConfigChange::AccountLifecycle { command: LifecycleCommand::Open { .. }, .. } => {
+            Err(ApprovalError::InvalidChange("accounts are opened without approval".to_string()))
+        },
+        ConfigChange::TradingRules { rules, .. } => match rules.invalid() {
+            Some(invalid) => Err(ApprovalError::InvalidChange(invalid)),
+            None => Ok(()),
+        },
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl Aggregate for Approval {
+    type Command = ApprovalCommand;
+    type Event = ApprovalEvent;
+    type Error = ApprovalError;
+    type Services = ApprovalServices;
+
+    fn aggregate_type() -> String {
+        "approval".to_string()
+    }
+
+    async fn handle(
+        &self,
+        command: Self::Command,
+        services: &Self::Services,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        match (self, command) {
+            (Approval::Uninitialized, ApprovalCommand::Propose { proposer, change, timestamp }) => {
+                validate(&change)?;
+                Ok(vec![ApprovalEvent::Proposed { proposer, change, timestamp }])
+            },
+            (Approval::Pending { proposer, change }, ApprovalCommand::Approve { approver, timestamp }) => {
+                if &approver == proposer {
+                    return Err(ApprovalError::SameOperator(approver));
+                }
+                services.applier.apply(change, proposer, &approver).await.map_err(ApprovalError::NotApplied)?;
+                Ok(vec![ApprovalEvent::Approved { approver, timestamp }])
+            },
+            (Approval::Pending { .. }, ApprovalCommand::Reject { approver, reason, timestamp }) => {
+                Ok(vec![ApprovalEvent::Rejected { approver, reason, timestamp }])
+            },
+            (state, cmd) => {
+                Err(ApprovalError::InvalidState(format!("Approval current at {:?} state, cannot accept {:?} command", state, cmd)))
+            }
+        }
+    }
+
+    fn apply(&mut self, event: Self::Event) {
+        let mut prev = Default::default();
+        swap(&mut prev, self);
+        *self = match (prev, event) {
+            (Approval::Uninitialized, ApprovalEvent::Proposed { proposer, change, .. }) => Approval::Pending { proposer, change },
+            (Approval::Pending { proposer, .. }, ApprovalEvent::Approved { approver, .. }) => Approval::Approved { proposer, approver },
+            (Approval::Pending { proposer, .. }, ApprovalEvent::Rejected { approver, .. }) => Approval::Rejected { proposer, approver },
+            (state, event) => unreachable!("Invalid state transition: {:?} -> {:?}", state, event),
+        };
+    }
+}
+
+impl StateMachine for Approval {
+    const TRANSITIONS: &'static [Transition] = &[
+        Transition { from: "Uninitialized", command: "Propose", guard: None, events: &["Proposed"], to: "Pending" },
+        Transition { from: "Pending", command: "Approve", guard: Some("another operator, change applied"), events: &["Approved"], to: "Approved" },
+        Transition { from: "Pending", command: "Reject", guard: None, events: &["Rejected"], to: "Rejected" },
+    ];
+}
+
+#[cfg(test)]
+mod aggregate_tests {
+    use std::sync::{Arc, Mutex};
+    use async_trait::async_trait;
+    use cqrs_es::test::TestFramework;
+    use crate::account::commands::LifecycleCommand;
+    use crate::account::overdraft::OverdraftLimit;
+    use crate::approval::aggregate::{Approval, ApprovalServices, ConfigApplier};
+    use crate::approval::commands::{ApprovalCommand, ConfigChange};
+    use crate::approval::events::ApprovalEvent;
+
+    #[derive(Default)]
+    struct MockApplier {
+        applied: Mutex<Vec<(ConfigChange, String, String)>>,
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl ConfigApplier for MockApplier {
+        async fn apply(&self, change: &ConfigChange, proposer: &str, approver: &str) -> Result<(), String> {
+            if self.fail {
+                return Err("Account not found".to_string());
+            }
+            self.applied.lock().unwrap().push((change.clone(), proposer.to_string(), approver.to_string()));
+            Ok(())
+        }
+    }
+
+    fn change() -> ConfigChange {
+        ConfigChange::OverdraftLimit { account_id: "ACCT-0001".to_string(), limit: OverdraftLimit { asset: "BTC".to_string(), limit: 1000 } }
+    }
+
+    fn proposed() -> ApprovalEvent {
+        ApprovalEvent::Proposed { proposer: "alice".to_string(), change: change(), timestamp: 1 }
+    }
+
+    #[test]
+    fn test_approve_by_another_operator() {
+        let applier = Arc::new(MockApplier::default());
+        TestFramework::<Approval>::with(ApprovalServices::new(applier.clone()))
+            .given(vec![proposed()])
+            .when(ApprovalCommand::Approve { approver: "bob".to_string(), timestamp: 2 })
+            .then_expect_events(vec![ApprovalEvent::Approved { approver: "bob".to_string(), timestamp: 2 }]);
+        assert_eq!(*applier.applied.lock().unwrap(), vec![(change(), "alice".to_string(), "bob".to_string())]);
+    }
+
+    #[test]
+    fn test_proposer_cannot_approve() {
+        let applier = Arc::new(MockApplier::default());
+        TestFramework::<Approval>::with(ApprovalServices::new(applier.clone()))
+            .given(vec![proposed()])
+            .when(ApprovalCommand::Approve { approver: "alice".to_string(), timestamp: 2 })
+            .then_expect_error_message("alice proposed the change, another operator must approve it");
+        assert!(applier.applied.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_approval_fails_with_the_change() {
+        let applier = Arc::new(MockApplier { fail: true, ..MockApplier::default() });
+        TestFramework::<Approval>::with(ApprovalServices::new(applier))
+            .given(vec![proposed()])
+            .when(ApprovalCommand::Approve { approver: "bob".to_string(), timestamp: 2 })
+            .then_expect_error_message("The change could not be applied: Account not found");
+    }
+
+    #[test]
+    fn test_rejected_stays_rejected() {
+        let services = || ApprovalServices::new(Arc::new(MockApplier::default()));
+        TestFramework::<Approval>::with(services())
+            .given(vec![proposed()])
+            .when(ApprovalCommand::Reject { approver: "alice".to_string(), reason: "wrong asset".to_string(), timestamp: 2 })
+            .then_expect_events(vec![ApprovalEvent::Rejected { approver: "alice".to_string(), reason: "wrong asset".to_string(), timestamp: 2 }]);
+        let rejected = ApprovalEvent::Rejected { approver: "alice".to_string(), reason: "wrong asset".to_string(), timestamp: 2 };
+        TestFramework::<Approval>::with(services())
+            .given(vec![proposed(), rejected])
+            .when(ApprovalCommand::Approve { approver: "bob".to_string(), timestamp: 3 })
+            .then_expect_error_message("Invalid state: Approval current at Rejected { proposer: \"alice\", approver: \"alice\" } state, cannot accept Approve { approver: \"bob\", timestamp: 3 } command");
+    }
+
+    #[test]
+    fn test_opening_needs_no_approval() {
+        let change = ConfigChange::AccountLifecycle { account_id: "ACCT-0001".to_string(), command: LifecycleCommand::Open { account_id: "ACCT-0001".to_string() } };
+        TestFramework::<Approval>::with(ApprovalServices::new(Arc::new(MockApplier::default())))
+            .given_no_previous_events()
+            .when(ApprovalCommand::Propose { proposer: "alice".to_string(), change, timestamp: 1 })
+            .then_expect_error_message("Invalid change: accounts are opened without approval");
+    }
+}
@@ -0,0 +1,56 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::account::commands::LifecycleCommand;
+use crate::account::overdraft::OverdraftLimit;
+use crate::asset::types::TradingRules;
+
+// A configuration change one operator proposes and another approves, applied only once
+// approved, see `crate::approval::aggregate`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub enum ConfigChange {
+    // Disables, enables or closes the account, or replaces its tags.
+    AccountLifecycle {
+        account_id: String,
+        command: LifecycleCommand,
+    },
+    OverdraftLimit {
+        account_id: String,
+        limit: OverdraftLimit,
+    },
+    // Rules of the pair `symbol`-`quote`.
+    TradingRules {
+        symbol: String,
+        quote: String,
+        rules: TradingRules,
+    },
+}
+
+// The operators are the principals the admin requests were authorized for, never taken
+// from the request body.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ApprovalCommand {
+    Propose {
+        proposer: String,
+        change: ConfigChange,
+        timestamp: u64,
+    },
+    Approve {
+        approver: String,
+        timestamp: u64,
+    },
+    Reject {
+        approver: String,
+        reason: String,
+        timestamp: u64,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ProposalResponse {
+    pub approval_id: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RejectionRequest {
+    pub reason: String,
+}
@@ -0,0 +1,36 @@
+use cqrs_es::DomainEvent;
+use serde::{Deserialize, Serialize};
+use crate::approval::commands::ConfigChange;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ApprovalEvent {
+    Proposed {
+        proposer: String,
+        change: ConfigChange,
+        timestamp: u64,
+    },
+    // Committed once the change was applied.
+    Approved {
+        approver: String,
+        timestamp: u64,
+    },
+    Rejected {
+        approver: String,
+        reason: String,
+        timestamp: u64,
+    },
+}
+
+impl DomainEvent for ApprovalEvent {
+    fn event_type(&self) -> String {
+        match self {
+            ApprovalEvent::Proposed { .. } => "Proposed".to_string(),
+            ApprovalEvent::Approved { .. } => "Approved".to_string(),
+            ApprovalEvent::Rejected { .. } => "Rejected".to_string(),
+        }
+    }
+
+    fn event_version(&self) -> String {
+        "1.0".to_string()
+    }
+}
@@ -0,0 +1,4 @@
+pub mod aggregate;
+pub mod commands;
+pub mod events;
+pub mod queries;
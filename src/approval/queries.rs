@@ -0,0 +1,90 @@
+use async_trait::async_trait;
+use cqrs_es::{EventEnvelope, Query, View};
+use cqrs_es::persist::GenericQuery;
+use postgres_es::PostgresViewRepository;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres, Row};
+use utoipa::ToSchema;
+use crate::approval::aggregate::Approval;
+use crate::approval::commands::ConfigChange;
+use crate::approval::events::ApprovalEvent;
+
+pub struct SimpleLoggingQuery {}
+
+#[async_trait]
+impl Query<Approval> for SimpleLoggingQuery {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<Approval>]) {
+        for event in events {
+            let payload = serde_json::to_string_pretty(&event.payload).unwrap();
+            tracing::debug!("{}-{}\n{}", aggregate_id, event.sequence, payload);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ToSchema)]
+pub enum ApprovalStatus {
+    #[default]
+    Pending,
+    Approved,
+    Rejected,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq, Eq, ToSchema)]
+pub struct ApprovalView {
+    pub approval_id: String,
+    pub status: ApprovalStatus,
+    pub change: Option<ConfigChange>,
+    pub proposer: String,
+    pub proposed_at: u64,
+    // Who approved or rejected it, and when.
+    pub approver: Option<String>,
+    pub decided_at: Option<u64>,
+    pub reason: Option<String>,
+}
+
+pub type ApprovalQuery = GenericQuery<
+    PostgresViewRepository<ApprovalView, Approval>,
+    ApprovalView,
+    Approval,
+>;
+
+impl View<Approval> for ApprovalView {
+    fn update(&mut self, event: &EventEnvelope<Approval>) {
+        match &event.payload {
+            ApprovalEvent::Proposed { proposer, change, timestamp } => {
+                self.approval_id = event.aggregate_id.clone();
+                self.change = Some(change.clone());
+                self.proposer = proposer.clone();
+                self.proposed_at = *timestamp;
+            }
+            ApprovalEvent::Approved { approver, timestamp } => {
+                self.status = ApprovalStatus::Approved;
+                self.approver = Some(approver.clone());
+                self.decided_at = Some(*timestamp);
+            }
+            ApprovalEvent::Rejected { approver, reason, timestamp } => {
+                self.status = ApprovalStatus::Rejected;
+                self.approver = Some(approver.clone());
+                self.decided_at = Some(*timestamp);
+                self.reason = Some(reason.clone());
+            }
+        }
+    }
+}
+
+// The changes waiting for a second operator, oldest first.
+pub async fn pending_approvals(pool: &Pool<Postgres>, limit: i64) -> Result<Vec<ApprovalView>, sqlx::Error> {
+    sqlx::query(
+        "SELECT payload FROM approval_query WHERE payload->>'status' = 'Pending'
+         ORDER BY (payload->>'proposed_at')::bigint, view_id LIMIT $1",
+    )
+        .bind(limit)
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| {
+            let payload: serde_json::Value = row.try_get("payload")?;
+            serde_json::from_value(payload).map_err(|e| sqlx::Error::Decode(Box::new(e)))
+        })
+        .collect()
+}
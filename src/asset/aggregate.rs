@@ -0,0 +1,289 @@
+use std::mem::swap;
+use async_trait::async_trait;
+use cqrs_es::Aggregate;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::asset::commands::AssetCommand;
+use crate::asset::events::AssetEvent;
+use crate::asset::types::ConversionRatio;
+use crate::statemachine::{StateMachine, Transition};
+use crate::metrics::ErrorVariant;
+
+const MAX_DECIMALS: u8 = 18;
+const MAX_DISPLAY_NAME_LEN: usize = 64;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AssetConfig {
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+// The asset an asset is migrated into, see `crate::asset::migration`. Once completed,
+// every balance held in the old asset has been converted and queries naming it are
+// answered for the new one.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub struct AssetMigration {
+    pub to: String,
+    pub ratio: ConversionRatio,
+    pub completed: bool,
+}
+
+// A tradable asset. Accounts only accept deposits, withdrawals and locks in assets
+// that are registered here and currently enabled. A migrating asset stays disabled.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub enum Asset {
+    #[default]
+    Uninitialized,
+    Registered {
+        config: AssetConfig,
+        enabled: bool,
+        #[serde(default)]
+        migration: Option<AssetMigration>,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AssetError {
+    #[error("Invalid state: {0}")]
+    InvalidState(String),
+    #[error("Invalid asset: {0}")]
+    InvalidAsset(String),
+}
+
+impl ErrorVariant for AssetError {
+    fn variant(&self) -> &'static str {
+        match self {
+            AssetError::InvalidState(_) => "InvalidState",
+            AssetError::InvalidAsset(_) => "InvalidAsset",
+        }
+    }
+}
+
+#[async_trait]
+impl Aggregate for Asset {
+    type Command = AssetCommand;
+    type Event = AssetEvent;
+    type Error = AssetError;
+    type Services = ();
+
+    fn aggregate_type() -> String {
+        "asset".to_string()
+    }
+
+    async fn handle(
+        &self,
+        command: Self::Command,
+        _services: &Self::Services,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        match (self, command) {
+            (Asset::Uninitialized, AssetCommand::Register { symbol, decimals, timestamp }) => {
+                if symbol.is_empty() || !symbol.chars().all(|c| c.is_ascii_alphanumeric()) {
+                    return Err(AssetError::InvalidAsset(format!("symbol {:?} must be non-empty and alphanumeric", symbol)));
+                }
+                if decimals > MAX_DECIMALS {
+                    return Err(AssetError::InvalidAsset(format!("decimals must not exceed {}", MAX_DECIMALS)));
+                }
+                Ok(vec![AssetEvent::Registered { symbol, decimals, timestamp }])
+            },
+            (Asset::Registered { enabled: false, migration: None, .. }, AssetCommand::Enable { timestamp }) => {
+                Ok(vec![AssetEvent::Enabled { timestamp }])
+            },
+            (Asset::Registered { enabled: true, .. }, AssetCommand::Disable { timestamp }) => {
+                Ok(vec![AssetEvent::Disabled { timestamp }])
+            },
+            (Asset::Registered { config, migration: None, .. }, AssetCommand::Migrate { to, ratio, timestamp }) => {
+                if to == config.symbol || to.is_empty() || !to.chars().all(|c| c.is_ascii_alphanumeric()) {
+                    return Err(AssetError::InvalidAsset(format!("cannot migrate {} to {:?}", config.symbol, to)));
+                }
+                if !ratio.is_valid() {
+                    return Err(AssetError::InvalidAsset(format!("{}:{} is not a conversion ratio", ratio.numerator, ratio.denominator)));
+                }
+                Ok(vec![AssetEvent::MigrationStarted { to, ratio, timestamp }])
+            },
+            (Asset::Registered { migration: Some(AssetMigration { completed: false, .. }), .. }, AssetCommand::CompleteMigration { timestamp }) => {
+                Ok(vec![AssetEvent::MigrationCompleted { timestamp }])
+            },
+            (Asset::Registered { config, .. }, AssetCommand::SetTradingRules { quote, rules, timestamp }) => {
+                if quote == config.symbol || quote.is_empty() || !quote.chars().all(|c| c.is_ascii_alphanumeric()) {
+                    return Err(AssetError::InvalidAsset(format!("{} cannot be traded against {:?}", config.symbol, quote)));
+                }
+                if let Some(invalid) = rules.invalid() {
+                    return Err(AssetError::InvalidAsset(invalid));
+                }
+                Ok(vec![AssetEvent::TradingRulesSet { quote, rules, timestamp }])
+            },
+            (Asset::Registered { .. }, AssetCommand::SetDisplayName { display_name, timestamp }) => {
+                let display_name = display_name.trim().to_string();
+                if display_name.is_empty() || display_name.chars().count() > MAX_DISPLAY_NAME_LEN {
+                    return Err(AssetError::InvalidAsset(format!("display name must be 1 to {} characters", MAX_DISPLAY_NAME_LEN)));
+                }
+                Ok(vec![AssetEvent::DisplayNameSet { display_name, timestamp }])
+            },
+            (state, cmd) => {
+                Err(AssetError::InvalidState(format!("Asset current at {:?} state, cannot accept {:?} command", state, cmd)))
+            }
+        }
+    }
+
+    fn apply(&mut self, event: Self::Event) {
+        let mut prev = Default::default();
+        swap(&mut prev, self);
+        *self = match (prev, event) {
+            (Asset::Uninitialized, AssetEvent::Registered { symbol, decimals, .. }) => Asset::Registered {
+                config: AssetConfig { symbol, decimals },
+                enabled: true,
+                migration: None,
+            },
+            (Asset::Registered { config, migration, .. }, AssetEvent::Enabled { .. }) => Asset::Registered {
+                config,
+                enabled: true,
+                migration,
+            },
+            (Asset::Registered { config, migration, .. }, AssetEvent::Disabled { .. }) => Asset::Registered {
+                config,
+                enabled: false,
+                migration,
+            },
+            (Asset::Registered { config, .. }, AssetEvent::MigrationStarted { to, ratio, .. }) => Asset::Registered {
+                config,
+                enabled: false,
+                migration: Some(AssetMigration { to, ratio, completed: false }),
+            },
+            (Asset::Registered { config, enabled, migration: Some(migration) }, AssetEvent::MigrationCompleted { .. }) => Asset::Registered {
+                config,
+                enabled,
+                migration: Some(AssetMigration { completed: true, ..migration }),
+            },
+            // Only orders read the rules, from the registry view.
+            (state @ Asset::Registered { .. }, AssetEvent::TradingRulesSet { .. }) => state,
+            // Only clients read the name, from the registry view.
+            (state @ Asset::Registered { .. }, AssetEvent::DisplayNameSet { .. }) => state,
+            (state, event) => unreachable!("Invalid state transition: {:?} -> {:?}", state, event),
+        };
+    }
+}
+
+impl StateMachine for Asset {
+    const TRANSITIONS: &'static [Transition] = &[
+        Transition { from: "Uninitialized", command: "Register", guard: None, events: &["Registered"], to: "Registered" },
+        Transition { from: "Registered", command: "Disable", guard: Some("enabled"), events: &["Disabled"], to: "Registered" },
+        Transition { from: "Registered", command: "Enable", guard: Some("disabled, not migrated"), events: &["Enabled"], to: "Registered" },
+        Transition { from: "Registered", command: "Migrate", guard: Some("not migrated"), events: &["MigrationStarted"], to: "Registered" },
+        Transition { from: "Registered", command: "CompleteMigration", guard: Some("migrating"), events: &["MigrationCompleted"], to: "Registered" },
+        Transition { from: "Registered", command: "SetTradingRules", guard: None, events: &["TradingRulesSet"], to: "Registered" },
+        Transition { from: "Registered", command: "SetDisplayName", guard: None, events: &["DisplayNameSet"], to: "Registered" },
+    ];
+}
+
+#[cfg(test)]
+mod aggregate_tests {
+    use cqrs_es::test::TestFramework;
+
+    use crate::asset::aggregate::Asset;
+    use crate::asset::types::{ConversionRatio, Price, TradingRules};
+    use crate::asset::commands::AssetCommand;
+    use crate::asset::events::AssetEvent;
+
+    #[test]
+    fn test_register_asset() {
+        TestFramework::<Asset>::with(())
+            .given_no_previous_events()
+            .when(AssetCommand::Register { symbol: "BTC".to_string(), decimals: 8, timestamp: 1 })
+            .then_expect_events(vec![AssetEvent::Registered { symbol: "BTC".to_string(), decimals: 8, timestamp: 1 }]);
+    }
+
+    #[test]
+    fn test_register_rejects_bad_symbol() {
+        TestFramework::<Asset>::with(())
+            .given_no_previous_events()
+            .when(AssetCommand::Register { symbol: "BT C".to_string(), decimals: 8, timestamp: 1 })
+            .then_expect_error_message("Invalid asset: symbol \"BT C\" must be non-empty and alphanumeric");
+    }
+
+    #[test]
+    fn test_disable_twice() {
+        TestFramework::<Asset>::with(())
+            .given(vec![
+                AssetEvent::Registered { symbol: "BTC".to_string(), decimals: 8, timestamp: 1 },
+                AssetEvent::Disabled { timestamp: 2 },
+            ])
+            .when(AssetCommand::Disable { timestamp: 3 })
+            .then_expect_error_message("Invalid state: Asset current at Registered { config: AssetConfig { symbol: \"BTC\", decimals: 8 }, enabled: false, migration: None } state, cannot accept Disable { timestamp: 3 } command");
+    }
+
+    #[test]
+    fn test_migrate_asset() {
+        let ratio = ConversionRatio { numerator: 1, denominator: 1000 };
+        let registered = AssetEvent::Registered { symbol: "OLD".to_string(), decimals: 8, timestamp: 1 };
+        TestFramework::<Asset>::with(())
+            .given(vec![registered.clone()])
+            .when(AssetCommand::Migrate { to: "NEW".to_string(), ratio, timestamp: 2 })
+            .then_expect_events(vec![AssetEvent::MigrationStarted { to: "NEW".to_string(), ratio, timestamp: 2 }]);
+        TestFramework::<Asset>::with(())
+            .given(vec![registered.clone()])
+            .when(AssetCommand::Migrate { to: "OLD".to_string(), ratio, timestamp: 2 })
+            .then_expect_error_message("Invalid asset: cannot migrate OLD to \"OLD\"");
+
+        let started = AssetEvent::MigrationStarted { to: "NEW".to_string(), ratio, timestamp: 2 };
+        // A migrating asset stays disabled.
+        TestFramework::<Asset>::with(())
+            .given(vec![registered.clone(), started.clone()])
+            .when(AssetCommand::Enable { timestamp: 3 })
+            .then_expect_error_message("Invalid state: Asset current at Registered { config: AssetConfig { symbol: \"OLD\", decimals: 8 }, enabled: false, migration: Some(AssetMigration { to: \"NEW\", ratio: ConversionRatio { numerator: 1, denominator: 1000 }, completed: false }) } state, cannot accept Enable { timestamp: 3 } command");
+        TestFramework::<Asset>::with(())
+            .given(vec![registered, started])
+            .when(AssetCommand::CompleteMigration { timestamp: 3 })
+            .then_expect_events(vec![AssetEvent::MigrationCompleted { timestamp: 3 }]);
+    }
+
+    #[test]
+    fn test_trading_rules() {
+        let rules = TradingRules { tick_size: 100, min_notional: 1_000, min_price: Some(Price::new(5, 1)), max_price: Some(Price::new(20, 1)) };
+        let registered = AssetEvent::Registered { symbol: "BTC".to_string(), decimals: 8, timestamp: 1 };
+        TestFramework::<Asset>::with(())
+            .given(vec![registered.clone()])
+            .when(AssetCommand::SetTradingRules { quote: "USDT".to_string(), rules, timestamp: 2 })
+            .then_expect_events(vec![AssetEvent::TradingRulesSet { quote: "USDT".to_string(), rules, timestamp: 2 }]);
+        TestFramework::<Asset>::with(())
+            .given(vec![registered.clone()])
+            .when(AssetCommand::SetTradingRules { quote: "USDT".to_string(), rules: TradingRules { tick_size: 0, ..rules }, timestamp: 2 })
+            .then_expect_error_message("Invalid asset: tick size must be positive");
+        TestFramework::<Asset>::with(())
+            .given(vec![registered.clone()])
+            .when(AssetCommand::SetTradingRules { quote: "USDT".to_string(), rules: TradingRules { min_price: Some(Price::new(30, 1)), ..rules }, timestamp: 2 })
+            .then_expect_error_message("Invalid asset: minimum price is above the maximum price");
+        TestFramework::<Asset>::with(())
+            .given(vec![registered])
+            .when(AssetCommand::SetTradingRules { quote: "BTC".to_string(), rules, timestamp: 2 })
+            .then_expect_error_message("Invalid asset: BTC cannot be traded against \"BTC\"");
+
+        // 200 base units for 2000 quote units trade at a price of 10 and a notional of 2000.
+        assert_eq!(rules.violation(200, 2_000), None);
+        assert!(rules.violation(100, 900).unwrap().contains("notional"));
+        assert!(rules.violation(250, 2_500).unwrap().contains("tick size"));
+        assert!(rules.violation(100, 2_100).unwrap().contains("above the band"));
+        assert!(rules.violation(1_000, 4_000).unwrap().contains("below the band"));
+        assert_eq!(TradingRules::default().violation(1, 1), None);
+    }
+
+    #[test]
+    fn test_conversion_ratio() {
+        let ratio = ConversionRatio { numerator: 1, denominator: 1000 };
+        assert_eq!(ratio.convert(123_999), Some(123));
+        assert_eq!(ConversionRatio { numerator: 2, denominator: 1 }.convert(u64::MAX), None);
+        assert!(!ConversionRatio { numerator: 0, denominator: 1 }.is_valid());
+    }
+
+    #[test]
+    fn test_display_name() {
+        let registered = AssetEvent::Registered { symbol: "BTC".to_string(), decimals: 8, timestamp: 1 };
+        TestFramework::<Asset>::with(())
+            .given(vec![registered.clone()])
+            .when(AssetCommand::SetDisplayName { display_name: " Bitcoin ".to_string(), timestamp: 2 })
+            .then_expect_events(vec![AssetEvent::DisplayNameSet { display_name: "Bitcoin".to_string(), timestamp: 2 }]);
+        TestFramework::<Asset>::with(())
+            .given(vec![registered])
+            .when(AssetCommand::SetDisplayName { display_name: " ".to_string(), timestamp: 2 })
+            .then_expect_error_message("Invalid asset: display name must be 1 to 64 characters");
+    }
+}
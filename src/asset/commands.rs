@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::asset::types::{ConversionRatio, TradingRules};
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub enum AssetCommand {
+    Register {
+        symbol: String,
+        decimals: u8,
+        timestamp: u64,
+    },
+    Enable {
+        timestamp: u64,
+    },
+    Disable {
+        timestamp: u64,
+    },
+    // Disables the asset for good and records what it converts into. Issued by
+    // `crate::asset::migration`, which then converts the balances of every account.
+    Migrate {
+        to: String,
+        ratio: ConversionRatio,
+        timestamp: u64,
+    },
+    CompleteMigration {
+        timestamp: u64,
+    },
+    // Replaces the rules orders trade the pair of this asset and `quote` under, see
+    // `TradingRules`.
+    SetTradingRules {
+        quote: String,
+        rules: TradingRules,
+        timestamp: u64,
+    },
+    // Replaces the name clients show the asset under, the symbol until one is set. Every
+    // client shows it, so like the other asset commands it is only taken on the admin route.
+    SetDisplayName {
+        display_name: String,
+        timestamp: u64,
+    },
+}
@@ -0,0 +1,103 @@
+use std::collections::{BTreeMap, BTreeSet};
+use cqrs_es::persist::{PersistenceError, ViewRepository};
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::account::queries::AccountView;
+use crate::asset::aggregate::Asset;
+use crate::asset::queries::AssetView;
+use crate::asset::types::AssetDisplay;
+use crate::order::book::OrderBook;
+use crate::order::queries::OrderView;
+use crate::rates::RateHistory;
+
+// A response with the display metadata of the assets it names, so clients format its
+// amounts from the registry rather than hard-coding decimals. The fields of the view
+// stay where they were, `assets` is added next to them. Assets missing from the
+// registry are left out.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct WithAssets<T> {
+    #[serde(flatten)]
+    pub view: T,
+    pub assets: BTreeMap<String, AssetDisplay>,
+}
+
+// The assets a response names amounts in.
+pub trait MentionsAssets {
+    fn assets(&self) -> BTreeSet<&str>;
+}
+
+impl MentionsAssets for AccountView {
+    fn assets(&self) -> BTreeSet<&str> {
+        self.balances().into_iter().flat_map(|(_, balances)| balances.keys().map(String::as_str)).collect()
+    }
+}
+
+impl MentionsAssets for OrderView {
+    fn assets(&self) -> BTreeSet<&str> {
+        BTreeSet::from([self.sell_asset.as_str(), self.buy_asset.as_str()])
+    }
+}
+
+impl MentionsAssets for OrderBook {
+    fn assets(&self) -> BTreeSet<&str> {
+        BTreeSet::from([self.base.as_str(), self.quote.as_str()])
+    }
+}
+
+impl MentionsAssets for RateHistory {
+    fn assets(&self) -> BTreeSet<&str> {
+        BTreeSet::from([self.base.as_str(), self.quote.as_str()])
+    }
+}
+
+pub async fn with_assets<T, R>(asset_query: &R, view: T) -> Result<WithAssets<T>, PersistenceError>
+where
+    T: MentionsAssets,
+    R: ViewRepository<AssetView, Asset>,
+{
+    let mut assets = BTreeMap::new();
+    for symbol in view.assets() {
+        if let Some(asset) = asset_query.load(symbol).await? {
+            assets.insert(symbol.to_string(), asset.display());
+        }
+    }
+    Ok(WithAssets { view, assets })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use cqrs_es::persist::{ViewContext, ViewRepository};
+    use postgres_es::PostgresViewRepository;
+    use crate::asset::aggregate::Asset;
+    use crate::asset::display::with_assets;
+    use crate::asset::queries::AssetView;
+    use crate::asset::types::AssetDisplay;
+    use crate::order::queries::OrderView;
+    use crate::util::test_db::{random_suffix, test_pool};
+
+    #[tokio::test]
+    async fn test_with_assets() {
+        let pool = test_pool();
+        let registry = PostgresViewRepository::<AssetView, Asset>::new("asset_query", pool);
+        let suffix = random_suffix().to_uppercase();
+        let (base, quote) = (format!("BTC{}", suffix), format!("USDT{}", suffix));
+        for (symbol, decimals, display_name) in [(&base, 8, Some("Bitcoin".to_string())), (&quote, 6, None)] {
+            let view = AssetView { symbol: symbol.clone(), decimals, display_name, ..Default::default() };
+            registry.update_view(view, ViewContext::new(symbol.clone(), 0)).await.unwrap();
+        }
+        let order = OrderView { sell_asset: base.clone(), buy_asset: quote.clone(), ..Default::default() };
+        let response = serde_json::to_value(with_assets(&registry, order).await.unwrap()).unwrap();
+        // The view keeps its fields, the name falls back to the symbol.
+        assert_eq!(response["sell_asset"], base.as_str());
+        let assets: BTreeMap<String, AssetDisplay> = serde_json::from_value(response["assets"].clone()).unwrap();
+        assert_eq!(assets, BTreeMap::from([
+            (base.clone(), AssetDisplay { symbol: base.clone(), decimals: 8, display_name: "Bitcoin".to_string() }),
+            (quote.clone(), AssetDisplay { symbol: quote.clone(), decimals: 6, display_name: quote.clone() }),
+        ]));
+
+        // Unregistered assets are left out.
+        let order = OrderView { sell_asset: base, buy_asset: format!("XYZ{}", suffix), ..Default::default() };
+        assert_eq!(with_assets(&registry, order).await.unwrap().assets.len(), 1);
+    }
+}
@@ -0,0 +1,53 @@
+use cqrs_es::DomainEvent;
+use serde::{Deserialize, Serialize};
+use crate::asset::types::{ConversionRatio, TradingRules};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum AssetEvent {
+    Registered {
+        symbol: String,
+        decimals: u8,
+        timestamp: u64,
+    },
+    Enabled {
+        timestamp: u64,
+    },
+    Disabled {
+        timestamp: u64,
+    },
+    MigrationStarted {
+        to: String,
+        ratio: ConversionRatio,
+        timestamp: u64,
+    },
+    MigrationCompleted {
+        timestamp: u64,
+    },
+    TradingRulesSet {
+        quote: String,
+        rules: TradingRules,
+        timestamp: u64,
+    },
+    DisplayNameSet {
+        display_name: String,
+        timestamp: u64,
+    },
+}
+
+impl DomainEvent for AssetEvent {
+    fn event_type(&self) -> String {
+        match self {
+            AssetEvent::Registered { .. } => "Registered".to_string(),
+            AssetEvent::Enabled { .. } => "Enabled".to_string(),
+            AssetEvent::Disabled { .. } => "Disabled".to_string(),
+            AssetEvent::MigrationStarted { .. } => "MigrationStarted".to_string(),
+            AssetEvent::MigrationCompleted { .. } => "MigrationCompleted".to_string(),
+            AssetEvent::TradingRulesSet { .. } => "TradingRulesSet".to_string(),
+            AssetEvent::DisplayNameSet { .. } => "DisplayNameSet".to_string(),
+        }
+    }
+
+    fn event_version(&self) -> String {
+        "1.0".to_string()
+    }
+}
@@ -0,0 +1,508 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use cqrs_es::persist::ViewRepository;
+use cqrs_es::AggregateError;
+use futures::StreamExt;
+use postgres_es::{PostgresCqrs, PostgresViewRepository};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgRow;
+use sqlx::{Pool, Postgres, Row};
+use utoipa::ToSchema;
+use crate::account::aggregate::Account;
+use crate::account::bulk::BulkFailure;
+use crate::account::commands::AccountCommand;
+use crate::account::events::AccountError;
+use crate::asset::aggregate::Asset;
+use crate::asset::types::ConversionRatio;
+use crate::asset::commands::AssetCommand;
+use crate::asset::queries::AssetView;
+use crate::config::BulkConfig;
+use crate::metrics::ErrorMetrics;
+use crate::sealing::SealedCqrs;
+use crate::util::clock;
+use crate::util::metadata::{CORRELATION_ID_KEY, INITIATOR_KEY};
+use crate::util::types::ByteArray32;
+
+// Failures kept in full per migration, the rest are only counted.
+const MAX_FAILURES: usize = 100;
+// Migrations followed when resolving an asset, a longer chain is a loop.
+const MAX_HOPS: usize = 8;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MigrationError {
+    #[error("Asset {0} is not registered")]
+    UnknownAsset(String),
+    #[error("Asset {0} is not enabled")]
+    NotEnabled(String),
+    #[error("Asset {0} is already migrated to {1}")]
+    AlreadyMigrated(String, String),
+    #[error("Migration of asset {0} is still running")]
+    Running(String),
+    #[error("{0}")]
+    Rejected(String),
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct MigrationRequest {
+    pub from: String,
+    pub to: String,
+    pub ratio: ConversionRatio,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+pub enum MigrationStatus {
+    Running,
+    Completed,
+    Failed,
+}
+
+impl MigrationStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            MigrationStatus::Running => "Running",
+            MigrationStatus::Completed => "Completed",
+            MigrationStatus::Failed => "Failed",
+        }
+    }
+
+    fn parse(status: &str) -> Self {
+        match status {
+            "Completed" => MigrationStatus::Completed,
+            "Failed" => MigrationStatus::Failed,
+            _ => MigrationStatus::Running,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct MigrationProgress {
+    pub from_asset: String,
+    pub to_asset: String,
+    pub ratio: ConversionRatio,
+    pub status: MigrationStatus,
+    pub initiator: String,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    // The last account looked at, the migration resumes after it.
+    pub cursor: String,
+    pub scanned: u64,
+    pub converted: u64,
+    // Accounts which held nothing of the asset any more, or were closed.
+    pub skipped: u64,
+    pub failed: u64,
+    pub failures: Vec<BulkFailure>,
+    // Why the migration stopped early, when it failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl MigrationProgress {
+    fn from_row(row: &PgRow) -> Result<Self, sqlx::Error> {
+        let failures: serde_json::Value = row.try_get("failures")?;
+        Ok(MigrationProgress {
+            from_asset: row.try_get("from_asset")?,
+            to_asset: row.try_get("to_asset")?,
+            ratio: ConversionRatio {
+                numerator: row.try_get::<i64, _>("numerator")? as u64,
+                denominator: row.try_get::<i64, _>("denominator")? as u64,
+            },
+            status: MigrationStatus::parse(row.try_get("status")?),
+            initiator: row.try_get("initiator")?,
+            started_at: row.try_get::<i64, _>("started_at")? as u64,
+            finished_at: row.try_get::<Option<i64>, _>("finished_at")?.map(|at| at as u64),
+            cursor: row.try_get("cursor")?,
+            scanned: row.try_get::<i64, _>("scanned")? as u64,
+            converted: row.try_get::<i64, _>("converted")? as u64,
+            skipped: row.try_get::<i64, _>("skipped")? as u64,
+            failed: row.try_get::<i64, _>("failed")? as u64,
+            failures: serde_json::from_value(failures).unwrap_or_default(),
+            error: row.try_get("error")?,
+        })
+    }
+}
+
+enum Outcome {
+    Converted,
+    Skipped,
+    Failed(BulkFailure),
+}
+
+// Migrates an asset into another one, renamed or redenominated by a ratio. The asset is
+// disabled for good, then every account that ever held it converts its balances in
+// batches of `BulkConfig::page_size`, at most `BulkConfig::concurrency` at once. Progress
+// is kept in `asset_migration`, so a migration interrupted by a restart resumes after
+// the last batch; one that failed is started again from the first account. Once every
+// account converted, the asset records the migration as completed and queries naming it
+// are answered for the new asset, see `resolve`.
+#[derive(Clone)]
+pub struct AssetMigrations {
+    asset_cqrs: Arc<PostgresCqrs<Asset>>,
+    asset_query: Arc<PostgresViewRepository<AssetView, Asset>>,
+    account_cqrs: Arc<SealedCqrs<Account>>,
+    pool: Pool<Postgres>,
+    error_metrics: ErrorMetrics,
+    config: BulkConfig,
+    // Migrations running on this node.
+    running: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl AssetMigrations {
+    pub fn new(
+        asset_cqrs: Arc<PostgresCqrs<Asset>>,
+        asset_query: Arc<PostgresViewRepository<AssetView, Asset>>,
+        account_cqrs: Arc<SealedCqrs<Account>>,
+        pool: Pool<Postgres>,
+        error_metrics: ErrorMetrics,
+        config: BulkConfig,
+    ) -> Self {
+        AssetMigrations { asset_cqrs, asset_query, account_cqrs, pool, error_metrics, config, running: Default::default() }
+    }
+
+    // Resumes the migrations a restart interrupted.
+    pub fn spawn(&self) {
+        let migrations = self.clone();
+        tokio::spawn(async move {
+            let running = match migrations.list().await {
+                Ok(list) => list.into_iter().filter(|progress| progress.status == MigrationStatus::Running),
+                Err(e) => return tracing::error!("Failed to load the asset migrations to resume: {}", e),
+            };
+            for progress in running {
+                if migrations.running.lock().expect("asset migrations poisoned").insert(progress.from_asset.clone()) {
+                    tracing::info!("Resuming the migration of {} to {} after {:?}", progress.from_asset, progress.to_asset, progress.cursor);
+                    let migrations = migrations.clone();
+                    tokio::spawn(async move { migrations.run(progress).await });
+                }
+            }
+        });
+    }
+
+    // Starting a migration again, with the same target and ratio, retries one that failed.
+    pub async fn start(&self, request: MigrationRequest, metadata: HashMap<String, String>) -> Result<MigrationProgress, MigrationError> {
+        let from = self.asset(&request.from).await?;
+        let to = self.asset(&request.to).await?;
+        if !to.enabled {
+            return Err(MigrationError::NotEnabled(to.symbol));
+        }
+        match &from.migration {
+            Some(migration) if migration.to != request.to || migration.ratio != request.ratio || migration.completed => {
+                return Err(MigrationError::AlreadyMigrated(from.symbol, migration.to.clone()));
+            }
+            Some(_) => {}
+            None => {
+                let command = AssetCommand::Migrate { to: request.to.clone(), ratio: request.ratio, timestamp: clock::now() };
+                if let Err(err) = self.asset_cqrs.execute_with_metadata(&request.from, command, metadata.clone()).await {
+                    self.error_metrics.record::<Asset>(&err);
+                    return Err(MigrationError::Rejected(err.to_string()));
+                }
+            }
+        }
+        if !self.running.lock().expect("asset migrations poisoned").insert(request.from.clone()) {
+            return Err(MigrationError::Running(request.from));
+        }
+        let initiator = metadata.get(INITIATOR_KEY).cloned().unwrap_or_default();
+        let row = sqlx::query(
+            "INSERT INTO asset_migration (from_asset, to_asset, numerator, denominator, status, initiator, started_at, cursor, scanned, converted, skipped, failed, failures)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, '', 0, 0, 0, 0, '[]')
+             ON CONFLICT (from_asset) DO UPDATE SET status = EXCLUDED.status, initiator = EXCLUDED.initiator, started_at = EXCLUDED.started_at,
+                 finished_at = NULL, cursor = '', scanned = 0, converted = 0, skipped = 0, failed = 0, failures = '[]', error = NULL
+             RETURNING *",
+        )
+            .bind(&request.from)
+            .bind(&request.to)
+            .bind(request.ratio.numerator as i64)
+            .bind(request.ratio.denominator as i64)
+            .bind(MigrationStatus::Running.as_str())
+            .bind(&initiator)
+            .bind(clock::now() as i64)
+            .fetch_one(&self.pool)
+            .await
+            .and_then(|row| MigrationProgress::from_row(&row));
+        let progress = match row {
+            Ok(progress) => progress,
+            Err(e) => {
+                self.running.lock().expect("asset migrations poisoned").remove(&request.from);
+                return Err(e.into());
+            }
+        };
+        tracing::info!("Migration of {} to {} at {}:{} started by {}", request.from, request.to, request.ratio.numerator, request.ratio.denominator, initiator);
+        let migrations = self.clone();
+        let started = progress.clone();
+        tokio::spawn(async move { migrations.run(started).await });
+        Ok(progress)
+    }
+
+    pub async fn progress(&self, asset: &str) -> Result<Option<MigrationProgress>, sqlx::Error> {
+        sqlx::query("SELECT * FROM asset_migration WHERE from_asset = $1")
+            .bind(asset)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|row| MigrationProgress::from_row(&row))
+            .transpose()
+    }
+
+    // Newest first.
+    pub async fn list(&self) -> Result<Vec<MigrationProgress>, sqlx::Error> {
+        sqlx::query("SELECT * FROM asset_migration ORDER BY started_at DESC, from_asset")
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(MigrationProgress::from_row)
+            .collect()
+    }
+
+    // The asset the balances of `asset` are held in now, following completed migrations.
+    pub async fn resolve(&self, asset: &str) -> Result<String, MigrationError> {
+        resolve(&self.asset_query, asset).await
+    }
+
+    async fn asset(&self, symbol: &str) -> Result<AssetView, MigrationError> {
+        match self.asset_query.load(symbol).await {
+            Ok(Some(view)) => Ok(view),
+            Ok(None) => Err(MigrationError::UnknownAsset(symbol.to_string())),
+            Err(err) => Err(MigrationError::Rejected(err.to_string())),
+        }
+    }
+
+    async fn run(&self, progress: MigrationProgress) {
+        let from = progress.from_asset.clone();
+        let result = self.process(progress).await;
+        let (status, error) = match result {
+            Ok(0) => {
+                let command = AssetCommand::CompleteMigration { timestamp: clock::now() };
+                match self.asset_cqrs.execute_with_metadata(&from, command, self.metadata(&from)).await {
+                    Ok(()) => (MigrationStatus::Completed, None),
+                    Err(err) => {
+                        self.error_metrics.record::<Asset>(&err);
+                        (MigrationStatus::Failed, Some(err.to_string()))
+                    }
+                }
+            }
+            Ok(failed) => (MigrationStatus::Failed, Some(format!("{} accounts failed to convert", failed))),
+            Err(err) => (MigrationStatus::Failed, Some(err.to_string())),
+        };
+        let finished = sqlx::query("UPDATE asset_migration SET status = $2, error = $3, finished_at = $4 WHERE from_asset = $1")
+            .bind(&from)
+            .bind(status.as_str())
+            .bind(&error)
+            .bind(clock::now() as i64)
+            .execute(&self.pool)
+            .await;
+        if let Err(e) = finished {
+            tracing::error!("Failed to record the end of the migration of {}: {}", from, e);
+        }
+        self.running.lock().expect("asset migrations poisoned").remove(&from);
+        tracing::info!("Migration of {} {:?}{}", from, status, error.map(|error| format!(": {}", error)).unwrap_or_default());
+    }
+
+    // The number of accounts that failed to convert.
+    async fn process(&self, mut progress: MigrationProgress) -> Result<u64, sqlx::Error> {
+        let page_size = self.config.page_size.max(1);
+        // One txid per migration, every account converts under it.
+        let txid = ByteArray32::derive("asset-migration", &format!("{}:{}", progress.from_asset, progress.to_asset));
+        let metadata = self.metadata(&progress.from_asset);
+        loop {
+            let page = self.holders(&progress.from_asset, &progress.cursor, page_size).await?;
+            let Some(last) = page.last().cloned() else {
+                return Ok(progress.failed);
+            };
+            let full = page.len() == page_size;
+            let outcomes: Vec<Outcome> = futures::stream::iter(page)
+                .map(|account_id| {
+                    let (progress, metadata) = (&progress, &metadata);
+                    async move { self.convert(&account_id, progress, txid, metadata).await }
+                })
+                .buffer_unordered(self.config.concurrency.max(1))
+                .collect()
+                .await;
+            for outcome in outcomes {
+                match outcome {
+                    Outcome::Converted => progress.converted += 1,
+                    Outcome::Skipped => progress.skipped += 1,
+                    Outcome::Failed(failure) => {
+                        progress.failed += 1;
+                        if progress.failures.len() < MAX_FAILURES {
+                            progress.failures.push(failure);
+                        }
+                    }
+                }
+                progress.scanned += 1;
+            }
+            progress.cursor = last;
+            sqlx::query(
+                "UPDATE asset_migration SET cursor = $2, scanned = $3, converted = $4, skipped = $5, failed = $6, failures = $7 WHERE from_asset = $1",
+            )
+                .bind(&progress.from_asset)
+                .bind(&progress.cursor)
+                .bind(progress.scanned as i64)
+                .bind(progress.converted as i64)
+                .bind(progress.skipped as i64)
+                .bind(progress.failed as i64)
+                .bind(serde_json::to_value(&progress.failures).expect("failures serialize"))
+                .execute(&self.pool)
+                .await?;
+            tracing::info!("Migration of {}: {} scanned, {} converted", progress.from_asset, progress.scanned, progress.converted);
+            if !full {
+                return Ok(progress.failed);
+            }
+        }
+    }
+
+    // The next accounts after `after` whose ledger ever named the asset, in id order.
+    async fn holders(&self, asset: &str, after: &str, page_size: usize) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query(
+            "SELECT DISTINCT account_id FROM ledger_entries
+             WHERE account_id > $1 AND $2 IN (detail->>'asset', detail->>'send_asset', detail->>'receive_asset', detail->>'to_asset')
+             ORDER BY account_id LIMIT $3",
+        )
+            .bind(after)
+            .bind(asset)
+            .bind(page_size as i64)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|row| row.try_get("account_id"))
+            .collect()
+    }
+
+    async fn convert(&self, account_id: &str, progress: &MigrationProgress, txid: ByteArray32, metadata: &HashMap<String, String>) -> Outcome {
+        let command = AccountCommand::convert_asset(txid, clock::now(), progress.from_asset.clone(), progress.to_asset.clone(), progress.ratio);
+        match self.account_cqrs.execute_with_metadata(account_id, command, metadata.clone()).await {
+            Ok(()) => Outcome::Converted,
+            Err(AggregateError::UserError(AccountError::AssetNotHeld(_) | AccountError::AccountNotFound)) => Outcome::Skipped,
+            Err(err) => {
+                self.error_metrics.record::<Account>(&err);
+                Outcome::Failed(BulkFailure { account_id: account_id.to_string(), error: err.to_string() })
+            }
+        }
+    }
+
+    fn metadata(&self, asset: &str) -> HashMap<String, String> {
+        HashMap::from([
+            (INITIATOR_KEY.to_string(), "asset-migration".to_string()),
+            (CORRELATION_ID_KEY.to_string(), format!("asset-migration:{}", asset)),
+        ])
+    }
+}
+
+// See `AssetMigrations::resolve`, for the handlers which only hold the asset query.
+pub async fn resolve(asset_query: &PostgresViewRepository<AssetView, Asset>, asset: &str) -> Result<String, MigrationError> {
+    let mut resolved = asset.to_string();
+    for _ in 0..MAX_HOPS {
+        match asset_query.load(&resolved).await {
+            Ok(Some(AssetView { migration: Some(migration), .. })) if migration.completed => resolved = migration.to,
+            Ok(_) => return Ok(resolved),
+            Err(err) => return Err(MigrationError::Rejected(err.to_string())),
+        }
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use cqrs_es::persist::ViewRepository;
+    use postgres_es::{postgres_cqrs, PostgresViewRepository};
+    use crate::account::commands::AccountCommand;
+    use crate::account::ledger::LedgerQuery;
+    use crate::account::queries::AccountQuery;
+    use crate::aggregate_cache::AggregateCache;
+    use crate::asset::types::ConversionRatio;
+    use crate::asset::commands::AssetCommand;
+    use crate::asset::migration::{AssetMigrations, MigrationError, MigrationProgress, MigrationRequest, MigrationStatus};
+    use crate::asset::queries::AssetQuery;
+    use crate::config::{AggregateCacheConfig, BulkConfig, ErrorAlertConfig, ShardConfig};
+    use crate::metrics::{AggregateCacheMetrics, ErrorMetrics};
+    use crate::commit_hooks::HookedEventRepository;
+    use crate::sealing::{sealed_snapshot_cqrs, SealedViewRepository};
+    use crate::services::{BankAccountServices, RegistryBankAccountServices};
+    use crate::util::test_db::{delete_rows, random_suffix, test_pool};
+    use crate::util::types::ByteArray32;
+
+    async fn wait(migrations: &AssetMigrations, asset: &str) -> MigrationProgress {
+        for _ in 0..100 {
+            let progress = migrations.progress(asset).await.unwrap().unwrap();
+            if progress.status != MigrationStatus::Running {
+                return progress;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("migration of {} did not finish", asset);
+    }
+
+    #[tokio::test]
+    async fn test_asset_migration() {
+        let pool = test_pool();
+        let asset_query = Arc::new(PostgresViewRepository::new("asset_query", pool.clone()));
+        let asset_cqrs = Arc::new(postgres_cqrs(pool.clone(), vec![Box::new(AssetQuery::new(asset_query.clone()))], ()));
+        let account_query = Arc::new(SealedViewRepository::new("account_query", pool.clone(), None));
+        let account_cqrs = Arc::new(sealed_snapshot_cqrs(
+            HookedEventRepository::new(pool.clone(), vec![]),
+            vec![Box::new(AccountQuery::new(account_query.clone())), Box::new(LedgerQuery::new(pool.clone()))],
+            100,
+            BankAccountServices::new(Box::new(RegistryBankAccountServices::new(asset_query.clone()))),
+            None,
+            AggregateCache::new(&AggregateCacheConfig::default(), AggregateCacheMetrics::default()),
+            &ShardConfig::default(),
+        ));
+        let suffix = random_suffix().to_uppercase();
+        let (old, new) = (format!("OLD{}", suffix), format!("NEW{}", suffix));
+        for symbol in [&old, &new] {
+            let command = AssetCommand::Register { symbol: symbol.clone(), decimals: 2, timestamp: 1 };
+            asset_cqrs.execute(symbol, command).await.unwrap();
+        }
+        let ids: Vec<String> = (1..=3).map(|n| format!("MIGRATE-{}-{}", suffix, n)).collect();
+        for (n, id) in ids.iter().enumerate() {
+            account_cqrs.execute(id, AccountCommand::account_opened(id.clone())).await.unwrap();
+            let txid = ByteArray32::derive("test", &format!("deposit-{}", n));
+            let asset = if n < 2 { &old } else { &new };
+            account_cqrs.execute(id, AccountCommand::deposited(txid, 1, asset.clone(), 1000 * (n as u64 + 1))).await.unwrap();
+        }
+        // Nothing left to convert on the second account.
+        let txid = ByteArray32::derive("test", "withdraw");
+        account_cqrs.execute(&ids[1], AccountCommand::withdrew(txid, 2, old.clone(), 2000)).await.unwrap();
+        account_cqrs.execute(&ids[0], AccountCommand::account_disabled()).await.unwrap();
+
+        let config = BulkConfig { concurrency: 2, page_size: 1 };
+        let migrations = AssetMigrations::new(asset_cqrs, asset_query, account_cqrs, pool.clone(), ErrorMetrics::new(ErrorAlertConfig::default()), config);
+        let ratio = ConversionRatio { numerator: 1, denominator: 1000 };
+        let request = |to: &str| MigrationRequest { from: old.clone(), to: to.to_string(), ratio };
+        let unknown = migrations.start(request("MISSING"), HashMap::new()).await;
+        assert!(matches!(unknown, Err(MigrationError::UnknownAsset(asset)) if asset == "MISSING"));
+
+        migrations.start(request(&new), HashMap::new()).await.unwrap();
+        let progress = wait(&migrations, &old).await;
+        assert_eq!(progress.status, MigrationStatus::Completed, "{:?}", progress.error);
+        assert_eq!((progress.scanned, progress.converted, progress.skipped, progress.failed), (2, 1, 1, 0));
+        assert_eq!(progress.cursor, ids[1]);
+        assert_eq!(migrations.resolve(&old).await.unwrap(), new);
+        assert_eq!(migrations.resolve(&new).await.unwrap(), new);
+        let balance = serde_json::to_value(account_query.load(&ids[0]).await.unwrap().unwrap()).unwrap()["balance"].clone();
+        assert_eq!(balance, serde_json::json!({ new.clone(): 1 }));
+
+        let again = migrations.start(request(&new), HashMap::new()).await;
+        assert!(matches!(again, Err(MigrationError::AlreadyMigrated(..))));
+
+        delete_rows(&pool, "asset_migration", "from_asset", &old).await;
+        for id in &ids {
+            for table in ["events", "snapshots"] {
+                sqlx::query(&format!("DELETE FROM {} WHERE aggregate_type = 'account' AND aggregate_id = $1", table))
+                    .bind(id)
+                    .execute(&pool)
+                    .await
+                    .unwrap();
+            }
+            for (table, column) in [("account_query", "view_id"), ("ledger_entries", "account_id")] {
+                delete_rows(&pool, table, column, id).await;
+            }
+        }
+        for symbol in [&old, &new] {
+            sqlx::query("DELETE FROM events WHERE aggregate_type = 'asset' AND aggregate_id = $1").bind(symbol).execute(&pool).await.unwrap();
+            delete_rows(&pool, "asset_query", "view_id", symbol).await;
+        }
+    }
+}
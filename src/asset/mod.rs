@@ -0,0 +1,11 @@
+#[cfg(feature = "server")]
+pub mod aggregate;
+pub mod commands;
+#[cfg(feature = "server")]
+pub mod display;
+pub mod events;
+#[cfg(feature = "server")]
+pub mod migration;
+#[cfg(feature = "server")]
+pub mod queries;
+pub mod types;
@@ -0,0 +1,97 @@
+use std::collections::BTreeMap;
+use async_trait::async_trait;
+use cqrs_es::{EventEnvelope, Query, View};
+use cqrs_es::persist::GenericQuery;
+use postgres_es::PostgresViewRepository;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::asset::aggregate::{Asset, AssetMigration};
+use crate::asset::types::{AssetDisplay, TradingRules};
+use crate::asset::events::AssetEvent;
+
+pub struct SimpleLoggingQuery {}
+
+#[derive(Debug, Serialize, Deserialize, Default, ToSchema)]
+pub struct AssetView {
+    pub symbol: String,
+    pub decimals: u8,
+    pub enabled: bool,
+    pub create_time: u64,
+    pub update_time: u64,
+    // What the asset is migrated into, see `crate::asset::migration`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub migration: Option<AssetMigration>,
+    // By the quote asset of each pair the asset is the base of.
+    #[serde(default)]
+    pub trading_rules: BTreeMap<String, TradingRules>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+}
+
+impl AssetView {
+    // How clients format amounts of the asset.
+    pub fn display(&self) -> AssetDisplay {
+        AssetDisplay {
+            symbol: self.symbol.clone(),
+            decimals: self.decimals,
+            display_name: self.display_name.clone().unwrap_or_else(|| self.symbol.clone()),
+        }
+    }
+}
+
+#[async_trait]
+impl Query<Asset> for SimpleLoggingQuery {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<Asset>]) {
+        for event in events {
+            let payload = serde_json::to_string_pretty(&event.payload).unwrap();
+            tracing::debug!("{}-{}\n{}", aggregate_id, event.sequence, payload);
+        }
+    }
+}
+
+pub type AssetQuery = GenericQuery<
+    PostgresViewRepository<AssetView, Asset>,
+    AssetView,
+    Asset,
+>;
+
+impl View<Asset> for AssetView {
+    fn update(&mut self, event: &EventEnvelope<Asset>) {
+        match &event.payload {
+            AssetEvent::Registered { symbol, decimals, timestamp } => {
+                self.symbol = symbol.clone();
+                self.decimals = *decimals;
+                self.enabled = true;
+                self.create_time = *timestamp;
+                self.update_time = *timestamp;
+            }
+            AssetEvent::Enabled { timestamp } => {
+                self.enabled = true;
+                self.update_time = *timestamp;
+            }
+            AssetEvent::Disabled { timestamp } => {
+                self.enabled = false;
+                self.update_time = *timestamp;
+            }
+            AssetEvent::MigrationStarted { to, ratio, timestamp } => {
+                self.enabled = false;
+                self.migration = Some(AssetMigration { to: to.clone(), ratio: *ratio, completed: false });
+                self.update_time = *timestamp;
+            }
+            AssetEvent::MigrationCompleted { timestamp } => {
+                if let Some(migration) = &mut self.migration {
+                    migration.completed = true;
+                }
+                self.update_time = *timestamp;
+            }
+            AssetEvent::TradingRulesSet { quote, rules, timestamp } => {
+                self.trading_rules.insert(quote.clone(), *rules);
+                self.update_time = *timestamp;
+            }
+            AssetEvent::DisplayNameSet { display_name, timestamp } => {
+                self.display_name = Some(display_name.clone());
+                self.update_time = *timestamp;
+            }
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use std::cmp::Ordering;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+// What an asset converts into when it is migrated: `numerator` units of the new asset
+// for every `denominator` units of the old one, rounded down. 1:1 renames the asset.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub struct ConversionRatio {
+    pub numerator: u64,
+    pub denominator: u64,
+}
+
+impl ConversionRatio {
+    pub fn is_valid(&self) -> bool {
+        self.numerator > 0 && self.denominator > 0
+    }
+
+    // None when the converted amount does not fit.
+    pub fn convert(&self, amount: u64) -> Option<u64> {
+        u64::try_from(amount as u128 * self.numerator as u128 / self.denominator as u128).ok()
+    }
+}
+
+// What clients need to show amounts of an asset, which are integers in units of
+// 10^-decimals: 123456 of an asset with 2 decimals reads 1234.56.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub struct AssetDisplay {
+    pub symbol: String,
+    pub decimals: u8,
+    pub display_name: String,
+}
+
+// A price expressed as `num` units of the buy asset per `den` units of the sell asset.
+// Kept as a ratio so clearing never goes through floating point.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, Default, ToSchema)]
+pub struct Price {
+    pub num: u64,
+    pub den: u64,
+}
+
+impl Price {
+    pub fn new(num: u64, den: u64) -> Self {
+        Price { num, den }
+    }
+
+    // The amount of buy asset owed for `sell_amount` at this price, rounded up
+    // so the seller never receives less than its limit.
+    pub fn cost_of(&self, sell_amount: u64) -> u64 {
+        let total = sell_amount as u128 * self.num as u128;
+        total.div_ceil(self.den as u128) as u64
+    }
+}
+
+impl PartialEq for Price {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl PartialOrd for Price {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Price {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.num as u128 * other.den as u128).cmp(&(other.num as u128 * self.den as u128))
+    }
+}
+
+// How orders may trade a pair, held by the base asset for each quote asset it trades
+// against: base quantities in multiples of `tick_size`, a notional of at least
+// `min_notional` of the quote asset, and prices within `min_price` and `max_price` where
+// set. Checked by `crate::order::policy` when an order is opened, the defaults let any
+// order through.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(default)]
+pub struct TradingRules {
+    pub tick_size: u64,
+    pub min_notional: u64,
+    // In quote units per base unit, like the prices of the order book.
+    pub min_price: Option<Price>,
+    pub max_price: Option<Price>,
+}
+
+impl Default for TradingRules {
+    fn default() -> Self {
+        TradingRules { tick_size: 1, min_notional: 0, min_price: None, max_price: None }
+    }
+}
+
+impl TradingRules {
+    // Why the rules cannot be set, if they cannot.
+    pub fn invalid(&self) -> Option<String> {
+        if self.tick_size == 0 {
+            return Some("tick size must be positive".to_string());
+        }
+        if self.min_price.iter().chain(&self.max_price).any(|price| price.num == 0 || price.den == 0) {
+            return Some("price limits must be positive".to_string());
+        }
+        match (self.min_price, self.max_price) {
+            (Some(min_price), Some(max_price)) if min_price > max_price => Some("minimum price is above the maximum price".to_string()),
+            _ => None,
+        }
+    }
+
+    // Why an order trading `quantity` of the base asset for `quote_amount` of the quote
+    // asset breaks the rules, if it does. Its price is `quote_amount / quantity`, and its
+    // notional that price times the quantity.
+    pub fn violation(&self, quantity: u64, quote_amount: u64) -> Option<String> {
+        let price = Price::new(quote_amount, quantity);
+        let notional = price.cost_of(quantity);
+        if !quantity.is_multiple_of(self.tick_size.max(1)) {
+            Some(format!("quantity {} is not a multiple of the tick size {}", quantity, self.tick_size))
+        } else if notional < self.min_notional {
+            Some(format!("notional {} is below the minimum of {}", notional, self.min_notional))
+        } else if self.min_price.is_some_and(|min_price| price < min_price) {
+            Some(format!("price {}/{} is below the band", price.num, price.den))
+        } else if self.max_price.is_some_and(|max_price| price > max_price) {
+            Some(format!("price {}/{} is above the band", price.num, price.den))
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,381 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem::swap;
+use std::sync::Arc;
+use async_trait::async_trait;
+use cqrs_es::{Aggregate, AggregateError};
+use serde::{Deserialize, Serialize};
+use crate::account::commands::AccountCommand;
+use crate::account::events::AccountError;
+use crate::auction::commands::AuctionCommand;
+use crate::auction::engine::clear;
+use crate::auction::events::{AuctionConfig, AuctionEvent, Bid, Fill, Price};
+use crate::services::AccountExecutor;
+use crate::statemachine::{StateMachine, Transition};
+use crate::metrics::ErrorVariant;
+use crate::util::clock::{Clock, SystemClock};
+use crate::util::types::ByteArray32;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub enum Auction {
+    #[default]
+    Uninitialized,
+    Open {
+        config: AuctionConfig,
+        bids: BTreeMap<String, Bid>,
+        // Ids of withdrawn bids, which name holds that are already released.
+        #[serde(default)]
+        withdrawn: BTreeSet<String>,
+    },
+    Cleared {
+        config: AuctionConfig,
+        price: Option<Price>,
+        pending: BTreeMap<String, Fill>,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuctionError {
+    #[error("Invalid state: {0}")]
+    InvalidState(String),
+    #[error("Invalid bid: {0}")]
+    InvalidBid(String),
+    #[error("Bid not found: {0}")]
+    BidNotFound(String),
+    #[error("Auction window is still open until {0}")]
+    WindowOpen(u64),
+    #[error("Fill not found: {0}")]
+    FillNotFound(String),
+    #[error("Account error: {0}")]
+    AccountError(#[from] AccountError),
+    #[error("Aggregate error: {0}")]
+    AggregateError(#[from] AggregateError<AccountError>),
+}
+
+impl ErrorVariant for AuctionError {
+    fn variant(&self) -> &'static str {
+        match self {
+            AuctionError::InvalidState(_) => "InvalidState",
+            AuctionError::InvalidBid(_) => "InvalidBid",
+            AuctionError::BidNotFound(_) => "BidNotFound",
+            AuctionError::WindowOpen(_) => "WindowOpen",
+            AuctionError::FillNotFound(_) => "FillNotFound",
+            AuctionError::AccountError(e) => e.variant(),
+            AuctionError::AggregateError(e) => e.variant(),
+        }
+    }
+}
+
+// The hold on the bidder account that funds a bid.
+pub fn bid_hold(auction_id: &str, bid_id: &str) -> String {
+    format!("auction-{}-{}", auction_id, bid_id)
+}
+
+// A bid holds its whole budget on the bidder account from the moment it is placed, so
+// a winning bid is funded at settlement. Withdrawing the bid releases the hold, and
+// clearing releases whatever the fills of the bid leave unused.
+#[derive(Clone)]
+pub struct AuctionServices {
+    account_service: Arc<dyn AccountExecutor>,
+    clock: Arc<dyn Clock>,
+}
+
+impl AuctionServices {
+    pub fn new(account_service: Arc<dyn AccountExecutor>) -> Self {
+        AuctionServices { account_service, clock: Arc::new(SystemClock) }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // The txids derive from the hold, so a command retried after its account side
+    // went through is a duplicate transaction and counts as done.
+    async fn execute(&self, account_id: &str, command: AccountCommand) -> Result<(), AuctionError> {
+        match self.account_service.execute(account_id, command).await {
+            Ok(_) | Err(AggregateError::UserError(AccountError::DuplicateTransaction(_))) => Ok(()),
+            Err(AggregateError::UserError(ae)) => Err(AuctionError::AccountError(ae)),
+            Err(e) => Err(AuctionError::AggregateError(e)),
+        }
+    }
+
+    async fn hold(&self, config: &AuctionConfig, bid: &Bid) -> Result<(), AuctionError> {
+        let hold = bid_hold(&config.auction_id, &bid.bid_id);
+        let txid = ByteArray32::derive("auction-bid", &hold);
+        let command = AccountCommand::place_hold(txid, bid.timestamp, hold, config.buy_asset.clone(), bid.max_total);
+        self.execute(&bid.bidder, command).await
+    }
+
+    async fn release(&self, config: &AuctionConfig, bid: &Bid, step: &str, amount: u64) -> Result<(), AuctionError> {
+        let hold = bid_hold(&config.auction_id, &bid.bid_id);
+        let txid = ByteArray32::derive(step, &hold);
+        let command = AccountCommand::release_hold(txid, self.clock.now(), hold, amount);
+        self.execute(&bid.bidder, command).await
+    }
+}
+
+#[async_trait]
+impl Aggregate for Auction {
+    type Command = AuctionCommand;
+    type Event = AuctionEvent;
+    type Error = AuctionError;
+    type Services = AuctionServices;
+
+    fn aggregate_type() -> String {
+        "auction".to_string()
+    }
+
+    async fn handle(
+        &self,
+        command: Self::Command,
+        services: &Self::Services,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        match (self, command) {
+            (Auction::Uninitialized, AuctionCommand::Open { config }) => {
+                if config.window_end <= config.window_start {
+                    return Err(AuctionError::InvalidState("Auction window must not be empty".to_string()));
+                }
+                Ok(vec![AuctionEvent::Opened { config }])
+            },
+            (Auction::Open { config, bids, withdrawn }, AuctionCommand::PlaceBid { bid }) => {
+                if bid.timestamp < config.window_start || bid.timestamp >= config.window_end {
+                    return Err(AuctionError::InvalidBid(format!("bid time {} is outside of the auction window", bid.timestamp)));
+                }
+                if bid.max_amount == 0 || bid.max_total == 0 {
+                    return Err(AuctionError::InvalidBid("bid amounts must be positive".to_string()));
+                }
+                if bids.contains_key(&bid.bid_id) || withdrawn.contains(&bid.bid_id) {
+                    return Err(AuctionError::InvalidBid(format!("duplicate bid id {}", bid.bid_id)));
+                }
+                services.hold(config, &bid).await?;
+                Ok(vec![AuctionEvent::BidPlaced { bid }])
+            },
+            (Auction::Open { config, bids, .. }, AuctionCommand::WithdrawBid { bid_id }) => {
+                let Some(bid) = bids.get(&bid_id) else {
+                    return Err(AuctionError::BidNotFound(bid_id));
+                };
+                services.release(config, bid, "auction-withdraw", bid.max_total).await?;
+                Ok(vec![AuctionEvent::BidWithdrawn { bid_id }])
+            },
+            (Auction::Open { config, bids, .. }, AuctionCommand::Clear { asks, timestamp }) => {
+                if timestamp < config.window_end {
+                    return Err(AuctionError::WindowOpen(config.window_end));
+                }
+                let bids: Vec<Bid> = bids.values().cloned().collect();
+                let (price, fills) = clear(&asks, &bids);
+                // The fills lock their cost out of the hold when they settle, the rest of
+                // the budget is spendable again.
+                for bid in &bids {
+                    let filled: u64 = fills.iter().filter(|fill| fill.bid_id == bid.bid_id).map(|fill| fill.buy_amount).sum();
+                    let unused = bid.max_total.saturating_sub(filled);
+                    if unused > 0 {
+                        services.release(config, bid, "auction-unused", unused).await?;
+                    }
+                }
+                Ok(vec![AuctionEvent::Cleared { price, fills, timestamp }])
+            },
+            (Auction::Cleared { pending, .. }, AuctionCommand::RecordFill { order_id, outcome }) => {
+                if !pending.contains_key(&order_id) {
+                    return Err(AuctionError::FillNotFound(order_id));
+                }
+                Ok(vec![AuctionEvent::FillRecorded { order_id, outcome }])
+            },
+            (state, cmd) => {
+                Err(AuctionError::InvalidState(format!("Auction current at {:?} state, cannot accept {:?} command", state, cmd)))
+            }
+        }
+    }
+
+    fn apply(&mut self, event: Self::Event) {
+        let mut prev = Default::default();
+        swap(&mut prev, self);
+        *self = match (prev, event) {
+            (Auction::Uninitialized, AuctionEvent::Opened { config }) => Auction::Open {
+                config,
+                bids: BTreeMap::new(),
+                withdrawn: BTreeSet::new(),
+            },
+            (Auction::Open { config, mut bids, withdrawn }, AuctionEvent::BidPlaced { bid }) => {
+                bids.insert(bid.bid_id.clone(), bid);
+                Auction::Open { config, bids, withdrawn }
+            },
+            (Auction::Open { config, mut bids, mut withdrawn }, AuctionEvent::BidWithdrawn { bid_id }) => {
+                bids.remove(&bid_id);
+                withdrawn.insert(bid_id);
+                Auction::Open { config, bids, withdrawn }
+            },
+            (Auction::Open { config, .. }, AuctionEvent::Cleared { price, fills, .. }) => Auction::Cleared {
+                config,
+                price,
+                pending: fills.into_iter().map(|fill| (fill.order_id.clone(), fill)).collect(),
+            },
+            (Auction::Cleared { config, price, mut pending }, AuctionEvent::FillRecorded { order_id, .. }) => {
+                pending.remove(&order_id);
+                Auction::Cleared { config, price, pending }
+            },
+            (state, event) => unreachable!("Invalid state transition: {:?} -> {:?}", state, event),
+        };
+    }
+}
+
+impl StateMachine for Auction {
+    const TRANSITIONS: &'static [Transition] = &[
+        Transition { from: "Uninitialized", command: "Open", guard: None, events: &["Opened"], to: "Open" },
+        Transition { from: "Open", command: "PlaceBid", guard: Some("inside the window, funds held"), events: &["BidPlaced"], to: "Open" },
+        Transition { from: "Open", command: "WithdrawBid", guard: None, events: &["BidWithdrawn"], to: "Open" },
+        Transition { from: "Open", command: "Clear", guard: Some("window closed"), events: &["Cleared"], to: "Cleared" },
+        Transition { from: "Cleared", command: "RecordFill", guard: None, events: &["FillRecorded"], to: "Cleared" },
+    ];
+}
+
+#[cfg(test)]
+mod aggregate_tests {
+    use std::sync::{Arc, Mutex};
+    use async_trait::async_trait;
+    use cqrs_es::AggregateError;
+    use cqrs_es::test::TestFramework;
+
+    use crate::account::commands::{AccountCommand, TransactionCommand};
+    use crate::account::events::AccountError;
+    use crate::auction::aggregate::{Auction, AuctionServices};
+    use crate::auction::commands::AuctionCommand;
+    use crate::auction::events::{Ask, AuctionConfig, AuctionEvent, Bid, Fill, Price};
+    use crate::services::AccountExecutor;
+    use crate::util::clock::FixedClock;
+
+    // Records the hold commands run, turning away holds above `funds`.
+    struct Accounts {
+        funds: u64,
+        executed: Mutex<Vec<(String, &'static str, u64)>>,
+    }
+
+    #[async_trait]
+    impl AccountExecutor for Accounts {
+        async fn execute(&self, account_id: &str, command: AccountCommand) -> Result<(), AggregateError<AccountError>> {
+            let AccountCommand::Transaction { command, .. } = command else {
+                panic!("an auction only runs transactions");
+            };
+            let executed = match command {
+                TransactionCommand::PlaceHold { amount, .. } if amount > self.funds => {
+                    return Err(AggregateError::UserError(AccountError::InsufficientFunds));
+                },
+                TransactionCommand::PlaceHold { hold, amount, .. } => (hold, "PlaceHold", amount),
+                TransactionCommand::ReleaseHold { hold, amount } => (hold, "ReleaseHold", amount),
+                _ => panic!("an auction only places and releases holds"),
+            };
+            assert_eq!(executed.0, "auction-BTC-ETH-0-b1");
+            self.executed.lock().unwrap().push((account_id.to_string(), executed.1, executed.2));
+            Ok(())
+        }
+    }
+
+    fn accounts(funds: u64) -> Arc<Accounts> {
+        Arc::new(Accounts { funds, executed: Mutex::new(vec![]) })
+    }
+
+    fn services(accounts: Arc<Accounts>) -> AuctionServices {
+        AuctionServices::new(accounts).with_clock(Arc::new(FixedClock::new(60)))
+    }
+
+    fn opened() -> AuctionEvent {
+        AuctionEvent::Opened {
+            config: AuctionConfig {
+                auction_id: "BTC-ETH-0".to_string(),
+                sell_asset: "BTC".to_string(),
+                buy_asset: "ETH".to_string(),
+                window_start: 0,
+                window_end: 60,
+            },
+        }
+    }
+
+    fn bid(bid_id: &str, timestamp: u64) -> Bid {
+        Bid {
+            bid_id: bid_id.to_string(),
+            bidder: "ACCT-0002".to_string(),
+            max_amount: 10,
+            max_total: 200,
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn test_place_bid_in_window() {
+        let accounts = accounts(1_000);
+        TestFramework::<Auction>::with(services(accounts.clone()))
+            .given(vec![opened()])
+            .when(AuctionCommand::PlaceBid { bid: bid("b1", 30) })
+            .then_expect_events(vec![AuctionEvent::BidPlaced { bid: bid("b1", 30) }]);
+        assert_eq!(*accounts.executed.lock().unwrap(), vec![("ACCT-0002".to_string(), "PlaceHold", 200)]);
+    }
+
+    #[test]
+    fn test_place_unfunded_bid() {
+        TestFramework::<Auction>::with(services(accounts(199)))
+            .given(vec![opened()])
+            .when(AuctionCommand::PlaceBid { bid: bid("b1", 30) })
+            .then_expect_error_message("Account error: Insufficient funds");
+    }
+
+    #[test]
+    fn test_withdrawn_bid_id_not_reused() {
+        let accounts = accounts(1_000);
+        TestFramework::<Auction>::with(services(accounts.clone()))
+            .given(vec![opened(), AuctionEvent::BidPlaced { bid: bid("b1", 30) }])
+            .when(AuctionCommand::WithdrawBid { bid_id: "b1".to_string() })
+            .then_expect_events(vec![AuctionEvent::BidWithdrawn { bid_id: "b1".to_string() }]);
+        assert_eq!(*accounts.executed.lock().unwrap(), vec![("ACCT-0002".to_string(), "ReleaseHold", 200)]);
+        TestFramework::<Auction>::with(services(accounts))
+            .given(vec![
+                opened(),
+                AuctionEvent::BidPlaced { bid: bid("b1", 30) },
+                AuctionEvent::BidWithdrawn { bid_id: "b1".to_string() },
+            ])
+            .when(AuctionCommand::PlaceBid { bid: bid("b1", 40) })
+            .then_expect_error_message("Invalid bid: duplicate bid id b1");
+    }
+
+    #[test]
+    fn test_place_bid_after_window() {
+        TestFramework::<Auction>::with(services(accounts(1_000)))
+            .given(vec![opened()])
+            .when(AuctionCommand::PlaceBid { bid: bid("b1", 60) })
+            .then_expect_error_message("Invalid bid: bid time 60 is outside of the auction window");
+    }
+
+    #[test]
+    fn test_clear_before_window_end() {
+        TestFramework::<Auction>::with(services(accounts(1_000)))
+            .given(vec![opened()])
+            .when(AuctionCommand::Clear { asks: vec![], timestamp: 59 })
+            .then_expect_error_message("Auction window is still open until 60");
+    }
+
+    #[test]
+    fn test_clear_matches_bid() {
+        let ask = Ask {
+            order_id: "order-1".to_string(),
+            seller: "ACCT-0001".to_string(),
+            sell_amount: 10,
+            buy_amount: 150,
+            timestamp: 10,
+        };
+        let accounts = accounts(1_000);
+        TestFramework::<Auction>::with(services(accounts.clone()))
+            .given(vec![opened(), AuctionEvent::BidPlaced { bid: bid("b1", 30) }])
+            .when(AuctionCommand::Clear { asks: vec![ask], timestamp: 60 })
+            .then_expect_events(vec![AuctionEvent::Cleared {
+                price: Some(Price::new(150, 10)),
+                fills: vec![Fill {
+                    order_id: "order-1".to_string(),
+                    buyer: "ACCT-0002".to_string(),
+                    bid_id: "b1".to_string(),
+                    sell_amount: 10,
+                    buy_amount: 150,
+                }],
+                timestamp: 60,
+            }]);
+        // The fill keeps its cost held, the rest of the budget is released.
+        assert_eq!(*accounts.executed.lock().unwrap(), vec![("ACCT-0002".to_string(), "ReleaseHold", 50)]);
+    }
+}
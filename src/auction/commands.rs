@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::auction::events::{Ask, AuctionConfig, Bid, FillOutcome};
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub enum AuctionCommand {
+    Open {
+        config: AuctionConfig,
+    },
+    PlaceBid {
+        bid: Bid,
+    },
+    WithdrawBid {
+        bid_id: String,
+    },
+    Clear {
+        asks: Vec<Ask>,
+        timestamp: u64,
+    },
+    RecordFill {
+        order_id: String,
+        outcome: FillOutcome,
+    },
+}
@@ -0,0 +1,399 @@
+use std::sync::Arc;
+use std::time::Duration;
+use cqrs_es::AggregateError;
+use cqrs_es::persist::ViewRepository;
+use postgres_es::{PostgresCqrs, PostgresViewRepository};
+use crate::background::{Background, Shutdown};
+use crate::commit_hooks::HookedCqrs;
+use sqlx::{Pool, Postgres, Row};
+use crate::account::commands::AccountCommand;
+use crate::account::events::AccountError;
+use crate::auction::aggregate::{bid_hold, Auction};
+use crate::auction::commands::AuctionCommand;
+use crate::auction::events::{Ask, AuctionConfig, Bid, Fill, FillOutcome, Price};
+use crate::auction::queries::AuctionView;
+use crate::order::aggregate::Order;
+use crate::order::commands::OrderCommand;
+use crate::order::queries::{OrderState, OrderView};
+use crate::services::AccountExecutor;
+use crate::util::clock;
+use crate::util::types::ByteArray32;
+
+// Attempts at settling a fill before it is given up and its funds are handed back.
+const FILL_ATTEMPTS: u32 = 3;
+const FILL_BACKOFF: Duration = Duration::from_millis(500);
+
+// Computes the uniform clearing price for a set of asks and bids and allocates
+// whole asks to bids at that price.
+//
+// The price is the candidate limit that executes the most volume; ties are broken
+// by the smallest supply/demand imbalance and then by the lowest price. Asks are
+// filled all-or-nothing, cheapest first, by the highest bids that still have
+// enough remaining size and budget.
+pub fn clear(asks: &[Ask], bids: &[Bid]) -> (Option<Price>, Vec<Fill>) {
+    let asks: Vec<&Ask> = asks.iter().filter(|a| a.sell_amount > 0).collect();
+    let bids: Vec<&Bid> = bids.iter().filter(|b| b.max_amount > 0).collect();
+
+    let mut best: Option<(u128, u128, Price)> = None;
+    for price in asks.iter().map(|a| a.limit()).chain(bids.iter().map(|b| b.limit())) {
+        let supply: u128 = asks.iter().filter(|a| a.limit() <= price).map(|a| a.sell_amount as u128).sum();
+        let demand: u128 = bids.iter().filter(|b| b.limit() >= price).map(|b| b.max_amount as u128).sum();
+        let executed = supply.min(demand);
+        let imbalance = supply.abs_diff(demand);
+        let better = match &best {
+            None => true,
+            Some((e, i, p)) => (executed, std::cmp::Reverse(imbalance), std::cmp::Reverse(price))
+                > (*e, std::cmp::Reverse(*i), std::cmp::Reverse(*p)),
+        };
+        if better {
+            best = Some((executed, imbalance, price));
+        }
+    }
+    let Some((executed, _, price)) = best else {
+        return (None, vec![]);
+    };
+    if executed == 0 {
+        return (None, vec![]);
+    }
+
+    let mut eligible_asks: Vec<&Ask> = asks.into_iter().filter(|a| a.limit() <= price).collect();
+    eligible_asks.sort_by(|a, b| {
+        a.limit().cmp(&b.limit())
+            .then(a.timestamp.cmp(&b.timestamp))
+            .then(a.order_id.cmp(&b.order_id))
+    });
+    let mut eligible_bids: Vec<(&Bid, u64, u64)> = bids
+        .into_iter()
+        .filter(|b| b.limit() >= price)
+        .map(|b| (b, b.max_amount, b.max_total))
+        .collect();
+    eligible_bids.sort_by(|(a, ..), (b, ..)| {
+        b.limit().cmp(&a.limit())
+            .then(a.timestamp.cmp(&b.timestamp))
+            .then(a.bid_id.cmp(&b.bid_id))
+    });
+
+    let mut fills = vec![];
+    for ask in eligible_asks {
+        let cost = price.cost_of(ask.sell_amount);
+        let matched = eligible_bids.iter_mut().find(|(bid, size, budget)| {
+            bid.bidder != ask.seller && *size >= ask.sell_amount && *budget >= cost
+        });
+        if let Some((bid, size, budget)) = matched {
+            *size -= ask.sell_amount;
+            *budget -= cost;
+            fills.push(Fill {
+                order_id: ask.order_id.clone(),
+                buyer: bid.bidder.clone(),
+                bid_id: bid.bid_id.clone(),
+                sell_amount: ask.sell_amount,
+                buy_amount: cost,
+            });
+        }
+    }
+    (Some(price), fills)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuctionPair {
+    pub sell_asset: String,
+    pub buy_asset: String,
+    pub window_secs: u64,
+}
+
+impl AuctionPair {
+    // Parses a schedule such as `BTC/ETH=60;ETH/BTC=300`, skipping malformed entries.
+    pub fn parse_schedule(schedule: &str) -> Vec<AuctionPair> {
+        schedule
+            .split(';')
+            .filter_map(|entry| {
+                let (pair, window) = entry.trim().split_once('=')?;
+                let (sell_asset, buy_asset) = pair.split_once('/')?;
+                let window_secs = window.trim().parse().ok().filter(|w| *w > 0)?;
+                Some(AuctionPair {
+                    sell_asset: sell_asset.trim().to_string(),
+                    buy_asset: buy_asset.trim().to_string(),
+                    window_secs,
+                })
+            })
+            .collect()
+    }
+
+    pub fn auction_id(&self, window_start: u64) -> String {
+        format!("{}-{}-{}", self.sell_asset, self.buy_asset, window_start)
+    }
+
+    fn window_start(&self, now: u64) -> u64 {
+        now - now % self.window_secs
+    }
+}
+
+// Drives the auction schedule: opens an auction for every window of every pair,
+// clears it once the window has passed, and settles the fills through the order saga.
+#[derive(Clone)]
+pub struct AuctionEngine {
+    auction_cqrs: Arc<PostgresCqrs<Auction>>,
+    auction_query: Arc<PostgresViewRepository<AuctionView, Auction>>,
+    order_cqrs: Arc<HookedCqrs<Order>>,
+    account_service: Arc<dyn AccountExecutor>,
+    pool: Pool<Postgres>,
+}
+
+impl AuctionEngine {
+    pub fn new(
+        auction_cqrs: Arc<PostgresCqrs<Auction>>,
+        auction_query: Arc<PostgresViewRepository<AuctionView, Auction>>,
+        order_cqrs: Arc<HookedCqrs<Order>>,
+        account_service: Arc<dyn AccountExecutor>,
+        pool: Pool<Postgres>,
+    ) -> Self {
+        AuctionEngine { auction_cqrs, auction_query, order_cqrs, account_service, pool }
+    }
+
+    pub fn spawn(self, background: &Background, schedule: Vec<AuctionPair>) {
+        for pair in schedule {
+            let engine = self.clone();
+            background.spawn(|shutdown| async move { engine.run(pair, shutdown).await });
+        }
+    }
+
+    // An auction left open by a shutdown is closed by the next node to run its pair.
+    async fn run(&self, pair: AuctionPair, mut shutdown: Shutdown) {
+        loop {
+            let now = clock::now();
+            let window_start = pair.window_start(now);
+            let window_end = window_start + pair.window_secs;
+            let config = AuctionConfig {
+                auction_id: pair.auction_id(window_start),
+                sell_asset: pair.sell_asset.clone(),
+                buy_asset: pair.buy_asset.clone(),
+                window_start,
+                window_end,
+            };
+            // Opening twice (e.g. after a restart) is rejected by the aggregate and harmless.
+            if let Err(e) = self.auction_cqrs.execute(&config.auction_id, AuctionCommand::Open { config: config.clone() }).await {
+                tracing::debug!("Auction {} not opened: {}", config.auction_id, e);
+            }
+            if !shutdown.pause(Duration::from_secs(window_end.saturating_sub(now))).await {
+                break;
+            }
+            if let Err(e) = self.close(&pair, &config.auction_id).await {
+                tracing::error!("Failed to close auction {}: {}", config.auction_id, e);
+            }
+        }
+    }
+
+    async fn close(&self, pair: &AuctionPair, auction_id: &str) -> Result<(), String> {
+        let asks = placed_auction_orders(&self.pool, &pair.sell_asset, &pair.buy_asset)
+            .await
+            .map_err(|e| e.to_string())?;
+        let command = AuctionCommand::Clear {
+            asks,
+            timestamp: clock::now(),
+        };
+        self.auction_cqrs.execute(auction_id, command).await.map_err(|e| e.to_string())?;
+        let Some(view) = self.auction_query.load(auction_id).await.map_err(|e| e.to_string())? else {
+            return Err("auction view not found after clearing".to_string());
+        };
+        for fill in view.fills {
+            let outcome = self.settle(auction_id, &fill.fill).await;
+            let command = AuctionCommand::RecordFill { order_id: fill.fill.order_id.clone(), outcome };
+            if let Err(e) = self.auction_cqrs.execute(auction_id, command).await {
+                tracing::error!("Failed to record fill of {} in {}: {}", fill.fill.order_id, auction_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    // Settles a fill, retrying with backoff from wherever the previous attempt left the
+    // order, and hands the funds back to the bidder once the attempts are exhausted.
+    async fn settle(&self, auction_id: &str, fill: &Fill) -> FillOutcome {
+        let mut reason = String::new();
+        for attempt in 0..FILL_ATTEMPTS {
+            if attempt > 0 {
+                tokio::time::sleep(FILL_BACKOFF * 2u32.pow(attempt - 1)).await;
+            }
+            match self.advance(auction_id, fill).await {
+                Ok(()) => return FillOutcome::Settled,
+                Err(e) => {
+                    tracing::warn!("Attempt {} at filling {} in {} failed: {}", attempt + 1, fill.order_id, auction_id, e);
+                    reason = e;
+                }
+            }
+        }
+        self.compensate(auction_id, fill).await;
+        FillOutcome::Failed { reason }
+    }
+
+    // Runs a fill through the order saga: Fill -> Continue (lock buyer) -> Continue (settle).
+    // The cost is locked out of the bid hold first, which the buyer lock of the saga
+    // then finds in place.
+    async fn advance(&self, auction_id: &str, fill: &Fill) -> Result<(), String> {
+        // A buyer lock that fails sends the order back to Placed, so the steps are bounded.
+        for _ in 0..4 {
+            let view = order_view(&self.pool, &fill.order_id)
+                .await
+                .map_err(|e| e.to_string())?
+                .ok_or_else(|| format!("order {} not found", fill.order_id))?;
+            let command = match view.status {
+                OrderState::Settled => return Ok(()),
+                OrderState::Placed => {
+                    let lock: ByteArray32 = view.id.parse().map_err(|_| format!("order {} has an invalid id", fill.order_id))?;
+                    let hold = bid_hold(auction_id, &fill.bid_id);
+                    let command = AccountCommand::lock_held(lock, clock::now(), hold, fill.buy_amount);
+                    match self.account_service.execute(&fill.buyer, command).await {
+                        Ok(_) | Err(AggregateError::UserError(AccountError::DuplicateLock)) => {},
+                        Err(e) => return Err(e.to_string()),
+                    }
+                    OrderCommand::Fill {
+                        buyer: fill.buyer.clone(),
+                        buy_amount: fill.buy_amount,
+                        timestamp: clock::now(),
+                    }
+                },
+                OrderState::Buying | OrderState::Bought => OrderCommand::Continue,
+                state => return Err(format!("order ended in {:?} state", state)),
+            };
+            self.order_cqrs.execute(&fill.order_id, command).await.map_err(|e| e.to_string())?;
+        }
+        Err("order not settled".to_string())
+    }
+
+    // Gives the cost of a fill that could not settle back to the bidder: unlocks it when
+    // it was locked out of the hold, releases it from the hold otherwise. An order the
+    // saga still owns keeps its lock, the order recovery finishes it.
+    async fn compensate(&self, auction_id: &str, fill: &Fill) {
+        let view = match order_view(&self.pool, &fill.order_id).await {
+            Ok(Some(view)) => view,
+            Ok(None) => return,
+            Err(e) => {
+                tracing::error!("Failed to load order {} to compensate its fill: {}", fill.order_id, e);
+                return;
+            }
+        };
+        if matches!(view.status, OrderState::Buying | OrderState::Bought | OrderState::Settled) {
+            return;
+        }
+        let Ok(lock) = view.id.parse::<ByteArray32>() else {
+            return;
+        };
+        let hold = bid_hold(auction_id, &fill.bid_id);
+        let unlocked = match self.account_service.execute(&fill.buyer, AccountCommand::unlock_funds(lock, clock::now())).await {
+            Err(AggregateError::UserError(AccountError::LockNotFound)) => {
+                let txid = ByteArray32::derive("auction-unfilled", &format!("{}-{}", hold, fill.order_id));
+                match self.account_service.execute(&fill.buyer, AccountCommand::release_hold(txid, clock::now(), hold, fill.buy_amount)).await {
+                    Err(AggregateError::UserError(AccountError::DuplicateTransaction(_))) => Ok(()),
+                    result => result,
+                }
+            },
+            result => result,
+        };
+        if let Err(e) = unlocked {
+            tracing::error!("Failed to hand back the funds of fill {} in {} to {}: {}", fill.order_id, auction_id, fill.buyer, e);
+        }
+    }
+}
+
+async fn placed_auction_orders(pool: &Pool<Postgres>, sell_asset: &str, buy_asset: &str) -> Result<Vec<Ask>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT view_id, payload FROM order_query
+         WHERE payload->>'status' = 'Placed' AND payload->>'auction' = 'true'
+           AND payload->>'sell_asset' = $1 AND payload->>'buy_asset' = $2",
+    )
+        .bind(sell_asset)
+        .bind(buy_asset)
+        .fetch_all(pool)
+        .await?;
+    let mut asks = Vec::with_capacity(rows.len());
+    for row in rows {
+        let payload: serde_json::Value = row.try_get("payload")?;
+        let view: OrderView = serde_json::from_value(payload).map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+        asks.push(Ask {
+            order_id: row.try_get("view_id")?,
+            seller: view.seller,
+            sell_amount: view.sell_amount,
+            buy_amount: view.buy_amount,
+            timestamp: view.create_time,
+        });
+    }
+    Ok(asks)
+}
+
+pub(crate) async fn order_status(pool: &Pool<Postgres>, order_id: &str) -> Result<Option<OrderState>, sqlx::Error> {
+    Ok(order_view(pool, order_id).await?.map(|view| view.status))
+}
+
+async fn order_view(pool: &Pool<Postgres>, order_id: &str) -> Result<Option<OrderView>, sqlx::Error> {
+    let row = sqlx::query("SELECT payload FROM order_query WHERE view_id = $1")
+        .bind(order_id)
+        .fetch_optional(pool)
+        .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let payload: serde_json::Value = row.try_get("payload")?;
+    let view: OrderView = serde_json::from_value(payload).map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+    Ok(Some(view))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::auction::engine::{clear, AuctionPair};
+    use crate::auction::events::{Ask, Bid, Price};
+
+    fn ask(order_id: &str, sell_amount: u64, buy_amount: u64) -> Ask {
+        Ask {
+            order_id: order_id.to_string(),
+            seller: format!("seller-{}", order_id),
+            sell_amount,
+            buy_amount,
+            timestamp: 0,
+        }
+    }
+
+    fn bid(bid_id: &str, max_amount: u64, max_total: u64) -> Bid {
+        Bid {
+            bid_id: bid_id.to_string(),
+            bidder: format!("bidder-{}", bid_id),
+            max_amount,
+            max_total,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn test_no_crossing_orders() {
+        let (price, fills) = clear(&[ask("a", 10, 300)], &[bid("b", 10, 100)]);
+        assert_eq!(price, None);
+        assert!(fills.is_empty());
+    }
+
+    #[test]
+    fn test_single_price_for_all_fills() {
+        let asks = [ask("a1", 10, 100), ask("a2", 10, 150), ask("a3", 10, 400)];
+        let bids = [bid("b1", 10, 300), bid("b2", 10, 200)];
+        let (price, fills) = clear(&asks, &bids);
+        assert_eq!(price, Some(Price::new(150, 10)));
+        assert_eq!(fills.len(), 2);
+        assert!(fills.iter().all(|f| f.buy_amount == 150));
+        assert_eq!(fills[0].order_id, "a1");
+        assert_eq!(fills[0].buyer, "bidder-b1");
+        assert_eq!(fills[1].order_id, "a2");
+        assert_eq!(fills[1].buyer, "bidder-b2");
+    }
+
+    #[test]
+    fn test_cost_rounds_up_for_seller() {
+        assert_eq!(Price::new(10, 3).cost_of(1), 4);
+    }
+
+    #[test]
+    fn test_parse_schedule() {
+        let schedule = AuctionPair::parse_schedule("BTC/ETH=60; ETH/BTC=0;junk");
+        assert_eq!(schedule, vec![AuctionPair {
+            sell_asset: "BTC".to_string(),
+            buy_asset: "ETH".to_string(),
+            window_secs: 60,
+        }]);
+    }
+}
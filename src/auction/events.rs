@@ -0,0 +1,100 @@
+use cqrs_es::DomainEvent;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+pub use crate::asset::types::Price;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default, ToSchema)]
+pub struct AuctionConfig {
+    pub auction_id: String,
+    pub sell_asset: String,
+    pub buy_asset: String,
+    pub window_start: u64,
+    pub window_end: u64,
+}
+
+// A placed auction order offered into the auction, identified by its order aggregate id.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default, ToSchema)]
+pub struct Ask {
+    pub order_id: String,
+    pub seller: String,
+    pub sell_amount: u64,
+    pub buy_amount: u64,
+    pub timestamp: u64,
+}
+
+impl Ask {
+    pub fn limit(&self) -> Price {
+        Price::new(self.buy_amount, self.sell_amount)
+    }
+}
+
+// A bid to buy up to `max_amount` of the sell asset paying at most `max_total` of the buy asset.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default, ToSchema)]
+pub struct Bid {
+    pub bid_id: String,
+    pub bidder: String,
+    pub max_amount: u64,
+    pub max_total: u64,
+    pub timestamp: u64,
+}
+
+impl Bid {
+    pub fn limit(&self) -> Price {
+        Price::new(self.max_total, self.max_amount)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub struct Fill {
+    pub order_id: String,
+    pub buyer: String,
+    // The bid filled, whose hold funds the fill.
+    #[serde(default)]
+    pub bid_id: String,
+    pub sell_amount: u64,
+    pub buy_amount: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub enum FillOutcome {
+    Settled,
+    Failed { reason: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum AuctionEvent {
+    Opened {
+        config: AuctionConfig,
+    },
+    BidPlaced {
+        bid: Bid,
+    },
+    BidWithdrawn {
+        bid_id: String,
+    },
+    Cleared {
+        price: Option<Price>,
+        fills: Vec<Fill>,
+        timestamp: u64,
+    },
+    FillRecorded {
+        order_id: String,
+        outcome: FillOutcome,
+    },
+}
+
+impl DomainEvent for AuctionEvent {
+    fn event_type(&self) -> String {
+        match self {
+            AuctionEvent::Opened { .. } => "Opened".to_string(),
+            AuctionEvent::BidPlaced { .. } => "BidPlaced".to_string(),
+            AuctionEvent::BidWithdrawn { .. } => "BidWithdrawn".to_string(),
+            AuctionEvent::Cleared { .. } => "Cleared".to_string(),
+            AuctionEvent::FillRecorded { .. } => "FillRecorded".to_string(),
+        }
+    }
+
+    fn event_version(&self) -> String {
+        "1.0".to_string()
+    }
+}
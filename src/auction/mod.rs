@@ -0,0 +1,5 @@
+pub mod aggregate;
+pub mod commands;
+pub mod engine;
+pub mod events;
+pub mod queries;
@@ -0,0 +1,89 @@
+use async_trait::async_trait;
+use cqrs_es::{EventEnvelope, Query, View};
+use cqrs_es::persist::GenericQuery;
+use postgres_es::PostgresViewRepository;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::auction::aggregate::Auction;
+use crate::auction::events::{AuctionEvent, Bid, Fill, FillOutcome, Price};
+
+pub struct SimpleLoggingQuery {}
+
+#[derive(Debug, Serialize, Deserialize, Default, ToSchema)]
+pub enum AuctionState {
+    #[default]
+    Open,
+    Cleared,
+    Completed,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct FillView {
+    pub fill: Fill,
+    pub outcome: Option<FillOutcome>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, ToSchema)]
+pub struct AuctionView {
+    pub id: String,
+    pub sell_asset: String,
+    pub buy_asset: String,
+    pub window_start: u64,
+    pub window_end: u64,
+    pub bids: Vec<Bid>,
+    pub clearing_price: Option<Price>,
+    pub fills: Vec<FillView>,
+    pub status: AuctionState,
+    pub clear_time: Option<u64>,
+}
+
+#[async_trait]
+impl Query<Auction> for SimpleLoggingQuery {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<Auction>]) {
+        for event in events {
+            let payload = serde_json::to_string_pretty(&event.payload).unwrap();
+            tracing::debug!("{}-{}\n{}", aggregate_id, event.sequence, payload);
+        }
+    }
+}
+
+pub type AuctionQuery = GenericQuery<
+    PostgresViewRepository<AuctionView, Auction>,
+    AuctionView,
+    Auction,
+>;
+
+impl View<Auction> for AuctionView {
+    fn update(&mut self, event: &EventEnvelope<Auction>) {
+        match &event.payload {
+            AuctionEvent::Opened { config } => {
+                self.id = config.auction_id.clone();
+                self.sell_asset = config.sell_asset.clone();
+                self.buy_asset = config.buy_asset.clone();
+                self.window_start = config.window_start;
+                self.window_end = config.window_end;
+                self.status = AuctionState::Open;
+            }
+            AuctionEvent::BidPlaced { bid } => {
+                self.bids.push(bid.clone());
+            }
+            AuctionEvent::BidWithdrawn { bid_id } => {
+                self.bids.retain(|bid| &bid.bid_id != bid_id);
+            }
+            AuctionEvent::Cleared { price, fills, timestamp } => {
+                self.clearing_price = *price;
+                self.fills = fills.iter().map(|fill| FillView { fill: fill.clone(), outcome: None }).collect();
+                self.clear_time = Some(*timestamp);
+                self.status = if fills.is_empty() { AuctionState::Completed } else { AuctionState::Cleared };
+            }
+            AuctionEvent::FillRecorded { order_id, outcome } => {
+                if let Some(fill) = self.fills.iter_mut().find(|f| &f.fill.order_id == order_id) {
+                    fill.outcome = Some(outcome.clone());
+                }
+                if self.fills.iter().all(|f| f.outcome.is_some()) {
+                    self.status = AuctionState::Completed;
+                }
+            }
+        }
+    }
+}
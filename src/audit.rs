@@ -0,0 +1,298 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde::Serialize;
+use utoipa::ToSchema;
+use sqlx::{Pool, Postgres, Row};
+use crate::background::{Background, Shutdown};
+use crate::config::AuditConfig;
+use crate::metrics::AuditMetrics;
+use crate::order::recovery::{orphaned_locks, OrphanedLock};
+use crate::util::clock;
+
+// Violations reported per invariant and audit, the counts are not capped.
+const MAX_REPORTED: i64 = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(tag = "invariant")]
+pub enum Violation {
+    // The legs of a transaction between accounts do not net out, see `crate::account::journal`.
+    UnbalancedTransaction {
+        txid: String,
+        asset: String,
+        debits: u64,
+        credits: u64,
+    },
+    // More of an asset left the account than ever reached it.
+    NegativeBalance {
+        account_id: String,
+        asset: String,
+        balance: i64,
+    },
+    OrphanedLock(OrphanedLock),
+}
+
+// Every invariant audited, as named by `Violation::invariant`.
+pub const INVARIANTS: [&str; 3] = ["UnbalancedTransaction", "NegativeBalance", "OrphanedLock"];
+
+impl Violation {
+    pub fn invariant(&self) -> &'static str {
+        match self {
+            Violation::UnbalancedTransaction { .. } => "UnbalancedTransaction",
+            Violation::NegativeBalance { .. } => "NegativeBalance",
+            Violation::OrphanedLock(_) => "OrphanedLock",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct AuditReport {
+    pub audits: u64,
+    pub last_audit_at: Option<u64>,
+    // Audits that could not complete, retried on the next interval.
+    pub failures: u64,
+    // Violations found by the last audit, by invariant.
+    pub violations_found: BTreeMap<String, u64>,
+    // Up to 100 of them per invariant.
+    pub violations: Vec<Violation>,
+    // The last audit that found nothing.
+    pub last_clean_at: Option<u64>,
+}
+
+// Periodically checks the invariants the accounts must keep across aggregates, from
+// the journal and ledger projections: every transaction between accounts nets out,
+// no account ever spent funds it did not have, and no ended order still holds funds.
+// It only reports; orphaned locks are released by `crate::order::recovery`, the rest
+// needs an operator. Projections lag behind the accounts, so transactions within the
+// grace period are left to the next audit.
+#[derive(Clone)]
+pub struct ConsistencyAudit {
+    pool: Pool<Postgres>,
+    config: AuditConfig,
+    report: Arc<Mutex<AuditReport>>,
+    metrics: AuditMetrics,
+}
+
+impl ConsistencyAudit {
+    pub fn new(pool: Pool<Postgres>, config: AuditConfig, metrics: AuditMetrics) -> Self {
+        ConsistencyAudit { pool, config, report: Default::default(), metrics }
+    }
+
+    pub fn spawn(&self, background: &Background) {
+        let audit = self.clone();
+        background.spawn(|shutdown| async move { audit.run(shutdown).await });
+    }
+
+    async fn run(&self, mut shutdown: Shutdown) {
+        loop {
+            if let Err(e) = self.audit().await {
+                tracing::error!("Failed to audit account invariants: {}", e);
+            }
+            if !shutdown.pause(Duration::from_secs(self.config.interval_secs.max(1))).await {
+                break;
+            }
+        }
+    }
+
+    pub async fn audit(&self) -> Result<AuditReport, sqlx::Error> {
+        let cutoff = clock::now().saturating_sub(self.config.grace_secs);
+        let checked = self.check(cutoff).await;
+        let mut report = self.report.lock().expect("audit report poisoned");
+        report.audits += 1;
+        report.last_audit_at = Some(clock::now());
+        let (found, violations) = match checked {
+            Ok(checked) => checked,
+            Err(e) => {
+                report.failures += 1;
+                self.metrics.failed();
+                return Err(e);
+            }
+        };
+        for violation in &violations {
+            tracing::error!("Invariant violated: {:?}", violation);
+        }
+        if found.is_empty() {
+            report.last_clean_at = report.last_audit_at;
+        }
+        for invariant in INVARIANTS {
+            self.metrics.audited(invariant, found.get(invariant).copied().unwrap_or(0));
+        }
+        self.metrics.completed(report.last_clean_at.filter(|_| found.is_empty()));
+        report.violations_found = found;
+        report.violations = violations;
+        Ok(report.clone())
+    }
+
+    async fn check(&self, cutoff: u64) -> Result<(BTreeMap<String, u64>, Vec<Violation>), sqlx::Error> {
+        let mut found = BTreeMap::new();
+        let mut violations = vec![];
+        let (count, unbalanced) = unbalanced_transactions(&self.pool, cutoff, MAX_REPORTED).await?;
+        record(&mut found, &mut violations, count, unbalanced);
+        let (count, negative) = negative_balances(&self.pool, MAX_REPORTED).await?;
+        record(&mut found, &mut violations, count, negative);
+        let orphaned = orphaned_locks(&self.pool, i64::MAX).await?;
+        let count = orphaned.len() as u64;
+        let orphaned = orphaned.into_iter().take(MAX_REPORTED as usize).map(Violation::OrphanedLock).collect();
+        record(&mut found, &mut violations, count, orphaned);
+        Ok((found, violations))
+    }
+
+    pub fn report(&self) -> AuditReport {
+        self.report.lock().expect("audit report poisoned").clone()
+    }
+}
+
+fn record(found: &mut BTreeMap<String, u64>, violations: &mut Vec<Violation>, count: u64, reported: Vec<Violation>) {
+    if let Some(violation) = reported.first() {
+        found.insert(violation.invariant().to_string(), count);
+    }
+    violations.extend(reported);
+}
+
+// Transactions between accounts settled before `cutoff` whose debits and credits differ
+// in some asset, with the number of them.
+pub async fn unbalanced_transactions(pool: &Pool<Postgres>, cutoff: u64, limit: i64) -> Result<(u64, Vec<Violation>), sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT txid, asset, debits, credits, COUNT(*) OVER () AS found
+         FROM (
+             SELECT txid, asset, COALESCE(SUM(debit_amount), 0)::bigint AS debits, COALESCE(SUM(credit_amount), 0)::bigint AS credits
+             FROM journal
+             GROUP BY txid, asset
+             HAVING MAX(timestamp) < $1
+         ) transactions
+         WHERE debits <> credits
+         ORDER BY txid, asset
+         LIMIT $2",
+    )
+        .bind(cutoff as i64)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+    let found = match rows.first() {
+        Some(row) => row.try_get::<i64, _>("found")? as u64,
+        None => 0,
+    };
+    let mut violations = Vec::with_capacity(rows.len());
+    for row in rows {
+        violations.push(Violation::UnbalancedTransaction {
+            txid: row.try_get("txid")?,
+            asset: row.try_get("asset")?,
+            debits: row.try_get::<i64, _>("debits")? as u64,
+            credits: row.try_get::<i64, _>("credits")? as u64,
+        });
+    }
+    Ok((found, violations))
+}
+
+// Accounts whose ledger takes out more of an asset than it puts in, with the number of
+// them. Locks, holds and earmarks keep funds on the account and do not count, funds
+// drawn on an overdraft count as put in until repaid, and quarantined deposits once
+// released.
+pub async fn negative_balances(pool: &Pool<Postgres>, limit: i64) -> Result<(u64, Vec<Violation>), sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT account_id, asset, balance, COUNT(*) OVER () AS found
+         FROM (
+             SELECT account_id, asset, SUM(amount)::bigint AS balance
+             FROM (
+                 SELECT account_id, detail->>'asset' AS asset,
+                        CASE WHEN detail->>'@t' IN ('Deposit', 'Credited', 'DebitReversed', 'Overdraft', 'QuarantineRelease') THEN 1 ELSE -1 END
+                            * (detail->>'amount')::numeric AS amount
+                 FROM ledger_entries
+                 WHERE detail->>'@t' IN (
+                     'Deposit', 'Credited', 'DebitReversed', 'Overdraft', 'QuarantineRelease',
+                     'Withdraw', 'Debited', 'CreditReversed', 'Fee', 'HoldCapture', 'OverdraftRepayment'
+                 )
+                 UNION ALL
+                 SELECT account_id, detail->>'send_asset', -(detail->>'send_amount')::numeric
+                 FROM ledger_entries
+                 WHERE detail->>'@t' = 'Settlement'
+                 UNION ALL
+                 SELECT account_id, detail->>'receive_asset', (detail->>'receive_amount')::numeric
+                 FROM ledger_entries
+                 WHERE detail->>'@t' = 'Settlement'
+             ) movements
+             GROUP BY account_id, asset
+         ) balances
+         WHERE balance < 0
+         ORDER BY account_id, asset
+         LIMIT $1",
+    )
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+    let found = match rows.first() {
+        Some(row) => row.try_get::<i64, _>("found")? as u64,
+        None => 0,
+    };
+    let mut violations = Vec::with_capacity(rows.len());
+    for row in rows {
+        violations.push(Violation::NegativeBalance {
+            account_id: row.try_get("account_id")?,
+            asset: row.try_get("asset")?,
+            balance: row.try_get("balance")?,
+        });
+    }
+    Ok((found, violations))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use cqrs_es::EventEnvelope;
+    use rand::random;
+    use crate::account::aggregate::Account;
+    use crate::account::events::AccountEvent;
+    use crate::account::journal::write_journal_entries;
+    use crate::account::ledger::write_ledger_entries;
+    use crate::audit::{negative_balances, unbalanced_transactions, Violation};
+    use crate::util::test_db::{delete_rows, random_suffix, test_pool};
+    use crate::util::types::ByteArray32;
+
+    #[tokio::test]
+    async fn test_invariants() {
+        let pool = test_pool();
+        let suffix = random_suffix().to_uppercase();
+        let usdt = format!("USDT{}", suffix);
+        let (alice, bob) = (format!("ALICE-{}", suffix), format!("BOB-{}", suffix));
+        let (lost, late, paid) = (ByteArray32(random()), ByteArray32(random()), ByteArray32(random()));
+        let envelope = |account_id: &str, sequence, payload| EventEnvelope::<Account> {
+            aggregate_id: account_id.to_string(),
+            sequence,
+            payload,
+            metadata: HashMap::new(),
+        };
+        let events = vec![
+            envelope(&alice, 1, AccountEvent::deposited(ByteArray32(random()), 10, usdt.clone(), 100)),
+            // Bob was never credited.
+            envelope(&alice, 2, AccountEvent::debited(lost, 20, bob.clone(), usdt.clone(), 60)),
+            // Still within the grace period.
+            envelope(&alice, 3, AccountEvent::debited(late, 1_000, bob.clone(), usdt.clone(), 10)),
+            envelope(&bob, 1, AccountEvent::credited(paid, 30, alice.clone(), usdt.clone(), 70)),
+            // More than alice ever had.
+            envelope(&alice, 4, AccountEvent::debited(paid, 30, bob.clone(), usdt.clone(), 70)),
+        ];
+        write_ledger_entries(&pool, &events).await.unwrap();
+        write_journal_entries(&pool, &events).await.unwrap();
+
+        let of_test = |violations: Vec<Violation>| violations.into_iter().filter(|violation| match violation {
+            Violation::UnbalancedTransaction { asset, .. } | Violation::NegativeBalance { asset, .. } => asset == &usdt,
+            Violation::OrphanedLock(lock) => lock.account_id.ends_with(&suffix),
+        }).collect::<Vec<_>>();
+        let (_, unbalanced) = unbalanced_transactions(&pool, 500, i64::MAX).await.unwrap();
+        assert_eq!(of_test(unbalanced), vec![
+            Violation::UnbalancedTransaction { txid: lost.hex(), asset: usdt.clone(), debits: 60, credits: 0 },
+        ]);
+        let (_, negative) = negative_balances(&pool, i64::MAX).await.unwrap();
+        assert_eq!(of_test(negative), vec![
+            Violation::NegativeBalance { account_id: alice.clone(), asset: usdt.clone(), balance: -40 },
+        ]);
+
+        sqlx::query("DELETE FROM ledger_entries WHERE account_id IN ($1, $2)")
+            .bind(&alice)
+            .bind(&bob)
+            .execute(&pool)
+            .await
+            .unwrap();
+        delete_rows(&pool, "journal", "asset", &usdt).await;
+    }
+}
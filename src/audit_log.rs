@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use async_trait::async_trait;
+use cqrs_es::{Aggregate, DomainEvent, EventEnvelope, Query};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgRow;
+use sqlx::{Pool, Postgres, Row};
+use utoipa::{IntoParams, ToSchema};
+use crate::util::clock;
+use crate::util::metadata::COMMAND_KEY;
+
+const MAX_LISTED: i64 = 500;
+
+// One command executed through the API and the events it resulted in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub aggregate_type: String,
+    pub aggregate_id: String,
+    // The command variant, as named in the request body.
+    pub command: Option<String>,
+    pub first_sequence: u64,
+    pub last_sequence: u64,
+    pub events: Vec<String>,
+    // The request metadata: the authenticated initiator, the unverified initiator the
+    // caller claimed, user agent, correlation id, uri and time.
+    pub metadata: BTreeMap<String, String>,
+    pub recorded_at: u64,
+}
+
+impl AuditEntry {
+    fn from_row(row: &PgRow) -> Result<Self, sqlx::Error> {
+        let metadata: serde_json::Value = row.try_get("metadata")?;
+        Ok(AuditEntry {
+            id: row.try_get("id")?,
+            aggregate_type: row.try_get("aggregate_type")?,
+            aggregate_id: row.try_get("aggregate_id")?,
+            command: row.try_get("command")?,
+            first_sequence: row.try_get::<i64, _>("first_sequence")? as u64,
+            last_sequence: row.try_get::<i64, _>("last_sequence")? as u64,
+            events: row.try_get("events")?,
+            metadata: serde_json::from_value(metadata).map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+            recorded_at: row.try_get::<i64, _>("recorded_at")? as u64,
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditLogFilter {
+    // Only this aggregate type, ids of different types may collide.
+    pub aggregate_type: Option<String>,
+}
+
+// Records who executed which command into `audit_log`: every committed batch of events
+// whose command carried request metadata, see `crate::command_extractor`, with that
+// metadata and the sequences it resulted in. Commands sagas and sweepers execute on
+// their own carry none and are left out. Dispatching a batch again is a no-op.
+pub struct AuditLogQuery<A> {
+    pool: Pool<Postgres>,
+    _aggregate: PhantomData<A>,
+}
+
+impl<A> AuditLogQuery<A> {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        AuditLogQuery { pool, _aggregate: PhantomData }
+    }
+}
+
+#[async_trait]
+impl<A: Aggregate> Query<A> for AuditLogQuery<A> {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<A>]) {
+        let (Some(first), Some(last)) = (events.first(), events.last()) else {
+            return;
+        };
+        if first.metadata.is_empty() {
+            return;
+        }
+        let metadata = serde_json::to_value(&first.metadata).expect("metadata is serializable");
+        let event_types: Vec<String> = events.iter().map(|event| event.payload.event_type()).collect();
+        let result = sqlx::query(
+            "INSERT INTO audit_log (aggregate_type, aggregate_id, command, first_sequence, last_sequence, events, metadata, recorded_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+             ON CONFLICT (aggregate_type, aggregate_id, first_sequence) DO NOTHING",
+        )
+            .bind(A::aggregate_type())
+            .bind(aggregate_id)
+            .bind(first.metadata.get(COMMAND_KEY))
+            .bind(first.sequence as i64)
+            .bind(last.sequence as i64)
+            .bind(event_types)
+            .bind(metadata)
+            .bind(clock::now() as i64)
+            .execute(&self.pool)
+            .await;
+        if let Err(e) = result {
+            tracing::error!("Failed to record the audit entry of {} {}-{}: {}", A::aggregate_type(), aggregate_id, first.sequence, e);
+        }
+    }
+}
+
+// Reads `audit_log`, behind `/admin/audit/:aggregate_id`.
+#[derive(Clone)]
+pub struct AuditLog {
+    pool: Pool<Postgres>,
+}
+
+impl AuditLog {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        AuditLog { pool }
+    }
+
+    // The latest commands executed on `aggregate_id`, newest first.
+    pub async fn list(&self, aggregate_id: &str, filter: &AuditLogFilter) -> Result<Vec<AuditEntry>, sqlx::Error> {
+        sqlx::query(
+            "SELECT * FROM audit_log WHERE aggregate_id = $1 AND ($2::text IS NULL OR aggregate_type = $2)
+             ORDER BY first_sequence DESC, id DESC LIMIT $3",
+        )
+            .bind(aggregate_id)
+            .bind(&filter.aggregate_type)
+            .bind(MAX_LISTED)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(AuditEntry::from_row)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use cqrs_es::{EventEnvelope, Query};
+    use crate::asset::aggregate::Asset;
+    use crate::asset::events::AssetEvent;
+    use crate::audit_log::{AuditLog, AuditLogFilter, AuditLogQuery};
+    use crate::util::metadata::{COMMAND_KEY, INITIATOR_KEY};
+    use crate::util::test_db::{delete_rows, random_suffix, test_pool};
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let pool = test_pool();
+        let symbol = format!("AUDIT{}", random_suffix().to_uppercase());
+        let envelope = |sequence, metadata: &HashMap<String, String>| EventEnvelope::<Asset> {
+            aggregate_id: symbol.clone(),
+            sequence,
+            payload: AssetEvent::Disabled { timestamp: 1 },
+            metadata: metadata.clone(),
+        };
+        let metadata = HashMap::from([
+            (INITIATOR_KEY.to_string(), "ops@example.com".to_string()),
+            (COMMAND_KEY.to_string(), "Disable".to_string()),
+        ]);
+        let query = AuditLogQuery::<Asset>::new(pool.clone());
+        query.dispatch(&symbol, &[envelope(1, &metadata), envelope(2, &metadata)]).await;
+        // Dispatched again, and executed by a saga without metadata.
+        query.dispatch(&symbol, &[envelope(1, &metadata), envelope(2, &metadata)]).await;
+        query.dispatch(&symbol, &[envelope(3, &HashMap::new())]).await;
+
+        let entries = AuditLog::new(pool.clone()).list(&symbol, &AuditLogFilter::default()).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].first_sequence, entries[0].last_sequence), (1, 2));
+        assert_eq!(entries[0].command.as_deref(), Some("Disable"));
+        assert_eq!(entries[0].events, vec!["Disabled".to_string(), "Disabled".to_string()]);
+        assert_eq!(entries[0].metadata[INITIATOR_KEY], "ops@example.com");
+        let filter = AuditLogFilter { aggregate_type: Some("account".to_string()) };
+        assert!(AuditLog::new(pool.clone()).list(&symbol, &filter).await.unwrap().is_empty());
+
+        delete_rows(&pool, "audit_log", "aggregate_id", &symbol).await;
+    }
+}
@@ -0,0 +1,205 @@
+use axum::extract::{Request, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use crate::config::AuthConfig;
+use crate::state::ApplicationState;
+
+pub const API_KEY_HDR: &str = "X-Api-Key";
+const BEARER_PREFIX: &str = "Bearer ";
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum AuthError {
+    #[error("Missing credentials, send an {API_KEY_HDR} header or a bearer token")]
+    MissingCredentials,
+    #[error("Invalid API key")]
+    InvalidApiKey,
+    #[error("Invalid token: {0}")]
+    InvalidToken(&'static str),
+    #[error("Token expired")]
+    TokenExpired,
+    #[error("Token lacks the {0} role")]
+    MissingRole(String),
+}
+
+impl AuthError {
+    fn status(&self) -> StatusCode {
+        match self {
+            AuthError::MissingRole(_) => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+}
+
+// Who an admin request was authorized for, recorded as the initiator of the commands
+// it sends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal(pub String);
+
+#[derive(Debug, Deserialize)]
+struct JwtHeader {
+    alg: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct JwtClaims {
+    #[serde(default)]
+    sub: Option<String>,
+    #[serde(default)]
+    exp: Option<i64>,
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+// Authorizes admin requests by API key or by an HS256 JWT carrying the admin role.
+// Nothing is authorized until one of them is configured.
+#[derive(Clone)]
+pub struct Authenticator {
+    // Keys are kept and compared as digests, so comparing takes the same time however
+    // much of a guessed key is right.
+    api_keys: Vec<[u8; 32]>,
+    jwt_secret: Option<Vec<u8>>,
+    admin_role: String,
+}
+
+impl Authenticator {
+    pub fn new(config: &AuthConfig) -> Self {
+        Authenticator {
+            api_keys: config.api_keys.iter().map(|key| digest(key)).collect(),
+            jwt_secret: (!config.jwt_secret.is_empty()).then(|| config.jwt_secret.as_bytes().to_vec()),
+            admin_role: config.admin_role.clone(),
+        }
+    }
+
+    pub fn is_configured(&self) -> bool {
+        !self.api_keys.is_empty() || self.jwt_secret.is_some()
+    }
+
+    pub fn authorize(&self, headers: &HeaderMap, now: i64) -> Result<Principal, AuthError> {
+        if let Some(key) = headers.get(API_KEY_HDR) {
+            let key = digest(key.to_str().map_err(|_| AuthError::InvalidApiKey)?);
+            return match self.api_keys.iter().position(|known| *known == key) {
+                Some(index) => Ok(Principal(format!("api-key:{}", index))),
+                None => Err(AuthError::InvalidApiKey),
+            };
+        }
+        let token = headers
+            .get(axum::http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix(BEARER_PREFIX))
+            .ok_or(AuthError::MissingCredentials)?;
+        let claims = self.verify_jwt(token)?;
+        if claims.exp.is_some_and(|exp| exp <= now) {
+            return Err(AuthError::TokenExpired);
+        }
+        if !claims.roles.contains(&self.admin_role) {
+            return Err(AuthError::MissingRole(self.admin_role.clone()));
+        }
+        Ok(Principal(format!("jwt:{}", claims.sub.unwrap_or_default())))
+    }
+
+    fn verify_jwt(&self, token: &str) -> Result<JwtClaims, AuthError> {
+        let secret = self.jwt_secret.as_ref().ok_or(AuthError::InvalidToken("tokens are not accepted"))?;
+        let Some((signed, signature)) = token.rsplit_once('.') else {
+            return Err(AuthError::InvalidToken("malformed"));
+        };
+        let Some((header, claims)) = signed.split_once('.').filter(|(_, claims)| !claims.contains('.')) else {
+            return Err(AuthError::InvalidToken("malformed"));
+        };
+        let decode = |part: &str| BASE64_URL_SAFE_NO_PAD.decode(part).map_err(|_| AuthError::InvalidToken("malformed"));
+        let header: JwtHeader = serde_json::from_slice(&decode(header)?).map_err(|_| AuthError::InvalidToken("malformed header"))?;
+        // Anything else, `none` in particular, would let the token pick how it is checked.
+        if header.alg != "HS256" {
+            return Err(AuthError::InvalidToken("unsupported algorithm"));
+        }
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+        mac.update(signed.as_bytes());
+        mac.verify_slice(&decode(signature)?).map_err(|_| AuthError::InvalidToken("bad signature"))?;
+        serde_json::from_slice(&decode(claims)?).map_err(|_| AuthError::InvalidToken("malformed claims"))
+    }
+}
+
+fn digest(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+// Guards the admin routes. The authorized `Principal` is handed on as a request extension.
+pub async fn auth_layer(State(state): State<ApplicationState>, mut request: Request, next: Next) -> Response {
+    match state.authenticator.authorize(request.headers(), chrono::Utc::now().timestamp()) {
+        Ok(principal) => {
+            request.extensions_mut().insert(principal);
+            next.run(request).await
+        }
+        Err(err) => {
+            tracing::warn!("Refused {} {}: {}", request.method(), request.uri().path(), err);
+            (err.status(), err.to_string()).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::{header, HeaderMap, HeaderValue};
+    use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+    use crate::auth::{AuthError, Authenticator, Principal, API_KEY_HDR};
+    use crate::config::AuthConfig;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn authenticator() -> Authenticator {
+        Authenticator::new(&AuthConfig {
+            api_keys: vec!["first-key".to_string(), "second-key".to_string()],
+            jwt_secret: "secret".to_string(),
+            admin_role: "admin".to_string(),
+        })
+    }
+
+    fn token(alg: &str, claims: serde_json::Value, secret: &str) -> String {
+        let encode = |value: serde_json::Value| BASE64_URL_SAFE_NO_PAD.encode(value.to_string());
+        let signed = format!("{}.{}", encode(serde_json::json!({ "alg": alg, "typ": "JWT" })), encode(claims));
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(signed.as_bytes());
+        format!("{}.{}", signed, BASE64_URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes()))
+    }
+
+    fn headers(name: &str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name.parse::<header::HeaderName>().unwrap(), HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn authorize(name: &str, value: &str) -> Result<Principal, AuthError> {
+        authenticator().authorize(&headers(name, value), NOW)
+    }
+
+    fn bearer(token: String) -> Result<Principal, AuthError> {
+        authorize(header::AUTHORIZATION.as_str(), &format!("Bearer {}", token))
+    }
+
+    #[test]
+    fn test_api_keys() {
+        assert_eq!(authorize(API_KEY_HDR, "second-key"), Ok(Principal("api-key:1".to_string())));
+        assert_eq!(authorize(API_KEY_HDR, "third-key"), Err(AuthError::InvalidApiKey));
+        assert_eq!(authenticator().authorize(&HeaderMap::new(), NOW), Err(AuthError::MissingCredentials));
+        let unconfigured = Authenticator::new(&AuthConfig::default());
+        assert!(!unconfigured.is_configured());
+        assert_eq!(unconfigured.authorize(&headers(API_KEY_HDR, ""), NOW), Err(AuthError::InvalidApiKey));
+    }
+
+    #[test]
+    fn test_jwt_roles() {
+        let claims = |roles: &[&str], exp: i64| serde_json::json!({ "sub": "ops@example.com", "roles": roles, "exp": exp });
+        assert_eq!(bearer(token("HS256", claims(&["admin"], NOW + 60), "secret")), Ok(Principal("jwt:ops@example.com".to_string())));
+        assert_eq!(bearer(token("HS256", claims(&["support"], NOW + 60), "secret")), Err(AuthError::MissingRole("admin".to_string())));
+        assert_eq!(bearer(token("HS256", claims(&["admin"], NOW), "secret")), Err(AuthError::TokenExpired));
+        assert_eq!(bearer(token("HS256", claims(&["admin"], NOW + 60), "guessed")), Err(AuthError::InvalidToken("bad signature")));
+        assert_eq!(bearer(token("none", claims(&["admin"], NOW + 60), "secret")), Err(AuthError::InvalidToken("unsupported algorithm")));
+        assert_eq!(bearer("not-a-token".to_string()), Err(AuthError::InvalidToken("malformed")));
+    }
+}
@@ -0,0 +1,104 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+// The background workers of a node. On shutdown they stop at the end of the round they
+// are in and are joined, see `crate::server::shutdown_signal`, so none is cut off halfway
+// through a sweep when the pool closes.
+#[derive(Clone)]
+pub struct Background {
+    stopping: Arc<watch::Sender<bool>>,
+    tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background { stopping: Arc::new(watch::channel(false).0), tasks: Default::default() }
+    }
+}
+
+impl Background {
+    // Runs a worker, handed the `Shutdown` it is to watch between its rounds.
+    pub fn spawn<F, Fut>(&self, worker: F)
+    where
+        F: FnOnce(Shutdown) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let task = tokio::spawn(worker(Shutdown(self.stopping.subscribe())));
+        let mut tasks = self.tasks.lock().expect("background tasks poisoned");
+        tasks.retain(|task| !task.is_finished());
+        tasks.push(task);
+    }
+
+    // Stops the workers and waits up to `timeout` for the rounds in progress, those
+    // still running then are aborted.
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.stopping.send_replace(true);
+        let tasks = std::mem::take(&mut *self.tasks.lock().expect("background tasks poisoned"));
+        let aborts: Vec<_> = tasks.iter().map(JoinHandle::abort_handle).collect();
+        let joined = futures::future::join_all(tasks);
+        if tokio::time::timeout(timeout, joined).await.is_err() {
+            tracing::warn!("Background tasks still running after {:?}, aborting them", timeout);
+            aborts.iter().for_each(|abort| abort.abort());
+        }
+    }
+}
+
+// Tells a worker the node is shutting down.
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    pub fn is_stopping(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    // Completes once the node is shutting down.
+    pub async fn stopped(&mut self) {
+        // The sender lives as long as the workers are joined.
+        let _ = self.0.wait_for(|stopping| *stopping).await;
+    }
+
+    // Waits `duration` before the next round, returns false when the worker is to stop
+    // instead.
+    pub async fn pause(&mut self, duration: Duration) -> bool {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => !self.is_stopping(),
+            _ = self.stopped() => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::background::Background;
+
+    #[tokio::test]
+    async fn test_shutdown_joins_workers() {
+        let background = Background::default();
+        let rounds = Arc::new(AtomicUsize::new(0));
+        let counted = rounds.clone();
+        background.spawn(|mut shutdown| async move {
+            loop {
+                // A round outlasting the pause is finished, not cut off.
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                counted.fetch_add(1, Ordering::SeqCst);
+                if !shutdown.pause(Duration::from_secs(60)).await {
+                    break;
+                }
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        background.shutdown(Duration::from_secs(5)).await;
+        assert_eq!(rounds.load(Ordering::SeqCst), 1);
+
+        // Those not stopping in time are aborted.
+        let background = Background::default();
+        background.spawn(|_| std::future::pending());
+        tokio::time::timeout(Duration::from_secs(1), background.shutdown(Duration::from_millis(10))).await.unwrap();
+    }
+}
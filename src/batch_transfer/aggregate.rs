@@ -0,0 +1,388 @@
+use std::mem::swap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use cqrs_es::{Aggregate, AggregateError};
+use crate::services::AccountExecutor;
+use serde::{Deserialize, Serialize};
+
+use crate::account::commands::AccountCommand;
+use crate::account::events::AccountError;
+use crate::statemachine::{StateMachine, TableDriven, Transition};
+use crate::util::clock::{Clock, SystemClock};
+use crate::compensation::{guard, journaled_undo, CompensationJournal, StepGuard};
+use crate::config::CommandPolicyConfig;
+use crate::util::types::ByteArray32;
+use super::commands::BatchTransferCommand;
+use super::events::{BatchTransferEvent, Leg};
+use crate::metrics::ErrorVariant;
+
+// Large enough for a payroll run, small enough for one `Continue` to get through.
+const MAX_LEGS: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Config {
+    pub batch_id: ByteArray32,
+    pub legs: Vec<Leg>,
+    pub timestamp: u64,
+    pub description: String,
+}
+
+impl Config {
+    // Every leg is its own account transaction, so a batch may pay the same account twice.
+    pub fn leg_txid(&self, index: usize) -> ByteArray32 {
+        ByteArray32::derive(&format!("batch_transfer:{}", self.batch_id.hex()), &index.to_string())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub enum BatchTransfer {
+    #[default]
+    Uninitialized,
+    Opened {
+        config: Config,
+    },
+    Settled {
+        config: Config,
+        timestamp: u64,
+    },
+    Failed {
+        config: Config,
+        leg: usize,
+        reason: String,
+        timestamp: u64,
+    },
+    Canceled {
+        config: Config,
+        reason: String,
+        timestamp: u64,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BatchTransferError {
+    #[error("Invalid state: {0}")]
+    InvalidState(String),
+    #[error("Invalid legs: {0}")]
+    InvalidLegs(String),
+    #[error("Aggregate error: {0}")]
+    AggregateError(#[from] AggregateError<AccountError>),
+    #[error("Policy violation: {0}")]
+    PolicyViolation(String),
+}
+
+impl ErrorVariant for BatchTransferError {
+    fn variant(&self) -> &'static str {
+        match self {
+            BatchTransferError::InvalidState(_) => "InvalidState",
+            BatchTransferError::InvalidLegs(_) => "InvalidLegs",
+            BatchTransferError::PolicyViolation(_) => "PolicyViolation",
+            BatchTransferError::AggregateError(e) => e.variant(),
+        }
+    }
+}
+
+fn validate_legs(legs: &[Leg]) -> Result<(), BatchTransferError> {
+    if legs.is_empty() || legs.len() > MAX_LEGS {
+        return Err(BatchTransferError::InvalidLegs(format!("a batch needs 1 to {} legs, got {}", MAX_LEGS, legs.len())));
+    }
+    for (index, leg) in legs.iter().enumerate() {
+        if leg.amount == 0 {
+            return Err(BatchTransferError::InvalidLegs(format!("leg {} has no amount", index)));
+        }
+        if leg.from_account == leg.to_account {
+            return Err(BatchTransferError::InvalidLegs(format!("leg {} pays {} to itself", index, leg.from_account)));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct BatchTransferServices {
+    account_service: Arc<dyn AccountExecutor>,
+    compensations: Option<CompensationJournal>,
+    clock: Arc<dyn Clock>,
+    command_policy: CommandPolicyConfig,
+}
+
+impl BatchTransferServices {
+    pub fn new(account_service: Arc<dyn AccountExecutor>) -> Self {
+        Self { account_service, compensations: None, clock: Arc::new(SystemClock), command_policy: CommandPolicyConfig::default() }
+    }
+
+    pub fn with_compensations(mut self, compensations: CompensationJournal) -> Self {
+        self.compensations = Some(compensations);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_command_policy(mut self, command_policy: CommandPolicyConfig) -> Self {
+        self.command_policy = command_policy;
+        self
+    }
+
+    pub(crate) fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    // Runs `command` against `account_id`, returning a guard that runs `undo` when
+    // dropped without being committed.
+    async fn apply_leg(
+        &self,
+        batch_id: ByteArray32,
+        account_id: String,
+        command: AccountCommand,
+        undo: AccountCommand,
+    ) -> Result<StepGuard, BatchTransferError> {
+        let account_service = self.account_service.clone();
+        let journaled = match &self.compensations {
+            Some(journal) => Some(journal.record("batch_transfer", &batch_id.hex(), &account_id, &undo).await?),
+            None => None,
+        };
+        let undo = journaled_undo(journaled.clone(), {
+            let account_id = account_id.clone();
+            async move {
+                match account_service.execute(&account_id, undo).await {
+                    Ok(_) | Err(AggregateError::UserError(AccountError::TransactionNotFound)) => {}
+                    Err(e) => {
+                        tracing::error!("Error undoing batch transfer leg on {}: {:?}", account_id, e);
+                    }
+                }
+            }
+        });
+        match self.account_service.execute(&account_id, command).await {
+            Ok(_) | Err(AggregateError::UserError(AccountError::DuplicateTransaction(_))) => {
+                Ok(guard(journaled, undo))
+            }
+            Err(agg_err) => {
+                undo.await;
+                Err(BatchTransferError::AggregateError(agg_err))
+            }
+        }
+    }
+
+    async fn debit(&self, config: &Config, index: usize, timestamp: u64) -> Result<StepGuard, BatchTransferError> {
+        let (txid, leg) = (config.leg_txid(index), &config.legs[index]);
+        self.apply_leg(
+            config.batch_id,
+            leg.from_account.clone(),
+            AccountCommand::debit(txid, timestamp, leg.to_account.clone(), leg.asset.clone(), leg.amount),
+            AccountCommand::reverse_debit(txid, timestamp, leg.to_account.clone(), leg.asset.clone(), leg.amount),
+        ).await
+    }
+
+    async fn credit(&self, config: &Config, index: usize, timestamp: u64) -> Result<StepGuard, BatchTransferError> {
+        let (txid, leg) = (config.leg_txid(index), &config.legs[index]);
+        self.apply_leg(
+            config.batch_id,
+            leg.to_account.clone(),
+            AccountCommand::credit(txid, timestamp, leg.from_account.clone(), leg.asset.clone(), leg.amount),
+            AccountCommand::reverse_credit(txid, timestamp, leg.from_account.clone(), leg.asset.clone(), leg.amount),
+        ).await
+    }
+
+    // Undoes whatever a crashed `Continue` left behind on the accounts, credits first so
+    // the destination still holds the funds when they are taken back.
+    async fn reverse(&self, config: &Config, timestamp: u64) -> Result<(), BatchTransferError> {
+        for (index, leg) in config.legs.iter().enumerate() {
+            let txid = config.leg_txid(index);
+            let reverse_credit = AccountCommand::reverse_credit(txid, timestamp, leg.from_account.clone(), leg.asset.clone(), leg.amount);
+            match self.account_service.execute(&leg.to_account, reverse_credit).await {
+                Ok(_)
+                | Err(AggregateError::UserError(AccountError::TransactionNotFound | AccountError::AccountNotFound)) => {}
+                Err(e) => return Err(BatchTransferError::AggregateError(e)),
+            }
+            let reverse_debit = AccountCommand::reverse_debit(txid, timestamp, leg.to_account.clone(), leg.asset.clone(), leg.amount);
+            match self.account_service.execute(&leg.from_account, reverse_debit).await {
+                Ok(_)
+                | Err(AggregateError::UserError(AccountError::TransactionNotFound | AccountError::AccountNotFound)) => {}
+                Err(e) => return Err(BatchTransferError::AggregateError(e)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Aggregate for BatchTransfer {
+    type Command = BatchTransferCommand;
+    type Event = BatchTransferEvent;
+    type Error = BatchTransferError;
+    type Services = BatchTransferServices;
+
+    fn aggregate_type() -> String {
+        "batch_transfer".to_string()
+    }
+
+    async fn handle(
+        &self,
+        command: Self::Command,
+        service: &Self::Services,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        if !self.accepts(&command) {
+            return Err(BatchTransferError::InvalidState(format!("BatchTransfer current at {} state, cannot accept {} command", self.state_name(), BatchTransfer::command_name(&command))));
+        }
+        if let BatchTransfer::Uninitialized = self {
+            service.command_policy.check_batch_transfer(&command).map_err(|err| BatchTransferError::PolicyViolation(err.to_string()))?;
+        }
+        match (self, command) {
+            (BatchTransfer::Uninitialized, BatchTransferCommand::Open { batch_id, legs, timestamp, description }) => {
+                validate_legs(&legs)?;
+                Ok(vec![BatchTransferEvent::Opened { batch_id, legs, timestamp, description }])
+            },
+            (BatchTransfer::Opened { config }, BatchTransferCommand::Continue) => {
+                let timestamp = service.now();
+                // Dropping the guards on any of the early returns below reverses every leg
+                // applied so far.
+                let mut guards = Vec::with_capacity(config.legs.len() * 2);
+                for index in 0..config.legs.len() {
+                    match service.debit(config, index, timestamp).await {
+                        Ok(guard) => guards.push(guard),
+                        Err(BatchTransferError::AggregateError(AggregateError::UserError(ae))) => {
+                            return Ok(vec![BatchTransferEvent::Failed { leg: index, reason: format!("Failed to debit: {:?}", ae), timestamp }]);
+                        },
+                        Err(e) => return Err(e),
+                    }
+                }
+                for index in 0..config.legs.len() {
+                    match service.credit(config, index, timestamp).await {
+                        Ok(guard) => guards.push(guard),
+                        Err(BatchTransferError::AggregateError(AggregateError::UserError(ae))) => {
+                            return Ok(vec![BatchTransferEvent::Failed { leg: index, reason: format!("Failed to credit: {:?}", ae), timestamp }]);
+                        },
+                        Err(e) => return Err(e),
+                    }
+                }
+                for guard in guards {
+                    guard.commit().await?;
+                }
+                Ok(vec![BatchTransferEvent::Settled { timestamp }])
+            },
+            (BatchTransfer::Opened { config }, BatchTransferCommand::Cancel { reason }) => {
+                let timestamp = service.now();
+                service.reverse(config, timestamp).await?;
+                Ok(vec![BatchTransferEvent::Canceled { reason, timestamp }])
+            },
+            (state, cmd) => {
+                Err(BatchTransferError::InvalidState(format!("BatchTransfer current at {:?} state, cannot accept {:?} command", state, cmd)))
+            }
+        }
+    }
+
+    fn apply(&mut self, event: Self::Event) {
+        let mut prev = Default::default();
+        swap(&mut prev, self);
+        *self = match (prev, event) {
+            (BatchTransfer::Uninitialized, BatchTransferEvent::Opened { batch_id, legs, timestamp, description }) => BatchTransfer::Opened {
+                config: Config { batch_id, legs, timestamp, description },
+            },
+            (BatchTransfer::Opened { config }, BatchTransferEvent::Settled { timestamp }) => BatchTransfer::Settled {
+                config,
+                timestamp,
+            },
+            (BatchTransfer::Opened { config }, BatchTransferEvent::Failed { leg, reason, timestamp }) => BatchTransfer::Failed {
+                config,
+                leg,
+                reason,
+                timestamp,
+            },
+            (BatchTransfer::Opened { config }, BatchTransferEvent::Canceled { reason, timestamp }) => BatchTransfer::Canceled {
+                config,
+                reason,
+                timestamp,
+            },
+            (state, event) => unreachable!("Invalid state transition: {:?} -> {:?}", state, event),
+        };
+    }
+}
+
+impl StateMachine for BatchTransfer {
+    const TRANSITIONS: &'static [Transition] = &[
+        Transition { from: "Uninitialized", command: "Open", guard: None, events: &["Opened"], to: "Opened" },
+        Transition { from: "Opened", command: "Continue", guard: Some("every leg debited and credited"), events: &["Settled"], to: "Settled" },
+        Transition { from: "Opened", command: "Continue", guard: Some("an account rejects a leg, all legs reversed"), events: &["Failed"], to: "Failed" },
+        Transition { from: "Opened", command: "Cancel", guard: Some("partial batch reversed"), events: &["Canceled"], to: "Canceled" },
+    ];
+}
+
+impl TableDriven for BatchTransfer {
+    fn state_name(&self) -> &'static str {
+        match self {
+            BatchTransfer::Uninitialized => "Uninitialized",
+            BatchTransfer::Opened { .. } => "Opened",
+            BatchTransfer::Settled { .. } => "Settled",
+            BatchTransfer::Failed { .. } => "Failed",
+            BatchTransfer::Canceled { .. } => "Canceled",
+        }
+    }
+
+    fn command_name(command: &BatchTransferCommand) -> &'static str {
+        match command {
+            BatchTransferCommand::Open { .. } => "Open",
+            BatchTransferCommand::Continue => "Continue",
+            BatchTransferCommand::Cancel { .. } => "Cancel",
+        }
+    }
+}
+
+#[cfg(test)]
+mod aggregate_tests {
+    use crate::batch_transfer::aggregate::{validate_legs, BatchTransfer, Config};
+    use crate::batch_transfer::commands::BatchTransferCommand;
+    use crate::batch_transfer::events::{BatchTransferEvent, Leg};
+    use crate::statemachine::verify_table;
+    use crate::util::types::ByteArray32;
+
+    fn leg(from: &str, to: &str, amount: u64) -> Leg {
+        Leg { from_account: from.to_string(), to_account: to.to_string(), asset: "BTC".to_string(), amount }
+    }
+
+    #[test]
+    fn test_transition_table() {
+        let config = Config::default;
+        let states = vec![
+            BatchTransfer::Uninitialized,
+            BatchTransfer::Opened { config: config() },
+            BatchTransfer::Settled { config: config(), timestamp: 2 },
+            BatchTransfer::Failed { config: config(), leg: 0, reason: "no funds".to_string(), timestamp: 2 },
+            BatchTransfer::Canceled { config: config(), reason: "user".to_string(), timestamp: 2 },
+        ];
+        let commands = vec![
+            BatchTransferCommand::Open {
+                batch_id: ByteArray32::default(),
+                legs: vec![leg("ACCT-0001", "ACCT-0002", 10)],
+                timestamp: 1,
+                description: String::new(),
+            },
+            BatchTransferCommand::Continue,
+            BatchTransferCommand::Cancel { reason: "user".to_string() },
+        ];
+        let events = vec![
+            BatchTransferEvent::Opened {
+                batch_id: ByteArray32::default(),
+                legs: vec![leg("ACCT-0001", "ACCT-0002", 10)],
+                timestamp: 1,
+                description: String::new(),
+            },
+            BatchTransferEvent::Settled { timestamp: 2 },
+            BatchTransferEvent::Failed { leg: 0, reason: "no funds".to_string(), timestamp: 2 },
+            BatchTransferEvent::Canceled { reason: "user".to_string(), timestamp: 2 },
+        ];
+        verify_table(&states, &commands, &events);
+    }
+
+    #[test]
+    fn test_validate_legs() {
+        assert!(validate_legs(&[leg("A", "B", 10), leg("A", "B", 5)]).is_ok());
+        assert!(validate_legs(&[]).is_err());
+        assert!(validate_legs(&[leg("A", "B", 0)]).is_err());
+        assert!(validate_legs(&[leg("A", "A", 10)]).is_err());
+
+        let config = Config { legs: vec![leg("A", "B", 10), leg("A", "B", 10)], ..Default::default() };
+        assert_ne!(config.leg_txid(0), config.leg_txid(1));
+    }
+}
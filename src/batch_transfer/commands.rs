@@ -0,0 +1,20 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::batch_transfer::events::Leg;
+use crate::util::types::ByteArray32;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub enum BatchTransferCommand {
+    Open {
+        batch_id: ByteArray32,
+        legs: Vec<Leg>,
+        timestamp: u64,
+        description: String,
+    },
+    // Debits every source, then credits every destination. Either all legs settle or
+    // all of them are reversed.
+    Continue,
+    Cancel {
+        reason: String,
+    },
+}
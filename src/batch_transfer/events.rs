@@ -0,0 +1,51 @@
+use cqrs_es::DomainEvent;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::util::types::ByteArray32;
+
+// One movement of a batch, e.g. a single salary of a payroll run.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub struct Leg {
+    pub from_account: String,
+    pub to_account: String,
+    pub asset: String,
+    pub amount: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum BatchTransferEvent {
+    Opened {
+        batch_id: ByteArray32,
+        legs: Vec<Leg>,
+        timestamp: u64,
+        description: String,
+    },
+    Settled {
+        timestamp: u64,
+    },
+    // `leg` is the index of the leg an account rejected; every other leg was reversed.
+    Failed {
+        leg: usize,
+        reason: String,
+        timestamp: u64,
+    },
+    Canceled {
+        reason: String,
+        timestamp: u64,
+    },
+}
+
+impl DomainEvent for BatchTransferEvent {
+    fn event_type(&self) -> String {
+        match self {
+            BatchTransferEvent::Opened { .. } => "Opened".to_string(),
+            BatchTransferEvent::Settled { .. } => "Settled".to_string(),
+            BatchTransferEvent::Failed { .. } => "Failed".to_string(),
+            BatchTransferEvent::Canceled { .. } => "Canceled".to_string(),
+        }
+    }
+
+    fn event_version(&self) -> String {
+        "1.0".to_string()
+    }
+}
@@ -0,0 +1,78 @@
+use async_trait::async_trait;
+use cqrs_es::persist::GenericQuery;
+use cqrs_es::{EventEnvelope, Query, View};
+use postgres_es::PostgresViewRepository;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::util::types::ByteArray32;
+use super::aggregate::BatchTransfer;
+use super::events::{BatchTransferEvent, Leg};
+
+pub struct SimpleLoggingQuery {}
+
+#[async_trait]
+impl Query<BatchTransfer> for SimpleLoggingQuery {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<BatchTransfer>]) {
+        for event in events {
+            let payload = serde_json::to_string_pretty(&event.payload).unwrap();
+            tracing::debug!("{}-{}\n{}", aggregate_id, event.sequence, payload);
+        }
+    }
+}
+
+pub type BatchTransferQuery = GenericQuery<
+    PostgresViewRepository<BatchTransferView, BatchTransfer>,
+    BatchTransferView,
+    BatchTransfer,
+>;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum BatchTransferStatus {
+    #[default]
+    Opened,
+    Settled,
+    Failed,
+    Canceled,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct BatchTransferView {
+    batch_id: Option<ByteArray32>,
+    legs: Vec<Leg>,
+    description: String,
+    create_timestamp: u64,
+    update_timestamp: u64,
+    status: BatchTransferStatus,
+    failed_leg: Option<usize>,
+    failed_reason: Option<String>,
+    cancel_reason: Option<String>,
+}
+
+impl View<BatchTransfer> for BatchTransferView {
+    fn update(&mut self, event: &EventEnvelope<BatchTransfer>) {
+        match &event.payload {
+            BatchTransferEvent::Opened { batch_id, legs, timestamp, description } => {
+                self.batch_id = Some(*batch_id);
+                self.legs = legs.clone();
+                self.description = description.clone();
+                self.create_timestamp = *timestamp;
+                self.status = BatchTransferStatus::Opened;
+            }
+            BatchTransferEvent::Settled { timestamp } => {
+                self.update_timestamp = *timestamp;
+                self.status = BatchTransferStatus::Settled;
+            }
+            BatchTransferEvent::Failed { leg, reason, timestamp } => {
+                self.update_timestamp = *timestamp;
+                self.failed_leg = Some(*leg);
+                self.failed_reason = Some(reason.clone());
+                self.status = BatchTransferStatus::Failed;
+            }
+            BatchTransferEvent::Canceled { reason, timestamp } => {
+                self.update_timestamp = *timestamp;
+                self.cancel_reason = Some(reason.clone());
+                self.status = BatchTransferStatus::Canceled;
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use cqrs_es::Aggregate;
+use prometheus::IntGauge;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use crate::config::BulkheadConfig;
+use crate::metrics::BulkheadMetrics;
+use crate::state::ApplicationState;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BulkheadError {
+    #[error("Too many {0} commands waiting, try again later")]
+    Full(String),
+    #[error("No turn to run the {0} command in time, try again later")]
+    Timeout(String),
+}
+
+struct Compartment {
+    permits: Arc<Semaphore>,
+    queued: AtomicUsize,
+    queue_depth: usize,
+}
+
+// Keeps a flood of commands to one aggregate type from taking what the others need, the
+// database connections above all: commands of a limited type run `concurrency` at a time
+// and up to `queue_depth` more wait for a turn, the rest are turned away. Configured by
+// `BulkheadConfig`, reported by `BulkheadMetrics`.
+#[derive(Clone)]
+pub struct Bulkheads {
+    compartments: Arc<HashMap<String, Compartment>>,
+    queue_timeout: Duration,
+    metrics: BulkheadMetrics,
+}
+
+impl Bulkheads {
+    pub fn new(config: &BulkheadConfig, metrics: BulkheadMetrics) -> Self {
+        let compartments = config.limits.iter()
+            .filter(|(_, limits)| limits.concurrency > 0)
+            .map(|(aggregate_type, limits)| {
+                let compartment = Compartment {
+                    permits: Arc::new(Semaphore::new(limits.concurrency)),
+                    queued: AtomicUsize::new(0),
+                    queue_depth: limits.queue_depth,
+                };
+                (aggregate_type.clone(), compartment)
+            })
+            .collect();
+        Bulkheads { compartments: Arc::new(compartments), queue_timeout: Duration::from_millis(config.queue_timeout_ms), metrics }
+    }
+
+    // Waits for a turn to run a command of the aggregate type, held until the permit is
+    // dropped.
+    pub async fn enter(&self, aggregate_type: &str) -> Result<BulkheadPermit, BulkheadError> {
+        let Some(compartment) = self.compartments.get(aggregate_type) else {
+            return Ok(BulkheadPermit(None));
+        };
+        let permit = match compartment.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                if compartment.queued.fetch_add(1, Ordering::SeqCst) >= compartment.queue_depth {
+                    compartment.queued.fetch_sub(1, Ordering::SeqCst);
+                    self.metrics.rejected(aggregate_type, "full");
+                    return Err(BulkheadError::Full(aggregate_type.to_string()));
+                }
+                // Leaves the queue however the wait ends, the request may also be dropped.
+                let _queued = QueueSlot::new(compartment, self.metrics.queued(aggregate_type));
+                match tokio::time::timeout(self.queue_timeout, compartment.permits.clone().acquire_owned()).await {
+                    Ok(permit) => permit.expect("bulkheads are never closed"),
+                    Err(_) => {
+                        self.metrics.rejected(aggregate_type, "timeout");
+                        return Err(BulkheadError::Timeout(aggregate_type.to_string()));
+                    },
+                }
+            },
+        };
+        let in_flight = self.metrics.in_flight(aggregate_type);
+        in_flight.inc();
+        Ok(BulkheadPermit(Some((permit, in_flight))))
+    }
+}
+
+struct QueueSlot<'a> {
+    compartment: &'a Compartment,
+    gauge: IntGauge,
+}
+
+impl<'a> QueueSlot<'a> {
+    fn new(compartment: &'a Compartment, gauge: IntGauge) -> Self {
+        gauge.inc();
+        QueueSlot { compartment, gauge }
+    }
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.compartment.queued.fetch_sub(1, Ordering::SeqCst);
+        self.gauge.dec();
+    }
+}
+
+// A turn in a bulkhead, none for aggregate types that are not limited.
+pub struct BulkheadPermit(Option<(OwnedSemaphorePermit, IntGauge)>);
+
+impl Drop for BulkheadPermit {
+    fn drop(&mut self) {
+        if let Some((_, in_flight)) = &self.0 {
+            in_flight.dec();
+        }
+    }
+}
+
+// Holds a turn in the bulkhead of `A` while the command handler runs, or answers 429
+// when there is none to be had.
+pub struct Bulkhead<A>(pub BulkheadPermit, PhantomData<fn() -> A>);
+
+#[async_trait]
+impl<A: Aggregate> FromRequestParts<ApplicationState> for Bulkhead<A> {
+    type Rejection = Response;
+
+    async fn from_request_parts(_parts: &mut Parts, state: &ApplicationState) -> Result<Self, Self::Rejection> {
+        match state.bulkheads.enter(&A::aggregate_type()).await {
+            Ok(permit) => Ok(Bulkhead(permit, PhantomData)),
+            Err(err) => {
+                tracing::warn!("{}", err);
+                Err((StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, "1")], err.to_string()).into_response())
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use prometheus::{Registry, TextEncoder};
+    use crate::bulkhead::{BulkheadError, Bulkheads};
+    use crate::config::{BulkheadConfig, BulkheadLimits};
+    use crate::metrics::BulkheadMetrics;
+
+    #[tokio::test]
+    async fn test_bulkhead_queue_depth() {
+        let limits = HashMap::from([("order".to_string(), BulkheadLimits { concurrency: 1, queue_depth: 1 })]);
+        let registry = Registry::new();
+        let metrics = BulkheadMetrics::default();
+        metrics.register(&registry).unwrap();
+        let bulkheads = Bulkheads::new(&BulkheadConfig { limits, queue_timeout_ms: 100 }, metrics);
+
+        let running = bulkheads.enter("order").await.unwrap();
+        // Waits in the queue for the running command, which leaves it no room.
+        let waiting = tokio::spawn({
+            let bulkheads = bulkheads.clone();
+            async move { bulkheads.enter("order").await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(matches!(bulkheads.enter("order").await, Err(BulkheadError::Full(_))));
+        assert!(matches!(waiting.await.unwrap(), Err(BulkheadError::Timeout(_))));
+        // Other aggregate types are not held back.
+        let _account = bulkheads.enter("account").await.unwrap();
+
+        drop(running);
+        let _next = bulkheads.enter("order").await.unwrap();
+        let metrics = TextEncoder::new().encode_to_string(&registry.gather()).unwrap();
+        assert!(metrics.contains("bulkhead_rejected_total{aggregate=\"order\",reason=\"full\"} 1"), "{}", metrics);
+        assert!(metrics.contains("bulkhead_rejected_total{aggregate=\"order\",reason=\"timeout\"} 1"), "{}", metrics);
+        assert!(metrics.contains("bulkhead_queued{aggregate=\"order\"} 0"), "{}", metrics);
+        assert!(metrics.contains("bulkhead_in_flight{aggregate=\"order\"} 1"), "{}", metrics);
+    }
+}
@@ -0,0 +1,461 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Weekday};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres, Row};
+use utoipa::ToSchema;
+use crate::background::{Background, Shutdown};
+use crate::account::journal::{trial_balance, TrialBalance};
+use crate::config::BusinessDayConfig;
+use crate::util::clock;
+
+// How often the open day is read back even when its cut-off is far off, so an early
+// close on another node and a fast-forwarded sandbox clock are picked up.
+const REFRESH: Duration = Duration::from_secs(30);
+// Days listed in the report.
+const LISTED_DAYS: i64 = 30;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BusinessDayError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Invalid cut-off {0:?}, expected HH:MM after midnight and up to 24:00")]
+    InvalidCutoff(String),
+    #[error("Malformed checkpoint of {0}: {1}")]
+    Payload(String, serde_json::Error),
+}
+
+// When business days end, and which days are business days at all.
+#[derive(Debug, Clone, Copy)]
+pub struct Calendar {
+    cutoff_secs: u64,
+    skip_weekends: bool,
+}
+
+impl Calendar {
+    pub fn new(config: &BusinessDayConfig) -> Result<Self, BusinessDayError> {
+        let invalid = || BusinessDayError::InvalidCutoff(config.cutoff.clone());
+        let (hour, minute) = config.cutoff.split_once(':').ok_or_else(invalid)?;
+        let hour: u64 = hour.parse().map_err(|_| invalid())?;
+        let minute: u64 = minute.parse().map_err(|_| invalid())?;
+        let cutoff_secs = hour * 3600 + minute * 60;
+        if minute >= 60 || cutoff_secs == 0 || cutoff_secs > 86400 {
+            return Err(invalid());
+        }
+        Ok(Calendar { cutoff_secs, skip_weekends: config.skip_weekends })
+    }
+
+    fn is_business_day(&self, date: NaiveDate) -> bool {
+        !self.skip_weekends || !matches!(date.weekday(), Weekday::Sat | Weekday::Sun)
+    }
+
+    // The first business day from `date` on.
+    fn roll(&self, mut date: NaiveDate) -> NaiveDate {
+        while !self.is_business_day(date) {
+            date = date.succ_opt().expect("date out of range");
+        }
+        date
+    }
+
+    pub fn next_day(&self, date: NaiveDate) -> NaiveDate {
+        self.roll(date.succ_opt().expect("date out of range"))
+    }
+
+    // The business day a posting at `timestamp` is booked on by the calendar alone,
+    // without any day closed early.
+    pub fn day_of(&self, timestamp: u64) -> NaiveDate {
+        // Moves the cut-off onto midnight, postings from the cut-off on fall on the next day.
+        let shifted = (timestamp + 86400 - self.cutoff_secs) as i64;
+        let date = NaiveDateTime::from_timestamp_opt(shifted, 0).expect("timestamp out of range").date();
+        self.roll(date)
+    }
+
+    // When `date` closes.
+    pub fn cutoff_at(&self, date: NaiveDate) -> u64 {
+        date.and_hms_opt(0, 0, 0).expect("midnight exists").timestamp() as u64 + self.cutoff_secs
+    }
+}
+
+// The business day postings are booked on, with its cut-off. The account aggregate stamps
+// it on every transaction as its value date, `BusinessDays` moves it on at the cut-off.
+#[derive(Debug, Clone)]
+pub struct PostingDate(Arc<RwLock<(NaiveDate, u64)>>);
+
+impl PostingDate {
+    pub fn new(date: NaiveDate, cutoff_at: u64) -> Self {
+        PostingDate(Arc::new(RwLock::new((date, cutoff_at))))
+    }
+
+    pub fn get(&self) -> NaiveDate {
+        self.0.read().expect("posting date poisoned").0
+    }
+
+    // The day open at `now`, or the day frozen at its cut-off while the next one is not
+    // opened yet.
+    pub fn open_at(&self, now: u64) -> Result<NaiveDate, NaiveDate> {
+        let (date, cutoff_at) = *self.0.read().expect("posting date poisoned");
+        match now < cutoff_at {
+            true => Ok(date),
+            false => Err(date),
+        }
+    }
+
+    fn set(&self, date: NaiveDate, cutoff_at: u64) {
+        *self.0.write().expect("posting date poisoned") = (date, cutoff_at);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BusinessDay {
+    pub date: String,
+    pub closed: bool,
+    pub opened_at: u64,
+    // When the calendar closes the day, it may have been closed early.
+    pub cutoff_at: u64,
+    pub closed_at: Option<u64>,
+    pub checkpointed: bool,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BusinessDayReport {
+    pub posting_date: String,
+    // The most recent days, newest first.
+    pub days: Vec<BusinessDay>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Movement {
+    pub asset: String,
+    // The type of ledger entry, e.g. `Deposit`. Settlements count under `Settlement` for
+    // the asset sent and `SettlementReceived` for the asset received.
+    pub kind: String,
+    pub count: u64,
+    pub amount: u64,
+}
+
+// What a business day booked, taken once the day is closed.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct DayCheckpoint {
+    pub date: String,
+    pub closed_at: u64,
+    pub taken_at: u64,
+    // Ledger entries value dated to the day.
+    pub entries: u64,
+    // Their totals, by asset and kind.
+    pub movements: Vec<Movement>,
+    // The journal across all days, as of the checkpoint.
+    pub trial_balance: TrialBalance,
+}
+
+// Keeps the business days in `business_day`: one is open and takes the postings, at its
+// cut-off it is closed, which freezes it, and the next business day is opened. Every node
+// closes the day at the cut-off itself, the first one records it. A closed day gets its
+// checkpoint once the postings stamped before the cut-off had the grace period to reach
+// the ledger; only postings value dated to the day count, whatever their timestamp.
+#[derive(Clone)]
+pub struct BusinessDays {
+    pool: Pool<Postgres>,
+    calendar: Calendar,
+    grace_secs: u64,
+    posting_date: PostingDate,
+}
+
+impl BusinessDays {
+    pub fn new(pool: Pool<Postgres>, config: &BusinessDayConfig) -> Result<Self, BusinessDayError> {
+        let calendar = Calendar::new(config)?;
+        let today = calendar.day_of(clock::now());
+        let posting_date = PostingDate::new(today, calendar.cutoff_at(today));
+        Ok(BusinessDays { pool, calendar, grace_secs: config.grace_secs, posting_date })
+    }
+
+    pub fn posting_date(&self) -> PostingDate {
+        self.posting_date.clone()
+    }
+
+    pub fn spawn(&self, background: &Background) {
+        let days = self.clone();
+        background.spawn(|shutdown| async move { days.run(shutdown).await });
+    }
+
+    async fn run(&self, mut shutdown: Shutdown) {
+        loop {
+            let wait = match self.tick().await {
+                Ok(wait) => wait,
+                Err(e) => {
+                    tracing::error!("Failed to keep the business day: {}", e);
+                    REFRESH
+                }
+            };
+            if !shutdown.pause(wait.min(REFRESH)).await {
+                break;
+            }
+        }
+    }
+
+    // Closes the open day once its cut-off passed, the days missed while no node ran
+    // included, and takes the checkpoints due. Returns the time left until the next cut-off.
+    pub async fn tick(&self) -> Result<Duration, BusinessDayError> {
+        loop {
+            let open = self.open_day().await?;
+            let now = clock::now();
+            let cutoff_at = self.calendar.cutoff_at(open);
+            if now < cutoff_at {
+                self.posting_date.set(open, cutoff_at);
+                self.take_checkpoints().await?;
+                return Ok(Duration::from_secs(cutoff_at - now));
+            }
+            self.close_day(open, now).await?;
+        }
+    }
+
+    // Closes the open day ahead of its cut-off.
+    pub async fn close(&self) -> Result<BusinessDay, BusinessDayError> {
+        let open = self.open_day().await?;
+        self.close_day(open, clock::now()).await?;
+        let next = self.open_day().await?;
+        self.posting_date.set(next, self.calendar.cutoff_at(next));
+        Ok(load_day(&self.pool, &self.calendar, open).await?.expect("the closed day is recorded"))
+    }
+
+    pub async fn report(&self) -> Result<BusinessDayReport, BusinessDayError> {
+        let rows = sqlx::query(
+            "SELECT date::text AS date, closed, opened_at, closed_at, checkpoint IS NOT NULL AS checkpointed
+             FROM business_day
+             ORDER BY date DESC
+             LIMIT $1",
+        )
+            .bind(LISTED_DAYS)
+            .fetch_all(&self.pool)
+            .await?;
+        let days = rows.iter().map(|row| business_day(&self.calendar, row)).collect::<Result<_, _>>()?;
+        Ok(BusinessDayReport { posting_date: self.posting_date.get().to_string(), days })
+    }
+
+    // The open day, opening the one the calendar gives for today when there is none.
+    async fn open_day(&self) -> Result<NaiveDate, BusinessDayError> {
+        sqlx::query(
+            "INSERT INTO business_day (date, closed, opened_at)
+             SELECT $1::date, false, $2
+             WHERE NOT EXISTS (SELECT 1 FROM business_day WHERE NOT closed)
+             ON CONFLICT DO NOTHING",
+        )
+            .bind(self.calendar.day_of(clock::now()).to_string())
+            .bind(clock::now() as i64)
+            .execute(&self.pool)
+            .await?;
+        let date: String = sqlx::query_scalar("SELECT date::text FROM business_day WHERE NOT closed")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(date.parse().expect("Postgres dates are ISO 8601"))
+    }
+
+    // Freezes the day and opens the next business day, unless another node did already.
+    async fn close_day(&self, date: NaiveDate, now: u64) -> Result<(), BusinessDayError> {
+        let next = self.calendar.next_day(date);
+        let mut tx = self.pool.begin().await?;
+        let closed = sqlx::query("UPDATE business_day SET closed = true, closed_at = $2 WHERE date = $1::date AND NOT closed")
+            .bind(date.to_string())
+            .bind(now as i64)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        if closed == 0 {
+            return Ok(());
+        }
+        sqlx::query("INSERT INTO business_day (date, closed, opened_at) VALUES ($1::date, false, $2)")
+            .bind(next.to_string())
+            .bind(now as i64)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        tracing::info!("Closed business day {}, booking on {} from now on", date, next);
+        Ok(())
+    }
+
+    async fn take_checkpoints(&self) -> Result<(), BusinessDayError> {
+        let due = sqlx::query("SELECT date::text AS date, closed_at FROM business_day WHERE closed AND checkpoint IS NULL AND closed_at <= $1 ORDER BY date")
+            .bind(clock::now().saturating_sub(self.grace_secs) as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        for row in due {
+            let date: String = row.try_get("date")?;
+            let closed_at = row.try_get::<i64, _>("closed_at")? as u64;
+            let checkpoint = checkpoint(&self.pool, &date, closed_at).await?;
+            let payload = serde_json::to_value(&checkpoint).map_err(|e| BusinessDayError::Payload(date.clone(), e))?;
+            sqlx::query("UPDATE business_day SET checkpoint = $2 WHERE date = $1::date AND checkpoint IS NULL")
+                .bind(&date)
+                .bind(payload)
+                .execute(&self.pool)
+                .await?;
+            tracing::info!("Checkpointed business day {}: {} entries, trial balance {}", date, checkpoint.entries, if checkpoint.trial_balance.balanced { "balanced" } else { "unbalanced" });
+        }
+        Ok(())
+    }
+
+    // Takes the checkpoint of a closed day again once postings were value dated to it
+    // after the fact.
+    pub async fn restate(&self, date: NaiveDate) -> Result<(), BusinessDayError> {
+        let restated = sqlx::query("UPDATE business_day SET checkpoint = NULL WHERE date = $1::date AND checkpoint IS NOT NULL")
+            .bind(date.to_string())
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        if restated > 0 {
+            tracing::warn!("Business day {} was posted to after its checkpoint, taking it again", date);
+            self.take_checkpoints().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn load_checkpoint(&self, date: NaiveDate) -> Result<Option<DayCheckpoint>, BusinessDayError> {
+        let payload: Option<Option<serde_json::Value>> = sqlx::query_scalar("SELECT checkpoint FROM business_day WHERE date = $1::date")
+            .bind(date.to_string())
+            .fetch_optional(&self.pool)
+            .await?;
+        payload
+            .flatten()
+            .map(|payload| serde_json::from_value(payload).map_err(|e| BusinessDayError::Payload(date.to_string(), e)))
+            .transpose()
+    }
+}
+
+async fn load_day(pool: &Pool<Postgres>, calendar: &Calendar, date: NaiveDate) -> Result<Option<BusinessDay>, BusinessDayError> {
+    let row = sqlx::query("SELECT date::text AS date, closed, opened_at, closed_at, checkpoint IS NOT NULL AS checkpointed FROM business_day WHERE date = $1::date")
+        .bind(date.to_string())
+        .fetch_optional(pool)
+        .await?;
+    row.map(|row| business_day(calendar, &row)).transpose()
+}
+
+fn business_day(calendar: &Calendar, row: &sqlx::postgres::PgRow) -> Result<BusinessDay, BusinessDayError> {
+    let date: String = row.try_get("date")?;
+    Ok(BusinessDay {
+        cutoff_at: calendar.cutoff_at(date.parse().expect("Postgres dates are ISO 8601")),
+        date,
+        closed: row.try_get("closed")?,
+        opened_at: row.try_get::<i64, _>("opened_at")? as u64,
+        closed_at: row.try_get::<Option<i64>, _>("closed_at")?.map(|closed_at| closed_at as u64),
+        checkpointed: row.try_get("checkpointed")?,
+    })
+}
+
+// Totals the ledger entries value dated to `date` and takes the trial balance.
+pub async fn checkpoint(pool: &Pool<Postgres>, date: &str, closed_at: u64) -> Result<DayCheckpoint, sqlx::Error> {
+    let entries: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM ledger_entries WHERE value_date = $1::date")
+        .bind(date)
+        .fetch_one(pool)
+        .await?;
+    let rows = sqlx::query(
+        "SELECT asset, kind, COUNT(*) AS count, SUM(amount)::bigint AS amount
+         FROM (
+             SELECT detail->>'asset' AS asset, detail->>'@t' AS kind, (detail->>'amount')::numeric AS amount
+             FROM ledger_entries
+             WHERE value_date = $1::date AND detail->>'@t' <> 'Settlement'
+             UNION ALL
+             SELECT detail->>'send_asset', 'Settlement', (detail->>'send_amount')::numeric
+             FROM ledger_entries
+             WHERE value_date = $1::date AND detail->>'@t' = 'Settlement'
+             UNION ALL
+             SELECT detail->>'receive_asset', 'SettlementReceived', (detail->>'receive_amount')::numeric
+             FROM ledger_entries
+             WHERE value_date = $1::date AND detail->>'@t' = 'Settlement'
+         ) movements
+         GROUP BY asset, kind
+         ORDER BY asset, kind",
+    )
+        .bind(date)
+        .fetch_all(pool)
+        .await?;
+    let mut movements = Vec::with_capacity(rows.len());
+    for row in rows {
+        movements.push(Movement {
+            asset: row.try_get("asset")?,
+            kind: row.try_get("kind")?,
+            count: row.try_get::<i64, _>("count")? as u64,
+            amount: row.try_get::<i64, _>("amount")? as u64,
+        });
+    }
+    Ok(DayCheckpoint {
+        date: date.to_string(),
+        closed_at,
+        taken_at: clock::now(),
+        entries: entries as u64,
+        movements,
+        trial_balance: trial_balance(pool).await?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use chrono::NaiveDate;
+    use cqrs_es::EventEnvelope;
+    use rand::random;
+    use crate::account::aggregate::Account;
+    use crate::account::events::AccountEvent;
+    use crate::account::ledger::write_ledger_entries;
+    use crate::business_day::{checkpoint, Calendar, Movement};
+    use crate::config::BusinessDayConfig;
+    use crate::util::test_db::{random_suffix, test_pool};
+    use crate::util::types::ByteArray32;
+
+    fn date(date: &str) -> NaiveDate {
+        date.parse().unwrap()
+    }
+
+    fn at(date_time: &str) -> u64 {
+        chrono::DateTime::parse_from_rfc3339(date_time).unwrap().timestamp() as u64
+    }
+
+    #[test]
+    fn test_calendar() {
+        let calendar = Calendar::new(&BusinessDayConfig { cutoff: "17:30".to_string(), ..BusinessDayConfig::default() }).unwrap();
+        // 2026-10-16 is a Friday.
+        assert_eq!(calendar.day_of(at("2026-10-15T17:29:59Z")), date("2026-10-15"));
+        assert_eq!(calendar.day_of(at("2026-10-15T17:30:00Z")), date("2026-10-16"));
+        assert_eq!(calendar.day_of(at("2026-10-16T18:00:00Z")), date("2026-10-19"));
+        assert_eq!(calendar.day_of(at("2026-10-18T12:00:00Z")), date("2026-10-19"));
+        assert_eq!(calendar.next_day(date("2026-10-16")), date("2026-10-19"));
+        assert_eq!(calendar.cutoff_at(date("2026-10-19")), at("2026-10-19T17:30:00Z"));
+
+        let calendar_days = Calendar::new(&BusinessDayConfig { cutoff: "24:00".to_string(), skip_weekends: false, ..BusinessDayConfig::default() }).unwrap();
+        assert_eq!(calendar_days.day_of(at("2026-10-17T23:59:59Z")), date("2026-10-17"));
+        assert_eq!(calendar_days.next_day(date("2026-10-16")), date("2026-10-17"));
+
+        for cutoff in ["00:00", "24:01", "17:60", "17", "5pm"] {
+            assert!(Calendar::new(&BusinessDayConfig { cutoff: cutoff.to_string(), ..BusinessDayConfig::default() }).is_err(), "{}", cutoff);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint() {
+        let pool = test_pool();
+        // A day of its own, far back, with assets of its own.
+        let day = NaiveDate::from_ymd_opt(1000, 1, 1).unwrap() + chrono::Days::new(random::<u64>() % 365_000);
+        let suffix = random_suffix().to_uppercase();
+        let (usdt, btc) = (format!("USDT{}", suffix), format!("BTC{}", suffix));
+        let account = format!("ALICE-{}", suffix);
+        let envelope = |sequence, value_date: NaiveDate, payload: AccountEvent| EventEnvelope::<Account> {
+            aggregate_id: account.clone(),
+            sequence,
+            payload: payload.with_value_date(Some(value_date)),
+            metadata: HashMap::new(),
+        };
+        let events = vec![
+            envelope(1, day, AccountEvent::deposited(ByteArray32(random()), 10, usdt.clone(), 500)),
+            envelope(2, day, AccountEvent::deposited(ByteArray32(random()), 20, usdt.clone(), 200)),
+            envelope(3, day, AccountEvent::settlement(ByteArray32(random()), 30, "BOB".to_string(), usdt.clone(), 100, btc.clone(), 2)),
+            envelope(4, day.succ_opt().unwrap(), AccountEvent::deposited(ByteArray32(random()), 40, usdt.clone(), 900)),
+        ];
+        write_ledger_entries(&pool, &events).await.unwrap();
+
+        let checkpoint = checkpoint(&pool, &day.to_string(), 50).await.unwrap();
+        assert_eq!(checkpoint.entries, 3);
+        let movement = |asset: &str, kind: &str, count, amount| Movement { asset: asset.to_string(), kind: kind.to_string(), count, amount };
+        assert_eq!(checkpoint.movements, vec![
+            movement(&btc, "SettlementReceived", 1, 2),
+            movement(&usdt, "Deposit", 2, 700),
+            movement(&usdt, "Settlement", 1, 100),
+        ]);
+    }
+}
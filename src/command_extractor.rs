@@ -1,16 +1,23 @@
 use async_trait::async_trait;
 use axum::body::{Body, Bytes};
-use axum::extract::FromRequest;
-use axum::http::{Request, StatusCode};
+use axum::extract::{FromRequest, FromRequestParts};
+use axum::http::{HeaderMap, Request, StatusCode, Uri};
+use axum::http::request::Parts;
 use axum::response::{IntoResponse, Response};
 use serde::de::DeserializeOwned;
 use std::collections::HashMap;
+use rand::Rng;
+use crate::auth::Principal;
+use crate::telemetry;
+use crate::util::metadata::{CLAIMED_INITIATOR_KEY, COMMAND_KEY, CORRELATION_ID_KEY, INITIATOR_KEY};
 
 // This is a custom Axum extension that builds metadata from the inbound request
 // and parses and deserializes the body as the command payload.
 pub struct CommandExtractor<T>(pub HashMap<String, String>, pub T);
 
 const USER_AGENT_HDR: &str = "User-Agent";
+pub const INITIATOR_HDR: &str = "X-Initiator";
+pub const CORRELATION_ID_HDR: &str = "X-Correlation-Id";
 
 #[async_trait]
 impl<S, T> FromRequest<S> for CommandExtractor<T>
@@ -21,24 +28,79 @@ where
     type Rejection = CommandExtractionError;
 
     async fn from_request(req: Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
-        // Here we are including the current date/time, the uri that was called and the user-agent
-        // in a HashMap that we will submit as metadata with the command.
-        let mut metadata = HashMap::default();
-        metadata.insert("time".to_string(), chrono::Utc::now().to_rfc3339());
-        metadata.insert("uri".to_string(), req.uri().to_string());
-        if let Some(user_agent) = req.headers().get(USER_AGENT_HDR) {
-            if let Ok(value) = user_agent.to_str() {
-                metadata.insert(USER_AGENT_HDR.to_string(), value.to_string());
-            }
-        }
+        let mut metadata = request_metadata(req.uri(), req.headers(), req.extensions().get::<Principal>());
 
         // Parse and deserialize the request body as the command payload.
         let body = Bytes::from_request(req, state).await?;
-        let command: T = serde_json::from_slice(body.as_ref())?;
+        let value: serde_json::Value = serde_json::from_slice(body.as_ref())?;
+        if let Some(name) = command_name(&value) {
+            metadata.insert(COMMAND_KEY.to_string(), name);
+        }
+        let command: T = serde_json::from_value(value)?;
         Ok(CommandExtractor(metadata, command))
     }
 }
 
+// Extracts only the request metadata, for handlers whose body is not a JSON command.
+pub struct MetadataExtractor(pub HashMap<String, String>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for MetadataExtractor
+where
+    S: Send + Sync,
+{
+    type Rejection = CommandExtractionError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(MetadataExtractor(request_metadata(&parts.uri, &parts.headers, parts.extensions.get::<Principal>())))
+    }
+}
+
+// Here we are including the current date/time, the uri that was called and the user-agent
+// in a HashMap that we will submit as metadata with the command.
+fn request_metadata(uri: &Uri, headers: &HeaderMap, principal: Option<&Principal>) -> HashMap<String, String> {
+    let mut metadata = HashMap::default();
+    metadata.insert("time".to_string(), chrono::Utc::now().to_rfc3339());
+    metadata.insert("uri".to_string(), uri.to_string());
+    if let Some(user_agent) = headers.get(USER_AGENT_HDR) {
+        if let Ok(value) = user_agent.to_str() {
+            metadata.insert(USER_AGENT_HDR.to_string(), value.to_string());
+        }
+    }
+    // The initiator and correlation id are carried through into the views; a correlation id
+    // is minted when the caller did not supply one so every request can be traced. The
+    // initiator is who `crate::auth::auth_layer` authorized the request for, what the
+    // caller says in `X-Initiator` is kept apart as unverified.
+    if let Some(principal) = principal {
+        metadata.insert(INITIATOR_KEY.to_string(), principal.0.clone());
+    }
+    if let Some(initiator) = headers.get(INITIATOR_HDR) {
+        if let Ok(value) = initiator.to_str() {
+            metadata.insert(CLAIMED_INITIATOR_KEY.to_string(), value.to_string());
+        }
+    }
+    let correlation_id = headers
+        .get(CORRELATION_ID_HDR)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| hex::encode(rand::thread_rng().gen::<[u8; 16]>()));
+    metadata.insert(CORRELATION_ID_KEY.to_string(), correlation_id);
+    // The request span, continuing the trace of the caller, see `telemetry::trace_layer`.
+    telemetry::inject(&mut metadata);
+    metadata
+}
+
+// The variant of an externally tagged command, `{"Deposit": {..}}` or `"Close"`, for the
+// audit log. Lists of commands and plain requests have none.
+pub(crate) fn command_name(value: &serde_json::Value) -> Option<String> {
+    let name = match value {
+        serde_json::Value::String(name) => name,
+        serde_json::Value::Object(object) if object.len() == 1 => object.keys().next()?,
+        _ => return None,
+    };
+    name.starts_with(|c: char| c.is_ascii_uppercase()).then(|| name.clone())
+}
+
 pub struct CommandExtractionError;
 
 impl IntoResponse for CommandExtractionError {
@@ -62,3 +124,69 @@ impl From<serde_json::Error> for CommandExtractionError {
         CommandExtractionError
     }
 }
+
+#[cfg(test)]
+mod test {
+    use axum::body::Body;
+    use axum::extract::FromRequest;
+    use axum::http::Request;
+    use base64::prelude::{Engine, BASE64_STANDARD};
+    use crate::account::commands::AccountCommand;
+    use serde_json::json;
+    use crate::auth::Principal;
+    use crate::command_extractor::{command_name, CommandExtractor, INITIATOR_HDR};
+    use crate::util::metadata::{CLAIMED_INITIATOR_KEY, INITIATOR_KEY};
+    use crate::util::types::ByteArray32;
+
+    async fn extract_txid(txid: serde_json::Value) -> Option<ByteArray32> {
+        let body = serde_json::json!({
+            "Transaction": { "txid": txid, "timestamp": 1, "command": { "Deposit": { "asset": "BTC", "amount": 1 } } }
+        });
+        let request = Request::post("/account/ACCT-0001").body(Body::from(body.to_string())).unwrap();
+        match CommandExtractor::<AccountCommand>::from_request(request, &()).await {
+            Ok(CommandExtractor(_, AccountCommand::Transaction { txid, .. })) => Some(txid),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_txid_encodings() {
+        let txid = ByteArray32::derive("test", "txid");
+        // Serializing and extracting again yields the same command.
+        let command = AccountCommand::deposited(txid, 1, "BTC".to_string(), 1);
+        let serialized = serde_json::to_value(&command).unwrap();
+        assert_eq!(serialized["Transaction"]["txid"], txid.hex());
+        assert_eq!(extract_txid(serialized["Transaction"]["txid"].clone()).await, Some(txid));
+
+        assert_eq!(extract_txid(BASE64_STANDARD.encode(txid.0).into()).await, Some(txid));
+        assert_eq!(extract_txid(bs58::encode(txid.0).into_string().into()).await, Some(txid));
+        assert_eq!(extract_txid(serde_json::to_value(txid.0).unwrap()).await, Some(txid));
+        assert_eq!(extract_txid("not a txid".into()).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_initiator() {
+        let request = || Request::post("/admin/account/ACCT-0001").header(INITIATOR_HDR, "ops@example.com").body(Body::from("\"Close\"")).unwrap();
+        let Ok(CommandExtractor(metadata, _)) = CommandExtractor::<serde_json::Value>::from_request(request(), &()).await else {
+            panic!("command not extracted");
+        };
+        assert_eq!(metadata.get(INITIATOR_KEY), None);
+        assert_eq!(metadata[CLAIMED_INITIATOR_KEY], "ops@example.com");
+
+        let mut request = request();
+        request.extensions_mut().insert(Principal("api-key:0".to_string()));
+        let Ok(CommandExtractor(metadata, _)) = CommandExtractor::<serde_json::Value>::from_request(request, &()).await else {
+            panic!("command not extracted");
+        };
+        assert_eq!(metadata[INITIATOR_KEY], "api-key:0");
+        assert_eq!(metadata[CLAIMED_INITIATOR_KEY], "ops@example.com");
+    }
+
+    #[test]
+    fn test_command_name() {
+        assert_eq!(command_name(&json!({ "Deposit": { "amount": 1 } })).as_deref(), Some("Deposit"));
+        assert_eq!(command_name(&json!("Close")).as_deref(), Some("Close"));
+        assert_eq!(command_name(&json!([{ "Deposit": { "amount": 1 } }])), None);
+        assert_eq!(command_name(&json!({ "accounts": [] })), None);
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use crate::account::commands::TransactionCommand;
+use crate::account::events::TransactionLabels;
+
+// The command types limits may be set for: the `TransactionCommand` variants moving an
+// amount in snake case, `transfer` for each leg of a transfer or swap and
+// `batch_transfer` per leg.
+pub const COMMAND_TYPES: &[&str] = &[
+    "deposit",
+    "withdraw",
+    "debit",
+    "reverse_debit",
+    "credit",
+    "reverse_credit",
+    "lock_funds",
+    "settle",
+    "place_hold",
+    "capture_hold",
+    "release_hold",
+    "earmark",
+    "convert_asset",
+    "transfer",
+    "batch_transfer",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PolicyViolation {
+    #[error("Amount {amount} of {command} exceeds the limit of {limit}")]
+    AmountTooLarge { command: &'static str, amount: u64, limit: u64 },
+    #[error("Memo of {size} bytes exceeds the limit of {limit}")]
+    MemoTooLarge { size: usize, limit: usize },
+    #[error("Batch of {len} exceeds the limit of {limit}")]
+    BatchTooLong { len: usize, limit: usize },
+}
+
+// The limits commands on behalf of one account are held to, see
+// `crate::config::CommandPolicyConfig`. They are checked by the account, transfer and
+// batch transfer aggregates, which every route and saga moving funds goes through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandPolicy {
+    // By command type, in minor units.
+    pub max_amounts: HashMap<String, u64>,
+    pub max_memo_bytes: usize,
+    pub max_batch_len: usize,
+}
+
+impl CommandPolicy {
+    // `available` is the balance of the account in an asset, which a conversion moves
+    // as a whole.
+    pub fn check_transaction(
+        &self,
+        command: &TransactionCommand,
+        labels: &TransactionLabels,
+        available: impl Fn(&str) -> u64,
+    ) -> Result<(), PolicyViolation> {
+        let memo = labels.category.as_ref().map_or(0, String::len)
+            + labels.metadata.iter().map(|(key, value)| key.len() + value.len()).sum::<usize>();
+        self.check_memo(memo)?;
+        match amount(command, available) {
+            Some((command, amount)) => self.check_amount(command, amount),
+            None => Ok(()),
+        }
+    }
+
+    pub fn check_batch(&self, len: usize) -> Result<(), PolicyViolation> {
+        match len > self.max_batch_len {
+            true => Err(PolicyViolation::BatchTooLong { len, limit: self.max_batch_len }),
+            false => Ok(()),
+        }
+    }
+
+    pub fn check_amount(&self, command: &'static str, amount: u64) -> Result<(), PolicyViolation> {
+        match self.max_amounts.get(command) {
+            Some(limit) if amount > *limit => Err(PolicyViolation::AmountTooLarge { command, amount, limit: *limit }),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_memo(&self, size: usize) -> Result<(), PolicyViolation> {
+        match size > self.max_memo_bytes {
+            true => Err(PolicyViolation::MemoTooLarge { size, limit: self.max_memo_bytes }),
+            false => Ok(()),
+        }
+    }
+}
+
+// The type of a transaction as named in the policy, with the amount it moves. Those
+// without one give back what an earlier command, checked in turn, set aside.
+fn amount(command: &TransactionCommand, available: impl Fn(&str) -> u64) -> Option<(&'static str, u64)> {
+    match command {
+        TransactionCommand::Deposit { amount, .. } => Some(("deposit", *amount)),
+        TransactionCommand::Withdraw { amount, .. } => Some(("withdraw", *amount)),
+        TransactionCommand::Debit { amount, .. } => Some(("debit", *amount)),
+        TransactionCommand::ReverseDebit { amount, .. } => Some(("reverse_debit", *amount)),
+        TransactionCommand::Credit { amount, .. } => Some(("credit", *amount)),
+        TransactionCommand::ReverseCredit { amount, .. } => Some(("reverse_credit", *amount)),
+        TransactionCommand::LockFunds { amount, .. } => Some(("lock_funds", *amount)),
+        TransactionCommand::Settle { receive_amount, .. } => Some(("settle", *receive_amount)),
+        TransactionCommand::PlaceHold { amount, .. } => Some(("place_hold", *amount)),
+        TransactionCommand::CaptureHold { amount, .. } => Some(("capture_hold", *amount)),
+        TransactionCommand::ReleaseHold { amount, .. } => Some(("release_hold", *amount)),
+        TransactionCommand::Earmark { amount, .. } => Some(("earmark", *amount)),
+        TransactionCommand::ConvertAsset { from_asset, .. } => Some(("convert_asset", available(from_asset))),
+        TransactionCommand::UnlockFunds
+        | TransactionCommand::LockHeld { .. }
+        | TransactionCommand::ReleaseQuarantine
+        | TransactionCommand::ReleaseEarmark { .. } => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use crate::account::commands::TransactionCommand;
+    use crate::account::events::TransactionLabels;
+    use crate::asset::types::ConversionRatio;
+    use crate::batch_transfer::commands::BatchTransferCommand;
+    use crate::batch_transfer::events::Leg;
+    use crate::command_policy::PolicyViolation;
+    use crate::config::AppConfig;
+    use crate::transfer::commands::TransferCommand;
+    use crate::util::types::ByteArray32;
+
+    #[test]
+    fn test_command_policy() {
+        let config = AppConfig::from_toml(
+            "app.toml",
+            "[command_policy]\nmax_amounts = { deposit = 1000000, convert_asset = 100, transfer = 500 }\nmax_memo_bytes = 16\nmax_batch_len = 3\n\
+             [command_policy.tenants.\"ACME-\"]\nmax_amounts = { deposit = 10 }\nmax_batch_len = 1\n",
+        ).unwrap();
+        let deposit = |amount| TransactionCommand::Deposit { asset: "BTC".to_string(), amount, source: None, value_date: None };
+        let labels = TransactionLabels::default();
+        let balance = |_: &str| 0;
+
+        let policy = config.command_policy.policy("ACCT-0001");
+        assert_eq!(policy.check_transaction(&deposit(1_000_000), &labels, balance), Ok(()));
+        // A fat-fingered deposit is caught before it reaches the account.
+        assert_eq!(
+            policy.check_transaction(&deposit(10u64.pow(18)), &labels, balance),
+            Err(PolicyViolation::AmountTooLarge { command: "deposit", amount: 10u64.pow(18), limit: 1_000_000 }),
+        );
+        assert_eq!(policy.check_amount("withdraw", u64::MAX), Ok(()));
+        let labelled = TransactionLabels { category: Some("payroll".to_string()), metadata: BTreeMap::from([("invoice".to_string(), "INV-00001".to_string())]) };
+        assert_eq!(policy.check_transaction(&deposit(1), &labelled, balance), Err(PolicyViolation::MemoTooLarge { size: 23, limit: 16 }));
+        assert_eq!(policy.check_batch(4), Err(PolicyViolation::BatchTooLong { len: 4, limit: 3 }));
+        // A conversion moves the whole balance of the asset.
+        let convert = TransactionCommand::ConvertAsset { from_asset: "OLD".to_string(), to_asset: "NEW".to_string(), ratio: ConversionRatio { numerator: 1, denominator: 1 } };
+        assert_eq!(policy.check_transaction(&convert, &labels, |_| 100), Ok(()));
+        assert_eq!(
+            policy.check_transaction(&convert, &labels, |_| 101),
+            Err(PolicyViolation::AmountTooLarge { command: "convert_asset", amount: 101, limit: 100 }),
+        );
+
+        // Tenants override single limits and keep the others.
+        let policy = config.command_policy.policy("ACME-0001");
+        assert_eq!(policy.max_amounts.get("deposit"), Some(&10));
+        assert_eq!(policy.max_amounts.get("transfer"), Some(&500));
+        assert_eq!((policy.max_memo_bytes, policy.max_batch_len), (16, 1));
+
+        // Each leg of a swap is held to the policy of the account funding it.
+        let swap = |amount, counter_amount| TransferCommand::SwapOpen {
+            transfer_id: ByteArray32::default(),
+            from_account: "ACCT-0001".to_string(),
+            to_account: "ACCT-0002".to_string(),
+            asset: "BTC".to_string(),
+            amount,
+            counter_asset: "USD".to_string(),
+            counter_amount,
+            timestamp: 1,
+            description: "swap".to_string(),
+        };
+        assert_eq!(config.command_policy.check_transfer(&swap(500, 500)), Ok(()));
+        assert_eq!(
+            config.command_policy.check_transfer(&swap(500, 501)),
+            Err(PolicyViolation::AmountTooLarge { command: "transfer", amount: 501, limit: 500 }),
+        );
+        let leg = |from_account: &str, amount| Leg { from_account: from_account.to_string(), to_account: "ACCT-0002".to_string(), asset: "BTC".to_string(), amount };
+        let batch = |legs| BatchTransferCommand::Open { batch_id: ByteArray32::default(), legs, timestamp: 1, description: "payroll".to_string() };
+        assert_eq!(config.command_policy.check_batch_transfer(&batch(vec![leg("ACCT-0001", u64::MAX)])), Ok(()));
+        assert_eq!(
+            config.command_policy.check_batch_transfer(&batch(vec![leg("ACCT-0001", 1); 4])),
+            Err(PolicyViolation::BatchTooLong { len: 4, limit: 3 }),
+        );
+
+        assert_eq!(AppConfig::default().command_policy.policy("ACCT-0001").check_transaction(&deposit(u64::MAX), &labels, balance), Ok(()));
+    }
+}
@@ -0,0 +1,265 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use cqrs_es::persist::{PersistedEventRepository, PersistedEventStore, PersistenceError, ReplayStream, SerializedEvent, SerializedSnapshot};
+use cqrs_es::{Aggregate, CqrsFramework, Query};
+use postgres_es::PostgresEventRepository;
+use serde_json::Value;
+use sqlx::{Pool, Postgres, Transaction};
+use tokio::sync::{mpsc, oneshot};
+use crate::config::BatchingConfig;
+
+// A write made in the transaction committing the events of a command, so it is made
+// exactly when the events are, unlike those of the queries dispatched after the commit.
+// Failing it fails the command, nothing it carries is committed.
+#[async_trait]
+pub trait CommitHook: Send + Sync {
+    async fn append(&self, tx: &mut Transaction<'_, Postgres>, events: &[SerializedEvent]) -> Result<(), PersistenceError>;
+}
+
+pub type HookedCqrs<A> = CqrsFramework<A, PersistedEventStore<HookedEventRepository, A>>;
+
+// `postgres_es::postgres_snapshot_cqrs` committing what `events` hooks write along.
+pub fn hooked_snapshot_cqrs<A: Aggregate>(events: HookedEventRepository, queries: Vec<Box<dyn Query<A>>>, snapshot_size: usize, services: A::Services) -> HookedCqrs<A> {
+    CqrsFramework::new(PersistedEventStore::new_snapshot_store(events, snapshot_size), queries, services)
+}
+
+// `PostgresEventRepository` committing the events together with what its hooks write.
+// With batching, the commits of concurrent commands are written in one transaction,
+// `max_batch` at most, each under a savepoint of its own so one losing its optimistic
+// lock leaves the others in.
+pub struct HookedEventRepository {
+    inner: PostgresEventRepository,
+    pool: Pool<Postgres>,
+    hooks: Vec<Arc<dyn CommitHook>>,
+    batches: Option<mpsc::Sender<Pending>>,
+}
+
+// A commit waiting for the batch it is written in.
+struct Pending {
+    aggregate_type: String,
+    events: Vec<SerializedEvent>,
+    snapshot_update: Option<(String, Value, usize)>,
+    done: oneshot::Sender<Result<(), PersistenceError>>,
+}
+
+impl HookedEventRepository {
+    pub fn new(pool: Pool<Postgres>, hooks: Vec<Arc<dyn CommitHook>>) -> Self {
+        HookedEventRepository { inner: PostgresEventRepository::new(pool.clone()), pool, hooks, batches: None }
+    }
+
+    // Batches the commits as configured, unless batching is off. Spawns the task writing
+    // them, which stops once the repository is dropped.
+    pub fn with_batching(mut self, config: &BatchingConfig) -> Self {
+        if !config.enabled {
+            return self;
+        }
+        let (sender, receiver) = mpsc::channel(config.queue_capacity.max(1));
+        let (pool, hooks, max_batch) = (self.pool.clone(), self.hooks.clone(), config.max_batch.max(1));
+        tokio::spawn(async move { write_batches(pool, hooks, receiver, max_batch).await });
+        self.batches = Some(sender);
+        self
+    }
+
+    async fn commit<A: Aggregate>(&self, events: &[SerializedEvent], snapshot_update: Option<(String, Value, usize)>) -> Result<(), PersistenceError> {
+        let mut tx = self.pool.begin().await.map_err(persistence_error)?;
+        write(&mut tx, &self.hooks, &A::aggregate_type(), events, snapshot_update).await?;
+        tx.commit().await.map_err(persistence_error)
+    }
+
+    async fn enqueue<A: Aggregate>(batches: &mpsc::Sender<Pending>, events: &[SerializedEvent], snapshot_update: Option<(String, Value, usize)>) -> Result<(), PersistenceError> {
+        let (done, result) = oneshot::channel();
+        let pending = Pending { aggregate_type: A::aggregate_type(), events: events.to_vec(), snapshot_update, done };
+        let stopped = || PersistenceError::ConnectionError("the commit batches are no longer written".into());
+        batches.send(pending).await.map_err(|_| stopped())?;
+        result.await.map_err(|_| stopped())?
+    }
+}
+
+async fn write(
+    tx: &mut Transaction<'_, Postgres>,
+    hooks: &[Arc<dyn CommitHook>],
+    aggregate_type: &str,
+    events: &[SerializedEvent],
+    snapshot_update: Option<(String, Value, usize)>,
+) -> Result<(), PersistenceError> {
+    let mut last_sequence = 0;
+    for event in events {
+        last_sequence = event.sequence;
+        sqlx::query(
+            "INSERT INTO events (aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        )
+            .bind(aggregate_type)
+            .bind(&event.aggregate_id)
+            .bind(event.sequence as i64)
+            .bind(&event.event_type)
+            .bind(&event.event_version)
+            .bind(&event.payload)
+            .bind(&event.metadata)
+            .execute(&mut **tx)
+            .await
+            .map_err(persistence_error)?;
+    }
+    // Same as `PostgresEventRepository`, another command got to the snapshot first
+    // when it is not at the one before.
+    match snapshot_update {
+        Some((aggregate_id, aggregate, 1)) => {
+            sqlx::query(
+                "INSERT INTO snapshots (aggregate_type, aggregate_id, last_sequence, current_snapshot, payload)
+                 VALUES ($1, $2, $3, 1, $4)",
+            )
+                .bind(aggregate_type)
+                .bind(aggregate_id)
+                .bind(last_sequence as i64)
+                .bind(aggregate)
+                .execute(&mut **tx)
+                .await
+                .map_err(persistence_error)?;
+        }
+        Some((aggregate_id, aggregate, current_snapshot)) => {
+            let updated = sqlx::query(
+                "UPDATE snapshots SET last_sequence = $3, payload = $4, current_snapshot = $5
+                 WHERE aggregate_type = $1 AND aggregate_id = $2 AND current_snapshot = $6",
+            )
+                .bind(aggregate_type)
+                .bind(aggregate_id)
+                .bind(last_sequence as i64)
+                .bind(aggregate)
+                .bind(current_snapshot as i64)
+                .bind(current_snapshot as i64 - 1)
+                .execute(&mut **tx)
+                .await
+                .map_err(persistence_error)?;
+            if updated.rows_affected() != 1 {
+                return Err(PersistenceError::OptimisticLockError);
+            }
+        }
+        None => {}
+    }
+    for hook in hooks {
+        hook.append(tx, events).await?;
+    }
+    Ok(())
+}
+
+async fn write_batches(pool: Pool<Postgres>, hooks: Vec<Arc<dyn CommitHook>>, mut receiver: mpsc::Receiver<Pending>, max_batch: usize) {
+    while let Some(first) = receiver.recv().await {
+        let mut batch = vec![first];
+        while batch.len() < max_batch {
+            match receiver.try_recv() {
+                Ok(pending) => batch.push(pending),
+                Err(_) => break,
+            }
+        }
+        let results = match write_batch(&pool, &hooks, &mut batch).await {
+            Ok(results) => results,
+            Err(e) => batch.iter().map(|_| Err(PersistenceError::ConnectionError(e.to_string().into()))).collect(),
+        };
+        for (pending, result) in batch.into_iter().zip(results) {
+            // The command may have given up waiting.
+            let _ = pending.done.send(result);
+        }
+    }
+}
+
+// The result of every commit of the batch, or the error that failed them all.
+async fn write_batch(pool: &Pool<Postgres>, hooks: &[Arc<dyn CommitHook>], batch: &mut [Pending]) -> Result<Vec<Result<(), PersistenceError>>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let mut results = Vec::with_capacity(batch.len());
+    for pending in batch.iter_mut() {
+        sqlx::query("SAVEPOINT batched_commit").execute(&mut *tx).await?;
+        let result = write(&mut tx, hooks, &pending.aggregate_type, &pending.events, pending.snapshot_update.take()).await;
+        if result.is_err() {
+            sqlx::query("ROLLBACK TO SAVEPOINT batched_commit").execute(&mut *tx).await?;
+        }
+        sqlx::query("RELEASE SAVEPOINT batched_commit").execute(&mut *tx).await?;
+        results.push(result);
+    }
+    tx.commit().await?;
+    Ok(results)
+}
+
+// A unique violation is another command committing first, as in `postgres_es`.
+pub fn persistence_error(err: sqlx::Error) -> PersistenceError {
+    match &err {
+        sqlx::Error::Database(database_error) if database_error.code().as_deref() == Some("23505") => PersistenceError::OptimisticLockError,
+        _ => PersistenceError::ConnectionError(Box::new(err)),
+    }
+}
+
+#[async_trait]
+impl PersistedEventRepository for HookedEventRepository {
+    async fn get_events<A: Aggregate>(&self, aggregate_id: &str) -> Result<Vec<SerializedEvent>, PersistenceError> {
+        self.inner.get_events::<A>(aggregate_id).await
+    }
+
+    async fn get_last_events<A: Aggregate>(&self, aggregate_id: &str, last_sequence: usize) -> Result<Vec<SerializedEvent>, PersistenceError> {
+        self.inner.get_last_events::<A>(aggregate_id, last_sequence).await
+    }
+
+    async fn get_snapshot<A: Aggregate>(&self, aggregate_id: &str) -> Result<Option<SerializedSnapshot>, PersistenceError> {
+        self.inner.get_snapshot::<A>(aggregate_id).await
+    }
+
+    async fn persist<A: Aggregate>(&self, events: &[SerializedEvent], snapshot_update: Option<(String, Value, usize)>) -> Result<(), PersistenceError> {
+        match &self.batches {
+            Some(batches) => Self::enqueue::<A>(batches, events, snapshot_update).await,
+            None if self.hooks.is_empty() => self.inner.persist::<A>(events, snapshot_update).await,
+            None => self.commit::<A>(events, snapshot_update).await,
+        }
+    }
+
+    async fn stream_events<A: Aggregate>(&self, aggregate_id: &str) -> Result<ReplayStream, PersistenceError> {
+        self.inner.stream_events::<A>(aggregate_id).await
+    }
+
+    async fn stream_all_events<A: Aggregate>(&self) -> Result<ReplayStream, PersistenceError> {
+        self.inner.stream_all_events::<A>().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::persist::{PersistedEventRepository, PersistenceError, SerializedEvent};
+    use serde_json::json;
+    use crate::commit_hooks::HookedEventRepository;
+    use crate::config::BatchingConfig;
+    use crate::order::aggregate::Order;
+    use crate::util::test_db::{delete_prefixed, random_suffix, test_pool};
+
+    fn event(aggregate_id: &str, sequence: usize) -> SerializedEvent {
+        SerializedEvent {
+            aggregate_id: aggregate_id.to_string(),
+            sequence,
+            aggregate_type: "order".to_string(),
+            event_type: "Placed".to_string(),
+            event_version: "1.0".to_string(),
+            payload: json!({ "Placed": { "timestamp": 1 } }),
+            metadata: json!({}),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batched_commits() {
+        let pool = test_pool();
+        let config = BatchingConfig { enabled: true, queue_capacity: 16, max_batch: 16 };
+        let repo = HookedEventRepository::new(pool.clone(), vec![]).with_batching(&config);
+        let prefix = format!("BATCHED-{}-", random_suffix());
+        let (a, b) = (format!("{}a", prefix), format!("{}b", prefix));
+
+        // Committed together, the second commit to `a` loses its optimistic lock alone.
+        let commits = [vec![event(&a, 1)], vec![event(&a, 1), event(&a, 2)], vec![event(&b, 1)]];
+        let (first, second, other) = tokio::join!(
+            repo.persist::<Order>(&commits[0], None),
+            repo.persist::<Order>(&commits[1], None),
+            repo.persist::<Order>(&commits[2], None),
+        );
+        assert!(first.is_ok());
+        assert!(matches!(second, Err(PersistenceError::OptimisticLockError)));
+        assert!(other.is_ok());
+        assert_eq!(repo.get_events::<Order>(&a).await.unwrap().len(), 1);
+        assert_eq!(repo.get_events::<Order>(&b).await.unwrap().len(), 1);
+
+        delete_prefixed(&pool, "events", "aggregate_id", &prefix).await;
+    }
+}
@@ -0,0 +1,132 @@
+use futures::TryStreamExt;
+use serde::Serialize;
+use sqlx::{Pool, Postgres, Row};
+use crate::config::ArchiveConfig;
+use crate::replay::ReplayThrottle;
+
+// Every stored event, hot or archived, for the tools that replay full histories.
+pub const ALL_EVENTS: &str = "(SELECT aggregate_type, aggregate_id, sequence, payload, metadata FROM events_archive
+    UNION ALL SELECT aggregate_type, aggregate_id, sequence, payload, metadata FROM events) AS all_events";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ArchiveReport {
+    pub aggregates: u64,
+    pub events: u64,
+}
+
+// Moves the events each snapshot already covers from `events` to `events_archive`.
+// Loading an aggregate only reads the snapshot and the events after it, so the
+// framework never notices; replaying a full history (projection rebuilds, the
+// compaction verifier) reads both tables through `ALL_EVENTS`.
+//
+// Each aggregate is moved in a single statement, so an event is always in exactly
+// one of the tables. Run `verify-compaction` first: once archived, a wrong snapshot
+// can no longer be told apart from the history it replaced without the archive.
+// Paced by `throttle` in events moved.
+pub async fn archive_events(pool: &Pool<Postgres>, aggregate_type: Option<&str>, config: &ArchiveConfig, throttle: &mut ReplayThrottle) -> Result<ArchiveReport, ArchiveError> {
+    let snapshots: Vec<(String, String, i64)> = sqlx::query(
+        "SELECT aggregate_type, aggregate_id, last_sequence FROM snapshots
+         WHERE $1::text IS NULL OR aggregate_type = $1
+         ORDER BY aggregate_type, aggregate_id",
+    )
+        .bind(aggregate_type)
+        .fetch(pool)
+        .map_ok(|row| (row.get("aggregate_type"), row.get("aggregate_id"), row.get("last_sequence")))
+        .try_collect()
+        .await?;
+
+    let mut report = ArchiveReport::default();
+    for (aggregate_type, aggregate_id, last_sequence) in snapshots {
+        let up_to = last_sequence - config.keep_events as i64;
+        if up_to < 1 {
+            continue;
+        }
+        let moved = sqlx::query(
+            "WITH moved AS (
+                 DELETE FROM events WHERE aggregate_type = $1 AND aggregate_id = $2 AND sequence <= $3
+                 RETURNING aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata
+             )
+             INSERT INTO events_archive (aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata, archived_at)
+             SELECT aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata, now() FROM moved",
+        )
+            .bind(&aggregate_type)
+            .bind(&aggregate_id)
+            .bind(up_to)
+            .execute(pool)
+            .await?
+            .rows_affected();
+        if moved > 0 {
+            report.aggregates += 1;
+            report.events += moved;
+            tracing::debug!("archived {} events of {} {}", moved, aggregate_type, aggregate_id);
+        }
+        throttle.advance(moved).await?;
+    }
+    tracing::info!("archived {} events of {} aggregates", report.events, report.aggregates);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::Row;
+    use crate::compaction::archive::{archive_events, ALL_EVENTS};
+    use crate::config::{ArchiveConfig, ReplayConfig};
+    use crate::replay::{ReplayPriority, ReplayThrottle};
+    use crate::util::test_db::{delete_rows, random_suffix, test_pool};
+
+    #[tokio::test]
+    async fn test_archive_moves_events_covered_by_snapshot() {
+        let pool = test_pool();
+        let aggregate_type = format!("archive-test-{}", random_suffix());
+        for sequence in 1..=5i64 {
+            sqlx::query("INSERT INTO events (aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata) VALUES ($1, 'A', $2, 'E', '1.0', '{}', '{}')")
+                .bind(&aggregate_type)
+                .bind(sequence)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        sqlx::query("INSERT INTO snapshots (aggregate_type, aggregate_id, last_sequence, current_snapshot, payload) VALUES ($1, 'A', 4, 1, '{}')")
+            .bind(&aggregate_type)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let mut throttle = ReplayThrottle::start(&pool, &aggregate_type, &ReplayConfig::default(), ReplayPriority::Urgent).await.unwrap();
+        let report = archive_events(&pool, Some(&aggregate_type), &ArchiveConfig { keep_events: 1 }, &mut throttle).await.unwrap();
+        throttle.finish(true).await.unwrap();
+        assert_eq!((report.aggregates, report.events), (1, 3));
+
+        let hot: Vec<i64> = sqlx::query("SELECT sequence FROM events WHERE aggregate_type = $1 ORDER BY sequence")
+            .bind(&aggregate_type)
+            .fetch_all(&pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get("sequence"))
+            .collect();
+        assert_eq!(hot, vec![4, 5]);
+        let all: i64 = sqlx::query(&format!("SELECT count(*) AS n FROM {} WHERE aggregate_type = $1", ALL_EVENTS))
+            .bind(&aggregate_type)
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get("n");
+        assert_eq!(all, 5);
+
+        delete_rows(&pool, "replay_jobs", "name", &aggregate_type).await;
+        for table in ["events", "events_archive", "snapshots"] {
+            sqlx::query(&format!("DELETE FROM {} WHERE aggregate_type = $1", table))
+                .bind(&aggregate_type)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+    }
+}
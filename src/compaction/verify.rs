@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use cqrs_es::{Aggregate, EventEnvelope, View};
+use futures::{StreamExt, TryStreamExt};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{Pool, Postgres, Row};
+use crate::account::aggregate::Account;
+use crate::account::events::AccountEvent;
+use crate::account::queries::AccountView;
+use crate::compaction::archive::ALL_EVENTS;
+use crate::replay::ReplayThrottle;
+use crate::sealing::{SealError, Sealer};
+
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Malformed payload for {0}: {1}")]
+    Payload(String, serde_json::Error),
+    #[error(transparent)]
+    Seal(#[from] SealError),
+}
+
+#[derive(Debug, Serialize)]
+pub struct Mismatch {
+    pub path: String,
+    pub expected: Value,
+    pub actual: Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AggregateReport {
+    pub aggregate_id: String,
+    pub events: u64,
+    pub snapshot_sequence: Option<u64>,
+    // Differences between the aggregate rebuilt from every event and the one rebuilt
+    // from its snapshot plus the events recorded after it.
+    pub state_mismatches: Vec<Mismatch>,
+    // Differences between the view rebuilt from every event and the stored view.
+    pub view_mismatches: Vec<Mismatch>,
+}
+
+impl AggregateReport {
+    pub fn is_consistent(&self) -> bool {
+        self.state_mismatches.is_empty() && self.view_mismatches.is_empty()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub inconsistent: usize,
+    pub aggregates: Vec<AggregateReport>,
+}
+
+// Replays every account twice, once from the full event history and once from its
+// snapshot plus the trailing events, and diffs both the aggregate states and the
+// views. Only inconsistent accounts are kept in the report unless `verbose` is set.
+// Sealed snapshots and views are opened with `sealer`, failing on any altered one.
+// Paced by `throttle` in events replayed.
+pub async fn verify_accounts(pool: &Pool<Postgres>, sealer: Option<&Sealer>, concurrency: usize, verbose: bool, throttle: &mut ReplayThrottle) -> Result<VerifyReport, VerifyError> {
+    let ids: Vec<String> = sqlx::query(&format!("SELECT DISTINCT aggregate_id FROM {} WHERE aggregate_type = $1 ORDER BY aggregate_id", ALL_EVENTS))
+        .bind(Account::aggregate_type())
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|row| row.try_get("aggregate_id"))
+        .collect::<Result<_, _>>()?;
+    let checked = ids.len();
+    let mut reports = futures::stream::iter(ids)
+        .map(|id| async move { verify_account(pool, sealer, id).await })
+        .buffer_unordered(concurrency.max(1));
+    let mut aggregates: Vec<AggregateReport> = vec![];
+    while let Some(report) = reports.try_next().await? {
+        throttle.advance(report.events).await?;
+        if verbose || !report.is_consistent() {
+            aggregates.push(report);
+        }
+    }
+    aggregates.sort_by(|a, b| a.aggregate_id.cmp(&b.aggregate_id));
+    Ok(VerifyReport {
+        checked,
+        inconsistent: aggregates.iter().filter(|r| !r.is_consistent()).count(),
+        aggregates,
+    })
+}
+
+async fn verify_account(pool: &Pool<Postgres>, sealer: Option<&Sealer>, aggregate_id: String) -> Result<AggregateReport, VerifyError> {
+    let (events, snapshot, stored_view) = tokio::try_join!(
+        load_events(pool, &aggregate_id),
+        load_snapshot(pool, sealer, &aggregate_id),
+        load_view(pool, sealer, &aggregate_id),
+    )?;
+
+    let mut full = Account::default();
+    let mut view = AccountView::default();
+    for envelope in &events {
+        full.apply(envelope.payload.clone());
+        view.update(envelope);
+    }
+
+    let state_mismatches = match &snapshot {
+        Some((last_sequence, payload)) => {
+            let mut compacted: Account = serde_json::from_value(payload.clone())
+                .map_err(|e| VerifyError::Payload(format!("snapshot of {}", aggregate_id), e))?;
+            for envelope in events.iter().filter(|e| e.sequence > *last_sequence as usize) {
+                compacted.apply(envelope.payload.clone());
+            }
+            diff(&to_value(&full), &to_value(&compacted))
+        }
+        None => vec![],
+    };
+    let view_mismatches = match stored_view {
+        Some(stored) => diff(&to_value(&view), &stored),
+        None => vec![Mismatch { path: "".to_string(), expected: to_value(&view), actual: Value::Null }],
+    };
+
+    Ok(AggregateReport {
+        aggregate_id,
+        events: events.len() as u64,
+        snapshot_sequence: snapshot.map(|(sequence, _)| sequence),
+        state_mismatches,
+        view_mismatches,
+    })
+}
+
+async fn load_events(pool: &Pool<Postgres>, aggregate_id: &str) -> Result<Vec<EventEnvelope<Account>>, VerifyError> {
+    let rows = sqlx::query(&format!("SELECT sequence, payload, metadata FROM {} WHERE aggregate_type = $1 AND aggregate_id = $2 ORDER BY sequence", ALL_EVENTS))
+        .bind(Account::aggregate_type())
+        .bind(aggregate_id)
+        .fetch_all(pool)
+        .await?;
+    let mut events = Vec::with_capacity(rows.len());
+    for row in rows {
+        let sequence: i64 = row.try_get("sequence")?;
+        let payload: Value = row.try_get("payload")?;
+        let metadata: Value = row.try_get("metadata")?;
+        let context = || format!("event {}-{}", aggregate_id, sequence);
+        let payload: AccountEvent = serde_json::from_value(payload).map_err(|e| VerifyError::Payload(context(), e))?;
+        let metadata: HashMap<String, String> = serde_json::from_value(metadata).map_err(|e| VerifyError::Payload(context(), e))?;
+        events.push(EventEnvelope {
+            aggregate_id: aggregate_id.to_string(),
+            sequence: sequence as usize,
+            payload,
+            metadata,
+        });
+    }
+    Ok(events)
+}
+
+async fn load_snapshot(pool: &Pool<Postgres>, sealer: Option<&Sealer>, aggregate_id: &str) -> Result<Option<(u64, Value)>, VerifyError> {
+    let row = sqlx::query("SELECT last_sequence, current_snapshot, payload FROM snapshots WHERE aggregate_type = $1 AND aggregate_id = $2")
+        .bind(Account::aggregate_type())
+        .bind(aggregate_id)
+        .fetch_optional(pool)
+        .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let last_sequence: i64 = row.try_get("last_sequence")?;
+    let payload: Value = row.try_get("payload")?;
+    let payload = match sealer {
+        Some(sealer) => {
+            let kind = format!("snapshot/{}", Account::aggregate_type());
+            sealer.open(&kind, aggregate_id, row.try_get("current_snapshot")?, payload)?
+        }
+        None => payload,
+    };
+    Ok(Some((last_sequence as u64, payload)))
+}
+
+async fn load_view(pool: &Pool<Postgres>, sealer: Option<&Sealer>, aggregate_id: &str) -> Result<Option<Value>, VerifyError> {
+    let row = sqlx::query("SELECT version, payload FROM account_query WHERE view_id = $1")
+        .bind(aggregate_id)
+        .fetch_optional(pool)
+        .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let payload: Value = row.try_get("payload")?;
+    Ok(Some(match sealer {
+        Some(sealer) => sealer.open("view/account_query", aggregate_id, row.try_get("version")?, payload)?,
+        None => payload,
+    }))
+}
+
+fn to_value<T: Serialize>(value: &T) -> Value {
+    serde_json::to_value(value).expect("aggregate and view are always serializable")
+}
+
+// Structural JSON diff, reporting the deepest differing paths as `/a/b/0` pointers.
+fn diff(expected: &Value, actual: &Value) -> Vec<Mismatch> {
+    let mut mismatches = vec![];
+    diff_at("", expected, actual, &mut mismatches);
+    mismatches
+}
+
+fn diff_at(path: &str, expected: &Value, actual: &Value, out: &mut Vec<Mismatch>) {
+    match (expected, actual) {
+        (Value::Object(e), Value::Object(a)) => {
+            for key in e.keys().chain(a.keys().filter(|k| !e.contains_key(*k))) {
+                let null = Value::Null;
+                diff_at(
+                    &format!("{}/{}", path, key),
+                    e.get(key).unwrap_or(&null),
+                    a.get(key).unwrap_or(&null),
+                    out,
+                );
+            }
+        }
+        (Value::Array(e), Value::Array(a)) if e.len() == a.len() => {
+            for (i, (e, a)) in e.iter().zip(a).enumerate() {
+                diff_at(&format!("{}/{}", path, i), e, a, out);
+            }
+        }
+        (e, a) if e != a => out.push(Mismatch {
+            path: path.to_string(),
+            expected: e.clone(),
+            actual: a.clone(),
+        }),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use crate::compaction::verify::diff;
+
+    #[test]
+    fn test_diff_reports_nested_paths() {
+        let expected = json!({"balance": {"BTC": 10, "ETH": 5}, "ledger": [1, 2]});
+        let actual = json!({"balance": {"BTC": 10, "ETH": 6}, "ledger": [1, 2], "extra": true});
+        let mismatches = diff(&expected, &actual);
+        let paths: Vec<&str> = mismatches.iter().map(|m| m.path.as_str()).collect();
+        assert_eq!(paths, vec!["/balance/ETH", "/extra"]);
+    }
+
+    #[test]
+    fn test_diff_identical() {
+        let value = json!({"a": [1, {"b": null}]});
+        assert!(diff(&value, &value).is_empty());
+    }
+}
@@ -0,0 +1,323 @@
+use std::future::Future;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use cqrs_es::{Aggregate, AggregateError, EventEnvelope, Query};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tracing::Instrument;
+use sqlx::{Pool, Postgres, Row};
+use crate::background::Background;
+use crate::account::commands::AccountCommand;
+use crate::account::events::AccountError;
+use crate::config::CompensationConfig;
+use crate::services::AccountExecutor;
+use crate::signals::{SignalKind, Signals};
+use crate::util::clock;
+use crate::util::transaction_guard::TransactionGuard;
+
+const SWEEP_BATCH: i64 = 500;
+
+// The undo of every saga step on an account, kept in `compensations` from before the
+// step executes until the saga event recording it commits, see `CompensationQuery`, or
+// its guard has undone it. A guard only undoes its step when dropped in a running
+// process, so what a crash cut short stays in the journal, and `spawn` executes it once
+// it is older than any saga still running could be, unless the saga recorded the step.
+#[derive(Clone)]
+pub struct CompensationJournal {
+    pool: Pool<Postgres>,
+    signals: Option<Signals>,
+}
+
+// A compensation in the journal, settled by the guard of its step.
+#[derive(Clone)]
+pub struct Journaled {
+    journal: CompensationJournal,
+    id: i64,
+    account_id: String,
+}
+
+impl CompensationJournal {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        CompensationJournal { pool, signals: None }
+    }
+
+    // Counts the compensations executed, see `Signals`.
+    pub fn with_signals(mut self, signals: Signals) -> Self {
+        self.signals = Some(signals);
+        self
+    }
+
+    // Journals `undo` before the step it compensates executes; the step must not execute
+    // when this fails, nothing could undo it after a crash. `saga` is the aggregate type
+    // of the saga and `saga_id` its aggregate id, the sequence it is at is taken along.
+    pub async fn record(&self, saga: &str, saga_id: &str, account_id: &str, undo: &AccountCommand) -> Result<Journaled, AggregateError<AccountError>> {
+        let id = sqlx::query_scalar(
+            "INSERT INTO compensations (saga, saga_id, account_id, command, attempts, created_at, sequence, kept)
+             VALUES ($1, $2, $3, $4, 0, $5,
+                     (SELECT COALESCE(MAX(sequence), 0) FROM events WHERE aggregate_type = $1 AND aggregate_id = $2), false)
+             RETURNING id",
+        )
+            .bind(saga)
+            .bind(saga_id)
+            .bind(account_id)
+            .bind(serde_json::to_value(undo).expect("account commands serialize"))
+            .bind(clock::now() as i64)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| AggregateError::DatabaseConnectionError(Box::new(e)))?;
+        Ok(Journaled { journal: self.clone(), id, account_id: account_id.to_string() })
+    }
+
+    // Marks the step kept, to stand once the saga event after `sequence` commits.
+    async fn keep(&self, id: i64) -> Result<(), AggregateError<AccountError>> {
+        sqlx::query("UPDATE compensations SET kept = true WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| AggregateError::DatabaseConnectionError(Box::new(e)))?;
+        Ok(())
+    }
+
+    async fn remove(&self, id: i64) {
+        let removed = sqlx::query("DELETE FROM compensations WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await;
+        if let Err(e) = removed {
+            tracing::error!("Failed to remove compensation {} from the journal: {}", id, e);
+        }
+    }
+
+    async fn executed(&self, account_id: &str) {
+        if let Some(signals) = &self.signals {
+            signals.record(account_id, SignalKind::Compensation, 1).await;
+        }
+    }
+
+    pub fn spawn(&self, background: &Background, accounts: Arc<dyn AccountExecutor>, config: CompensationConfig) {
+        let journal = self.clone();
+        background.spawn(|mut shutdown| async move {
+            loop {
+                match journal.recover(&*accounts, config.stale_after_secs).await {
+                    Ok(recovered) if recovered as i64 == SWEEP_BATCH && !shutdown.is_stopping() => continue,
+                    Ok(_) => {}
+                    Err(e) => tracing::error!("Failed to recover journaled compensations: {}", e),
+                }
+                if !shutdown.pause(Duration::from_secs(config.sweep_interval_secs.max(1))).await {
+                    break;
+                }
+            }
+        });
+    }
+
+    // Executes the compensations journaled over `stale_after_secs` ago, oldest first, and
+    // returns how many it settled. Those failing stay for the next sweep. Steps kept by a
+    // saga which went on past them are only taken out, `CompensationQuery` missed them.
+    pub async fn recover(&self, accounts: &dyn AccountExecutor, stale_after_secs: u64) -> Result<usize, sqlx::Error> {
+        let rows = sqlx::query(
+            "SELECT id, saga, saga_id, account_id, command,
+                    kept AND EXISTS (
+                        SELECT 1 FROM events
+                        WHERE aggregate_type = c.saga AND aggregate_id = c.saga_id AND events.sequence > c.sequence
+                    ) AS recorded
+             FROM compensations c WHERE created_at <= $1 ORDER BY id LIMIT $2",
+        )
+            .bind(clock::now().saturating_sub(stale_after_secs) as i64)
+            .bind(SWEEP_BATCH)
+            .fetch_all(&self.pool)
+            .await?;
+        let mut recovered = 0;
+        for row in rows {
+            let (id, saga, saga_id, account_id): (i64, String, String, String) =
+                (row.try_get("id")?, row.try_get("saga")?, row.try_get("saga_id")?, row.try_get("account_id")?);
+            if row.try_get("recorded")? {
+                self.remove(id).await;
+                recovered += 1;
+                continue;
+            }
+            let command: AccountCommand = serde_json::from_value(row.try_get("command")?).map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+            // Nothing left to undo when the step never executed or was undone already.
+            let executed = accounts.execute(&account_id, command).await;
+            if executed.is_ok() {
+                self.executed(&account_id).await;
+            }
+            match executed {
+                Ok(_)
+                | Err(AggregateError::UserError(
+                    AccountError::LockNotFound | AccountError::TransactionNotFound | AccountError::AccountNotFound,
+                )) => {
+                    tracing::warn!("Recovered the compensation of {} {} on {}", saga, saga_id, account_id);
+                    self.remove(id).await;
+                    recovered += 1;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to recover the compensation of {} {} on {}: {}", saga, saga_id, account_id, e);
+                    sqlx::query("UPDATE compensations SET attempts = attempts + 1, error = $2 WHERE id = $1")
+                        .bind(id)
+                        .bind(e.to_string())
+                        .execute(&self.pool)
+                        .await?;
+                }
+            }
+        }
+        Ok(recovered)
+    }
+}
+
+impl Journaled {
+    async fn undone(self) {
+        self.journal.executed(&self.account_id).await;
+        self.journal.remove(self.id).await
+    }
+}
+
+// Takes the steps a saga kept out of the journal once the event recording them commits.
+pub struct CompensationQuery<A> {
+    pool: Pool<Postgres>,
+    _aggregate: PhantomData<A>,
+}
+
+impl<A> CompensationQuery<A> {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        CompensationQuery { pool, _aggregate: PhantomData }
+    }
+}
+
+#[async_trait]
+impl<A: Aggregate> Query<A> for CompensationQuery<A> {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<A>]) {
+        let Some(last) = events.last() else {
+            return;
+        };
+        let removed = sqlx::query("DELETE FROM compensations WHERE saga = $1 AND saga_id = $2 AND kept AND sequence < $3")
+            .bind(A::aggregate_type())
+            .bind(aggregate_id)
+            .bind(last.sequence as i64)
+            .execute(&self.pool)
+            .await;
+        if let Err(e) = removed {
+            tracing::error!("Failed to remove the compensations of {} {} from the journal: {}", A::aggregate_type(), aggregate_id, e);
+        }
+    }
+}
+
+// The undo of a step, taking its compensation out of the journal once it ran and counting
+// it among the signals of the account. Failures of the undo itself are left to the step,
+// see `crate::dlq`.
+pub fn journaled_undo(journaled: Option<Journaled>, undo: impl Future<Output = ()> + Send + 'static) -> BoxFuture<'static, ()> {
+    // Spawned when the guard drops, in the trace of the step it undoes.
+    let span = tracing::info_span!("compensation.undo");
+    async move {
+        undo.await;
+        if let Some(journaled) = journaled {
+            journaled.undone().await;
+        }
+    }
+    .instrument(span)
+    .boxed()
+}
+
+// Guards a step undone by `undo`, see `journaled_undo`.
+pub fn guard(journaled: Option<Journaled>, undo: BoxFuture<'static, ()>) -> StepGuard {
+    StepGuard { guard: TransactionGuard::new(undo), journaled }
+}
+
+pub struct StepGuard {
+    guard: TransactionGuard<BoxFuture<'static, ()>>,
+    journaled: Option<Journaled>,
+}
+
+impl StepGuard {
+    // Keeps the step, before the saga raises the event recording it; the compensation
+    // stays in the journal until that event commits. The step is undone when the
+    // compensation cannot be marked kept.
+    pub async fn commit(self) -> Result<(), AggregateError<AccountError>> {
+        if let Some(journaled) = &self.journaled {
+            journaled.journal.keep(journaled.id).await?;
+        }
+        self.guard.commit();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use async_trait::async_trait;
+    use cqrs_es::{AggregateError, EventEnvelope, Query};
+    use crate::account::commands::AccountCommand;
+    use crate::account::events::AccountError;
+    use crate::compensation::{guard, journaled_undo, CompensationJournal, CompensationQuery};
+    use crate::services::AccountExecutor;
+    use crate::transfer::aggregate::Transfer;
+    use crate::transfer::events::TransferEvent;
+    use crate::util::test_db::{random_suffix, test_pool};
+    use crate::util::types::ByteArray32;
+
+    // Records the accounts commands ran on, failing the first on each.
+    #[derive(Default)]
+    struct Accounts(Mutex<Vec<String>>);
+
+    #[async_trait]
+    impl AccountExecutor for Accounts {
+        async fn execute(&self, account_id: &str, _command: AccountCommand) -> Result<(), AggregateError<AccountError>> {
+            let mut executed = self.0.lock().unwrap();
+            let first = !executed.iter().any(|executed| executed == account_id);
+            executed.push(account_id.to_string());
+            match first {
+                true => Err(AggregateError::DatabaseConnectionError("gone".into())),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compensation_journal() {
+        let pool = test_pool();
+        let journal = CompensationJournal::new(pool.clone());
+        let suffix = random_suffix();
+        let (settled, recorded, running) = (format!("settled-{}", suffix), format!("recorded-{}", suffix), format!("running-{}", suffix));
+        let undo = AccountCommand::unlock_funds(ByteArray32::derive("test", &suffix), 1);
+        let pending = || sqlx::query_scalar::<_, String>("SELECT account_id FROM compensations WHERE saga = 'transfer' AND saga_id = ANY($1) ORDER BY id")
+            .bind(vec![settled.clone(), recorded.clone(), running.clone()])
+            .fetch_all(&pool);
+
+        // Kept until the saga event commits, undone, and cut short by a crash.
+        let kept = journal.record("transfer", &settled, "SETTLED", &undo).await.unwrap();
+        guard(Some(kept.clone()), journaled_undo(Some(kept), async {})).commit().await.unwrap();
+        let kept = journal.record("transfer", &recorded, "RECORDED", &undo).await.unwrap();
+        guard(Some(kept.clone()), journaled_undo(Some(kept), async {})).commit().await.unwrap();
+        let kept = journal.record("transfer", &running, "UNRECORDED", &undo).await.unwrap();
+        guard(Some(kept.clone()), journaled_undo(Some(kept), async {})).commit().await.unwrap();
+        let undone = journal.record("transfer", &running, "UNDONE", &undo).await.unwrap();
+        drop(guard(Some(undone.clone()), journaled_undo(Some(undone), async {})));
+        journal.record("transfer", &running, "CRASHED", &undo).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(pending().await.unwrap(), vec!["SETTLED", "RECORDED", "UNRECORDED", "CRASHED"]);
+
+        let done = EventEnvelope { aggregate_id: settled.clone(), sequence: 1, payload: TransferEvent::Done { timestamp: 2 }, metadata: HashMap::new() };
+        CompensationQuery::<Transfer>::new(pool.clone()).dispatch(&settled, &[done]).await;
+        assert_eq!(pending().await.unwrap(), vec!["RECORDED", "UNRECORDED", "CRASHED"]);
+
+        // Executed once stale, and again after failing, unless the saga went on past them.
+        sqlx::query("INSERT INTO events (aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata) VALUES ('transfer', $1, 1, 'Done', '1.0', '{}', '{}')")
+            .bind(&recorded)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let accounts = Accounts::default();
+        assert_eq!(journal.recover(&accounts, 3600).await.unwrap(), 0);
+        journal.recover(&accounts, 0).await.unwrap();
+        assert_eq!(pending().await.unwrap(), vec!["UNRECORDED", "CRASHED"]);
+        journal.recover(&accounts, 0).await.unwrap();
+        assert!(pending().await.unwrap().is_empty());
+        let executed = accounts.0.lock().unwrap().clone();
+        assert_eq!(executed, vec!["UNRECORDED", "CRASHED", "UNRECORDED", "CRASHED"]);
+
+        sqlx::query("DELETE FROM events WHERE aggregate_type = 'transfer' AND aggregate_id = $1").bind(&recorded).execute(&pool).await.unwrap();
+    }
+}
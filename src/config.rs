@@ -1,28 +1,1732 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::Arc;
+use std::time::Duration;
 
 use cqrs_es::Query;
 use postgres_es::{PostgresCqrs, PostgresViewRepository};
+use serde::Deserialize;
 use sqlx::{Pool, Postgres};
 
 use crate::account::aggregate::Account;
-use crate::account::queries::{AccountQuery, AccountView};
+use crate::account::batch::MAX_BATCH_SIZE;
+use crate::account::canary::{next_logic, Canary, CanaryMetrics};
+use crate::account::closure::ClosureHook;
+use crate::account::events::DEFAULT_TTL;
+use crate::account::dedup::TxidStore;
+use crate::account::fees::{FeeHook, FeeQuery};
+use crate::account::journal::JournalQuery;
+use crate::account::overdraft::OverdraftLimits;
+use crate::account::ledger::LedgerQuery;
+use crate::account::queries::{AccountQuery, AccountViewRepository};
+use crate::account::stats::{AccountStatsQuery, AccountStatsRepository};
+use crate::account::stream::AccountEventStream;
+use crate::aggregate_cache::AggregateCache;
+use crate::auction::aggregate::{Auction, AuctionServices};
+use crate::auction::engine::AuctionPair;
+use crate::auction::queries::{AuctionQuery, AuctionView};
+use crate::batch_transfer::aggregate::{BatchTransfer, BatchTransferServices};
+use crate::batch_transfer::commands::BatchTransferCommand;
+use crate::batch_transfer::queries::{BatchTransferQuery, BatchTransferView};
+use crate::business_day::PostingDate;
+use crate::command_policy::{CommandPolicy, PolicyViolation, COMMAND_TYPES};
 use crate::order::aggregate::{Order, OrderServices};
+use crate::audit_log::AuditLogQuery;
+use crate::approval::aggregate::{Approval, ApprovalServices, ConfigChanges};
+use crate::approval::queries::{ApprovalQuery, ApprovalView};
+use crate::invalidation::InvalidationHook;
+use crate::metrics::{AggregateCacheMetrics, SagaMetrics};
+use crate::order::book::OrderBookQuery;
+use crate::order::halts::TradingHalts;
+use crate::order::matching::MatchingQuery;
+use crate::order::policy::OrderPolicyService;
 use crate::order::queries::{OrderQuery, OrderView};
-use crate::services::{BankAccountServices, HappyPathBankAccountServices};
+use crate::notification::WebhookNotifier;
+use crate::outbox::{OutboxConfig, OutboxHook};
+use crate::preferences::aggregate::Preferences;
+use crate::preferences::alerts::BalanceAlertQuery;
+use crate::preferences::queries::{PreferencesQuery, PreferencesView};
+use crate::rfq::aggregate::{Rfq, RfqServices};
+use crate::rfq::queries::{RfqQuery, RfqView};
+use crate::standing_order::aggregate::StandingOrder;
+use crate::standing_order::queries::{StandingOrderQuery, StandingOrderView};
+use crate::asset::aggregate::Asset;
+use crate::asset::queries::{AssetQuery, AssetView};
+use crate::account::counterparty::CounterpartyNames;
+use crate::commit_hooks::{hooked_snapshot_cqrs, CommitHook, HookedCqrs, HookedEventRepository};
+use crate::compensation::{CompensationJournal, CompensationQuery};
+use crate::dlq::DeadLetters;
+use crate::retry::RetryingExecutor;
+use crate::services::{BankAccountServices, DedupStore, RegistryBankAccountServices};
+use crate::field_encryption::{encrypted_snapshot_cqrs, EncryptedCqrs, FieldCipher};
+use crate::sealing::{sealed_snapshot_cqrs, SealedCqrs, SealedViewRepository, Sealer};
+use crate::sla::{RoutePolicy, RoutePriority, SlaConfig};
 use crate::transfer::aggregate::{Transfer, TransferServices};
-use crate::transfer::queries::{TransferQuery, TransferView};
+use crate::transfer::commands::TransferCommand;
+use crate::transfer::queries::{RefundLinkQuery, TransferQuery, TransferView};
+use crate::webhooks::{LifecycleWebhookQuery, WebhookRegistry};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Cannot read {0}: {1}")]
+    Io(String, std::io::Error),
+    #[error("Invalid config file {0}: {1}")]
+    Toml(String, toml::de::Error),
+    #[error("Invalid {0}: {1:?}")]
+    Env(String, String),
+    #[error("Invalid config: {0}")]
+    Invalid(String),
+}
+
+// Typed application settings, read from the TOML file named by `APP_CONFIG` (if any)
+// and then overridden by environment variables, e.g.
+//
+// ```toml
+// [snapshots]
+// default_interval = 100
+// intervals = { account = 20, auction = 500 }
+//
+// [database]
+// max_connections = 10
+//
+// [batching]
+// enabled = true
+// queue_capacity = 1024
+// max_batch = 1024
+//
+// [archive]
+// keep_events = 0
+//
+// [sandbox]
+// enabled = true
+// schema = "sandbox"
+//
+// [transfer]
+// timeout_secs = 900
+//
+// [duplicate_detection]
+// window_secs = 2592000
+// tenants = { "ACME-" = 86400 }
+// max_txids = 100000
+// tenant_max_txids = { "ACME-" = 10000 }
+// store = "table"
+//
+// [cache_invalidation]
+// enabled = true
+//
+// [fees]
+// collection_account = "FEES"
+// withdraw = { BTC = { flat = 1000 } }
+// settle = { USDT = { bps = 10 } }
+//
+// [payout]
+// account = "PAYOUTS"
+//
+// [accruals]
+// interest_bps = { USDT = 150 }
+// daily_fee = { BTC = 10 }
+//
+// [error_alerts]
+// threshold = 20
+// window_secs = 60
+// webhook_url = "https://alerts.example.com/hooks/cqrs"
+//
+// [auth]
+// api_keys = ["change-me"]
+// jwt_secret = "change-me-too"
+//
+// [sealing]
+// integrity_key = "<64 hex digits>"
+// encryption_key = "<64 hex digits>"
+//
+// [field_encryption]
+// keys = { k1 = "<64 hex digits>", k2 = "<64 hex digits>" }
+// active = "k2"
+//
+// [bulk]
+// concurrency = 8
+//
+// [approvals]
+// required = true
+//
+// [trading_halts]
+// pairs = { "BTC-USDT" = { reason = "Exchange incident", resume_at = 1767225600 } }
+//
+// [order_recovery]
+// sweep_interval_secs = 60
+// stranded_match_secs = 60
+//
+// [order_expiry]
+// sweep_interval_secs = 30
+//
+// [saga_watchdog]
+// stall_secs = 300
+// max_attempts = 5
+// webhook_url = "https://alerts.example.com/hooks/cqrs"
+//
+// [standing_orders]
+// sweep_interval_secs = 30
+//
+// [inbox]
+// sweep_interval_secs = 5
+// max_batch_len = 100
+// stall_secs = 300
+//
+// [account_closure]
+// grace_secs = 2592000
+// sweep_interval_secs = 60
+//
+// [audit]
+// interval_secs = 300
+// grace_secs = 60
+//
+// [integrity]
+// interval_secs = 600
+// grace_secs = 300
+//
+// [view_audit]
+// on_startup = true
+// sample = 1000
+// repair = false
+//
+// [quarantine]
+// enabled = true
+// verified_sources = ["bank-wire", "custody"]
+//
+// [command_policy]
+// max_amounts = { deposit = 1000000000000, transfer = 100000000000 }
+// max_memo_bytes = 1024
+// tenants = { "ACME-" = { max_amounts = { deposit = 1000000 } } }
+//
+// [replay]
+// rows_per_sec = 5000
+// urgent_rows_per_sec = 50000
+// off_peak_hours = [{ start_hour = 22, end_hour = 6 }]
+//
+// [server]
+// http2 = true
+// max_connections = 10000
+// http2_keep_alive_interval_secs = 20
+//
+// [bulkheads]
+// queue_timeout_ms = 1000
+// limits = { order = { concurrency = 32, queue_depth = 64 }, account = { concurrency = 256, queue_depth = 1024 } }
+//
+// [rate_limits]
+// global = { per_sec = 2000.0, burst = 4000 }
+// account = { per_sec = 10.0, burst = 50 }
+//
+// [quotas]
+// events_per_day = 100000
+// storage_bytes = 1073741824
+// tenants = { "ACME-" = { events_per_day = 5000000 } }
+// enforcement = "throttle"
+//
+// [routing]
+// region = "eu"
+// secret = "shared-by-all-regions"
+// accounts = { "ACME-US-" = "us" }
+// assets = { "USD" = "us" }
+// hashed = true
+//
+// [routing.regions]
+// eu = ["http://eu-1.internal:3030"]
+// us = ["http://us-1.internal:3030", "http://us-2.internal:3030"]
+//
+// [aggregate_cache]
+// capacity = 50000
+//
+// [shards]
+// workers = 32
+//
+// [outbound.default]
+// timeout_ms = 5000
+// attempts = 3
+//
+// [outbound.destinations.alerts]
+// attempts = 5
+// breaker_threshold = 0
+//
+// [conflict_retry]
+// attempts = 5
+// backoff_ms = 20
+//
+// [compensations]
+// stale_after_secs = 120
+//
+// [canary]
+// percent = 5
+// mode = "shadow"
+//
+// [event_store]
+// backend = "sqlite"
+// sqlite_url = "sqlite:///var/lib/cqrs-account/events.db"
+//
+// [business_day]
+// enabled = true
+// cutoff = "17:30"
+// max_backdate_days = 3
+//
+// [telemetry]
+// otlp_endpoint = "http://otel-collector:4318/v1/traces"
+// sample_ratio = 0.1
+// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    pub snapshots: SnapshotConfig,
+    pub database: DatabaseConfig,
+    pub batching: BatchingConfig,
+    pub archive: ArchiveConfig,
+    pub sandbox: SandboxConfig,
+    pub transfer: TransferConfig,
+    pub duplicate_detection: DuplicateDetectionConfig,
+    pub cache_invalidation: CacheInvalidationConfig,
+    pub fees: FeeConfig,
+    pub payout: PayoutConfig,
+    pub accruals: AccrualConfig,
+    pub error_alerts: ErrorAlertConfig,
+    pub auth: AuthConfig,
+    pub sealing: SealingConfig,
+    pub field_encryption: FieldEncryptionConfig,
+    pub bulk: BulkConfig,
+    pub approvals: ApprovalConfig,
+    pub trading_halts: TradingHaltConfig,
+    pub order_recovery: OrderRecoveryConfig,
+    pub order_expiry: OrderExpiryConfig,
+    pub saga_watchdog: SagaWatchdogConfig,
+    pub standing_orders: StandingOrderConfig,
+    pub inbox: InboxConfig,
+    pub account_closure: AccountClosureConfig,
+    pub audit: AuditConfig,
+    pub integrity: IntegrityConfig,
+    pub view_audit: ViewAuditConfig,
+    pub quarantine: QuarantineConfig,
+    pub command_policy: CommandPolicyConfig,
+    pub replay: ReplayConfig,
+    pub server: ServerConfig,
+    pub event_store: EventStoreConfig,
+    pub bulkheads: BulkheadConfig,
+    pub business_day: BusinessDayConfig,
+    pub rate_limits: RateLimitConfig,
+    pub quotas: QuotaConfig,
+    pub routing: RoutingConfig,
+    pub aggregate_cache: AggregateCacheConfig,
+    pub shards: ShardConfig,
+    pub outbound: OutboundConfig,
+    pub conflict_retry: ConflictRetryConfig,
+    pub compensations: CompensationConfig,
+    pub canary: CanaryConfig,
+    pub telemetry: TelemetryConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotConfig {
+    // Number of events between two snapshots of an aggregate.
+    pub default_interval: usize,
+    // Per aggregate type overrides, e.g. busy accounts snapshot more often.
+    pub intervals: HashMap<String, usize>,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        SnapshotConfig { default_interval: 100, intervals: HashMap::new() }
+    }
+}
+
+impl SnapshotConfig {
+    pub fn interval(&self, aggregate_type: &str) -> usize {
+        self.intervals.get(aggregate_type).copied().unwrap_or(self.default_interval)
+    }
+}
+
+// The connection pool to Postgres, shared by the event stores, the projections and the
+// background workers. `tune` measures what a pool size is worth under load.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub max_connections: u32,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig { max_connections: 10 }
+    }
+}
+
+// Commits the account and order event stores queue before commands wait, and write at
+// most in one transaction once `enabled`, see `HookedEventRepository::with_batching`;
+// without it every command commits on its own. The batched store of `crate::simple`
+// always batches.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchingConfig {
+    pub enabled: bool,
+    pub queue_capacity: usize,
+    pub max_batch: usize,
+}
+
+impl Default for BatchingConfig {
+    fn default() -> Self {
+        BatchingConfig { enabled: false, queue_capacity: 1024, max_batch: 1024 }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArchiveConfig {
+    // Events right before the snapshot that stay in the hot table anyway, so recent
+    // history can still be read without going to the archive.
+    pub keep_events: u64,
+}
+
+// Sandbox mode for integrators: all data lives in its own Postgres schema so it can be
+// wiped in one call, the faucet mints test deposits and the clock can be fast-forwarded.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SandboxConfig {
+    pub enabled: bool,
+    pub schema: String,
+    // Largest amount a single faucet call may mint.
+    pub faucet_limit: u64,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        SandboxConfig { enabled: false, schema: "sandbox".to_string(), faucet_limit: 1_000_000_000 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransferConfig {
+    // Seconds an opened transfer has to complete before it is expired and reversed.
+    pub timeout_secs: u64,
+    // How often the expiry sweeper looks for timed out transfers.
+    pub sweep_interval_secs: u64,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        TransferConfig { timeout_secs: 900, sweep_interval_secs: 30 }
+    }
+}
+
+// How long accounts remember a txid to reject it when resent, and how many txids they
+// remember at most; past that the oldest ones are evicted even within the window. Tenants
+// are keyed by account id prefix and the longest matching prefix wins. Both are fixed into
+// the account when it is opened; changing them only affects accounts opened afterwards.
+// With the `table` store, accounts opened afterwards keep the txids of the last
+// `recent_secs` only and look older ones up in the `account_txids` table, see
+// `crate::account::dedup`; the window then applies as configured when a command is checked.
+// The embedded event store keeps them in the accounts regardless.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DuplicateDetectionConfig {
+    pub window_secs: u64,
+    pub tenants: HashMap<String, u64>,
+    // Unbounded when unset.
+    pub max_txids: Option<u64>,
+    pub tenant_max_txids: HashMap<String, u64>,
+    pub store: DuplicateStore,
+    // Covers the txids committed but not yet in the table.
+    pub recent_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateStore {
+    // In the state of the account, and so in its snapshots.
+    #[default]
+    Aggregate,
+    Table,
+}
+
+impl std::str::FromStr for DuplicateStore {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aggregate" => Ok(DuplicateStore::Aggregate),
+            "table" => Ok(DuplicateStore::Table),
+            other => Err(format!("unknown duplicate store {}", other)),
+        }
+    }
+}
+
+impl Default for DuplicateDetectionConfig {
+    fn default() -> Self {
+        DuplicateDetectionConfig {
+            window_secs: DEFAULT_TTL,
+            tenants: HashMap::new(),
+            max_txids: None,
+            tenant_max_txids: HashMap::new(),
+            store: DuplicateStore::Aggregate,
+            recent_secs: 300,
+        }
+    }
+}
+
+fn by_tenant(tenants: &HashMap<String, u64>, account_id: &str) -> Option<u64> {
+    tenants
+        .iter()
+        .filter(|(prefix, _)| account_id.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, value)| *value)
+}
+
+impl DuplicateDetectionConfig {
+    pub fn window_secs(&self, account_id: &str) -> u64 {
+        by_tenant(&self.tenants, account_id).unwrap_or(self.window_secs)
+    }
+
+    pub fn max_txids(&self, account_id: &str) -> Option<u64> {
+        by_tenant(&self.tenant_max_txids, account_id).or(self.max_txids)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeeOperation {
+    Withdraw,
+    Settle,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeRate {
+    pub flat: u64,
+    // Basis points of the amount, rounded down.
+    pub bps: u64,
+}
+
+// Fees per operation and asset, charged on withdrawals (in the withdrawn asset) and
+// settlements (in the received asset). Nothing is charged without a collection account.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeConfig {
+    pub collection_account: String,
+    pub withdraw: HashMap<String, FeeRate>,
+    pub settle: HashMap<String, FeeRate>,
+}
+
+impl FeeConfig {
+    // The collection account and the fee owed by `account_id`, if any. The collection
+    // account itself is never charged.
+    pub fn fee(&self, account_id: &str, operation: FeeOperation, asset: &str, amount: u64) -> Option<(String, u64)> {
+        if self.collection_account.is_empty() || self.collection_account == account_id {
+            return None;
+        }
+        let schedule = match operation {
+            FeeOperation::Withdraw => &self.withdraw,
+            FeeOperation::Settle => &self.settle,
+        };
+        let rate = schedule.get(asset)?;
+        let proportional = (amount as u128 * rate.bps as u128 / 10_000) as u64;
+        let fee = rate.flat.saturating_add(proportional);
+        (fee > 0).then(|| (self.collection_account.clone(), fee))
+    }
+}
+
+// Payout batches, see `crate::payout`. The negative rows of a batch claw money back into
+// `account`, and are rejected without one.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PayoutConfig {
+    pub account: String,
+}
+
+// Contractual interest and periodic fees by asset, see `crate::account::accruals`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccrualConfig {
+    // Simple interest on the funds held, in basis points a year.
+    pub interest_bps: HashMap<String, u64>,
+    // Owed for every day the asset is held.
+    pub daily_fee: HashMap<String, u64>,
+}
+
+// Multi-node deployments publish every committed account and order change on a Postgres
+// NOTIFY channel so the caches of all nodes can drop stale entries, see `crate::invalidation`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheInvalidationConfig {
+    pub enabled: bool,
+    pub channel: String,
+}
+
+impl Default for CacheInvalidationConfig {
+    fn default() -> Self {
+        CacheInvalidationConfig { enabled: false, channel: "cqrs_invalidation".to_string() }
+    }
+}
+
+// Alerts when more than `threshold` framework errors (conflicts, database and
+// deserialization failures) hit commands within `window_secs`, see `crate::metrics`.
+// The alert is logged and, when `webhook_url` is set, posted there; 0 disables it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ErrorAlertConfig {
+    pub threshold: usize,
+    pub window_secs: u64,
+    pub webhook_url: String,
+}
+
+impl Default for ErrorAlertConfig {
+    fn default() -> Self {
+        ErrorAlertConfig { threshold: 0, window_secs: 60, webhook_url: String::new() }
+    }
+}
+
+// Credentials accepted on the admin routes, see `crate::auth`: any of the API keys, or an
+// HS256 JWT signed with `jwt_secret` whose `roles` claim holds `admin_role`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub api_keys: Vec<String>,
+    pub jwt_secret: String,
+    pub admin_role: String,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig { api_keys: Vec::new(), jwt_secret: String::new(), admin_role: "admin".to_string() }
+    }
+}
+
+// Keys sealing account snapshots and views, see `crate::sealing`. Without an integrity
+// key they are stored in plain JSON; the encryption key is optional on top of it.
+// `accept_unsealed` lets payloads written before sealing was enabled load until they
+// are next updated.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SealingConfig {
+    pub integrity_key: String,
+    pub encryption_key: String,
+    pub accept_unsealed: bool,
+}
+
+// Keys encrypting the personal data in transfer events, see `crate::field_encryption`,
+// by key id. New events are encrypted under `active`, the other keys decrypt what was
+// written under them until the `rewrap-field-keys` binary moved it onto the active one.
+// Without keys events are stored in plain JSON; without an active key new ones are.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FieldEncryptionConfig {
+    pub keys: BTreeMap<String, String>,
+    pub active: String,
+}
+
+// Pace of the bulk account operations, see `crate::account::bulk`: how many commands run
+// at once, and how many accounts are read from the view table per page. Asset migrations
+// keep to it too, see `crate::asset::migration`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BulkConfig {
+    pub concurrency: usize,
+    pub page_size: usize,
+}
+
+impl Default for BulkConfig {
+    fn default() -> Self {
+        BulkConfig { concurrency: 8, page_size: 500 }
+    }
+}
+
+// Maker-checker for configuration changes, see `crate::approval`. Once `required`, account
+// lifecycle commands other than opening, overdraft limits and trading rules are only
+// taken as proposals under `/admin/approval`, approved by a second operator.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApprovalConfig {
+    pub required: bool,
+}
+
+// Pairs halted from startup, see `crate::order::halts`, on top of the ones halted under
+// `/admin/halts`. `recheck_secs` is how often the matcher retries orders it held back
+// while their pair was halted.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TradingHaltConfig {
+    pub pairs: BTreeMap<String, ConfiguredHalt>,
+    pub recheck_secs: u64,
+}
+
+impl Default for TradingHaltConfig {
+    fn default() -> Self {
+        TradingHaltConfig { pairs: BTreeMap::new(), recheck_secs: 5 }
+    }
+}
+
+// Without `resume_at` the pair stays halted until the configuration changes.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfiguredHalt {
+    pub reason: String,
+    pub resume_at: Option<u64>,
+}
+
+// See `crate::order::recovery`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OrderRecoveryConfig {
+    // How often failed and cancelled orders are checked for funds they left locked.
+    pub sweep_interval_secs: u64,
+    // How long a book order may stay reserved by a match that never finished, e.g. when
+    // the server went down mid-match, before the matcher puts it back up.
+    pub stranded_match_secs: u64,
+}
+
+impl Default for OrderRecoveryConfig {
+    fn default() -> Self {
+        OrderRecoveryConfig { sweep_interval_secs: 60, stranded_match_secs: 60 }
+    }
+}
+
+// See `crate::order::expiry`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OrderExpiryConfig {
+    // How often placed orders are checked against their `expires_at`.
+    pub sweep_interval_secs: u64,
+}
+
+impl Default for OrderExpiryConfig {
+    fn default() -> Self {
+        OrderExpiryConfig { sweep_interval_secs: 30 }
+    }
+}
+
+// See `crate::watchdog`: every `sweep_interval_secs` the sagas unchanged for `stall_secs`
+// are sent `Continue`, for up to `max_attempts` sweeps. Then they are alerted on, logged
+// and, when `webhook_url` is set, posted there. 0 `stall_secs` disables the watchdog.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SagaWatchdogConfig {
+    pub sweep_interval_secs: u64,
+    pub stall_secs: u64,
+    pub max_attempts: u32,
+    pub webhook_url: String,
+}
+
+impl Default for SagaWatchdogConfig {
+    fn default() -> Self {
+        SagaWatchdogConfig { sweep_interval_secs: 60, stall_secs: 300, max_attempts: 5, webhook_url: String::new() }
+    }
+}
+
+// See `crate::standing_order::scheduler`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StandingOrderConfig {
+    // How often standing orders are checked for an occurrence that came due.
+    pub sweep_interval_secs: u64,
+}
+
+impl Default for StandingOrderConfig {
+    fn default() -> Self {
+        StandingOrderConfig { sweep_interval_secs: 30 }
+    }
+}
+
+// See `crate::inbox`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InboxConfig {
+    // How often the inbox is checked for commands that came due.
+    pub sweep_interval_secs: u64,
+    // Commands a client may queue in one batch.
+    pub max_batch_len: usize,
+    // A command running for longer was left by a node that stopped, and is run again.
+    pub stall_secs: u64,
+}
+
+impl Default for InboxConfig {
+    fn default() -> Self {
+        InboxConfig { sweep_interval_secs: 5, max_batch_len: 100, stall_secs: 300 }
+    }
+}
+
+// See `crate::account::closure`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountClosureConfig {
+    // How long an account asked to be closed stays open for withdrawals.
+    pub grace_secs: u64,
+    // How often accounts are checked for a grace period that ran out.
+    pub sweep_interval_secs: u64,
+}
+
+impl Default for AccountClosureConfig {
+    fn default() -> Self {
+        AccountClosureConfig { grace_secs: 30 * 86400, sweep_interval_secs: 60 }
+    }
+}
+
+// See `crate::view_audit`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ViewAuditConfig {
+    // Audit the account views once at boot, besides on request.
+    pub on_startup: bool,
+    // Accounts picked at random per audit, 0 audits them all.
+    pub sample: u64,
+    // Rewrite the views found to differ from their replayed events.
+    pub repair: bool,
+}
+
+// Span export, see `crate::telemetry`. Traces are only logged without an `otlp_endpoint`,
+// the full URL of the OTLP/HTTP traces endpoint of a collector.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+    // Of the traces started here; traces continued from a caller follow its decision.
+    pub sample_ratio: f64,
+    // Of each export.
+    pub timeout_ms: u64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            otlp_endpoint: None,
+            service_name: "cqrs-account".to_string(),
+            sample_ratio: 1.0,
+            timeout_ms: 10000,
+        }
+    }
+}
+
+// See `crate::audit`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    // How often the invariants are checked.
+    pub interval_secs: u64,
+    // Transactions younger than this may still be in flight and are not checked yet.
+    pub grace_secs: u64,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig { interval_secs: 300, grace_secs: 60 }
+    }
+}
+
+// See `crate::order::integrity`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IntegrityConfig {
+    // How often the orders and the locks held for them are checked against each other.
+    pub interval_secs: u64,
+    // Orders and locks changed more recently may be halfway through their saga.
+    pub grace_secs: u64,
+}
+
+impl Default for IntegrityConfig {
+    fn default() -> Self {
+        IntegrityConfig { interval_secs: 600, grace_secs: 300 }
+    }
+}
+
+// Deposits from a source that is not verified are held apart from the balance until an
+// operator releases them. A deposit naming no source is not verified either, see
+// `TransactionCommand::Deposit`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuarantineConfig {
+    pub enabled: bool,
+    pub verified_sources: BTreeSet<String>,
+}
+
+impl QuarantineConfig {
+    pub fn quarantines(&self, source: Option<&str>) -> bool {
+        self.enabled && !source.is_some_and(|source| self.verified_sources.contains(source))
+    }
+}
+
+// Sanity limits on the commands moving funds, checked by the account, transfer and batch
+// transfer aggregates so an amount off by orders of magnitude is rejected whichever route
+// or saga sent it. Amounts are limited in minor units per command type, see
+// `crate::command_policy::COMMAND_TYPES`; other keys fail the config. Types without a
+// limit take any amount. Memos are the labels of a transaction and the
+// descriptions of transfers, batches are account command batches and the legs of batch
+// transfers. Tenants are keyed by account id prefix, the longest matching
+// prefix wins and overrides the limits it sets.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommandPolicyConfig {
+    pub max_amounts: HashMap<String, u64>,
+    pub max_memo_bytes: usize,
+    pub max_batch_len: usize,
+    pub tenants: HashMap<String, TenantCommandPolicy>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantCommandPolicy {
+    pub max_amounts: HashMap<String, u64>,
+    pub max_memo_bytes: Option<usize>,
+    pub max_batch_len: Option<usize>,
+}
+
+impl Default for CommandPolicyConfig {
+    fn default() -> Self {
+        CommandPolicyConfig { max_amounts: HashMap::new(), max_memo_bytes: 4096, max_batch_len: MAX_BATCH_SIZE, tenants: HashMap::new() }
+    }
+}
+
+impl CommandPolicyConfig {
+    // Without tenant overrides, for what is not done on behalf of one account.
+    pub fn defaults(&self) -> CommandPolicy {
+        CommandPolicy {
+            max_amounts: self.max_amounts.clone(),
+            max_memo_bytes: self.max_memo_bytes,
+            max_batch_len: self.max_batch_len,
+        }
+    }
+
+    pub fn policy(&self, account_id: &str) -> CommandPolicy {
+        let mut policy = self.defaults();
+        let tenant = self.tenants
+            .iter()
+            .filter(|(prefix, _)| account_id.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, tenant)| tenant);
+        if let Some(tenant) = tenant {
+            policy.max_amounts.extend(tenant.max_amounts.clone());
+            policy.max_memo_bytes = tenant.max_memo_bytes.unwrap_or(policy.max_memo_bytes);
+            policy.max_batch_len = tenant.max_batch_len.unwrap_or(policy.max_batch_len);
+        }
+        policy
+    }
+
+    // The opening of a transfer or swap, each leg held to the policy of the account
+    // funding it.
+    pub fn check_transfer(&self, command: &TransferCommand) -> Result<(), PolicyViolation> {
+        match command {
+            TransferCommand::Open { from_account, amount, description, .. } => {
+                let policy = self.policy(from_account);
+                policy.check_memo(description.len())?;
+                policy.check_amount("transfer", *amount)
+            }
+            TransferCommand::SwapOpen { from_account, to_account, amount, counter_amount, description, .. } => {
+                let policy = self.policy(from_account);
+                policy.check_memo(description.len())?;
+                policy.check_amount("transfer", *amount)?;
+                self.policy(to_account).check_amount("transfer", *counter_amount)
+            }
+            TransferCommand::Refund { .. } | TransferCommand::Continue | TransferCommand::Cancel { .. } | TransferCommand::Expire | TransferCommand::Retry => Ok(()),
+        }
+    }
+
+    // The length and description of a batch, which spans tenants, by the defaults and
+    // the amount of each leg by the policy of its source account.
+    pub fn check_batch_transfer(&self, command: &BatchTransferCommand) -> Result<(), PolicyViolation> {
+        match command {
+            BatchTransferCommand::Open { legs, description, .. } => {
+                let defaults = self.defaults();
+                defaults.check_batch(legs.len())?;
+                defaults.check_memo(description.len())?;
+                legs.iter().try_for_each(|leg| self.policy(&leg.from_account).check_amount("batch_transfer", leg.amount))
+            }
+            BatchTransferCommand::Continue | BatchTransferCommand::Cancel { .. } => Ok(()),
+        }
+    }
+
+    fn unknown_key(&self) -> Option<&str> {
+        self.max_amounts
+            .keys()
+            .chain(self.tenants.values().flat_map(|tenant| tenant.max_amounts.keys()))
+            .find(|key| !COMMAND_TYPES.contains(&key.as_str()))
+            .map(String::as_str)
+    }
+}
+
+// Paces the bulk replays, projection rebuilds, archiving and the compaction verifier,
+// see `crate::replay`. Rates are in events or rows per second, 0 for no limit. Background
+// replays only run within the off-peak hours, UTC and wrapping past midnight, or at any
+// time without any; urgent replays ignore them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplayConfig {
+    pub rows_per_sec: u64,
+    pub urgent_rows_per_sec: u64,
+    pub off_peak_hours: Vec<OffPeakWindow>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OffPeakWindow {
+    pub start_hour: u32,
+    // Exclusive.
+    pub end_hour: u32,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        ReplayConfig { rows_per_sec: 1000, urgent_rows_per_sec: 0, off_peak_hours: vec![] }
+    }
+}
+
+impl ReplayConfig {
+    pub fn is_off_peak(&self, hour: u32) -> bool {
+        self.off_peak_hours.is_empty() || self.off_peak_hours.iter().any(|window| match window.start_hour <= window.end_hour {
+            true => (window.start_hour..window.end_hour).contains(&hour),
+            false => hour >= window.start_hour || hour < window.end_hour,
+        })
+    }
+}
+
+// Connection handling of the HTTP server, see `crate::server`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    // Serves HTTP/2 next to HTTP/1.1, over cleartext with prior knowledge, as a TLS
+    // terminating proxy or a load balancer in front of the server speaks it.
+    pub http2: bool,
+    // Keeps HTTP/1.1 connections open between requests.
+    pub keep_alive: bool,
+    // Connections served at once, 0 for no limit. Further ones wait in the listen backlog.
+    pub max_connections: usize,
+    // Requests in flight on one HTTP/2 connection.
+    pub max_concurrent_streams: u32,
+    // Pings idle HTTP/2 connections so dead peers are dropped, 0 for never.
+    pub http2_keep_alive_interval_secs: u64,
+    pub http2_keep_alive_timeout_secs: u64,
+    pub tcp_nodelay: bool,
+    // Given to the requests in flight on shutdown before their connections are closed,
+    // then to the background workers to finish their round.
+    pub shutdown_timeout_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            http2: true,
+            keep_alive: true,
+            max_connections: 0,
+            max_concurrent_streams: 200,
+            http2_keep_alive_interval_secs: 0,
+            http2_keep_alive_timeout_secs: 20,
+            tcp_nodelay: true,
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventStoreBackend {
+    #[default]
+    Postgres,
+    // Needs the `sqlite` feature, see `crate::sqlite`.
+    Sqlite,
+}
+
+impl std::str::FromStr for EventStoreBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "postgres" => Ok(EventStoreBackend::Postgres),
+            "sqlite" => Ok(EventStoreBackend::Sqlite),
+            other => Err(format!("unknown event store backend {}", other)),
+        }
+    }
+}
+
+// Where the events are kept. The HTTP server needs Postgres, its ledger, journal, outbox
+// and idempotency keys live next to the events; SQLite is for embedding the account,
+// transfer and order aggregates in a process of their own, e.g. on an edge device.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventStoreConfig {
+    pub backend: EventStoreBackend,
+    // Created when missing.
+    pub sqlite_url: String,
+}
+
+impl Default for EventStoreConfig {
+    fn default() -> Self {
+        EventStoreConfig { backend: EventStoreBackend::Postgres, sqlite_url: "sqlite://cqrs-account.db".to_string() }
+    }
+}
+
+// Commands of an aggregate type run at most `concurrency` at a time, with up to
+// `queue_depth` more waiting up to `queue_timeout_ms` for a turn, see `crate::bulkhead`.
+// Aggregate types without limits, or with a concurrency of 0, are not held back; the
+// defaults only limit the trading aggregates, so an order storm leaves the database to
+// deposits and withdrawals.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BulkheadConfig {
+    // By aggregate type, e.g. `order`. Replaces the defaults as a whole when set.
+    pub limits: HashMap<String, BulkheadLimits>,
+    pub queue_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BulkheadLimits {
+    pub concurrency: usize,
+    pub queue_depth: usize,
+}
+
+impl Default for BulkheadConfig {
+    fn default() -> Self {
+        let limits = [("order", 64, 256), ("rfq", 32, 128), ("auction", 32, 128), ("batch_transfer", 16, 64)]
+            .into_iter()
+            .map(|(aggregate_type, concurrency, queue_depth)| (aggregate_type.to_string(), BulkheadLimits { concurrency, queue_depth }))
+            .collect();
+        BulkheadConfig { limits, queue_timeout_ms: 1000 }
+    }
+}
+
+// Business days end at the cut-off, UTC, e.g. `"17:00"` or `"24:00"` for calendar days;
+// postings from then on are booked on the next business day, see `crate::business_day`.
+// Weekends are no business days unless `skip_weekends` is off. Closed days get their
+// checkpoint once the postings from before the cut-off had `grace_secs` to reach the ledger.
+// Operators may value date deposits and withdrawals up to `max_backdate_days` calendar days
+// before the posting date, or before today while business days are not kept.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BusinessDayConfig {
+    pub enabled: bool,
+    pub cutoff: String,
+    pub skip_weekends: bool,
+    pub grace_secs: u64,
+    pub max_backdate_days: u32,
+}
+
+impl Default for BusinessDayConfig {
+    fn default() -> Self {
+        BusinessDayConfig { enabled: false, cutoff: "17:00".to_string(), skip_weekends: true, grace_secs: 60, max_backdate_days: 5 }
+    }
+}
+
+// Token buckets in front of the command endpoints, see `crate::rate_limit`: `global` is
+// shared by every command, `account` is kept per account the command is sent to. Either
+// is off while unset. Account buckets left alone for `idle_secs` are refilled anyway, so
+// they are forgotten.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub global: Option<RateLimit>,
+    pub account: Option<RateLimit>,
+    pub idle_secs: u64,
+}
+
+// Commands are let through at `per_sec` on average, up to `burst` at once.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub per_sec: f64,
+    pub burst: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig { global: None, account: None, idle_secs: 300 }
+    }
+}
+
+// Caps on what accounts write to the event store, see `crate::quota`: events committed
+// per UTC day and bytes of event payload kept, 0 for no cap. Tenants are keyed by account
+// id prefix, the longest matching prefix wins, and are capped on the sum of their
+// accounts. Usage is counted from the events as they are committed and read again after
+// `refresh_secs`, other nodes' commands are seen with that delay.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    pub events_per_day: u64,
+    pub storage_bytes: u64,
+    pub tenants: HashMap<String, TenantQuota>,
+    pub enforcement: QuotaEnforcement,
+    // Commands let through to an account over quota while throttled.
+    pub throttle_interval_ms: u64,
+    pub refresh_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantQuota {
+    pub events_per_day: u64,
+    pub storage_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaEnforcement {
+    // Commands to an account over quota are turned away.
+    #[default]
+    Reject,
+    // One command per `throttle_interval_ms` goes through, for clients to wind down.
+    Throttle,
+}
+
+impl std::str::FromStr for QuotaEnforcement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(QuotaEnforcement::Reject),
+            "throttle" => Ok(QuotaEnforcement::Throttle),
+            other => Err(format!("unknown quota enforcement {}", other)),
+        }
+    }
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        QuotaConfig {
+            events_per_day: 0,
+            storage_bytes: 0,
+            tenants: HashMap::new(),
+            enforcement: QuotaEnforcement::Reject,
+            throttle_interval_ms: 1000,
+            refresh_secs: 10,
+        }
+    }
+}
+
+impl QuotaConfig {
+    // The tenant of an account and its quota.
+    pub fn tenant(&self, account_id: &str) -> Option<(&str, TenantQuota)> {
+        self.tenants
+            .iter()
+            .filter(|(prefix, _)| account_id.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(prefix, quota)| (prefix.as_str(), *quota))
+    }
+}
+
+// Deployments running one book per region or per asset class side by side, see
+// `crate::routing`: requests to an account or an asset another region owns are forwarded
+// to one of its endpoints. Accounts belong to the region of their longest matching id
+// prefix, assets to the one listed for their symbol, and the rest to `region`, unless
+// `hashed` spreads them over all regions by rendezvous hashing. Off without a `region`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingConfig {
+    // The region this node serves.
+    pub region: String,
+    // Base URLs of the nodes of each region.
+    pub regions: HashMap<String, Vec<String>>,
+    pub accounts: HashMap<String, String>,
+    pub assets: HashMap<String, String>,
+    pub hashed: bool,
+    // Of a forwarded request, its one attempt.
+    pub timeout_ms: u64,
+    // Shared by the regions to sign the requests they forward each other, required once
+    // `regions` lists any.
+    pub secret: String,
+}
+
+impl Default for RoutingConfig {
+    fn default() -> Self {
+        RoutingConfig {
+            region: String::new(),
+            regions: HashMap::new(),
+            accounts: HashMap::new(),
+            assets: HashMap::new(),
+            hashed: false,
+            timeout_ms: 5000,
+            secret: String::new(),
+        }
+    }
+}
+
+// Accounts kept hydrated between their commands, see `crate::aggregate_cache`; the least
+// recently used ones are dropped past `capacity`, 0 turns the cache off.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AggregateCacheConfig {
+    pub capacity: usize,
+}
+
+impl Default for AggregateCacheConfig {
+    fn default() -> Self {
+        AggregateCacheConfig { capacity: 10_000 }
+    }
+}
+
+// The workers account commands are executed on, see `crate::sharding`; each takes up to
+// `queue_depth` commands before callers wait for room. 0 workers executes the commands on
+// the tasks issuing them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShardConfig {
+    pub workers: usize,
+    pub queue_depth: usize,
+}
+
+impl Default for ShardConfig {
+    fn default() -> Self {
+        ShardConfig { workers: 16, queue_depth: 256 }
+    }
+}
+
+// How commands losing a race to another command on the same aggregate are retried, see
+// `crate::retry`. Attempts include the first one, 1 hands the conflict to the caller.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConflictRetryConfig {
+    pub attempts: u32,
+    // Wait before the second attempt, doubled for every attempt after up to `max_backoff_ms`.
+    // Each wait is jittered down to half of it, so racing commands spread out.
+    pub backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for ConflictRetryConfig {
+    fn default() -> Self {
+        ConflictRetryConfig { attempts: 3, backoff_ms: 10, max_backoff_ms: 200 }
+    }
+}
+
+// See `crate::compensation`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompensationConfig {
+    // Compensations journaled longer ago than this are taken for those of a saga cut short
+    // by a crash and executed. Sagas still running must have finished their steps by then.
+    pub stale_after_secs: u64,
+    pub sweep_interval_secs: u64,
+}
+
+impl Default for CompensationConfig {
+    fn default() -> Self {
+        CompensationConfig { stale_after_secs: 60, sweep_interval_secs: 60 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CanaryMode {
+    // The stable logic decides, the next one runs alongside for comparison.
+    #[default]
+    Shadow,
+    // The next logic decides for the routed accounts.
+    Live,
+}
+
+// The share of accounts routed to the account logic under rollout, see
+// `crate::account::canary`. 0 routes none.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CanaryConfig {
+    pub percent: u8,
+    pub mode: CanaryMode,
+}
+
+// How the HTTP calls out of the system are retried, timed out and cut off, see
+// `crate::services::outbound`. Destinations without a policy of their own, e.g.
+// `webhooks` or `alerts`, use the default one.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboundConfig {
+    pub default: OutboundPolicy,
+    pub destinations: HashMap<String, OutboundPolicy>,
+}
+
+impl OutboundConfig {
+    pub fn policy(&self, destination: &str) -> &OutboundPolicy {
+        self.destinations.get(destination).unwrap_or(&self.default)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboundPolicy {
+    // Of each attempt.
+    pub timeout_ms: u64,
+    // Attempts of a call, the first one included.
+    pub attempts: u32,
+    // Wait before the second attempt, doubled for every attempt after up to `max_backoff_ms`.
+    pub backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    // Calls failing in a row before the destination is cut off, 0 never cuts it off.
+    pub breaker_threshold: u32,
+    // How long calls to a cut off destination fail right away, before one is let through
+    // to try it again.
+    pub breaker_open_secs: u64,
+}
+
+impl Default for OutboundPolicy {
+    fn default() -> Self {
+        OutboundPolicy { timeout_ms: 10_000, attempts: 3, backoff_ms: 1_000, max_backoff_ms: 30_000, breaker_threshold: 5, breaker_open_secs: 30 }
+    }
+}
+
+impl AppConfig {
+    // Environment overrides:
+    // - `SNAPSHOT_INTERVAL`: default snapshot interval
+    // - `SNAPSHOT_INTERVAL_<AGGREGATE>`: interval of one aggregate type, e.g. `SNAPSHOT_INTERVAL_ACCOUNT`
+    // - `DATABASE_MAX_CONNECTIONS`: see `DatabaseConfig`
+    // - `BATCHING_ENABLED`, `BATCHING_QUEUE_CAPACITY`, `BATCHING_MAX_BATCH`: see `BatchingConfig`
+    // - `ARCHIVE_KEEP_EVENTS`: see `ArchiveConfig::keep_events`
+    // - `SANDBOX`, `SANDBOX_SCHEMA`, `SANDBOX_FAUCET_LIMIT`: see `SandboxConfig`
+    // - `TRANSFER_TIMEOUT_SECS`, `TRANSFER_SWEEP_INTERVAL_SECS`: see `TransferConfig`
+    // - `DUPLICATE_WINDOW_SECS`, `DUPLICATE_MAX_TXIDS`, `DUPLICATE_STORE` (`aggregate` or `table`),
+    //   `DUPLICATE_RECENT_SECS`: see `DuplicateDetectionConfig`
+    // - `CACHE_INVALIDATION`, `CACHE_INVALIDATION_CHANNEL`: see `CacheInvalidationConfig`
+    // - `FEE_COLLECTION_ACCOUNT`: see `FeeConfig::collection_account`
+    // - `PAYOUT_ACCOUNT`: see `PayoutConfig::account`
+    // - `ERROR_ALERT_THRESHOLD`, `ERROR_ALERT_WINDOW_SECS`, `ERROR_ALERT_WEBHOOK_URL`: see `ErrorAlertConfig`
+    // - `ADMIN_API_KEYS` (comma separated), `ADMIN_JWT_SECRET`, `ADMIN_ROLE`: see `AuthConfig`
+    // - `SEALING_INTEGRITY_KEY`, `SEALING_ENCRYPTION_KEY`, `SEALING_ACCEPT_UNSEALED`: see `SealingConfig`
+    // - `FIELD_ENCRYPTION_KEY_<KID>`, e.g. `FIELD_ENCRYPTION_KEY_K2`, `FIELD_ENCRYPTION_ACTIVE_KEY`:
+    //   see `FieldEncryptionConfig`
+    // - `BULK_CONCURRENCY`: see `BulkConfig`
+    // - `APPROVALS_REQUIRED`: see `ApprovalConfig`
+    // - `TRADING_HALTS` (comma separated pairs): see `TradingHaltConfig`
+    // - `ORDER_RECOVERY_SWEEP_INTERVAL_SECS`: see `OrderRecoveryConfig`
+    // - `ORDER_EXPIRY_SWEEP_INTERVAL_SECS`: see `OrderExpiryConfig`
+    // - `SAGA_WATCHDOG_SWEEP_INTERVAL_SECS`, `SAGA_WATCHDOG_STALL_SECS`, `SAGA_WATCHDOG_MAX_ATTEMPTS`,
+    //   `SAGA_WATCHDOG_WEBHOOK_URL`: see `SagaWatchdogConfig`
+    // - `AUDIT_INTERVAL_SECS`, `AUDIT_GRACE_SECS`: see `AuditConfig`
+    // - `STANDING_ORDERS_SWEEP_INTERVAL_SECS`: see `StandingOrderConfig`
+    // - `INBOX_SWEEP_INTERVAL_SECS`, `INBOX_MAX_BATCH_LEN`, `INBOX_STALL_SECS`: see `InboxConfig`
+    // - `ACCOUNT_CLOSURE_GRACE_SECS`, `ACCOUNT_CLOSURE_SWEEP_INTERVAL_SECS`: see `AccountClosureConfig`
+    // - `INTEGRITY_INTERVAL_SECS`, `INTEGRITY_GRACE_SECS`: see `IntegrityConfig`
+    // - `VIEW_AUDIT_ON_STARTUP`, `VIEW_AUDIT_SAMPLE`, `VIEW_AUDIT_REPAIR`: see `ViewAuditConfig`
+    // - `QUARANTINE`, `QUARANTINE_VERIFIED_SOURCES` (comma separated): see `QuarantineConfig`
+    // - `COMMAND_MAX_AMOUNT_<TYPE>`, e.g. `COMMAND_MAX_AMOUNT_DEPOSIT`, `COMMAND_MAX_MEMO_BYTES`,
+    //   `COMMAND_MAX_BATCH_LEN`: see `CommandPolicyConfig`
+    // - `REPLAY_ROWS_PER_SEC`, `REPLAY_URGENT_ROWS_PER_SEC`, `REPLAY_OFF_PEAK_HOURS` (comma
+    //   separated, e.g. `22-6`): see `ReplayConfig`
+    // - `SERVER_HTTP2`, `SERVER_KEEP_ALIVE`, `SERVER_MAX_CONNECTIONS`, `SERVER_MAX_CONCURRENT_STREAMS`,
+    //   `SERVER_HTTP2_KEEP_ALIVE_INTERVAL_SECS`, `SERVER_TCP_NODELAY`, `SERVER_SHUTDOWN_TIMEOUT_SECS`:
+    //   see `ServerConfig`
+    // - `EVENT_STORE_BACKEND` (`postgres` or `sqlite`), `EVENT_STORE_SQLITE_URL`: see `EventStoreConfig`
+    // - `BULKHEAD_QUEUE_TIMEOUT_MS`, `BULKHEAD_<AGGREGATE>` (`<concurrency>/<queue_depth>`), e.g.
+    //   `BULKHEAD_ORDER=32/64`: see `BulkheadConfig`
+    // - `BUSINESS_DAY`, `BUSINESS_DAY_CUTOFF`, `BUSINESS_DAY_SKIP_WEEKENDS`, `BUSINESS_DAY_GRACE_SECS`,
+    //   `BUSINESS_DAY_MAX_BACKDATE_DAYS`: see `BusinessDayConfig`
+    // - `RATE_LIMIT_GLOBAL`, `RATE_LIMIT_ACCOUNT` (`<per_sec>/<burst>`, e.g. `10/50`, or `off`),
+    //   `RATE_LIMIT_IDLE_SECS`: see `RateLimitConfig`
+    // - `QUOTA_EVENTS_PER_DAY`, `QUOTA_STORAGE_BYTES`, `QUOTA_ENFORCEMENT` (`reject` or `throttle`),
+    //   `QUOTA_THROTTLE_INTERVAL_MS`, `QUOTA_REFRESH_SECS`: see `QuotaConfig`
+    // - `ROUTING_REGION`, `ROUTING_HASHED`, `ROUTING_TIMEOUT_MS`, `ROUTING_SECRET`: see `RoutingConfig`
+    // - `AGGREGATE_CACHE_CAPACITY`: see `AggregateCacheConfig`
+    // - `SHARD_WORKERS`, `SHARD_QUEUE_DEPTH`: see `ShardConfig`
+    // - `OUTBOUND_TIMEOUT_MS`, `OUTBOUND_ATTEMPTS`, `OUTBOUND_BREAKER_THRESHOLD`,
+    //   `OUTBOUND_BREAKER_OPEN_SECS`: the default policy of `OutboundConfig`
+    // - `CONFLICT_RETRY_ATTEMPTS`, `CONFLICT_RETRY_BACKOFF_MS`: see `ConflictRetryConfig`
+    // - `COMPENSATION_STALE_AFTER_SECS`, `COMPENSATION_SWEEP_INTERVAL_SECS`: see `CompensationConfig`
+    // - `TELEMETRY_OTLP_ENDPOINT`, `TELEMETRY_SERVICE_NAME`, `TELEMETRY_SAMPLE_RATIO`: see `TelemetryConfig`
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match std::env::var("APP_CONFIG") {
+            Ok(path) => {
+                let content = std::fs::read_to_string(&path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+                AppConfig::from_toml(&path, &content)?
+            },
+            Err(_) => AppConfig::default(),
+        };
+        config.apply_env(std::env::vars())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Settings that only make sense together.
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !self.routing.region.is_empty() && !self.routing.regions.is_empty() && self.routing.secret.is_empty() {
+            return Err(ConfigError::Invalid("routing.secret is required to forward requests between regions".to_string()));
+        }
+        if let Some(key) = self.command_policy.unknown_key() {
+            return Err(ConfigError::Invalid(format!("command_policy limits unknown command type {}", key)));
+        }
+        Ok(())
+    }
+
+    pub fn from_toml(path: &str, content: &str) -> Result<Self, ConfigError> {
+        toml::from_str(content).map_err(|e| ConfigError::Toml(path.to_string(), e))
+    }
+
+    fn apply_env(&mut self, vars: impl Iterator<Item = (String, String)>) -> Result<(), ConfigError> {
+        fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, ConfigError> {
+            value.parse().map_err(|_| ConfigError::Env(key.to_string(), value.to_string()))
+        }
+        for (key, value) in vars {
+            if key == "SNAPSHOT_INTERVAL" {
+                self.snapshots.default_interval = parse(&key, &value)?;
+            } else if let Some(aggregate_type) = key.strip_prefix("SNAPSHOT_INTERVAL_") {
+                self.snapshots.intervals.insert(aggregate_type.to_lowercase(), parse(&key, &value)?);
+            } else if key == "DATABASE_MAX_CONNECTIONS" {
+                self.database.max_connections = parse(&key, &value)?;
+            } else if key == "BATCHING_ENABLED" {
+                self.batching.enabled = value == "true" || value == "1";
+            } else if key == "BATCHING_QUEUE_CAPACITY" {
+                self.batching.queue_capacity = parse(&key, &value)?;
+            } else if key == "BATCHING_MAX_BATCH" {
+                self.batching.max_batch = parse(&key, &value)?;
+            } else if key == "ARCHIVE_KEEP_EVENTS" {
+                self.archive.keep_events = parse(&key, &value)?;
+            } else if key == "SANDBOX" {
+                self.sandbox.enabled = value == "true" || value == "1";
+            } else if key == "SANDBOX_SCHEMA" {
+                self.sandbox.schema = value;
+            } else if key == "SANDBOX_FAUCET_LIMIT" {
+                self.sandbox.faucet_limit = parse(&key, &value)?;
+            } else if key == "TRANSFER_TIMEOUT_SECS" {
+                self.transfer.timeout_secs = parse(&key, &value)?;
+            } else if key == "TRANSFER_SWEEP_INTERVAL_SECS" {
+                self.transfer.sweep_interval_secs = parse(&key, &value)?;
+            } else if key == "DUPLICATE_WINDOW_SECS" {
+                self.duplicate_detection.window_secs = parse(&key, &value)?;
+            } else if key == "DUPLICATE_MAX_TXIDS" {
+                self.duplicate_detection.max_txids = Some(parse(&key, &value)?);
+            } else if key == "DUPLICATE_STORE" {
+                self.duplicate_detection.store = parse(&key, &value)?;
+            } else if key == "DUPLICATE_RECENT_SECS" {
+                self.duplicate_detection.recent_secs = parse(&key, &value)?;
+            } else if key == "CACHE_INVALIDATION" {
+                self.cache_invalidation.enabled = value == "true" || value == "1";
+            } else if key == "CACHE_INVALIDATION_CHANNEL" {
+                self.cache_invalidation.channel = value;
+            } else if key == "FEE_COLLECTION_ACCOUNT" {
+                self.fees.collection_account = value;
+            } else if key == "PAYOUT_ACCOUNT" {
+                self.payout.account = value;
+            } else if key == "ERROR_ALERT_THRESHOLD" {
+                self.error_alerts.threshold = parse(&key, &value)?;
+            } else if key == "ERROR_ALERT_WINDOW_SECS" {
+                self.error_alerts.window_secs = parse(&key, &value)?;
+            } else if key == "ERROR_ALERT_WEBHOOK_URL" {
+                self.error_alerts.webhook_url = value;
+            } else if key == "ADMIN_API_KEYS" {
+                self.auth.api_keys = value.split(',').map(str::trim).filter(|key| !key.is_empty()).map(str::to_string).collect();
+            } else if key == "ADMIN_JWT_SECRET" {
+                self.auth.jwt_secret = value;
+            } else if key == "ADMIN_ROLE" {
+                self.auth.admin_role = value;
+            } else if key == "SEALING_INTEGRITY_KEY" {
+                self.sealing.integrity_key = value;
+            } else if key == "SEALING_ENCRYPTION_KEY" {
+                self.sealing.encryption_key = value;
+            } else if key == "SEALING_ACCEPT_UNSEALED" {
+                self.sealing.accept_unsealed = value == "true" || value == "1";
+            } else if key == "FIELD_ENCRYPTION_ACTIVE_KEY" {
+                self.field_encryption.active = value.to_lowercase();
+            } else if let Some(kid) = key.strip_prefix("FIELD_ENCRYPTION_KEY_") {
+                self.field_encryption.keys.insert(kid.to_lowercase(), value);
+            } else if key == "BULK_CONCURRENCY" {
+                self.bulk.concurrency = parse(&key, &value)?;
+            } else if key == "APPROVALS_REQUIRED" {
+                self.approvals.required = value == "true" || value == "1";
+            } else if key == "TRADING_HALTS" {
+                for pair in value.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+                    let halt = ConfiguredHalt { reason: "Halted by configuration".to_string(), resume_at: None };
+                    self.trading_halts.pairs.insert(pair.to_string(), halt);
+                }
+            } else if key == "ORDER_RECOVERY_SWEEP_INTERVAL_SECS" {
+                self.order_recovery.sweep_interval_secs = parse(&key, &value)?;
+            } else if key == "ORDER_EXPIRY_SWEEP_INTERVAL_SECS" {
+                self.order_expiry.sweep_interval_secs = parse(&key, &value)?;
+            } else if key == "SAGA_WATCHDOG_SWEEP_INTERVAL_SECS" {
+                self.saga_watchdog.sweep_interval_secs = parse(&key, &value)?;
+            } else if key == "SAGA_WATCHDOG_STALL_SECS" {
+                self.saga_watchdog.stall_secs = parse(&key, &value)?;
+            } else if key == "SAGA_WATCHDOG_MAX_ATTEMPTS" {
+                self.saga_watchdog.max_attempts = parse(&key, &value)?;
+            } else if key == "SAGA_WATCHDOG_WEBHOOK_URL" {
+                self.saga_watchdog.webhook_url = value;
+            } else if key == "STANDING_ORDERS_SWEEP_INTERVAL_SECS" {
+                self.standing_orders.sweep_interval_secs = parse(&key, &value)?;
+            } else if key == "INBOX_SWEEP_INTERVAL_SECS" {
+                self.inbox.sweep_interval_secs = parse(&key, &value)?;
+            } else if key == "INBOX_MAX_BATCH_LEN" {
+                self.inbox.max_batch_len = parse(&key, &value)?;
+            } else if key == "INBOX_STALL_SECS" {
+                self.inbox.stall_secs = parse(&key, &value)?;
+            } else if key == "ACCOUNT_CLOSURE_GRACE_SECS" {
+                self.account_closure.grace_secs = parse(&key, &value)?;
+            } else if key == "ACCOUNT_CLOSURE_SWEEP_INTERVAL_SECS" {
+                self.account_closure.sweep_interval_secs = parse(&key, &value)?;
+            } else if key == "AUDIT_INTERVAL_SECS" {
+                self.audit.interval_secs = parse(&key, &value)?;
+            } else if key == "AUDIT_GRACE_SECS" {
+                self.audit.grace_secs = parse(&key, &value)?;
+            } else if key == "INTEGRITY_INTERVAL_SECS" {
+                self.integrity.interval_secs = parse(&key, &value)?;
+            } else if key == "INTEGRITY_GRACE_SECS" {
+                self.integrity.grace_secs = parse(&key, &value)?;
+            } else if key == "VIEW_AUDIT_ON_STARTUP" {
+                self.view_audit.on_startup = value == "true" || value == "1";
+            } else if key == "VIEW_AUDIT_SAMPLE" {
+                self.view_audit.sample = parse(&key, &value)?;
+            } else if key == "VIEW_AUDIT_REPAIR" {
+                self.view_audit.repair = value == "true" || value == "1";
+            } else if key == "QUARANTINE" {
+                self.quarantine.enabled = value == "true" || value == "1";
+            } else if key == "QUARANTINE_VERIFIED_SOURCES" {
+                self.quarantine.verified_sources = value.split(',').map(str::trim).filter(|source| !source.is_empty()).map(str::to_string).collect();
+            } else if let Some(command) = key.strip_prefix("COMMAND_MAX_AMOUNT_") {
+                self.command_policy.max_amounts.insert(command.to_lowercase(), parse(&key, &value)?);
+            } else if key == "COMMAND_MAX_MEMO_BYTES" {
+                self.command_policy.max_memo_bytes = parse(&key, &value)?;
+            } else if key == "COMMAND_MAX_BATCH_LEN" {
+                self.command_policy.max_batch_len = parse(&key, &value)?;
+            } else if key == "REPLAY_ROWS_PER_SEC" {
+                self.replay.rows_per_sec = parse(&key, &value)?;
+            } else if key == "REPLAY_URGENT_ROWS_PER_SEC" {
+                self.replay.urgent_rows_per_sec = parse(&key, &value)?;
+            } else if key == "REPLAY_OFF_PEAK_HOURS" {
+                let mut windows = vec![];
+                for window in value.split(',').map(str::trim).filter(|window| !window.is_empty()) {
+                    let (start, end) = window.split_once('-').ok_or_else(|| ConfigError::Env(key.clone(), value.clone()))?;
+                    windows.push(OffPeakWindow { start_hour: parse(&key, start.trim())?, end_hour: parse(&key, end.trim())? });
+                }
+                self.replay.off_peak_hours = windows;
+            } else if key == "SERVER_HTTP2" {
+                self.server.http2 = value == "true" || value == "1";
+            } else if key == "SERVER_KEEP_ALIVE" {
+                self.server.keep_alive = value == "true" || value == "1";
+            } else if key == "SERVER_MAX_CONNECTIONS" {
+                self.server.max_connections = parse(&key, &value)?;
+            } else if key == "SERVER_MAX_CONCURRENT_STREAMS" {
+                self.server.max_concurrent_streams = parse(&key, &value)?;
+            } else if key == "SERVER_HTTP2_KEEP_ALIVE_INTERVAL_SECS" {
+                self.server.http2_keep_alive_interval_secs = parse(&key, &value)?;
+            } else if key == "SERVER_TCP_NODELAY" {
+                self.server.tcp_nodelay = value == "true" || value == "1";
+            } else if key == "SERVER_SHUTDOWN_TIMEOUT_SECS" {
+                self.server.shutdown_timeout_secs = parse(&key, &value)?;
+            } else if key == "EVENT_STORE_BACKEND" {
+                self.event_store.backend = parse(&key, &value)?;
+            } else if key == "EVENT_STORE_SQLITE_URL" {
+                self.event_store.sqlite_url = value;
+            } else if key == "BULKHEAD_QUEUE_TIMEOUT_MS" {
+                self.bulkheads.queue_timeout_ms = parse(&key, &value)?;
+            } else if key == "BUSINESS_DAY" {
+                self.business_day.enabled = value == "true" || value == "1";
+            } else if key == "BUSINESS_DAY_CUTOFF" {
+                self.business_day.cutoff = value;
+            } else if key == "BUSINESS_DAY_SKIP_WEEKENDS" {
+                self.business_day.skip_weekends = value == "true" || value == "1";
+            } else if key == "BUSINESS_DAY_GRACE_SECS" {
+                self.business_day.grace_secs = parse(&key, &value)?;
+            } else if key == "BUSINESS_DAY_MAX_BACKDATE_DAYS" {
+                self.business_day.max_backdate_days = parse(&key, &value)?;
+            } else if key == "RATE_LIMIT_GLOBAL" || key == "RATE_LIMIT_ACCOUNT" {
+                let limit = if value == "off" {
+                    None
+                } else {
+                    let (per_sec, burst) = value.split_once('/').ok_or_else(|| ConfigError::Env(key.clone(), value.clone()))?;
+                    Some(RateLimit { per_sec: parse(&key, per_sec.trim())?, burst: parse(&key, burst.trim())? })
+                };
+                if key == "RATE_LIMIT_GLOBAL" {
+                    self.rate_limits.global = limit;
+                } else {
+                    self.rate_limits.account = limit;
+                }
+            } else if key == "RATE_LIMIT_IDLE_SECS" {
+                self.rate_limits.idle_secs = parse(&key, &value)?;
+            } else if key == "QUOTA_EVENTS_PER_DAY" {
+                self.quotas.events_per_day = parse(&key, &value)?;
+            } else if key == "QUOTA_STORAGE_BYTES" {
+                self.quotas.storage_bytes = parse(&key, &value)?;
+            } else if key == "QUOTA_ENFORCEMENT" {
+                self.quotas.enforcement = parse(&key, &value)?;
+            } else if key == "QUOTA_THROTTLE_INTERVAL_MS" {
+                self.quotas.throttle_interval_ms = parse(&key, &value)?;
+            } else if key == "QUOTA_REFRESH_SECS" {
+                self.quotas.refresh_secs = parse(&key, &value)?;
+            } else if key == "ROUTING_REGION" {
+                self.routing.region = value;
+            } else if key == "ROUTING_HASHED" {
+                self.routing.hashed = value == "true" || value == "1";
+            } else if key == "ROUTING_TIMEOUT_MS" {
+                self.routing.timeout_ms = parse(&key, &value)?;
+            } else if key == "ROUTING_SECRET" {
+                self.routing.secret = value;
+            } else if key == "AGGREGATE_CACHE_CAPACITY" {
+                self.aggregate_cache.capacity = parse(&key, &value)?;
+            } else if key == "SHARD_WORKERS" {
+                self.shards.workers = parse(&key, &value)?;
+            } else if key == "SHARD_QUEUE_DEPTH" {
+                self.shards.queue_depth = parse(&key, &value)?;
+            } else if key == "OUTBOUND_TIMEOUT_MS" {
+                self.outbound.default.timeout_ms = parse(&key, &value)?;
+            } else if key == "OUTBOUND_ATTEMPTS" {
+                self.outbound.default.attempts = parse(&key, &value)?;
+            } else if key == "OUTBOUND_BREAKER_THRESHOLD" {
+                self.outbound.default.breaker_threshold = parse(&key, &value)?;
+            } else if key == "OUTBOUND_BREAKER_OPEN_SECS" {
+                self.outbound.default.breaker_open_secs = parse(&key, &value)?;
+            } else if key == "CONFLICT_RETRY_ATTEMPTS" {
+                self.conflict_retry.attempts = parse(&key, &value)?;
+            } else if key == "CONFLICT_RETRY_BACKOFF_MS" {
+                self.conflict_retry.backoff_ms = parse(&key, &value)?;
+            } else if key == "COMPENSATION_STALE_AFTER_SECS" {
+                self.compensations.stale_after_secs = parse(&key, &value)?;
+            } else if key == "COMPENSATION_SWEEP_INTERVAL_SECS" {
+                self.compensations.sweep_interval_secs = parse(&key, &value)?;
+            } else if let Some(aggregate_type) = key.strip_prefix("BULKHEAD_") {
+                let (concurrency, queue_depth) = value.split_once('/').ok_or_else(|| ConfigError::Env(key.clone(), value.clone()))?;
+                let limits = BulkheadLimits { concurrency: parse(&key, concurrency.trim())?, queue_depth: parse(&key, queue_depth.trim())? };
+                self.bulkheads.limits.insert(aggregate_type.to_lowercase(), limits);
+            } else if key == "TELEMETRY_OTLP_ENDPOINT" {
+                self.telemetry.otlp_endpoint = Some(value);
+            } else if key == "TELEMETRY_SERVICE_NAME" {
+                self.telemetry.service_name = value;
+            } else if key == "TELEMETRY_SAMPLE_RATIO" {
+                self.telemetry.sample_ratio = parse(&key, &value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalidation_hook(config: &AppConfig) -> Option<Arc<dyn CommitHook>> {
+    let invalidation = &config.cache_invalidation;
+    invalidation.enabled.then(|| Arc::new(InvalidationHook::new(invalidation.channel.clone())) as Arc<dyn CommitHook>)
+}
+
+// The queries notifying account owners and downstream systems of account events,
+// delivered by the notifier.
+pub fn notification_queries(
+    pool: &Pool<Postgres>,
+    preferences_query: Arc<PostgresViewRepository<PreferencesView, Preferences>>,
+    notifier: WebhookNotifier,
+) -> Vec<Box<dyn Query<Account>>> {
+    vec![
+        // Evaluates the balance alerts of the account owner on every transaction, delivered
+        // only to public addresses as the owner chose the url.
+        Box::new(BalanceAlertQuery::new(preferences_query, pool.clone(), notifier.clone().public_only())),
+        // Notifies downstream systems subscribed to account lifecycle events.
+        Box::new(LifecycleWebhookQuery::new(WebhookRegistry::new(pool.clone()), notifier)),
+    ]
+}
+
+// The accounts with their views, the cache keeping them hydrated and the metrics of the
+// canary routing them, see `account_cqrs_framework`.
+pub type AccountFramework = (
+    Arc<SealedCqrs<Account>>,
+    Arc<AccountViewRepository>,
+    Arc<AccountStatsRepository>,
+    AggregateCache<Account>,
+    CanaryMetrics,
+);
 
 pub fn account_cqrs_framework(
     pool: Pool<Postgres>,
-) -> (
-    Arc<PostgresCqrs<Account>>,
-    Arc<PostgresViewRepository<AccountView, Account>>,
-) {
+    config: &AppConfig,
+    asset_query: Arc<PostgresViewRepository<AssetView, Asset>>,
+    account_stream: AccountEventStream,
+    notifications: Vec<Box<dyn Query<Account>>>,
+    fee_query: FeeQuery,
+    posting_date: Option<PostingDate>,
+) -> AccountFramework {
     // A very simple query that writes each event to stdout.
     let simple_query = crate::account::queries::SimpleLoggingQuery {};
 
     // A query that stores the current state of an individual account.
-    let account_view_repo = Arc::new(PostgresViewRepository::new("account_query", pool.clone()));
+    // Balances are sealed at rest when keys are configured.
+    let sealer = Sealer::new(&config.sealing).expect("invalid sealing keys");
+    let account_view_repo = Arc::new(SealedViewRepository::new("account_query", pool.clone(), sealer.clone()));
     let mut account_query = AccountQuery::new(account_view_repo.clone());
 
     // Without a query error handler there will be no indication if an
@@ -30,50 +1734,664 @@ pub fn account_cqrs_framework(
     // Consider logging an error or panicking in your own application.
     account_query.use_error_handler(Box::new(|e| println!("{}", e)));
 
+    // Lifetime totals of the account, sealed like the balances.
+    let stats_view_repo = Arc::new(SealedViewRepository::new("account_stats_query", pool.clone(), sealer.clone()));
+    let mut stats_query = AccountStatsQuery::new(stats_view_repo.clone());
+    stats_query.use_error_handler(Box::new(|e| println!("{}", e)));
+
     // Create and return an event-sourced `CqrsFramework`.
-    let queries: Vec<Box<dyn Query<Account>>> =
-        vec![Box::new(simple_query), Box::new(account_query)];
-    let services = BankAccountServices::new(Box::new(HappyPathBankAccountServices));
+    let mut queries: Vec<Box<dyn Query<Account>>> = vec![
+        Box::new(simple_query),
+        Box::new(account_query),
+        Box::new(stats_query),
+        Box::new(LedgerQuery::new(pool.clone())),
+        Box::new(fee_query),
+        Box::new(JournalQuery::new(pool.clone())),
+        Box::new(account_stream),
+    ];
+    // Balance alerts and lifecycle webhooks, see `notification_queries`, and the signal counts.
+    queries.extend(notifications);
+    queries.push(Box::new(AuditLogQuery::new(pool.clone())));
+    let dedup_store = (config.duplicate_detection.store == DuplicateStore::Table)
+        .then(|| Arc::new(TxidStore::new(pool.clone(), config.duplicate_detection.clone())));
+    let mut hooks = outbox_hooks();
+    hooks.extend(invalidation_hook(config));
+    if let Some(dedup_store) = &dedup_store {
+        hooks.push(dedup_store.clone());
+    }
+    if !config.fees.collection_account.is_empty() {
+        hooks.push(Arc::new(FeeHook));
+    }
+    hooks.push(Arc::new(ClosureHook));
+    // Its metrics are handed back with the rest to report to the metrics registry.
+    let canary_metrics = CanaryMetrics::default();
+    let canary = Canary::new(&config.canary, next_logic(), canary_metrics.clone());
+    let services = BankAccountServices::new(Box::new(RegistryBankAccountServices::new(asset_query)))
+        .with_duplicate_detection(config.duplicate_detection.clone())
+        .with_fees(config.fees.clone())
+        .with_overdraft(Box::new(OverdraftLimits::new(pool.clone())))
+        .with_quarantine(config.quarantine.clone())
+        .with_command_policy(config.command_policy.clone())
+        .with_posting_date(posting_date)
+        .with_max_backdate_days(config.business_day.max_backdate_days)
+        .with_closure_grace_secs(config.account_closure.grace_secs)
+        .with_canary(canary)
+        .with_dedup_store(dedup_store.map(|store| store as Arc<dyn DedupStore>));
+    // Keeps the accounts hydrated between their commands, handed back to be kept in step
+    // with the other nodes and to report to the metrics registry.
+    let cache = AggregateCache::new(&config.aggregate_cache, AggregateCacheMetrics::default());
     (
-        Arc::new(postgres_es::postgres_snapshot_cqrs(
-            pool, queries, 100, services,
+        Arc::new(sealed_snapshot_cqrs(
+            HookedEventRepository::new(pool, hooks).with_batching(&config.batching), queries, config.snapshots.interval("account"), services, sealer, cache.clone(), &config.shards,
         )),
         account_view_repo,
+        stats_view_repo,
+        cache,
+        canary_metrics,
     )
 }
 
-pub fn transfer_cqrs_framework(pool: Pool<Postgres>, account_cqrs: Arc<PostgresCqrs<Account>>) -> (Arc<PostgresCqrs<Transfer>>, Arc<PostgresViewRepository<TransferView, Transfer>>) {
+pub fn asset_cqrs_framework(pool: Pool<Postgres>, config: &AppConfig) -> (Arc<PostgresCqrs<Asset>>, Arc<PostgresViewRepository<AssetView, Asset>>) {
+    let simple_query = crate::asset::queries::SimpleLoggingQuery {};
+
+    let asset_view_repo = Arc::new(PostgresViewRepository::new("asset_query", pool.clone()));
+    let mut asset_query = AssetQuery::new(asset_view_repo.clone());
+    asset_query.use_error_handler(Box::new(|e| println!("{}", e)));
+
+    let mut queries: Vec<Box<dyn Query<Asset>>> = vec![Box::new(simple_query), Box::new(asset_query)];
+    queries.push(Box::new(AuditLogQuery::new(pool.clone())));
+
+    (
+        Arc::new(postgres_es::postgres_snapshot_cqrs(
+            pool, queries, config.snapshots.interval("asset"), (),
+        )),
+        asset_view_repo,
+    )
+}
+
+pub fn preferences_cqrs_framework(pool: Pool<Postgres>, config: &AppConfig, counterparty_names: CounterpartyNames) -> (Arc<PostgresCqrs<Preferences>>, Arc<PostgresViewRepository<PreferencesView, Preferences>>) {
+    let simple_query = crate::preferences::queries::SimpleLoggingQuery {};
+
+    let preferences_view_repo = Arc::new(PostgresViewRepository::new("preferences_query", pool.clone()));
+    let mut preferences_query = PreferencesQuery::new(preferences_view_repo.clone());
+    preferences_query.use_error_handler(Box::new(|e| println!("{}", e)));
+
+    let mut queries: Vec<Box<dyn Query<Preferences>>> = vec![Box::new(simple_query), Box::new(preferences_query), Box::new(counterparty_names)];
+    queries.push(Box::new(AuditLogQuery::new(pool.clone())));
+
+    (
+        Arc::new(postgres_es::postgres_snapshot_cqrs(
+            pool, queries, config.snapshots.interval("preferences"), (),
+        )),
+        preferences_view_repo,
+    )
+}
+
+pub fn transfer_cqrs_framework(pool: Pool<Postgres>, config: &AppConfig, account_cqrs: Arc<SealedCqrs<Account>>, compensations: CompensationJournal) -> (Arc<EncryptedCqrs<Transfer>>, Arc<PostgresViewRepository<TransferView, Transfer>>) {
     let simple_query = crate::transfer::queries::SimpleLoggingQuery {};
 
     let transfer_view_repo = Arc::new(PostgresViewRepository::new("transfer_query", pool.clone()));
     let mut transfer_query = TransferQuery::new(transfer_view_repo.clone());
     transfer_query.use_error_handler(Box::new(|e| println!("{}", e)));
 
-    let queries: Vec<Box<dyn Query<Transfer>>> = vec![Box::new(simple_query), Box::new(transfer_query)];
-    let services = TransferServices::new(account_cqrs);
+    let refund_link_query = RefundLinkQuery::new(transfer_view_repo.clone());
+
+    let mut queries: Vec<Box<dyn Query<Transfer>>> = vec![Box::new(simple_query), Box::new(transfer_query), Box::new(refund_link_query)];
+    queries.push(Box::new(AuditLogQuery::new(pool.clone())));
+    queries.push(Box::new(CompensationQuery::new(pool.clone())));
+    let services = TransferServices::new(Arc::new(RetryingExecutor::new(account_cqrs, config.conflict_retry.clone())), config.transfer.timeout_secs)
+        .with_dead_letters(DeadLetters::new(pool.clone()))
+        .with_compensations(compensations)
+        .with_command_policy(config.command_policy.clone());
+
+    let cipher = FieldCipher::new(&config.field_encryption).expect("invalid field encryption keys");
 
     (
-        Arc::new(postgres_es::postgres_snapshot_cqrs(
-            pool, queries, 100, services,
+        Arc::new(encrypted_snapshot_cqrs(
+            pool, queries, config.snapshots.interval("transfer"), services, cipher,
         )),
         transfer_view_repo,
     )
 }
 
-pub fn order_cqrs_framework(pool: Pool<Postgres>, account_cqrs: Arc<PostgresCqrs<Account>>) -> (Arc<PostgresCqrs<Order>>, Arc<PostgresViewRepository<OrderView, Order>>) {
+pub fn batch_transfer_cqrs_framework(pool: Pool<Postgres>, config: &AppConfig, account_cqrs: Arc<SealedCqrs<Account>>, compensations: CompensationJournal) -> (Arc<EncryptedCqrs<BatchTransfer>>, Arc<PostgresViewRepository<BatchTransferView, BatchTransfer>>) {
+    let simple_query = crate::batch_transfer::queries::SimpleLoggingQuery {};
+
+    let batch_transfer_view_repo = Arc::new(PostgresViewRepository::new("batch_transfer_query", pool.clone()));
+    let mut batch_transfer_query = BatchTransferQuery::new(batch_transfer_view_repo.clone());
+    batch_transfer_query.use_error_handler(Box::new(|e| println!("{}", e)));
+
+    let mut queries: Vec<Box<dyn Query<BatchTransfer>>> = vec![Box::new(simple_query), Box::new(batch_transfer_query)];
+    queries.push(Box::new(AuditLogQuery::new(pool.clone())));
+    queries.push(Box::new(CompensationQuery::new(pool.clone())));
+    let services = BatchTransferServices::new(Arc::new(RetryingExecutor::new(account_cqrs, config.conflict_retry.clone())))
+        .with_compensations(compensations)
+        .with_command_policy(config.command_policy.clone());
+
+    let cipher = FieldCipher::new(&config.field_encryption).expect("invalid field encryption keys");
+
+    (
+        Arc::new(encrypted_snapshot_cqrs(
+            pool, queries, config.snapshots.interval("batch_transfer"), services, cipher,
+        )),
+        batch_transfer_view_repo,
+    )
+}
+
+pub fn order_cqrs_framework(pool: Pool<Postgres>, config: &AppConfig, account_cqrs: Arc<SealedCqrs<Account>>, matching_query: MatchingQuery, halts: TradingHalts, saga_metrics: SagaMetrics, compensations: CompensationJournal) -> (Arc<HookedCqrs<Order>>, Arc<PostgresViewRepository<OrderView, Order>>) {
     let simple_query = crate::order::queries::SimpleLoggingQuery {};
 
     let order_view_repo = Arc::new(PostgresViewRepository::new("order_query", pool.clone()));
     let mut order_query = OrderQuery::new(order_view_repo.clone());
     order_query.use_error_handler(Box::new(|e| println!("{}", e)));
 
-    let queries: Vec<Box<dyn Query<Order>>> = vec![Box::new(simple_query), Box::new(order_query)];
-    let services = OrderServices::new(account_cqrs);
+    let mut queries: Vec<Box<dyn Query<Order>>> = vec![
+        Box::new(simple_query),
+        Box::new(order_query),
+        Box::new(OrderBookQuery::new(pool.clone())),
+        // After the order book, so the matcher finds placed orders listed.
+        Box::new(matching_query),
+    ];
+    queries.push(Box::new(AuditLogQuery::new(pool.clone())));
+    queries.push(Box::new(CompensationQuery::new(pool.clone())));
+    let asset_query = Arc::new(PostgresViewRepository::<AssetView, Asset>::new("asset_query", pool.clone()));
+    let mut hooks = outbox_hooks();
+    hooks.extend(invalidation_hook(config));
+    let services = OrderServices::new(Arc::new(RetryingExecutor::new(account_cqrs, config.conflict_retry.clone()))).with_halts(halts).with_metrics("order", saga_metrics)
+        .with_policy(OrderPolicyService::new(asset_query))
+        .with_dead_letters(DeadLetters::new(pool.clone()))
+        .with_compensations(compensations);
 
     (
-        Arc::new(postgres_es::postgres_snapshot_cqrs(
-            pool, queries, 100, services,
+        Arc::new(hooked_snapshot_cqrs(
+            HookedEventRepository::new(pool, hooks).with_batching(&config.batching), queries, config.snapshots.interval("order"), services,
         )),
         order_view_repo,
     )
-}
\ No newline at end of file
+}
+
+pub fn rfq_cqrs_framework(pool: Pool<Postgres>, config: &AppConfig, account_cqrs: Arc<SealedCqrs<Account>>, saga_metrics: SagaMetrics, compensations: CompensationJournal) -> (Arc<PostgresCqrs<Rfq>>, Arc<PostgresViewRepository<RfqView, Rfq>>) {
+    let simple_query = crate::rfq::queries::SimpleLoggingQuery {};
+
+    let rfq_view_repo = Arc::new(PostgresViewRepository::new("rfq_query", pool.clone()));
+    let mut rfq_query = RfqQuery::new(rfq_view_repo.clone());
+    rfq_query.use_error_handler(Box::new(|e| println!("{}", e)));
+
+    let mut queries: Vec<Box<dyn Query<Rfq>>> = vec![Box::new(simple_query), Box::new(rfq_query)];
+    queries.push(Box::new(AuditLogQuery::new(pool.clone())));
+    queries.push(Box::new(CompensationQuery::new(pool.clone())));
+    let services = RfqServices::new(OrderServices::new(Arc::new(RetryingExecutor::new(account_cqrs, config.conflict_retry.clone()))).with_metrics("rfq", saga_metrics)
+        .with_dead_letters(DeadLetters::new(pool.clone()))
+        .with_compensations(compensations));
+
+    (
+        Arc::new(postgres_es::postgres_snapshot_cqrs(
+            pool, queries, config.snapshots.interval("rfq"), services,
+        )),
+        rfq_view_repo,
+    )
+}
+
+pub fn auction_cqrs_framework(pool: Pool<Postgres>, config: &AppConfig, account_cqrs: Arc<SealedCqrs<Account>>) -> (Arc<PostgresCqrs<Auction>>, Arc<PostgresViewRepository<AuctionView, Auction>>) {
+    let simple_query = crate::auction::queries::SimpleLoggingQuery {};
+
+    let auction_view_repo = Arc::new(PostgresViewRepository::new("auction_query", pool.clone()));
+    let mut auction_query = AuctionQuery::new(auction_view_repo.clone());
+    auction_query.use_error_handler(Box::new(|e| println!("{}", e)));
+
+    let mut queries: Vec<Box<dyn Query<Auction>>> = vec![Box::new(simple_query), Box::new(auction_query)];
+    queries.push(Box::new(AuditLogQuery::new(pool.clone())));
+    let services = AuctionServices::new(Arc::new(RetryingExecutor::new(account_cqrs, config.conflict_retry.clone())));
+
+    (
+        Arc::new(postgres_es::postgres_snapshot_cqrs(
+            pool, queries, config.snapshots.interval("auction"), services,
+        )),
+        auction_view_repo,
+    )
+}
+
+pub fn standing_order_cqrs_framework(pool: Pool<Postgres>, config: &AppConfig) -> (Arc<PostgresCqrs<StandingOrder>>, Arc<PostgresViewRepository<StandingOrderView, StandingOrder>>) {
+    let simple_query = crate::standing_order::queries::SimpleLoggingQuery {};
+
+    let standing_order_view_repo = Arc::new(PostgresViewRepository::new("standing_order_query", pool.clone()));
+    let mut standing_order_query = StandingOrderQuery::new(standing_order_view_repo.clone());
+    standing_order_query.use_error_handler(Box::new(|e| println!("{}", e)));
+
+    let mut queries: Vec<Box<dyn Query<StandingOrder>>> = vec![Box::new(simple_query), Box::new(standing_order_query)];
+    queries.push(Box::new(AuditLogQuery::new(pool.clone())));
+
+    (
+        Arc::new(postgres_es::postgres_snapshot_cqrs(
+            pool, queries, config.snapshots.interval("standing_order"), (),
+        )),
+        standing_order_view_repo,
+    )
+}
+
+pub fn approval_cqrs_framework(
+    pool: Pool<Postgres>,
+    config: &AppConfig,
+    account_cqrs: Arc<SealedCqrs<Account>>,
+    asset_cqrs: Arc<PostgresCqrs<Asset>>,
+) -> (Arc<PostgresCqrs<Approval>>, Arc<PostgresViewRepository<ApprovalView, Approval>>) {
+    let simple_query = crate::approval::queries::SimpleLoggingQuery {};
+
+    let approval_view_repo = Arc::new(PostgresViewRepository::new("approval_query", pool.clone()));
+    let mut approval_query = ApprovalQuery::new(approval_view_repo.clone());
+    approval_query.use_error_handler(Box::new(|e| println!("{}", e)));
+
+    let mut queries: Vec<Box<dyn Query<Approval>>> = vec![Box::new(simple_query), Box::new(approval_query)];
+    queries.push(Box::new(AuditLogQuery::new(pool.clone())));
+    let changes = ConfigChanges::new(account_cqrs, asset_cqrs, OverdraftLimits::new(pool.clone()), config.conflict_retry.clone());
+
+    (
+        Arc::new(postgres_es::postgres_snapshot_cqrs(
+            pool, queries, config.snapshots.interval("approval"), ApprovalServices::new(Arc::new(changes)),
+        )),
+        approval_view_repo,
+    )
+}
+
+// Pairs traded by batch auction, read from `AUCTION_SCHEDULE` (e.g. `BTC/ETH=60;ETH/USDT=300`,
+// window length in seconds). Empty when unset, which leaves the auction engine idle.
+pub fn auction_schedule() -> Vec<AuctionPair> {
+    std::env::var("AUCTION_SCHEDULE")
+        .map(|schedule| AuctionPair::parse_schedule(&schedule))
+        .unwrap_or_default()
+}
+
+// Records command requests for `replay-traffic` when `RECORD_TRAFFIC` is set.
+pub fn traffic_recording_enabled() -> bool {
+    std::env::var("RECORD_TRAFFIC")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false)
+}
+
+// Global txid uniqueness is opt-in with `GLOBAL_TXID_REGISTRY=true`, deployments that
+// already reuse txids across accounts keep the per-account behaviour.
+pub fn global_txid_registry_enabled() -> bool {
+    std::env::var("GLOBAL_TXID_REGISTRY")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false)
+}
+
+// The outbox is written along with the events of each command, see `OutboxHook`.
+fn outbox_hooks() -> Vec<Arc<dyn CommitHook>> {
+    match outbox_config() {
+        Some(_) => vec![Arc::new(OutboxHook)],
+        None => vec![],
+    }
+}
+
+// Account and order events are published to Kafka when `KAFKA_BROKERS` is set
+// (e.g. `kafka-1:9092,kafka-2:9092`). Without it nothing is written to the outbox.
+// - `KAFKA_TOPIC_PREFIX`: topic name before the aggregate type, default `cqrs-account.`
+// - `OUTBOX_BATCH_SIZE`: events published per round trip, default 500
+// - `OUTBOX_POLL_MS`: delay between polls once the outbox is drained, default 500
+pub fn outbox_config() -> Option<OutboxConfig> {
+    let brokers = std::env::var("KAFKA_BROKERS").ok().filter(|brokers| !brokers.is_empty())?;
+    Some(OutboxConfig {
+        brokers,
+        topic_prefix: std::env::var("KAFKA_TOPIC_PREFIX").unwrap_or("cqrs-account.".to_string()),
+        batch_size: std::env::var("OUTBOX_BATCH_SIZE").ok().and_then(|size| size.parse().ok()).unwrap_or(500),
+        poll_interval: Duration::from_millis(
+            std::env::var("OUTBOX_POLL_MS").ok().and_then(|ms| ms.parse().ok()).unwrap_or(500),
+        ),
+    })
+}
+
+// Route priorities and p99 latency budgets used for load shedding. Money movement on
+// accounts is critical and never shed; reporting style routes go first. Adjust with
+// `SLA_ROUTES` (e.g. `GET /rfq=normal;POST /order/:order_id=critical:300`, budget in
+// milliseconds) and the rolling window with `SLA_WINDOW_SECS` (default 30).
+pub fn sla_config() -> SlaConfig {
+    let policy = |priority, budget_ms: Option<u64>| RoutePolicy { priority, budget: budget_ms.map(Duration::from_millis) };
+    let mut config = SlaConfig {
+        window: Duration::from_secs(
+            std::env::var("SLA_WINDOW_SECS").ok().and_then(|secs| secs.parse().ok()).unwrap_or(30),
+        ),
+        routes: [
+            ("POST /account/:account_id", policy(RoutePriority::Critical, Some(250))),
+            ("GET /account/:account_id", policy(RoutePriority::Normal, Some(100))),
+            // Risk checks, never shed. Misses on the aggregate cache read the view, so their
+            // latency says little about the load and no budget is set.
+            ("GET /account/:account_id/coverage", policy(RoutePriority::Critical, None)),
+            ("POST /account/:account_id/commands", policy(RoutePriority::Low, None)),
+            ("POST /transfer", policy(RoutePriority::Normal, Some(500))),
+            ("POST /account/:account_id/transfer", policy(RoutePriority::Normal, Some(500))),
+            ("POST /transfer/:transfer_id", policy(RoutePriority::Normal, Some(500))),
+            ("POST /transfer/:transfer_id/refund", policy(RoutePriority::Normal, Some(500))),
+            ("POST /batch-transfer/:batch_id", policy(RoutePriority::Normal, None)),
+            ("POST /standing-order/:standing_order_id", policy(RoutePriority::Normal, None)),
+            ("GET /standing-order/:standing_order_id", policy(RoutePriority::Low, None)),
+            ("POST /inbox/:batch_id", policy(RoutePriority::Normal, None)),
+            ("GET /inbox/:batch_id", policy(RoutePriority::Low, None)),
+            ("POST /order/:order_id", policy(RoutePriority::Normal, Some(500))),
+            ("GET /account/:account_id/stream", policy(RoutePriority::Low, None)),
+            ("GET /account/:account_id/stats", policy(RoutePriority::Low, None)),
+            ("GET /account/:account_id/accruals/preview", policy(RoutePriority::Low, None)),
+            ("GET /account/:account_id/ledger", policy(RoutePriority::Low, None)),
+            ("GET /account/:account_id/ledger/search", policy(RoutePriority::Low, None)),
+            ("GET /account/:account_id/ledger.csv", policy(RoutePriority::Low, None)),
+            ("GET /account/:account_id/ledger.ofx", policy(RoutePriority::Low, None)),
+            ("GET /account/:account_id/preferences", policy(RoutePriority::Low, None)),
+            ("POST /account/:account_id/preferences", policy(RoutePriority::Low, None)),
+            ("GET /rfq", policy(RoutePriority::Low, None)),
+            ("GET /orderbook/:pair", policy(RoutePriority::Low, None)),
+            ("GET /rates/:pair/history", policy(RoutePriority::Low, None)),
+            ("GET /payout/:batch_id", policy(RoutePriority::Low, None)),
+            ("POST /payout/:batch_id", policy(RoutePriority::Low, None)),
+            ("GET /admin/statemachine/:aggregate", policy(RoutePriority::Low, None)),
+            ("GET /admin/matching", policy(RoutePriority::Low, None)),
+            ("GET /admin/halts", policy(RoutePriority::Low, None)),
+            ("POST /admin/halts/:pair", policy(RoutePriority::Critical, None)),
+            ("DELETE /admin/halts/:pair", policy(RoutePriority::Critical, None)),
+            ("GET /admin/recovery", policy(RoutePriority::Low, None)),
+            ("POST /admin/recovery", policy(RoutePriority::Normal, None)),
+            ("GET /admin/approval", policy(RoutePriority::Low, None)),
+            ("POST /admin/approval", policy(RoutePriority::Low, None)),
+            ("GET /admin/approval/:approval_id", policy(RoutePriority::Low, None)),
+            ("POST /admin/approval/:approval_id/approve", policy(RoutePriority::Normal, None)),
+            ("POST /admin/approval/:approval_id/reject", policy(RoutePriority::Low, None)),
+            ("GET /admin/watchdog", policy(RoutePriority::Low, None)),
+            ("POST /admin/watchdog", policy(RoutePriority::Normal, None)),
+            ("GET /admin/view-audit", policy(RoutePriority::Low, None)),
+            ("POST /admin/view-audit", policy(RoutePriority::Low, None)),
+            ("GET /admin/audit", policy(RoutePriority::Low, None)),
+            ("POST /admin/audit", policy(RoutePriority::Low, None)),
+            ("GET /admin/audit/:aggregate_id", policy(RoutePriority::Low, None)),
+            ("GET /admin/integrity", policy(RoutePriority::Low, None)),
+            ("POST /admin/integrity", policy(RoutePriority::Low, None)),
+            ("POST /admin/integrity/:account_id/:txid", policy(RoutePriority::Normal, None)),
+            ("GET /admin/replays", policy(RoutePriority::Low, None)),
+            ("POST /admin/replays/:name/pause", policy(RoutePriority::Critical, None)),
+            ("POST /admin/replays/:name/resume", policy(RoutePriority::Low, None)),
+            ("GET /reports/trial-balance", policy(RoutePriority::Low, None)),
+            ("GET /reports/business-day/:date", policy(RoutePriority::Low, None)),
+            ("GET /admin/business-days", policy(RoutePriority::Low, None)),
+            ("POST /admin/business-days/close", policy(RoutePriority::Critical, None)),
+            ("GET /admin/errors", policy(RoutePriority::Low, None)),
+            ("GET /admin/signals", policy(RoutePriority::Low, None)),
+            ("GET /admin/signals/:account_id", policy(RoutePriority::Low, None)),
+            ("GET /admin/quota", policy(RoutePriority::Low, None)),
+            ("GET /admin/quota/:account_id", policy(RoutePriority::Low, None)),
+            ("GET /admin/routing", policy(RoutePriority::Low, None)),
+            ("GET /admin/metrics", policy(RoutePriority::Low, None)),
+            ("POST /admin/account/:account_id", policy(RoutePriority::Normal, None)),
+            ("GET /admin/account/:account_id/overdraft", policy(RoutePriority::Low, None)),
+            ("PUT /admin/account/:account_id/overdraft", policy(RoutePriority::Normal, None)),
+            ("POST /admin/account/:account_id/value-dated", policy(RoutePriority::Critical, None)),
+            ("POST /admin/account/:account_id/quarantine/:txid", policy(RoutePriority::Normal, None)),
+            ("POST /admin/account/:account_id/earmark/:earmark", policy(RoutePriority::Normal, None)),
+            ("DELETE /admin/account/:account_id/earmark/:earmark", policy(RoutePriority::Normal, None)),
+            ("GET /admin/bulk", policy(RoutePriority::Low, None)),
+            ("POST /admin/bulk", policy(RoutePriority::Normal, None)),
+            ("GET /admin/bulk/:job_id", policy(RoutePriority::Low, None)),
+            ("POST /admin/bulk/:job_id/cancel", policy(RoutePriority::Normal, None)),
+            ("GET /api-docs/openapi.json", policy(RoutePriority::Low, None)),
+        ]
+            .into_iter()
+            .map(|(route, policy)| (route.to_string(), policy))
+            .collect(),
+    };
+    if let Ok(overrides) = std::env::var("SLA_ROUTES") {
+        config.apply_overrides(&overrides).expect("invalid SLA_ROUTES");
+    }
+    config
+}
+
+#[cfg(test)]
+mod test {
+    use crate::account::events::DEFAULT_TTL;
+    use crate::config::{AppConfig, BulkheadLimits, ConfigError, DuplicateStore, EventStoreBackend, FeeOperation, OutboundPolicy, QuotaEnforcement, RateLimit, TenantQuota};
+
+    #[test]
+    fn test_snapshot_intervals() {
+        let mut config = AppConfig::from_toml("app.toml", "[snapshots]\ndefault_interval = 50\nintervals = { auction = 500 }\n").unwrap();
+        config.apply_env(vec![("SNAPSHOT_INTERVAL_ACCOUNT".to_string(), "20".to_string())].into_iter()).unwrap();
+        assert_eq!(config.snapshots.interval("account"), 20);
+        assert_eq!(config.snapshots.interval("auction"), 500);
+        assert_eq!(config.snapshots.interval("order"), 50);
+        assert!(AppConfig::from_toml("app.toml", "[snapshot]\n").is_err());
+        assert!(config.apply_env(vec![("SNAPSHOT_INTERVAL".to_string(), "often".to_string())].into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_duplicate_window_by_tenant() {
+        let config = AppConfig::from_toml(
+            "app.toml",
+            "[duplicate_detection]\nwindow_secs = 600\ntenants = { \"ACME-\" = 60, \"ACME-EU-\" = 30 }\n",
+        ).unwrap();
+        assert_eq!(config.duplicate_detection.window_secs("ACME-0001"), 60);
+        assert_eq!(config.duplicate_detection.window_secs("ACME-EU-0001"), 30);
+        assert_eq!(config.duplicate_detection.window_secs("ACCT-0001"), 600);
+        assert_eq!(AppConfig::default().duplicate_detection.window_secs("ACCT-0001"), DEFAULT_TTL);
+    }
+
+    #[test]
+    fn test_max_txids_by_tenant() {
+        let config = AppConfig::from_toml(
+            "app.toml",
+            "[duplicate_detection]\nmax_txids = 1000\ntenant_max_txids = { \"ACME-\" = 10 }\n",
+        ).unwrap();
+        assert_eq!(config.duplicate_detection.max_txids("ACME-0001"), Some(10));
+        assert_eq!(config.duplicate_detection.max_txids("ACCT-0001"), Some(1000));
+        assert_eq!(AppConfig::default().duplicate_detection.max_txids("ACCT-0001"), None);
+    }
+
+    #[test]
+    fn test_duplicate_store() {
+        let mut config = AppConfig::from_toml("app.toml", "[duplicate_detection]\nstore = \"table\"\nrecent_secs = 60\n").unwrap();
+        assert_eq!(config.duplicate_detection.store, DuplicateStore::Table);
+        assert_eq!(config.duplicate_detection.recent_secs, 60);
+        config.apply_env(vec![("DUPLICATE_STORE".to_string(), "aggregate".to_string())].into_iter()).unwrap();
+        assert_eq!(config.duplicate_detection.store, DuplicateStore::Aggregate);
+        assert!(config.apply_env(vec![("DUPLICATE_STORE".to_string(), "redis".to_string())].into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_fee_schedule() {
+        let config = AppConfig::from_toml(
+            "app.toml",
+            "[fees]\ncollection_account = \"FEES\"\nwithdraw = { BTC = { flat = 10, bps = 50 } }\nsettle = { USDT = { bps = 10 } }\n",
+        ).unwrap();
+        let fees = &config.fees;
+        assert_eq!(fees.fee("ACCT-0001", FeeOperation::Withdraw, "BTC", 1000), Some(("FEES".to_string(), 15)));
+        assert_eq!(fees.fee("ACCT-0001", FeeOperation::Settle, "BTC", 1000), None);
+        // Rounded down, so small settlements go free.
+        assert_eq!(fees.fee("ACCT-0001", FeeOperation::Settle, "USDT", 999), None);
+        assert_eq!(fees.fee("ACCT-0001", FeeOperation::Settle, "USDT", 1000), Some(("FEES".to_string(), 1)));
+        assert_eq!(fees.fee("FEES", FeeOperation::Withdraw, "BTC", 1000), None);
+        assert_eq!(AppConfig::default().fees.fee("ACCT-0001", FeeOperation::Withdraw, "BTC", 1000), None);
+    }
+
+    #[test]
+    fn test_error_alerts() {
+        let mut config = AppConfig::from_toml("app.toml", "[error_alerts]\nthreshold = 20\n").unwrap();
+        assert_eq!((config.error_alerts.threshold, config.error_alerts.window_secs), (20, 60));
+        let vars = vec![
+            ("ERROR_ALERT_WINDOW_SECS".to_string(), "300".to_string()),
+            ("ERROR_ALERT_WEBHOOK_URL".to_string(), "http://localhost/alerts".to_string()),
+        ];
+        config.apply_env(vars.into_iter()).unwrap();
+        assert_eq!(config.error_alerts.window_secs, 300);
+        assert_eq!(config.error_alerts.webhook_url, "http://localhost/alerts");
+        assert!(config.apply_env(vec![("ERROR_ALERT_THRESHOLD".to_string(), "-1".to_string())].into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_trading_halts() {
+        let mut config = AppConfig::from_toml(
+            "app.toml",
+            "[trading_halts]\npairs = { \"BTC-USDT\" = { reason = \"Exchange incident\", resume_at = 1767225600 } }\n",
+        ).unwrap();
+        assert_eq!(config.trading_halts.pairs["BTC-USDT"].resume_at, Some(1767225600));
+        assert_eq!(config.trading_halts.recheck_secs, 5);
+        config.apply_env(vec![("TRADING_HALTS".to_string(), "ETH-USDT, SOL-USDT".to_string())].into_iter()).unwrap();
+        let pairs: Vec<_> = config.trading_halts.pairs.keys().cloned().collect();
+        assert_eq!(pairs, vec!["BTC-USDT", "ETH-USDT", "SOL-USDT"]);
+        assert_eq!(config.trading_halts.pairs["ETH-USDT"].resume_at, None);
+    }
+
+    #[test]
+    fn test_telemetry() {
+        let config = AppConfig::from_toml("app.toml", "[telemetry]\nsample_ratio = 0.1\n").unwrap();
+        assert_eq!((config.telemetry.otlp_endpoint.as_deref(), config.telemetry.sample_ratio), (None, 0.1));
+        let mut config = AppConfig::default();
+        config.apply_env(vec![("TELEMETRY_OTLP_ENDPOINT".to_string(), "http://otel:4318/v1/traces".to_string())].into_iter()).unwrap();
+        assert_eq!(config.telemetry.otlp_endpoint.as_deref(), Some("http://otel:4318/v1/traces"));
+        assert!(AppConfig::from_toml("app.toml", "[telemetry]\nendpoint = \"http://otel:4318\"\n").is_err());
+    }
+
+    #[test]
+    fn test_view_audit() {
+        let mut config = AppConfig::from_toml("app.toml", "[view_audit]\non_startup = true\nsample = 500\n").unwrap();
+        assert!(config.view_audit.on_startup && !config.view_audit.repair);
+        assert_eq!(config.view_audit.sample, 500);
+        config.apply_env(vec![("VIEW_AUDIT_REPAIR".to_string(), "1".to_string()), ("VIEW_AUDIT_SAMPLE".to_string(), "0".to_string())].into_iter()).unwrap();
+        assert!(config.view_audit.repair);
+        assert_eq!(config.view_audit.sample, 0);
+        assert!(AppConfig::from_toml("app.toml", "[view_audit]\nrebuild = true\n").is_err());
+    }
+
+    #[test]
+    fn test_standing_orders() {
+        let mut config = AppConfig::from_toml("app.toml", "[standing_orders]\nsweep_interval_secs = 60\n").unwrap();
+        assert_eq!(config.standing_orders.sweep_interval_secs, 60);
+        config.apply_env(vec![("STANDING_ORDERS_SWEEP_INTERVAL_SECS".to_string(), "5".to_string())].into_iter()).unwrap();
+        assert_eq!(config.standing_orders.sweep_interval_secs, 5);
+        assert!(AppConfig::from_toml("app.toml", "[standing_orders]\ninterval_secs = 60\n").is_err());
+    }
+
+    #[test]
+    fn test_inbox() {
+        let mut config = AppConfig::from_toml("app.toml", "[inbox]\nmax_batch_len = 20\n").unwrap();
+        assert_eq!((config.inbox.max_batch_len, config.inbox.stall_secs), (20, 300));
+        config.apply_env(vec![("INBOX_STALL_SECS".to_string(), "60".to_string())].into_iter()).unwrap();
+        assert_eq!(config.inbox.stall_secs, 60);
+        assert!(AppConfig::from_toml("app.toml", "[inbox]\nbatch_len = 20\n").is_err());
+    }
+
+    #[test]
+    fn test_account_closure() {
+        let mut config = AppConfig::from_toml("app.toml", "[account_closure]\ngrace_secs = 86400\n").unwrap();
+        assert_eq!((config.account_closure.grace_secs, config.account_closure.sweep_interval_secs), (86400, 60));
+        config.apply_env(vec![("ACCOUNT_CLOSURE_GRACE_SECS".to_string(), "3600".to_string())].into_iter()).unwrap();
+        assert_eq!(config.account_closure.grace_secs, 3600);
+        assert!(AppConfig::from_toml("app.toml", "[account_closure]\ngrace_days = 30\n").is_err());
+    }
+
+    #[test]
+    fn test_quarantine_sources() {
+        let mut config = AppConfig::from_toml("app.toml", "[quarantine]\nverified_sources = [\"custody\"]\n").unwrap();
+        assert!(!config.quarantine.quarantines(Some("unknown")));
+        config.apply_env(vec![("QUARANTINE".to_string(), "1".to_string())].into_iter()).unwrap();
+        assert!(config.quarantine.quarantines(Some("unknown")));
+        assert!(!config.quarantine.quarantines(Some("custody")));
+        config.apply_env(vec![("QUARANTINE_VERIFIED_SOURCES".to_string(), "bank-wire, unknown".to_string())].into_iter()).unwrap();
+        assert!(!config.quarantine.quarantines(Some("unknown")));
+        assert!(config.quarantine.quarantines(Some("custody")));
+    }
+
+    #[test]
+    fn test_off_peak_hours() {
+        let mut config = AppConfig::from_toml("app.toml", "[replay]\noff_peak_hours = [{ start_hour = 22, end_hour = 6 }]\n").unwrap();
+        assert_eq!(config.replay.rows_per_sec, 1000);
+        assert!(config.replay.is_off_peak(23) && config.replay.is_off_peak(0) && config.replay.is_off_peak(5));
+        assert!(!config.replay.is_off_peak(6) && !config.replay.is_off_peak(12));
+        config.apply_env(vec![("REPLAY_OFF_PEAK_HOURS".to_string(), "1-3, 12-13".to_string())].into_iter()).unwrap();
+        assert!(config.replay.is_off_peak(12) && !config.replay.is_off_peak(23));
+        assert!(config.apply_env(vec![("REPLAY_OFF_PEAK_HOURS".to_string(), "night".to_string())].into_iter()).is_err());
+        assert!(AppConfig::default().replay.is_off_peak(12));
+    }
+
+    #[test]
+    fn test_event_store_backend() {
+        let mut config = AppConfig::from_toml("app.toml", "[event_store]\nbackend = \"sqlite\"\n").unwrap();
+        assert_eq!(config.event_store.backend, EventStoreBackend::Sqlite);
+        assert_eq!(config.event_store.sqlite_url, "sqlite://cqrs-account.db");
+        assert!(AppConfig::from_toml("app.toml", "[event_store]\nbackend = \"mysql\"\n").is_err());
+        config.apply_env(vec![("EVENT_STORE_BACKEND".to_string(), "postgres".to_string())].into_iter()).unwrap();
+        assert_eq!(config.event_store.backend, EventStoreBackend::Postgres);
+        assert!(config.apply_env(vec![("EVENT_STORE_BACKEND".to_string(), "mysql".to_string())].into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_bulkhead_limits() {
+        let mut config = AppConfig::from_toml("app.toml", "[bulkheads]\nlimits = { account = { concurrency = 8, queue_depth = 16 } }\n").unwrap();
+        assert_eq!(config.bulkheads.limits.len(), 1);
+        assert_eq!(config.bulkheads.queue_timeout_ms, 1000);
+        config.apply_env(vec![("BULKHEAD_ORDER".to_string(), "4/2".to_string())].into_iter()).unwrap();
+        assert_eq!(config.bulkheads.limits["order"], BulkheadLimits { concurrency: 4, queue_depth: 2 });
+        assert!(config.apply_env(vec![("BULKHEAD_ORDER".to_string(), "4".to_string())].into_iter()).is_err());
+        assert!(AppConfig::default().bulkheads.limits.contains_key("order"));
+        assert!(!AppConfig::default().bulkheads.limits.contains_key("account"));
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let mut config = AppConfig::from_toml("app.toml", "[rate_limits]\naccount = { per_sec = 0.5, burst = 5 }\n").unwrap();
+        assert_eq!(config.rate_limits.account, Some(RateLimit { per_sec: 0.5, burst: 5 }));
+        assert_eq!(config.rate_limits.global, None);
+        assert_eq!(config.rate_limits.idle_secs, 300);
+        let vars = vec![("RATE_LIMIT_GLOBAL".to_string(), "1000/2000".to_string()), ("RATE_LIMIT_ACCOUNT".to_string(), "off".to_string())];
+        config.apply_env(vars.into_iter()).unwrap();
+        assert_eq!(config.rate_limits.global, Some(RateLimit { per_sec: 1000.0, burst: 2000 }));
+        assert_eq!(config.rate_limits.account, None);
+        assert!(config.apply_env(vec![("RATE_LIMIT_ACCOUNT".to_string(), "10".to_string())].into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_quotas() {
+        let toml = "[quotas]\nevents_per_day = 1000\ntenants = { \"ACME-\" = { storage_bytes = 4096 }, \"ACME-EU-\" = { events_per_day = 10 } }\n";
+        let mut config = AppConfig::from_toml("app.toml", toml).unwrap();
+        assert_eq!(config.quotas.tenant("ACME-EU-0001"), Some(("ACME-EU-", TenantQuota { events_per_day: 10, storage_bytes: 0 })));
+        assert_eq!(config.quotas.tenant("ACME-0001"), Some(("ACME-", TenantQuota { events_per_day: 0, storage_bytes: 4096 })));
+        assert_eq!(config.quotas.tenant("ACCT-0001"), None);
+        assert_eq!(config.quotas.enforcement, QuotaEnforcement::Reject);
+        config.apply_env(vec![("QUOTA_ENFORCEMENT".to_string(), "throttle".to_string())].into_iter()).unwrap();
+        assert_eq!(config.quotas.enforcement, QuotaEnforcement::Throttle);
+        assert!(config.apply_env(vec![("QUOTA_ENFORCEMENT".to_string(), "drop".to_string())].into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_command_policy_keys() {
+        let mut config = AppConfig::from_toml("app.toml", "[command_policy]\nmax_amounts = { deposit = 10, batch_transfer = 10 }\n").unwrap();
+        assert!(config.validate().is_ok());
+        // A typo would leave the command without its limit.
+        config.apply_env(vec![("COMMAND_MAX_AMOUNT_DEPOSITS".to_string(), "10".to_string())].into_iter()).unwrap();
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        let config = AppConfig::from_toml("app.toml", "[command_policy.tenants.\"ACME-\"]\nmax_amounts = { release_earmark = 10 }\n").unwrap();
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_routing() {
+        let toml = "[routing]\nregion = \"eu\"\naccounts = { \"ACME-US-\" = \"us\" }\n\n[routing.regions]\nus = [\"http://us-1:3030\"]\n";
+        let mut config = AppConfig::from_toml("app.toml", toml).unwrap();
+        assert_eq!(config.routing.regions["us"], ["http://us-1:3030"]);
+        assert_eq!(config.routing.accounts["ACME-US-"], "us");
+        assert!(!config.routing.hashed);
+        config.apply_env(vec![("ROUTING_HASHED".to_string(), "true".to_string()), ("ROUTING_REGION".to_string(), "us".to_string())].into_iter()).unwrap();
+        assert!(config.routing.hashed);
+        assert_eq!(config.routing.region, "us");
+        assert!(AppConfig::from_toml("app.toml", "[routing]\nzones = []\n").is_err());
+        // Forwarded requests could not be told from any other without a secret.
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))));
+        config.apply_env(vec![("ROUTING_SECRET".to_string(), "shared".to_string())].into_iter()).unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_outbound_policies() {
+        let toml = "[outbound.default]\ntimeout_ms = 5000\n\n[outbound.destinations.alerts]\nattempts = 5\nbreaker_threshold = 0\n";
+        let mut config = AppConfig::from_toml("app.toml", toml).unwrap();
+        assert_eq!(config.outbound.policy("webhooks"), &OutboundPolicy { timeout_ms: 5000, ..OutboundPolicy::default() });
+        // Unset fields of a destination are not taken from the default policy.
+        assert_eq!(config.outbound.policy("alerts"), &OutboundPolicy { attempts: 5, breaker_threshold: 0, ..OutboundPolicy::default() });
+        config.apply_env(vec![("OUTBOUND_ATTEMPTS".to_string(), "1".to_string())].into_iter()).unwrap();
+        assert_eq!(config.outbound.policy("webhooks").attempts, 1);
+        assert_eq!(config.outbound.policy("alerts").attempts, 5);
+    }
+}
@@ -0,0 +1,217 @@
+use std::future::Future;
+use std::time::Duration;
+use async_trait::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::header::IF_MATCH;
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use cqrs_es::persist::{PersistenceError, SerializedEvent};
+use cqrs_es::{Aggregate, EventEnvelope};
+use serde::Deserialize;
+use sqlx::{Pool, Postgres, Row};
+use tokio::time::Instant;
+use utoipa::IntoParams;
+
+// Carries the sequence an aggregate reached with a command, to be passed back as
+// `min_sequence` by a query that must reflect the command.
+pub const SEQUENCE_HDR: &str = "X-Sequence";
+
+// Command metadata carrying the sequence of an `If-Match` header, checked at commit by
+// `check_expected_sequence`.
+pub const EXPECTED_SEQUENCE_KEY: &str = "expected_sequence";
+
+const DEFAULT_WAIT_MS: u64 = 2_000;
+const MAX_WAIT_MS: u64 = 10_000;
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ConsistencyParams {
+    // Waits until the view has applied the events up to this sequence, from `X-Sequence`.
+    pub min_sequence: Option<usize>,
+    // How long to wait, 2 seconds by default and at most 10.
+    pub wait_ms: Option<u64>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConsistencyError<E> {
+    #[error("{0}")]
+    Load(E),
+    #[error("The view is at sequence {applied}, not yet at {min_sequence}")]
+    Timeout { min_sequence: usize, applied: usize },
+}
+
+// The sequence of the last event committed to an aggregate. Archived events leave their
+// sequence behind in the snapshot.
+pub async fn committed_sequence<A: Aggregate>(pool: &Pool<Postgres>, aggregate_id: &str) -> Result<usize, sqlx::Error> {
+    let row = sqlx::query(
+        "SELECT GREATEST(
+             (SELECT MAX(sequence) FROM events WHERE aggregate_type = $1 AND aggregate_id = $2),
+             (SELECT MAX(last_sequence) FROM snapshots WHERE aggregate_type = $1 AND aggregate_id = $2)
+         ) AS sequence",
+    )
+        .bind(A::aggregate_type())
+        .bind(aggregate_id)
+        .fetch_one(pool)
+        .await?;
+    Ok(row.try_get::<Option<i64>, _>("sequence")?.unwrap_or(0) as usize)
+}
+
+// Loads a view until it has applied `min_sequence`, as told by `sequence`, or the wait
+// is over. Views are updated as the events are committed, so this only waits when the
+// projection fell behind, e.g. on a lost optimistic lock. Without `min_sequence` the view
+// is loaded once, as is.
+pub async fn load_consistent<V, E, F, Fut>(params: &ConsistencyParams, sequence: impl Fn(&V) -> usize, mut load: F) -> Result<Option<V>, ConsistencyError<E>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Option<V>, E>>,
+{
+    let deadline = Instant::now() + Duration::from_millis(params.wait_ms.unwrap_or(DEFAULT_WAIT_MS).min(MAX_WAIT_MS));
+    loop {
+        let view = load().await.map_err(ConsistencyError::Load)?;
+        let Some(min_sequence) = params.min_sequence else {
+            return Ok(view);
+        };
+        // An aggregate without a view yet has applied nothing.
+        let applied = view.as_ref().map_or(0, &sequence);
+        if applied >= min_sequence {
+            return Ok(view);
+        }
+        if Instant::now() >= deadline {
+            return Err(ConsistencyError::Timeout { min_sequence, applied });
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+// The `ETag` of a view at `sequence`, to be sent back in `If-Match`.
+pub fn etag(sequence: usize) -> String {
+    format!("\"{}\"", sequence)
+}
+
+// The sequence a command expects its aggregate to be at, from `If-Match: "<sequence>"`
+// as in the `ETag` of the view or `X-Sequence`, so clients can compare and set: the
+// command only applies when no other one came in between. `*` and no header at all
+// expect nothing.
+pub struct IfMatch(pub Option<usize>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for IfMatch
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts.headers.get(IF_MATCH) else {
+            return Ok(IfMatch(None));
+        };
+        let invalid = || (StatusCode::BAD_REQUEST, "If-Match must be a sequence, e.g. \"42\"".to_string());
+        let value = value.to_str().map_err(|_| invalid())?.trim();
+        if value == "*" {
+            return Ok(IfMatch(None));
+        }
+        let sequence = value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value);
+        sequence.parse().map(|sequence| IfMatch(Some(sequence))).map_err(|_| invalid())
+    }
+}
+
+// Fails the commit of a command expecting another sequence than the one its events
+// follow. They were produced from the aggregate at that sequence and are committed under
+// the optimistic lock on it, so this cannot race with another command. The expectation is
+// dropped from the stored metadata.
+pub fn check_expected_sequence(events: &[SerializedEvent]) -> Result<Vec<SerializedEvent>, PersistenceError> {
+    let expected = events
+        .first()
+        .and_then(|event| event.metadata.get(EXPECTED_SEQUENCE_KEY))
+        .and_then(|expected| expected.as_str()?.parse::<usize>().ok());
+    let mut events = events.to_vec();
+    for event in &mut events {
+        if let Some(metadata) = event.metadata.as_object_mut() {
+            metadata.remove(EXPECTED_SEQUENCE_KEY);
+        }
+    }
+    match (expected, events.first()) {
+        (Some(expected), Some(first)) if first.sequence != expected + 1 => Err(PersistenceError::OptimisticLockError),
+        _ => Ok(events),
+    }
+}
+
+// The queries are handed the committed events with the metadata of their command, the
+// expectation is dropped from it as it is from the stored metadata.
+pub fn drop_expected_sequence<A: Aggregate>(events: &mut [EventEnvelope<A>]) {
+    for event in events {
+        event.metadata.remove(EXPECTED_SEQUENCE_KEY);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use axum::extract::FromRequestParts;
+    use axum::http::Request;
+    use std::collections::HashMap;
+    use cqrs_es::persist::{PersistenceError, SerializedEvent};
+    use cqrs_es::EventEnvelope;
+    use serde_json::json;
+    use crate::account::aggregate::Account;
+    use crate::account::events::AccountEvent;
+    use crate::consistency::{check_expected_sequence, drop_expected_sequence, load_consistent, ConsistencyError, ConsistencyParams, IfMatch};
+
+    #[tokio::test]
+    async fn test_load_consistent() {
+        // The view applies one more event on every load.
+        let loads = AtomicUsize::new(0);
+        let load = || async { Ok::<_, ()>(Some(loads.fetch_add(1, Ordering::SeqCst))) };
+
+        let params = ConsistencyParams { min_sequence: Some(3), wait_ms: Some(1_000) };
+        assert_eq!(load_consistent(&params, |view| *view, load).await.unwrap(), Some(3));
+        let params = ConsistencyParams { min_sequence: None, wait_ms: None };
+        assert_eq!(load_consistent(&params, |view| *view, load).await.unwrap(), Some(4));
+
+        let params = ConsistencyParams { min_sequence: Some(1), wait_ms: Some(50) };
+        let missing = load_consistent(&params, |view: &usize| *view, || async { Ok::<_, ()>(None) }).await;
+        assert!(matches!(missing, Err(ConsistencyError::Timeout { min_sequence: 1, applied: 0 })));
+    }
+
+    async fn if_match(value: Option<&str>) -> Option<Option<usize>> {
+        let mut request = Request::post("/account/ACCT-0001");
+        if let Some(value) = value {
+            request = request.header("If-Match", value);
+        }
+        let (mut parts, _) = request.body(()).unwrap().into_parts();
+        IfMatch::from_request_parts(&mut parts, &()).await.map(|IfMatch(expected)| expected).ok()
+    }
+
+    #[tokio::test]
+    async fn test_if_match() {
+        assert_eq!(if_match(Some("\"42\"")).await, Some(Some(42)));
+        assert_eq!(if_match(Some("7")).await, Some(Some(7)));
+        assert_eq!(if_match(Some("*")).await, Some(None));
+        assert_eq!(if_match(None).await, Some(None));
+        assert_eq!(if_match(Some("W/\"42\"")).await, None);
+    }
+
+    #[test]
+    fn test_check_expected_sequence() {
+        let event = |sequence, metadata| SerializedEvent::new(
+            "ACCT-0001".to_string(), sequence, "account".to_string(), "Deposit".to_string(), "1.0".to_string(), json!({}), metadata,
+        );
+        let expecting = |expected: &str| json!({"expected_sequence": expected, "initiator": "alice"});
+        let committed = check_expected_sequence(&[event(4, expecting("3")), event(5, expecting("3"))]).unwrap();
+        assert_eq!(committed[1].metadata, json!({"initiator": "alice"}));
+        // Another command was committed since the client read sequence 2.
+        assert!(matches!(check_expected_sequence(&[event(4, expecting("2"))]), Err(PersistenceError::OptimisticLockError)));
+        assert_eq!(check_expected_sequence(&[event(1, json!({}))]).unwrap().len(), 1);
+
+        // Nor do the queries see it.
+        let mut dispatched = vec![EventEnvelope::<Account> {
+            aggregate_id: "ACCT-0001".to_string(),
+            sequence: 4,
+            payload: AccountEvent::account_opened("ACCT-0001".to_string()),
+            metadata: HashMap::from([("expected_sequence".to_string(), "3".to_string()), ("initiator".to_string(), "alice".to_string())]),
+        }];
+        drop_expected_sequence(&mut dispatched);
+        assert_eq!(dispatched[0].metadata, HashMap::from([("initiator".to_string(), "alice".to_string())]));
+    }
+}
@@ -0,0 +1,277 @@
+use cqrs_es::AggregateError;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgRow;
+use sqlx::{Pool, Postgres, Row};
+use utoipa::{IntoParams, ToSchema};
+use crate::account::commands::AccountCommand;
+use crate::account::events::AccountError;
+use crate::services::AccountExecutor;
+use crate::util::clock;
+
+const MAX_LISTED: i64 = 500;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeadLetterError {
+    #[error("Dead letter {0} not found")]
+    NotFound(i64),
+    #[error("Dead letter {0} is already {1}")]
+    Resolved(i64, String),
+    #[error("Retrying dead letter {0} failed: {1}")]
+    RetryFailed(i64, String),
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum DeadLetterStatus {
+    Pending,
+    // Executed again from the admin API, or found to have been applied after all.
+    Retried,
+    Discarded,
+}
+
+impl DeadLetterStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            DeadLetterStatus::Pending => "Pending",
+            DeadLetterStatus::Retried => "Retried",
+            DeadLetterStatus::Discarded => "Discarded",
+        }
+    }
+
+    fn parse(status: &str) -> Self {
+        match status {
+            "Retried" => DeadLetterStatus::Retried,
+            "Discarded" => DeadLetterStatus::Discarded,
+            _ => DeadLetterStatus::Pending,
+        }
+    }
+}
+
+// A step of a saga, or the compensation of one, that failed on an account and left the
+// saga without a way to finish it on its own.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DeadLetter {
+    pub id: i64,
+    // `order`, `rfq` or `transfer`, and the step as labelled in the saga metrics.
+    pub saga: String,
+    pub step: String,
+    // Hex id of the order, RFQ or transfer.
+    pub saga_id: String,
+    pub account_id: String,
+    // Executed again as it is on a retry.
+    pub command: AccountCommand,
+    // Of the last attempt.
+    pub error: String,
+    pub status: DeadLetterStatus,
+    pub attempts: u32,
+    pub created_at: u64,
+    pub updated_at: u64,
+    // Who retried or discarded it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolved_by: Option<String>,
+}
+
+impl DeadLetter {
+    fn from_row(row: &PgRow) -> Result<Self, sqlx::Error> {
+        let command: serde_json::Value = row.try_get("command")?;
+        Ok(DeadLetter {
+            id: row.try_get("id")?,
+            saga: row.try_get("saga")?,
+            step: row.try_get("step")?,
+            saga_id: row.try_get("saga_id")?,
+            account_id: row.try_get("account_id")?,
+            command: serde_json::from_value(command).map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+            error: row.try_get("error")?,
+            status: DeadLetterStatus::parse(row.try_get("status")?),
+            attempts: row.try_get::<i32, _>("attempts")? as u32,
+            created_at: row.try_get::<i64, _>("created_at")? as u64,
+            updated_at: row.try_get::<i64, _>("updated_at")? as u64,
+            resolved_by: row.try_get("resolved_by")?,
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeadLetterFilter {
+    pub status: Option<DeadLetterStatus>,
+    pub saga: Option<String>,
+}
+
+// Keeps the saga steps that failed in `dead_letter` for an operator to retry or discard
+// under `/admin/dlq`. Sagas record a step when it fails on the framework rather than on
+// the business rules, and a compensation whenever it fails, since nothing undoes it.
+#[derive(Clone)]
+pub struct DeadLetters {
+    pool: Pool<Postgres>,
+}
+
+impl DeadLetters {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        DeadLetters { pool }
+    }
+
+    // Failing to record is only logged, the saga goes on failing the way it did before.
+    pub async fn record(&self, saga: &str, step: &str, saga_id: &str, account_id: &str, command: &AccountCommand, error: &AggregateError<AccountError>) {
+        tracing::error!("Saga {} {} failed at {} on {}: {}", saga, saga_id, step, account_id, error);
+        let recorded = sqlx::query(
+            "INSERT INTO dead_letter (saga, step, saga_id, account_id, command, error, status, attempts, created_at, updated_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, 1, $8, $8)",
+        )
+            .bind(saga)
+            .bind(step)
+            .bind(saga_id)
+            .bind(account_id)
+            .bind(serde_json::to_value(command).expect("account commands serialize"))
+            .bind(error.to_string())
+            .bind(DeadLetterStatus::Pending.as_str())
+            .bind(clock::now() as i64)
+            .execute(&self.pool)
+            .await;
+        if let Err(e) = recorded {
+            tracing::error!("Failed to record the dead letter of saga {} {} at {}: {}", saga, saga_id, step, e);
+        }
+    }
+
+    // Newest first.
+    pub async fn list(&self, filter: &DeadLetterFilter) -> Result<Vec<DeadLetter>, sqlx::Error> {
+        sqlx::query(
+            "SELECT * FROM dead_letter WHERE ($1::text IS NULL OR status = $1) AND ($2::text IS NULL OR saga = $2)
+             ORDER BY id DESC LIMIT $3",
+        )
+            .bind(filter.status.map(DeadLetterStatus::as_str))
+            .bind(&filter.saga)
+            .bind(MAX_LISTED)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(DeadLetter::from_row)
+            .collect()
+    }
+
+    pub async fn get(&self, id: i64) -> Result<Option<DeadLetter>, sqlx::Error> {
+        sqlx::query("SELECT * FROM dead_letter WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|row| DeadLetter::from_row(&row))
+            .transpose()
+    }
+
+    // Executes the command again. Accounts refusing it as already applied, or as having
+    // nothing left to undo, resolve the letter as well; any other failure leaves it pending.
+    pub async fn retry(&self, id: i64, accounts: &dyn AccountExecutor, initiator: &str) -> Result<DeadLetter, DeadLetterError> {
+        let letter = self.pending(id).await?;
+        let result = accounts.execute(&letter.account_id, letter.command.clone()).await;
+        let (status, error, resolved_by) = match &result {
+            Ok(())
+            | Err(AggregateError::UserError(
+                AccountError::DuplicateTransaction(_) | AccountError::LockNotFound | AccountError::TransactionNotFound,
+            )) => (DeadLetterStatus::Retried, letter.error.clone(), Some(initiator)),
+            Err(e) => (DeadLetterStatus::Pending, e.to_string(), None),
+        };
+        let row = sqlx::query(
+            "UPDATE dead_letter SET status = $2, error = $3, resolved_by = $4, attempts = attempts + 1, updated_at = $5
+             WHERE id = $1 RETURNING *",
+        )
+            .bind(id)
+            .bind(status.as_str())
+            .bind(&error)
+            .bind(resolved_by)
+            .bind(clock::now() as i64)
+            .fetch_one(&self.pool)
+            .await?;
+        match status {
+            DeadLetterStatus::Pending => Err(DeadLetterError::RetryFailed(id, error)),
+            _ => {
+                tracing::warn!("Dead letter {} of saga {} {} retried by {}", id, letter.saga, letter.saga_id, initiator);
+                Ok(DeadLetter::from_row(&row)?)
+            }
+        }
+    }
+
+    // For steps an operator settled by other means.
+    pub async fn discard(&self, id: i64, initiator: &str) -> Result<DeadLetter, DeadLetterError> {
+        let letter = self.pending(id).await?;
+        let row = sqlx::query("UPDATE dead_letter SET status = $2, resolved_by = $3, updated_at = $4 WHERE id = $1 RETURNING *")
+            .bind(id)
+            .bind(DeadLetterStatus::Discarded.as_str())
+            .bind(initiator)
+            .bind(clock::now() as i64)
+            .fetch_one(&self.pool)
+            .await?;
+        tracing::warn!("Dead letter {} of saga {} {} discarded by {}", id, letter.saga, letter.saga_id, initiator);
+        Ok(DeadLetter::from_row(&row)?)
+    }
+
+    async fn pending(&self, id: i64) -> Result<DeadLetter, DeadLetterError> {
+        match self.get(id).await? {
+            None => Err(DeadLetterError::NotFound(id)),
+            Some(letter) if letter.status != DeadLetterStatus::Pending => {
+                Err(DeadLetterError::Resolved(id, letter.status.as_str().to_lowercase()))
+            }
+            Some(letter) => Ok(letter),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+    use async_trait::async_trait;
+    use cqrs_es::AggregateError;
+    use crate::account::commands::AccountCommand;
+    use crate::account::events::AccountError;
+    use crate::dlq::{DeadLetterError, DeadLetterFilter, DeadLetterStatus, DeadLetters};
+    use crate::services::AccountExecutor;
+    use crate::util::test_db::{delete_rows, random_suffix, test_pool};
+    use crate::util::types::ByteArray32;
+
+    // Answers with the queued results, in order.
+    struct Accounts(Mutex<Vec<Result<(), AggregateError<AccountError>>>>);
+
+    #[async_trait]
+    impl AccountExecutor for Accounts {
+        async fn execute(&self, _account_id: &str, _command: AccountCommand) -> Result<(), AggregateError<AccountError>> {
+            self.0.lock().unwrap().remove(0)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dead_letters() {
+        let pool = test_pool();
+        let dead_letters = DeadLetters::new(pool.clone());
+        let saga = format!("test-{}", random_suffix());
+        let order_id = ByteArray32::derive("test", &saga);
+        let command = AccountCommand::settle(order_id, 1, "SELLER".to_string(), "USD".to_string(), 100);
+        let conflict = AggregateError::<AccountError>::AggregateConflict;
+        for step in ["settle_leg_2", "compensate_unlock"] {
+            dead_letters.record(&saga, step, &order_id.hex(), "BUYER", &command, &conflict).await;
+        }
+        let filter = DeadLetterFilter { status: Some(DeadLetterStatus::Pending), saga: Some(saga.clone()) };
+        let letters = dead_letters.list(&filter).await.unwrap();
+        assert_eq!(letters.len(), 2);
+        let (unlock, settle) = (&letters[0], &letters[1]);
+        assert_eq!((settle.step.as_str(), settle.account_id.as_str(), settle.attempts), ("settle_leg_2", "BUYER", 1));
+        assert!(matches!(&settle.command, AccountCommand::Transaction { txid, .. } if *txid == order_id));
+
+        // Failing again leaves the letter pending, until the account takes the command.
+        let accounts = Accounts(Mutex::new(vec![
+            Err(AggregateError::DatabaseConnectionError("gone".into())),
+            Err(AggregateError::UserError(AccountError::DuplicateTransaction(1))),
+        ]));
+        let failed = dead_letters.retry(settle.id, &accounts, "api-key:0").await;
+        assert!(matches!(failed, Err(DeadLetterError::RetryFailed(..))));
+        let retried = dead_letters.retry(settle.id, &accounts, "api-key:0").await.unwrap();
+        assert_eq!((retried.status, retried.attempts, retried.resolved_by.as_deref()), (DeadLetterStatus::Retried, 3, Some("api-key:0")));
+        assert!(matches!(dead_letters.retry(settle.id, &accounts, "api-key:0").await, Err(DeadLetterError::Resolved(..))));
+
+        let discarded = dead_letters.discard(unlock.id, "api-key:1").await.unwrap();
+        assert_eq!(discarded.status, DeadLetterStatus::Discarded);
+        assert!(dead_letters.list(&filter).await.unwrap().is_empty());
+        assert!(matches!(dead_letters.discard(-1, "api-key:1").await, Err(DeadLetterError::NotFound(-1))));
+
+        delete_rows(&pool, "dead_letter", "saga", &saga).await;
+    }
+}
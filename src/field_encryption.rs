@@ -0,0 +1,607 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use async_trait::async_trait;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use cqrs_es::persist::{EventUpcaster, PersistedEventRepository, PersistedEventStore, PersistenceError, ReplayStream, SerializedEvent, SerializedSnapshot};
+use cqrs_es::{Aggregate, CqrsFramework, Query};
+use postgres_es::PostgresEventRepository;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{Pool, Postgres, Row};
+use crate::config::FieldEncryptionConfig;
+use crate::util::metadata::{CLAIMED_INITIATOR_KEY, INITIATOR_KEY};
+
+const FORMAT_VERSION: u32 = 1;
+const STREAM_CHANNEL_SIZE: usize = 200;
+const REWRAP_PAGE_SIZE: i64 = 500;
+
+// Where the personal data sits in the events of each aggregate type, `*` standing for
+// the variant of the event.
+const SENSITIVE_FIELDS: &[(&str, &[&str])] = &[
+    ("transfer", &["/*/description"]),
+    ("batch_transfer", &["/*/description"]),
+];
+
+// The same data as kept in the snapshots, `*` standing for the state.
+const SENSITIVE_SNAPSHOT_FIELDS: &[(&str, &[&str])] = &[
+    ("transfer", &["/*/config/description"]),
+];
+
+// Event metadata naming who acted.
+const SENSITIVE_METADATA: &[&str] = &[INITIATOR_KEY, CLAIMED_INITIATOR_KEY];
+
+#[derive(Debug, thiserror::Error)]
+pub enum FieldEncryptionError {
+    #[error("Invalid field encryption key {0}: expected 32 hex encoded bytes")]
+    InvalidKey(String),
+    #[error("Active field encryption key {0} is not configured")]
+    UnknownActiveKey(String),
+    #[error("{1} is encrypted under key {0}, which is not configured")]
+    UnknownKey(String, String),
+    #[error("Malformed encrypted field in {0}")]
+    Malformed(String),
+    #[error("Decryption of {0} failed, the field was altered or moved")]
+    Tampered(String),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RewrapError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error(transparent)]
+    Encryption(#[from] FieldEncryptionError),
+}
+
+// What an encrypted field holds in place of its value.
+#[derive(Debug, Serialize, Deserialize)]
+struct EncryptedField {
+    encrypted: u32,
+    // The key the data key is wrapped under.
+    kid: String,
+    // The data key, AES-256-GCM encrypted under `kid`, behind its nonce.
+    key: String,
+    nonce: String,
+    // The JSON of the value, encrypted under the data key and bound to the aggregate,
+    // sequence and path it was written at.
+    ciphertext: String,
+}
+
+// Envelope-encrypts the personal data in events before they reach the event store, see
+// `SENSITIVE_FIELDS`: each field gets a data key of its own, which is wrapped under the
+// active key of the keyring and stored next to the ciphertext. Rotating a key only takes
+// a new active one; fields written under an older key decrypt as long as it stays in
+// the keyring, and `rewrap_stored` moves them onto the active key without touching the
+// ciphertexts. Fields written before encryption was enabled load as they are.
+#[derive(Clone)]
+pub struct FieldCipher {
+    keys: Arc<BTreeMap<String, LessSafeKey>>,
+    active: Option<String>,
+}
+
+impl std::fmt::Debug for FieldCipher {
+    // Never prints the keys.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FieldCipher")
+            .field("kids", &self.keys.keys().collect::<Vec<_>>())
+            .field("active", &self.active)
+            .finish()
+    }
+}
+
+impl FieldCipher {
+    // None when no key is configured, events are then stored in plain JSON. Without an
+    // active key new events are too, but the keyring still decrypts the older ones.
+    pub fn new(config: &FieldEncryptionConfig) -> Result<Option<Self>, FieldEncryptionError> {
+        if config.keys.is_empty() {
+            return match config.active.is_empty() {
+                true => Ok(None),
+                false => Err(FieldEncryptionError::UnknownActiveKey(config.active.clone())),
+            };
+        }
+        let mut keys = BTreeMap::new();
+        for (kid, key) in &config.keys {
+            let key = hex::decode(key).ok()
+                .and_then(|bytes| UnboundKey::new(&AES_256_GCM, &bytes).ok())
+                .ok_or_else(|| FieldEncryptionError::InvalidKey(kid.clone()))?;
+            keys.insert(kid.clone(), LessSafeKey::new(key));
+        }
+        let active = match config.active.is_empty() {
+            true => None,
+            false if keys.contains_key(&config.active) => Some(config.active.clone()),
+            false => return Err(FieldEncryptionError::UnknownActiveKey(config.active.clone())),
+        };
+        Ok(Some(FieldCipher { keys: Arc::new(keys), active }))
+    }
+
+    pub fn encrypt_event(&self, event: &mut SerializedEvent) {
+        let (kind, id, version) = (event.aggregate_type.clone(), event.aggregate_id.clone(), event.sequence);
+        let _ = event_fields(&kind, &mut event.payload, &mut event.metadata, |path, value| {
+            self.seal(&associated_data(&kind, &id, version, path), value);
+            Ok(())
+        });
+    }
+
+    pub fn decrypt_event(&self, event: &mut SerializedEvent) -> Result<(), FieldEncryptionError> {
+        self.decrypt_stored(&event.aggregate_type.clone(), &event.aggregate_id.clone(), event.sequence, &mut event.payload, &mut event.metadata)
+    }
+
+    // `decrypt_event` on the columns of an event row, for the readers of the event
+    // tables, e.g. `crate::rebuild`.
+    pub fn decrypt_stored(&self, aggregate_type: &str, aggregate_id: &str, sequence: usize, payload: &mut Value, metadata: &mut Value) -> Result<(), FieldEncryptionError> {
+        let context = format!("{} {}-{}", aggregate_type, aggregate_id, sequence);
+        event_fields(aggregate_type, payload, metadata, |path, value| {
+            self.open(&associated_data(aggregate_type, aggregate_id, sequence, path), value, &context)
+        })
+    }
+
+    pub fn encrypt_snapshot(&self, aggregate_type: &str, aggregate_id: &str, current_snapshot: usize, aggregate: &mut Value) {
+        let kind = snapshot_kind(aggregate_type);
+        for path in sensitive_paths(SENSITIVE_SNAPSHOT_FIELDS, aggregate_type) {
+            if let Some(value) = resolve(aggregate, path) {
+                self.seal(&associated_data(&kind, aggregate_id, current_snapshot, path), value);
+            }
+        }
+    }
+
+    pub fn decrypt_snapshot(&self, aggregate_type: &str, aggregate_id: &str, current_snapshot: usize, aggregate: &mut Value) -> Result<(), FieldEncryptionError> {
+        let kind = snapshot_kind(aggregate_type);
+        let context = format!("snapshot of {} {}", aggregate_type, aggregate_id);
+        for path in sensitive_paths(SENSITIVE_SNAPSHOT_FIELDS, aggregate_type) {
+            if let Some(value) = resolve(aggregate, path) {
+                self.open(&associated_data(&kind, aggregate_id, current_snapshot, path), value, &context)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Wraps the data keys of the encrypted fields anywhere in `stored` under the active
+    // key, returning whether any was under another one.
+    pub fn rewrap(&self, stored: &mut Value) -> Result<bool, FieldEncryptionError> {
+        let Some(active) = &self.active else {
+            return Ok(false);
+        };
+        if is_encrypted(stored) {
+            let mut field: EncryptedField = serde_json::from_value(stored.clone())
+                .map_err(|_| FieldEncryptionError::Malformed("a stored payload".to_string()))?;
+            if &field.kid == active {
+                return Ok(false);
+            }
+            let data_key = self.unwrap_key(&field, "a stored payload")?;
+            field.key = self.wrap_key(active, &data_key);
+            field.kid = active.clone();
+            *stored = serde_json::to_value(field).expect("encrypted fields are always serializable");
+            return Ok(true);
+        }
+        let mut rewrapped = false;
+        match stored {
+            Value::Object(map) => for value in map.values_mut() {
+                rewrapped |= self.rewrap(value)?;
+            },
+            Value::Array(values) => for value in values {
+                rewrapped |= self.rewrap(value)?;
+            },
+            _ => {}
+        }
+        Ok(rewrapped)
+    }
+
+    fn seal(&self, aad: &[u8], value: &mut Value) {
+        let Some(kid) = &self.active else {
+            return;
+        };
+        if is_encrypted(value) {
+            return;
+        }
+        let data_key: [u8; 32] = rand::random();
+        let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &data_key).expect("data keys are 32 bytes"));
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let mut ciphertext = serde_json::to_vec(value).expect("JSON values are always serializable");
+        key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(aad), &mut ciphertext)
+            .expect("fields are far below the AES-GCM size limit");
+        let field = EncryptedField {
+            encrypted: FORMAT_VERSION,
+            kid: kid.clone(),
+            key: self.wrap_key(kid, &data_key),
+            nonce: hex::encode(nonce),
+            ciphertext: BASE64_STANDARD.encode(ciphertext),
+        };
+        *value = serde_json::to_value(field).expect("encrypted fields are always serializable");
+    }
+
+    fn open(&self, aad: &[u8], value: &mut Value, context: &str) -> Result<(), FieldEncryptionError> {
+        if !is_encrypted(value) {
+            // Written before encryption was enabled.
+            return Ok(());
+        }
+        let malformed = || FieldEncryptionError::Malformed(context.to_string());
+        let field: EncryptedField = serde_json::from_value(value.clone()).map_err(|_| malformed())?;
+        if field.encrypted != FORMAT_VERSION {
+            return Err(malformed());
+        }
+        let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &self.unwrap_key(&field, context)?).map_err(|_| malformed())?);
+        let nonce: [u8; NONCE_LEN] = hex::decode(&field.nonce).ok().and_then(|nonce| nonce.try_into().ok()).ok_or_else(malformed)?;
+        let mut data = BASE64_STANDARD.decode(&field.ciphertext).map_err(|_| malformed())?;
+        let plaintext = key.open_in_place(Nonce::assume_unique_for_key(nonce), Aad::from(aad), &mut data)
+            .map_err(|_| FieldEncryptionError::Tampered(context.to_string()))?;
+        *value = serde_json::from_slice(plaintext).map_err(|_| malformed())?;
+        Ok(())
+    }
+
+    fn wrap_key(&self, kid: &str, data_key: &[u8]) -> String {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let mut wrapped = data_key.to_vec();
+        self.keys[kid].seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(kid.as_bytes()), &mut wrapped)
+            .expect("data keys are far below the AES-GCM size limit");
+        BASE64_STANDARD.encode([nonce.as_slice(), &wrapped].concat())
+    }
+
+    fn unwrap_key(&self, field: &EncryptedField, context: &str) -> Result<Vec<u8>, FieldEncryptionError> {
+        let kek = self.keys.get(&field.kid).ok_or_else(|| FieldEncryptionError::UnknownKey(field.kid.clone(), context.to_string()))?;
+        let wrapped = BASE64_STANDARD.decode(&field.key).map_err(|_| FieldEncryptionError::Malformed(context.to_string()))?;
+        if wrapped.len() < NONCE_LEN {
+            return Err(FieldEncryptionError::Malformed(context.to_string()));
+        }
+        let (nonce, wrapped) = wrapped.split_at(NONCE_LEN);
+        let nonce: [u8; NONCE_LEN] = nonce.try_into().expect("split at the nonce length");
+        let mut data_key = wrapped.to_vec();
+        let len = kek.open_in_place(Nonce::assume_unique_for_key(nonce), Aad::from(field.kid.as_bytes()), &mut data_key)
+            .map_err(|_| FieldEncryptionError::Tampered(context.to_string()))?
+            .len();
+        data_key.truncate(len);
+        Ok(data_key)
+    }
+}
+
+fn is_encrypted(value: &Value) -> bool {
+    value.get("encrypted").is_some() && value.get("kid").is_some()
+}
+
+fn associated_data(kind: &str, id: &str, version: usize, path: &str) -> Vec<u8> {
+    format!("{}\n{}\n{}\n{}\n", kind, id, version, path).into_bytes()
+}
+
+fn snapshot_kind(aggregate_type: &str) -> String {
+    format!("snapshot/{}", aggregate_type)
+}
+
+fn sensitive_paths(table: &[(&str, &'static [&'static str])], aggregate_type: &str) -> &'static [&'static str] {
+    table.iter().find(|(kind, _)| *kind == aggregate_type).map(|(_, paths)| *paths).unwrap_or_default()
+}
+
+// Calls `f` with each sensitive field of an event and its path.
+fn event_fields(
+    aggregate_type: &str,
+    payload: &mut Value,
+    metadata: &mut Value,
+    mut f: impl FnMut(&str, &mut Value) -> Result<(), FieldEncryptionError>,
+) -> Result<(), FieldEncryptionError> {
+    for path in sensitive_paths(SENSITIVE_FIELDS, aggregate_type) {
+        if let Some(value) = resolve(payload, path) {
+            f(&format!("payload{}", path), value)?;
+        }
+    }
+    for key in SENSITIVE_METADATA {
+        if let Some(value) = metadata.get_mut(*key) {
+            f(&format!("metadata/{}", key), value)?;
+        }
+    }
+    Ok(())
+}
+
+// The value at `path`, `*` stepping into the single entry of an enum variant.
+fn resolve<'a>(mut value: &'a mut Value, path: &str) -> Option<&'a mut Value> {
+    for segment in path.split('/').skip(1) {
+        value = match segment {
+            "*" => match value {
+                Value::Object(map) if map.len() == 1 => map.values_mut().next()?,
+                _ => return None,
+            },
+            key => value.get_mut(key)?,
+        };
+    }
+    Some(value)
+}
+
+pub type EncryptedCqrs<A> = CqrsFramework<A, PersistedEventStore<EncryptedEventRepository<PostgresEventRepository>, A>>;
+
+// `postgres_es::postgres_snapshot_cqrs` with the sensitive fields of its events and
+// snapshots encrypted.
+pub fn encrypted_snapshot_cqrs<A>(
+    pool: Pool<Postgres>,
+    queries: Vec<Box<dyn Query<A>>>,
+    snapshot_size: usize,
+    services: A::Services,
+    cipher: Option<FieldCipher>,
+) -> EncryptedCqrs<A>
+where
+    A: Aggregate,
+{
+    let repo = EncryptedEventRepository::new(PostgresEventRepository::new(pool), cipher);
+    CqrsFramework::new(PersistedEventStore::new_snapshot_store(repo, snapshot_size), queries, services)
+}
+
+// Encrypts the sensitive fields of the events and snapshots written through `inner` and
+// decrypts them on every read, replays included, so the aggregates and the queries
+// replayed from the store only ever see plaintext. Queries are dispatched the events as
+// committed and never see the ciphertext at all.
+pub struct EncryptedEventRepository<R> {
+    inner: R,
+    cipher: Option<FieldCipher>,
+}
+
+impl<R> EncryptedEventRepository<R> {
+    pub fn new(inner: R, cipher: Option<FieldCipher>) -> Self {
+        EncryptedEventRepository { inner, cipher }
+    }
+
+    fn decrypt(&self, mut events: Vec<SerializedEvent>) -> Result<Vec<SerializedEvent>, PersistenceError> {
+        if let Some(cipher) = &self.cipher {
+            for event in events.iter_mut() {
+                cipher.decrypt_event(event).map_err(|e| PersistenceError::DeserializationError(Box::new(e)))?;
+            }
+        }
+        Ok(events)
+    }
+
+    // Feeds the events of `stream` on decrypted. A field that does not decrypt is left as
+    // it is and fails to deserialize in the reader.
+    fn decrypt_stream<A: Aggregate>(&self, mut stream: ReplayStream) -> ReplayStream {
+        let Some(cipher) = self.cipher.clone() else {
+            return stream;
+        };
+        let (mut feed, decrypted) = ReplayStream::new(STREAM_CHANNEL_SIZE);
+        tokio::spawn(async move {
+            let upcasters: Option<Vec<Box<dyn EventUpcaster>>> = Some(vec![Box::new(DecryptingUpcaster(cipher))]);
+            while let Some(event) = stream.next::<A>(&upcasters).await {
+                let event = event.and_then(|envelope| SerializedEvent::try_from(&envelope));
+                if feed.push(event).await.is_err() {
+                    break;
+                }
+            }
+        });
+        decrypted
+    }
+}
+
+#[async_trait]
+impl<R: PersistedEventRepository> PersistedEventRepository for EncryptedEventRepository<R> {
+    async fn get_events<A: Aggregate>(&self, aggregate_id: &str) -> Result<Vec<SerializedEvent>, PersistenceError> {
+        self.decrypt(self.inner.get_events::<A>(aggregate_id).await?)
+    }
+
+    async fn get_last_events<A: Aggregate>(&self, aggregate_id: &str, last_sequence: usize) -> Result<Vec<SerializedEvent>, PersistenceError> {
+        self.decrypt(self.inner.get_last_events::<A>(aggregate_id, last_sequence).await?)
+    }
+
+    async fn get_snapshot<A: Aggregate>(&self, aggregate_id: &str) -> Result<Option<SerializedSnapshot>, PersistenceError> {
+        let Some(mut snapshot) = self.inner.get_snapshot::<A>(aggregate_id).await? else {
+            return Ok(None);
+        };
+        if let Some(cipher) = &self.cipher {
+            cipher
+                .decrypt_snapshot(&A::aggregate_type(), aggregate_id, snapshot.current_snapshot, &mut snapshot.aggregate)
+                .map_err(|e| PersistenceError::DeserializationError(Box::new(e)))?;
+        }
+        Ok(Some(snapshot))
+    }
+
+    async fn persist<A: Aggregate>(&self, events: &[SerializedEvent], snapshot_update: Option<(String, Value, usize)>) -> Result<(), PersistenceError> {
+        let Some(cipher) = &self.cipher else {
+            return self.inner.persist::<A>(events, snapshot_update).await;
+        };
+        let mut events = events.to_vec();
+        for event in events.iter_mut() {
+            cipher.encrypt_event(event);
+        }
+        let snapshot_update = snapshot_update.map(|(aggregate_id, mut aggregate, current_snapshot)| {
+            cipher.encrypt_snapshot(&A::aggregate_type(), &aggregate_id, current_snapshot, &mut aggregate);
+            (aggregate_id, aggregate, current_snapshot)
+        });
+        self.inner.persist::<A>(&events, snapshot_update).await
+    }
+
+    async fn stream_events<A: Aggregate>(&self, aggregate_id: &str) -> Result<ReplayStream, PersistenceError> {
+        Ok(self.decrypt_stream::<A>(self.inner.stream_events::<A>(aggregate_id).await?))
+    }
+
+    async fn stream_all_events<A: Aggregate>(&self) -> Result<ReplayStream, PersistenceError> {
+        Ok(self.decrypt_stream::<A>(self.inner.stream_all_events::<A>().await?))
+    }
+}
+
+// Decrypts events as a replay stream reads them, it applies upcasters before it
+// deserializes.
+struct DecryptingUpcaster(FieldCipher);
+
+impl EventUpcaster for DecryptingUpcaster {
+    fn can_upcast(&self, _event_type: &str, _event_version: &str) -> bool {
+        true
+    }
+
+    fn upcast(&self, mut event: SerializedEvent) -> SerializedEvent {
+        if let Err(e) = self.0.decrypt_event(&mut event) {
+            tracing::error!("Failed to decrypt a replayed event: {}", e);
+        }
+        event
+    }
+}
+
+// The tables holding encrypted fields, with the column ordering the rows of an
+// aggregate and the columns holding the fields.
+const REWRAP_TABLES: &[(&str, &str, &[&str])] = &[
+    ("events", "sequence", &["payload", "metadata"]),
+    ("events_archive", "sequence", &["payload", "metadata"]),
+    ("snapshots", "last_sequence", &["payload"]),
+];
+
+// Wraps the data key of every encrypted field in the event store under the active key,
+// after which the keys it was wrapped under before can leave the keyring. The
+// ciphertexts are left as they are. Returns how many rows were rewritten.
+pub async fn rewrap_stored(pool: &Pool<Postgres>, cipher: &FieldCipher) -> Result<u64, RewrapError> {
+    let mut rewritten = 0;
+    for (table, order, columns) in REWRAP_TABLES {
+        let encrypted = columns.iter().map(|column| format!("{}::text LIKE '%\"encrypted\":%'", column)).collect::<Vec<_>>().join(" OR ");
+        let select = format!(
+            "SELECT aggregate_type, aggregate_id, {order} AS position, {columns} FROM {table}
+             WHERE ({encrypted}) AND ($1::text IS NULL OR (aggregate_type, aggregate_id, {order}) > ($1, $2, $3))
+             ORDER BY aggregate_type, aggregate_id, {order}
+             LIMIT $4",
+            order = order, columns = columns.join(", "), table = table, encrypted = encrypted,
+        );
+        let mut after: Option<(String, String, i64)> = None;
+        loop {
+            let (after_type, after_id, after_position) = match after.clone() {
+                Some((aggregate_type, aggregate_id, position)) => (Some(aggregate_type), Some(aggregate_id), Some(position)),
+                None => (None, None, None),
+            };
+            let rows = sqlx::query(&select)
+                .bind(after_type)
+                .bind(after_id)
+                .bind(after_position)
+                .bind(REWRAP_PAGE_SIZE)
+                .fetch_all(pool)
+                .await?;
+            for row in &rows {
+                let key: (String, String, i64) = (row.try_get("aggregate_type")?, row.try_get("aggregate_id")?, row.try_get("position")?);
+                for column in columns.iter() {
+                    let mut stored: Value = row.try_get(*column)?;
+                    if cipher.rewrap(&mut stored)? {
+                        sqlx::query(&format!("UPDATE {} SET {} = $1 WHERE aggregate_type = $2 AND aggregate_id = $3 AND {} = $4", table, column, order))
+                            .bind(stored)
+                            .bind(&key.0)
+                            .bind(&key.1)
+                            .bind(key.2)
+                            .execute(pool)
+                            .await?;
+                        rewritten += 1;
+                    }
+                }
+                after = Some(key);
+            }
+            if (rows.len() as i64) < REWRAP_PAGE_SIZE {
+                break;
+            }
+        }
+        tracing::info!("rewrapped {}: {} columns rewritten so far", table, rewritten);
+    }
+    Ok(rewritten)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use cqrs_es::persist::{PersistedEventRepository, SerializedEvent};
+    use postgres_es::PostgresEventRepository;
+    use serde_json::{json, Value};
+    use sqlx::Row;
+    use crate::config::FieldEncryptionConfig;
+    use crate::field_encryption::{rewrap_stored, EncryptedEventRepository, FieldCipher, FieldEncryptionError};
+    use crate::transfer::aggregate::Transfer;
+    use crate::util::test_db::{random_suffix, test_pool};
+
+    const KEY_1: &str = "0303030303030303030303030303030303030303030303030303030303030303";
+    const KEY_2: &str = "0404040404040404040404040404040404040404040404040404040404040404";
+
+    fn cipher(keys: &[(&str, &str)], active: &str) -> FieldCipher {
+        let keys = keys.iter().map(|(kid, key)| (kid.to_string(), key.to_string())).collect::<BTreeMap<_, _>>();
+        FieldCipher::new(&FieldEncryptionConfig { keys, active: active.to_string() }).unwrap().unwrap()
+    }
+
+    fn opened(aggregate_id: &str, sequence: usize) -> SerializedEvent {
+        SerializedEvent {
+            aggregate_id: aggregate_id.to_string(),
+            sequence,
+            aggregate_type: "transfer".to_string(),
+            event_type: "Opened".to_string(),
+            event_version: "1.0".to_string(),
+            payload: json!({ "Opened": { "amount": 100, "description": "Rent for Jane Doe" } }),
+            metadata: json!({ "initiator": "jane@example.com", "correlation_id": "abc" }),
+        }
+    }
+
+    #[test]
+    fn test_encrypt_round_trip() {
+        let cipher = cipher(&[("k1", KEY_1)], "k1");
+        let plain = opened("T-1", 1);
+        let mut event = plain.clone();
+        cipher.encrypt_event(&mut event);
+        let stored = json!([&event.payload, &event.metadata]).to_string();
+        assert!(!stored.contains("Jane") && !stored.contains("jane@"));
+        assert_eq!(event.payload["Opened"]["amount"], 100);
+        assert_eq!(event.metadata["correlation_id"], "abc");
+
+        let mut decrypted = event.clone();
+        cipher.decrypt_event(&mut decrypted).unwrap();
+        assert_eq!((decrypted.payload, decrypted.metadata), (plain.payload.clone(), plain.metadata.clone()));
+
+        // Bound to the event it was written in.
+        let mut moved = SerializedEvent { sequence: 2, ..event };
+        assert!(matches!(cipher.decrypt_event(&mut moved), Err(FieldEncryptionError::Tampered(_))));
+        // Events written before encryption load as they are.
+        let mut unencrypted = plain.clone();
+        cipher.decrypt_event(&mut unencrypted).unwrap();
+        assert_eq!(unencrypted.payload, plain.payload);
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let mut event = opened("T-1", 1);
+        cipher(&[("k1", KEY_1)], "k1").encrypt_event(&mut event);
+
+        // k2 takes over, k1 still decrypts what was written under it.
+        let rotated = cipher(&[("k1", KEY_1), ("k2", KEY_2)], "k2");
+        let mut decrypted = event.clone();
+        rotated.decrypt_event(&mut decrypted).unwrap();
+        assert_eq!(decrypted.payload["Opened"]["description"], "Rent for Jane Doe");
+        let retired = cipher(&[("k2", KEY_2)], "k2");
+        assert!(matches!(retired.decrypt_event(&mut event.clone()), Err(FieldEncryptionError::UnknownKey(..))));
+
+        // Rewrapped onto k2, k1 can go.
+        assert!(rotated.rewrap(&mut event.payload).unwrap());
+        assert!(rotated.rewrap(&mut event.metadata).unwrap());
+        assert!(!rotated.rewrap(&mut event.payload).unwrap());
+        retired.decrypt_event(&mut event).unwrap();
+        assert_eq!(event.metadata["initiator"], "jane@example.com");
+    }
+
+    #[test]
+    fn test_keys() {
+        assert!(FieldCipher::new(&FieldEncryptionConfig::default()).unwrap().is_none());
+        let keys = BTreeMap::from([("k1".to_string(), "00ff".to_string())]);
+        assert!(matches!(FieldCipher::new(&FieldEncryptionConfig { keys, active: "k1".to_string() }), Err(FieldEncryptionError::InvalidKey(_))));
+        let keys = BTreeMap::from([("k1".to_string(), KEY_1.to_string())]);
+        assert!(matches!(FieldCipher::new(&FieldEncryptionConfig { keys, active: "k2".to_string() }), Err(FieldEncryptionError::UnknownActiveKey(_))));
+
+        // Without an active key nothing new is encrypted.
+        let mut event = opened("T-1", 1);
+        cipher(&[("k1", KEY_1)], "").encrypt_event(&mut event);
+        assert_eq!(event.payload, opened("T-1", 1).payload);
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_event_repository() {
+        let pool = test_pool();
+        let repo = EncryptedEventRepository::new(PostgresEventRepository::new(pool.clone()), Some(cipher(&[("k1", KEY_1)], "k1")));
+        let transfer_id = format!("PII-{}", random_suffix());
+        repo.persist::<Transfer>(&[opened(&transfer_id, 1)], None).await.unwrap();
+
+        let stored: Value = sqlx::query("SELECT payload FROM events WHERE aggregate_type = 'transfer' AND aggregate_id = $1")
+            .bind(&transfer_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get("payload");
+        assert!(!stored.to_string().contains("Jane"));
+        let events = repo.get_events::<Transfer>(&transfer_id).await.unwrap();
+        assert_eq!(events[0].payload, opened(&transfer_id, 1).payload);
+
+        // Rotated to k2 and rewrapped, k1 can leave the keyring.
+        assert!(rewrap_stored(&pool, &cipher(&[("k1", KEY_1), ("k2", KEY_2)], "k2")).await.unwrap() >= 2);
+        let repo = EncryptedEventRepository::new(PostgresEventRepository::new(pool.clone()), Some(cipher(&[("k2", KEY_2)], "k2")));
+        let events = repo.get_events::<Transfer>(&transfer_id).await.unwrap();
+        assert_eq!(events[0].metadata, opened(&transfer_id, 1).metadata);
+
+        sqlx::query("DELETE FROM events WHERE aggregate_type = 'transfer' AND aggregate_id = $1").bind(&transfer_id).execute(&pool).await.unwrap();
+    }
+}
@@ -0,0 +1,335 @@
+use axum::body::{to_bytes, Body, Bytes};
+use axum::extract::{Request, State};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres, Row};
+use crate::auth::{Authenticator, Principal};
+use crate::state::ApplicationState;
+
+pub const IDEMPOTENCY_KEY_HDR: &str = "Idempotency-Key";
+pub const IDEMPOTENT_REPLAYED_HDR: &str = "Idempotent-Replayed";
+
+// Payout files are the largest bodies we accept or return.
+const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+const MAX_KEY_LENGTH: usize = 255;
+// Keys are only remembered for a day, after that the same key starts a new request.
+const KEY_TTL_HOURS: i32 = 24;
+
+#[derive(Debug, thiserror::Error)]
+pub enum IdempotencyError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Malformed cached response: {0}")]
+    Payload(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Claim {
+    // First time the key is seen, the caller executes the request and records the outcome.
+    Started,
+    // The request already completed, its response is replayed as is.
+    Completed(CachedResponse),
+    // Another request with the same key is still being handled.
+    InProgress,
+    // The key was used before for a different request.
+    Mismatch,
+}
+
+// Remembers the response of every POST that carries an `Idempotency-Key` header, so a
+// client retrying after a timeout gets the original answer instead of executing the
+// command again (which would typically fail with a 400 `DuplicateTransaction`).
+#[derive(Clone)]
+pub struct IdempotencyStore {
+    pool: Pool<Postgres>,
+}
+
+impl IdempotencyStore {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        IdempotencyStore { pool }
+    }
+
+    pub async fn claim(&self, key: &str, fingerprint: &str) -> Result<Claim, IdempotencyError> {
+        sqlx::query("DELETE FROM idempotency_key WHERE idempotency_key = $1 AND created_at < now() - make_interval(hours => $2)")
+            .bind(key)
+            .bind(KEY_TTL_HOURS)
+            .execute(&self.pool)
+            .await?;
+        let inserted = sqlx::query(
+            "INSERT INTO idempotency_key (idempotency_key, fingerprint, created_at) VALUES ($1, $2, now())
+             ON CONFLICT (idempotency_key) DO NOTHING",
+        )
+            .bind(key)
+            .bind(fingerprint)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        if inserted == 1 {
+            return Ok(Claim::Started);
+        }
+        let Some(row) = sqlx::query("SELECT fingerprint, status, headers, body FROM idempotency_key WHERE idempotency_key = $1")
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?
+        else {
+            // The other request gave up on the key in between, treat it as still busy
+            // rather than racing it.
+            return Ok(Claim::InProgress);
+        };
+        let existing: String = row.try_get("fingerprint")?;
+        if existing != fingerprint {
+            return Ok(Claim::Mismatch);
+        }
+        let Some(status) = row.try_get::<Option<i16>, _>("status")? else {
+            return Ok(Claim::InProgress);
+        };
+        let headers: serde_json::Value = row.try_get("headers")?;
+        Ok(Claim::Completed(CachedResponse {
+            status: status as u16,
+            headers: serde_json::from_value(headers)?,
+            body: row.try_get::<Option<Vec<u8>>, _>("body")?.unwrap_or_default(),
+        }))
+    }
+
+    pub async fn complete(&self, key: &str, response: &CachedResponse) -> Result<(), IdempotencyError> {
+        sqlx::query("UPDATE idempotency_key SET status = $2, headers = $3, body = $4 WHERE idempotency_key = $1")
+            .bind(key)
+            .bind(response.status as i16)
+            .bind(serde_json::to_value(&response.headers)?)
+            .bind(&response.body)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // Forgets a key whose request failed on our side, so that the retry runs again.
+    pub async fn release(&self, key: &str) -> Result<(), IdempotencyError> {
+        sqlx::query("DELETE FROM idempotency_key WHERE idempotency_key = $1 AND status IS NULL")
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+// Binds a key to the request it was first used with, so reusing a key for a different
+// command is reported instead of silently replaying an unrelated response. That includes
+// who sent it: the responses to admin requests are only replayed to the same principal.
+pub fn fingerprint(principal: Option<&Principal>, method: &Method, path: &str, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    if let Some(Principal(principal)) = principal {
+        hasher.update(principal.as_bytes());
+        hasher.update([0u8]);
+    }
+    hasher.update(method.as_str().as_bytes());
+    hasher.update([0u8]);
+    hasher.update(path.as_bytes());
+    hasher.update([0u8]);
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+pub async fn idempotency_layer(State(state): State<ApplicationState>, request: Request, next: Next) -> Response {
+    idempotent(&state.idempotency, &state.authenticator, request, next).await
+}
+
+// The key is claimed before the admin routes check the credentials, so whoever the
+// request authorizes for, if anyone, is part of its fingerprint.
+async fn idempotent(store: &IdempotencyStore, authenticator: &Authenticator, request: Request, next: Next) -> Response {
+    if request.method() != Method::POST {
+        return next.run(request).await;
+    }
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY_HDR) else {
+        return next.run(request).await;
+    };
+    let key = match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => key.to_string(),
+        _ => return (StatusCode::BAD_REQUEST, format!("{} must be 1 to {} visible ASCII characters", IDEMPOTENCY_KEY_HDR, MAX_KEY_LENGTH)).into_response(),
+    };
+
+    let (parts, body) = request.into_parts();
+    let body = match to_bytes(body, MAX_BODY_BYTES).await {
+        Ok(body) => body,
+        Err(err) => return (StatusCode::PAYLOAD_TOO_LARGE, err.to_string()).into_response(),
+    };
+    let principal = authenticator.authorize(&parts.headers, chrono::Utc::now().timestamp()).ok();
+    let fingerprint = fingerprint(principal.as_ref(), &parts.method, parts.uri.path(), &body);
+    match store.claim(&key, &fingerprint).await {
+        Ok(Claim::Started) => {},
+        Ok(Claim::Completed(cached)) => return replay(cached),
+        Ok(Claim::InProgress) => {
+            return (StatusCode::CONFLICT, format!("A request with {} {} is still in progress", IDEMPOTENCY_KEY_HDR, key)).into_response();
+        },
+        Ok(Claim::Mismatch) => {
+            return (StatusCode::UNPROCESSABLE_ENTITY, format!("{} {} was already used for a different request", IDEMPOTENCY_KEY_HDR, key)).into_response();
+        },
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        },
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    // Server errors are not cached: the command may not have run, so a retry should try
+    // again. Neither are requests throttled by the rate limits, quotas or bulkheads, a
+    // retry after their Retry-After would otherwise only get the 429 back.
+    if response.status().is_server_error() || response.status() == StatusCode::TOO_MANY_REQUESTS {
+        if let Err(err) = store.release(&key).await {
+            tracing::error!("Failed to release idempotency key {}: {}", key, err);
+        }
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, MAX_BODY_BYTES).await {
+        Ok(body) => body,
+        Err(err) => {
+            tracing::error!("Failed to buffer response for idempotency key {}: {}", key, err);
+            if let Err(err) = store.release(&key).await {
+                tracing::error!("Failed to release idempotency key {}: {}", key, err);
+            }
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let cached = CachedResponse {
+        status: parts.status.as_u16(),
+        headers: headers_to_vec(&parts.headers),
+        body: body.to_vec(),
+    };
+    if let Err(err) = store.complete(&key, &cached).await {
+        tracing::error!("Failed to record response for idempotency key {}: {}", key, err);
+    }
+    Response::from_parts(parts, Body::from(body))
+}
+
+fn headers_to_vec(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+fn replay(cached: CachedResponse) -> Response {
+    let mut response = Response::new(Body::from(Bytes::from(cached.body)));
+    *response.status_mut() = StatusCode::from_u16(cached.status).unwrap_or(StatusCode::OK);
+    for (name, value) in cached.headers {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+            response.headers_mut().append(name, value);
+        }
+    }
+    response.headers_mut().insert(IDEMPOTENT_REPLAYED_HDR, HeaderValue::from_static("true"));
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use axum::body::Body;
+    use axum::extract::{Request, State};
+    use axum::http::{Method, StatusCode};
+    use axum::middleware::{from_fn_with_state, Next};
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::post;
+    use axum::Router;
+    use rand::random;
+    use tower::ServiceExt;
+    use crate::auth::{Authenticator, API_KEY_HDR};
+    use crate::config::AuthConfig;
+    use crate::idempotency::{fingerprint, idempotent, CachedResponse, Claim, IdempotencyStore, IDEMPOTENCY_KEY_HDR, IDEMPOTENT_REPLAYED_HDR};
+    use crate::util::test_db::test_pool;
+
+    #[tokio::test]
+    async fn test_claim_replays_completed_response() {
+        let pool = test_pool();
+        let store = IdempotencyStore::new(pool);
+        let key = hex::encode(random::<[u8; 16]>());
+        let request = fingerprint(None, &Method::POST, "/account/ACCT-0001", b"{}");
+
+        assert_eq!(store.claim(&key, &request).await.unwrap(), Claim::Started);
+        assert_eq!(store.claim(&key, &request).await.unwrap(), Claim::InProgress);
+        let response = CachedResponse { status: 204, headers: vec![], body: vec![] };
+        store.complete(&key, &response).await.unwrap();
+        assert_eq!(store.claim(&key, &request).await.unwrap(), Claim::Completed(response));
+
+        let other = fingerprint(None, &Method::POST, "/account/ACCT-0002", b"{}");
+        assert_eq!(store.claim(&key, &other).await.unwrap(), Claim::Mismatch);
+    }
+
+    #[tokio::test]
+    async fn test_release_allows_retry() {
+        let pool = test_pool();
+        let store = IdempotencyStore::new(pool);
+        let key = hex::encode(random::<[u8; 16]>());
+        let request = fingerprint(None, &Method::POST, "/order/1", b"{}");
+
+        assert_eq!(store.claim(&key, &request).await.unwrap(), Claim::Started);
+        store.release(&key).await.unwrap();
+        assert_eq!(store.claim(&key, &request).await.unwrap(), Claim::Started);
+    }
+
+    async fn layer(State((store, authenticator)): State<(IdempotencyStore, Authenticator)>, request: Request, next: Next) -> Response {
+        idempotent(&store, &authenticator, request, next).await
+    }
+
+    fn authenticator() -> Authenticator {
+        Authenticator::new(&AuthConfig { api_keys: vec!["operator-key".to_string()], ..Default::default() })
+    }
+
+    #[tokio::test]
+    async fn test_throttled_request_runs_on_retry() {
+        let store = IdempotencyStore::new(test_pool());
+        let key = hex::encode(random::<[u8; 16]>());
+        // Throttled the first time, like a rate limit inside the idempotency layer.
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let router = Router::new()
+            .route("/order/1", post(move || async move {
+                match counted.fetch_add(1, Ordering::SeqCst) {
+                    0 => StatusCode::TOO_MANY_REQUESTS.into_response(),
+                    _ => StatusCode::NO_CONTENT.into_response(),
+                }
+            }))
+            .layer(from_fn_with_state((store, authenticator()), layer));
+        let request = || Request::post("/order/1").header(IDEMPOTENCY_KEY_HDR, &key).body(Body::from("{}")).unwrap();
+
+        assert_eq!(router.clone().oneshot(request()).await.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(router.clone().oneshot(request()).await.unwrap().status(), StatusCode::NO_CONTENT);
+        // Only the response of the request that ran is replayed.
+        assert_eq!(router.oneshot(request()).await.unwrap().status(), StatusCode::NO_CONTENT);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_replays_only_to_same_principal() {
+        let store = IdempotencyStore::new(test_pool());
+        let key = hex::encode(random::<[u8; 16]>());
+        let router = Router::new()
+            .route("/admin/approval", post(|| async { "approval of ACCT-0001" }))
+            .layer(from_fn_with_state((store, authenticator()), layer));
+        let request = |api_key: Option<&str>| {
+            let request = Request::post("/admin/approval").header(IDEMPOTENCY_KEY_HDR, &key);
+            let request = match api_key {
+                Some(api_key) => request.header(API_KEY_HDR, api_key),
+                None => request,
+            };
+            request.body(Body::from("{}")).unwrap()
+        };
+
+        assert_eq!(router.clone().oneshot(request(Some("operator-key"))).await.unwrap().status(), StatusCode::OK);
+        let replayed = router.clone().oneshot(request(Some("operator-key"))).await.unwrap();
+        assert_eq!(replayed.status(), StatusCode::OK);
+        assert!(replayed.headers().contains_key(IDEMPOTENT_REPLAYED_HDR));
+        // The same key, path and body without the credentials, or with wrong ones, get
+        // nothing of the response.
+        assert_eq!(router.clone().oneshot(request(None)).await.unwrap().status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(router.oneshot(request(Some("guessed-key"))).await.unwrap().status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
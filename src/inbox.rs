@@ -0,0 +1,512 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use cqrs_es::{Aggregate, AggregateError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{Pool, Postgres, Row};
+use utoipa::ToSchema;
+use crate::background::{Background, Shutdown};
+use crate::account::aggregate::Account;
+use crate::account::commands::AccountCommand;
+use crate::account::events::AccountError;
+use crate::bulkhead::BulkheadPermit;
+use crate::command_extractor::command_name;
+use crate::config::InboxConfig;
+use crate::retry::retry_conflicts;
+use crate::route_handler::{admit_account_command, admit_transfer_command, execute_transfer_command, transfer_txid, try_claim_txid};
+use crate::state::ApplicationState;
+use crate::transfer::aggregate::Transfer;
+use crate::transfer::commands::TransferCommand;
+use crate::util::clock;
+use crate::util::metadata::COMMAND_KEY;
+
+#[derive(Debug, thiserror::Error)]
+pub enum InboxError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Invalid batch: {0}")]
+    Invalid(String),
+}
+
+// A command as it would be sent to its route, `/account/{account_id}` or
+// `/transfer/{transfer_id}`, and checked the same way.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub enum InboxCommand {
+    Account {
+        account_id: String,
+        command: AccountCommand,
+    },
+    Transfer {
+        transfer_id: String,
+        command: TransferCommand,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct InboxEntry {
+    // Unique within the batch, named by `depends_on`.
+    pub command_id: String,
+    pub command: InboxCommand,
+    // Not run before then, nor is any command after it in the batch.
+    #[serde(default)]
+    pub not_before: u64,
+    // Earlier commands of the batch that must have succeeded, otherwise it is skipped.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum InboxStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+    // A command it depends on did not succeed.
+    Skipped,
+}
+
+impl InboxStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            InboxStatus::Pending => "Pending",
+            InboxStatus::Running => "Running",
+            InboxStatus::Done => "Done",
+            InboxStatus::Failed => "Failed",
+            InboxStatus::Skipped => "Skipped",
+        }
+    }
+
+    fn parse(status: &str) -> Self {
+        match status {
+            "Running" => InboxStatus::Running,
+            "Done" => InboxStatus::Done,
+            "Failed" => InboxStatus::Failed,
+            "Skipped" => InboxStatus::Skipped,
+            _ => InboxStatus::Pending,
+        }
+    }
+
+    fn is_final(&self) -> bool {
+        matches!(self, InboxStatus::Done | InboxStatus::Failed | InboxStatus::Skipped)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct InboxEntryStatus {
+    pub command_id: String,
+    pub status: InboxStatus,
+    // Why it failed or was skipped.
+    pub error: Option<String>,
+    pub not_before: u64,
+    pub depends_on: Vec<String>,
+    pub executed_at: Option<u64>,
+}
+
+// One stored command of a batch, in the order submitted.
+struct Queued {
+    command_id: String,
+    command: Value,
+    metadata: Value,
+    not_before: u64,
+    depends_on: Vec<String>,
+    status: InboxStatus,
+    claimed_at: Option<u64>,
+}
+
+// Commands clients queue up while offline, run in the background in the order they were
+// submitted, each once its `not_before` passed and only if the commands it depends on
+// succeeded. A batch is stored once, resending it changes nothing, so a client on a
+// flaky connection can retry the upload and poll the status of every command. Commands
+// are checked as on their routes when submitted and again when run, when the quotas,
+// rate limits and bulkheads of their routes apply too; their txids are claimed when run.
+// A command left running by a node that stopped is run again after `stall_secs`, its
+// txid keeps a transaction from applying twice.
+#[derive(Clone)]
+pub struct CommandInbox {
+    pool: Pool<Postgres>,
+    config: InboxConfig,
+}
+
+impl CommandInbox {
+    pub fn new(pool: Pool<Postgres>, config: InboxConfig) -> Self {
+        CommandInbox { pool, config }
+    }
+
+    pub fn spawn(&self, background: &Background, state: ApplicationState) {
+        let inbox = self.clone();
+        background.spawn(|shutdown| async move { inbox.run(state, shutdown).await });
+    }
+
+    async fn run(&self, state: ApplicationState, mut shutdown: Shutdown) {
+        loop {
+            if let Err(e) = self.sweep(&state).await {
+                tracing::error!("Failed to run the command inbox: {}", e);
+            }
+            if !shutdown.pause(Duration::from_secs(self.config.sweep_interval_secs.max(1))).await {
+                break;
+            }
+        }
+    }
+
+    // Stores the batch, unless it was stored before, and answers with its statuses.
+    pub async fn submit(&self, batch_id: &str, entries: Vec<InboxEntry>, metadata: HashMap<String, String>) -> Result<Vec<InboxEntryStatus>, InboxError> {
+        validate(&entries, self.config.max_batch_len)?;
+        for entry in &entries {
+            admit(&entry.command).map_err(|e| InboxError::Invalid(format!("command {}: {}", entry.command_id, e)))?;
+        }
+        let mut tx = self.pool.begin().await?;
+        let stored = sqlx::query("SELECT 1 FROM command_inbox WHERE batch_id = $1 LIMIT 1").bind(batch_id).fetch_optional(&mut *tx).await?;
+        if stored.is_none() {
+            let metadata = serde_json::to_value(metadata).expect("metadata serializes");
+            let now = clock::now();
+            for (position, entry) in entries.into_iter().enumerate() {
+                sqlx::query(
+                    "INSERT INTO command_inbox (batch_id, position, command_id, command, metadata, not_before, depends_on, status, submitted_at)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, 'Pending', $8)
+                     ON CONFLICT (batch_id, command_id) DO NOTHING",
+                )
+                    .bind(batch_id)
+                    .bind(position as i32)
+                    .bind(&entry.command_id)
+                    .bind(serde_json::to_value(&entry.command).expect("commands serialize"))
+                    .bind(&metadata)
+                    .bind(entry.not_before as i64)
+                    .bind(&entry.depends_on)
+                    .bind(now as i64)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+        tx.commit().await?;
+        self.status(batch_id).await
+    }
+
+    // Every command of the batch in the order submitted, none for an unknown batch.
+    pub async fn status(&self, batch_id: &str) -> Result<Vec<InboxEntryStatus>, InboxError> {
+        sqlx::query("SELECT command_id, status, error, not_before, depends_on, executed_at FROM command_inbox WHERE batch_id = $1 ORDER BY position")
+            .bind(batch_id)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|row| Ok(InboxEntryStatus {
+                command_id: row.try_get("command_id")?,
+                status: InboxStatus::parse(row.try_get("status")?),
+                error: row.try_get("error")?,
+                not_before: row.try_get::<i64, _>("not_before")? as u64,
+                depends_on: row.try_get("depends_on")?,
+                executed_at: row.try_get::<Option<i64>, _>("executed_at")?.map(|at| at as u64),
+            }))
+            .collect()
+    }
+
+    async fn sweep(&self, state: &ApplicationState) -> Result<(), InboxError> {
+        let now = clock::now();
+        let stalled = now.saturating_sub(self.config.stall_secs) as i64;
+        let batches: Vec<String> = sqlx::query(
+            "SELECT DISTINCT batch_id FROM command_inbox
+             WHERE (status = 'Pending' AND not_before <= $1) OR (status = 'Running' AND claimed_at <= $2)",
+        )
+            .bind(now as i64)
+            .bind(stalled)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|row| row.try_get("batch_id"))
+            .collect::<Result<_, _>>()?;
+        for batch_id in batches {
+            if let Err(e) = self.run_batch(state, &batch_id).await {
+                tracing::error!("Failed to run inbox batch {}: {}", batch_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn run_batch(&self, state: &ApplicationState, batch_id: &str) -> Result<(), InboxError> {
+        let mut queued = self.queued(batch_id).await?;
+        loop {
+            let now = clock::now();
+            let Some((index, skip)) = next_step(&queued, now, self.config.stall_secs) else {
+                return Ok(());
+            };
+            let entry = &queued[index];
+            // Held back commands keep their place, the batch is tried again on the next sweep.
+            let admitted = match skip {
+                Some(reason) => Err((InboxStatus::Skipped, reason)),
+                None => match admit_run(state, &entry.command).await {
+                    Ok(admitted) => Ok(admitted),
+                    Err(Admission::Rejected(reason)) => Err((InboxStatus::Failed, reason)),
+                    Err(Admission::Deferred(reason)) => {
+                        tracing::debug!("Inbox command {} of {} held back: {}", entry.command_id, batch_id, reason);
+                        return Ok(());
+                    }
+                },
+            };
+            // Another node may be running the batch, the claim decides.
+            let claimed = sqlx::query(
+                "UPDATE command_inbox SET status = 'Running', claimed_at = $3
+                 WHERE batch_id = $1 AND command_id = $2 AND status = $4 AND claimed_at IS NOT DISTINCT FROM $5",
+            )
+                .bind(batch_id)
+                .bind(&entry.command_id)
+                .bind(now as i64)
+                .bind(entry.status.as_str())
+                .bind(entry.claimed_at.map(|at| at as i64))
+                .execute(&self.pool)
+                .await?
+                .rows_affected();
+            if claimed == 0 {
+                return Ok(());
+            }
+            // Claimed again after it stalled, it may have gone through the first time.
+            let rerun = entry.status == InboxStatus::Running;
+            let (status, error) = match admitted {
+                Err((status, reason)) => (status, Some(reason)),
+                Ok((command, _permit)) => match execute(state, command, &entry.metadata, rerun).await {
+                    Ok(()) => (InboxStatus::Done, None),
+                    Err(e) => (InboxStatus::Failed, Some(e)),
+                },
+            };
+            sqlx::query("UPDATE command_inbox SET status = $3, error = $4, executed_at = $5 WHERE batch_id = $1 AND command_id = $2")
+                .bind(batch_id)
+                .bind(&entry.command_id)
+                .bind(status.as_str())
+                .bind(&error)
+                .bind(clock::now() as i64)
+                .execute(&self.pool)
+                .await?;
+            queued[index].status = status;
+        }
+    }
+
+    async fn queued(&self, batch_id: &str) -> Result<Vec<Queued>, InboxError> {
+        sqlx::query("SELECT command_id, command, metadata, not_before, depends_on, status, claimed_at FROM command_inbox WHERE batch_id = $1 ORDER BY position")
+            .bind(batch_id)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|row| Ok(Queued {
+                command_id: row.try_get("command_id")?,
+                command: row.try_get("command")?,
+                metadata: row.try_get("metadata")?,
+                not_before: row.try_get::<i64, _>("not_before")? as u64,
+                depends_on: row.try_get("depends_on")?,
+                status: InboxStatus::parse(row.try_get("status")?),
+                claimed_at: row.try_get::<Option<i64>, _>("claimed_at")?.map(|at| at as u64),
+            }))
+            .collect()
+    }
+}
+
+fn validate(entries: &[InboxEntry], max_batch_len: usize) -> Result<(), InboxError> {
+    if entries.is_empty() || entries.len() > max_batch_len {
+        return Err(InboxError::Invalid(format!("a batch carries 1 to {} commands", max_batch_len)));
+    }
+    let mut earlier = HashSet::new();
+    for entry in entries {
+        if entry.command_id.is_empty() {
+            return Err(InboxError::Invalid("command_id must not be empty".to_string()));
+        }
+        if let Some(dependency) = entry.depends_on.iter().find(|dependency| !earlier.contains(dependency)) {
+            return Err(InboxError::Invalid(format!("command {} depends on {}, which does not come before it", entry.command_id, dependency)));
+        }
+        if !earlier.insert(&entry.command_id) {
+            return Err(InboxError::Invalid(format!("command_id {} is not unique", entry.command_id)));
+        }
+    }
+    Ok(())
+}
+
+// The first command of the batch that has not finished, if it is due, with the reason
+// to skip it when a command it depends on did not succeed. None while it waits for its
+// `not_before` or runs on another node.
+fn next_step(queued: &[Queued], now: u64, stall_secs: u64) -> Option<(usize, Option<String>)> {
+    let index = queued.iter().position(|entry| !entry.status.is_final())?;
+    let entry = &queued[index];
+    let stalled = entry.claimed_at.map(|at| at + stall_secs <= now).unwrap_or(true);
+    if entry.not_before > now || (entry.status == InboxStatus::Running && !stalled) {
+        return None;
+    }
+    let statuses: HashMap<&str, InboxStatus> = queued[..index].iter().map(|earlier| (earlier.command_id.as_str(), earlier.status)).collect();
+    let failed = entry.depends_on.iter().find(|dependency| statuses.get(dependency.as_str()) != Some(&InboxStatus::Done));
+    Some((index, failed.map(|dependency| format!("{} did not succeed", dependency))))
+}
+
+fn admit(command: &InboxCommand) -> Result<(), String> {
+    match command {
+        InboxCommand::Account { account_id, command } => admit_account_command(account_id, command),
+        InboxCommand::Transfer { transfer_id, command } => admit_transfer_command(transfer_id, command),
+    }
+        .map_err(|(_, message)| message)
+}
+
+// Why a command due in the inbox is not run.
+enum Admission {
+    // Over a quota, a rate limit or a bulkhead, tried again on a later sweep.
+    Deferred(String),
+    Rejected(String),
+}
+
+// The account a command is charged to by the quotas and rate limits, as on its route.
+fn charged_account(command: &InboxCommand) -> Option<&str> {
+    match command {
+        InboxCommand::Account { account_id, .. } => Some(account_id),
+        InboxCommand::Transfer { command: TransferCommand::Open { from_account, .. } | TransferCommand::SwapOpen { from_account, .. }, .. } => Some(from_account),
+        InboxCommand::Transfer { .. } => None,
+    }
+}
+
+// Lets a command through the checks of the layers and the bulkhead of its route, holding
+// its turn in the bulkhead until the permit is dropped. Commands of accounts another
+// region owns were forwarded there with their batch, they are not run here.
+async fn admit_run(state: &ApplicationState, command: &Value) -> Result<(InboxCommand, BulkheadPermit), Admission> {
+    let command: InboxCommand = serde_json::from_value(command.clone()).map_err(|e| Admission::Rejected(e.to_string()))?;
+    admit(&command).map_err(Admission::Rejected)?;
+    let router = &state.router;
+    if router.is_enabled() {
+        let owner = match &command {
+            InboxCommand::Account { account_id, .. } => Some(router.owner_of_account(account_id)),
+            InboxCommand::Transfer { .. } => {
+                let body = serde_json::to_vec(&command).expect("commands serialize");
+                router.owner_of_body(&body).map_err(Admission::Rejected)?
+            }
+        };
+        if let Some(owner) = owner.filter(|owner| *owner != router.region()) {
+            return Err(Admission::Rejected(format!("The command belongs to region {}", owner)));
+        }
+    }
+    let account_id = charged_account(&command);
+    if let (Some(account_id), true) = (account_id, state.quotas.is_enabled()) {
+        state.quotas.check(account_id).await.map_err(|e| Admission::Deferred(e.to_string()))?;
+    }
+    state.rate_limiter.acquire(account_id).map_err(|e| Admission::Deferred(e.to_string()))?;
+    let aggregate_type = match &command {
+        InboxCommand::Account { .. } => Account::aggregate_type(),
+        InboxCommand::Transfer { .. } => Transfer::aggregate_type(),
+    };
+    let permit = state.bulkheads.enter(&aggregate_type).await.map_err(|e| Admission::Deferred(e.to_string()))?;
+    Ok((command, permit))
+}
+
+async fn execute(state: &ApplicationState, command: InboxCommand, metadata: &Value, rerun: bool) -> Result<(), String> {
+    let mut metadata: HashMap<String, String> = serde_json::from_value(metadata.clone()).map_err(|e| e.to_string())?;
+    match command {
+        InboxCommand::Account { account_id, command } => {
+            if let Some(name) = command_name(&serde_json::to_value(&command).expect("commands serialize")) {
+                metadata.insert(COMMAND_KEY.to_string(), name);
+            }
+            if let AccountCommand::Transaction { txid, .. } = &command {
+                try_claim_txid(state, txid, &format!("account:{}", account_id)).await.map_err(|(_, message)| message)?;
+            }
+            let execute = || state.account_cqrs.execute_with_metadata(&account_id, command.clone(), metadata.clone());
+            match retry_conflicts(&state.conflict_retry, execute).await {
+                Ok(_) => Ok(()),
+                // The run that stalled applied the transaction already.
+                Err(AggregateError::UserError(AccountError::DuplicateTransaction(_))) if rerun => Ok(()),
+                Err(err) => {
+                    state.error_metrics.record::<Account>(&err);
+                    Err(err.to_string())
+                }
+            }
+        }
+        InboxCommand::Transfer { transfer_id, command } => {
+            if let Some(name) = command_name(&serde_json::to_value(&command).expect("commands serialize")) {
+                metadata.insert(COMMAND_KEY.to_string(), name);
+            }
+            if let Some(txid) = transfer_txid(&command) {
+                try_claim_txid(state, txid, &format!("transfer:{}", transfer_id)).await.map_err(|(_, message)| message)?;
+            }
+            execute_transfer_command(state, &transfer_id, &command, &metadata).await.map_err(|err| {
+                state.error_metrics.record::<Transfer>(&err);
+                err.to_string()
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::Value;
+    use crate::account::commands::{AccountCommand, LifecycleCommand};
+    use crate::inbox::{admit, charged_account, next_step, validate, InboxCommand, InboxEntry, InboxStatus, Queued};
+    use crate::transfer::commands::TransferCommand;
+    use crate::util::types::ByteArray32;
+
+    fn entry(command_id: &str, depends_on: &[&str]) -> InboxEntry {
+        InboxEntry {
+            command_id: command_id.to_string(),
+            command: InboxCommand::Account { account_id: "ACCT-0001".to_string(), command: AccountCommand::Lifecycle(LifecycleCommand::Disable) },
+            not_before: 0,
+            depends_on: depends_on.iter().map(|id| id.to_string()).collect(),
+        }
+    }
+
+    fn queued(command_id: &str, status: InboxStatus, not_before: u64, depends_on: &[&str]) -> Queued {
+        Queued {
+            command_id: command_id.to_string(),
+            command: Value::Null,
+            metadata: Value::Null,
+            not_before,
+            depends_on: depends_on.iter().map(|id| id.to_string()).collect(),
+            status,
+            claimed_at: None,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&[entry("a", &[]), entry("b", &["a"])], 10).is_ok());
+        assert!(validate(&[], 10).is_err());
+        assert!(validate(&[entry("a", &[]), entry("b", &[])], 1).is_err());
+        assert!(validate(&[entry("a", &[]), entry("a", &[])], 10).is_err());
+        // Dependencies come first, which rules out cycles.
+        assert!(validate(&[entry("a", &["b"]), entry("b", &[])], 10).is_err());
+        assert!(validate(&[entry("a", &["a"])], 10).is_err());
+    }
+
+    #[test]
+    fn test_admit_rejects_refunds() {
+        // A refund id picked by the client could reuse a txid of one of the accounts.
+        let refund = TransferCommand::Refund { refund_id: ByteArray32([9; 32]), amount: 4, reason: String::new() };
+        assert!(admit(&InboxCommand::Transfer { transfer_id: ByteArray32([7; 32]).hex(), command: refund }).is_err());
+        assert!(admit(&InboxCommand::Transfer { transfer_id: ByteArray32([7; 32]).hex(), command: TransferCommand::Continue }).is_ok());
+    }
+
+    #[test]
+    fn test_next_step() {
+        let batch = vec![
+            queued("a", InboxStatus::Done, 0, &[]),
+            queued("b", InboxStatus::Failed, 0, &[]),
+            queued("c", InboxStatus::Pending, 100, &["a"]),
+            queued("d", InboxStatus::Pending, 0, &["b"]),
+        ];
+        // Until `c` is due, `d` waits behind it.
+        assert_eq!(next_step(&batch, 99, 300), None);
+        assert_eq!(next_step(&batch, 100, 300), Some((2, None)));
+        let mut batch = batch;
+        batch[2].status = InboxStatus::Done;
+        assert_eq!(next_step(&batch, 100, 300), Some((3, Some("b did not succeed".to_string()))));
+
+        // Running elsewhere, until it stalled.
+        batch[3].status = InboxStatus::Running;
+        batch[3].claimed_at = Some(100);
+        assert_eq!(next_step(&batch, 399, 300), None);
+        assert_eq!(next_step(&batch, 400, 300).map(|(index, _)| index), Some(3));
+    }
+
+    #[test]
+    fn test_charged_account() {
+        assert_eq!(charged_account(&entry("a", &[]).command), Some("ACCT-0001"));
+        let open = TransferCommand::Open {
+            transfer_id: Default::default(),
+            from_account: "ACCT-0002".to_string(),
+            to_account: "ACCT-0003".to_string(),
+            asset: "USD".to_string(),
+            amount: 1,
+            timestamp: 0,
+            description: String::new(),
+        };
+        let transfer = |command| InboxCommand::Transfer { transfer_id: "T".to_string(), command };
+        assert_eq!(charged_account(&transfer(open)), Some("ACCT-0002"));
+        assert_eq!(charged_account(&transfer(TransferCommand::Continue)), None);
+    }
+}
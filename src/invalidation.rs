@@ -0,0 +1,178 @@
+use std::time::Duration;
+use async_trait::async_trait;
+use cqrs_es::persist::{PersistenceError, SerializedEvent};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use sqlx::{Pool, Postgres, Transaction};
+use tokio::sync::broadcast;
+use crate::commit_hooks::{persistence_error, CommitHook};
+
+// Invalidations buffered for a slow cache before it starts missing some.
+const BUS_CAPACITY: usize = 1024;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// An aggregate changed on some node: cached copies older than `version` are stale.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Invalidation {
+    pub aggregate_type: String,
+    pub aggregate_id: String,
+    // Sequence of the last committed event.
+    pub version: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Invalidated {
+    One(Invalidation),
+    // Notifications may have been missed (the listener reconnected or the subscriber
+    // lagged behind), every cached entry has to be dropped.
+    All,
+}
+
+// Publishes an `Invalidation` on the Postgres channel for every committed batch of
+// events, so the caches of every node, this one included, hear about it. Notified in the
+// transaction committing the events, which Postgres delivers only once it commits.
+pub struct InvalidationHook {
+    channel: String,
+}
+
+impl InvalidationHook {
+    pub fn new(channel: String) -> Self {
+        InvalidationHook { channel }
+    }
+}
+
+#[async_trait]
+impl CommitHook for InvalidationHook {
+    async fn append(&self, tx: &mut Transaction<'_, Postgres>, events: &[SerializedEvent]) -> Result<(), PersistenceError> {
+        let Some(last) = events.last() else {
+            return Ok(());
+        };
+        let invalidation = Invalidation {
+            aggregate_type: last.aggregate_type.clone(),
+            aggregate_id: last.aggregate_id.clone(),
+            version: last.sequence,
+        };
+        let payload = serde_json::to_string(&invalidation).expect("invalidation is serializable");
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(&self.channel)
+            .bind(payload)
+            .execute(&mut **tx)
+            .await
+            .map_err(persistence_error)?;
+        Ok(())
+    }
+}
+
+// Fans the invalidations published by every node out to the caches of this node.
+#[derive(Clone)]
+pub struct InvalidationBus {
+    sender: broadcast::Sender<Invalidated>,
+}
+
+impl InvalidationBus {
+    // Listens on `channel` until the server shuts down, reconnecting when the
+    // connection is lost.
+    pub fn listen(pool: Pool<Postgres>, channel: String) -> Self {
+        let (sender, _) = broadcast::channel(BUS_CAPACITY);
+        let bus = InvalidationBus { sender };
+        let forwarder = bus.clone();
+        tokio::spawn(async move { forwarder.run(pool, channel).await });
+        bus
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Invalidated> {
+        self.sender.subscribe()
+    }
+
+    async fn run(&self, pool: Pool<Postgres>, channel: String) {
+        loop {
+            if let Err(e) = self.forward(&pool, &channel).await {
+                tracing::error!("Invalidation listener on {} failed: {}", channel, e);
+            }
+            // Whatever was published while we were not listening is lost.
+            let _ = self.sender.send(Invalidated::All);
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn forward(&self, pool: &Pool<Postgres>, channel: &str) -> Result<(), sqlx::Error> {
+        let mut listener = PgListener::connect_with(pool).await?;
+        listener.listen(channel).await?;
+        // `try_recv` returns `None` when the connection was lost, it reconnects on the next call.
+        while let Some(notification) = listener.try_recv().await? {
+            match serde_json::from_str::<Invalidation>(notification.payload()) {
+                // Nobody listening yet is fine.
+                Ok(invalidation) => {
+                    let _ = self.sender.send(Invalidated::One(invalidation));
+                }
+                Err(e) => tracing::warn!("Ignoring malformed invalidation {:?}: {}", notification.payload(), e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use cqrs_es::persist::SerializedEvent;
+    use serde_json::json;
+    use sqlx::{Pool, Postgres};
+    use crate::commit_hooks::CommitHook;
+    use crate::invalidation::{Invalidated, Invalidation, InvalidationBus, InvalidationHook};
+    use crate::util::test_db::{random_suffix, test_pool};
+
+    fn event(aggregate_id: &str, sequence: usize) -> SerializedEvent {
+        SerializedEvent {
+            aggregate_id: aggregate_id.to_string(),
+            sequence,
+            aggregate_type: "order".to_string(),
+            event_type: "Placed".to_string(),
+            event_version: "1.0".to_string(),
+            payload: json!({}),
+            metadata: json!({}),
+        }
+    }
+
+    async fn append(pool: &Pool<Postgres>, hook: &InvalidationHook, events: &[SerializedEvent], commit: bool) {
+        let mut tx = pool.begin().await.unwrap();
+        hook.append(&mut tx, events).await.unwrap();
+        if commit {
+            tx.commit().await.unwrap();
+        } else {
+            tx.rollback().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalidation_round_trip() {
+        let pool = test_pool();
+        let channel = format!("invalidation_{}", random_suffix());
+        let bus = InvalidationBus::listen(pool.clone(), channel.clone());
+        let mut invalidations = bus.subscribe();
+
+        let hook = InvalidationHook::new(channel);
+        let invalidated = |aggregate_id: &str, version| Invalidated::One(Invalidation {
+            aggregate_type: "order".to_string(),
+            aggregate_id: aggregate_id.to_string(),
+            version,
+        });
+        // The listener subscribes in the background; publish until it hears one.
+        let received = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                append(&pool, &hook, &[event("order-1", 2), event("order-1", 3)], true).await;
+                if let Ok(Ok(invalidated)) = tokio::time::timeout(Duration::from_millis(200), invalidations.recv()).await {
+                    break invalidated;
+                }
+            }
+        }).await.unwrap();
+        assert_eq!(received, invalidated("order-1", 3));
+        while invalidations.try_recv().is_ok() {}
+
+        // Events rolled back are never announced.
+        append(&pool, &hook, &[event("order-2", 1)], false).await;
+        append(&pool, &hook, &[event("order-3", 1)], true).await;
+        let received = tokio::time::timeout(Duration::from_secs(10), invalidations.recv()).await.unwrap().unwrap();
+        assert_eq!(received, invalidated("order-3", 1));
+    }
+}
@@ -1,13 +1,124 @@
 #![forbid(unsafe_code)]
 #![deny(clippy::all)]
 
-mod account;
+pub mod account;
+#[cfg(feature = "server")]
+pub mod aggregate_cache;
+pub mod asset;
+#[cfg(feature = "server")]
+pub mod approval;
+#[cfg(feature = "server")]
+pub mod auth;
+#[cfg(feature = "server")]
+pub mod audit_log;
+#[cfg(feature = "server")]
+mod auction;
+#[cfg(feature = "server")]
+mod audit;
+#[cfg(feature = "server")]
+pub mod background;
+#[cfg(feature = "server")]
+mod batch_transfer;
+#[cfg(feature = "server")]
+pub mod bulkhead;
+#[cfg(feature = "server")]
+pub mod business_day;
+#[cfg(feature = "server")]
 pub mod command_extractor;
-mod config;
+#[cfg(feature = "server")]
+pub mod command_policy;
+#[cfg(feature = "server")]
+pub mod commit_hooks;
+#[cfg(feature = "server")]
+pub mod compaction;
+#[cfg(feature = "server")]
+pub mod compensation;
+#[cfg(feature = "server")]
+pub mod config;
+#[cfg(feature = "server")]
+pub mod consistency;
+#[cfg(feature = "server")]
+pub mod dlq;
+#[cfg(feature = "server")]
+pub mod idempotency;
+#[cfg(feature = "server")]
+mod inbox;
+#[cfg(feature = "server")]
+pub mod invalidation;
+#[cfg(feature = "server")]
+pub mod metrics;
+#[cfg(feature = "server")]
+mod notification;
+#[cfg(feature = "server")]
+pub mod openapi;
+#[cfg(feature = "server")]
 mod order;
+#[cfg(feature = "server")]
+mod outbox;
+#[cfg(feature = "server")]
+mod payout;
+#[cfg(feature = "server")]
+mod preferences;
+#[cfg(feature = "server")]
+pub mod rate_limit;
+#[cfg(feature = "server")]
+pub mod quota;
+#[cfg(feature = "server")]
+pub mod rates;
+#[cfg(feature = "server")]
+pub mod rebuild;
+#[cfg(feature = "server")]
+pub mod recording;
+#[cfg(feature = "server")]
+pub mod replay;
+#[cfg(feature = "server")]
+pub mod retry;
+#[cfg(feature = "server")]
+mod rfq;
+#[cfg(feature = "server")]
+pub mod sandbox;
+#[cfg(feature = "server")]
 pub mod route_handler;
-mod services;
+#[cfg(feature = "server")]
+pub mod routing;
+#[cfg(feature = "server")]
+pub mod field_encryption;
+#[cfg(feature = "server")]
+pub mod watchdog;
+#[cfg(feature = "server")]
+pub mod telemetry;
+#[cfg(feature = "server")]
+pub mod sealing;
+#[cfg(feature = "server")]
+pub mod seeder;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "server")]
+pub mod services;
+#[cfg(feature = "server")]
+pub mod sharding;
+#[cfg(feature = "server")]
+pub mod signals;
+#[cfg(feature = "server")]
+pub mod sla;
+#[cfg(feature = "server")]
+mod standing_order;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(feature = "server")]
+mod statemachine;
+#[cfg(feature = "server")]
 pub mod state;
+#[cfg(feature = "server")]
+pub mod tuning;
+#[cfg(feature = "server")]
 mod transfer;
+#[cfg(feature = "server")]
+mod txid_registry;
 pub mod util;
+#[cfg(feature = "server")]
+mod view_audit;
+#[cfg(feature = "server")]
+mod webhooks;
+#[cfg(feature = "server")]
 pub mod simple;
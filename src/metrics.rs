@@ -0,0 +1,639 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use cqrs_es::{Aggregate, AggregateError};
+use prometheus::core::Collector;
+use prometheus::{HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry};
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::config::ErrorAlertConfig;
+use crate::notification::WebhookNotifier;
+
+const ALERT_KIND: &str = "framework_error_rate";
+
+// Names the variant of a domain error, so errors can be counted without their payload.
+// Errors wrapping the error of another aggregate name the wrapped variant.
+pub trait ErrorVariant {
+    fn variant(&self) -> &'static str;
+}
+
+impl<T: ErrorVariant + std::error::Error> ErrorVariant for AggregateError<T> {
+    fn variant(&self) -> &'static str {
+        match self {
+            AggregateError::UserError(e) => e.variant(),
+            AggregateError::AggregateConflict => "AggregateConflict",
+            AggregateError::DatabaseConnectionError(_) => "DatabaseConnectionError",
+            AggregateError::DeserializationError(_) => "DeserializationError",
+            AggregateError::UnexpectedError(_) => "UnexpectedError",
+        }
+    }
+}
+
+// Variants raised by the framework rather than by the business rules, also when a saga
+// hits them executing on another aggregate.
+pub fn is_framework_error(variant: &str) -> bool {
+    matches!(variant, "AggregateConflict" | "DatabaseConnectionError" | "DeserializationError" | "UnexpectedError")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct ErrorCount {
+    pub aggregate: String,
+    pub variant: String,
+    pub framework: bool,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct ErrorStats {
+    pub errors: Vec<ErrorCount>,
+    // Framework errors within the current alert window.
+    pub recent_framework_errors: u64,
+    pub window_secs: u64,
+    pub alerting: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct FrameworkErrorAlert {
+    framework_errors: u64,
+    window_secs: u64,
+    threshold: usize,
+}
+
+// The alert window the framework error counter is read against: when it started and
+// what the counter was then.
+struct AlertWindow {
+    started: Instant,
+    framework_errors: u64,
+    alerting: bool,
+}
+
+// Counts command errors by aggregate and variant, exposed in the Prometheus format once
+// registered, and alerts once when more framework errors than the configured threshold
+// happen within a window. The alert is re-armed when a window starts.
+#[derive(Clone)]
+pub struct ErrorMetrics {
+    config: ErrorAlertConfig,
+    notifier: WebhookNotifier,
+    errors: IntCounterVec,
+    framework_errors: IntCounter,
+    window: Arc<Mutex<AlertWindow>>,
+}
+
+impl ErrorMetrics {
+    pub fn new(config: ErrorAlertConfig) -> Self {
+        let errors = IntCounterVec::new(
+            Opts::new("command_errors_total", "Command errors by aggregate and variant"),
+            &["aggregate", "variant"],
+        ).expect("invalid command error counter");
+        let framework_errors = IntCounter::new("command_framework_errors_total", "Command errors raised by the framework")
+            .expect("invalid framework error counter");
+        let window = AlertWindow { started: Instant::now(), framework_errors: 0, alerting: false };
+        ErrorMetrics { config, notifier: WebhookNotifier::default(), errors, framework_errors, window: Arc::new(Mutex::new(window)) }
+    }
+
+    pub fn with_notifier(mut self, notifier: WebhookNotifier) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.errors.clone()))?;
+        registry.register(Box::new(self.framework_errors.clone()))
+    }
+
+    pub fn record<A>(&self, err: &AggregateError<A::Error>)
+    where
+        A: Aggregate,
+        A::Error: ErrorVariant,
+    {
+        self.record_at(&A::aggregate_type(), err.variant(), Instant::now());
+    }
+
+    // Returns whether this error raised the alert.
+    fn record_at(&self, aggregate: &str, variant: &'static str, now: Instant) -> bool {
+        self.errors.with_label_values(&[aggregate, variant]).inc();
+        if !is_framework_error(variant) {
+            return false;
+        }
+        let mut window = self.window.lock().expect("error metrics poisoned");
+        self.roll(&mut window, now);
+        self.framework_errors.inc();
+        let recent = self.framework_errors.get() - window.framework_errors;
+        if self.config.threshold == 0 || recent <= self.config.threshold as u64 || window.alerting {
+            return false;
+        }
+        window.alerting = true;
+        tracing::error!(
+            "{} framework errors within {}s exceed the alert threshold of {}, latest {} on {}",
+            recent, self.config.window_secs, self.config.threshold, variant, aggregate,
+        );
+        if !self.config.webhook_url.is_empty() {
+            let alert = FrameworkErrorAlert {
+                framework_errors: recent,
+                window_secs: self.config.window_secs,
+                threshold: self.config.threshold,
+            };
+            self.notifier.notify(&self.config.webhook_url, ALERT_KIND, &alert);
+        }
+        true
+    }
+
+    // Starts a new window once the current one is over.
+    fn roll(&self, window: &mut AlertWindow, now: Instant) {
+        if now.duration_since(window.started) > Duration::from_secs(self.config.window_secs) {
+            *window = AlertWindow { started: now, framework_errors: self.framework_errors.get(), alerting: false };
+        }
+    }
+
+    pub fn stats(&self, now: Instant) -> ErrorStats {
+        let mut window = self.window.lock().expect("error metrics poisoned");
+        self.roll(&mut window, now);
+        let errors = self
+            .errors
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .map(|metric| {
+                let label = |name: &str| metric.get_label().iter()
+                    .find(|pair| pair.get_name() == name)
+                    .map(|pair| pair.get_value().to_string())
+                    .unwrap_or_default();
+                let variant = label("variant");
+                ErrorCount {
+                    aggregate: label("aggregate"),
+                    framework: is_framework_error(&variant),
+                    variant,
+                    count: metric.get_counter().get_value() as u64,
+                }
+            })
+            .collect();
+        ErrorStats {
+            errors,
+            recent_framework_errors: self.framework_errors.get() - window.framework_errors,
+            window_secs: self.config.window_secs,
+            alerting: window.alerting,
+        }
+    }
+}
+
+// The steps of the order and RFQ settlement sagas, each a command on an account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SagaStep {
+    SellerLock,
+    BuyerLock,
+    // The buyer pays the seller, then the seller delivers to the buyer.
+    SettleLeg1,
+    SettleLeg2,
+    // Unlocking the seller of a cancelled order.
+    CancelUnlock,
+    // Unlocking funds a failed saga locked before the step that failed.
+    CompensateUnlock,
+    // Taking back the first leg of a trade whose second leg failed.
+    CompensateSettle,
+}
+
+impl SagaStep {
+    pub fn label(&self) -> &'static str {
+        match self {
+            SagaStep::SellerLock => "seller_lock",
+            SagaStep::BuyerLock => "buyer_lock",
+            SagaStep::SettleLeg1 => "settle_leg_1",
+            SagaStep::SettleLeg2 => "settle_leg_2",
+            SagaStep::CancelUnlock => "cancel_unlock",
+            SagaStep::CompensateUnlock => "compensate_unlock",
+            SagaStep::CompensateSettle => "compensate_settle",
+        }
+    }
+}
+
+// Durations and failures of saga steps, by saga and step, so a rise in settlement
+// latency can be traced to the step behind it. Failures are also counted by variant;
+// a step failing on the business rules, such as a lock on insufficient funds, counts
+// as well. Exposed in the Prometheus format once registered.
+#[derive(Clone)]
+pub struct SagaMetrics {
+    durations: HistogramVec,
+    failures: IntCounterVec,
+}
+
+impl Default for SagaMetrics {
+    fn default() -> Self {
+        let durations = HistogramVec::new(
+            HistogramOpts::new("saga_step_duration_seconds", "Duration of saga steps"),
+            &["saga", "step"],
+        ).expect("invalid saga step histogram");
+        let failures = IntCounterVec::new(
+            Opts::new("saga_step_failures_total", "Failed saga steps"),
+            &["saga", "step", "variant"],
+        ).expect("invalid saga step counter");
+        SagaMetrics { durations, failures }
+    }
+}
+
+impl SagaMetrics {
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.durations.clone()))?;
+        registry.register(Box::new(self.failures.clone()))
+    }
+
+    pub fn observe(&self, saga: &str, step: SagaStep, duration: Duration, failure: Option<&'static str>) {
+        self.durations.with_label_values(&[saga, step.label()]).observe(duration.as_secs_f64());
+        if let Some(variant) = failure {
+            self.failures.with_label_values(&[saga, step.label(), variant]).inc();
+        }
+    }
+}
+
+// Connections accepted by the HTTP server and those still open, see `crate::server`.
+#[derive(Clone)]
+pub struct ConnectionMetrics {
+    accepted: IntCounter,
+    active: IntGauge,
+}
+
+impl Default for ConnectionMetrics {
+    fn default() -> Self {
+        let accepted = IntCounter::new("http_connections_accepted_total", "Accepted HTTP connections")
+            .expect("invalid accepted connection counter");
+        let active = IntGauge::new("http_connections_active", "Open HTTP connections")
+            .expect("invalid active connection gauge");
+        ConnectionMetrics { accepted, active }
+    }
+}
+
+impl ConnectionMetrics {
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.accepted.clone()))?;
+        registry.register(Box::new(self.active.clone()))
+    }
+
+    // Counts a connection as open until the guard is dropped.
+    pub fn accepted(&self) -> ActiveConnection {
+        self.accepted.inc();
+        self.active.inc();
+        ActiveConnection(self.active.clone())
+    }
+}
+
+pub struct ActiveConnection(IntGauge);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+// Commands running and waiting in the bulkhead of each aggregate type, and those turned
+// away because it was full or the wait timed out, see `crate::bulkhead`.
+#[derive(Clone)]
+pub struct BulkheadMetrics {
+    in_flight: IntGaugeVec,
+    queued: IntGaugeVec,
+    rejected: IntCounterVec,
+}
+
+impl Default for BulkheadMetrics {
+    fn default() -> Self {
+        let in_flight = IntGaugeVec::new(Opts::new("bulkhead_in_flight", "Commands running in a bulkhead"), &["aggregate"])
+            .expect("invalid bulkhead in flight gauge");
+        let queued = IntGaugeVec::new(Opts::new("bulkhead_queued", "Commands waiting for a bulkhead"), &["aggregate"])
+            .expect("invalid bulkhead queue gauge");
+        let rejected = IntCounterVec::new(
+            Opts::new("bulkhead_rejected_total", "Commands turned away by a bulkhead"),
+            &["aggregate", "reason"],
+        ).expect("invalid bulkhead rejection counter");
+        BulkheadMetrics { in_flight, queued, rejected }
+    }
+}
+
+impl BulkheadMetrics {
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.in_flight.clone()))?;
+        registry.register(Box::new(self.queued.clone()))?;
+        registry.register(Box::new(self.rejected.clone()))
+    }
+
+    pub fn in_flight(&self, aggregate_type: &str) -> IntGauge {
+        self.in_flight.with_label_values(&[aggregate_type])
+    }
+
+    pub fn queued(&self, aggregate_type: &str) -> IntGauge {
+        self.queued.with_label_values(&[aggregate_type])
+    }
+
+    pub fn rejected(&self, aggregate_type: &str, reason: &str) {
+        self.rejected.with_label_values(&[aggregate_type, reason]).inc();
+    }
+}
+
+// Loads of an aggregate served from the command side cache or not, and the aggregates it
+// holds, see `crate::aggregate_cache`.
+#[derive(Clone)]
+pub struct AggregateCacheMetrics {
+    loads: IntCounterVec,
+    entries: IntGaugeVec,
+}
+
+impl Default for AggregateCacheMetrics {
+    fn default() -> Self {
+        let loads = IntCounterVec::new(
+            Opts::new("aggregate_cache_loads_total", "Aggregates loaded to handle a command, from the cache or not"),
+            &["aggregate", "result"],
+        ).expect("invalid aggregate cache load counter");
+        let entries = IntGaugeVec::new(Opts::new("aggregate_cache_entries", "Aggregates held in the cache"), &["aggregate"])
+            .expect("invalid aggregate cache entry gauge");
+        AggregateCacheMetrics { loads, entries }
+    }
+}
+
+impl AggregateCacheMetrics {
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.loads.clone()))?;
+        registry.register(Box::new(self.entries.clone()))
+    }
+
+    pub fn loaded(&self, aggregate_type: &str, hit: bool) {
+        self.loads.with_label_values(&[aggregate_type, if hit { "hit" } else { "miss" }]).inc();
+    }
+
+    pub fn entries(&self, aggregate_type: &str) -> IntGauge {
+        self.entries.with_label_values(&[aggregate_type])
+    }
+}
+
+// Commands turned away by a rate limit, by the bucket that was empty, see `crate::rate_limit`.
+#[derive(Clone)]
+pub struct RateLimitMetrics {
+    rejected: IntCounterVec,
+}
+
+impl Default for RateLimitMetrics {
+    fn default() -> Self {
+        let rejected = IntCounterVec::new(
+            Opts::new("rate_limit_rejected_total", "Commands turned away by a rate limit"),
+            &["scope"],
+        ).expect("invalid rate limit rejection counter");
+        RateLimitMetrics { rejected }
+    }
+}
+
+impl RateLimitMetrics {
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.rejected.clone()))
+    }
+
+    pub fn rejected(&self, scope: &str) {
+        self.rejected.with_label_values(&[scope]).inc();
+    }
+}
+
+// HTTP calls out of the system by destination and how they ended, the attempts retried
+// and the destinations cut off by their circuit breaker, see `crate::services::outbound`.
+#[derive(Clone)]
+pub struct OutboundMetrics {
+    calls: IntCounterVec,
+    retries: IntCounterVec,
+    durations: HistogramVec,
+    open: IntGaugeVec,
+}
+
+impl Default for OutboundMetrics {
+    fn default() -> Self {
+        let calls = IntCounterVec::new(
+            Opts::new("outbound_calls_total", "HTTP calls out of the system, by how they ended"),
+            &["destination", "outcome"],
+        ).expect("invalid outbound call counter");
+        let retries = IntCounterVec::new(Opts::new("outbound_retries_total", "Attempts of outbound calls retried"), &["destination"])
+            .expect("invalid outbound retry counter");
+        let durations = HistogramVec::new(
+            HistogramOpts::new("outbound_call_duration_seconds", "Duration of outbound calls, retries included"),
+            &["destination"],
+        ).expect("invalid outbound call histogram");
+        let open = IntGaugeVec::new(Opts::new("outbound_circuit_open", "How many endpoints of a destination calls are cut off from, 1 for a destination cut off as a whole"), &["destination"])
+            .expect("invalid outbound circuit gauge");
+        OutboundMetrics { calls, retries, durations, open }
+    }
+}
+
+impl OutboundMetrics {
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.calls.clone()))?;
+        registry.register(Box::new(self.retries.clone()))?;
+        registry.register(Box::new(self.durations.clone()))?;
+        registry.register(Box::new(self.open.clone()))
+    }
+
+    pub fn called(&self, destination: &str, outcome: &str, elapsed: Duration) {
+        self.calls.with_label_values(&[destination, outcome]).inc();
+        self.durations.with_label_values(&[destination]).observe(elapsed.as_secs_f64());
+    }
+
+    pub fn retried(&self, destination: &str) {
+        self.retries.with_label_values(&[destination]).inc();
+    }
+
+    pub fn circuit(&self, destination: &str, cut_off: usize) {
+        self.open.with_label_values(&[destination]).set(cut_off as i64);
+    }
+}
+
+// Requests forwarded to the region owning their account or asset, by region and how
+// they ended, see `crate::routing`.
+#[derive(Clone)]
+pub struct RoutingMetrics {
+    forwarded: IntCounterVec,
+    durations: HistogramVec,
+}
+
+impl Default for RoutingMetrics {
+    fn default() -> Self {
+        let forwarded = IntCounterVec::new(
+            Opts::new("cross_region_requests_total", "Requests forwarded to the region owning their account or asset"),
+            &["region", "outcome"],
+        ).expect("invalid cross region request counter");
+        let durations = HistogramVec::new(
+            HistogramOpts::new("cross_region_request_duration_seconds", "Duration of requests forwarded to another region"),
+            &["region"],
+        ).expect("invalid cross region request histogram");
+        RoutingMetrics { forwarded, durations }
+    }
+}
+
+impl RoutingMetrics {
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.forwarded.clone()))?;
+        registry.register(Box::new(self.durations.clone()))
+    }
+
+    pub fn forwarded(&self, region: &str, outcome: &str, elapsed: Duration) {
+        self.forwarded.with_label_values(&[region, outcome]).inc();
+        self.durations.with_label_values(&[region]).observe(elapsed.as_secs_f64());
+    }
+}
+
+// Audits of the account invariants by how they ended, the violations the last one found
+// per invariant and when the invariants last held, see `crate::audit`.
+#[derive(Clone)]
+pub struct AuditMetrics {
+    audits: IntCounterVec,
+    violations: IntGaugeVec,
+    last_clean: IntGauge,
+}
+
+impl Default for AuditMetrics {
+    fn default() -> Self {
+        let audits = IntCounterVec::new(
+            Opts::new("consistency_audits_total", "Audits of the account invariants, by whether they were clean, violated or failed"),
+            &["result"],
+        ).expect("invalid consistency audit counter");
+        let violations = IntGaugeVec::new(
+            Opts::new("consistency_violations", "Violations of an account invariant found by the last audit"),
+            &["invariant"],
+        ).expect("invalid consistency violation gauge");
+        let last_clean = IntGauge::new("consistency_audit_last_clean_timestamp_seconds", "When an audit last found no violations")
+            .expect("invalid consistency audit timestamp gauge");
+        AuditMetrics { audits, violations, last_clean }
+    }
+}
+
+impl AuditMetrics {
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.audits.clone()))?;
+        registry.register(Box::new(self.violations.clone()))?;
+        registry.register(Box::new(self.last_clean.clone()))
+    }
+
+    pub fn failed(&self) {
+        self.audits.with_label_values(&["failed"]).inc();
+    }
+
+    pub fn audited(&self, invariant: &str, found: u64) {
+        self.violations.with_label_values(&[invariant]).set(found as i64);
+    }
+
+    pub fn completed(&self, clean_at: Option<u64>) {
+        match clean_at {
+            Some(at) => {
+                self.audits.with_label_values(&["clean"]).inc();
+                self.last_clean.set(at as i64);
+            }
+            None => self.audits.with_label_values(&["violated"]).inc(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use cqrs_es::AggregateError;
+    use crate::account::events::AccountError;
+    use crate::config::ErrorAlertConfig;
+    use prometheus::{Registry, TextEncoder};
+    use crate::metrics::{AuditMetrics, ErrorMetrics, ErrorVariant, SagaMetrics, SagaStep};
+    use crate::transfer::aggregate::TransferError;
+
+    #[test]
+    fn test_error_variants() {
+        let insufficient: AggregateError<AccountError> = AggregateError::UserError(AccountError::InsufficientFunds);
+        assert_eq!(insufficient.variant(), "InsufficientFunds");
+        assert_eq!(AggregateError::<AccountError>::AggregateConflict.variant(), "AggregateConflict");
+        // Errors of the accounts a transfer moves funds between are counted as themselves.
+        let nested = AggregateError::UserError(TransferError::AggregateError(insufficient));
+        assert_eq!(nested.variant(), "InsufficientFunds");
+        let conflict = AggregateError::UserError(TransferError::AggregateError(AggregateError::AggregateConflict));
+        assert_eq!(conflict.variant(), "AggregateConflict");
+        let invalid = AggregateError::UserError(TransferError::InvalidState("closed".to_string()));
+        assert_eq!(invalid.variant(), "InvalidState");
+    }
+
+    #[test]
+    fn test_framework_error_alert() {
+        let registry = Registry::new();
+        let metrics = ErrorMetrics::new(ErrorAlertConfig { threshold: 2, window_secs: 60, webhook_url: String::new() });
+        metrics.register(&registry).unwrap();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // User errors never count towards the alert.
+        for secs in 0..5 {
+            assert!(!metrics.record_at("account", "InsufficientFunds", at(secs)));
+        }
+        assert!(!metrics.record_at("account", "AggregateConflict", at(10)));
+        assert!(!metrics.record_at("transfer", "DatabaseConnectionError", at(11)));
+        assert!(metrics.record_at("account", "AggregateConflict", at(12)));
+        // Raised once per breach.
+        assert!(!metrics.record_at("account", "AggregateConflict", at(13)));
+        assert!(metrics.stats(at(13)).alerting);
+
+        // Back under the threshold once the next window started.
+        assert!(!metrics.record_at("account", "AggregateConflict", at(100)));
+        assert!(!metrics.stats(at(100)).alerting);
+        assert!(!metrics.record_at("account", "AggregateConflict", at(101)));
+        assert!(metrics.record_at("account", "AggregateConflict", at(102)));
+
+        let stats = metrics.stats(at(102));
+        assert_eq!(stats.recent_framework_errors, 3);
+        let count = |aggregate: &str, variant: &str| stats.errors.iter()
+            .find(|e| e.aggregate == aggregate && e.variant == variant)
+            .map(|e| (e.framework, e.count));
+        assert_eq!(count("account", "InsufficientFunds"), Some((false, 5)));
+        assert_eq!(count("account", "AggregateConflict"), Some((true, 6)));
+        assert_eq!(count("transfer", "DatabaseConnectionError"), Some((true, 1)));
+
+        let exposed = TextEncoder::new().encode_to_string(&registry.gather()).unwrap();
+        let lines = exposed.lines().collect::<Vec<_>>();
+        for line in [
+            r#"command_errors_total{aggregate="account",variant="AggregateConflict"} 6"#,
+            r#"command_errors_total{aggregate="account",variant="InsufficientFunds"} 5"#,
+            "command_framework_errors_total 7",
+        ] {
+            assert!(lines.contains(&line), "{} missing from\n{}", line, exposed);
+        }
+    }
+
+    #[test]
+    fn test_saga_step_metrics() {
+        let registry = Registry::new();
+        let metrics = SagaMetrics::default();
+        metrics.register(&registry).unwrap();
+        metrics.observe("order", SagaStep::SellerLock, Duration::from_millis(20), None);
+        metrics.observe("order", SagaStep::SellerLock, Duration::from_millis(30), Some("InsufficientFunds"));
+        metrics.observe("rfq", SagaStep::SettleLeg2, Duration::from_secs(2), Some("AggregateConflict"));
+
+        let exposed = TextEncoder::new().encode_to_string(&registry.gather()).unwrap();
+        let lines = exposed.lines().collect::<Vec<_>>();
+        for line in [
+            r#"saga_step_duration_seconds_count{saga="order",step="seller_lock"} 2"#,
+            r#"saga_step_duration_seconds_sum{saga="order",step="seller_lock"} 0.05"#,
+            r#"saga_step_duration_seconds_bucket{saga="rfq",step="settle_leg_2",le="1"} 0"#,
+            r#"saga_step_duration_seconds_bucket{saga="rfq",step="settle_leg_2",le="2.5"} 1"#,
+            r#"saga_step_failures_total{saga="order",step="seller_lock",variant="InsufficientFunds"} 1"#,
+            r#"saga_step_failures_total{saga="rfq",step="settle_leg_2",variant="AggregateConflict"} 1"#,
+        ] {
+            assert!(lines.contains(&line), "{} missing from\n{}", line, exposed);
+        }
+    }
+
+    #[test]
+    fn test_audit_metrics() {
+        let registry = Registry::new();
+        let metrics = AuditMetrics::default();
+        metrics.register(&registry).unwrap();
+        metrics.audited("NegativeBalance", 3);
+        metrics.completed(None);
+        metrics.failed();
+        metrics.audited("NegativeBalance", 0);
+        metrics.completed(Some(1_700_000_000));
+
+        let exposed = TextEncoder::new().encode_to_string(&registry.gather()).unwrap();
+        let lines = exposed.lines().collect::<Vec<_>>();
+        for line in [
+            r#"consistency_audits_total{result="clean"} 1"#,
+            r#"consistency_audits_total{result="violated"} 1"#,
+            r#"consistency_audits_total{result="failed"} 1"#,
+            r#"consistency_violations{invariant="NegativeBalance"} 0"#,
+            "consistency_audit_last_clean_timestamp_seconds 1700000000",
+        ] {
+            assert!(lines.contains(&line), "{} missing from\n{}", line, exposed);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use reqwest::Url;
+use serde::Serialize;
+use crate::config::OutboundConfig;
+use crate::metrics::OutboundMetrics;
+use crate::services::outbound::{OutboundClient, OutboundRequest};
+
+// Delivers notifications to user configured webhooks through the outbound client, under
+// the policy of its destination and with a breaker per endpoint. Delivery runs in the background so a slow or unreachable
+// endpoint never holds up the command that triggered it; once the client gives up the
+// notification is logged and dropped.
+#[derive(Clone)]
+pub struct WebhookNotifier {
+    outbound: OutboundClient,
+    destination: &'static str,
+    public_only: bool,
+}
+
+impl WebhookNotifier {
+    pub fn new(outbound: OutboundClient, destination: &'static str) -> Self {
+        WebhookNotifier { outbound, destination, public_only: false }
+    }
+
+    // For urls set by account owners: each delivery resolves the host again and is dropped
+    // when it points into the private network, see `check_public_url`.
+    pub fn public_only(mut self) -> Self {
+        self.public_only = true;
+        self
+    }
+
+    pub fn notify<T: Serialize>(&self, url: &str, kind: &str, payload: &T) {
+        let request = match OutboundRequest::post_json(url, payload) {
+            Ok(request) => request.header("X-Notification-Type", kind),
+            Err(e) => {
+                tracing::error!("Cannot serialize {} notification: {}", kind, e);
+                return;
+            }
+        };
+        let outbound = self.outbound.clone();
+        let destination = self.destination;
+        let kind = kind.to_string();
+        let public_only = self.public_only;
+        tokio::spawn(async move {
+            let url = request.url.clone();
+            if public_only {
+                if let Err(e) = check_public_url(&url).await {
+                    tracing::error!("Not delivering {} notification to {}: {}", kind, url, e);
+                    return;
+                }
+            }
+            if let Err(e) = outbound.send_to_endpoint(destination, request).await {
+                tracing::error!("Giving up delivering {} notification to {}: {}", kind, url, e);
+            }
+        });
+    }
+}
+
+impl Default for WebhookNotifier {
+    fn default() -> Self {
+        Self::new(OutboundClient::new(&OutboundConfig::default(), OutboundMetrics::default()), "webhooks")
+    }
+}
+
+// Checks an http(s) url resolves only to public addresses, so webhooks set by account owners
+// cannot reach the loopback, the private network or the metadata endpoint of the cloud.
+pub async fn check_public_url(url: &str) -> Result<(), String> {
+    let parsed = Url::parse(url).map_err(|e| format!("{} is not a valid url: {}", url, e))?;
+    if parsed.scheme() != "https" && parsed.scheme() != "http" {
+        return Err(format!("{} is not an http(s) url", url));
+    }
+    let port = parsed.port_or_known_default().unwrap_or(443);
+    let Some(host) = parsed.host_str() else {
+        return Err(format!("{} has no host", url));
+    };
+    let addresses: Vec<IpAddr> = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| format!("cannot resolve {}: {}", host, e))?
+            .map(|address| address.ip())
+            .collect(),
+    };
+    if addresses.is_empty() {
+        return Err(format!("{} resolves to no address", url));
+    }
+    match addresses.iter().find(|ip| !is_public(ip)) {
+        Some(ip) => Err(format!("{} resolves to {}, which is not a public address", url, ip)),
+        None => Ok(()),
+    }
+}
+
+fn is_public(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => is_public_v4(&mapped),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: &Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        // 169.254.0.0/16, the metadata endpoint at 169.254.169.254 included.
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || a == 0
+        // Carrier-grade NAT, 100.64.0.0/10.
+        || (a == 100 && (64..128).contains(&b))
+        // IETF protocol assignments, 192.0.0.0/24.
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking, 198.18.0.0/15.
+        || (a == 198 && (b == 18 || b == 19))
+        // Reserved, 240.0.0.0/4.
+        || a >= 240)
+}
+
+fn is_public_v6(ip: &Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    // NAT64, 64:ff9b::/96, reaches the IPv4 address it embeds.
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [_, _, _, _, _, _, high, low] = segments;
+        return is_public_v4(&Ipv4Addr::from(((high as u32) << 16) | low as u32));
+    }
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local, fc00::/7, the metadata endpoint at fd00:ec2::254 included.
+        || (segments[0] & 0xfe00) == 0xfc00
+        // Link local, fe80::/10.
+        || (segments[0] & 0xffc0) == 0xfe80
+        // Documentation, 2001:db8::/32.
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::notification::check_public_url;
+
+    #[tokio::test]
+    async fn test_rejects_urls_into_the_private_network() {
+        for url in [
+            "http://127.0.0.1/hook",
+            "http://10.1.2.3/hook",
+            "https://172.16.0.1/hook",
+            "https://192.168.1.1:8443/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/hook",
+            "http://0.0.0.0/hook",
+            "http://[::1]/hook",
+            "http://[fd00:ec2::254]/hook",
+            "http://[fe80::1]/hook",
+            "http://[::ffff:127.0.0.1]/hook",
+            "http://[64:ff9b::a00:1]/hook",
+            "http://localhost/hook",
+            "ftp://93.184.216.34/hook",
+        ] {
+            assert!(check_public_url(url).await.is_err(), "{} should be rejected", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accepts_public_addresses() {
+        for url in ["https://93.184.216.34/hook", "http://[2606:4700::1111]/hook", "https://8.8.8.8:8443/hook"] {
+            assert_eq!(check_public_url(url).await, Ok(()), "{} should be accepted", url);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use utoipa_swagger_ui::SwaggerUi;
+use crate::auth::API_KEY_HDR;
+use crate::route_handler;
+
+pub const SPEC_PATH: &str = "/api-docs/openapi.json";
+pub const SWAGGER_UI_PATH: &str = "/swagger-ui";
+
+// The specification of every route, generated from the handlers and the types they
+// take and return. Request and response schemas are collected from the paths.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "cqrs-account"),
+    paths(
+        route_handler::account_query_handler,
+        route_handler::account_command_handler,
+        route_handler::account_batch_handler,
+        route_handler::account_stream_handler,
+        route_handler::account_stats_handler,
+        route_handler::account_coverage_handler,
+        route_handler::account_accrual_preview_handler,
+        route_handler::account_ledger_handler,
+        route_handler::account_ledger_search_handler,
+        route_handler::account_ledger_export_handler,
+        route_handler::account_ledger_ofx_handler,
+        route_handler::preferences_query_handler,
+        route_handler::preferences_command_handler,
+        route_handler::preferences_admin_handler,
+        route_handler::asset_query_handler,
+        route_handler::asset_command_handler,
+        route_handler::transfer_open_handler,
+        route_handler::account_transfer_handler,
+        route_handler::transfer_query_handler,
+        route_handler::transfer_command_handler,
+        route_handler::transfer_refund_handler,
+        route_handler::batch_transfer_query_handler,
+        route_handler::batch_transfer_command_handler,
+        route_handler::order_query_handler,
+        route_handler::order_command_handler,
+        route_handler::order_book_handler,
+        route_handler::rate_history_handler,
+        route_handler::open_rfqs_handler,
+        route_handler::rfq_query_handler,
+        route_handler::rfq_command_handler,
+        route_handler::auction_query_handler,
+        route_handler::auction_command_handler,
+        route_handler::standing_order_query_handler,
+        route_handler::standing_order_command_handler,
+        route_handler::inbox_submit_handler,
+        route_handler::inbox_status_handler,
+        route_handler::payout_report_handler,
+        route_handler::payout_command_handler,
+        route_handler::account_lifecycle_handler,
+        route_handler::overdraft_limits_handler,
+        route_handler::overdraft_limit_handler,
+        route_handler::approval_propose_handler,
+        route_handler::pending_approvals_handler,
+        route_handler::approval_query_handler,
+        route_handler::approval_approve_handler,
+        route_handler::approval_reject_handler,
+        route_handler::value_dated_handler,
+        route_handler::quarantine_release_handler,
+        route_handler::earmark_handler,
+        route_handler::earmark_release_handler,
+        route_handler::bulk_list_handler,
+        route_handler::bulk_start_handler,
+        route_handler::bulk_job_handler,
+        route_handler::bulk_cancel_handler,
+        route_handler::asset_migration_list_handler,
+        route_handler::asset_migration_start_handler,
+        route_handler::asset_migration_handler,
+        route_handler::dead_letter_list_handler,
+        route_handler::dead_letter_handler,
+        route_handler::dead_letter_retry_handler,
+        route_handler::dead_letter_discard_handler,
+        route_handler::statemachine_handler,
+        route_handler::matching_stats_handler,
+        route_handler::halt_list_handler,
+        route_handler::halt_handler,
+        route_handler::resume_handler,
+        route_handler::recovery_report_handler,
+        route_handler::recovery_sweep_handler,
+        route_handler::watchdog_report_handler,
+        route_handler::watchdog_sweep_handler,
+        route_handler::audit_log_handler,
+        route_handler::audit_report_handler,
+        route_handler::audit_run_handler,
+        route_handler::view_audit_report_handler,
+        route_handler::view_audit_run_handler,
+        route_handler::integrity_report_handler,
+        route_handler::integrity_check_handler,
+        route_handler::integrity_remediate_handler,
+        route_handler::replay_list_handler,
+        route_handler::replay_pause_handler,
+        route_handler::replay_resume_handler,
+        route_handler::trial_balance_handler,
+        route_handler::business_days_handler,
+        route_handler::business_day_close_handler,
+        route_handler::business_day_checkpoint_handler,
+        route_handler::error_stats_handler,
+        route_handler::signal_report_handler,
+        route_handler::account_signal_report_handler,
+        route_handler::quota_report_handler,
+        route_handler::account_quota_report_handler,
+        route_handler::routing_report_handler,
+        route_handler::metrics_handler,
+        route_handler::webhook_list_handler,
+        route_handler::webhook_subscribe_handler,
+        route_handler::webhook_unsubscribe_handler,
+        route_handler::faucet_handler,
+        route_handler::sandbox_reset_handler,
+        route_handler::clock_query_handler,
+        route_handler::clock_advance_handler,
+    ),
+    modifiers(&AdminSecurity),
+    tags(
+        (name = "account", description = "Balances, ledgers and preferences of accounts"),
+        (name = "transfer", description = "Transfers and batch transfers between accounts"),
+        (name = "order", description = "Limit orders and order books"),
+        (name = "rfq", description = "Requests for quotes"),
+        (name = "auction", description = "Sealed-bid auctions"),
+        (name = "inbox", description = "Commands queued by offline clients"),
+        (name = "asset", description = "Asset registry"),
+        (name = "payout", description = "CSV payout batches"),
+        (name = "admin", description = "Operator endpoints, requiring admin credentials"),
+        (name = "sandbox", description = "Integrator endpoints, only served in sandbox mode"),
+    ),
+)]
+pub struct ApiDoc;
+
+// Declares the credentials `auth_layer` accepts on the admin routes.
+struct AdminSecurity;
+
+impl Modify for AdminSecurity {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("api_key", SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(API_KEY_HDR))));
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).bearer_format("JWT").build()),
+        );
+    }
+}
+
+// Serves the specification and a Swagger UI browsing it.
+pub fn swagger_ui() -> SwaggerUi {
+    SwaggerUi::new(SWAGGER_UI_PATH).url(SPEC_PATH, ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod test {
+    use utoipa::OpenApi;
+    use crate::openapi::ApiDoc;
+
+    #[test]
+    fn test_spec_covers_routes() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let paths = &spec["paths"];
+        assert!(paths["/account/{account_id}"]["get"].is_object());
+        assert!(paths["/account/{account_id}"]["post"].is_object());
+        assert!(paths["/transfer"]["post"].is_object());
+        assert!(paths["/order/{order_id}"]["post"].is_object());
+        assert_eq!(paths["/admin/bulk"]["post"]["security"][0]["api_key"], serde_json::json!([]));
+        assert!(paths["/account/{account_id}/ledger"]["get"]["parameters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|param| param["name"] == "cursor" && param["in"] == "query"));
+
+        let schemas = &spec["components"]["schemas"];
+        for schema in ["AccountCommand", "TransferCommand", "OrderCommand", "AccountView", "TransferView", "OrderView", "AssetDisplay"] {
+            assert!(schemas[schema].is_object(), "{} is missing", schema);
+        }
+        assert!(spec["components"]["securitySchemes"]["bearer"].is_object());
+    }
+}
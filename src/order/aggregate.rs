@@ -1,17 +1,24 @@
+use std::future::Future;
 use std::mem::swap;
 use std::sync::Arc;
+use std::time::Instant;
 use async_trait::async_trait;
+use tracing::Instrument;
 use cqrs_es::{Aggregate, AggregateError};
-use futures::future::BoxFuture;
-use postgres_es::PostgresCqrs;
+use crate::services::AccountExecutor;
 use serde::{Deserialize, Serialize};
-use crate::account::aggregate::Account;
 use crate::account::commands::AccountCommand;
 use crate::account::events::AccountError;
+use crate::compensation::{guard, journaled_undo, CompensationJournal, Journaled, StepGuard};
+use crate::dlq::DeadLetters;
 use crate::order::commands::OrderCommand;
 use crate::order::events::{OrderConfig, OrderEvent};
-use crate::util::transaction_guard::TransactionGuard;
+use crate::order::halts::{HaltError, TradingHalts};
+use crate::order::policy::OrderPolicyService;
+use crate::statemachine::{StateMachine, TableDriven, Transition};
+use crate::util::clock::{Clock, SystemClock};
 use crate::util::types::ByteArray32;
+use crate::metrics::{is_framework_error, ErrorVariant, SagaMetrics, SagaStep};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub enum Order {
@@ -38,11 +45,15 @@ pub enum Order {
         config: OrderConfig,
         buyer: String,
         timestamp: u64,
+        #[serde(default)]
+        fill_amount: Option<u64>,
     },
     Bought {
         config: OrderConfig,
         buyer: String,
         timestamp: u64,
+        #[serde(default)]
+        fill_amount: Option<u64>,
     },
     Failed {
         config: OrderConfig,
@@ -79,41 +90,164 @@ pub enum OrderError {
     AccountError(#[from] AccountError),
     #[error("Aggregate error: {0}")]
     AggregateError(#[from] AggregateError<AccountError>),
+    #[error("{0}")]
+    Halt(#[from] HaltError),
+    #[error("Policy violation: {0}")]
+    PolicyViolation(String),
+}
+
+impl ErrorVariant for OrderError {
+    fn variant(&self) -> &'static str {
+        match self {
+            OrderError::InvalidState(_) => "InvalidState",
+            OrderError::AccountError(e) => e.variant(),
+            OrderError::AggregateError(e) => e.variant(),
+            OrderError::Halt(HaltError::Halted(_)) => "TradingHalted",
+            OrderError::Halt(_) => "HaltCheckFailed",
+            OrderError::PolicyViolation(_) => "PolicyViolation",
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct OrderServices {
-    account_service: Arc<PostgresCqrs<Account>>,
+    account_service: Arc<dyn AccountExecutor>,
+    halts: Option<TradingHalts>,
+    policy: Option<OrderPolicyService>,
+    // The saga the steps are recorded under, "order" unless set with the metrics.
+    saga: &'static str,
+    metrics: SagaMetrics,
+    dead_letters: Option<DeadLetters>,
+    compensations: Option<CompensationJournal>,
+    clock: Arc<dyn Clock>,
 }
 
 impl OrderServices {
-    pub fn new(account_service: Arc<PostgresCqrs<Account>>) -> Self {
-        OrderServices { account_service }
+    pub fn new(account_service: Arc<dyn AccountExecutor>) -> Self {
+        OrderServices {
+            account_service,
+            halts: None,
+            policy: None,
+            saga: "order",
+            metrics: SagaMetrics::default(),
+            dead_letters: None,
+            compensations: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn with_halts(mut self, halts: TradingHalts) -> Self {
+        self.halts = Some(halts);
+        self
+    }
+
+    pub fn with_policy(mut self, policy: OrderPolicyService) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    pub fn with_metrics(mut self, saga: &'static str, metrics: SagaMetrics) -> Self {
+        self.saga = saga;
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn with_dead_letters(mut self, dead_letters: DeadLetters) -> Self {
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+
+    pub fn with_compensations(mut self, compensations: CompensationJournal) -> Self {
+        self.compensations = Some(compensations);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub(crate) fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    async fn journal(&self, order_id: ByteArray32, account_id: &str, undo: &AccountCommand) -> Result<Option<Journaled>, OrderError> {
+        match &self.compensations {
+            Some(journal) => Ok(Some(journal.record(self.saga, &order_id.hex(), account_id, undo).await?)),
+            None => Ok(None),
+        }
+    }
+
+    // Runs a step of the saga, recording how long it took and whether it failed.
+    pub(crate) async fn timed<T>(&self, step: SagaStep, run: impl Future<Output = Result<T, OrderError>>) -> Result<T, OrderError> {
+        let started = Instant::now();
+        let result = run.await;
+        self.metrics.observe(self.saga, step, started.elapsed(), result.as_ref().err().map(|e| e.variant()));
+        result
+    }
+
+    // A step failing on the framework leaves the order half way, for an operator to take
+    // up from `/admin/dlq` unless the saga gets through on its own.
+    async fn dead_letter(&self, step: SagaStep, order_id: ByteArray32, account_id: &str, command: &AccountCommand, error: &AggregateError<AccountError>) {
+        if let Some(dead_letters) = &self.dead_letters {
+            if is_framework_error(error.variant()) {
+                dead_letters.record(self.saga, step.label(), &order_id.hex(), account_id, command, error).await;
+            }
+        }
+    }
+
+    // Orders of a halted pair are neither opened nor hit. Those already being bought
+    // settle, and cancelling stays possible.
+    async fn check_halt(&self, config: &OrderConfig) -> Result<(), OrderError> {
+        match &self.halts {
+            Some(halts) if !config.auction => Ok(halts.check(&config.sell_asset, &config.buy_asset).await?),
+            _ => Ok(()),
+        }
     }
 
-    async fn lock_funds(
+    async fn check_policy(&self, config: &OrderConfig) -> Result<(), OrderError> {
+        match &self.policy {
+            Some(policy) => match policy.violation(config).await {
+                Some(violation) => Err(OrderError::PolicyViolation(violation)),
+                None => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) async fn lock_funds(
         &self,
         order_id: ByteArray32,
         seller: String,
         sell_asset: String,
         sell_amount: u64,
         timestamp: u64,
-    ) -> Result<TransactionGuard<BoxFuture<'static, ()>>, OrderError> {
+    ) -> Result<StepGuard, OrderError> {
         let account_service = self.account_service.clone();
-        let undo = {
+        let journaled = self.journal(order_id, &seller, &AccountCommand::unlock_funds(order_id, self.now())).await?;
+        let undo = journaled_undo(journaled.clone(), {
             let account_service = account_service.clone();
             let seller = seller.clone();
+            let (saga, metrics, dead_letters, clock) = (self.saga, self.metrics.clone(), self.dead_letters.clone(), self.clock.clone());
             async move {
                 tracing::info!("Undo: unlock funds for {} in order {}", seller, order_id.hex());
-                let command = AccountCommand::unlock_funds(order_id);
-                match account_service.execute(&seller, command).await {
-                    Ok(_) | Err(AggregateError::UserError(AccountError::LockNotFound)) => {}
+                let started = Instant::now();
+                let command = AccountCommand::unlock_funds(order_id, clock.now());
+                let failure = match account_service.execute(&seller, command.clone()).await {
+                    Ok(_) | Err(AggregateError::UserError(AccountError::LockNotFound)) => None,
                     Err(e) => {
                         tracing::error!("Failed to unlock funds: {:?}", e);
+                        // Nothing compensates a compensation, whatever it failed on.
+                        if let Some(dead_letters) = dead_letters {
+                            let step = SagaStep::CompensateUnlock.label();
+                            dead_letters.record(saga, step, &order_id.hex(), &seller, &command, &e).await;
+                        }
+                        Some(e.variant())
                     }
-                }
+                };
+                metrics.observe(saga, SagaStep::CompensateUnlock, started.elapsed(), failure);
             }
-        };
+        });
         let command = AccountCommand::lock_funds(
             order_id,
             timestamp,
@@ -122,7 +256,7 @@ impl OrderServices {
         );
         match self.account_service.execute(&seller, command).await {
             Ok(_) | Err(AggregateError::UserError(AccountError::DuplicateLock)) => {
-                Ok(TransactionGuard::new(Box::pin(undo)))
+                Ok(guard(journaled, undo))
             },
             Err(AggregateError::UserError(ae)) => {
                 undo.await;
@@ -136,23 +270,29 @@ impl OrderServices {
         }
     }
 
-    async fn unlock_funds(
+    pub(crate) async fn unlock_funds(
         &self,
         order_id: ByteArray32,
         seller: String,
     ) -> Result<(), OrderError> {
-        let command = AccountCommand::unlock_funds(order_id);
-        match self.account_service.execute(&seller, command).await {
-            Ok(_) => Ok(()),
+        let command = AccountCommand::unlock_funds(order_id, self.now());
+        // The lock is already gone if a previous attempt unlocked it, or if the order is
+        // cancelled before it was placed.
+        match self.account_service.execute(&seller, command.clone()).await {
+            Ok(_) | Err(AggregateError::UserError(AccountError::LockNotFound)) => Ok(()),
             Err(AggregateError::UserError(ae)) => {
                 Err(OrderError::AccountError(ae))
             },
-            Err(e) => Err(OrderError::AggregateError(e)),
+            Err(e) => {
+                self.dead_letter(SagaStep::CancelUnlock, order_id, &seller, &command, &e).await;
+                Err(OrderError::AggregateError(e))
+            },
         }
     }
 
-    async fn settle(
+    pub(crate) async fn settle(
         &self,
+        step: SagaStep,
         order_id: ByteArray32,
         account_id: String,
         pair_account_id: String,
@@ -161,52 +301,88 @@ impl OrderServices {
     ) -> Result<(), OrderError> {
         let command = AccountCommand::settle(
             order_id,
+            self.now(),
             pair_account_id,
             receive_asset,
             receive_amount,
         );
-        match self.account_service.execute(&account_id, command).await {
+        match self.account_service.execute(&account_id, command.clone()).await {
             Ok(_) | Err(AggregateError::UserError(AccountError::DuplicateTransaction(_))) => Ok(()),
             Err(AggregateError::UserError(ae)) => {
                 Err(OrderError::AccountError(ae))
             },
-            Err(e) => Err(OrderError::AggregateError(e)),
+            Err(e) => {
+                self.dead_letter(step, order_id, &account_id, &command, &e).await;
+                Err(OrderError::AggregateError(e))
+            },
         }
     }
-}
 
-#[async_trait]
-impl Aggregate for Order {
-    type Command = OrderCommand;
-    type Event = OrderEvent;
-    type Error = OrderError;
-    type Services = OrderServices;
-
-    fn aggregate_type() -> String {
-        "order".to_string()
+    // Runs a compensation of a failed saga, true when it went through. Nothing compensates
+    // a compensation, whatever it failed on is left to an operator at `/admin/dlq`.
+    async fn compensate(&self, step: SagaStep, order_id: ByteArray32, account_id: &str, command: AccountCommand) -> bool {
+        let started = Instant::now();
+        let failure = match self.account_service.execute(account_id, command.clone()).await {
+            Ok(_) | Err(AggregateError::UserError(AccountError::LockNotFound)) => None,
+            Err(e) => {
+                tracing::error!("Failed to compensate {} of {} on {}: {:?}", step.label(), order_id.hex(), account_id, e);
+                if let Some(dead_letters) = &self.dead_letters {
+                    dead_letters.record(self.saga, step.label(), &order_id.hex(), account_id, &command, &e).await;
+                }
+                Some(e.variant())
+            }
+        };
+        self.metrics.observe(self.saga, step, started.elapsed(), failure);
+        failure.is_none()
     }
 
-    async fn handle(
+    // Takes back the settled leg of a trade whose other leg failed: `account_id` returns
+    // what it received and gets back what it sent, under the txid of the trade, then the
+    // lock of the counterparty is released. The reversals are skipped once one fails, so
+    // the account never gets back what it sent while keeping what it received.
+    pub(crate) async fn reverse_settlement(
         &self,
-        command: Self::Command,
-        services: &Self::Services,
-    ) -> Result<Vec<Self::Event>, Self::Error> {
-        let span = tracing::span!(tracing::Level::INFO, "Order::handle", order_id = self.id().map(|id| id.hex()));
-        let _ = span.enter();
+        order_id: ByteArray32,
+        account_id: String,
+        pair_account_id: String,
+        sent: (String, u64),
+        received: (String, u64),
+    ) {
+        let now = self.now();
+        let reversals = [
+            AccountCommand::reverse_credit(order_id, now, pair_account_id.clone(), received.0, received.1),
+            AccountCommand::reverse_debit(order_id, now, pair_account_id.clone(), sent.0, sent.1),
+        ];
+        for command in reversals {
+            if !self.compensate(SagaStep::CompensateSettle, order_id, &account_id, command).await {
+                return;
+            }
+        }
+        self.compensate(SagaStep::CompensateUnlock, order_id, &pair_account_id, AccountCommand::unlock_funds(order_id, now)).await;
+    }
+}
+
+impl Order {
+    pub(crate) async fn decide(&self, command: OrderCommand, services: &OrderServices) -> Result<Vec<OrderEvent>, OrderError> {
+        if !self.accepts(&command) {
+            return Err(OrderError::InvalidState(format!("Order current at {} state, cannot accept {} command", self.state_name(), Order::command_name(&command))));
+        }
         match (self, command) {
             (Order::Uninitialized, OrderCommand::Open { config }) => {
+                services.check_halt(&config).await?;
+                services.check_policy(&config).await?;
                 let event = OrderEvent::Initialized { config };
                 Ok(vec![event])
             },
             (Order::Initialized { config }, OrderCommand::Continue) => {
-                let now = chrono::Utc::now().timestamp() as u64;
-                match services.lock_funds(
+                let now = services.now();
+                match services.timed(SagaStep::SellerLock, services.lock_funds(
                     config.order_id,
                     config.seller.clone(),
                     config.sell_asset.clone(),
                     config.sell_amount,
                     now,
-                ).await {
+                )).await {
                     Err(OrderError::AccountError(ae)) => {
                         Ok(vec![OrderEvent::Failed {
                             timestamp: now,
@@ -218,40 +394,61 @@ impl Aggregate for Order {
                         let event = OrderEvent::Placed {
                             timestamp: now,
                         };
-                        lock_undo.commit();
+                        lock_undo.commit().await?;
                         Ok(vec![event])
                     },
                 }
             },
-            (Order::Placed { .. }, OrderCommand::Cancel { reason }) => {
+            (Order::Initialized { .. } | Order::Placed { .. }, OrderCommand::Cancel { reason }) => {
                 let event = OrderEvent::Cancelling {
-                    timestamp: chrono::Utc::now().timestamp() as u64,
+                    timestamp: services.now(),
                     reason,
                 };
                 Ok(vec![event])
             },
             (Order::Cancelling { config, timestamp, .. }, OrderCommand::Continue) => {
-                services.unlock_funds(config.order_id, config.seller.clone()).await?;
+                services.timed(SagaStep::CancelUnlock, services.unlock_funds(config.order_id, config.seller.clone())).await?;
                 let event = OrderEvent::Cancelled {
                     timestamp: *timestamp,
                 };
                 Ok(vec![event])
             },
-            (Order::Placed { .. }, OrderCommand::Buy { buyer, timestamp }) => {
+            (Order::Placed { config, .. }, OrderCommand::Buy { .. } | OrderCommand::Fill { .. }) if config.expired(services.now()) => {
+                Err(OrderError::InvalidState("Order expired".to_string()))
+            },
+            (Order::Placed { config, .. }, OrderCommand::Buy { .. }) if config.auction => {
+                Err(OrderError::InvalidState("Auction orders are only filled at the clearing price".to_string()))
+            },
+            (Order::Placed { config, .. }, OrderCommand::Buy { buyer, timestamp }) => {
+                services.check_halt(config).await?;
                 let event = OrderEvent::Buying {
                     buyer,
                     timestamp,
+                    fill_amount: None,
                 };
                 Ok(vec![event])
             },
-            (Order::Buying { config, buyer, timestamp }, OrderCommand::Continue) => {
-                match services.lock_funds(
+            (Order::Placed { config, .. }, OrderCommand::Fill { buyer, buy_amount, timestamp }) => {
+                if !config.auction {
+                    return Err(OrderError::InvalidState("Only auction orders can be filled".to_string()));
+                }
+                if buy_amount < config.buy_amount {
+                    return Err(OrderError::InvalidState(format!("Fill amount {} is below the order limit {}", buy_amount, config.buy_amount)));
+                }
+                Ok(vec![OrderEvent::Buying {
+                    buyer,
+                    timestamp,
+                    fill_amount: Some(buy_amount),
+                }])
+            },
+            (Order::Buying { config, buyer, timestamp, fill_amount }, OrderCommand::Continue) => {
+                match services.timed(SagaStep::BuyerLock, services.lock_funds(
                     config.order_id,
                     buyer.clone(),
                     config.buy_asset.clone(),
-                    config.buy_amount,
+                    fill_amount.unwrap_or(config.buy_amount),
                     *timestamp
-                ).await {
+                )).await {
                     Err(OrderError::AccountError(ae)) => {
                         tracing::info!("Failed to lock funds: {:?}", ae);
                         Ok(vec![OrderEvent::Placed {
@@ -261,28 +458,35 @@ impl Aggregate for Order {
                     Err(e) => Err(e),
                     Ok(lock_undo) => {
                         let event = OrderEvent::Bought {
-                            timestamp: chrono::Utc::now().timestamp() as u64,
+                            timestamp: services.now(),
                         };
-                        lock_undo.commit();
+                        lock_undo.commit().await?;
                         Ok(vec![event])
                     },
                 }
             },
-            (Order::Bought { config, buyer, timestamp }, OrderCommand::Continue) => {
-                services.settle(
+            (Order::Buying { .. }, OrderCommand::Release) => {
+                Ok(vec![OrderEvent::Placed {
+                    timestamp: services.now(),
+                }])
+            },
+            (Order::Bought { config, buyer, timestamp, fill_amount }, OrderCommand::Continue) => {
+                services.timed(SagaStep::SettleLeg1, services.settle(
+                    SagaStep::SettleLeg1,
                     config.order_id,
                     config.seller.clone(),
                     buyer.clone(),
                     config.buy_asset.clone(),
-                    config.buy_amount
-                ).await?;
-                services.settle(
+                    fill_amount.unwrap_or(config.buy_amount)
+                )).await?;
+                services.timed(SagaStep::SettleLeg2, services.settle(
+                    SagaStep::SettleLeg2,
                     config.order_id,
                     buyer.clone(),
                     config.seller.clone(),
                     config.sell_asset.clone(),
                     config.sell_amount
-                ).await?;
+                )).await?;
                 let event = OrderEvent::Settled {
                     timestamp: *timestamp,
                 };
@@ -293,6 +497,27 @@ impl Aggregate for Order {
             }
         }
     }
+}
+
+#[async_trait]
+impl Aggregate for Order {
+    type Command = OrderCommand;
+    type Event = OrderEvent;
+    type Error = OrderError;
+    type Services = OrderServices;
+
+    fn aggregate_type() -> String {
+        "order".to_string()
+    }
+
+    async fn handle(
+        &self,
+        command: Self::Command,
+        services: &Self::Services,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        let span = tracing::info_span!("Order::handle", order_id = self.id().map(|id| id.hex()), command = Order::command_name(&command));
+        self.decide(command, services).instrument(span).await
+    }
 
     fn apply(&mut self, event: Self::Event) {
         let mut prev = Default::default();
@@ -311,7 +536,7 @@ impl Aggregate for Order {
                     timestamp,
                 };
             },
-            (Order::Placed { ref mut config, .. }, OrderEvent::Cancelling { timestamp, reason }) => {
+            (Order::Initialized { ref mut config } | Order::Placed { ref mut config, .. }, OrderEvent::Cancelling { timestamp, reason }) => {
                 let mut temp = Default::default();
                 swap(&mut temp, config);
                 *self = Order::Cancelling {
@@ -329,16 +554,17 @@ impl Aggregate for Order {
                     reason: reason.clone()
                 };
             },
-            (Order::Placed { ref mut config, .. }, OrderEvent::Buying { buyer, timestamp }) => {
+            (Order::Placed { ref mut config, .. }, OrderEvent::Buying { buyer, timestamp, fill_amount }) => {
                 let mut temp = Default::default();
                 swap(&mut temp, config);
                 *self = Order::Buying {
                     config: temp,
                     buyer,
                     timestamp,
+                    fill_amount,
                 };
             },
-            (Order::Buying { ref mut config, ref mut buyer, .. }, OrderEvent::Bought { timestamp }) => {
+            (Order::Buying { ref mut config, ref mut buyer, fill_amount, .. }, OrderEvent::Bought { timestamp }) => {
                 let mut temp = Default::default();
                 swap(&mut temp, config);
                 let mut temp_buyer = Default::default();
@@ -346,10 +572,11 @@ impl Aggregate for Order {
                 *self = Order::Bought {
                     config: temp,
                     timestamp,
-                    buyer: temp_buyer
+                    buyer: temp_buyer,
+                    fill_amount,
                 };
             },
-            (Order::Buying { ref mut config, .. }, OrderEvent::Failed { timestamp, reason }) => {
+            (Order::Initialized { ref mut config }, OrderEvent::Failed { timestamp, reason }) => {
                 let mut temp = Default::default();
                 swap(&mut temp, config);
                 *self = Order::Failed {
@@ -377,4 +604,142 @@ impl Aggregate for Order {
             (state, event) => unreachable!("Invalid state transition: {:?} -> {:?}", state, event),
         }
     }
-}
\ No newline at end of file
+}
+
+impl StateMachine for Order {
+    const TRANSITIONS: &'static [Transition] = &[
+        Transition { from: "Uninitialized", command: "Open", guard: None, events: &["Initialized"], to: "Initialized" },
+        Transition { from: "Initialized", command: "Continue", guard: Some("seller funds locked"), events: &["Placed"], to: "Placed" },
+        Transition { from: "Initialized", command: "Continue", guard: Some("seller lock failed"), events: &["Failed"], to: "Failed" },
+        Transition { from: "Initialized", command: "Cancel", guard: None, events: &["Cancelling"], to: "Cancelling" },
+        Transition { from: "Placed", command: "Cancel", guard: None, events: &["Cancelling"], to: "Cancelling" },
+        Transition { from: "Cancelling", command: "Continue", guard: None, events: &["Cancelled"], to: "Cancelled" },
+        Transition { from: "Placed", command: "Buy", guard: Some("not an auction order"), events: &["Buying"], to: "Buying" },
+        Transition { from: "Placed", command: "Fill", guard: Some("auction order"), events: &["Buying"], to: "Buying" },
+        Transition { from: "Buying", command: "Continue", guard: Some("buyer funds locked"), events: &["Bought"], to: "Bought" },
+        Transition { from: "Buying", command: "Continue", guard: Some("buyer lock failed"), events: &["Placed"], to: "Placed" },
+        Transition { from: "Buying", command: "Release", guard: None, events: &["Placed"], to: "Placed" },
+        Transition { from: "Bought", command: "Continue", guard: None, events: &["Settled"], to: "Settled" },
+    ];
+}
+
+impl TableDriven for Order {
+    fn state_name(&self) -> &'static str {
+        match self {
+            Order::Uninitialized => "Uninitialized",
+            Order::Initialized { .. } => "Initialized",
+            Order::Placed { .. } => "Placed",
+            Order::Cancelling { .. } => "Cancelling",
+            Order::Cancelled { .. } => "Cancelled",
+            Order::Buying { .. } => "Buying",
+            Order::Bought { .. } => "Bought",
+            Order::Failed { .. } => "Failed",
+            Order::Settled { .. } => "Settled",
+        }
+    }
+
+    fn command_name(command: &OrderCommand) -> &'static str {
+        match command {
+            OrderCommand::Open { .. } => "Open",
+            OrderCommand::Continue => "Continue",
+            OrderCommand::Cancel { .. } => "Cancel",
+            OrderCommand::Buy { .. } => "Buy",
+            OrderCommand::Fill { .. } => "Fill",
+            OrderCommand::Release => "Release",
+        }
+    }
+}
+
+#[cfg(test)]
+mod aggregate_tests {
+    use std::sync::Arc;
+    use async_trait::async_trait;
+    use cqrs_es::test::TestFramework;
+    use cqrs_es::AggregateError;
+    use crate::account::commands::AccountCommand;
+    use crate::account::events::AccountError;
+    use crate::order::aggregate::{Order, OrderServices};
+    use crate::order::commands::OrderCommand;
+    use crate::order::events::{OrderConfig, OrderEvent};
+    use crate::services::AccountExecutor;
+    use crate::statemachine::verify_table;
+    use crate::util::clock::FixedClock;
+
+    #[test]
+    fn test_transition_table() {
+        let config = OrderConfig::default;
+        let states = vec![
+            Order::Uninitialized,
+            Order::Initialized { config: config() },
+            Order::Placed { config: config(), timestamp: 1 },
+            Order::Cancelling { config: config(), reason: "user".to_string(), timestamp: 2 },
+            Order::Cancelled { config: config(), timestamp: 2, reason: "user".to_string() },
+            Order::Buying { config: config(), buyer: "ACCT-0002".to_string(), timestamp: 2, fill_amount: None },
+            Order::Bought { config: config(), buyer: "ACCT-0002".to_string(), timestamp: 3, fill_amount: None },
+            Order::Failed { config: config(), timestamp: 1, reason: "no funds".to_string() },
+            Order::Settled { config: config(), timestamp: 4 },
+        ];
+        let commands = vec![
+            OrderCommand::Open { config: config() },
+            OrderCommand::Continue,
+            OrderCommand::Cancel { reason: "user".to_string() },
+            OrderCommand::Buy { buyer: "ACCT-0002".to_string(), timestamp: 2 },
+            OrderCommand::Fill { buyer: "ACCT-0002".to_string(), buy_amount: 10, timestamp: 2 },
+            OrderCommand::Release,
+        ];
+        let events = vec![
+            OrderEvent::Initialized { config: config() },
+            OrderEvent::Placed { timestamp: 1 },
+            OrderEvent::Cancelling { timestamp: 2, reason: "user".to_string() },
+            OrderEvent::Cancelled { timestamp: 2 },
+            OrderEvent::Buying { buyer: "ACCT-0002".to_string(), timestamp: 2, fill_amount: None },
+            OrderEvent::Bought { timestamp: 3 },
+            OrderEvent::Failed { timestamp: 1, reason: "no funds".to_string() },
+            OrderEvent::Settled { timestamp: 4 },
+        ];
+        verify_table(&states, &commands, &events);
+    }
+
+    struct NoAccounts;
+
+    #[async_trait]
+    impl AccountExecutor for NoAccounts {
+        async fn execute(&self, _account_id: &str, _command: AccountCommand) -> Result<(), AggregateError<AccountError>> {
+            panic!("buying an order does not touch the accounts");
+        }
+    }
+
+    #[test]
+    fn test_expired_order_cannot_be_bought() {
+        let clock = Arc::new(FixedClock::new(1_000));
+        let services = || OrderServices::new(Arc::new(NoAccounts)).with_clock(clock.clone());
+        let placed = |expires_at| vec![
+            OrderEvent::Initialized { config: OrderConfig { expires_at, ..Default::default() } },
+            OrderEvent::Placed { timestamp: 900 },
+        ];
+        let buy = || OrderCommand::Buy { buyer: "ACCT-0002".to_string(), timestamp: 1_000 };
+        let buying = || vec![OrderEvent::Buying { buyer: "ACCT-0002".to_string(), timestamp: 1_000, fill_amount: None }];
+
+        TestFramework::<Order>::with(services()).given(placed(Some(1_060))).when(buy()).then_expect_events(buying());
+        TestFramework::<Order>::with(services()).given(placed(None)).when(buy()).then_expect_events(buying());
+        clock.set(1_060);
+        TestFramework::<Order>::with(services())
+            .given(placed(Some(1_060)))
+            .when(buy())
+            .then_expect_error_message("Invalid state: Order expired");
+        let fill = OrderCommand::Fill { buyer: "ACCT-0002".to_string(), buy_amount: 10, timestamp: 1_060 };
+        TestFramework::<Order>::with(services())
+            .given(placed(Some(1_059)))
+            .when(fill)
+            .then_expect_error_message("Invalid state: Order expired");
+    }
+
+    #[test]
+    fn test_cancel_stamped_by_clock() {
+        let services = OrderServices::new(Arc::new(NoAccounts)).with_clock(Arc::new(FixedClock::new(1_000)));
+        TestFramework::<Order>::with(services)
+            .given(vec![OrderEvent::Initialized { config: OrderConfig::default() }, OrderEvent::Placed { timestamp: 900 }])
+            .when(OrderCommand::Cancel { reason: "user".to_string() })
+            .then_expect_events(vec![OrderEvent::Cancelling { timestamp: 1_000, reason: "user".to_string() }]);
+    }
+}
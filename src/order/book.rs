@@ -0,0 +1,267 @@
+use async_trait::async_trait;
+use cqrs_es::{EventEnvelope, Query};
+use serde::Serialize;
+use utoipa::ToSchema;
+use sqlx::{PgConnection, Pool, Postgres, Row};
+use crate::auction::events::Price;
+use crate::order::aggregate::Order;
+use crate::order::events::OrderEvent;
+use crate::order::halts::{HaltError, TradingHalt, TradingHalts};
+
+const DEFAULT_DEPTH: u32 = 50;
+const MAX_DEPTH: u32 = 500;
+
+#[derive(Debug, thiserror::Error)]
+pub enum OrderBookError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Invalid pair {0}, expected BASE-QUOTE")]
+    InvalidPair(String),
+    #[error("{0}")]
+    Halt(#[from] HaltError),
+}
+
+// A trading pair such as `BTC-USDT`: asks sell the base asset for the quote asset,
+// bids sell the quote asset for the base asset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pair {
+    pub base: String,
+    pub quote: String,
+}
+
+impl std::str::FromStr for Pair {
+    type Err = OrderBookError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('-') {
+            Some((base, quote)) if !base.is_empty() && !quote.is_empty() && base != quote && !quote.contains('-') => {
+                Ok(Pair { base: base.to_string(), quote: quote.to_string() })
+            }
+            _ => Err(OrderBookError::InvalidPair(s.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for Pair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.base, self.quote)
+    }
+}
+
+// A placed order that can be hit, priced in quote units per base unit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct BookEntry {
+    pub order_id: String,
+    pub seller: String,
+    pub price: Price,
+    // Base asset the order sells (asks) or asks for (bids).
+    pub amount: u64,
+    // Quote asset the order asks for (asks) or sells (bids).
+    pub total: u64,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct OrderBook {
+    pub base: String,
+    pub quote: String,
+    // Best price first: lowest asks, highest bids.
+    pub asks: Vec<BookEntry>,
+    pub bids: Vec<BookEntry>,
+    // Present while the pair is halted, the orders listed cannot be hit until it resumes.
+    pub halt: Option<TradingHalt>,
+}
+
+// Keeps `open_orders` in step with the orders, so buyers can discover what to hit.
+// Every non-auction order has a row from `Initialized` until it is bought, cancelled
+// or fails; only rows of `Placed` orders are listed.
+pub struct OrderBookQuery {
+    pool: Pool<Postgres>,
+}
+
+impl OrderBookQuery {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        OrderBookQuery { pool }
+    }
+}
+
+#[async_trait]
+impl Query<Order> for OrderBookQuery {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<Order>]) {
+        let result = async {
+            let mut tx = self.pool.begin().await?;
+            write_order_book(&mut tx, events).await?;
+            tx.commit().await
+        };
+        if let Err(e) = result.await {
+            tracing::error!("Failed to update the order book for {}: {}", aggregate_id, e);
+        }
+    }
+}
+
+pub async fn write_order_book(conn: &mut PgConnection, events: &[EventEnvelope<Order>]) -> Result<(), sqlx::Error> {
+    for event in events {
+        let order_id = &event.aggregate_id;
+        match &event.payload {
+            // Auction orders are only filled by the auction engine.
+            OrderEvent::Initialized { config } if !config.auction => {
+                sqlx::query(
+                    "INSERT INTO open_orders (order_id, seller, sell_asset, buy_asset, sell_amount, buy_amount, price, placed, timestamp)
+                     VALUES ($1, $2, $3, $4, $5, $6, $6::numeric / $5::numeric, false, $7)
+                     ON CONFLICT (order_id) DO NOTHING",
+                )
+                    .bind(order_id)
+                    .bind(&config.seller)
+                    .bind(&config.sell_asset)
+                    .bind(&config.buy_asset)
+                    .bind(config.sell_amount as i64)
+                    .bind(config.buy_amount as i64)
+                    .bind(config.timestamp as i64)
+                    .execute(&mut *conn)
+                    .await?;
+            }
+            OrderEvent::Initialized { .. } => {}
+            // Also sent when a buyer fails to lock its funds, putting the order back up.
+            OrderEvent::Placed { .. } => {
+                sqlx::query("UPDATE open_orders SET placed = true WHERE order_id = $1")
+                    .bind(order_id)
+                    .execute(&mut *conn)
+                    .await?;
+            }
+            OrderEvent::Buying { .. } => {
+                sqlx::query("UPDATE open_orders SET placed = false WHERE order_id = $1")
+                    .bind(order_id)
+                    .execute(&mut *conn)
+                    .await?;
+            }
+            OrderEvent::Cancelling { .. }
+            | OrderEvent::Cancelled { .. }
+            | OrderEvent::Bought { .. }
+            | OrderEvent::Failed { .. }
+            | OrderEvent::Settled { .. } => {
+                sqlx::query("DELETE FROM open_orders WHERE order_id = $1")
+                    .bind(order_id)
+                    .execute(&mut *conn)
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Both sides are read in ascending `price` (buy amount per sell amount), which is the
+// best price for a buyer on either side. `depth` caps the entries per side.
+pub async fn load_order_book(pool: &Pool<Postgres>, halts: &TradingHalts, pair: &Pair, depth: Option<u32>) -> Result<OrderBook, OrderBookError> {
+    let depth = depth.unwrap_or(DEFAULT_DEPTH).clamp(1, MAX_DEPTH);
+    let side = |sell_asset: &str, buy_asset: &str| {
+        sqlx::query(
+            "SELECT order_id, seller, sell_amount, buy_amount, timestamp FROM open_orders
+             WHERE sell_asset = $1 AND buy_asset = $2 AND placed
+             ORDER BY price, timestamp, order_id
+             LIMIT $3",
+        )
+            .bind(sell_asset.to_string())
+            .bind(buy_asset.to_string())
+            .bind(depth as i64)
+            .fetch_all(pool)
+    };
+    let entry = |row: &sqlx::postgres::PgRow, ask: bool| -> Result<BookEntry, sqlx::Error> {
+        let sell_amount = row.try_get::<i64, _>("sell_amount")? as u64;
+        let buy_amount = row.try_get::<i64, _>("buy_amount")? as u64;
+        let (amount, total) = if ask { (sell_amount, buy_amount) } else { (buy_amount, sell_amount) };
+        Ok(BookEntry {
+            order_id: row.try_get("order_id")?,
+            seller: row.try_get("seller")?,
+            price: Price::new(total, amount),
+            amount,
+            total,
+            timestamp: row.try_get::<i64, _>("timestamp")? as u64,
+        })
+    };
+    let asks = side(&pair.base, &pair.quote).await?.iter().map(|row| entry(row, true)).collect::<Result<_, _>>()?;
+    let bids = side(&pair.quote, &pair.base).await?.iter().map(|row| entry(row, false)).collect::<Result<_, _>>()?;
+    let halt = halts.active(&pair.base, &pair.quote).await?;
+    Ok(OrderBook { base: pair.base.clone(), quote: pair.quote.clone(), asks, bids, halt })
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::EventEnvelope;
+    use std::collections::HashMap;
+    use crate::auction::events::Price;
+    use crate::order::aggregate::Order;
+    use crate::order::book::{load_order_book, write_order_book, Pair};
+    use crate::order::events::{OrderConfig, OrderEvent};
+    use crate::order::halts::{HaltRequest, TradingHalts};
+    use crate::util::test_db::{random_suffix, test_pool};
+
+    #[test]
+    fn test_parse_pair() {
+        assert_eq!("BTC-USDT".parse::<Pair>().unwrap(), Pair { base: "BTC".to_string(), quote: "USDT".to_string() });
+        assert!("BTCUSDT".parse::<Pair>().is_err());
+        assert!("BTC-".parse::<Pair>().is_err());
+        assert!("BTC-BTC".parse::<Pair>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_order_book() {
+        let pool = test_pool();
+        let suffix = random_suffix();
+        let (base, quote) = (format!("BASE{}", suffix), format!("QUOTE{}", suffix));
+        let envelope = |order_id: &str, sequence, payload| EventEnvelope::<Order> {
+            aggregate_id: format!("{}-{}", order_id, suffix),
+            sequence,
+            payload,
+            metadata: HashMap::new(),
+        };
+        let order = |order_id: &str, sell_asset: &str, sell_amount, buy_asset: &str, buy_amount, auction| {
+            vec![
+                envelope(order_id, 1, OrderEvent::Initialized {
+                    config: OrderConfig {
+                        seller: "ACCT-0001".to_string(),
+                        sell_asset: sell_asset.to_string(),
+                        sell_amount,
+                        buy_asset: buy_asset.to_string(),
+                        buy_amount,
+                        auction,
+                        ..Default::default()
+                    },
+                }),
+                envelope(order_id, 2, OrderEvent::Placed { timestamp: 1 }),
+            ]
+        };
+        let mut events = vec![];
+        events.extend(order("cheap-ask", &base, 10, &quote, 100, false));
+        events.extend(order("dear-ask", &base, 10, &quote, 120, false));
+        events.extend(order("auction-ask", &base, 10, &quote, 90, true));
+        events.extend(order("low-bid", &quote, 90, &base, 10, false));
+        events.extend(order("high-bid", &quote, 95, &base, 10, false));
+        events.extend(order("sold-ask", &base, 10, &quote, 80, false));
+        events.push(envelope("sold-ask", 3, OrderEvent::Buying { buyer: "ACCT-0002".to_string(), timestamp: 2, fill_amount: None }));
+
+        let mut conn = pool.acquire().await.unwrap();
+        write_order_book(&mut conn, &events).await.unwrap();
+
+        let pair = Pair { base: base.clone(), quote: quote.clone() };
+        let halts = TradingHalts::new(pool.clone(), &Default::default()).unwrap();
+        let book = load_order_book(&pool, &halts, &pair, None).await.unwrap();
+        let ids = |entries: &[crate::order::book::BookEntry]| entries.iter().map(|e| e.order_id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids(&book.asks), vec![format!("cheap-ask-{}", suffix), format!("dear-ask-{}", suffix)]);
+        assert_eq!(ids(&book.bids), vec![format!("high-bid-{}", suffix), format!("low-bid-{}", suffix)]);
+        assert_eq!(book.bids[0].price, Price::new(95, 10));
+        assert_eq!((book.bids[0].amount, book.bids[0].total), (10, 95));
+        assert_eq!(book.halt, None);
+
+        let request = HaltRequest { reason: "Oracle outage".to_string(), resume_at: None };
+        let halt = halts.halt(&pair, request).await.unwrap();
+        let book = load_order_book(&pool, &halts, &pair, None).await.unwrap();
+        assert_eq!(book.halt, Some(halt));
+        assert!(halts.resume(&pair).await.unwrap());
+
+        sqlx::query("DELETE FROM open_orders WHERE order_id LIKE $1")
+            .bind(format!("%-{}", suffix))
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
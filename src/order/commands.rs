@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 use crate::order::events::OrderConfig;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub enum OrderCommand {
     Open {
         config: OrderConfig
@@ -14,4 +15,11 @@ pub enum OrderCommand {
         buyer: String,
         timestamp: u64,
     },
+    Fill {
+        buyer: String,
+        buy_amount: u64,
+        timestamp: u64,
+    },
+    // Puts an order being bought back up before the buyer locked any funds.
+    Release,
 }
\ No newline at end of file
@@ -1,8 +1,9 @@
 use cqrs_es::DomainEvent;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 use crate::util::types::ByteArray32;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default, ToSchema)]
 pub struct OrderConfig {
     pub order_id: ByteArray32,
     pub seller: String,
@@ -11,6 +12,19 @@ pub struct OrderConfig {
     pub buy_asset: String,
     pub buy_amount: u64,
     pub timestamp: u64,
+    // Auction orders are only filled by the auction engine at the clearing price.
+    #[serde(default)]
+    pub auction: bool,
+    // Unix seconds after which the order can no longer be bought and is cancelled,
+    // good till cancelled without one.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+impl OrderConfig {
+    pub fn expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -30,7 +44,9 @@ pub enum OrderEvent {
     },
     Buying {
         buyer: String,
-        timestamp: u64
+        timestamp: u64,
+        #[serde(default)]
+        fill_amount: Option<u64>,
     },
     Bought {
         timestamp: u64,
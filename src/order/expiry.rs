@@ -0,0 +1,71 @@
+use std::sync::Arc;
+use std::time::Duration;
+use crate::background::{Background, Shutdown};
+use crate::commit_hooks::HookedCqrs;
+use sqlx::{Pool, Postgres, Row};
+use crate::config::OrderExpiryConfig;
+use crate::order::aggregate::Order;
+use crate::order::commands::OrderCommand;
+use crate::util::clock;
+
+// Cancels placed orders once their `expires_at` has passed, which releases the funds
+// locked for the seller. Orders being bought by then settle or go back to placed first,
+// and are cancelled on a later sweep. An order whose unlock fails stays cancelling and
+// is continued on the next sweep.
+#[derive(Clone)]
+pub struct OrderExpiry {
+    order_cqrs: Arc<HookedCqrs<Order>>,
+    pool: Pool<Postgres>,
+    config: OrderExpiryConfig,
+}
+
+impl OrderExpiry {
+    pub fn new(order_cqrs: Arc<HookedCqrs<Order>>, pool: Pool<Postgres>, config: OrderExpiryConfig) -> Self {
+        OrderExpiry { order_cqrs, pool, config }
+    }
+
+    pub fn spawn(self, background: &Background) {
+        background.spawn(|shutdown| async move { self.run(shutdown).await });
+    }
+
+    async fn run(&self, mut shutdown: Shutdown) {
+        loop {
+            if let Err(e) = self.sweep().await {
+                tracing::error!("Failed to sweep expired orders: {}", e);
+            }
+            if !shutdown.pause(Duration::from_secs(self.config.sweep_interval_secs.max(1))).await {
+                break;
+            }
+        }
+    }
+
+    async fn sweep(&self) -> Result<(), sqlx::Error> {
+        for (order_id, status) in expired_orders(&self.pool, clock::now()).await? {
+            if status == "Placed" {
+                let cancel = OrderCommand::Cancel { reason: "expired".to_string() };
+                if let Err(e) = self.order_cqrs.execute(&order_id, cancel).await {
+                    tracing::error!("Failed to cancel expired order {}: {}", order_id, e);
+                    continue;
+                }
+            }
+            match self.order_cqrs.execute(&order_id, OrderCommand::Continue).await {
+                Ok(_) => tracing::info!("Order {} expired", order_id),
+                Err(e) => tracing::error!("Failed to release expired order {}: {}", order_id, e),
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn expired_orders(pool: &Pool<Postgres>, now: u64) -> Result<Vec<(String, String)>, sqlx::Error> {
+    sqlx::query(
+        "SELECT view_id, payload->>'status' AS status FROM order_query
+         WHERE payload->>'status' IN ('Placed', 'Cancelling') AND (payload->>'expires_at')::bigint <= $1",
+    )
+        .bind(now as i64)
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| Ok((row.try_get("view_id")?, row.try_get("status")?)))
+        .collect()
+}
@@ -0,0 +1,255 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use sqlx::{Pool, Postgres, Row};
+use crate::config::TradingHaltConfig;
+use crate::order::book::Pair;
+use crate::util::clock;
+
+#[derive(Debug, thiserror::Error)]
+pub enum HaltError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Invalid halt: {0}")]
+    Invalid(String),
+    #[error("Trading in {} is halted: {}", .0.pair, .0.reason)]
+    Halted(Box<TradingHalt>),
+    #[error("Halt of {0} comes from the configuration and can only be lifted there")]
+    Configured(String),
+}
+
+// A pair in which no orders are opened, hit or matched. Halts cover both directions of
+// the pair, `BTC-USDT` also halts orders selling USDT for BTC.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TradingHalt {
+    pub pair: String,
+    pub reason: String,
+    pub halted_at: u64,
+    // Trading resumes on its own from then on. Absent, the halt lasts until lifted.
+    pub resume_at: Option<u64>,
+    pub configured: bool,
+}
+
+impl TradingHalt {
+    fn active(&self, now: u64) -> bool {
+        self.resume_at.is_none_or(|resume_at| resume_at > now)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct HaltRequest {
+    pub reason: String,
+    #[serde(default)]
+    pub resume_at: Option<u64>,
+}
+
+// Pair-level trading halts, separate from disabling an asset: the assets stay usable
+// for deposits, withdrawals and transfers. Halts come from the configuration, or are
+// managed under `/admin/halts` and kept in `trading_halt`. Auctions run on their own
+// schedule and are not halted.
+#[derive(Clone)]
+pub struct TradingHalts {
+    pool: Pool<Postgres>,
+    configured: Vec<TradingHalt>,
+}
+
+impl TradingHalts {
+    pub fn new(pool: Pool<Postgres>, config: &TradingHaltConfig) -> Result<Self, HaltError> {
+        let now = clock::now();
+        let configured = config
+            .pairs
+            .iter()
+            .map(|(pair, halt)| {
+                let pair = pair.parse::<Pair>().map_err(|e| HaltError::Invalid(e.to_string()))?;
+                Ok(TradingHalt {
+                    pair: pair.to_string(),
+                    reason: halt.reason.clone(),
+                    halted_at: now,
+                    resume_at: halt.resume_at,
+                    configured: true,
+                })
+            })
+            .collect::<Result<_, HaltError>>()?;
+        Ok(TradingHalts { pool, configured })
+    }
+
+    // Halts the pair, or replaces the reason and resume time of its halt.
+    pub async fn halt(&self, pair: &Pair, request: HaltRequest) -> Result<TradingHalt, HaltError> {
+        let now = clock::now();
+        if request.reason.trim().is_empty() {
+            return Err(HaltError::Invalid("a reason is required".to_string()));
+        }
+        if request.resume_at.is_some_and(|resume_at| resume_at <= now) {
+            return Err(HaltError::Invalid(format!("resume_at must be after {}", now)));
+        }
+        if let Some(halt) = self.configured_halt(&pair.base, &pair.quote, now) {
+            return Err(HaltError::Configured(halt.pair.clone()));
+        }
+        sqlx::query(
+            "INSERT INTO trading_halt (base, quote, reason, halted_at, resume_at) VALUES ($1, $2, $3, $4, $5)
+             ON CONFLICT ((LEAST(base, quote)), (GREATEST(base, quote)))
+             DO UPDATE SET base = EXCLUDED.base, quote = EXCLUDED.quote, reason = EXCLUDED.reason,
+                           halted_at = EXCLUDED.halted_at, resume_at = EXCLUDED.resume_at",
+        )
+            .bind(&pair.base)
+            .bind(&pair.quote)
+            .bind(&request.reason)
+            .bind(now as i64)
+            .bind(request.resume_at.map(|resume_at| resume_at as i64))
+            .execute(&self.pool)
+            .await?;
+        Ok(TradingHalt {
+            pair: pair.to_string(),
+            reason: request.reason,
+            halted_at: now,
+            resume_at: request.resume_at,
+            configured: false,
+        })
+    }
+
+    // Returns whether the pair was halted.
+    pub async fn resume(&self, pair: &Pair) -> Result<bool, HaltError> {
+        let now = clock::now();
+        if let Some(halt) = self.configured_halt(&pair.base, &pair.quote, now) {
+            return Err(HaltError::Configured(halt.pair.clone()));
+        }
+        let deleted = sqlx::query(
+            "DELETE FROM trading_halt
+             WHERE LEAST(base, quote) = LEAST($1, $2) AND GREATEST(base, quote) = GREATEST($1, $2)
+               AND (resume_at IS NULL OR resume_at > $3)",
+        )
+            .bind(&pair.base)
+            .bind(&pair.quote)
+            .bind(now as i64)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        Ok(deleted > 0)
+    }
+
+    // The halts in force, configured ones first.
+    pub async fn list(&self) -> Result<Vec<TradingHalt>, HaltError> {
+        let now = clock::now();
+        let rows = sqlx::query(
+            "SELECT base, quote, reason, halted_at, resume_at FROM trading_halt
+             WHERE resume_at IS NULL OR resume_at > $1
+             ORDER BY base, quote",
+        )
+            .bind(now as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        let stored = rows.iter().map(stored_halt).collect::<Result<Vec<_>, _>>()?;
+        Ok(self.configured.iter().filter(|halt| halt.active(now)).cloned().chain(stored).collect())
+    }
+
+    // The halt in force on the pair of the two assets, in either direction.
+    pub async fn active(&self, asset: &str, other_asset: &str) -> Result<Option<TradingHalt>, HaltError> {
+        let now = clock::now();
+        if let Some(halt) = self.configured_halt(asset, other_asset, now) {
+            return Ok(Some(halt.clone()));
+        }
+        let row = sqlx::query(
+            "SELECT base, quote, reason, halted_at, resume_at FROM trading_halt
+             WHERE LEAST(base, quote) = LEAST($1, $2) AND GREATEST(base, quote) = GREATEST($1, $2)
+               AND (resume_at IS NULL OR resume_at > $3)",
+        )
+            .bind(asset)
+            .bind(other_asset)
+            .bind(now as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.as_ref().map(stored_halt).transpose()?)
+    }
+
+    // Fails with `HaltError::Halted` while the pair of the two assets is halted.
+    pub async fn check(&self, asset: &str, other_asset: &str) -> Result<(), HaltError> {
+        match self.active(asset, other_asset).await? {
+            Some(halt) => Err(HaltError::Halted(Box::new(halt))),
+            None => Ok(()),
+        }
+    }
+
+    fn configured_halt(&self, asset: &str, other_asset: &str, now: u64) -> Option<&TradingHalt> {
+        let pair = format!("{}-{}", asset, other_asset);
+        let reversed = format!("{}-{}", other_asset, asset);
+        self.configured.iter().find(|halt| (halt.pair == pair || halt.pair == reversed) && halt.active(now))
+    }
+}
+
+fn stored_halt(row: &sqlx::postgres::PgRow) -> Result<TradingHalt, sqlx::Error> {
+    Ok(TradingHalt {
+        pair: format!("{}-{}", row.try_get::<String, _>("base")?, row.try_get::<String, _>("quote")?),
+        reason: row.try_get("reason")?,
+        halted_at: row.try_get::<i64, _>("halted_at")? as u64,
+        resume_at: row.try_get::<Option<i64>, _>("resume_at")?.map(|resume_at| resume_at as u64),
+        configured: false,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{ConfiguredHalt, TradingHaltConfig};
+    use crate::order::book::Pair;
+    use crate::order::halts::{HaltError, HaltRequest, TradingHalts};
+    use crate::util::clock;
+    use crate::util::test_db::{random_suffix, test_pool};
+
+    #[tokio::test]
+    async fn test_trading_halts() {
+        let pool = test_pool();
+        let suffix = random_suffix();
+        let (base, quote, other) = (format!("BASE{}", suffix), format!("QUOTE{}", suffix), format!("OTHER{}", suffix));
+        let pair = Pair { base: base.clone(), quote: quote.clone() };
+        let mut config = TradingHaltConfig::default();
+        let configured = ConfiguredHalt { reason: "Listing review".to_string(), resume_at: None };
+        config.pairs.insert(format!("{}-{}", other, quote), configured);
+        let halts = TradingHalts::new(pool.clone(), &config).unwrap();
+
+        assert!(halts.check(&base, &quote).await.is_ok());
+        let request = |resume_at| HaltRequest { reason: "Oracle outage".to_string(), resume_at };
+        let halt = halts.halt(&pair, request(None)).await.unwrap();
+        assert_eq!(halt.pair, format!("{}-{}", base, quote));
+        // Both directions of the pair are halted.
+        assert_eq!(halts.active(&quote, &base).await.unwrap(), Some(halt.clone()));
+        assert!(matches!(halts.check(&base, &quote).await, Err(HaltError::Halted(_))));
+        assert!(halts.list().await.unwrap().contains(&halt));
+
+        // Halting the reversed pair replaces the halt rather than adding another.
+        let reversed = Pair { base: quote.clone(), quote: base.clone() };
+        halts.halt(&reversed, request(Some(clock::now() + 3600))).await.unwrap();
+        let active = halts.active(&base, &quote).await.unwrap().unwrap();
+        assert_eq!((active.pair, active.resume_at.is_some()), (format!("{}-{}", quote, base), true));
+        assert!(matches!(halts.halt(&pair, request(Some(clock::now()))).await, Err(HaltError::Invalid(_))));
+
+        assert!(halts.resume(&pair).await.unwrap());
+        assert!(!halts.resume(&pair).await.unwrap());
+        assert!(halts.check(&base, &quote).await.is_ok());
+
+        // Configured halts can only be lifted in the configuration.
+        let configured = halts.active(&quote, &other).await.unwrap().unwrap();
+        assert!(configured.configured);
+        let other_pair = Pair { base: other.clone(), quote: quote.clone() };
+        assert!(matches!(halts.resume(&other_pair).await, Err(HaltError::Configured(_))));
+        assert!(matches!(halts.halt(&other_pair, request(None)).await, Err(HaltError::Configured(_))));
+
+        // A halt past its resume time no longer applies, without anyone lifting it.
+        halts.halt(&pair, request(None)).await.unwrap();
+        sqlx::query("UPDATE trading_halt SET resume_at = $1 WHERE base = $2")
+            .bind(clock::now() as i64 - 1)
+            .bind(&base)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(halts.active(&base, &quote).await.unwrap(), None);
+        assert!(!halts.list().await.unwrap().iter().any(|halt| halt.pair.contains(&suffix) && !halt.configured));
+
+        sqlx::query("DELETE FROM trading_halt WHERE base = $1 OR quote = $1")
+            .bind(&base)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(TradingHalts::new(pool, &TradingHaltConfig {
+            pairs: [("BTCUSDT".to_string(), ConfiguredHalt::default())].into_iter().collect(),
+            recheck_secs: 5,
+        }).is_err());
+    }
+}
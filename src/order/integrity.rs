@@ -0,0 +1,463 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use cqrs_es::AggregateError;
+use crate::background::{Background, Shutdown};
+use crate::commit_hooks::HookedCqrs;
+use serde::Serialize;
+use utoipa::ToSchema;
+use sqlx::{Pool, Postgres, Row};
+use crate::account::aggregate::Account;
+use crate::account::commands::AccountCommand;
+use crate::account::events::AccountError;
+use crate::config::IntegrityConfig;
+use crate::order::aggregate::{Order, OrderError};
+use crate::order::commands::OrderCommand;
+use crate::sealing::SealedCqrs;
+use crate::util::clock;
+use crate::util::types::ByteArray32;
+
+// Issues reported per kind and check, the counts are not capped.
+const MAX_REPORTED: i64 = 100;
+
+// What remediating an issue does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+pub enum Remediation {
+    // Returns the locked funds to the account.
+    Unlock,
+    // Cancels the order, putting it back up first while it is being bought.
+    CancelOrder,
+    // Locks the funds of the order again, so it can settle.
+    Relock,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(tag = "issue")]
+pub enum IntegrityIssue {
+    // An order holding funds by its status without the lock on the account: the seller's
+    // once placed, the buyer's too once bought.
+    MissingLock {
+        order_id: String,
+        order_status: String,
+        account_id: String,
+        asset: String,
+        amount: u64,
+        remediation: Remediation,
+    },
+    // A lock held under the id of an order or RFQ that holds no funds anymore.
+    DanglingLock {
+        txid: String,
+        // `order` or `rfq`.
+        owner: String,
+        owner_status: String,
+        account_id: String,
+        asset: String,
+        amount: u64,
+        remediation: Remediation,
+    },
+}
+
+impl IntegrityIssue {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            IntegrityIssue::MissingLock { .. } => "MissingLock",
+            IntegrityIssue::DanglingLock { .. } => "DanglingLock",
+        }
+    }
+
+    pub fn remediation(&self) -> Remediation {
+        match self {
+            IntegrityIssue::MissingLock { remediation, .. } | IntegrityIssue::DanglingLock { remediation, .. } => *remediation,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct IntegrityReport {
+    pub checks: u64,
+    pub last_check_at: Option<u64>,
+    // Checks that could not complete, retried on the next interval.
+    pub failures: u64,
+    // Issues found by the last check, by kind.
+    pub issues_found: BTreeMap<String, u64>,
+    // Up to 100 of them per kind, less those remediated since.
+    pub issues: Vec<IntegrityIssue>,
+    // The last check that found nothing.
+    pub last_clean_at: Option<u64>,
+    // Issues remediated by an operator since startup.
+    pub remediated: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IntegrityError {
+    #[error("No integrity issue with the lock of {1} on {0}, it may have been resolved meanwhile")]
+    NotFound(String, String),
+    #[error("Storage error: {0}")]
+    Storage(#[from] sqlx::Error),
+    #[error("Account error: {0}")]
+    Account(#[from] AggregateError<AccountError>),
+    #[error("Order error: {0}")]
+    Order(#[from] Box<AggregateError<OrderError>>),
+}
+
+// Periodically checks the orders and the locks held for them against each other, from
+// the order, RFQ and ledger projections: every order in `Placed`, `Buying` or `Bought`
+// has its funds locked on the accounts it trades, and every lock held under the id of
+// an order or RFQ belongs to one still under way. It only reports; an operator remediates
+// an issue at a time, see `Remediation`. Locks of failed and cancelled orders are released
+// by `crate::order::recovery`, and locks taken through the account commands belong to no
+// saga, neither is reported. Projections lag behind the aggregates, so orders and locks
+// within the grace period are left to the next check.
+#[derive(Clone)]
+pub struct LockIntegrity {
+    account_cqrs: Arc<SealedCqrs<Account>>,
+    order_cqrs: Arc<HookedCqrs<Order>>,
+    pool: Pool<Postgres>,
+    config: IntegrityConfig,
+    report: Arc<Mutex<IntegrityReport>>,
+}
+
+impl LockIntegrity {
+    pub fn new(account_cqrs: Arc<SealedCqrs<Account>>, order_cqrs: Arc<HookedCqrs<Order>>, pool: Pool<Postgres>, config: IntegrityConfig) -> Self {
+        LockIntegrity { account_cqrs, order_cqrs, pool, config, report: Default::default() }
+    }
+
+    pub fn spawn(&self, background: &Background) {
+        let integrity = self.clone();
+        background.spawn(|shutdown| async move { integrity.run(shutdown).await });
+    }
+
+    async fn run(&self, mut shutdown: Shutdown) {
+        loop {
+            if let Err(e) = self.check().await {
+                tracing::error!("Failed to check the integrity of order locks: {}", e);
+            }
+            if !shutdown.pause(Duration::from_secs(self.config.interval_secs.max(1))).await {
+                break;
+            }
+        }
+    }
+
+    pub async fn check(&self) -> Result<IntegrityReport, sqlx::Error> {
+        let checked = self.find(None, MAX_REPORTED).await;
+        let mut report = self.report.lock().expect("integrity report poisoned");
+        report.checks += 1;
+        report.last_check_at = Some(clock::now());
+        let (found, issues) = match checked {
+            Ok(checked) => checked,
+            Err(e) => {
+                report.failures += 1;
+                return Err(e);
+            }
+        };
+        for issue in &issues {
+            tracing::error!("Integrity issue: {:?}", issue);
+        }
+        if found.is_empty() {
+            report.last_clean_at = report.last_check_at;
+        }
+        report.issues_found = found;
+        report.issues = issues;
+        Ok(report.clone())
+    }
+
+    async fn find(&self, lock: Option<(&str, &str)>, limit: i64) -> Result<(BTreeMap<String, u64>, Vec<IntegrityIssue>), sqlx::Error> {
+        let cutoff = clock::now().saturating_sub(self.config.grace_secs);
+        let mut found = BTreeMap::new();
+        let mut issues = vec![];
+        for (count, reported) in [missing_locks(&self.pool, cutoff, lock, limit).await?, dangling_locks(&self.pool, cutoff, lock, limit).await?] {
+            if let Some(issue) = reported.first() {
+                found.insert(issue.kind().to_string(), count);
+            }
+            issues.extend(reported);
+        }
+        Ok((found, issues))
+    }
+
+    // Remediates the issue with the lock of `txid` on the account, as found now rather
+    // than by the last check, and returns it.
+    pub async fn remediate(&self, account_id: &str, txid: ByteArray32, metadata: HashMap<String, String>) -> Result<IntegrityIssue, IntegrityError> {
+        let (_, issues) = self.find(Some((account_id, &txid.hex())), 1).await?;
+        let Some(issue) = issues.into_iter().next() else {
+            return Err(IntegrityError::NotFound(account_id.to_string(), txid.hex()));
+        };
+        match &issue {
+            IntegrityIssue::DanglingLock { .. } => {
+                let command = AccountCommand::unlock_funds(txid, clock::now());
+                match self.account_cqrs.execute_with_metadata(account_id, command, metadata).await {
+                    Ok(_) | Err(AggregateError::UserError(AccountError::LockNotFound)) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            IntegrityIssue::MissingLock { order_status, asset, amount, remediation: Remediation::Relock, .. } => {
+                tracing::info!("Relocking {} {} on {} for {} order {}", amount, asset, account_id, order_status, txid.hex());
+                let command = AccountCommand::lock_funds(txid, clock::now(), asset.clone(), *amount);
+                match self.account_cqrs.execute_with_metadata(account_id, command, metadata).await {
+                    Ok(_) | Err(AggregateError::UserError(AccountError::DuplicateLock)) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            IntegrityIssue::MissingLock { order_id, order_status, .. } => {
+                let execute = |command| self.order_cqrs.execute_with_metadata(order_id, command, metadata.clone());
+                if order_status == "Buying" {
+                    execute(OrderCommand::Release).await.map_err(Box::new)?;
+                }
+                let reason = format!("Funds of {} no longer locked", account_id);
+                execute(OrderCommand::Cancel { reason }).await.map_err(Box::new)?;
+                execute(OrderCommand::Continue).await.map_err(Box::new)?;
+            }
+        }
+        tracing::warn!("Remediated {:?} with {:?}", issue, issue.remediation());
+        let mut report = self.report.lock().expect("integrity report poisoned");
+        report.remediated += 1;
+        if let Some(position) = report.issues.iter().position(|reported| reported == &issue) {
+            report.issues.remove(position);
+            if let Some(count) = report.issues_found.get_mut(issue.kind()) {
+                *count = count.saturating_sub(1);
+            }
+        }
+        Ok(issue)
+    }
+
+    pub fn report(&self) -> IntegrityReport {
+        self.report.lock().expect("integrity report poisoned").clone()
+    }
+}
+
+// Orders last changed before `cutoff` whose status holds funds without the lock on the
+// seller or buyer, with the number of them. Locks are held under the id of the order, the
+// order is reported under the id it is kept by. A lock counts until unlocked: settling the
+// first leg of a bought order takes the lock of the seller while it is still `Bought`.
+// `lock` narrows the check to the lock of a transaction on an account.
+pub async fn missing_locks(pool: &Pool<Postgres>, cutoff: u64, lock: Option<(&str, &str)>, limit: i64) -> Result<(u64, Vec<IntegrityIssue>), sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT o.view_id AS order_id, o.payload->>'status' AS order_status, side.account_id, side.asset, side.amount,
+                COUNT(*) OVER () AS found
+         FROM order_query o
+         CROSS JOIN LATERAL (VALUES
+             (o.payload->>'seller', o.payload->>'sell_asset', (o.payload->>'sell_amount')::bigint, TRUE),
+             (o.payload->>'buyer', o.payload->>'buy_asset', COALESCE(o.payload->>'fill_amount', o.payload->>'buy_amount')::bigint,
+              o.payload->>'status' = 'Bought')
+         ) AS side(account_id, asset, amount, holds)
+         WHERE o.payload->>'status' IN ('Placed', 'Buying', 'Bought')
+           AND side.holds
+           AND (o.payload->>'update_time')::bigint < $1
+           AND ($2::text IS NULL OR side.account_id = $2)
+           AND ($3::text IS NULL OR o.payload->>'id' = $3)
+           AND NOT EXISTS (
+               SELECT 1 FROM ledger_entries l
+               WHERE l.account_id = side.account_id AND l.txid = o.payload->>'id' AND l.detail->>'@t' = 'Lock'
+                 AND NOT EXISTS (
+                     SELECT 1 FROM ledger_entries r
+                     WHERE r.account_id = l.account_id AND r.txid = l.txid AND r.sequence > l.sequence
+                       AND r.detail->>'@t' = 'Unlock'
+                 )
+           )
+         ORDER BY (o.payload->>'update_time')::bigint, o.view_id, side.account_id
+         LIMIT $4",
+    )
+        .bind(cutoff as i64)
+        .bind(lock.map(|(account_id, _)| account_id))
+        .bind(lock.map(|(_, txid)| txid))
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+    let found = match rows.first() {
+        Some(row) => row.try_get::<i64, _>("found")? as u64,
+        None => 0,
+    };
+    let mut issues = Vec::with_capacity(rows.len());
+    for row in rows {
+        let order_status: String = row.try_get("order_status")?;
+        // Bought orders are past being cancelled, they settle once relocked.
+        let remediation = if order_status == "Bought" { Remediation::Relock } else { Remediation::CancelOrder };
+        issues.push(IntegrityIssue::MissingLock {
+            order_id: row.try_get("order_id")?,
+            order_status,
+            account_id: row.try_get("account_id")?,
+            asset: row.try_get("asset")?,
+            amount: row.try_get::<i64, _>("amount")? as u64,
+            remediation,
+        });
+    }
+    Ok((found, issues))
+}
+
+// Locks taken before `cutoff` and neither unlocked nor settled afterwards, held under the
+// id of a settled order or of an RFQ that is not accepted, with the number of them.
+// `lock` narrows the check to the lock of a transaction on an account.
+pub async fn dangling_locks(pool: &Pool<Postgres>, cutoff: u64, lock: Option<(&str, &str)>, limit: i64) -> Result<(u64, Vec<IntegrityIssue>), sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT l.txid, l.account_id, l.detail,
+                CASE WHEN o.payload IS NOT NULL THEN 'order' ELSE 'rfq' END AS owner,
+                COALESCE(o.payload->>'status', q.payload->>'status') AS owner_status,
+                COUNT(*) OVER () AS found
+         FROM ledger_entries l
+         LEFT JOIN order_query o ON o.payload->>'id' = l.txid
+         LEFT JOIN rfq_query q ON q.payload->>'id' = l.txid
+         WHERE l.detail->>'@t' = 'Lock'
+           AND l.timestamp < $1
+           AND ($2::text IS NULL OR l.account_id = $2)
+           AND ($3::text IS NULL OR l.txid = $3)
+           AND (o.payload->>'status' = 'Settled' OR q.payload->>'status' IN ('Open', 'Cancelled', 'Failed', 'Settled'))
+           AND NOT EXISTS (
+               SELECT 1 FROM ledger_entries r
+               WHERE r.account_id = l.account_id AND r.txid = l.txid AND r.sequence > l.sequence
+                 AND r.detail->>'@t' IN ('Unlock', 'Settlement')
+           )
+         ORDER BY l.timestamp, l.account_id, l.sequence
+         LIMIT $4",
+    )
+        .bind(cutoff as i64)
+        .bind(lock.map(|(account_id, _)| account_id))
+        .bind(lock.map(|(_, txid)| txid))
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+    let found = match rows.first() {
+        Some(row) => row.try_get::<i64, _>("found")? as u64,
+        None => 0,
+    };
+    let mut issues = Vec::with_capacity(rows.len());
+    for row in rows {
+        let detail: serde_json::Value = row.try_get("detail")?;
+        let field = |name: &str| detail.get(name).cloned().ok_or_else(|| sqlx::Error::ColumnNotFound(format!("detail.{}", name)));
+        let decode = |e: serde_json::Error| sqlx::Error::Decode(Box::new(e));
+        issues.push(IntegrityIssue::DanglingLock {
+            txid: row.try_get("txid")?,
+            owner: row.try_get("owner")?,
+            owner_status: row.try_get("owner_status")?,
+            account_id: row.try_get("account_id")?,
+            asset: serde_json::from_value(field("asset")?).map_err(decode)?,
+            amount: serde_json::from_value(field("amount")?).map_err(decode)?,
+            remediation: Remediation::Unlock,
+        });
+    }
+    Ok((found, issues))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use cqrs_es::EventEnvelope;
+    use rand::random;
+    use crate::account::aggregate::Account;
+    use crate::account::events::AccountEvent;
+    use crate::account::ledger::write_ledger_entries;
+    use crate::order::integrity::{dangling_locks, missing_locks, IntegrityIssue, Remediation};
+    use crate::util::test_db::{random_suffix, test_pool};
+    use crate::util::types::ByteArray32;
+
+    #[tokio::test]
+    async fn test_lock_integrity() {
+        let pool = test_pool();
+        let suffix = random_suffix();
+        let (seller, buyer) = (format!("SELLER-{}", suffix), format!("BUYER-{}", suffix));
+        let [placed, unlocked, buying, bought, settled, failed, rfq, direct] = [(); 8].map(|_| ByteArray32(random()));
+        // Kept by the id the commands were sent to, prefixed or not.
+        let view_id = |id: ByteArray32| format!("0x{}", id.hex());
+        let orders = [
+            (placed, "Placed", None),
+            (unlocked, "Placed", None),
+            (buying, "Buying", Some(&buyer)),
+            (bought, "Bought", Some(&buyer)),
+            (settled, "Settled", Some(&buyer)),
+            (failed, "Failed", None),
+        ];
+        for (order_id, status, buyer) in orders {
+            let payload = serde_json::json!({
+                "id": order_id.hex(), "seller": seller, "buyer": buyer, "status": status,
+                "sell_asset": "BTC", "sell_amount": 2, "buy_asset": "USDT", "buy_amount": 100, "update_time": 10,
+            });
+            sqlx::query("INSERT INTO order_query (view_id, version, payload) VALUES ($1, 1, $2::json)")
+                .bind(view_id(order_id))
+                .bind(payload.to_string())
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        sqlx::query("INSERT INTO rfq_query (view_id, version, payload) VALUES ($1, 1, $2::json)")
+            .bind(view_id(rfq))
+            .bind(serde_json::json!({ "id": rfq.hex(), "taker": seller, "status": "Failed" }).to_string())
+            .execute(&pool)
+            .await
+            .unwrap();
+        let envelope = |account_id: &str, sequence, payload| EventEnvelope::<Account> {
+            aggregate_id: account_id.to_string(),
+            sequence,
+            payload,
+            metadata: HashMap::new(),
+        };
+        write_ledger_entries(&pool, &[
+            envelope(&seller, 1, AccountEvent::funds_locked(placed, 10, "BTC".to_string(), 2)),
+            // Unlocked while the order is still up.
+            envelope(&seller, 2, AccountEvent::funds_locked(unlocked, 10, "BTC".to_string(), 2)),
+            envelope(&seller, 3, AccountEvent::funds_unlocked(unlocked, 10, "BTC".to_string(), 2)),
+            // The buyer has yet to lock, the seller did.
+            envelope(&seller, 4, AccountEvent::funds_locked(buying, 10, "BTC".to_string(), 2)),
+            // Bought without the buyer's lock.
+            envelope(&seller, 5, AccountEvent::funds_locked(bought, 10, "BTC".to_string(), 2)),
+            // Settled without ever settling the lock.
+            envelope(&seller, 6, AccountEvent::funds_locked(settled, 10, "BTC".to_string(), 2)),
+            // Left to the fund recovery.
+            envelope(&seller, 7, AccountEvent::funds_locked(failed, 10, "BTC".to_string(), 2)),
+            envelope(&buyer, 1, AccountEvent::funds_locked(rfq, 10, "USDT".to_string(), 50)),
+            // Locked through the account commands, of no saga.
+            envelope(&buyer, 2, AccountEvent::funds_locked(direct, 10, "USDT".to_string(), 5)),
+        ]).await.unwrap();
+
+        let ours = |issues: Vec<IntegrityIssue>| -> Vec<IntegrityIssue> {
+            issues.into_iter().filter(|issue| match issue {
+                IntegrityIssue::MissingLock { account_id, .. } | IntegrityIssue::DanglingLock { account_id, .. } => account_id.ends_with(&suffix),
+            }).collect()
+        };
+        let (_, missing) = missing_locks(&pool, 20, None, i64::MAX).await.unwrap();
+        let missing_lock = |order_id: ByteArray32, order_status: &str, account_id: &str, asset: &str, amount, remediation| IntegrityIssue::MissingLock {
+            order_id: view_id(order_id),
+            order_status: order_status.to_string(),
+            account_id: account_id.to_string(),
+            asset: asset.to_string(),
+            amount,
+            remediation,
+        };
+        let mut missing = ours(missing);
+        missing.sort_by_key(|issue| format!("{:?}", issue));
+        let mut expected = vec![
+            missing_lock(unlocked, "Placed", &seller, "BTC", 2, Remediation::CancelOrder),
+            missing_lock(bought, "Bought", &buyer, "USDT", 100, Remediation::Relock),
+        ];
+        expected.sort_by_key(|issue| format!("{:?}", issue));
+        assert_eq!(missing, expected);
+        // Within the grace period nothing is reported.
+        assert!(ours(missing_locks(&pool, 10, None, i64::MAX).await.unwrap().1).is_empty());
+
+        let (_, dangling) = dangling_locks(&pool, 20, None, i64::MAX).await.unwrap();
+        let dangling_lock = |txid: ByteArray32, owner: &str, account_id: &str, asset: &str, amount| IntegrityIssue::DanglingLock {
+            txid: txid.hex(),
+            owner: owner.to_string(),
+            owner_status: if owner == "order" { "Settled" } else { "Failed" }.to_string(),
+            account_id: account_id.to_string(),
+            asset: asset.to_string(),
+            amount,
+            remediation: Remediation::Unlock,
+        };
+        let mut dangling = ours(dangling);
+        dangling.sort_by_key(|issue| format!("{:?}", issue));
+        let mut expected = vec![dangling_lock(settled, "order", &seller, "BTC", 2), dangling_lock(rfq, "rfq", &buyer, "USDT", 50)];
+        expected.sort_by_key(|issue| format!("{:?}", issue));
+        assert_eq!(dangling, expected);
+        // Narrowed to one lock.
+        let (found, issues) = dangling_locks(&pool, 20, Some((&buyer, &rfq.hex())), 10).await.unwrap();
+        assert_eq!((found, issues), (1, vec![dangling_lock(rfq, "rfq", &buyer, "USDT", 50)]));
+
+        sqlx::query("DELETE FROM ledger_entries WHERE account_id IN ($1, $2)")
+            .bind(&seller)
+            .bind(&buyer)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let ids: Vec<String> = [placed, unlocked, buying, bought, settled, failed, rfq].map(view_id).to_vec();
+        sqlx::query("DELETE FROM order_query WHERE view_id = ANY($1)").bind(&ids).execute(&pool).await.unwrap();
+        sqlx::query("DELETE FROM rfq_query WHERE view_id = ANY($1)").bind(&ids).execute(&pool).await.unwrap();
+    }
+}
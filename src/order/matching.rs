@@ -0,0 +1,472 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use cqrs_es::{EventEnvelope, Query};
+use crate::background::Background;
+use crate::commit_hooks::HookedCqrs;
+use serde::Serialize;
+use utoipa::ToSchema;
+use sqlx::{Pool, Postgres, Row};
+use tokio::sync::mpsc;
+use crate::auction::engine::order_status;
+use crate::order::aggregate::Order;
+use crate::order::commands::OrderCommand;
+use crate::order::events::OrderEvent;
+use crate::order::halts::TradingHalts;
+use crate::order::queries::OrderState;
+use crate::util::clock;
+
+// Counter-orders tried per placed order before giving up on it.
+const MAX_CANDIDATES: i64 = 10;
+// Window `matches_per_sec` is averaged over.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+const MAX_SAMPLES: usize = 100_000;
+// Stranded makers released per sweep.
+const STRANDED_BATCH: i64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Matched {
+    // Matched, or left resting in the book.
+    Done,
+    Halted,
+}
+
+// A placed order as listed in `open_orders`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BookOrder {
+    pub order_id: String,
+    pub seller: String,
+    pub sell_asset: String,
+    pub sell_amount: u64,
+    pub buy_asset: String,
+    pub buy_amount: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+pub struct MatchingStats {
+    pub matches: u64,
+    pub failures: u64,
+    pub matches_per_sec: f64,
+}
+
+#[derive(Clone, Default)]
+pub struct MatchingMetrics {
+    matches: Arc<AtomicU64>,
+    failures: Arc<AtomicU64>,
+    recent: Arc<Mutex<VecDeque<Instant>>>,
+}
+
+impl MatchingMetrics {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    fn record_match(&self, now: Instant) {
+        self.matches.fetch_add(1, Ordering::Relaxed);
+        let mut recent = self.recent.lock().expect("matching metrics poisoned");
+        if recent.len() == MAX_SAMPLES {
+            recent.pop_front();
+        }
+        recent.push_back(now);
+    }
+
+    fn record_failure(&self) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self, now: Instant) -> MatchingStats {
+        let mut recent = self.recent.lock().expect("matching metrics poisoned");
+        while recent.front().is_some_and(|at| now.duration_since(*at) > RATE_WINDOW) {
+            recent.pop_front();
+        }
+        MatchingStats {
+            matches: self.matches.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            matches_per_sec: recent.len() as f64 / RATE_WINDOW.as_secs_f64(),
+        }
+    }
+}
+
+// Hands every placed order to the `OrderMatcher`. Commands cannot be sent to the order
+// aggregate from within its own query dispatch, hence the channel.
+pub struct MatchingQuery {
+    sender: mpsc::UnboundedSender<String>,
+}
+
+impl MatchingQuery {
+    pub fn channel() -> (Self, mpsc::UnboundedReceiver<String>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (MatchingQuery { sender }, receiver)
+    }
+}
+
+#[async_trait]
+impl Query<Order> for MatchingQuery {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<Order>]) {
+        if events.iter().any(|e| matches!(e.payload, OrderEvent::Placed { .. })) {
+            // The receiver only goes away when the server shuts down.
+            let _ = self.sender.send(aggregate_id.to_string());
+        }
+    }
+}
+
+// Matches every newly placed order (the taker) against the resting counter-orders of
+// the book (the makers). Orders are all-or-nothing, so a maker only crosses when it asks
+// for exactly the amount the taker sells, and pays at least what the taker asks for.
+// The trade happens at the maker's price; among crossing makers the best price wins,
+// then the oldest, then the lowest order id.
+//
+// A match reserves the maker with `Buy`, withdraws the taker with `Cancel`/`Continue`
+// to free the taker's funds, and settles the maker with the taker's seller as buyer
+// through `Continue`/`Continue`. A maker whose taker cannot be withdrawn is put back up
+// with `Release`.
+//
+// Orders of a halted pair are held back and retried every `recheck` until the halt is
+// lifted or runs out.
+//
+// A match cut short between `Buy` and the maker's settlement, e.g. by a crash, leaves the
+// maker in `Buying`. Makers still there `stranded` after they last moved are released on
+// startup and every `recheck`; their `Placed` brings them back to the matcher.
+pub struct OrderMatcher {
+    order_cqrs: Arc<HookedCqrs<Order>>,
+    pool: Pool<Postgres>,
+    metrics: MatchingMetrics,
+    halts: TradingHalts,
+    recheck: Duration,
+    stranded: Duration,
+}
+
+impl OrderMatcher {
+    pub fn new(
+        order_cqrs: Arc<HookedCqrs<Order>>,
+        pool: Pool<Postgres>,
+        metrics: MatchingMetrics,
+        halts: TradingHalts,
+        recheck: Duration,
+        stranded: Duration,
+    ) -> Self {
+        OrderMatcher { order_cqrs, pool, metrics, halts, recheck, stranded }
+    }
+
+    // Orders are matched one at a time, so two takers never race for the same maker.
+    // Orders placed while the server was down are matched on startup, oldest first.
+    // Those still queued on shutdown are left to the startup of the next node.
+    pub fn spawn(self, background: &Background, mut placed: mpsc::UnboundedReceiver<String>) {
+        background.spawn(|mut shutdown| async move {
+            // In placement order, so a resumed pair matches its oldest orders first.
+            let mut held = Vec::new();
+            self.release_stranded().await;
+            match resting_orders(&self.pool).await {
+                Ok(order_ids) => {
+                    for order_id in order_ids {
+                        if self.match_order(&order_id).await == Matched::Halted {
+                            held.push(order_id);
+                        }
+                    }
+                }
+                Err(e) => tracing::error!("Failed to load resting orders for matching: {}", e),
+            }
+            let mut recheck = tokio::time::interval(self.recheck);
+            loop {
+                tokio::select! {
+                    order_id = placed.recv() => {
+                        let Some(order_id) = order_id else { break };
+                        if self.match_order(&order_id).await == Matched::Halted && !held.contains(&order_id) {
+                            held.push(order_id);
+                        }
+                    }
+                    _ = recheck.tick() => {
+                        self.release_stranded().await;
+                        for order_id in std::mem::take(&mut held) {
+                            if self.match_order(&order_id).await == Matched::Halted {
+                                held.push(order_id);
+                            }
+                        }
+                    }
+                    _ = shutdown.stopped() => break,
+                }
+            }
+        });
+    }
+
+    // Matches run one at a time in this task, so no maker released here is mid-match.
+    async fn release_stranded(&self) {
+        let cutoff = clock::now().saturating_sub(self.stranded.as_secs());
+        let order_ids = match stranded_makers(&self.pool, cutoff, STRANDED_BATCH).await {
+            Ok(order_ids) => order_ids,
+            Err(e) => {
+                tracing::error!("Failed to load stranded orders: {}", e);
+                return;
+            }
+        };
+        for order_id in order_ids {
+            // The order may have moved on since the view was read; nothing to release then.
+            match self.order_cqrs.execute(&order_id, OrderCommand::Release).await {
+                Ok(()) => tracing::warn!("Released order {} stranded in a match", order_id),
+                Err(e) => tracing::debug!("Order {} not released: {}", order_id, e),
+            }
+        }
+    }
+
+    async fn match_order(&self, order_id: &str) -> Matched {
+        let taker = match placed_order(&self.pool, order_id).await {
+            Ok(Some(taker)) => taker,
+            Ok(None) => return Matched::Done,
+            Err(e) => {
+                tracing::error!("Failed to load order {} for matching: {}", order_id, e);
+                return Matched::Done;
+            }
+        };
+        match self.halts.active(&taker.sell_asset, &taker.buy_asset).await {
+            Ok(None) => {}
+            Ok(Some(halt)) => {
+                tracing::debug!("Order {} held back, trading in {} is halted", order_id, halt.pair);
+                return Matched::Halted;
+            }
+            Err(e) => {
+                tracing::error!("Failed to check the halt on order {}: {}", order_id, e);
+                return Matched::Halted;
+            }
+        }
+        let makers = match crossing_orders(&self.pool, &taker).await {
+            Ok(makers) => makers,
+            Err(e) => {
+                tracing::error!("Failed to find orders crossing {}: {}", order_id, e);
+                return Matched::Done;
+            }
+        };
+        for maker in makers {
+            // Another buyer got there first; try the next one.
+            let command = OrderCommand::Buy { buyer: taker.seller.clone(), timestamp: clock::now() };
+            if let Err(e) = self.order_cqrs.execute(&maker.order_id, command).await {
+                tracing::debug!("Order {} not matched against {}: {}", maker.order_id, order_id, e);
+                continue;
+            }
+            match self.execute(&maker, &taker).await {
+                Ok(()) => {
+                    tracing::info!("Order {} matched against {}", order_id, maker.order_id);
+                    self.metrics.record_match(Instant::now());
+                }
+                Err(e) => {
+                    tracing::error!("Failed to match order {} against {}: {}", order_id, maker.order_id, e);
+                    self.metrics.record_failure();
+                }
+            }
+            return Matched::Done;
+        }
+        Matched::Done
+    }
+
+    async fn execute(&self, maker: &BookOrder, taker: &BookOrder) -> Result<(), String> {
+        let withdraw = OrderCommand::Cancel { reason: format!("Matched against order {}", maker.order_id) };
+        let withdrawn = match self.order_cqrs.execute(&taker.order_id, withdraw).await {
+            Ok(()) => self.order_cqrs.execute(&taker.order_id, OrderCommand::Continue).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = withdrawn {
+            if let Err(release) = self.order_cqrs.execute(&maker.order_id, OrderCommand::Release).await {
+                tracing::error!("Failed to release order {}: {}", maker.order_id, release);
+            }
+            return Err(format!("taker not withdrawn: {}", e));
+        }
+        for _ in 0..2 {
+            self.order_cqrs.execute(&maker.order_id, OrderCommand::Continue).await.map_err(|e| e.to_string())?;
+        }
+        // A taker whose freed funds were spent in the meantime sends the maker back to Placed.
+        match order_status(&self.pool, &maker.order_id).await {
+            Ok(Some(OrderState::Settled)) => Ok(()),
+            Ok(state) => Err(format!("maker ended in {:?} state", state)),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+fn book_order(row: &sqlx::postgres::PgRow) -> Result<BookOrder, sqlx::Error> {
+    Ok(BookOrder {
+        order_id: row.try_get("order_id")?,
+        seller: row.try_get("seller")?,
+        sell_asset: row.try_get("sell_asset")?,
+        sell_amount: row.try_get::<i64, _>("sell_amount")? as u64,
+        buy_asset: row.try_get("buy_asset")?,
+        buy_amount: row.try_get::<i64, _>("buy_amount")? as u64,
+    })
+}
+
+async fn resting_orders(pool: &Pool<Postgres>) -> Result<Vec<String>, sqlx::Error> {
+    let rows = sqlx::query("SELECT order_id FROM open_orders WHERE placed ORDER BY timestamp, order_id")
+        .fetch_all(pool)
+        .await?;
+    rows.iter().map(|row| row.try_get("order_id")).collect()
+}
+
+async fn placed_order(pool: &Pool<Postgres>, order_id: &str) -> Result<Option<BookOrder>, sqlx::Error> {
+    sqlx::query(
+        "SELECT order_id, seller, sell_asset, sell_amount, buy_asset, buy_amount FROM open_orders
+         WHERE order_id = $1 AND placed",
+    )
+        .bind(order_id)
+        .fetch_optional(pool)
+        .await?
+        .as_ref()
+        .map(book_order)
+        .transpose()
+}
+
+// Book orders reserved by a buyer that last moved at or before `cutoff`, oldest first.
+// Auction fills are left to the auction engine.
+pub async fn stranded_makers(pool: &Pool<Postgres>, cutoff: u64, limit: i64) -> Result<Vec<String>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT view_id FROM order_query
+         WHERE payload->>'status' = 'Buying'
+           AND NOT COALESCE((payload->>'auction')::boolean, false)
+           AND (payload->>'update_time')::bigint <= $1
+         ORDER BY (payload->>'update_time')::bigint, view_id
+         LIMIT $2",
+    )
+        .bind(cutoff as i64)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+    rows.iter().map(|row| row.try_get("view_id")).collect()
+}
+
+// Best counter-orders for `taker` first. `price` is the maker's buy amount per sell
+// amount, which is the taker's sell amount here, so a lower price pays the taker more.
+pub async fn crossing_orders(pool: &Pool<Postgres>, taker: &BookOrder) -> Result<Vec<BookOrder>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT order_id, seller, sell_asset, sell_amount, buy_asset, buy_amount FROM open_orders
+         WHERE placed AND sell_asset = $1 AND buy_asset = $2 AND buy_amount = $3 AND sell_amount >= $4
+           AND seller <> $5 AND order_id <> $6
+         ORDER BY price, timestamp, order_id
+         LIMIT $7",
+    )
+        .bind(&taker.buy_asset)
+        .bind(&taker.sell_asset)
+        .bind(taker.sell_amount as i64)
+        .bind(taker.buy_amount as i64)
+        .bind(&taker.seller)
+        .bind(&taker.order_id)
+        .bind(MAX_CANDIDATES)
+        .fetch_all(pool)
+        .await?;
+    rows.iter().map(book_order).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::EventEnvelope;
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+    use crate::order::aggregate::Order;
+    use crate::order::book::write_order_book;
+    use crate::order::events::{OrderConfig, OrderEvent};
+    use crate::order::matching::{crossing_orders, stranded_makers, BookOrder, MatchingMetrics};
+    use serde_json::json;
+    use crate::util::test_db::{delete_prefixed, random_suffix, test_pool};
+
+    #[test]
+    fn test_matching_rate() {
+        let metrics = MatchingMetrics::new();
+        let start = Instant::now();
+        for _ in 0..30 {
+            metrics.record_match(start);
+        }
+        metrics.record_failure();
+        let stats = metrics.stats(start);
+        assert_eq!((stats.matches, stats.failures), (30, 1));
+        assert_eq!(stats.matches_per_sec, 0.5);
+        // Matches older than the window no longer count towards the rate.
+        let stats = metrics.stats(start + Duration::from_secs(61));
+        assert_eq!((stats.matches, stats.matches_per_sec), (30, 0.0));
+    }
+
+    #[tokio::test]
+    async fn test_crossing_orders() {
+        let pool = test_pool();
+        let suffix = random_suffix();
+        let (base, quote) = (format!("BASE{}", suffix), format!("QUOTE{}", suffix));
+        let order = |order_id: &str, seller: &str, sell_asset: &str, sell_amount, buy_asset: &str, buy_amount, timestamp| {
+            let aggregate_id = format!("{}-{}", order_id, suffix);
+            let envelope = |sequence, payload| EventEnvelope::<Order> {
+                aggregate_id: aggregate_id.clone(),
+                sequence,
+                payload,
+                metadata: HashMap::new(),
+            };
+            vec![
+                envelope(1, OrderEvent::Initialized {
+                    config: OrderConfig {
+                        seller: seller.to_string(),
+                        sell_asset: sell_asset.to_string(),
+                        sell_amount,
+                        buy_asset: buy_asset.to_string(),
+                        buy_amount,
+                        timestamp,
+                        ..Default::default()
+                    },
+                }),
+                envelope(2, OrderEvent::Placed { timestamp }),
+            ]
+        };
+        let mut events = vec![];
+        events.extend(order("fair", "ACCT-0002", &quote, 100, &base, 10, 1));
+        events.extend(order("generous-late", "ACCT-0003", &quote, 110, &base, 10, 3));
+        events.extend(order("generous-early", "ACCT-0004", &quote, 110, &base, 10, 2));
+        events.extend(order("stingy", "ACCT-0005", &quote, 90, &base, 10, 1));
+        events.extend(order("too-big", "ACCT-0006", &quote, 200, &base, 20, 1));
+        events.extend(order("own", "ACCT-0001", &quote, 150, &base, 10, 1));
+
+        let mut conn = pool.acquire().await.unwrap();
+        write_order_book(&mut conn, &events).await.unwrap();
+
+        let taker = BookOrder {
+            order_id: format!("taker-{}", suffix),
+            seller: "ACCT-0001".to_string(),
+            sell_asset: base.clone(),
+            sell_amount: 10,
+            buy_asset: quote.clone(),
+            buy_amount: 100,
+        };
+        let makers: Vec<String> = crossing_orders(&pool, &taker).await.unwrap().into_iter().map(|o| o.order_id).collect();
+        assert_eq!(makers, vec![
+            format!("generous-early-{}", suffix),
+            format!("generous-late-{}", suffix),
+            format!("fair-{}", suffix),
+        ]);
+
+        sqlx::query("DELETE FROM open_orders WHERE order_id LIKE $1")
+            .bind(format!("%-{}", suffix))
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stranded_makers() {
+        let pool = test_pool();
+        let prefix = format!("STRANDED-{}-", random_suffix());
+        // Far in the past, so nothing else in the table sorts before them.
+        let orders = [
+            ("buying", "Buying", false, 5),
+            ("auction", "Buying", true, 5),
+            ("placed", "Placed", false, 5),
+            ("recent", "Buying", false, 500),
+        ];
+        for (id, status, auction, update_time) in orders {
+            sqlx::query("INSERT INTO order_query (view_id, version, payload) VALUES ($1, 1, $2)")
+                .bind(format!("{}{}", prefix, id))
+                .bind(json!({ "status": status, "auction": auction, "update_time": update_time }))
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        let stranded = stranded_makers(&pool, 100, 1000).await.unwrap();
+        let ours: Vec<&str> = stranded.iter().filter_map(|id| id.strip_prefix(&prefix)).collect();
+        assert_eq!(ours, vec!["buying"]);
+
+        delete_prefixed(&pool, "order_query", "view_id", &prefix).await;
+    }
+}
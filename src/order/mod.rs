@@ -1,4 +1,11 @@
 pub mod aggregate;
+pub mod book;
 pub mod commands;
 pub mod events;
+pub mod expiry;
+pub mod halts;
+pub mod integrity;
+pub mod matching;
+pub mod policy;
 pub mod queries;
+pub mod recovery;
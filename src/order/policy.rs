@@ -0,0 +1,95 @@
+use std::sync::Arc;
+use cqrs_es::persist::ViewRepository;
+use crate::asset::aggregate::Asset;
+use crate::asset::queries::AssetView;
+use crate::asset::types::TradingRules;
+use crate::order::events::OrderConfig;
+
+// Keeps orders no one could sensibly trade from being opened: both amounts must be
+// positive and meet the trading rules of their pair, which the asset registry holds on
+// the base asset. An order selling the base asset trades its sell amount for a quote
+// amount it buys, one buying it the other way round. Pairs without rules keep the
+// defaults, the accounts reject unregistered assets when the order locks its funds.
+#[derive(Clone)]
+pub struct OrderPolicyService {
+    asset_query: Arc<dyn ViewRepository<AssetView, Asset>>,
+}
+
+impl OrderPolicyService {
+    pub fn new(asset_query: Arc<dyn ViewRepository<AssetView, Asset>>) -> Self {
+        OrderPolicyService { asset_query }
+    }
+
+    // Why the order may not be opened, if it may not.
+    pub async fn violation(&self, config: &OrderConfig) -> Option<String> {
+        if config.sell_amount == 0 || config.buy_amount == 0 {
+            return Some("order amounts must be positive".to_string());
+        }
+        let sides = [
+            (&config.sell_asset, &config.buy_asset, config.sell_amount, config.buy_amount),
+            (&config.buy_asset, &config.sell_asset, config.buy_amount, config.sell_amount),
+        ];
+        for (base, quote, quantity, quote_amount) in sides {
+            let rules = match self.asset_query.load(base).await {
+                Ok(view) => view.and_then(|view| view.trading_rules.get(quote).copied()),
+                Err(e) => {
+                    // Fail closed, an unreadable registry must not let any order through.
+                    tracing::error!("Failed to load the trading rules of {}: {}", base, e);
+                    return Some(format!("trading rules of {} are unavailable", base));
+                }
+            };
+            if let Some(rules) = rules {
+                return rules.violation(quantity, quote_amount).map(|violation| format!("{}-{} {}", base, quote, violation));
+            }
+        }
+        TradingRules::default().violation(config.sell_amount, config.buy_amount)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use cqrs_es::persist::{ViewContext, ViewRepository};
+    use postgres_es::PostgresViewRepository;
+    use crate::asset::aggregate::Asset;
+    use crate::asset::types::{Price, TradingRules};
+    use crate::asset::queries::AssetView;
+    use crate::order::events::OrderConfig;
+    use crate::order::policy::OrderPolicyService;
+    use crate::util::test_db::{delete_rows, random_suffix, test_pool};
+
+    #[tokio::test]
+    async fn test_order_policy() {
+        let pool = test_pool();
+        let repo = Arc::new(PostgresViewRepository::<AssetView, Asset>::new("asset_query", pool.clone()));
+        let suffix = random_suffix().to_uppercase();
+        let (base, quote) = (format!("BASE{}", suffix), format!("QUOTE{}", suffix));
+        let rules = TradingRules { tick_size: 100, min_notional: 1_000, min_price: Some(Price::new(5, 1)), max_price: Some(Price::new(20, 1)) };
+        let view = AssetView { symbol: base.clone(), enabled: true, trading_rules: BTreeMap::from([(quote.clone(), rules)]), ..Default::default() };
+        repo.update_view(view, ViewContext::new(base.clone(), 0)).await.unwrap();
+
+        let policy = OrderPolicyService::new(repo);
+        let order = |sell_asset: &String, sell_amount, buy_asset: &String, buy_amount| OrderConfig {
+            sell_asset: sell_asset.clone(),
+            sell_amount,
+            buy_asset: buy_asset.clone(),
+            buy_amount,
+            ..Default::default()
+        };
+        // Asks sell the base asset, bids buy it, both under the rules of the pair.
+        assert_eq!(policy.violation(&order(&base, 200, &quote, 2_000)).await, None);
+        assert_eq!(policy.violation(&order(&quote, 2_000, &base, 200)).await, None);
+        assert!(policy.violation(&order(&base, 100, &quote, 900)).await.unwrap().contains("notional"));
+        assert!(policy.violation(&order(&quote, 900, &base, 100)).await.unwrap().contains("notional"));
+        assert!(policy.violation(&order(&base, 250, &quote, 2_500)).await.unwrap().contains("tick size"));
+        assert!(policy.violation(&order(&base, 100, &quote, 2_100)).await.unwrap().contains("above the band"));
+        assert!(policy.violation(&order(&quote, 1_000, &base, 300)).await.unwrap().contains("below the band"));
+        assert!(policy.violation(&order(&base, 200, &quote, 0)).await.is_some());
+        // The base asset trades against other assets under the default rules.
+        let other = format!("OTHER{}", suffix);
+        assert_eq!(policy.violation(&order(&base, 7, &other, 1)).await, None);
+
+        delete_rows(&pool, "asset_query", "view_id", &base).await;
+    }
+}
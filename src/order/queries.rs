@@ -3,12 +3,14 @@ use cqrs_es::{EventEnvelope, Query, View};
 use cqrs_es::persist::GenericQuery;
 use postgres_es::PostgresViewRepository;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 use crate::order::aggregate::Order;
 use crate::order::events::OrderEvent;
+use crate::util::metadata::EventOrigin;
 
 pub struct SimpleLoggingQuery {}
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, ToSchema)]
 pub enum OrderState {
     #[default]
     Initial,
@@ -21,7 +23,7 @@ pub enum OrderState {
     Settled,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, ToSchema)]
 pub struct OrderView {
     pub id: String,
     pub buyer: Option<String>,
@@ -30,11 +32,22 @@ pub struct OrderView {
     pub sell_amount: u64,
     pub buy_asset: String,
     pub buy_amount: u64,
+    #[serde(default)]
+    pub auction: bool,
+    #[serde(default)]
+    pub fill_amount: Option<u64>,
+    #[serde(default)]
+    pub expires_at: Option<u64>,
     pub status: OrderState,
     pub reason: Option<String>,
     pub create_time: u64,
     pub update_time: u64,
     pub settle_time: Option<u64>,
+    #[serde(default)]
+    pub created_by: Option<EventOrigin>,
+    // Origin of the most recent change to this order.
+    #[serde(default)]
+    pub updated_by: Option<EventOrigin>,
 }
 
 #[async_trait]
@@ -55,6 +68,7 @@ pub type OrderQuery = GenericQuery<
 
 impl View<Order> for OrderView {
     fn update(&mut self, event: &EventEnvelope<Order>) {
+        self.updated_by = EventOrigin::from_metadata(&event.metadata);
         match &event.payload {
             OrderEvent::Initialized { config } => {
                 self.id = config.order_id.hex();
@@ -63,13 +77,16 @@ impl View<Order> for OrderView {
                 self.sell_amount = config.sell_amount;
                 self.buy_asset = config.buy_asset.clone();
                 self.buy_amount = config.buy_amount;
+                self.auction = config.auction;
+                self.expires_at = config.expires_at;
                 self.status = OrderState::Initial;
                 self.create_time = config.timestamp;
                 self.update_time = config.timestamp;
-
+                self.created_by = self.updated_by.clone();
             }
             OrderEvent::Placed { timestamp } => {
                 self.update_time = *timestamp;
+                self.fill_amount = None;
                 self.status = OrderState::Placed;
             }
             OrderEvent::Cancelling { timestamp, reason } => {
@@ -81,8 +98,9 @@ impl View<Order> for OrderView {
                 self.update_time = *timestamp;
                 self.status = OrderState::Cancelled;
             }
-            OrderEvent::Buying { buyer, timestamp } => {
+            OrderEvent::Buying { buyer, timestamp, fill_amount } => {
                 self.buyer = Some(buyer.clone());
+                self.fill_amount = *fill_amount;
                 self.update_time = *timestamp;
                 self.status = OrderState::Buying;
             }
@@ -0,0 +1,237 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use cqrs_es::AggregateError;
+use serde::Serialize;
+use utoipa::ToSchema;
+use sqlx::{Pool, Postgres, Row};
+use crate::background::{Background, Shutdown};
+use crate::account::aggregate::Account;
+use crate::account::commands::AccountCommand;
+use crate::account::events::AccountError;
+use crate::config::OrderRecoveryConfig;
+use crate::sealing::SealedCqrs;
+use crate::util::clock;
+use crate::util::types::ByteArray32;
+
+const SWEEP_BATCH: i64 = 500;
+const MAX_RECENT: usize = 100;
+
+// A lock an order took on an account and never released, although the order ended.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct OrphanedLock {
+    pub order_id: String,
+    pub account_id: String,
+    // `Failed` or `Cancelled`.
+    pub order_status: String,
+    pub asset: String,
+    pub amount: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct RecoveredLock {
+    #[serde(flatten)]
+    pub lock: OrphanedLock,
+    pub recovered_at: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct RecoveryReport {
+    pub sweeps: u64,
+    pub last_sweep_at: Option<u64>,
+    // Funds returned to their accounts since startup, by asset.
+    pub recovered: BTreeMap<String, u64>,
+    pub recovered_locks: u64,
+    // Unlocks that failed, retried on the next sweep.
+    pub failures: u64,
+    // The latest recoveries, newest first.
+    #[schema(value_type = Vec<RecoveredLock>)]
+    pub recent: VecDeque<RecoveredLock>,
+}
+
+// Returns funds an order saga left locked when it died between locking and failing or
+// cancelling, e.g. on a crash or a framework error after the lock was stored. Failed
+// and cancelled orders are final, so a lock still held under their id is orphaned and
+// unlocking it cannot race the saga. Orphans are found from the ledger projection, which
+// may lag behind the accounts: a lock released meanwhile is reported as `LockNotFound`
+// and skipped.
+#[derive(Clone)]
+pub struct FundRecovery {
+    account_cqrs: Arc<SealedCqrs<Account>>,
+    pool: Pool<Postgres>,
+    config: OrderRecoveryConfig,
+    report: Arc<Mutex<RecoveryReport>>,
+}
+
+impl FundRecovery {
+    pub fn new(account_cqrs: Arc<SealedCqrs<Account>>, pool: Pool<Postgres>, config: OrderRecoveryConfig) -> Self {
+        FundRecovery { account_cqrs, pool, config, report: Default::default() }
+    }
+
+    pub fn spawn(&self, background: &Background) {
+        let recovery = self.clone();
+        background.spawn(|shutdown| async move { recovery.run(shutdown).await });
+    }
+
+    async fn run(&self, mut shutdown: Shutdown) {
+        loop {
+            match self.sweep().await {
+                // A batch fully recovered means more may be waiting.
+                Ok(recovered) if recovered.len() as i64 == SWEEP_BATCH && !shutdown.is_stopping() => continue,
+                Ok(_) => {}
+                Err(e) => tracing::error!("Failed to sweep orphaned order locks: {}", e),
+            }
+            if !shutdown.pause(Duration::from_secs(self.config.sweep_interval_secs.max(1))).await {
+                break;
+            }
+        }
+    }
+
+    // Unlocks the orphaned locks found and returns the ones released by this sweep.
+    pub async fn sweep(&self) -> Result<Vec<RecoveredLock>, sqlx::Error> {
+        let mut recovered = vec![];
+        let mut failures = 0;
+        for lock in orphaned_locks(&self.pool, SWEEP_BATCH).await? {
+            let Ok(order_id) = lock.order_id.parse::<ByteArray32>() else {
+                tracing::error!("Lock of {} has a malformed order id {}", lock.account_id, lock.order_id);
+                continue;
+            };
+            match self.account_cqrs.execute(&lock.account_id, AccountCommand::unlock_funds(order_id, clock::now())).await {
+                Ok(_) => {
+                    tracing::warn!("Recovered {} {} locked by {} order {} on {}", lock.amount, lock.asset, lock.order_status, lock.order_id, lock.account_id);
+                    recovered.push(RecoveredLock { lock, recovered_at: clock::now() });
+                }
+                Err(AggregateError::UserError(AccountError::LockNotFound)) => {}
+                Err(e) => {
+                    failures += 1;
+                    tracing::error!("Failed to recover the lock of order {} on {}: {}", lock.order_id, lock.account_id, e);
+                }
+            }
+        }
+        self.record(&recovered, failures);
+        Ok(recovered)
+    }
+
+    fn record(&self, recovered: &[RecoveredLock], failures: u64) {
+        let mut report = self.report.lock().expect("recovery report poisoned");
+        report.sweeps += 1;
+        report.last_sweep_at = Some(clock::now());
+        report.failures += failures;
+        report.recovered_locks += recovered.len() as u64;
+        for lock in recovered {
+            *report.recovered.entry(lock.lock.asset.clone()).or_insert(0) += lock.lock.amount;
+            report.recent.push_front(lock.clone());
+        }
+        report.recent.truncate(MAX_RECENT);
+    }
+
+    pub fn report(&self) -> RecoveryReport {
+        self.report.lock().expect("recovery report poisoned").clone()
+    }
+}
+
+// Locks of failed and cancelled orders on their seller or buyer that were neither
+// unlocked nor settled afterwards, oldest first.
+pub async fn orphaned_locks(pool: &Pool<Postgres>, limit: i64) -> Result<Vec<OrphanedLock>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT o.payload->>'id' AS order_id, o.payload->>'status' AS order_status, l.account_id, l.detail
+         FROM order_query o
+         JOIN ledger_entries l
+           ON l.account_id IN (o.payload->>'seller', o.payload->>'buyer') AND l.txid = o.payload->>'id'
+         WHERE o.payload->>'status' IN ('Failed', 'Cancelled')
+           AND l.detail->>'@t' = 'Lock'
+           AND NOT EXISTS (
+               SELECT 1 FROM ledger_entries r
+               WHERE r.account_id = l.account_id AND r.txid = l.txid AND r.sequence > l.sequence
+                 AND r.detail->>'@t' IN ('Unlock', 'Settlement')
+           )
+         ORDER BY l.timestamp, l.account_id, l.sequence
+         LIMIT $1",
+    )
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+    let mut locks = Vec::with_capacity(rows.len());
+    for row in rows {
+        let detail: serde_json::Value = row.try_get("detail")?;
+        let field = |name: &str| detail.get(name).cloned().ok_or_else(|| sqlx::Error::ColumnNotFound(format!("detail.{}", name)));
+        let decode = |e: serde_json::Error| sqlx::Error::Decode(Box::new(e));
+        locks.push(OrphanedLock {
+            order_id: row.try_get("order_id")?,
+            account_id: row.try_get("account_id")?,
+            order_status: row.try_get("order_status")?,
+            asset: serde_json::from_value(field("asset")?).map_err(decode)?,
+            amount: serde_json::from_value(field("amount")?).map_err(decode)?,
+        });
+    }
+    Ok(locks)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use cqrs_es::EventEnvelope;
+    use rand::random;
+    use crate::account::aggregate::Account;
+    use crate::account::events::AccountEvent;
+    use crate::account::ledger::write_ledger_entries;
+    use crate::order::recovery::{orphaned_locks, OrphanedLock};
+    use crate::util::test_db::{random_suffix, test_pool};
+    use crate::util::types::ByteArray32;
+
+    #[tokio::test]
+    async fn test_orphaned_locks() {
+        let pool = test_pool();
+        let suffix = random_suffix();
+        let (seller, buyer) = (format!("SELLER-{}", suffix), format!("BUYER-{}", suffix));
+        let (failed, cancelled, placed) = (ByteArray32(random()), ByteArray32(random()), ByteArray32(random()));
+        for (order_id, status, buyer) in [(failed, "Failed", None), (cancelled, "Cancelled", Some(&buyer)), (placed, "Placed", None)] {
+            let payload = serde_json::json!({ "id": order_id.hex(), "seller": seller, "buyer": buyer, "status": status });
+            sqlx::query("INSERT INTO order_query (view_id, version, payload) VALUES ($1, 1, $2::json)")
+                .bind(order_id.hex())
+                .bind(payload.to_string())
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        let envelope = |account_id: &str, sequence, payload| EventEnvelope::<Account> {
+            aggregate_id: account_id.to_string(),
+            sequence,
+            payload,
+            metadata: HashMap::new(),
+        };
+        write_ledger_entries(&pool, &[
+            // Locked by an order that failed right after, never unlocked.
+            envelope(&seller, 1, AccountEvent::funds_locked(failed, 10, "BTC".to_string(), 3)),
+            // Locked and released when cancelled, then locked again by the buyer side.
+            envelope(&seller, 2, AccountEvent::funds_locked(cancelled, 20, "BTC".to_string(), 5)),
+            envelope(&seller, 3, AccountEvent::funds_unlocked(cancelled, 30, "BTC".to_string(), 5)),
+            envelope(&buyer, 1, AccountEvent::funds_locked(cancelled, 40, "USDT".to_string(), 700)),
+            // Still open, its lock is in use.
+            envelope(&seller, 4, AccountEvent::funds_locked(placed, 50, "BTC".to_string(), 1)),
+        ]).await.unwrap();
+
+        let found: Vec<_> = orphaned_locks(&pool, 10_000).await.unwrap()
+            .into_iter()
+            .filter(|lock| lock.account_id.ends_with(&suffix))
+            .collect();
+        assert_eq!(found, vec![
+            OrphanedLock { order_id: failed.hex(), account_id: seller.clone(), order_status: "Failed".to_string(), asset: "BTC".to_string(), amount: 3 },
+            OrphanedLock { order_id: cancelled.hex(), account_id: buyer.clone(), order_status: "Cancelled".to_string(), asset: "USDT".to_string(), amount: 700 },
+        ]);
+
+        sqlx::query("DELETE FROM ledger_entries WHERE account_id IN ($1, $2)")
+            .bind(&seller)
+            .bind(&buyer)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM order_query WHERE view_id IN ($1, $2, $3)")
+            .bind(failed.hex())
+            .bind(cancelled.hex())
+            .bind(placed.hex())
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
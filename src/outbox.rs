@@ -0,0 +1,249 @@
+use std::time::Duration;
+use async_trait::async_trait;
+use cqrs_es::persist::{PersistenceError, SerializedEvent};
+use rdkafka::config::ClientConfig;
+use rdkafka::error::KafkaError;
+use rdkafka::message::{Header, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{Pool, Postgres, Row, Transaction};
+use crate::background::{Background, Shutdown};
+use crate::commit_hooks::{persistence_error, CommitHook};
+
+const PUBLISHER_NAME: &str = "kafka";
+
+#[derive(Debug, thiserror::Error)]
+pub enum OutboxError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Kafka error: {0}")]
+    Kafka(#[from] KafkaError),
+    #[error("Malformed outbox row {0}: {1}")]
+    Payload(i64, serde_json::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct OutboxConfig {
+    pub brokers: String,
+    // Events of an aggregate go to `<topic_prefix><aggregate_type>`, e.g. `cqrs-account.order`.
+    pub topic_prefix: String,
+    pub batch_size: i64,
+    pub poll_interval: Duration,
+}
+
+// Copies every event into `event_outbox` in the transaction committing it, where the
+// publisher picks it up, see `crate::commit_hooks`. Writing a row again is a no-op.
+pub struct OutboxHook;
+
+#[async_trait]
+impl CommitHook for OutboxHook {
+    async fn append(&self, tx: &mut Transaction<'_, Postgres>, events: &[SerializedEvent]) -> Result<(), PersistenceError> {
+        for event in events {
+            sqlx::query(
+                "INSERT INTO event_outbox (aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata, created_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, now())
+                 ON CONFLICT (aggregate_type, aggregate_id, sequence) DO NOTHING",
+            )
+                .bind(&event.aggregate_type)
+                .bind(&event.aggregate_id)
+                .bind(event.sequence as i64)
+                .bind(&event.event_type)
+                .bind(&event.event_version)
+                .bind(&event.payload)
+                .bind(&event.metadata)
+                .execute(&mut **tx)
+                .await
+                .map_err(persistence_error)?;
+        }
+        Ok(())
+    }
+}
+
+// The message published for each event, keyed by aggregate id so that all events of an
+// aggregate land on the same partition in order.
+#[derive(Debug, Serialize)]
+pub struct OutboxMessage {
+    pub aggregate_type: String,
+    pub aggregate_id: String,
+    pub sequence: i64,
+    pub event_type: String,
+    pub event_version: String,
+    pub payload: Value,
+    pub metadata: Value,
+}
+
+// Publishes the outbox to Kafka with at-least-once delivery: the offset of the last
+// published row only moves once Kafka acknowledged the whole batch, so a crash or a
+// broker error republishes the batch. Consumers deduplicate on (aggregate_id, sequence).
+#[derive(Clone)]
+pub struct OutboxPublisher {
+    pool: Pool<Postgres>,
+    producer: FutureProducer,
+    config: OutboxConfig,
+}
+
+impl OutboxPublisher {
+    pub fn new(pool: Pool<Postgres>, config: OutboxConfig) -> Result<Self, OutboxError> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", &config.brokers)
+            .set("enable.idempotence", "true")
+            .set("message.timeout.ms", "30000")
+            .create()?;
+        Ok(OutboxPublisher { pool, producer, config })
+    }
+
+    pub fn spawn(self, background: &Background) {
+        background.spawn(|shutdown| async move { self.run(shutdown).await });
+    }
+
+    async fn run(&self, mut shutdown: Shutdown) {
+        loop {
+            match self.publish_batch().await {
+                Ok(published) if published as i64 == self.config.batch_size && !shutdown.is_stopping() => continue,
+                Ok(_) => {},
+                Err(e) => tracing::error!("Failed to publish outbox: {}", e),
+            }
+            if !shutdown.pause(self.config.poll_interval).await {
+                break;
+            }
+        }
+    }
+
+    async fn publish_batch(&self) -> Result<usize, OutboxError> {
+        let offset = load_offset(&self.pool, PUBLISHER_NAME).await?;
+        let batch = fetch_batch(&self.pool, offset, self.config.batch_size).await?;
+        let Some((last_id, _)) = batch.last() else {
+            return Ok(0);
+        };
+        let last_id = *last_id;
+
+        let mut deliveries = Vec::with_capacity(batch.len());
+        for (id, message) in &batch {
+            let topic = format!("{}{}", self.config.topic_prefix, message.aggregate_type);
+            let body = serde_json::to_vec(message).map_err(|e| OutboxError::Payload(*id, e))?;
+            let sequence = message.sequence.to_string();
+            let headers = OwnedHeaders::new()
+                .insert(Header { key: "event_type", value: Some(message.event_type.as_str()) })
+                .insert(Header { key: "event_version", value: Some(message.event_version.as_str()) })
+                .insert(Header { key: "sequence", value: Some(sequence.as_str()) });
+            let record = FutureRecord::to(&topic)
+                .key(&message.aggregate_id)
+                .payload(&body)
+                .headers(headers);
+            // Enqueue in outbox order without waiting, the idempotent producer keeps the
+            // per-partition order even when it has to retry.
+            let delivery = self.producer.send_result(record).map_err(|(e, _)| e)?;
+            deliveries.push(delivery);
+        }
+        for delivery in deliveries {
+            match delivery.await {
+                Ok(Ok(_)) => {},
+                Ok(Err((e, _))) => return Err(e.into()),
+                Err(_) => return Err(KafkaError::Canceled.into()),
+            }
+        }
+
+        store_offset(&self.pool, PUBLISHER_NAME, last_id).await?;
+        tracing::debug!("Published {} outbox events up to {}", batch.len(), last_id);
+        Ok(batch.len())
+    }
+}
+
+// Outbox ids are handed out before the writing transaction commits, so a row with a
+// lower id can become visible after a higher one was already published. Only rows
+// written by transactions older than every transaction still running are returned,
+// which guarantees no earlier id can show up later.
+pub async fn fetch_batch(pool: &Pool<Postgres>, after: i64, limit: i64) -> Result<Vec<(i64, OutboxMessage)>, OutboxError> {
+    let rows = sqlx::query(
+        "SELECT id, aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata
+         FROM event_outbox
+         WHERE id > $1 AND txid < pg_snapshot_xmin(pg_current_snapshot())
+         ORDER BY id
+         LIMIT $2",
+    )
+        .bind(after)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+    rows.into_iter()
+        .map(|row| {
+            Ok((row.try_get("id")?, OutboxMessage {
+                aggregate_type: row.try_get("aggregate_type")?,
+                aggregate_id: row.try_get("aggregate_id")?,
+                sequence: row.try_get("sequence")?,
+                event_type: row.try_get("event_type")?,
+                event_version: row.try_get("event_version")?,
+                payload: row.try_get("payload")?,
+                metadata: row.try_get("metadata")?,
+            }))
+        })
+        .collect()
+}
+
+async fn load_offset(pool: &Pool<Postgres>, publisher: &str) -> Result<i64, OutboxError> {
+    let row = sqlx::query("SELECT last_id FROM outbox_offset WHERE publisher = $1")
+        .bind(publisher)
+        .fetch_optional(pool)
+        .await?;
+    Ok(match row {
+        Some(row) => row.try_get("last_id")?,
+        None => 0,
+    })
+}
+
+async fn store_offset(pool: &Pool<Postgres>, publisher: &str, last_id: i64) -> Result<(), OutboxError> {
+    sqlx::query(
+        "INSERT INTO outbox_offset (publisher, last_id, updated_at) VALUES ($1, $2, now())
+         ON CONFLICT (publisher) DO UPDATE SET last_id = EXCLUDED.last_id, updated_at = EXCLUDED.updated_at",
+    )
+        .bind(publisher)
+        .bind(last_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::persist::SerializedEvent;
+    use cqrs_es::DomainEvent;
+    use sqlx::Row;
+    use crate::account::events::AccountEvent;
+    use crate::commit_hooks::CommitHook;
+    use crate::outbox::OutboxHook;
+    use crate::util::test_db::{random_suffix, test_pool};
+
+    #[tokio::test]
+    async fn test_append_writes_each_event_once() {
+        let pool = test_pool();
+        let account_id = format!("OUTBOX-{}", random_suffix());
+        let events: Vec<SerializedEvent> = [AccountEvent::account_opened(account_id.clone()), AccountEvent::account_disabled()]
+            .into_iter()
+            .enumerate()
+            .map(|(index, event)| {
+                let (event_type, event_version) = (event.event_type(), event.event_version());
+                let payload = serde_json::to_value(event).unwrap();
+                SerializedEvent::new(account_id.clone(), index + 1, "account".to_string(), event_type, event_version, payload, serde_json::json!({}))
+            })
+            .collect();
+        for _ in 0..2 {
+            let mut tx = pool.begin().await.unwrap();
+            OutboxHook.append(&mut tx, &events).await.unwrap();
+            tx.commit().await.unwrap();
+        }
+        // Nothing is written when the transaction is not committed.
+        let mut tx = pool.begin().await.unwrap();
+        let unseen = SerializedEvent { sequence: 3, ..events[1].clone() };
+        OutboxHook.append(&mut tx, &[unseen]).await.unwrap();
+        tx.rollback().await.unwrap();
+
+        let rows = sqlx::query("SELECT event_type FROM event_outbox WHERE aggregate_type = 'account' AND aggregate_id = $1 ORDER BY sequence")
+            .bind(&account_id)
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        let types: Vec<String> = rows.iter().map(|row| row.get("event_type")).collect();
+        assert_eq!(types, vec!["Lifecycle::Opened", "Lifecycle::Disabled"]);
+    }
+}
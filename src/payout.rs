@@ -0,0 +1,331 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use futures::StreamExt;
+use crate::sealing::SealedCqrs;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres, Row};
+use cqrs_es::AggregateError;
+use crate::account::aggregate::Account;
+use crate::account::commands::{AccountCommand, TransactionCommand};
+use crate::account::events::{AccountError, TransactionLabels};
+use crate::config::{ConflictRetryConfig, PayoutConfig};
+use crate::retry::retry_conflicts;
+use crate::txid_registry::TxidRegistry;
+use crate::util::clock;
+use crate::util::types::ByteArray32;
+
+const PAYOUT_CONCURRENCY: usize = 16;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PayoutError {
+    #[error("Invalid payout file: {}", .0.join("; "))]
+    Invalid(Vec<String>),
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Failed to write report: {0}")]
+    Report(String),
+}
+
+// One line of a payout file. Positive amounts are paid into the account, negative
+// amounts are moved from it into the payout account (e.g. to claw back an earlier payout).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayoutRow {
+    pub line: usize,
+    pub account_id: String,
+    pub asset: String,
+    pub amount: i128,
+    pub reference: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PayoutStatus {
+    Executed,
+    // The transaction id derived from the reference was already processed by the account.
+    AlreadyProcessed,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PayoutResult {
+    pub line: usize,
+    pub account_id: String,
+    pub asset: String,
+    pub amount: i128,
+    pub reference: String,
+    pub txid: String,
+    pub status: PayoutStatus,
+    pub error: String,
+}
+
+// Parses and validates a `account_id,asset,amount,reference` CSV. The whole file is
+// rejected if any line is invalid, so a batch is never half applied because of a typo.
+pub fn parse_payout_csv(body: &[u8]) -> Result<Vec<PayoutRow>, PayoutError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .trim(csv::Trim::All)
+        .from_reader(body);
+    let mut rows = vec![];
+    let mut errors = vec![];
+    let mut references = HashSet::new();
+    for (index, record) in reader.records().enumerate() {
+        // Line 1 is the header.
+        let line = index + 2;
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                errors.push(format!("line {}: {}", line, e));
+                continue;
+            }
+        };
+        if record.len() != 4 {
+            errors.push(format!("line {}: expected 4 columns, found {}", line, record.len()));
+            continue;
+        }
+        let (account_id, asset, amount, reference) = (&record[0], &record[1], &record[2], &record[3]);
+        if account_id.is_empty() || asset.is_empty() || reference.is_empty() {
+            errors.push(format!("line {}: account_id, asset and reference are required", line));
+            continue;
+        }
+        let amount = match amount.parse::<i128>() {
+            Ok(amount) if amount != 0 && amount.unsigned_abs() <= u64::MAX as u128 => amount,
+            _ => {
+                errors.push(format!("line {}: invalid amount {:?}", line, amount));
+                continue;
+            }
+        };
+        if !references.insert(reference.to_string()) {
+            errors.push(format!("line {}: duplicate reference {}", line, reference));
+            continue;
+        }
+        rows.push(PayoutRow {
+            line,
+            account_id: account_id.to_string(),
+            asset: asset.to_string(),
+            amount,
+            reference: reference.to_string(),
+        });
+    }
+    if !errors.is_empty() {
+        return Err(PayoutError::Invalid(errors));
+    }
+    if rows.is_empty() {
+        return Err(PayoutError::Invalid(vec!["payout file has no rows".to_string()]));
+    }
+    Ok(rows)
+}
+
+// Negative rows need the payout account to credit what they debit.
+pub fn check_clawbacks(rows: &[PayoutRow], config: &PayoutConfig) -> Result<(), PayoutError> {
+    let errors: Vec<String> = rows
+        .iter()
+        .filter(|row| row.amount < 0)
+        .filter_map(|row| {
+            if config.account.is_empty() {
+                Some(format!("line {}: negative amounts need a payout account", row.line))
+            } else if row.account_id == config.account {
+                Some(format!("line {}: the payout account cannot be clawed back from", row.line))
+            } else {
+                None
+            }
+        })
+        .collect();
+    if errors.is_empty() { Ok(()) } else { Err(PayoutError::Invalid(errors)) }
+}
+
+// Executes a validated batch against the account aggregates. Transaction ids are derived
+// from the batch id and the row reference, so resubmitting a file only executes the rows
+// that did not go through the first time.
+pub async fn execute_payout(
+    account_cqrs: Arc<SealedCqrs<Account>>,
+    txid_registry: TxidRegistry,
+    config: &PayoutConfig,
+    retry: &ConflictRetryConfig,
+    batch_id: &str,
+    rows: Vec<PayoutRow>,
+    metadata: HashMap<String, String>,
+) -> Vec<PayoutResult> {
+    let batch = PayoutBatch { batch_id, timestamp: clock::now(), config, retry };
+    // Rows of the same account run in file order, so a debit can rely on an earlier credit;
+    // different accounts are paid concurrently.
+    let mut by_account: Vec<Vec<PayoutRow>> = vec![];
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for row in rows {
+        let position = *positions.entry(row.account_id.clone()).or_insert_with(|| {
+            by_account.push(vec![]);
+            by_account.len() - 1
+        });
+        by_account[position].push(row);
+    }
+    let mut results: Vec<PayoutResult> = futures::stream::iter(by_account)
+        .map(|rows| {
+            let account_cqrs = account_cqrs.clone();
+            let txid_registry = txid_registry.clone();
+            let metadata = metadata.clone();
+            let batch = &batch;
+            async move {
+                let mut results = Vec::with_capacity(rows.len());
+                for row in rows {
+                    results.push(execute_row(&account_cqrs, &txid_registry, batch, row, metadata.clone()).await);
+                }
+                results
+            }
+        })
+        .buffer_unordered(PAYOUT_CONCURRENCY)
+        .flat_map(futures::stream::iter)
+        .collect()
+        .await;
+    results.sort_by_key(|result| result.line);
+    results
+}
+
+struct PayoutBatch<'a> {
+    batch_id: &'a str,
+    timestamp: u64,
+    config: &'a PayoutConfig,
+    retry: &'a ConflictRetryConfig,
+}
+
+async fn execute_row(
+    account_cqrs: &SealedCqrs<Account>,
+    txid_registry: &TxidRegistry,
+    batch: &PayoutBatch<'_>,
+    row: PayoutRow,
+    metadata: HashMap<String, String>,
+) -> PayoutResult {
+    let PayoutBatch { batch_id, timestamp, config, retry } = *batch;
+    let txid = ByteArray32::derive(&format!("payout:{}", batch_id), &row.reference);
+    let amount = row.amount.unsigned_abs() as u64;
+    let command = if row.amount > 0 {
+        TransactionCommand::Deposit { asset: row.asset.clone(), amount, source: None, value_date: None }
+    } else {
+        TransactionCommand::Debit { to_account: config.account.clone(), asset: row.asset.clone(), amount }
+    };
+    let command = AccountCommand::Transaction { timestamp, txid, command, labels: TransactionLabels::default() };
+    let executed = match txid_registry.claim(&txid, &format!("payout:{}", batch_id)).await {
+        Ok(()) => account_cqrs.execute_with_metadata(&row.account_id, command, metadata.clone()).await,
+        Err(e) => Err(AggregateError::UnexpectedError(Box::new(e))),
+    };
+    let (mut status, mut error) = match executed {
+        Ok(_) => (PayoutStatus::Executed, String::new()),
+        Err(AggregateError::UserError(AccountError::DuplicateTransaction(_))) => (PayoutStatus::AlreadyProcessed, String::new()),
+        Err(e) => (PayoutStatus::Failed, e.to_string()),
+    };
+    // The credit leg of a clawback runs under the txid of its debit, also when the debit
+    // went through with an earlier submission, so resubmitting the file completes a
+    // clawback whose credit failed and never credits it twice.
+    if row.amount < 0 && status != PayoutStatus::Failed {
+        let command = AccountCommand::credit(txid, timestamp, row.account_id.clone(), row.asset.clone(), amount);
+        let execute = || account_cqrs.execute_with_metadata(&config.account, command.clone(), metadata.clone());
+        match retry_conflicts(retry, execute).await {
+            Ok(_) | Err(AggregateError::UserError(AccountError::DuplicateTransaction(_))) => {},
+            Err(e) => {
+                status = PayoutStatus::Failed;
+                error = format!("debited, but crediting {} failed: {}", config.account, e);
+            }
+        }
+    }
+    PayoutResult {
+        line: row.line,
+        account_id: row.account_id,
+        asset: row.asset,
+        amount: row.amount,
+        reference: row.reference,
+        txid: txid.hex(),
+        status,
+        error,
+    }
+}
+
+pub fn payout_report_csv(results: &[PayoutResult]) -> Result<String, PayoutError> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    for result in results {
+        writer.serialize(result).map_err(|e| PayoutError::Report(e.to_string()))?;
+    }
+    let bytes = writer.into_inner().map_err(|e| PayoutError::Report(e.to_string()))?;
+    String::from_utf8(bytes).map_err(|e| PayoutError::Report(e.to_string()))
+}
+
+// Keeps the latest report of each batch so it can be downloaded again later.
+pub async fn save_payout_report(pool: &Pool<Postgres>, batch_id: &str, report: &str) -> Result<(), PayoutError> {
+    sqlx::query(
+        "INSERT INTO payout_report (batch_id, report, created_at) VALUES ($1, $2, now())
+         ON CONFLICT (batch_id) DO UPDATE SET report = EXCLUDED.report, created_at = EXCLUDED.created_at",
+    )
+        .bind(batch_id)
+        .bind(report)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn load_payout_report(pool: &Pool<Postgres>, batch_id: &str) -> Result<Option<String>, PayoutError> {
+    let row = sqlx::query("SELECT report FROM payout_report WHERE batch_id = $1")
+        .bind(batch_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(match row {
+        Some(row) => Some(row.try_get("report")?),
+        None => None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::PayoutConfig;
+    use crate::payout::{check_clawbacks, parse_payout_csv, payout_report_csv, PayoutError, PayoutResult, PayoutStatus};
+
+    #[test]
+    fn test_parse_payout_csv() {
+        let body = b"account_id,asset,amount,reference\nACCT-0001, BTC ,150,salary-01\nACCT-0002,ETH,-20,refund-7\n";
+        let rows = parse_payout_csv(body).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].asset, "BTC");
+        assert_eq!(rows[0].line, 2);
+        assert_eq!(rows[1].amount, -20);
+    }
+
+    #[test]
+    fn test_parse_payout_csv_rejects_whole_file() {
+        let body = b"account_id,asset,amount,reference\nACCT-0001,BTC,0,a\nACCT-0002,ETH,5,b\nACCT-0003,ETH,5,b\n";
+        let Err(PayoutError::Invalid(errors)) = parse_payout_csv(body) else {
+            panic!("expected the batch to be rejected");
+        };
+        assert_eq!(errors, vec![
+            "line 2: invalid amount \"0\"".to_string(),
+            "line 4: duplicate reference b".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_clawbacks_need_payout_account() {
+        let rows = parse_payout_csv(b"account_id,asset,amount,reference\nACCT-0001,BTC,150,a\nPAYOUTS,BTC,-5,b\nACCT-0002,ETH,-20,c\n").unwrap();
+        let Err(PayoutError::Invalid(errors)) = check_clawbacks(&rows, &PayoutConfig::default()) else {
+            panic!("expected the clawbacks to be rejected");
+        };
+        assert_eq!(errors, vec![
+            "line 3: negative amounts need a payout account".to_string(),
+            "line 4: negative amounts need a payout account".to_string(),
+        ]);
+        let config = PayoutConfig { account: "PAYOUTS".to_string() };
+        let Err(PayoutError::Invalid(errors)) = check_clawbacks(&rows, &config) else {
+            panic!("expected the payout account to be rejected");
+        };
+        assert_eq!(errors, vec!["line 3: the payout account cannot be clawed back from".to_string()]);
+        assert!(check_clawbacks(&rows[..1], &PayoutConfig::default()).is_ok());
+    }
+
+    #[test]
+    fn test_payout_report_csv() {
+        let report = payout_report_csv(&[PayoutResult {
+            line: 2,
+            account_id: "ACCT-0001".to_string(),
+            asset: "BTC".to_string(),
+            amount: 150,
+            reference: "salary-01".to_string(),
+            txid: "ab".to_string(),
+            status: PayoutStatus::AlreadyProcessed,
+            error: String::new(),
+        }]).unwrap();
+        assert_eq!(report, "line,account_id,asset,amount,reference,txid,status,error\n2,ACCT-0001,BTC,150,salary-01,ab,AlreadyProcessed,\n");
+    }
+}
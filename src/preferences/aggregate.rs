@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+use std::mem::swap;
+use async_trait::async_trait;
+use cqrs_es::Aggregate;
+use serde::{Deserialize, Serialize};
+use crate::preferences::commands::PreferencesCommand;
+use crate::preferences::events::{AlertRule, PreferencesEvent};
+use crate::statemachine::{StateMachine, Transition};
+use crate::metrics::ErrorVariant;
+
+const MAX_ALERTS: usize = 32;
+const MAX_DISPLAY_NAME_CHARS: usize = 64;
+
+// Notification settings of an account, and the name it shows its counterparties, stored
+// under the account id.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub enum Preferences {
+    #[default]
+    Uninitialized,
+    Active {
+        webhook_url: Option<String>,
+        alerts: BTreeMap<String, AlertRule>,
+        #[serde(default)]
+        display_name: Option<String>,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PreferencesError {
+    #[error("Invalid state: {0}")]
+    InvalidState(String),
+    #[error("Invalid webhook: {0}")]
+    InvalidWebhook(String),
+    #[error("Invalid alert: {0}")]
+    InvalidAlert(String),
+    #[error("Alert not found: {0}")]
+    AlertNotFound(String),
+    #[error("Invalid display name: {0}")]
+    InvalidDisplayName(String),
+}
+
+impl ErrorVariant for PreferencesError {
+    fn variant(&self) -> &'static str {
+        match self {
+            PreferencesError::InvalidState(_) => "InvalidState",
+            PreferencesError::InvalidWebhook(_) => "InvalidWebhook",
+            PreferencesError::InvalidAlert(_) => "InvalidAlert",
+            PreferencesError::AlertNotFound(_) => "AlertNotFound",
+            PreferencesError::InvalidDisplayName(_) => "InvalidDisplayName",
+        }
+    }
+}
+
+fn validate_webhook(url: &Option<String>) -> Result<(), PreferencesError> {
+    match url {
+        Some(url) if !url.starts_with("https://") && !url.starts_with("http://") => {
+            Err(PreferencesError::InvalidWebhook(format!("{} is not an http(s) url", url)))
+        },
+        _ => Ok(()),
+    }
+}
+
+fn validate_alert(alert_id: &str, rule: &AlertRule, alerts: Option<&BTreeMap<String, AlertRule>>) -> Result<(), PreferencesError> {
+    if alert_id.is_empty() {
+        return Err(PreferencesError::InvalidAlert("alert id is required".to_string()));
+    }
+    if rule.asset().is_empty() {
+        return Err(PreferencesError::InvalidAlert("asset is required".to_string()));
+    }
+    if let AlertRule::LargeCredit { min_amount: 0, .. } = rule {
+        return Err(PreferencesError::InvalidAlert("min_amount must be positive".to_string()));
+    }
+    if let Some(alerts) = alerts {
+        if alerts.len() >= MAX_ALERTS && !alerts.contains_key(alert_id) {
+            return Err(PreferencesError::InvalidAlert(format!("at most {} alerts per account", MAX_ALERTS)));
+        }
+    }
+    Ok(())
+}
+
+// Names end up on the statements and exports of other accounts, so they stay short, single
+// line and without surrounding whitespace.
+fn validate_display_name(display_name: &Option<String>) -> Result<(), PreferencesError> {
+    let Some(display_name) = display_name else {
+        return Ok(());
+    };
+    if display_name.trim().is_empty() {
+        return Err(PreferencesError::InvalidDisplayName("display name is empty".to_string()));
+    }
+    if display_name.trim() != display_name {
+        return Err(PreferencesError::InvalidDisplayName("display name has surrounding whitespace".to_string()));
+    }
+    if display_name.chars().count() > MAX_DISPLAY_NAME_CHARS {
+        return Err(PreferencesError::InvalidDisplayName(format!("at most {} characters", MAX_DISPLAY_NAME_CHARS)));
+    }
+    if display_name.chars().any(char::is_control) {
+        return Err(PreferencesError::InvalidDisplayName("display name has control characters".to_string()));
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Aggregate for Preferences {
+    type Command = PreferencesCommand;
+    type Event = PreferencesEvent;
+    type Error = PreferencesError;
+    type Services = ();
+
+    fn aggregate_type() -> String {
+        "preferences".to_string()
+    }
+
+    async fn handle(
+        &self,
+        command: Self::Command,
+        _services: &Self::Services,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        match (self, command) {
+            (Preferences::Uninitialized | Preferences::Active { .. }, PreferencesCommand::SetWebhook { url }) => {
+                validate_webhook(&url)?;
+                Ok(vec![PreferencesEvent::WebhookSet { url }])
+            },
+            (Preferences::Uninitialized, PreferencesCommand::SetAlert { alert_id, rule }) => {
+                validate_alert(&alert_id, &rule, None)?;
+                Ok(vec![PreferencesEvent::AlertSet { alert_id, rule }])
+            },
+            (Preferences::Active { alerts, .. }, PreferencesCommand::SetAlert { alert_id, rule }) => {
+                validate_alert(&alert_id, &rule, Some(alerts))?;
+                Ok(vec![PreferencesEvent::AlertSet { alert_id, rule }])
+            },
+            (Preferences::Active { alerts, .. }, PreferencesCommand::RemoveAlert { alert_id }) => {
+                if !alerts.contains_key(&alert_id) {
+                    return Err(PreferencesError::AlertNotFound(alert_id));
+                }
+                Ok(vec![PreferencesEvent::AlertRemoved { alert_id }])
+            },
+            (Preferences::Uninitialized | Preferences::Active { .. }, PreferencesCommand::SetDisplayName { display_name }) => {
+                validate_display_name(&display_name)?;
+                Ok(vec![PreferencesEvent::DisplayNameSet { display_name }])
+            },
+            (state, cmd) => {
+                Err(PreferencesError::InvalidState(format!("Preferences current at {:?} state, cannot accept {:?} command", state, cmd)))
+            }
+        }
+    }
+
+    fn apply(&mut self, event: Self::Event) {
+        let mut prev = Default::default();
+        swap(&mut prev, self);
+        *self = match (prev, event) {
+            (Preferences::Uninitialized, PreferencesEvent::WebhookSet { url }) => Preferences::Active {
+                webhook_url: url,
+                alerts: BTreeMap::new(),
+                display_name: None,
+            },
+            (Preferences::Uninitialized, PreferencesEvent::AlertSet { alert_id, rule }) => Preferences::Active {
+                webhook_url: None,
+                alerts: BTreeMap::from([(alert_id, rule)]),
+                display_name: None,
+            },
+            (Preferences::Uninitialized, PreferencesEvent::DisplayNameSet { display_name }) => Preferences::Active {
+                webhook_url: None,
+                alerts: BTreeMap::new(),
+                display_name,
+            },
+            (Preferences::Active { alerts, display_name, .. }, PreferencesEvent::WebhookSet { url }) => Preferences::Active {
+                webhook_url: url,
+                alerts,
+                display_name,
+            },
+            (Preferences::Active { webhook_url, mut alerts, display_name }, PreferencesEvent::AlertSet { alert_id, rule }) => {
+                alerts.insert(alert_id, rule);
+                Preferences::Active { webhook_url, alerts, display_name }
+            },
+            (Preferences::Active { webhook_url, mut alerts, display_name }, PreferencesEvent::AlertRemoved { alert_id }) => {
+                alerts.remove(&alert_id);
+                Preferences::Active { webhook_url, alerts, display_name }
+            },
+            (Preferences::Active { webhook_url, alerts, .. }, PreferencesEvent::DisplayNameSet { display_name }) => Preferences::Active {
+                webhook_url,
+                alerts,
+                display_name,
+            },
+            (state, event) => unreachable!("Invalid state transition: {:?} -> {:?}", state, event),
+        };
+    }
+}
+
+impl StateMachine for Preferences {
+    const TRANSITIONS: &'static [Transition] = &[
+        Transition { from: "Uninitialized", command: "SetWebhook", guard: None, events: &["WebhookSet"], to: "Active" },
+        Transition { from: "Uninitialized", command: "SetAlert", guard: None, events: &["AlertSet"], to: "Active" },
+        Transition { from: "Uninitialized", command: "SetDisplayName", guard: None, events: &["DisplayNameSet"], to: "Active" },
+        Transition { from: "Active", command: "SetWebhook", guard: None, events: &["WebhookSet"], to: "Active" },
+        Transition { from: "Active", command: "SetAlert", guard: Some("below the alert limit"), events: &["AlertSet"], to: "Active" },
+        Transition { from: "Active", command: "RemoveAlert", guard: Some("alert exists"), events: &["AlertRemoved"], to: "Active" },
+        Transition { from: "Active", command: "SetDisplayName", guard: None, events: &["DisplayNameSet"], to: "Active" },
+    ];
+}
+
+#[cfg(test)]
+mod aggregate_tests {
+    use cqrs_es::test::TestFramework;
+
+    use crate::preferences::aggregate::Preferences;
+    use crate::preferences::commands::PreferencesCommand;
+    use crate::preferences::events::{AlertRule, PreferencesEvent};
+
+    fn low_btc() -> AlertRule {
+        AlertRule::BalanceBelow { asset: "BTC".to_string(), threshold: 10 }
+    }
+
+    #[test]
+    fn test_set_alert() {
+        TestFramework::<Preferences>::with(())
+            .given_no_previous_events()
+            .when(PreferencesCommand::SetAlert { alert_id: "low-btc".to_string(), rule: low_btc() })
+            .then_expect_events(vec![PreferencesEvent::AlertSet { alert_id: "low-btc".to_string(), rule: low_btc() }]);
+    }
+
+    #[test]
+    fn test_reject_non_http_webhook() {
+        TestFramework::<Preferences>::with(())
+            .given_no_previous_events()
+            .when(PreferencesCommand::SetWebhook { url: Some("ftp://example.com".to_string()) })
+            .then_expect_error_message("Invalid webhook: ftp://example.com is not an http(s) url");
+    }
+
+    #[test]
+    fn test_remove_unknown_alert() {
+        TestFramework::<Preferences>::with(())
+            .given(vec![PreferencesEvent::AlertSet { alert_id: "low-btc".to_string(), rule: low_btc() }])
+            .when(PreferencesCommand::RemoveAlert { alert_id: "high-eth".to_string() })
+            .then_expect_error_message("Alert not found: high-eth");
+    }
+
+    #[test]
+    fn test_set_display_name() {
+        let display_name = Some("Acme Payroll".to_string());
+        TestFramework::<Preferences>::with(())
+            .given(vec![PreferencesEvent::AlertSet { alert_id: "low-btc".to_string(), rule: low_btc() }])
+            .when(PreferencesCommand::SetDisplayName { display_name: display_name.clone() })
+            .then_expect_events(vec![PreferencesEvent::DisplayNameSet { display_name }]);
+        TestFramework::<Preferences>::with(())
+            .given_no_previous_events()
+            .when(PreferencesCommand::SetDisplayName { display_name: Some(" Acme".to_string()) })
+            .then_expect_error_message("Invalid display name: display name has surrounding whitespace");
+    }
+}
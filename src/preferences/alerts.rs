@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use async_trait::async_trait;
+use cqrs_es::{EventEnvelope, Query};
+use cqrs_es::persist::ViewRepository;
+use postgres_es::PostgresViewRepository;
+use serde::Serialize;
+use sqlx::{Pool, Postgres, Row};
+use crate::account::aggregate::Account;
+use crate::account::events::{AccountEvent, BalanceSnapshot, TransactionEvent};
+use crate::notification::WebhookNotifier;
+use crate::preferences::aggregate::Preferences;
+use crate::preferences::events::AlertRule;
+use crate::preferences::queries::PreferencesView;
+
+const NOTIFICATION_KIND: &str = "balance_alert";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AlertNotification {
+    pub account_id: String,
+    pub alert_id: String,
+    pub rule: AlertRule,
+    pub asset: String,
+    // Available balance once the transaction is applied, for the balance rules.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available: Option<u64>,
+    // Credited amount, for `LargeCredit`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount: Option<u64>,
+    pub txid: String,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Evaluation {
+    // The transaction does not concern the rule.
+    NotApplicable,
+    // A balance rule, holding or not once the transaction is applied.
+    Balance { holds: bool, available: u64 },
+    // A credit large enough for a `LargeCredit` rule.
+    Credit { amount: u64 },
+}
+
+fn evaluate(rule: &AlertRule, event: &TransactionEvent, balances: &BTreeMap<String, BalanceSnapshot>) -> Evaluation {
+    match rule {
+        AlertRule::BalanceBelow { asset, threshold } => match balances.get(asset) {
+            Some(balance) => Evaluation::Balance { holds: balance.available < *threshold, available: balance.available },
+            None => Evaluation::NotApplicable,
+        },
+        AlertRule::BalanceAbove { asset, threshold } => match balances.get(asset) {
+            Some(balance) => Evaluation::Balance { holds: balance.available > *threshold, available: balance.available },
+            None => Evaluation::NotApplicable,
+        },
+        AlertRule::LargeCredit { asset, min_amount } => match event {
+            TransactionEvent::Deposited { asset: credited, amount }
+            | TransactionEvent::Credited { asset: credited, amount, .. }
+                if credited == asset && amount >= min_amount => Evaluation::Credit { amount: *amount },
+            _ => Evaluation::NotApplicable,
+        },
+    }
+}
+
+// Evaluates the alerts of an account against its transactions and notifies the account
+// webhook. Balance rules are edge triggered: they fire when the condition starts to
+// hold and stay quiet until it stopped holding in between, so an account hovering
+// below a threshold is not notified on every transaction. The last known state of each
+// alert lives in `alert_state`; redefining an alert resets it.
+pub struct BalanceAlertQuery {
+    preferences: Arc<PostgresViewRepository<PreferencesView, Preferences>>,
+    pool: Pool<Postgres>,
+    notifier: WebhookNotifier,
+}
+
+impl BalanceAlertQuery {
+    pub fn new(
+        preferences: Arc<PostgresViewRepository<PreferencesView, Preferences>>,
+        pool: Pool<Postgres>,
+        notifier: WebhookNotifier,
+    ) -> Self {
+        BalanceAlertQuery { preferences, pool, notifier }
+    }
+
+    // Records whether the rule holds and returns whether this is a new edge.
+    async fn crossed(&self, account_id: &str, alert_id: &str, rule: &AlertRule, holds: bool) -> Result<bool, sqlx::Error> {
+        let rule = serde_json::to_value(rule).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+        let row = sqlx::query(
+            "WITH previous AS (
+                 SELECT rule, triggered FROM alert_state WHERE account_id = $1 AND alert_id = $2
+             ), updated AS (
+                 INSERT INTO alert_state (account_id, alert_id, rule, triggered, updated_at) VALUES ($1, $2, $3, $4, now())
+                 ON CONFLICT (account_id, alert_id) DO UPDATE
+                 SET rule = EXCLUDED.rule, triggered = EXCLUDED.triggered, updated_at = EXCLUDED.updated_at
+             )
+             SELECT coalesce((SELECT triggered AND rule = $3 FROM previous), false) AS was_triggered",
+        )
+            .bind(account_id)
+            .bind(alert_id)
+            .bind(rule)
+            .bind(holds)
+            .fetch_one(&self.pool)
+            .await?;
+        let was_triggered: bool = row.try_get("was_triggered")?;
+        Ok(holds && !was_triggered)
+    }
+
+    async fn evaluate_events(&self, account_id: &str, events: &[EventEnvelope<Account>]) -> Result<(), String> {
+        let Some(preferences) = self.preferences.load(account_id).await.map_err(|e| e.to_string())? else {
+            return Ok(());
+        };
+        if preferences.alerts.is_empty() {
+            return Ok(());
+        }
+        for envelope in events {
+            let AccountEvent::Transaction { timestamp, txid, event, balance_after: Some(balances), .. } = &envelope.payload else {
+                continue;
+            };
+            for (alert_id, rule) in &preferences.alerts {
+                let (available, amount) = match evaluate(rule, event, balances) {
+                    Evaluation::NotApplicable => continue,
+                    Evaluation::Balance { holds, available } => {
+                        if !self.crossed(account_id, alert_id, rule, holds).await.map_err(|e| e.to_string())? {
+                            continue;
+                        }
+                        (Some(available), None)
+                    }
+                    Evaluation::Credit { amount } => (None, Some(amount)),
+                };
+                let Some(url) = &preferences.webhook_url else {
+                    continue;
+                };
+                let notification = AlertNotification {
+                    account_id: account_id.to_string(),
+                    alert_id: alert_id.clone(),
+                    rule: rule.clone(),
+                    asset: rule.asset().to_string(),
+                    available,
+                    amount,
+                    txid: txid.hex(),
+                    timestamp: *timestamp,
+                };
+                tracing::info!("Alert {} of {} triggered by {}", alert_id, account_id, notification.txid);
+                self.notifier.notify(url, NOTIFICATION_KIND, &notification);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Query<Account> for BalanceAlertQuery {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<Account>]) {
+        if let Err(e) = self.evaluate_events(aggregate_id, events).await {
+            tracing::error!("Failed to evaluate alerts of {}: {}", aggregate_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use crate::account::events::{BalanceSnapshot, TransactionEvent};
+    use crate::preferences::alerts::{evaluate, Evaluation};
+    use crate::preferences::events::AlertRule;
+
+    #[test]
+    fn test_evaluate_rules() {
+        let balances = BTreeMap::from([("BTC".to_string(), BalanceSnapshot { available: 5, locked: 0 })]);
+        let deposit = TransactionEvent::Deposited { asset: "BTC".to_string(), amount: 100 };
+
+        let below = AlertRule::BalanceBelow { asset: "BTC".to_string(), threshold: 10 };
+        assert_eq!(evaluate(&below, &deposit, &balances), Evaluation::Balance { holds: true, available: 5 });
+        let above = AlertRule::BalanceAbove { asset: "BTC".to_string(), threshold: 10 };
+        assert_eq!(evaluate(&above, &deposit, &balances), Evaluation::Balance { holds: false, available: 5 });
+        let other_asset = AlertRule::BalanceBelow { asset: "ETH".to_string(), threshold: 10 };
+        assert_eq!(evaluate(&other_asset, &deposit, &balances), Evaluation::NotApplicable);
+
+        let large = AlertRule::LargeCredit { asset: "BTC".to_string(), min_amount: 100 };
+        assert_eq!(evaluate(&large, &deposit, &balances), Evaluation::Credit { amount: 100 });
+        let withdrawal = TransactionEvent::Withdrew { asset: "BTC".to_string(), amount: 500 };
+        assert_eq!(evaluate(&large, &withdrawal, &balances), Evaluation::NotApplicable);
+    }
+}
@@ -0,0 +1,22 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::preferences::events::AlertRule;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub enum PreferencesCommand {
+    // `None` stops delivering notifications without dropping the alerts.
+    SetWebhook {
+        url: Option<String>,
+    },
+    SetAlert {
+        alert_id: String,
+        rule: AlertRule,
+    },
+    RemoveAlert {
+        alert_id: String,
+    },
+    // Shown to the counterparties of the account on their statements, `None` clears it.
+    SetDisplayName {
+        display_name: Option<String>,
+    },
+}
@@ -0,0 +1,64 @@
+use cqrs_es::DomainEvent;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub enum AlertRule {
+    // Available balance of the asset drops below the threshold.
+    BalanceBelow {
+        asset: String,
+        threshold: u64,
+    },
+    // Available balance of the asset rises above the threshold.
+    BalanceAbove {
+        asset: String,
+        threshold: u64,
+    },
+    // A single deposit or credit of at least `min_amount`.
+    LargeCredit {
+        asset: String,
+        min_amount: u64,
+    },
+}
+
+impl AlertRule {
+    pub fn asset(&self) -> &str {
+        match self {
+            AlertRule::BalanceBelow { asset, .. } => asset,
+            AlertRule::BalanceAbove { asset, .. } => asset,
+            AlertRule::LargeCredit { asset, .. } => asset,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum PreferencesEvent {
+    WebhookSet {
+        url: Option<String>,
+    },
+    AlertSet {
+        alert_id: String,
+        rule: AlertRule,
+    },
+    AlertRemoved {
+        alert_id: String,
+    },
+    DisplayNameSet {
+        display_name: Option<String>,
+    },
+}
+
+impl DomainEvent for PreferencesEvent {
+    fn event_type(&self) -> String {
+        match self {
+            PreferencesEvent::WebhookSet { .. } => "WebhookSet".to_string(),
+            PreferencesEvent::AlertSet { .. } => "AlertSet".to_string(),
+            PreferencesEvent::AlertRemoved { .. } => "AlertRemoved".to_string(),
+            PreferencesEvent::DisplayNameSet { .. } => "DisplayNameSet".to_string(),
+        }
+    }
+
+    fn event_version(&self) -> String {
+        "1.0".to_string()
+    }
+}
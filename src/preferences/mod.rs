@@ -0,0 +1,5 @@
+pub mod aggregate;
+pub mod alerts;
+pub mod commands;
+pub mod events;
+pub mod queries;
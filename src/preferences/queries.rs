@@ -0,0 +1,54 @@
+use std::collections::BTreeMap;
+use async_trait::async_trait;
+use cqrs_es::{EventEnvelope, Query, View};
+use cqrs_es::persist::GenericQuery;
+use postgres_es::PostgresViewRepository;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::preferences::aggregate::Preferences;
+use crate::preferences::events::{AlertRule, PreferencesEvent};
+
+pub struct SimpleLoggingQuery {}
+
+#[derive(Debug, Serialize, Deserialize, Default, ToSchema)]
+pub struct PreferencesView {
+    pub webhook_url: Option<String>,
+    pub alerts: BTreeMap<String, AlertRule>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+}
+
+#[async_trait]
+impl Query<Preferences> for SimpleLoggingQuery {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<Preferences>]) {
+        for event in events {
+            let payload = serde_json::to_string_pretty(&event.payload).unwrap();
+            tracing::debug!("{}-{}\n{}", aggregate_id, event.sequence, payload);
+        }
+    }
+}
+
+pub type PreferencesQuery = GenericQuery<
+    PostgresViewRepository<PreferencesView, Preferences>,
+    PreferencesView,
+    Preferences,
+>;
+
+impl View<Preferences> for PreferencesView {
+    fn update(&mut self, event: &EventEnvelope<Preferences>) {
+        match &event.payload {
+            PreferencesEvent::WebhookSet { url } => {
+                self.webhook_url = url.clone();
+            }
+            PreferencesEvent::AlertSet { alert_id, rule } => {
+                self.alerts.insert(alert_id.clone(), rule.clone());
+            }
+            PreferencesEvent::AlertRemoved { alert_id } => {
+                self.alerts.remove(alert_id);
+            }
+            PreferencesEvent::DisplayNameSet { display_name } => {
+                self.display_name = display_name.clone();
+            }
+        }
+    }
+}
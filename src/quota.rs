@@ -0,0 +1,497 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use axum::body::{to_bytes, Body, Bytes};
+use axum::extract::{RawPathParams, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use cqrs_es::{EventEnvelope, Query};
+use prometheus::{IntCounterVec, Opts, Registry};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{Pool, Postgres, Row};
+use utoipa::ToSchema;
+use crate::account::aggregate::Account;
+use crate::config::{QuotaConfig, QuotaEnforcement};
+use crate::routing::{body_accounts, MAX_BODY};
+use crate::state::ApplicationState;
+use crate::util::clock;
+
+const DAY_SECS: u64 = 24 * 3600;
+const TOP_ACCOUNTS: i64 = 20;
+// Fields of command bodies naming the accounts a command is charged to, for routes
+// without the account in their path: the sources of transfers and standing orders, the
+// sellers of orders, the takers, makers and bidders of RFQs and auctions, and the
+// accounts of inbox batches.
+const CHARGED_ACCOUNT_KEYS: &[&str] = &["from_account", "seller", "account_id", "taker", "maker", "bidder"];
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum QuotaError {
+    #[error("{0} committed its {1} events of the day, try again tomorrow")]
+    EventsPerDay(String, u64, Duration),
+    #[error("{0} keeps its {1} bytes of events, no more are taken")]
+    Storage(String, u64),
+    #[error("{0} is over quota, commands are throttled")]
+    Throttled(String, Duration),
+}
+
+impl QuotaError {
+    // How long until a command may go through again, none until the quota is raised.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            QuotaError::EventsPerDay(_, _, wait) | QuotaError::Throttled(_, wait) => Some(*wait),
+            QuotaError::Storage(..) => None,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            QuotaError::EventsPerDay(..) => "events_per_day",
+            QuotaError::Storage(..) => "storage",
+            QuotaError::Throttled(..) => "throttled",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, ToSchema)]
+pub struct Usage {
+    // Start of the UTC day the events are counted in, in unix seconds.
+    pub day: u64,
+    pub events_today: u64,
+    // Bytes of event payload committed since the account was opened, archived or not.
+    pub storage_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct QuotaUsage {
+    // An account id, or the account id prefix of a tenant.
+    pub scope: String,
+    pub usage: Usage,
+    // The caps, none when unset.
+    pub events_per_day: Option<u64>,
+    pub storage_bytes: Option<u64>,
+}
+
+impl QuotaUsage {
+    fn new(scope: &str, usage: Usage, events_per_day: u64, storage_bytes: u64) -> Self {
+        QuotaUsage {
+            scope: scope.to_string(),
+            usage,
+            events_per_day: (events_per_day > 0).then_some(events_per_day),
+            storage_bytes: (storage_bytes > 0).then_some(storage_bytes),
+        }
+    }
+
+    fn exceeded(&self) -> Option<QuotaError> {
+        if let Some(limit) = self.storage_bytes.filter(|limit| self.usage.storage_bytes >= *limit) {
+            return Some(QuotaError::Storage(self.scope.clone(), limit));
+        }
+        let tomorrow = Duration::from_secs((self.usage.day + DAY_SECS).saturating_sub(clock::now()));
+        self.events_per_day
+            .filter(|limit| self.usage.events_today >= *limit)
+            .map(|limit| QuotaError::EventsPerDay(self.scope.clone(), limit, tomorrow))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct QuotaReport {
+    pub day: u64,
+    // The accounts that committed the most events today.
+    pub top_accounts: Vec<QuotaUsage>,
+    pub tenants: Vec<QuotaUsage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct AccountQuotaReport {
+    pub account: QuotaUsage,
+    // Of all the accounts of its tenant, when it has one.
+    pub tenant: Option<QuotaUsage>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Cached {
+    usage: Usage,
+    loaded: Instant,
+}
+
+struct QuotaState {
+    // Usage read from `account_usage`, by account id and by tenant prefix.
+    accounts: HashMap<String, Cached>,
+    tenants: HashMap<String, Cached>,
+    // When each throttled account last had a command let through.
+    throttled: HashMap<String, Instant>,
+    swept_at: Instant,
+}
+
+// Keeps abusive clients from flooding the shared event store, see `QuotaConfig`: counts
+// the events each account commits per UTC day and the bytes of their payloads in
+// `account_usage`, and turns away or throttles the commands to accounts, or to tenants,
+// past their caps. Payloads are counted as serialized, before they are sealed or
+// encrypted at rest. Commands are checked against the route's `account_id`, the accounts
+// a transfer or an order debits are not. Counted from the events as they are committed,
+// so a projection replay would count them again.
+#[derive(Clone)]
+pub struct Quotas {
+    pool: Pool<Postgres>,
+    config: QuotaConfig,
+    state: Arc<Mutex<QuotaState>>,
+    rejected: IntCounterVec,
+}
+
+impl Quotas {
+    pub fn new(pool: Pool<Postgres>, config: QuotaConfig) -> Self {
+        let rejected = IntCounterVec::new(
+            Opts::new("quota_rejected_total", "Commands turned away for an account over quota"),
+            &["quota"],
+        ).expect("invalid quota rejection counter");
+        let state = QuotaState {
+            accounts: HashMap::new(),
+            tenants: HashMap::new(),
+            throttled: HashMap::new(),
+            swept_at: Instant::now(),
+        };
+        Quotas { pool, config, state: Arc::new(Mutex::new(state)), rejected }
+    }
+
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.rejected.clone()))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.events_per_day > 0
+            || self.config.storage_bytes > 0
+            || self.config.tenants.values().any(|quota| quota.events_per_day > 0 || quota.storage_bytes > 0)
+    }
+
+    fn refresh(&self) -> Duration {
+        Duration::from_secs(self.config.refresh_secs)
+    }
+
+    // Whether a command to the account may go through. Usage that cannot be read lets it.
+    pub async fn check(&self, account_id: &str) -> Result<(), QuotaError> {
+        match self.exceeded(account_id).await {
+            Ok(None) => Ok(()),
+            Ok(Some(err)) => self.enforce(account_id, err, Instant::now()),
+            Err(e) => {
+                tracing::error!("Failed to read the usage of {}: {}", account_id, e);
+                Ok(())
+            }
+        }
+    }
+
+    async fn exceeded(&self, account_id: &str) -> Result<Option<QuotaError>, sqlx::Error> {
+        let usage = self.cached(account_id, false).await?;
+        let account = QuotaUsage::new(account_id, usage, self.config.events_per_day, self.config.storage_bytes);
+        if let Some(err) = account.exceeded() {
+            return Ok(Some(err));
+        }
+        let Some((prefix, quota)) = self.config.tenant(account_id) else {
+            return Ok(None);
+        };
+        if quota.events_per_day == 0 && quota.storage_bytes == 0 {
+            return Ok(None);
+        }
+        let usage = self.cached(prefix, true).await?;
+        Ok(QuotaUsage::new(prefix, usage, quota.events_per_day, quota.storage_bytes).exceeded())
+    }
+
+    // Throttled, an account over quota still has a command let through every interval.
+    fn enforce(&self, account_id: &str, err: QuotaError, now: Instant) -> Result<(), QuotaError> {
+        let err = match self.config.enforcement {
+            QuotaEnforcement::Reject => err,
+            QuotaEnforcement::Throttle => {
+                let interval = Duration::from_millis(self.config.throttle_interval_ms);
+                let mut state = self.state.lock().expect("quota state poisoned");
+                match state.throttled.get(account_id) {
+                    Some(last) if now.saturating_duration_since(*last) < interval => {
+                        QuotaError::Throttled(account_id.to_string(), interval - now.saturating_duration_since(*last))
+                    },
+                    _ => {
+                        state.throttled.insert(account_id.to_string(), now);
+                        return Ok(());
+                    },
+                }
+            },
+        };
+        self.rejected.with_label_values(&[err.label()]).inc();
+        Err(err)
+    }
+
+    // The usage of an account, or of a tenant, read again once `refresh_secs` old or of
+    // another day.
+    async fn cached(&self, scope: &str, tenant: bool) -> Result<Usage, sqlx::Error> {
+        let today = today();
+        {
+            let mut state = self.state.lock().expect("quota state poisoned");
+            let now = Instant::now();
+            let refresh = self.refresh();
+            if now.saturating_duration_since(state.swept_at) >= refresh {
+                state.accounts.retain(|_, cached| now.saturating_duration_since(cached.loaded) < refresh);
+                state.throttled.retain(|_, last| now.saturating_duration_since(*last) < refresh.max(Duration::from_millis(self.config.throttle_interval_ms)));
+                state.swept_at = now;
+            }
+            let cache = if tenant { &state.tenants } else { &state.accounts };
+            if let Some(cached) = cache.get(scope) {
+                if cached.usage.day == today && now.saturating_duration_since(cached.loaded) < refresh {
+                    return Ok(cached.usage);
+                }
+            }
+        }
+        let usage = load_usage(&self.pool, scope, tenant, today).await?;
+        let mut state = self.state.lock().expect("quota state poisoned");
+        let cache = if tenant { &mut state.tenants } else { &mut state.accounts };
+        cache.insert(scope.to_string(), Cached { usage, loaded: Instant::now() });
+        Ok(usage)
+    }
+
+    // Counts committed events into `account_usage`, and into the usage cached for the
+    // account and its tenant so this node sees them before the next refresh.
+    async fn record(&self, account_id: &str, events: u64, bytes: u64) {
+        let day = today();
+        let recorded = sqlx::query(
+            "INSERT INTO account_usage (account_id, day, events, bytes) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (account_id, day) DO UPDATE SET events = account_usage.events + EXCLUDED.events, bytes = account_usage.bytes + EXCLUDED.bytes",
+        )
+            .bind(account_id)
+            .bind(day as i64)
+            .bind(events as i64)
+            .bind(bytes as i64)
+            .execute(&self.pool)
+            .await;
+        if let Err(e) = recorded {
+            tracing::error!("Failed to record the usage of {}: {}", account_id, e);
+            return;
+        }
+        let bump = |cached: Option<&mut Cached>| {
+            if let Some(cached) = cached.filter(|cached| cached.usage.day == day) {
+                cached.usage.events_today += events;
+                cached.usage.storage_bytes += bytes;
+            }
+        };
+        let mut state = self.state.lock().expect("quota state poisoned");
+        bump(state.accounts.get_mut(account_id));
+        if let Some((prefix, _)) = self.config.tenant(account_id) {
+            bump(state.tenants.get_mut(prefix));
+        }
+    }
+
+    // Usage of the account and its tenant, read afresh.
+    pub async fn account_report(&self, account_id: &str) -> Result<AccountQuotaReport, sqlx::Error> {
+        let today = today();
+        let usage = load_usage(&self.pool, account_id, false, today).await?;
+        let account = QuotaUsage::new(account_id, usage, self.config.events_per_day, self.config.storage_bytes);
+        let tenant = match self.config.tenant(account_id) {
+            Some((prefix, quota)) => {
+                let usage = load_usage(&self.pool, prefix, true, today).await?;
+                Some(QuotaUsage::new(prefix, usage, quota.events_per_day, quota.storage_bytes))
+            },
+            None => None,
+        };
+        Ok(AccountQuotaReport { account, tenant })
+    }
+
+    // The accounts busiest today and every tenant with a quota.
+    pub async fn report(&self) -> Result<QuotaReport, sqlx::Error> {
+        let day = today();
+        let rows = sqlx::query(
+            "SELECT account_id, coalesce(sum(events) FILTER (WHERE day = $1), 0)::bigint AS events_today,
+                    sum(bytes)::bigint AS storage_bytes
+             FROM account_usage
+             WHERE account_id IN (SELECT account_id FROM account_usage WHERE day = $1)
+             GROUP BY account_id ORDER BY events_today DESC, account_id LIMIT $2",
+        )
+            .bind(day as i64)
+            .bind(TOP_ACCOUNTS)
+            .fetch_all(&self.pool)
+            .await?;
+        let mut top_accounts = Vec::with_capacity(rows.len());
+        for row in rows {
+            let usage = Usage {
+                day,
+                events_today: row.try_get::<i64, _>("events_today")? as u64,
+                storage_bytes: row.try_get::<i64, _>("storage_bytes")? as u64,
+            };
+            let account_id: String = row.try_get("account_id")?;
+            top_accounts.push(QuotaUsage::new(&account_id, usage, self.config.events_per_day, self.config.storage_bytes));
+        }
+        let mut prefixes: Vec<_> = self.config.tenants.iter().collect();
+        prefixes.sort_by_key(|(prefix, _)| prefix.as_str());
+        let mut tenants = Vec::with_capacity(prefixes.len());
+        for (prefix, quota) in prefixes {
+            let usage = load_usage(&self.pool, prefix, true, day).await?;
+            tenants.push(QuotaUsage::new(prefix, usage, quota.events_per_day, quota.storage_bytes));
+        }
+        Ok(QuotaReport { day, top_accounts, tenants })
+    }
+}
+
+#[async_trait]
+impl Query<Account> for Quotas {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<Account>]) {
+        if events.is_empty() {
+            return;
+        }
+        let bytes = events
+            .iter()
+            .map(|event| serde_json::to_vec(&event.payload).map_or(0, |payload| payload.len() as u64))
+            .sum();
+        self.record(aggregate_id, events.len() as u64, bytes).await;
+    }
+}
+
+fn today() -> u64 {
+    clock::now() / DAY_SECS * DAY_SECS
+}
+
+// The usage of an account, or summed over the accounts of a tenant.
+async fn load_usage(pool: &Pool<Postgres>, scope: &str, tenant: bool, day: u64) -> Result<Usage, sqlx::Error> {
+    let row = sqlx::query(
+        "SELECT coalesce(sum(events) FILTER (WHERE day = $2), 0)::bigint AS events_today,
+                coalesce(sum(bytes), 0)::bigint AS storage_bytes
+         FROM account_usage
+         WHERE CASE WHEN $3 THEN starts_with(account_id, $1) ELSE account_id = $1 END",
+    )
+        .bind(scope)
+        .bind(day as i64)
+        .bind(tenant)
+        .fetch_one(pool)
+        .await?;
+    Ok(Usage {
+        day,
+        events_today: row.try_get::<i64, _>("events_today")? as u64,
+        storage_bytes: row.try_get::<i64, _>("storage_bytes")? as u64,
+    })
+}
+
+// The accounts a command body is charged to, see `CHARGED_ACCOUNT_KEYS`, each once.
+fn charged_accounts(body: &[u8]) -> Vec<String> {
+    let Ok(body) = serde_json::from_slice::<Value>(body) else {
+        return vec![];
+    };
+    let mut accounts = vec![];
+    body_accounts(&body, CHARGED_ACCOUNT_KEYS, &mut accounts);
+    let mut seen = HashSet::new();
+    accounts.retain(|account_id| seen.insert(account_id.clone()));
+    accounts
+}
+
+// Holds commands to accounts over quota, on the routes it is layered on; queries go
+// through. The account is the `account_id` of the route, otherwise the accounts the
+// command body names, each of which must be under quota. Past the events of the day,
+// commands are answered 429 with the seconds until the next UTC day in `Retry-After`,
+// past the storage cap 507, and while throttled 429 until the next command is let through.
+pub async fn quota_layer(
+    State(state): State<ApplicationState>,
+    params: Option<RawPathParams>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if request.method().is_safe() || !state.quotas.is_enabled() {
+        return next.run(request).await;
+    }
+    let account_id = params
+        .as_ref()
+        .and_then(|params| params.iter().find(|(name, _)| *name == "account_id").map(|(_, value)| value.to_string()));
+    let (request, accounts) = match account_id {
+        Some(account_id) => (request, vec![account_id]),
+        None => {
+            let (parts, body) = request.into_parts();
+            let body: Bytes = match to_bytes(body, MAX_BODY).await {
+                Ok(body) => body,
+                Err(err) => return (StatusCode::PAYLOAD_TOO_LARGE, err.to_string()).into_response(),
+            };
+            let accounts = charged_accounts(&body);
+            (Request::from_parts(parts, Body::from(body)), accounts)
+        },
+    };
+    for account_id in &accounts {
+        if let Err(err) = state.quotas.check(account_id).await {
+            tracing::warn!("{}", err);
+            let status = match err {
+                QuotaError::Storage(..) => StatusCode::INSUFFICIENT_STORAGE,
+                _ => StatusCode::TOO_MANY_REQUESTS,
+            };
+            return match err.retry_after() {
+                Some(wait) => {
+                    let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+                    (status, [(header::RETRY_AFTER, retry_after.to_string())], err.to_string()).into_response()
+                },
+                None => (status, err.to_string()).into_response(),
+            };
+        }
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+    use cqrs_es::{EventEnvelope, Query};
+    use crate::account::aggregate::Account;
+    use crate::account::events::AccountEvent;
+    use crate::config::{QuotaConfig, QuotaEnforcement, TenantQuota};
+    use crate::quota::{charged_accounts, QuotaError, Quotas};
+    use crate::util::test_db::{delete_prefixed, random_suffix, test_pool};
+
+    #[tokio::test]
+    async fn test_quotas() {
+        let pool = test_pool();
+        let tenant = format!("QUOTA-{}-", random_suffix());
+        let (noisy, quiet) = (format!("{}NOISY", tenant), format!("{}QUIET", tenant));
+        let config = QuotaConfig {
+            events_per_day: 3,
+            tenants: HashMap::from([(tenant.clone(), TenantQuota { events_per_day: 4, storage_bytes: 0 })]),
+            ..QuotaConfig::default()
+        };
+        let quotas = Quotas::new(pool.clone(), config.clone());
+        assert!(quotas.is_enabled());
+        let envelopes = |account_id: &str, count: usize| -> Vec<EventEnvelope<Account>> {
+            (1..=count).map(|sequence| EventEnvelope {
+                aggregate_id: account_id.to_string(),
+                sequence,
+                payload: AccountEvent::account_disabled(),
+                metadata: HashMap::new(),
+            }).collect()
+        };
+
+        quotas.dispatch(&noisy, &envelopes(&noisy, 2)).await;
+        assert_eq!(quotas.check(&noisy).await, Ok(()));
+        // Counted into the cached usage as well, the account is over quota right away.
+        quotas.dispatch(&noisy, &envelopes(&noisy, 1)).await;
+        assert!(matches!(quotas.check(&noisy).await, Err(QuotaError::EventsPerDay(account_id, 3, _)) if account_id == noisy));
+        // The quiet account is under its own cap, and takes the tenant over its one.
+        quotas.dispatch(&quiet, &envelopes(&quiet, 1)).await;
+        assert!(matches!(quotas.check(&quiet).await, Err(QuotaError::EventsPerDay(prefix, 4, _)) if prefix == tenant));
+
+        let report = quotas.account_report(&noisy).await.unwrap();
+        assert_eq!(report.account.usage.events_today, 3);
+        assert!(report.account.usage.storage_bytes > 0);
+        assert_eq!(report.tenant.map(|tenant| (tenant.usage.events_today, tenant.events_per_day, tenant.storage_bytes)), Some((4, Some(4), None)));
+        let report = quotas.report().await.unwrap();
+        assert!(report.tenants.iter().any(|usage| usage.scope == tenant && usage.usage.events_today == 4));
+
+        // Throttled, one command per interval is let through.
+        let throttled = Quotas::new(pool.clone(), QuotaConfig { enforcement: QuotaEnforcement::Throttle, ..config });
+        let now = Instant::now();
+        let err = || QuotaError::Storage(noisy.clone(), 1);
+        assert_eq!(throttled.enforce(&noisy, err(), now), Ok(()));
+        assert_eq!(throttled.enforce(&noisy, err(), now + Duration::from_millis(400)), Err(QuotaError::Throttled(noisy.clone(), Duration::from_millis(600))));
+        assert_eq!(throttled.enforce(&noisy, err(), now + Duration::from_secs(1)), Ok(()));
+        assert_eq!(throttled.rejected.with_label_values(&["throttled"]).get(), 1);
+
+        delete_prefixed(&pool, "account_usage", "account_id", &tenant).await;
+    }
+
+    #[test]
+    fn test_charged_accounts() {
+        let batch = br#"{"Open": {"legs": [{"from_account": "A", "to_account": "B"}, {"from_account": "A", "to_account": "C"}, {"from_account": "D"}]}}"#;
+        assert_eq!(charged_accounts(batch), vec!["A", "D"]);
+        let bid = br#"{"PlaceBid": {"bid": {"bid_id": "1", "bidder": "E", "max_amount": 1, "max_total": 1, "timestamp": 0}}}"#;
+        assert_eq!(charged_accounts(bid), vec!["E"]);
+        assert!(charged_accounts(b"\"Continue\"").is_empty());
+        assert!(charged_accounts(b"not json").is_empty());
+    }
+}
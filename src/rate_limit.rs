@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use axum::extract::{RawPathParams, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use crate::config::{RateLimit, RateLimitConfig};
+use crate::metrics::RateLimitMetrics;
+use crate::state::ApplicationState;
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum RateLimitError {
+    #[error("Too many commands, try again later")]
+    Global(Duration),
+    #[error("Too many commands to account {0}, try again later")]
+    Account(String, Duration),
+}
+
+impl RateLimitError {
+    // How long until the bucket has a token again.
+    pub fn retry_after(&self) -> Duration {
+        match self {
+            RateLimitError::Global(wait) | RateLimitError::Account(_, wait) => *wait,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(limit: RateLimit, now: Instant) -> Self {
+        Bucket { tokens: limit.burst as f64, updated: now }
+    }
+
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_sec).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    // Time until the next token, none while there is one to take.
+    fn wait(&self, limit: RateLimit) -> Option<Duration> {
+        (self.tokens < 1.0).then(|| Duration::from_secs_f64((1.0 - self.tokens) / limit.per_sec))
+    }
+}
+
+struct Buckets {
+    global: Bucket,
+    accounts: HashMap<String, Bucket>,
+    swept_at: Instant,
+}
+
+// Token buckets keeping a burst of commands from stampeding the event store: one shared by
+// every command and one per account, so a hot account is held back before it takes the
+// global budget from the others. A command takes a token from both buckets or from none.
+// Configured by `RateLimitConfig`, reported by `RateLimitMetrics`.
+#[derive(Clone)]
+pub struct RateLimiter {
+    global: Option<RateLimit>,
+    account: Option<RateLimit>,
+    idle: Duration,
+    buckets: Arc<Mutex<Buckets>>,
+    metrics: RateLimitMetrics,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig, metrics: RateLimitMetrics) -> Self {
+        // Nothing would ever get through an empty bucket that is never refilled.
+        let enabled = |limit: &RateLimit| limit.per_sec > 0.0 && limit.burst > 0;
+        let global = config.global.filter(enabled);
+        let now = Instant::now();
+        let buckets = Buckets {
+            global: Bucket { tokens: global.map_or(0.0, |limit| limit.burst as f64), updated: now },
+            accounts: HashMap::new(),
+            swept_at: now,
+        };
+        RateLimiter {
+            global,
+            account: config.account.filter(enabled),
+            idle: Duration::from_secs(config.idle_secs),
+            buckets: Arc::new(Mutex::new(buckets)),
+            metrics,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.global.is_some() || self.account.is_some()
+    }
+
+    // Takes a token for a command, to the account when it is sent to one.
+    pub fn acquire(&self, account_id: Option<&str>) -> Result<(), RateLimitError> {
+        self.acquire_at(account_id, Instant::now())
+    }
+
+    fn acquire_at(&self, account_id: Option<&str>, now: Instant) -> Result<(), RateLimitError> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let mut buckets = self.buckets.lock().expect("rate limit buckets poisoned");
+        if now.saturating_duration_since(buckets.swept_at) >= self.idle {
+            let idle = self.idle;
+            buckets.accounts.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < idle);
+            buckets.swept_at = now;
+        }
+        if let Some(limit) = self.global {
+            buckets.global.refill(limit, now);
+            if let Some(wait) = buckets.global.wait(limit) {
+                self.metrics.rejected("global");
+                return Err(RateLimitError::Global(wait));
+            }
+        }
+        if let (Some(limit), Some(account_id)) = (self.account, account_id) {
+            let bucket = buckets.accounts.entry(account_id.to_string()).or_insert_with(|| Bucket::full(limit, now));
+            bucket.refill(limit, now);
+            if let Some(wait) = bucket.wait(limit) {
+                self.metrics.rejected("account");
+                return Err(RateLimitError::Account(account_id.to_string(), wait));
+            }
+            bucket.tokens -= 1.0;
+        }
+        if self.global.is_some() {
+            buckets.global.tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
+
+// Rate limits the commands sent to the routes it is layered on, queries go through. The
+// account is the `account_id` of the route, commands to routes without one only take from
+// the global bucket. Turned away commands are answered 429 with the seconds until the
+// bucket has a token again in `Retry-After`.
+pub async fn rate_limit_layer(
+    State(state): State<ApplicationState>,
+    params: Option<RawPathParams>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if request.method().is_safe() || !state.rate_limiter.is_enabled() {
+        return next.run(request).await;
+    }
+    let account_id = params
+        .as_ref()
+        .and_then(|params| params.iter().find(|(name, _)| *name == "account_id").map(|(_, value)| value));
+    match state.rate_limiter.acquire(account_id) {
+        Ok(()) => next.run(request).await,
+        Err(err) => {
+            tracing::warn!("{}", err);
+            let retry_after = err.retry_after().as_secs_f64().ceil().max(1.0) as u64;
+            (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, retry_after.to_string())], err.to_string()).into_response()
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use prometheus::{Registry, TextEncoder};
+    use crate::config::{RateLimit, RateLimitConfig};
+    use crate::metrics::RateLimitMetrics;
+    use crate::rate_limit::{RateLimitError, RateLimiter};
+
+    #[test]
+    fn test_rate_limits() {
+        let config = RateLimitConfig {
+            global: Some(RateLimit { per_sec: 10.0, burst: 4 }),
+            account: Some(RateLimit { per_sec: 1.0, burst: 2 }),
+            idle_secs: 60,
+        };
+        let registry = Registry::new();
+        let metrics = RateLimitMetrics::default();
+        metrics.register(&registry).unwrap();
+        let limiter = RateLimiter::new(&config, metrics);
+        let now = Instant::now();
+
+        limiter.acquire_at(Some("ACCT-0001"), now).unwrap();
+        limiter.acquire_at(Some("ACCT-0001"), now).unwrap();
+        // The hot account is held back on its own, without taking from the global bucket.
+        let err = limiter.acquire_at(Some("ACCT-0001"), now).unwrap_err();
+        assert_eq!(err, RateLimitError::Account("ACCT-0001".to_string(), Duration::from_secs(1)));
+        limiter.acquire_at(Some("ACCT-0002"), now).unwrap();
+        limiter.acquire_at(None, now).unwrap();
+        assert!(matches!(limiter.acquire_at(Some("ACCT-0003"), now), Err(RateLimitError::Global(_))));
+
+        // Both refill over time, up to the burst.
+        let later = now + Duration::from_secs(1);
+        limiter.acquire_at(Some("ACCT-0001"), later).unwrap();
+        assert!(limiter.acquire_at(Some("ACCT-0001"), later).is_err());
+        let metrics = TextEncoder::new().encode_to_string(&registry.gather()).unwrap();
+        assert!(metrics.contains("rate_limit_rejected_total{scope=\"account\"} 2"), "{}", metrics);
+        assert!(metrics.contains("rate_limit_rejected_total{scope=\"global\"} 1"), "{}", metrics);
+
+        // Unset or empty limits let everything through.
+        let unlimited = RateLimiter::new(&RateLimitConfig { global: Some(RateLimit { per_sec: 0.0, burst: 4 }), ..RateLimitConfig::default() }, RateLimitMetrics::default());
+        assert!(!unlimited.is_enabled());
+        assert!(unlimited.acquire_at(Some("ACCT-0001"), now).is_ok());
+    }
+}
@@ -0,0 +1,239 @@
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use sqlx::{PgConnection, Pool, Postgres, Row};
+use crate::auction::events::Price;
+use crate::order::book::Pair;
+
+pub const DEFAULT_PAGE_SIZE: i64 = 100;
+pub const MAX_PAGE_SIZE: i64 = 1000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RateError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Invalid cursor: {0}")]
+    InvalidCursor(String),
+}
+
+// A rate a pair settled at, in quote units per base unit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Rate {
+    pub price: Price,
+    pub base_amount: u64,
+    pub quote_amount: u64,
+    pub timestamp: u64,
+    // Transaction of the settlement, the order id for orders and auction fills.
+    pub txid: String,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RateFilter {
+    // Inclusive lower and exclusive upper bound on the settlement timestamp.
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    // `next_cursor` of the previous page.
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RateHistory {
+    pub base: String,
+    pub quote: String,
+    // Newest first.
+    pub rates: Vec<Rate>,
+    // Absent on the last page.
+    pub next_cursor: Option<String>,
+}
+
+// The value of a statement entry in another asset, at the latest rate the two assets
+// settled at up to the entry. Entries already in that asset keep their amount and have
+// no `rate_timestamp`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Valuation {
+    pub asset: String,
+    // Rounded down.
+    pub value: u64,
+    pub price: Price,
+    pub rate_timestamp: Option<u64>,
+}
+
+// Records the rate of a settlement. Both sides of a trade settle under the same txid,
+// and rows are kept per pair in alphabetical order of the assets, so a trade is
+// recorded once whichever side comes first. Called from the ledger projection, which
+// makes rates part of the ledger backfill.
+pub async fn write_rate(
+    conn: &mut PgConnection,
+    txid: &str,
+    timestamp: u64,
+    (send_asset, send_amount): (&str, u64),
+    (receive_asset, receive_amount): (&str, u64),
+) -> Result<(), sqlx::Error> {
+    if send_amount == 0 || receive_amount == 0 || send_asset == receive_asset {
+        return Ok(());
+    }
+    let ((asset_a, amount_a), (asset_b, amount_b)) = if send_asset < receive_asset {
+        ((send_asset, send_amount), (receive_asset, receive_amount))
+    } else {
+        ((receive_asset, receive_amount), (send_asset, send_amount))
+    };
+    sqlx::query(
+        "INSERT INTO rates (asset_a, asset_b, txid, amount_a, amount_b, timestamp) VALUES ($1, $2, $3, $4, $5, $6)
+         ON CONFLICT (asset_a, asset_b, txid) DO NOTHING",
+    )
+        .bind(asset_a)
+        .bind(asset_b)
+        .bind(txid)
+        .bind(amount_a as i64)
+        .bind(amount_b as i64)
+        .bind(timestamp as i64)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+// Reads a row of `rates` priced in the other asset per unit of `base`.
+fn rate(row: &sqlx::postgres::PgRow, base: &str) -> Result<Rate, sqlx::Error> {
+    let asset_a: String = row.try_get("asset_a")?;
+    let amount_a = row.try_get::<i64, _>("amount_a")? as u64;
+    let amount_b = row.try_get::<i64, _>("amount_b")? as u64;
+    let (base_amount, quote_amount) = if asset_a == base { (amount_a, amount_b) } else { (amount_b, amount_a) };
+    Ok(Rate {
+        price: Price::new(quote_amount, base_amount),
+        base_amount,
+        quote_amount,
+        timestamp: row.try_get::<i64, _>("timestamp")? as u64,
+        txid: row.try_get("txid")?,
+    })
+}
+
+// Returns the rates of a pair newest first, whichever direction its trades went.
+pub async fn load_rate_history(pool: &Pool<Postgres>, pair: &Pair, filter: &RateFilter) -> Result<RateHistory, RateError> {
+    let before = filter.cursor.as_deref()
+        .map(|cursor| {
+            cursor.split_once(':')
+                .and_then(|(timestamp, txid)| Some((timestamp.parse::<i64>().ok()?, txid.to_string())))
+                .ok_or_else(|| RateError::InvalidCursor(cursor.to_string()))
+        })
+        .transpose()?;
+    let limit = filter.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let rows = sqlx::query(
+        "SELECT asset_a, txid, amount_a, amount_b, timestamp FROM rates
+         WHERE asset_a = LEAST($1, $2) AND asset_b = GREATEST($1, $2)
+           AND ($3::bigint IS NULL OR timestamp >= $3)
+           AND ($4::bigint IS NULL OR timestamp < $4)
+           AND ($5::bigint IS NULL OR (timestamp, txid) < ($5, $6))
+         ORDER BY timestamp DESC, txid DESC
+         LIMIT $7",
+    )
+        .bind(&pair.base)
+        .bind(&pair.quote)
+        .bind(filter.from.map(|from| from as i64))
+        .bind(filter.to.map(|to| to as i64))
+        .bind(before.as_ref().map(|(timestamp, _)| *timestamp))
+        .bind(before.as_ref().map(|(_, txid)| txid.as_str()))
+        .bind(limit + 1)
+        .fetch_all(pool)
+        .await?;
+    let mut rates = rows.iter().map(|row| rate(row, &pair.base)).collect::<Result<Vec<_>, _>>()?;
+    let next_cursor = if rates.len() as i64 > limit {
+        rates.truncate(limit as usize);
+        rates.last().map(|rate| format!("{}:{}", rate.timestamp, rate.txid))
+    } else {
+        None
+    };
+    Ok(RateHistory { base: pair.base.clone(), quote: pair.quote.clone(), rates, next_cursor })
+}
+
+// Values `(asset, amount, timestamp)` items in `value_in`, each at the latest rate of its
+// asset against `value_in` up to its timestamp, in one round trip. Only assets that
+// settled directly against `value_in` are valued; there is no conversion through a
+// third asset.
+pub async fn value_at(pool: &Pool<Postgres>, value_in: &str, items: &[(&str, u64, u64)]) -> Result<Vec<Option<Valuation>>, RateError> {
+    if items.is_empty() {
+        return Ok(vec![]);
+    }
+    let assets: Vec<&str> = items.iter().map(|(asset, _, _)| *asset).collect();
+    let timestamps: Vec<i64> = items.iter().map(|(_, _, timestamp)| *timestamp as i64).collect();
+    let rows = sqlx::query(
+        "SELECT item.ordinality, r.asset_a, r.txid, r.amount_a, r.amount_b, r.timestamp
+         FROM unnest($1::text[], $2::bigint[]) WITH ORDINALITY AS item(asset, timestamp, ordinality)
+         LEFT JOIN LATERAL (
+             SELECT asset_a, txid, amount_a, amount_b, timestamp FROM rates
+             WHERE asset_a = LEAST(item.asset, $3) AND asset_b = GREATEST(item.asset, $3)
+               AND timestamp <= item.timestamp
+             ORDER BY timestamp DESC, txid DESC
+             LIMIT 1
+         ) r ON true
+         ORDER BY item.ordinality",
+    )
+        .bind(&assets)
+        .bind(&timestamps)
+        .bind(value_in)
+        .fetch_all(pool)
+        .await?;
+    items
+        .iter()
+        .zip(rows.iter())
+        .map(|((asset, amount, _), row)| {
+            if *asset == value_in {
+                return Ok(Some(Valuation { asset: value_in.to_string(), value: *amount, price: Price::new(1, 1), rate_timestamp: None }));
+            }
+            if row.try_get::<Option<String>, _>("asset_a")?.is_none() {
+                return Ok(None);
+            }
+            let rate = rate(row, asset)?;
+            let value = (*amount as u128 * rate.price.num as u128 / rate.price.den as u128) as u64;
+            Ok(Some(Valuation { asset: value_in.to_string(), value, price: rate.price, rate_timestamp: Some(rate.timestamp) }))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::auction::events::Price;
+    use crate::order::book::Pair;
+    use crate::rates::{load_rate_history, value_at, write_rate, RateFilter, Valuation};
+    use crate::util::test_db::{delete_rows, random_suffix, test_pool};
+
+    #[tokio::test]
+    async fn test_rate_history_and_valuation() {
+        let pool = test_pool();
+        let suffix = random_suffix();
+        let (btc, usdt) = (format!("BTC{}", suffix), format!("USDT{}", suffix));
+        let mut conn = pool.acquire().await.unwrap();
+        // Both sides of the first trade settle, the seller of BTC first.
+        write_rate(&mut conn, "trade-1", 100, (&btc, 2), (&usdt, 60_000)).await.unwrap();
+        write_rate(&mut conn, "trade-1", 100, (&usdt, 60_000), (&btc, 2)).await.unwrap();
+        write_rate(&mut conn, "trade-2", 200, (&usdt, 31_000), (&btc, 1)).await.unwrap();
+        write_rate(&mut conn, "trade-3", 300, (&btc, 4), (&usdt, 128_000)).await.unwrap();
+
+        let pair = Pair { base: btc.clone(), quote: usdt.clone() };
+        let first = load_rate_history(&pool, &pair, &RateFilter { limit: Some(2), ..Default::default() }).await.unwrap();
+        let prices: Vec<_> = first.rates.iter().map(|rate| (rate.timestamp, rate.price)).collect();
+        assert_eq!(prices, vec![(300, Price::new(32_000, 1)), (200, Price::new(31_000, 1))]);
+        assert_eq!((first.rates[0].base_amount, first.rates[0].quote_amount), (4, 128_000));
+        let filter = RateFilter { limit: Some(2), cursor: first.next_cursor, ..Default::default() };
+        let second = load_rate_history(&pool, &pair, &filter).await.unwrap();
+        assert_eq!(second.rates.iter().map(|rate| rate.txid.as_str()).collect::<Vec<_>>(), vec!["trade-1"]);
+        assert_eq!(second.next_cursor, None);
+        // The reversed pair reads the same trades the other way around.
+        let reversed = Pair { base: usdt.clone(), quote: btc.clone() };
+        let history = load_rate_history(&pool, &reversed, &RateFilter { from: Some(100), to: Some(200), ..Default::default() }).await.unwrap();
+        assert_eq!(history.rates.iter().map(|rate| rate.price).collect::<Vec<_>>(), vec![Price::new(1, 30_000)]);
+
+        let valued = value_at(&pool, &usdt, &[(&btc, 3, 250), (&btc, 3, 50), (&usdt, 7, 50), (&btc, 1, 300)]).await.unwrap();
+        assert_eq!(valued, vec![
+            Some(Valuation { asset: usdt.clone(), value: 93_000, price: Price::new(31_000, 1), rate_timestamp: Some(200) }),
+            // Nothing had settled yet.
+            None,
+            Some(Valuation { asset: usdt.clone(), value: 7, price: Price::new(1, 1), rate_timestamp: None }),
+            Some(Valuation { asset: usdt.clone(), value: 32_000, price: Price::new(32_000, 1), rate_timestamp: Some(300) }),
+        ]);
+        let valued = value_at(&pool, &btc, &[(&usdt, 45_000, 100)]).await.unwrap();
+        assert_eq!(valued[0].as_ref().map(|valuation| valuation.value), Some(1));
+
+        delete_rows(&pool, "rates", "asset_a", &btc).await;
+    }
+}
@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use cqrs_es::{Aggregate, EventEnvelope, View};
+use futures::TryStreamExt;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::postgres::PgRow;
+use sqlx::{Executor, PgConnection, Pool, Postgres, Row};
+use crate::account::aggregate::Account;
+use crate::account::journal::write_journal_entries;
+use crate::account::ledger::{write_ledger_entries, LedgerError};
+use crate::account::queries::AccountView;
+use crate::account::stats::AccountStats;
+use crate::asset::aggregate::Asset;
+use crate::asset::queries::AssetView;
+use crate::auction::aggregate::Auction;
+use crate::auction::queries::AuctionView;
+use crate::batch_transfer::aggregate::BatchTransfer;
+use crate::batch_transfer::queries::BatchTransferView;
+use crate::compaction::archive::ALL_EVENTS;
+use crate::field_encryption::{FieldCipher, FieldEncryptionError};
+use crate::order::aggregate::Order;
+use crate::order::book::write_order_book;
+use crate::order::queries::OrderView;
+use crate::preferences::aggregate::Preferences;
+use crate::preferences::queries::PreferencesView;
+use crate::replay::ReplayThrottle;
+use crate::rfq::aggregate::Rfq;
+use crate::rfq::queries::RfqView;
+use crate::sealing::Sealer;
+use crate::standing_order::aggregate::StandingOrder;
+use crate::standing_order::queries::StandingOrderView;
+use crate::transfer::aggregate::Transfer;
+use crate::transfer::queries::{refund_view, TransferView};
+
+const PROGRESS_INTERVAL: u64 = 1000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RebuildError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Malformed event {0}: {1}")]
+    Payload(String, serde_json::Error),
+    #[error("Ledger error: {0}")]
+    Ledger(#[from] LedgerError),
+    #[error("Unknown projection: {0}")]
+    UnknownProjection(String),
+    #[error(transparent)]
+    Encryption(#[from] FieldEncryptionError),
+}
+
+// The projections that can be rebuilt, each backed by a `GenericQuery` view table,
+// plus the account ledger, the journal and the order book.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Projection {
+    Account,
+    AccountStats,
+    Asset,
+    Transfer,
+    BatchTransfer,
+    Order,
+    Rfq,
+    Auction,
+    StandingOrder,
+    Preferences,
+    Ledger,
+    Journal,
+    OrderBook,
+}
+
+impl Projection {
+    pub fn table(&self) -> &'static str {
+        match self {
+            Projection::Account => "account_query",
+            Projection::AccountStats => "account_stats_query",
+            Projection::Asset => "asset_query",
+            Projection::Transfer => "transfer_query",
+            Projection::BatchTransfer => "batch_transfer_query",
+            Projection::Order => "order_query",
+            Projection::Rfq => "rfq_query",
+            Projection::Auction => "auction_query",
+            Projection::StandingOrder => "standing_order_query",
+            Projection::Preferences => "preferences_query",
+            Projection::Ledger => "ledger_entries",
+            Projection::Journal => "journal",
+            Projection::OrderBook => "open_orders",
+        }
+    }
+}
+
+impl FromStr for Projection {
+    type Err = RebuildError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "account" => Ok(Projection::Account),
+            "account_stats" => Ok(Projection::AccountStats),
+            "asset" => Ok(Projection::Asset),
+            "transfer" => Ok(Projection::Transfer),
+            "batch_transfer" => Ok(Projection::BatchTransfer),
+            "order" => Ok(Projection::Order),
+            "rfq" => Ok(Projection::Rfq),
+            "auction" => Ok(Projection::Auction),
+            "standing_order" => Ok(Projection::StandingOrder),
+            "preferences" => Ok(Projection::Preferences),
+            "ledger" => Ok(Projection::Ledger),
+            "journal" => Ok(Projection::Journal),
+            "order_book" => Ok(Projection::OrderBook),
+            other => Err(RebuildError::UnknownProjection(other.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RebuildOptions {
+    // Rebuild into `<table>_rebuild` and only swap it in once it is complete,
+    // instead of overwriting the live rows one by one.
+    pub shadow: bool,
+    // Stop after filling the shadow table, leaving the swap to an operator.
+    pub skip_swap: bool,
+    // Seals the rebuilt account views, as the live account query does.
+    pub sealer: Option<Sealer>,
+    // Decrypts the personal data in the events replayed, see `crate::field_encryption`.
+    pub cipher: Option<FieldCipher>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RebuildReport {
+    pub table: String,
+    pub aggregates: u64,
+    pub events: u64,
+    pub caught_up: u64,
+    pub swapped: bool,
+}
+
+// Paced by `throttle`, except for the order book, which is locked until it commits, and
+// the catch-up before a swap, which runs under the lock of the live table.
+pub async fn rebuild_projection(pool: &Pool<Postgres>, projection: Projection, options: &RebuildOptions, throttle: &mut ReplayThrottle) -> Result<RebuildReport, RebuildError> {
+    let table = projection.table();
+    match projection {
+        Projection::Account => rebuild::<Account, AccountView>(pool, table, options, options.sealer.as_ref(), throttle).await,
+        Projection::AccountStats => rebuild::<Account, AccountStats>(pool, table, options, options.sealer.as_ref(), throttle).await,
+        Projection::Asset => rebuild::<Asset, AssetView>(pool, table, options, None, throttle).await,
+        Projection::Transfer => {
+            let report = rebuild::<Transfer, TransferView>(pool, table, options, None, throttle).await?;
+            let target = match options.shadow && options.skip_swap {
+                true => format!("{}_rebuild", table),
+                false => table.to_string(),
+            };
+            relink_refunds(pool, &target, options).await?;
+            Ok(report)
+        },
+        Projection::BatchTransfer => rebuild::<BatchTransfer, BatchTransferView>(pool, table, options, None, throttle).await,
+        Projection::Order => rebuild::<Order, OrderView>(pool, table, options, None, throttle).await,
+        Projection::Rfq => rebuild::<Rfq, RfqView>(pool, table, options, None, throttle).await,
+        Projection::Auction => rebuild::<Auction, AuctionView>(pool, table, options, None, throttle).await,
+        Projection::StandingOrder => rebuild::<StandingOrder, StandingOrderView>(pool, table, options, None, throttle).await,
+        Projection::Preferences => rebuild::<Preferences, PreferencesView>(pool, table, options, None, throttle).await,
+        Projection::Ledger | Projection::Journal => backfill(pool, projection, throttle).await,
+        Projection::OrderBook => rebuild_order_book(pool).await,
+    }
+}
+
+// Streams every event of `A` out of the event store in aggregate order and re-applies
+// it to a fresh `V`, writing one row per aggregate the same way `GenericQuery` would.
+//
+// With a shadow table the swap happens under an exclusive lock on the live table,
+// after replaying any aggregate that received events while the bulk pass was running.
+// Views are sealed with `sealer` when given, bound to the live table name.
+pub async fn rebuild<A, V>(pool: &Pool<Postgres>, table: &str, options: &RebuildOptions, sealer: Option<&Sealer>, throttle: &mut ReplayThrottle) -> Result<RebuildReport, RebuildError>
+where
+    A: Aggregate,
+    V: View<A> + Default + Serialize,
+{
+    let target = if options.shadow {
+        let shadow = format!("{}_rebuild", table);
+        pool.execute(format!("DROP TABLE IF EXISTS {}", shadow).as_str()).await?;
+        pool.execute(format!("CREATE TABLE {} (LIKE {} INCLUDING ALL)", shadow, table).as_str()).await?;
+        shadow
+    } else {
+        table.to_string()
+    };
+    let mut report = RebuildReport { table: table.to_string(), ..Default::default() };
+    let seal = sealer.map(|sealer| (sealer, format!("view/{}", table)));
+    let seal = seal.as_ref().map(|(sealer, kind)| (*sealer, kind.as_str()));
+
+    let mut conn = pool.acquire().await?;
+    let mut rows = EventPages::new(pool, A::aggregate_type());
+    let mut current: Option<(String, V, i64)> = None;
+    let mut replayed: HashMap<String, i64> = HashMap::new();
+    while let Some(row) = rows.next().await? {
+        let aggregate_id: String = row.try_get("aggregate_id")?;
+        let envelope = envelope::<A>(&aggregate_id, &row, options.cipher.as_ref())?;
+        if current.as_ref().map(|(id, ..)| id != &aggregate_id).unwrap_or(true) {
+            if let Some((id, view, version)) = current.take() {
+                write_view(&mut conn, &target, &id, &view, version, seal).await?;
+                replayed.insert(id, version);
+                report.aggregates += 1;
+                if report.aggregates.is_multiple_of(PROGRESS_INTERVAL) {
+                    tracing::info!("rebuilding {}: {} aggregates, {} events", table, report.aggregates, report.events);
+                }
+            }
+            current = Some((aggregate_id, V::default(), 0));
+        }
+        let (_, view, version) = current.as_mut().expect("current view was just set");
+        view.update(&envelope);
+        *version = envelope.sequence as i64;
+        report.events += 1;
+        throttle.advance(1).await?;
+    }
+    if let Some((id, view, version)) = current.take() {
+        write_view(&mut conn, &target, &id, &view, version, seal).await?;
+        replayed.insert(id, version);
+        report.aggregates += 1;
+    }
+    tracing::info!("rebuilt {}: {} aggregates, {} events", table, report.aggregates, report.events);
+
+    if !options.shadow || options.skip_swap {
+        return Ok(report);
+    }
+
+    let mut tx = pool.begin().await?;
+    tx.execute(format!("LOCK TABLE {} IN ACCESS EXCLUSIVE MODE", table).as_str()).await?;
+    // New events always land in the hot table, so it is enough to find the aggregates
+    // that changed during the bulk pass.
+    let stale: Vec<String> = sqlx::query("SELECT aggregate_id, max(sequence) AS last FROM events WHERE aggregate_type = $1 GROUP BY aggregate_id")
+        .bind(A::aggregate_type())
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .filter_map(|row| {
+            let id: String = row.get("aggregate_id");
+            let last: i64 = row.get("last");
+            (replayed.get(&id) != Some(&last)).then_some(id)
+        })
+        .collect();
+    for id in stale {
+        let (view, version) = replay::<A, V>(&mut tx, &id, options.cipher.as_ref()).await?;
+        write_view(&mut tx, &target, &id, &view, version, seal).await?;
+        report.caught_up += 1;
+    }
+    tx.execute(format!("DROP TABLE {}", table).as_str()).await?;
+    tx.execute(format!("ALTER TABLE {} RENAME TO {}", target, table).as_str()).await?;
+    tx.execute(format!("ALTER INDEX {}_pkey RENAME TO {}_pkey", target, table).as_str()).await?;
+    tx.commit().await?;
+    report.swapped = true;
+    tracing::info!("swapped rebuilt {} into place ({} aggregates caught up)", table, report.caught_up);
+    Ok(report)
+}
+
+// Refunds have no events of their own, their views are written from the `Refunded`
+// events of the transfers they refund, after those are rebuilt.
+async fn relink_refunds(pool: &Pool<Postgres>, table: &str, options: &RebuildOptions) -> Result<(), RebuildError> {
+    let rows = sqlx::query(&format!(
+        "SELECT aggregate_id, sequence, payload, metadata FROM {} WHERE aggregate_type = $1 AND payload::jsonb ? 'Refunded' ORDER BY aggregate_id, sequence",
+        ALL_EVENTS
+    ))
+        .bind(Transfer::aggregate_type())
+        .fetch_all(pool)
+        .await?;
+    let mut conn = pool.acquire().await?;
+    for row in rows {
+        let aggregate_id: String = row.try_get("aggregate_id")?;
+        let envelope = envelope::<Transfer>(&aggregate_id, &row, options.cipher.as_ref())?;
+        if let Some((refund_id, view)) = refund_view(&aggregate_id, &envelope.payload) {
+            write_view(&mut conn, table, &refund_id, &view, 1, None).await?;
+        }
+    }
+    Ok(())
+}
+
+// Ledger and journal writes are idempotent, so both are backfilled in place, alongside
+// the live query, and the shadow options do not apply. Settlement rates are written
+// with the ledger and backfilled along with it.
+async fn backfill(pool: &Pool<Postgres>, projection: Projection, throttle: &mut ReplayThrottle) -> Result<RebuildReport, RebuildError> {
+    let table = projection.table();
+    let write = |batch: Vec<EventEnvelope<Account>>| async move {
+        match projection {
+            Projection::Journal => write_journal_entries(pool, &batch).await.map_err(RebuildError::from),
+            _ => write_ledger_entries(pool, &batch).await.map_err(RebuildError::from),
+        }
+    };
+    let mut report = RebuildReport { table: table.to_string(), ..Default::default() };
+    let mut rows = EventPages::new(pool, Account::aggregate_type());
+    let mut batch: Vec<EventEnvelope<Account>> = Vec::with_capacity(PROGRESS_INTERVAL as usize);
+    let mut last_id: Option<String> = None;
+    while let Some(row) = rows.next().await? {
+        let aggregate_id: String = row.try_get("aggregate_id")?;
+        if last_id.as_ref() != Some(&aggregate_id) {
+            report.aggregates += 1;
+            last_id = Some(aggregate_id.clone());
+        }
+        batch.push(envelope::<Account>(&aggregate_id, &row, None)?);
+        report.events += 1;
+        if batch.len() as u64 == PROGRESS_INTERVAL {
+            write(std::mem::take(&mut batch)).await?;
+            throttle.advance(PROGRESS_INTERVAL).await?;
+            tracing::info!("backfilling {}: {} aggregates, {} events", table, report.aggregates, report.events);
+        }
+    }
+    write(batch).await?;
+    tracing::info!("backfilled {}: {} aggregates, {} events", table, report.aggregates, report.events);
+    Ok(report)
+}
+
+// The book only holds current state, so it is emptied and replayed in one transaction;
+// readers keep seeing the old book until it commits.
+async fn rebuild_order_book(pool: &Pool<Postgres>) -> Result<RebuildReport, RebuildError> {
+    let table = Projection::OrderBook.table();
+    let mut report = RebuildReport { table: table.to_string(), ..Default::default() };
+    let mut tx = pool.begin().await?;
+    sqlx::query(&format!("DELETE FROM {}", table)).execute(&mut *tx).await?;
+    let events = format!("SELECT aggregate_id, sequence, payload, metadata FROM {} WHERE aggregate_type = $1 ORDER BY aggregate_id, sequence", ALL_EVENTS);
+    let mut rows = sqlx::query(&events)
+        .bind(Order::aggregate_type())
+        .fetch(pool);
+    let mut batch: Vec<EventEnvelope<Order>> = Vec::with_capacity(PROGRESS_INTERVAL as usize);
+    let mut last_id: Option<String> = None;
+    while let Some(row) = rows.try_next().await? {
+        let aggregate_id: String = row.try_get("aggregate_id")?;
+        if last_id.as_ref() != Some(&aggregate_id) {
+            report.aggregates += 1;
+            last_id = Some(aggregate_id.clone());
+        }
+        batch.push(envelope::<Order>(&aggregate_id, &row, None)?);
+        report.events += 1;
+        if batch.len() as u64 == PROGRESS_INTERVAL {
+            write_order_book(&mut tx, &batch).await?;
+            batch.clear();
+            tracing::info!("rebuilding {}: {} aggregates, {} events", table, report.aggregates, report.events);
+        }
+    }
+    write_order_book(&mut tx, &batch).await?;
+    tx.commit().await?;
+    report.swapped = true;
+    tracing::info!("rebuilt {}: {} aggregates, {} events", table, report.aggregates, report.events);
+    Ok(report)
+}
+
+// The events of one aggregate type in aggregate order, read a page at a time so that a
+// throttled replay holds no query open while it sleeps or waits.
+struct EventPages<'a> {
+    pool: &'a Pool<Postgres>,
+    aggregate_type: String,
+    page: std::vec::IntoIter<PgRow>,
+    // The last event read, where the next page starts after.
+    after: Option<(String, i64)>,
+    exhausted: bool,
+}
+
+impl<'a> EventPages<'a> {
+    fn new(pool: &'a Pool<Postgres>, aggregate_type: String) -> Self {
+        EventPages { pool, aggregate_type, page: Vec::new().into_iter(), after: None, exhausted: false }
+    }
+
+    async fn next(&mut self) -> Result<Option<PgRow>, sqlx::Error> {
+        if let Some(row) = self.page.next() {
+            return Ok(Some(row));
+        }
+        if self.exhausted {
+            return Ok(None);
+        }
+        let (after_id, after_sequence) = self.after.clone().unzip();
+        let rows = sqlx::query(&format!(
+            "SELECT aggregate_id, sequence, payload, metadata FROM {}
+             WHERE aggregate_type = $1 AND ($2::text IS NULL OR (aggregate_id, sequence) > ($2, $3))
+             ORDER BY aggregate_id, sequence
+             LIMIT $4",
+            ALL_EVENTS
+        ))
+            .bind(&self.aggregate_type)
+            .bind(after_id)
+            .bind(after_sequence)
+            .bind(PROGRESS_INTERVAL as i64)
+            .fetch_all(self.pool)
+            .await?;
+        self.exhausted = (rows.len() as u64) < PROGRESS_INTERVAL;
+        if let Some(last) = rows.last() {
+            self.after = Some((last.try_get("aggregate_id")?, last.try_get("sequence")?));
+        }
+        self.page = rows.into_iter();
+        Ok(self.page.next())
+    }
+}
+
+fn envelope<A: Aggregate>(aggregate_id: &str, row: &PgRow, cipher: Option<&FieldCipher>) -> Result<EventEnvelope<A>, RebuildError> {
+    let sequence: i64 = row.try_get("sequence")?;
+    let mut payload: Value = row.try_get("payload")?;
+    let mut metadata: Value = row.try_get("metadata")?;
+    let context = || format!("{}-{}", aggregate_id, sequence);
+    if let Some(cipher) = cipher {
+        cipher.decrypt_stored(&A::aggregate_type(), aggregate_id, sequence as usize, &mut payload, &mut metadata)?;
+    }
+    Ok(EventEnvelope {
+        aggregate_id: aggregate_id.to_string(),
+        sequence: sequence as usize,
+        payload: serde_json::from_value(payload).map_err(|e| RebuildError::Payload(context(), e))?,
+        metadata: serde_json::from_value(metadata).map_err(|e| RebuildError::Payload(context(), e))?,
+    })
+}
+
+// Applies every event of one aggregate to a fresh `V`, returning it with the sequence
+// of the last event, 0 when there are none.
+pub(crate) async fn replay<A, V>(conn: &mut PgConnection, aggregate_id: &str, cipher: Option<&FieldCipher>) -> Result<(V, i64), RebuildError>
+where
+    A: Aggregate,
+    V: View<A> + Default,
+{
+    let rows = sqlx::query(&format!("SELECT aggregate_id, sequence, payload, metadata FROM {} WHERE aggregate_type = $1 AND aggregate_id = $2 ORDER BY sequence", ALL_EVENTS))
+        .bind(A::aggregate_type())
+        .bind(aggregate_id)
+        .fetch_all(&mut *conn)
+        .await?;
+    let mut view = V::default();
+    let mut version = 0;
+    for row in rows {
+        let envelope = envelope::<A>(aggregate_id, &row, cipher)?;
+        view.update(&envelope);
+        version = envelope.sequence as i64;
+    }
+    Ok((view, version))
+}
+
+// Sequences start at 1 and have no gaps, so the last applied sequence is also the
+// number of updates, which is what `GenericQuery` stores as the view version.
+pub(crate) async fn write_view<V: Serialize>(conn: &mut PgConnection, table: &str, view_id: &str, view: &V, version: i64, seal: Option<(&Sealer, &str)>) -> Result<(), RebuildError> {
+    let payload = serde_json::to_value(view).map_err(|e| RebuildError::Payload(view_id.to_string(), e))?;
+    let payload = match seal {
+        Some((sealer, kind)) => sealer.seal(kind, view_id, version, &payload),
+        None => payload,
+    };
+    sqlx::query(&format!(
+        "INSERT INTO {} (view_id, version, payload) VALUES ($1, $2, $3)
+         ON CONFLICT (view_id) DO UPDATE SET version = EXCLUDED.version, payload = EXCLUDED.payload",
+        table
+    ))
+        .bind(view_id)
+        .bind(version)
+        .bind(payload)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rebuild::Projection;
+
+    #[test]
+    fn test_parse_projection() {
+        assert_eq!("order".parse::<Projection>().unwrap().table(), "order_query");
+        assert_eq!("ledger".parse::<Projection>().unwrap().table(), "ledger_entries");
+        assert_eq!("journal".parse::<Projection>().unwrap().table(), "journal");
+        assert_eq!(
+            "balances".parse::<Projection>().unwrap_err().to_string(),
+            "Unknown projection: balances"
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::{BTreeMap, BTreeSet};
+use axum::body::{to_bytes, Body};
+use axum::extract::{Request, State};
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use futures::TryStreamExt;
+use rand::Rng;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{Pool, Postgres, Row};
+use crate::command_extractor::{CORRELATION_ID_HDR, INITIATOR_HDR};
+use crate::idempotency::IDEMPOTENCY_KEY_HDR;
+use crate::state::ApplicationState;
+
+// Payout files are the largest bodies we accept.
+const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+// Only the headers that change what a command does are kept; credentials never are.
+const RECORDED_HEADERS: &[&str] = &["Content-Type", CORRELATION_ID_HDR, INITIATOR_HDR, IDEMPOTENCY_KEY_HDR];
+
+#[derive(Debug, thiserror::Error)]
+pub enum RecordingError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Malformed recording {0}: {1}")]
+    Payload(i64, serde_json::Error),
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedRequest {
+    pub id: i64,
+    pub correlation_id: String,
+    pub method: String,
+    // Path and query, e.g. `/account/ACCT-0001`.
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub status: u16,
+}
+
+// Records every command (POST, PUT or DELETE) request with the status it was answered
+// with, so that production traffic can be replayed against another build by
+// `replay-traffic`. Admin requests are recorded too, without their credentials, the
+// replay sends its own. Requests without a correlation id get one before they reach
+// the handler, which ties each recording to the events it produced.
+#[derive(Clone)]
+pub struct TrafficRecorder {
+    pool: Pool<Postgres>,
+    enabled: bool,
+}
+
+impl TrafficRecorder {
+    pub fn new(pool: Pool<Postgres>, enabled: bool) -> Self {
+        TrafficRecorder { pool, enabled }
+    }
+
+    pub async fn record(&self, request: &RecordedRequest) -> Result<(), RecordingError> {
+        sqlx::query(
+            "INSERT INTO recorded_request (correlation_id, method, path, headers, body, status, recorded_at)
+             VALUES ($1, $2, $3, $4, $5, $6, now())",
+        )
+            .bind(&request.correlation_id)
+            .bind(&request.method)
+            .bind(&request.path)
+            .bind(serde_json::to_value(&request.headers).map_err(|e| RecordingError::Payload(request.id, e))?)
+            .bind(&request.body)
+            .bind(request.status as i16)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+pub async fn recording_layer(State(state): State<ApplicationState>, request: Request, next: Next) -> Response {
+    if !state.recorder.enabled || ![Method::POST, Method::PUT, Method::DELETE].contains(request.method()) {
+        return next.run(request).await;
+    }
+    let (mut parts, body) = request.into_parts();
+    let body = match to_bytes(body, MAX_BODY_BYTES).await {
+        Ok(body) => body,
+        Err(err) => return (StatusCode::PAYLOAD_TOO_LARGE, err.to_string()).into_response(),
+    };
+    if !parts.headers.contains_key(CORRELATION_ID_HDR) {
+        let correlation_id = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+        parts.headers.insert(CORRELATION_ID_HDR, HeaderValue::from_str(&correlation_id).expect("hex is a valid header value"));
+    }
+    let mut recorded = RecordedRequest {
+        id: 0,
+        correlation_id: parts.headers[CORRELATION_ID_HDR].to_str().unwrap_or_default().to_string(),
+        method: parts.method.to_string(),
+        path: parts.uri.path_and_query().map(|path| path.to_string()).unwrap_or_default(),
+        headers: recorded_headers(&parts.headers),
+        body: body.to_vec(),
+        status: 0,
+    };
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    // Written before the response goes out, so a client's next request is always
+    // recorded after this one and the replay keeps their order.
+    recorded.status = response.status().as_u16();
+    if let Err(err) = state.recorder.record(&recorded).await {
+        tracing::error!("Failed to record request {} {}: {}", recorded.method, recorded.path, err);
+    }
+    response
+}
+
+fn recorded_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    RECORDED_HEADERS
+        .iter()
+        .filter_map(|name| Some((name.to_string(), headers.get(*name)?.to_str().ok()?.to_string())))
+        .collect()
+}
+
+pub async fn load_recordings(pool: &Pool<Postgres>, after: i64) -> Result<Vec<RecordedRequest>, RecordingError> {
+    sqlx::query("SELECT id, correlation_id, method, path, headers, body, status FROM recorded_request WHERE id > $1 ORDER BY id")
+        .bind(after)
+        .fetch(pool)
+        .map_err(RecordingError::from)
+        .and_then(|row| async move {
+            let id: i64 = row.try_get("id")?;
+            let headers: Value = row.try_get("headers")?;
+            Ok(RecordedRequest {
+                id,
+                correlation_id: row.try_get("correlation_id")?,
+                method: row.try_get("method")?,
+                path: row.try_get("path")?,
+                headers: serde_json::from_value(headers).map_err(|e| RecordingError::Payload(id, e))?,
+                body: row.try_get("body")?,
+                status: row.try_get::<i16, _>("status")? as u16,
+            })
+        })
+        .try_collect()
+        .await
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusMismatch {
+    pub id: i64,
+    pub correlation_id: String,
+    pub method: String,
+    pub path: String,
+    pub recorded: u16,
+    pub replayed: u16,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReplayReport {
+    pub requests: usize,
+    pub status_mismatches: Vec<StatusMismatch>,
+    // The JSON view of every resource the replayed commands touched, keyed by path.
+    pub views: BTreeMap<String, Value>,
+}
+
+// Re-sends the recordings in order to `target` (e.g. `http://localhost:3030`), then reads
+// back the view of every resource they touched.
+pub async fn replay(client: &reqwest::Client, target: &str, recordings: &[RecordedRequest]) -> Result<ReplayReport, RecordingError> {
+    let mut report = ReplayReport { requests: recordings.len(), ..Default::default() };
+    let mut touched = BTreeSet::new();
+    for recording in recordings {
+        let method = reqwest::Method::from_bytes(recording.method.as_bytes()).unwrap_or(reqwest::Method::POST);
+        let mut request = client.request(method, format!("{}{}", target, recording.path)).body(recording.body.clone());
+        for (name, value) in &recording.headers {
+            request = request.header(name, value);
+        }
+        let status = request.send().await?.status().as_u16();
+        if status != recording.status {
+            report.status_mismatches.push(StatusMismatch {
+                id: recording.id,
+                correlation_id: recording.correlation_id.clone(),
+                method: recording.method.clone(),
+                path: recording.path.clone(),
+                recorded: recording.status,
+                replayed: status,
+            });
+        }
+        touched.insert(recording.path.split('?').next().unwrap_or_default().to_string());
+    }
+    // Command endpoints without a JSON view (payouts, the faucet, ...) are skipped.
+    for path in touched {
+        let response = client.get(format!("{}{}", target, path)).send().await?;
+        if !response.status().is_success() {
+            continue;
+        }
+        if let Ok(view) = response.json::<Value>().await {
+            report.views.insert(path, view);
+        }
+    }
+    Ok(report)
+}
+
+// Lists every difference between two sets of views as `<path><json pointer>: <expected> != <actual>`.
+// Fields named in `ignore` (e.g. server assigned `timestamp`s) are skipped at any depth.
+pub fn diff_views(expected: &BTreeMap<String, Value>, actual: &BTreeMap<String, Value>, ignore: &[String]) -> Vec<String> {
+    let mut differences = Vec::new();
+    let paths: BTreeSet<&String> = expected.keys().chain(actual.keys()).collect();
+    for path in paths {
+        diff_value(path, expected.get(path).unwrap_or(&Value::Null), actual.get(path).unwrap_or(&Value::Null), ignore, &mut differences);
+    }
+    differences
+}
+
+fn diff_value(location: &str, expected: &Value, actual: &Value, ignore: &[String], differences: &mut Vec<String>) {
+    match (expected, actual) {
+        (Value::Object(expected), Value::Object(actual)) => {
+            let keys: BTreeSet<&String> = expected.keys().chain(actual.keys()).collect();
+            for key in keys.into_iter().filter(|key| !ignore.contains(key)) {
+                let location = format!("{}/{}", location, key);
+                diff_value(&location, expected.get(key).unwrap_or(&Value::Null), actual.get(key).unwrap_or(&Value::Null), ignore, differences);
+            }
+        },
+        (Value::Array(expected), Value::Array(actual)) if expected.len() == actual.len() => {
+            for (index, (expected, actual)) in expected.iter().zip(actual).enumerate() {
+                diff_value(&format!("{}/{}", location, index), expected, actual, ignore, differences);
+            }
+        },
+        (expected, actual) if expected != actual => differences.push(format!("{}: {} != {}", location, expected, actual)),
+        _ => {},
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use axum::http::{header, HeaderMap, HeaderValue};
+    use serde_json::json;
+    use crate::auth::API_KEY_HDR;
+    use crate::command_extractor::CORRELATION_ID_HDR;
+    use crate::recording::{diff_views, recorded_headers};
+
+    #[test]
+    fn test_recorded_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(API_KEY_HDR, HeaderValue::from_static("first-key"));
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer token"));
+        headers.insert(CORRELATION_ID_HDR, HeaderValue::from_static("abc"));
+        assert_eq!(recorded_headers(&headers), vec![(CORRELATION_ID_HDR.to_string(), "abc".to_string())]);
+    }
+
+    #[test]
+    fn test_diff_views() {
+        let expected = BTreeMap::from([
+            ("/account/A".to_string(), json!({"balance": {"BTC": 10}, "recent_ledger": [{"timestamp": 1, "amount": 10}]})),
+            ("/order/O".to_string(), json!({"state": "Filled"})),
+        ]);
+        let actual = BTreeMap::from([
+            ("/account/A".to_string(), json!({"balance": {"BTC": 12}, "recent_ledger": [{"timestamp": 2, "amount": 10}]})),
+            ("/order/O".to_string(), json!({"state": "Filled"})),
+        ]);
+        assert_eq!(
+            diff_views(&expected, &actual, &["timestamp".to_string()]),
+            vec!["/account/A/balance/BTC: 10 != 12".to_string()]
+        );
+        assert_eq!(diff_views(&expected, &BTreeMap::new(), &[]).len(), 2);
+    }
+}
@@ -0,0 +1,240 @@
+use std::time::Duration;
+use chrono::Timelike;
+use serde::Serialize;
+use sqlx::{Pool, Postgres, Row};
+use tokio::time::Instant;
+use utoipa::ToSchema;
+use crate::config::ReplayConfig;
+
+// How often a replay reports its progress and reads its controls.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+// How often a paused or waiting replay looks again.
+const WAIT_INTERVAL: Duration = Duration::from_secs(5);
+
+// Background replays run at `ReplayConfig::rows_per_sec` in the off-peak hours, urgent
+// ones, e.g. the rebuild of a view that went wrong, at `urgent_rows_per_sec` at any time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReplayPriority {
+    #[default]
+    Background,
+    Urgent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReplayState {
+    Running,
+    Paused,
+    // Outside the off-peak hours.
+    Waiting,
+    Done,
+    Failed,
+}
+
+impl ReplayState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ReplayState::Running => "Running",
+            ReplayState::Paused => "Paused",
+            ReplayState::Waiting => "Waiting",
+            ReplayState::Done => "Done",
+            ReplayState::Failed => "Failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ReplayProgress {
+    pub name: String,
+    pub priority: String,
+    pub state: String,
+    // Events or rows replayed so far.
+    pub rows: u64,
+    // Set by an operator, the replay stops at its next report until resumed.
+    pub paused: bool,
+    pub started_at: u64,
+    pub updated_at: u64,
+}
+
+// Paces a bulk replay, the projection rebuilds, archiving and the compaction verifier,
+// so it leaves the database to live traffic: it sleeps to keep to the rows per second of
+// its priority and, in the background, waits for the off-peak hours. Progress is kept in
+// `replay_jobs` under the name of the replay, where an operator can pause it, see
+// `pause`; the replays run as separate processes and only meet the server there.
+pub struct ReplayThrottle {
+    pool: Pool<Postgres>,
+    name: String,
+    config: ReplayConfig,
+    priority: ReplayPriority,
+    rows: u64,
+    // Rows since the pace was last reset, after a pause.
+    paced_since: Instant,
+    paced_rows: u64,
+    reported_at: Instant,
+}
+
+impl ReplayThrottle {
+    // Registers the replay, replacing the progress of an earlier run under the name.
+    pub async fn start(pool: &Pool<Postgres>, name: &str, config: &ReplayConfig, priority: ReplayPriority) -> Result<Self, sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO replay_jobs (name, priority, state, rows, paused, started_at, updated_at)
+             VALUES ($1, $2, $3, 0, false, now(), now())
+             ON CONFLICT (name) DO UPDATE SET priority = EXCLUDED.priority, state = EXCLUDED.state, rows = 0,
+                 paused = false, started_at = EXCLUDED.started_at, updated_at = EXCLUDED.updated_at",
+        )
+            .bind(name)
+            .bind(format!("{:?}", priority))
+            .bind(ReplayState::Running.as_str())
+            .execute(pool)
+            .await?;
+        let now = Instant::now();
+        let mut throttle = ReplayThrottle {
+            pool: pool.clone(),
+            name: name.to_string(),
+            config: config.clone(),
+            priority,
+            rows: 0,
+            paced_since: now,
+            paced_rows: 0,
+            reported_at: now,
+        };
+        throttle.hold().await?;
+        // Pacing from here, not counting the registration against the rows.
+        throttle.paced_since = Instant::now();
+        Ok(throttle)
+    }
+
+    // Counts `rows` as replayed and waits as long as the replay must.
+    pub async fn advance(&mut self, rows: u64) -> Result<(), sqlx::Error> {
+        self.rows += rows;
+        self.paced_rows += rows;
+        if let Some(rate) = self.rate() {
+            let earned = Duration::from_secs_f64(self.paced_rows as f64 / rate as f64);
+            let elapsed = self.paced_since.elapsed();
+            if earned > elapsed {
+                tokio::time::sleep(earned - elapsed).await;
+            }
+        }
+        if self.reported_at.elapsed() >= REPORT_INTERVAL {
+            self.hold().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn finish(mut self, succeeded: bool) -> Result<(), sqlx::Error> {
+        let state = if succeeded { ReplayState::Done } else { ReplayState::Failed };
+        self.report(state).await?;
+        Ok(())
+    }
+
+    fn rate(&self) -> Option<u64> {
+        let rate = match self.priority {
+            ReplayPriority::Background => self.config.rows_per_sec,
+            ReplayPriority::Urgent => self.config.urgent_rows_per_sec,
+        };
+        (rate > 0).then_some(rate)
+    }
+
+    // Reports the progress and waits while the replay is paused or, in the background,
+    // outside the off-peak hours.
+    async fn hold(&mut self) -> Result<(), sqlx::Error> {
+        let mut held = false;
+        loop {
+            let paused = self.report(ReplayState::Running).await?;
+            let off_peak = self.priority == ReplayPriority::Urgent || self.config.is_off_peak(chrono::Utc::now().hour());
+            if !paused && off_peak {
+                break;
+            }
+            if !held {
+                tracing::info!("replay {} {} after {} rows", self.name, if paused { "paused" } else { "waiting for off-peak hours" }, self.rows);
+                held = true;
+            }
+            self.report(if paused { ReplayState::Paused } else { ReplayState::Waiting }).await?;
+            tokio::time::sleep(WAIT_INTERVAL).await;
+        }
+        if held {
+            tracing::info!("replay {} resumed", self.name);
+            // No catching up on the time spent waiting.
+            self.paced_since = Instant::now();
+            self.paced_rows = 0;
+        }
+        Ok(())
+    }
+
+    // Whether an operator paused the replay.
+    async fn report(&mut self, state: ReplayState) -> Result<bool, sqlx::Error> {
+        self.reported_at = Instant::now();
+        let row = sqlx::query("UPDATE replay_jobs SET state = $2, rows = $3, updated_at = now() WHERE name = $1 RETURNING paused")
+            .bind(&self.name)
+            .bind(state.as_str())
+            .bind(self.rows as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+        // Removed from under us, nothing left to pause it.
+        Ok(row.map(|row| row.try_get("paused")).transpose()?.unwrap_or(false))
+    }
+}
+
+pub async fn list_replays(pool: &Pool<Postgres>) -> Result<Vec<ReplayProgress>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT name, priority, state, rows, paused, EXTRACT(EPOCH FROM started_at)::bigint AS started_at, EXTRACT(EPOCH FROM updated_at)::bigint AS updated_at
+         FROM replay_jobs ORDER BY started_at DESC",
+    )
+        .fetch_all(pool)
+        .await?;
+    let mut replays = Vec::with_capacity(rows.len());
+    for row in rows {
+        replays.push(ReplayProgress {
+            name: row.try_get("name")?,
+            priority: row.try_get("priority")?,
+            state: row.try_get("state")?,
+            rows: row.try_get::<i64, _>("rows")? as u64,
+            paused: row.try_get("paused")?,
+            started_at: row.try_get::<i64, _>("started_at")? as u64,
+            updated_at: row.try_get::<i64, _>("updated_at")? as u64,
+        });
+    }
+    Ok(replays)
+}
+
+// Pauses or resumes a replay, which notices within a second; false when there is no
+// replay under the name.
+pub async fn pause(pool: &Pool<Postgres>, name: &str, paused: bool) -> Result<bool, sqlx::Error> {
+    let updated = sqlx::query("UPDATE replay_jobs SET paused = $2 WHERE name = $1")
+        .bind(name)
+        .bind(paused)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    Ok(updated > 0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use tokio::time::Instant;
+    use crate::config::ReplayConfig;
+    use crate::replay::{list_replays, pause, ReplayPriority, ReplayThrottle};
+    use crate::util::test_db::{delete_rows, random_suffix, test_pool};
+
+    #[tokio::test]
+    async fn test_replay_throttle() {
+        let pool = test_pool();
+        let name = format!("test-{}", random_suffix());
+        let config = ReplayConfig { rows_per_sec: 1, urgent_rows_per_sec: 100, off_peak_hours: vec![] };
+        let mut throttle = ReplayThrottle::start(&pool, &name, &config, ReplayPriority::Urgent).await.unwrap();
+        let started = Instant::now();
+        for _ in 0..5 {
+            throttle.advance(10).await.unwrap();
+        }
+        // 50 rows at 100 a second.
+        assert!(started.elapsed() >= Duration::from_millis(490));
+        assert!(pause(&pool, &name, true).await.unwrap());
+        assert!(!pause(&pool, "no such replay", true).await.unwrap());
+        throttle.finish(true).await.unwrap();
+
+        let progress = list_replays(&pool).await.unwrap().into_iter().find(|replay| replay.name == name).unwrap();
+        assert_eq!((progress.state.as_str(), progress.rows, progress.paused), ("Done", 50, true));
+
+        delete_rows(&pool, "replay_jobs", "name", &name).await;
+    }
+}
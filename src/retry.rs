@@ -0,0 +1,111 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use cqrs_es::AggregateError;
+use rand::Rng;
+use crate::account::commands::AccountCommand;
+use crate::account::events::AccountError;
+use crate::config::ConflictRetryConfig;
+use crate::metrics::ErrorVariant;
+use crate::services::AccountExecutor;
+
+// Executes a command again while it loses to concurrent commands on its aggregate, also
+// when a saga lost on an aggregate it executes on, with a jittered backoff in between.
+// The losing attempt committed nothing, so the command is simply executed again against
+// the aggregate as the winner left it. Whatever else it fails with is returned as is.
+pub async fn retry_conflicts<T, E, F>(config: &ConflictRetryConfig, mut execute: impl FnMut() -> F) -> Result<T, E>
+where
+    E: ErrorVariant,
+    F: Future<Output = Result<T, E>>,
+{
+    let mut backoff = Duration::from_millis(config.backoff_ms);
+    let mut attempt = 1;
+    loop {
+        match execute().await {
+            Err(err) if err.variant() == "AggregateConflict" && attempt < config.attempts => {
+                tracing::debug!("Command lost a conflict, attempt {} of {}", attempt, config.attempts);
+                tokio::time::sleep(jittered(backoff)).await;
+                backoff = (backoff * 2).min(Duration::from_millis(config.max_backoff_ms));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn jittered(backoff: Duration) -> Duration {
+    let millis = backoff.as_millis() as u64;
+    Duration::from_millis(rand::thread_rng().gen_range(millis / 2..=millis))
+}
+
+// Retries the account commands of the sagas, which share their accounts with the routes
+// and with each other.
+pub struct RetryingExecutor {
+    inner: Arc<dyn AccountExecutor>,
+    config: ConflictRetryConfig,
+}
+
+impl RetryingExecutor {
+    pub fn new(inner: Arc<dyn AccountExecutor>, config: ConflictRetryConfig) -> Self {
+        RetryingExecutor { inner, config }
+    }
+}
+
+#[async_trait]
+impl AccountExecutor for RetryingExecutor {
+    async fn execute(&self, account_id: &str, command: AccountCommand) -> Result<(), AggregateError<AccountError>> {
+        retry_conflicts(&self.config, || self.inner.execute(account_id, command.clone())).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use cqrs_es::AggregateError;
+    use crate::account::events::AccountError;
+    use crate::config::ConflictRetryConfig;
+    use crate::retry::retry_conflicts;
+    use crate::transfer::aggregate::TransferError;
+
+    #[tokio::test]
+    async fn test_retry_conflicts() {
+        let config = ConflictRetryConfig { attempts: 3, backoff_ms: 1, max_backoff_ms: 2 };
+        let attempts = AtomicU32::new(0);
+        // Conflicts until the given attempt.
+        let execute = |until: u32| {
+            let attempts = &attempts;
+            move || async move {
+                match attempts.fetch_add(1, Ordering::Relaxed) + 1 < until {
+                    true => Err(AggregateError::<AccountError>::AggregateConflict),
+                    false => Ok(()),
+                }
+            }
+        };
+        assert!(retry_conflicts(&config, execute(3)).await.is_ok());
+        assert_eq!(attempts.swap(0, Ordering::Relaxed), 3);
+        assert!(matches!(retry_conflicts(&config, execute(4)).await, Err(AggregateError::AggregateConflict)));
+        assert_eq!(attempts.swap(0, Ordering::Relaxed), 3);
+
+        // Business errors are not retried.
+        let rejected = retry_conflicts(&config, || async {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            Err::<(), _>(AggregateError::UserError(AccountError::InvalidTransaction))
+        });
+        assert!(matches!(rejected.await, Err(AggregateError::UserError(AccountError::InvalidTransaction))));
+        assert_eq!(attempts.swap(0, Ordering::Relaxed), 1);
+
+        // Nor are conflicts when the policy says so, but those of a saga are.
+        let once = ConflictRetryConfig { attempts: 1, ..config.clone() };
+        assert!(retry_conflicts(&once, execute(2)).await.is_err());
+        assert_eq!(attempts.swap(0, Ordering::Relaxed), 1);
+        let saga = retry_conflicts(&config, || async {
+            match attempts.fetch_add(1, Ordering::Relaxed) {
+                0 => Err(AggregateError::UserError(TransferError::AggregateError(AggregateError::AggregateConflict))),
+                _ => Ok(()),
+            }
+        });
+        assert!(saga.await.is_ok());
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+    }
+}
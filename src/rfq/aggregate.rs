@@ -0,0 +1,490 @@
+use std::collections::BTreeMap;
+use std::mem::swap;
+use async_trait::async_trait;
+use cqrs_es::{Aggregate, AggregateError};
+use serde::{Deserialize, Serialize};
+use crate::account::events::AccountError;
+use crate::order::aggregate::{OrderError, OrderServices};
+use crate::statemachine::{StateMachine, Transition};
+use crate::rfq::commands::RfqCommand;
+use crate::rfq::events::{Quote, RfqConfig, RfqEvent};
+use crate::util::types::ByteArray32;
+use crate::metrics::{ErrorVariant, SagaStep};
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub enum Rfq {
+    #[default]
+    Uninitialized,
+    Requested {
+        config: RfqConfig,
+        quotes: BTreeMap<String, Quote>,
+    },
+    Accepted {
+        config: RfqConfig,
+        quote: Quote,
+        timestamp: u64,
+    },
+    Locked {
+        config: RfqConfig,
+        quote: Quote,
+        timestamp: u64,
+    },
+    Cancelled {
+        config: RfqConfig,
+        reason: String,
+        timestamp: u64,
+    },
+    Failed {
+        config: RfqConfig,
+        quote: Quote,
+        reason: String,
+        timestamp: u64,
+    },
+    Settled {
+        config: RfqConfig,
+        quote: Quote,
+        timestamp: u64,
+    },
+}
+
+impl Rfq {
+    fn id(&self) -> Option<ByteArray32> {
+        match self {
+            Rfq::Uninitialized => None,
+            Rfq::Requested { config, .. }
+            | Rfq::Accepted { config, .. }
+            | Rfq::Locked { config, .. }
+            | Rfq::Cancelled { config, .. }
+            | Rfq::Failed { config, .. }
+            | Rfq::Settled { config, .. } => Some(config.rfq_id),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RfqError {
+    #[error("Invalid state: {0}")]
+    InvalidState(String),
+    #[error("Quote not found: {0}")]
+    QuoteNotFound(String),
+    #[error("Duplicate quote: {0}")]
+    DuplicateQuote(String),
+    #[error("Quote {0} expired at {1}")]
+    QuoteExpired(String, u64),
+    #[error("RFQ expired at {0}")]
+    RfqExpired(u64),
+    #[error("Taker cannot quote its own request")]
+    SelfQuote,
+    #[error("Account error: {0}")]
+    AccountError(#[from] AccountError),
+    #[error("Aggregate error: {0}")]
+    AggregateError(#[from] AggregateError<AccountError>),
+}
+
+impl ErrorVariant for RfqError {
+    fn variant(&self) -> &'static str {
+        match self {
+            RfqError::InvalidState(_) => "InvalidState",
+            RfqError::QuoteNotFound(_) => "QuoteNotFound",
+            RfqError::DuplicateQuote(_) => "DuplicateQuote",
+            RfqError::QuoteExpired(..) => "QuoteExpired",
+            RfqError::RfqExpired(_) => "RfqExpired",
+            RfqError::SelfQuote => "SelfQuote",
+            RfqError::AccountError(e) => e.variant(),
+            RfqError::AggregateError(e) => e.variant(),
+        }
+    }
+}
+
+impl From<OrderError> for RfqError {
+    fn from(e: OrderError) -> Self {
+        match e {
+            OrderError::InvalidState(s) => RfqError::InvalidState(s),
+            OrderError::AccountError(ae) => RfqError::AccountError(ae),
+            OrderError::AggregateError(ae) => RfqError::AggregateError(ae),
+            OrderError::Halt(e) => RfqError::InvalidState(e.to_string()),
+            OrderError::PolicyViolation(s) => RfqError::InvalidState(s),
+        }
+    }
+}
+
+// An accepted quote is settled with the same lock/settle steps as an order,
+// the RFQ id doubles as the lock id on both the taker and the maker account.
+#[derive(Clone)]
+pub struct RfqServices {
+    settlement: OrderServices,
+}
+
+impl RfqServices {
+    pub fn new(settlement: OrderServices) -> Self {
+        RfqServices { settlement }
+    }
+}
+
+#[async_trait]
+impl Aggregate for Rfq {
+    type Command = RfqCommand;
+    type Event = RfqEvent;
+    type Error = RfqError;
+    type Services = RfqServices;
+
+    fn aggregate_type() -> String {
+        "rfq".to_string()
+    }
+
+    async fn handle(
+        &self,
+        command: Self::Command,
+        services: &Self::Services,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        let span = tracing::span!(tracing::Level::INFO, "Rfq::handle", rfq_id = self.id().map(|id| id.hex()));
+        let _ = span.enter();
+        match (self, command) {
+            (Rfq::Uninitialized, RfqCommand::Request { config }) => {
+                Ok(vec![RfqEvent::Requested { config }])
+            },
+            (Rfq::Requested { config, quotes }, RfqCommand::Quote { quote }) => {
+                if quote.maker == config.taker {
+                    return Err(RfqError::SelfQuote);
+                }
+                if services.settlement.now() > config.expires_at {
+                    return Err(RfqError::RfqExpired(config.expires_at));
+                }
+                if quotes.contains_key(&quote.quote_id) {
+                    return Err(RfqError::DuplicateQuote(quote.quote_id));
+                }
+                Ok(vec![RfqEvent::Quoted { quote }])
+            },
+            // Expiry is judged by the clock of the saga, not by a time the taker sends.
+            (Rfq::Requested { config, quotes }, RfqCommand::Accept { quote_id }) => {
+                let Some(quote) = quotes.get(&quote_id) else {
+                    return Err(RfqError::QuoteNotFound(quote_id));
+                };
+                let timestamp = services.settlement.now();
+                if timestamp > config.expires_at {
+                    return Err(RfqError::RfqExpired(config.expires_at));
+                }
+                if timestamp > quote.expires_at {
+                    return Err(RfqError::QuoteExpired(quote_id, quote.expires_at));
+                }
+                Ok(vec![RfqEvent::Accepted { quote_id, timestamp }])
+            },
+            (Rfq::Requested { .. }, RfqCommand::Cancel { reason }) => {
+                Ok(vec![RfqEvent::Cancelled {
+                    timestamp: services.settlement.now(),
+                    reason,
+                }])
+            },
+            (Rfq::Accepted { config, quote, timestamp }, RfqCommand::Continue) => {
+                // The taker sells and the maker buys.
+                let taker_lock = match services.settlement.timed(SagaStep::SellerLock, services.settlement.lock_funds(
+                    config.rfq_id,
+                    config.taker.clone(),
+                    config.sell_asset.clone(),
+                    config.sell_amount,
+                    *timestamp,
+                )).await {
+                    Err(OrderError::AccountError(ae)) => {
+                        return Ok(vec![RfqEvent::Failed {
+                            timestamp: services.settlement.now(),
+                            reason: format!("Failed to lock taker funds: {:?}", ae),
+                        }]);
+                    },
+                    Err(e) => return Err(e.into()),
+                    Ok(guard) => guard,
+                };
+                let maker_lock = match services.settlement.timed(SagaStep::BuyerLock, services.settlement.lock_funds(
+                    config.rfq_id,
+                    quote.maker.clone(),
+                    config.buy_asset.clone(),
+                    quote.buy_amount,
+                    *timestamp,
+                )).await {
+                    Err(OrderError::AccountError(ae)) => {
+                        // Dropping the taker guard releases the taker's funds.
+                        drop(taker_lock);
+                        return Ok(vec![RfqEvent::Failed {
+                            timestamp: services.settlement.now(),
+                            reason: format!("Failed to lock maker funds: {:?}", ae),
+                        }]);
+                    },
+                    Err(e) => return Err(e.into()),
+                    Ok(guard) => guard,
+                };
+                maker_lock.commit().await.map_err(OrderError::from)?;
+                taker_lock.commit().await.map_err(OrderError::from)?;
+                Ok(vec![RfqEvent::Locked {
+                    timestamp: services.settlement.now(),
+                }])
+            },
+            (Rfq::Locked { config, quote, timestamp }, RfqCommand::Continue) => {
+                services.settlement.timed(SagaStep::SettleLeg1, services.settlement.settle(
+                    SagaStep::SettleLeg1,
+                    config.rfq_id,
+                    quote.maker.clone(),
+                    config.taker.clone(),
+                    config.sell_asset.clone(),
+                    config.sell_amount,
+                )).await?;
+                match services.settlement.timed(SagaStep::SettleLeg2, services.settlement.settle(
+                    SagaStep::SettleLeg2,
+                    config.rfq_id,
+                    config.taker.clone(),
+                    quote.maker.clone(),
+                    config.buy_asset.clone(),
+                    quote.buy_amount,
+                )).await {
+                    // The maker is settled, the taker cannot be: the maker gives back
+                    // what it received and the taker's lock is released.
+                    Err(OrderError::AccountError(ae)) => {
+                        services.settlement.reverse_settlement(
+                            config.rfq_id,
+                            quote.maker.clone(),
+                            config.taker.clone(),
+                            (config.buy_asset.clone(), quote.buy_amount),
+                            (config.sell_asset.clone(), config.sell_amount),
+                        ).await;
+                        Ok(vec![RfqEvent::Failed {
+                            timestamp: services.settlement.now(),
+                            reason: format!("Failed to settle taker: {:?}", ae),
+                        }])
+                    },
+                    Err(e) => Err(e.into()),
+                    Ok(()) => Ok(vec![RfqEvent::Settled {
+                        timestamp: *timestamp,
+                    }]),
+                }
+            },
+            (state, cmd) => {
+                Err(RfqError::InvalidState(format!("RFQ current at {:?} state, cannot accept {:?} command", state, cmd)))
+            }
+        }
+    }
+
+    fn apply(&mut self, event: Self::Event) {
+        let mut prev = Default::default();
+        swap(&mut prev, self);
+        *self = match (prev, event) {
+            (Rfq::Uninitialized, RfqEvent::Requested { config }) => Rfq::Requested {
+                config,
+                quotes: BTreeMap::new(),
+            },
+            (Rfq::Requested { config, mut quotes }, RfqEvent::Quoted { quote }) => {
+                quotes.insert(quote.quote_id.clone(), quote);
+                Rfq::Requested { config, quotes }
+            },
+            (Rfq::Requested { config, mut quotes }, RfqEvent::Accepted { quote_id, timestamp }) => {
+                let quote = quotes.remove(&quote_id).expect("accepted quote should exist");
+                Rfq::Accepted { config, quote, timestamp }
+            },
+            (Rfq::Requested { config, .. }, RfqEvent::Cancelled { timestamp, reason }) => {
+                Rfq::Cancelled { config, reason, timestamp }
+            },
+            (Rfq::Accepted { config, quote, .. }, RfqEvent::Locked { timestamp }) => {
+                Rfq::Locked { config, quote, timestamp }
+            },
+            (Rfq::Accepted { config, quote, .. } | Rfq::Locked { config, quote, .. }, RfqEvent::Failed { timestamp, reason }) => {
+                Rfq::Failed { config, quote, reason, timestamp }
+            },
+            (Rfq::Locked { config, quote, .. }, RfqEvent::Settled { timestamp }) => {
+                Rfq::Settled { config, quote, timestamp }
+            },
+            (state, event) => unreachable!("Invalid state transition: {:?} -> {:?}", state, event),
+        };
+    }
+}
+
+impl StateMachine for Rfq {
+    const TRANSITIONS: &'static [Transition] = &[
+        Transition { from: "Uninitialized", command: "Request", guard: None, events: &["Requested"], to: "Requested" },
+        Transition { from: "Requested", command: "Quote", guard: None, events: &["Quoted"], to: "Requested" },
+        Transition { from: "Requested", command: "Accept", guard: None, events: &["Accepted"], to: "Accepted" },
+        Transition { from: "Requested", command: "Cancel", guard: None, events: &["Cancelled"], to: "Cancelled" },
+        Transition { from: "Accepted", command: "Continue", guard: Some("both legs locked"), events: &["Locked"], to: "Locked" },
+        Transition { from: "Accepted", command: "Continue", guard: Some("a lock failed"), events: &["Failed"], to: "Failed" },
+        Transition { from: "Locked", command: "Continue", guard: Some("both legs settled"), events: &["Settled"], to: "Settled" },
+        Transition { from: "Locked", command: "Continue", guard: Some("the taker leg failed"), events: &["Failed"], to: "Failed" },
+    ];
+}
+
+#[cfg(test)]
+mod aggregate_tests {
+    use std::sync::{Arc, Mutex};
+    use async_trait::async_trait;
+    use cqrs_es::test::TestFramework;
+    use cqrs_es::AggregateError;
+    use crate::util::test_db::test_pool;
+
+    use crate::account::commands::{AccountCommand, TransactionCommand};
+    use crate::account::events::AccountError;
+    use crate::order::aggregate::OrderServices;
+    use crate::rfq::aggregate::{Rfq, RfqServices};
+    use crate::rfq::commands::RfqCommand;
+    use crate::rfq::events::{Quote, RfqConfig, RfqEvent};
+    use crate::services::{AccountExecutor, BankAccountServices, HappyPathBankAccountServices};
+    use crate::util::clock::FixedClock;
+    use crate::util::types::ByteArray32;
+
+    // The request/quote/accept steps never touch the account service, so a
+    // lazily connected pool is enough to build the services.
+    fn services() -> RfqServices {
+        services_at(120)
+    }
+
+    fn services_at(now: u64) -> RfqServices {
+        let pool = test_pool();
+        let account_cqrs = crate::sealing::sealed_snapshot_cqrs(
+            crate::commit_hooks::HookedEventRepository::new(pool, vec![]),
+            vec![],
+            100,
+            BankAccountServices::new(Box::new(HappyPathBankAccountServices)),
+            None,
+            crate::aggregate_cache::AggregateCache::new(&Default::default(), Default::default()),
+            &Default::default(),
+        );
+        RfqServices::new(OrderServices::new(Arc::new(account_cqrs)).with_clock(Arc::new(FixedClock::new(now))))
+    }
+
+    fn config() -> RfqConfig {
+        RfqConfig {
+            rfq_id: ByteArray32([7; 32]),
+            taker: "ACCT-0001".to_string(),
+            sell_asset: "BTC".to_string(),
+            sell_amount: 10,
+            buy_asset: "ETH".to_string(),
+            timestamp: 100,
+            expires_at: 200,
+        }
+    }
+
+    fn quote(quote_id: &str, maker: &str) -> Quote {
+        Quote {
+            quote_id: quote_id.to_string(),
+            maker: maker.to_string(),
+            buy_amount: 150,
+            timestamp: 110,
+            expires_at: 150,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quote_open_rfq() {
+        TestFramework::<Rfq>::with(services())
+            .given(vec![RfqEvent::Requested { config: config() }])
+            .when_async(RfqCommand::Quote { quote: quote("q1", "ACCT-0002") })
+            .await
+            .then_expect_events(vec![RfqEvent::Quoted { quote: quote("q1", "ACCT-0002") }]);
+    }
+
+    #[tokio::test]
+    async fn test_taker_cannot_quote() {
+        TestFramework::<Rfq>::with(services())
+            .given(vec![RfqEvent::Requested { config: config() }])
+            .when_async(RfqCommand::Quote { quote: quote("q1", "ACCT-0001") })
+            .await
+            .then_expect_error_message("Taker cannot quote its own request");
+    }
+
+    #[tokio::test]
+    async fn test_accept_quote() {
+        TestFramework::<Rfq>::with(services())
+            .given(vec![
+                RfqEvent::Requested { config: config() },
+                RfqEvent::Quoted { quote: quote("q1", "ACCT-0002") },
+            ])
+            .when_async(RfqCommand::Accept { quote_id: "q1".to_string() })
+            .await
+            .then_expect_events(vec![RfqEvent::Accepted { quote_id: "q1".to_string(), timestamp: 120 }]);
+    }
+
+    #[tokio::test]
+    async fn test_accept_expired_quote() {
+        TestFramework::<Rfq>::with(services_at(151))
+            .given(vec![
+                RfqEvent::Requested { config: config() },
+                RfqEvent::Quoted { quote: quote("q1", "ACCT-0002") },
+            ])
+            .when_async(RfqCommand::Accept { quote_id: "q1".to_string() })
+            .await
+            .then_expect_error_message("Quote q1 expired at 150");
+    }
+
+    #[tokio::test]
+    async fn test_accept_on_expired_rfq() {
+        let lasting = Quote { expires_at: 300, ..quote("q1", "ACCT-0002") };
+        TestFramework::<Rfq>::with(services_at(201))
+            .given(vec![
+                RfqEvent::Requested { config: config() },
+                RfqEvent::Quoted { quote: lasting },
+            ])
+            .when_async(RfqCommand::Accept { quote_id: "q1".to_string() })
+            .await
+            .then_expect_error_message("RFQ expired at 200");
+        TestFramework::<Rfq>::with(services_at(201))
+            .given(vec![RfqEvent::Requested { config: config() }])
+            .when_async(RfqCommand::Quote { quote: quote("q1", "ACCT-0002") })
+            .await
+            .then_expect_error_message("RFQ expired at 200");
+    }
+
+    // Settles the maker and turns the taker away, recording the commands run.
+    #[derive(Default)]
+    struct Accounts {
+        executed: Mutex<Vec<(String, &'static str)>>,
+    }
+
+    #[async_trait]
+    impl AccountExecutor for Accounts {
+        async fn execute(&self, account_id: &str, command: AccountCommand) -> Result<(), AggregateError<AccountError>> {
+            let AccountCommand::Transaction { command, .. } = command else {
+                panic!("an RFQ only runs transactions");
+            };
+            let name = match command {
+                TransactionCommand::Settle { .. } if account_id == "ACCT-0001" => return Err(AggregateError::UserError(AccountError::AccountClosing)),
+                TransactionCommand::Settle { .. } => "Settle",
+                TransactionCommand::ReverseCredit { .. } => "ReverseCredit",
+                TransactionCommand::ReverseDebit { .. } => "ReverseDebit",
+                TransactionCommand::UnlockFunds => "UnlockFunds",
+                _ => "Other",
+            };
+            self.executed.lock().unwrap().push((account_id.to_string(), name));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_taker_leg_reverses_maker_leg() {
+        let accounts = Arc::new(Accounts::default());
+        let services = RfqServices::new(OrderServices::new(accounts.clone()).with_clock(Arc::new(FixedClock::new(130))));
+        TestFramework::<Rfq>::with(services)
+            .given(vec![
+                RfqEvent::Requested { config: config() },
+                RfqEvent::Quoted { quote: quote("q1", "ACCT-0002") },
+                RfqEvent::Accepted { quote_id: "q1".to_string(), timestamp: 120 },
+                RfqEvent::Locked { timestamp: 125 },
+            ])
+            .when_async(RfqCommand::Continue)
+            .await
+            .then_expect_events(vec![RfqEvent::Failed { timestamp: 130, reason: "Failed to settle taker: AccountClosing".to_string() }]);
+        assert_eq!(*accounts.executed.lock().unwrap(), vec![
+            ("ACCT-0002".to_string(), "Settle"),
+            ("ACCT-0002".to_string(), "ReverseCredit"),
+            ("ACCT-0002".to_string(), "ReverseDebit"),
+            ("ACCT-0001".to_string(), "UnlockFunds"),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_quote_after_accept_rejected() {
+        let result = TestFramework::<Rfq>::with(services())
+            .given(vec![
+                RfqEvent::Requested { config: config() },
+                RfqEvent::Quoted { quote: quote("q1", "ACCT-0002") },
+                RfqEvent::Accepted { quote_id: "q1".to_string(), timestamp: 120 },
+            ])
+            .when_async(RfqCommand::Quote { quote: quote("q2", "ACCT-0003") })
+            .await
+            .inspect_result();
+        assert!(matches!(result, Err(super::RfqError::InvalidState(_))));
+    }
+}
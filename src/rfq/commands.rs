@@ -0,0 +1,20 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::rfq::events::{Quote, RfqConfig};
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub enum RfqCommand {
+    Request {
+        config: RfqConfig,
+    },
+    Quote {
+        quote: Quote,
+    },
+    Accept {
+        quote_id: String,
+    },
+    Cancel {
+        reason: String,
+    },
+    Continue,
+}
@@ -0,0 +1,70 @@
+use cqrs_es::DomainEvent;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::util::types::ByteArray32;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default, ToSchema)]
+pub struct RfqConfig {
+    pub rfq_id: ByteArray32,
+    pub taker: String,
+    pub sell_asset: String,
+    pub sell_amount: u64,
+    pub buy_asset: String,
+    pub timestamp: u64,
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default, ToSchema)]
+pub struct Quote {
+    pub quote_id: String,
+    pub maker: String,
+    pub buy_amount: u64,
+    pub timestamp: u64,
+    pub expires_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum RfqEvent {
+    Requested {
+        config: RfqConfig,
+    },
+    Quoted {
+        quote: Quote,
+    },
+    Accepted {
+        quote_id: String,
+        timestamp: u64,
+    },
+    Locked {
+        timestamp: u64,
+    },
+    Cancelled {
+        timestamp: u64,
+        reason: String,
+    },
+    Failed {
+        timestamp: u64,
+        reason: String,
+    },
+    Settled {
+        timestamp: u64,
+    },
+}
+
+impl DomainEvent for RfqEvent {
+    fn event_type(&self) -> String {
+        match self {
+            RfqEvent::Requested { .. } => "Requested".to_string(),
+            RfqEvent::Quoted { .. } => "Quoted".to_string(),
+            RfqEvent::Accepted { .. } => "Accepted".to_string(),
+            RfqEvent::Locked { .. } => "Locked".to_string(),
+            RfqEvent::Cancelled { .. } => "Cancelled".to_string(),
+            RfqEvent::Failed { .. } => "Failed".to_string(),
+            RfqEvent::Settled { .. } => "Settled".to_string(),
+        }
+    }
+
+    fn event_version(&self) -> String {
+        "1.0".to_string()
+    }
+}
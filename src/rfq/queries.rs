@@ -0,0 +1,113 @@
+use async_trait::async_trait;
+use cqrs_es::{EventEnvelope, Query, View};
+use cqrs_es::persist::GenericQuery;
+use postgres_es::PostgresViewRepository;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use sqlx::{Pool, Postgres, Row};
+use crate::rfq::aggregate::Rfq;
+use crate::rfq::events::{Quote, RfqEvent};
+
+pub struct SimpleLoggingQuery {}
+
+#[derive(Debug, Serialize, Deserialize, Default, ToSchema)]
+pub enum RfqState {
+    #[default]
+    Open,
+    Accepted,
+    Locked,
+    Cancelled,
+    Failed,
+    Settled,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, ToSchema)]
+pub struct RfqView {
+    pub id: String,
+    pub taker: String,
+    pub sell_asset: String,
+    pub sell_amount: u64,
+    pub buy_asset: String,
+    pub quotes: Vec<Quote>,
+    pub accepted_quote: Option<String>,
+    pub status: RfqState,
+    pub reason: Option<String>,
+    pub create_time: u64,
+    pub update_time: u64,
+    pub expires_at: u64,
+}
+
+#[async_trait]
+impl Query<Rfq> for SimpleLoggingQuery {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<Rfq>]) {
+        for event in events {
+            let payload = serde_json::to_string_pretty(&event.payload).unwrap();
+            tracing::debug!("{}-{}\n{}", aggregate_id, event.sequence, payload);
+        }
+    }
+}
+
+pub type RfqQuery = GenericQuery<
+    PostgresViewRepository<RfqView, Rfq>,
+    RfqView,
+    Rfq,
+>;
+
+impl View<Rfq> for RfqView {
+    fn update(&mut self, event: &EventEnvelope<Rfq>) {
+        match &event.payload {
+            RfqEvent::Requested { config } => {
+                self.id = config.rfq_id.hex();
+                self.taker = config.taker.clone();
+                self.sell_asset = config.sell_asset.clone();
+                self.sell_amount = config.sell_amount;
+                self.buy_asset = config.buy_asset.clone();
+                self.status = RfqState::Open;
+                self.create_time = config.timestamp;
+                self.update_time = config.timestamp;
+                self.expires_at = config.expires_at;
+            }
+            RfqEvent::Quoted { quote } => {
+                self.update_time = quote.timestamp;
+                self.quotes.push(quote.clone());
+            }
+            RfqEvent::Accepted { quote_id, timestamp } => {
+                self.update_time = *timestamp;
+                self.accepted_quote = Some(quote_id.clone());
+                self.status = RfqState::Accepted;
+            }
+            RfqEvent::Locked { timestamp } => {
+                self.update_time = *timestamp;
+                self.status = RfqState::Locked;
+            }
+            RfqEvent::Cancelled { timestamp, reason } => {
+                self.update_time = *timestamp;
+                self.reason = Some(reason.clone());
+                self.status = RfqState::Cancelled;
+            }
+            RfqEvent::Failed { timestamp, reason } => {
+                self.update_time = *timestamp;
+                self.reason = Some(reason.clone());
+                self.status = RfqState::Failed;
+            }
+            RfqEvent::Settled { timestamp } => {
+                self.update_time = *timestamp;
+                self.status = RfqState::Settled;
+            }
+        }
+    }
+}
+
+// Lists the RFQs still accepting quotes, straight from the `rfq_query` view table
+// so makers can discover what to quote on.
+pub async fn open_rfqs(pool: &Pool<Postgres>) -> Result<Vec<RfqView>, sqlx::Error> {
+    let rows = sqlx::query("SELECT payload FROM rfq_query WHERE payload->>'status' = 'Open'")
+        .fetch_all(pool)
+        .await?;
+    let mut views = Vec::with_capacity(rows.len());
+    for row in rows {
+        let payload: serde_json::Value = row.try_get("payload")?;
+        views.push(serde_json::from_value(payload).map_err(|e| sqlx::Error::Decode(Box::new(e)))?);
+    }
+    Ok(views)
+}
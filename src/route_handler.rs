@@ -1,118 +1,3400 @@
-use crate::command_extractor::CommandExtractor;
+use crate::auth::Principal;
+use crate::command_extractor::{CommandExtractor, MetadataExtractor};
+use crate::config::ConflictRetryConfig;
+use crate::consistency::{committed_sequence, etag, load_consistent, ConsistencyError, ConsistencyParams, IfMatch, EXPECTED_SEQUENCE_KEY, SEQUENCE_HDR};
 use crate::state::ApplicationState;
-use axum::extract::{Path, State};
-use axum::http::StatusCode;
-use axum::response::{IntoResponse, Response};
-use axum::Json;
+use axum::extract::{Path, Query, State};
+use axum::body::{Body, Bytes};
+use axum::http::{header, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{AppendHeaders, IntoResponse, Response};
+use axum::{Extension, Json};
+use futures::TryStreamExt;
+use prometheus::{Encoder, TextEncoder};
+use serde::Deserialize;
+use utoipa::IntoParams;
 use cqrs_es::persist::ViewRepository;
-use crate::account::commands::AccountCommand;
+use cqrs_es::AggregateError;
+use tokio::sync::broadcast::error::RecvError;
+use crate::account::aggregate::Account;
+use crate::account::batch::{execute_batch, BatchOutcome, CommandOutcome};
+use crate::account::bulk::{BulkError, BulkJob, BulkRequest};
+use crate::account::accruals::{preview_accruals, AccrualParams, AccrualPreview};
+use crate::account::coverage::{check_coverage, Coverage, CoverageParams};
+use crate::account::commands::{AccountCommand, EarmarkRequest, LifecycleCommand, TransactionCommand};
+use crate::account::events::TransactionLabels;
+use crate::account::journal::{trial_balance, TrialBalance};
+use crate::business_day::{BusinessDay, BusinessDayReport, DayCheckpoint};
+use crate::account::ofx::{export_ofx, OfxParams};
+use crate::account::overdraft::OverdraftLimit;
+use crate::account::ledger::{export_statement, load_statement, search_ledger, ExportParams, LedgerError, SearchPage, SearchParams, StatementFilter, StatementPage};
+use crate::account::queries::AccountView;
+use crate::account::stats::AccountStats;
+use crate::audit::AuditReport;
+use crate::inbox::{InboxEntry, InboxEntryStatus, InboxError};
+use crate::asset::aggregate::Asset;
+use crate::asset::commands::AssetCommand;
+use crate::asset::display::{with_assets, WithAssets};
+use crate::dlq::{DeadLetter, DeadLetterError, DeadLetterFilter};
+use crate::asset::migration::{resolve, MigrationError, MigrationProgress, MigrationRequest};
+use crate::asset::queries::AssetView;
+use crate::auction::aggregate::Auction;
+use crate::auction::commands::AuctionCommand;
+use crate::auction::queries::AuctionView;
+use crate::standing_order::aggregate::StandingOrder;
+use crate::standing_order::commands::StandingOrderCommand;
+use crate::standing_order::queries::StandingOrderView;
+use crate::batch_transfer::aggregate::BatchTransfer;
+use crate::batch_transfer::commands::BatchTransferCommand;
+use crate::batch_transfer::queries::BatchTransferView;
+use crate::bulkhead::Bulkhead;
+use crate::order::book::{load_order_book, OrderBook, OrderBookError, Pair};
+use crate::order::aggregate::Order;
 use crate::order::commands::OrderCommand;
-use crate::transfer::commands::TransferCommand;
+use crate::order::halts::{HaltError, HaltRequest, TradingHalt};
+use crate::order::matching::MatchingStats;
+use crate::order::queries::OrderView;
+use crate::order::integrity::{IntegrityError, IntegrityIssue, IntegrityReport};
+use crate::order::recovery::{RecoveredLock, RecoveryReport};
+use crate::view_audit::ViewAuditReport;
+use crate::watchdog::{StalledSaga, WatchdogReport};
+use crate::audit_log::{AuditEntry, AuditLogFilter};
+use crate::approval::aggregate::{Approval, ApprovalError};
+use crate::approval::commands::{ApprovalCommand, ConfigChange, ProposalResponse, RejectionRequest};
+use crate::approval::queries::{pending_approvals, ApprovalView};
+use crate::preferences::aggregate::Preferences;
+use crate::preferences::commands::PreferencesCommand;
+use crate::preferences::queries::PreferencesView;
+use crate::notification::check_public_url;
+use crate::payout::{check_clawbacks, execute_payout, load_payout_report, parse_payout_csv, payout_report_csv, save_payout_report};
+use crate::rates::{load_rate_history, RateError, RateFilter, RateHistory};
+use crate::replay::{list_replays, pause, ReplayProgress};
+use crate::retry::retry_conflicts;
+use crate::rfq::aggregate::Rfq;
+use crate::rfq::commands::RfqCommand;
+use crate::rfq::queries::RfqView;
+use crate::sandbox::{save_clock, wipe, AdvanceClockRequest, ClockResponse, FaucetRequest, FaucetResponse, WipeReport};
+use crate::signals::{SignalFilter, SignalReport};
+use crate::quota::{AccountQuotaReport, QuotaReport};
+use crate::routing::RoutingReport;
+use crate::rfq::queries::open_rfqs;
+use crate::statemachine::{render, transitions_of, GraphFormat};
+use crate::transfer::aggregate::{Transfer, TransferError};
+use crate::transfer::commands::{AccountTransferRequest, OpenTransferRequest, OpenTransferResponse, RefundTransferRequest, RefundTransferResponse, TransferCommand};
+use crate::transfer::queries::TransferView;
+use crate::txid_registry::TxidRegistryError;
+use crate::util::clock;
+use crate::util::metadata::INITIATOR_KEY;
+use crate::util::types::ByteArray32;
+use crate::metrics::ErrorStats;
+use crate::webhooks::{Subscription, WebhookError};
+use std::collections::HashMap;
+
+// Pending approvals listed at once.
+const MAX_PENDING_APPROVALS: i64 = 500;
 
 // Serves as our query endpoint to respond with the materialized `BankAccountView`
 // for the requested account.
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}",
+    tag = "account",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+        ConsistencyParams,
+    ),
+    responses(
+        (status = 200, body = WithAssets<AccountView>, headers(("ETag" = String, description = "Sequence of the view, for If-Match"))),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+        (status = 504, description = "The view did not reach min_sequence in time", body = String),
+    ),
+)]
 pub async fn account_query_handler(
     Path(account_id): Path<String>,
+    Query(consistency): Query<ConsistencyParams>,
     State(state): State<ApplicationState>,
 ) -> Response {
-    let view = match state.account_query.load(&account_id).await {
+    let view = match load_consistent(&consistency, AccountView::sequence, || state.account_query.load(&account_id)).await {
         Ok(view) => view,
+        Err(err @ ConsistencyError::Timeout { .. }) => return (StatusCode::GATEWAY_TIMEOUT, err.to_string()).into_response(),
         Err(err) => {
             tracing::error!("Error: {:#?}\n", err);
             return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
         }
     };
-    match view {
-        None => StatusCode::NOT_FOUND.into_response(),
-        Some(account_view) => (StatusCode::OK, Json(account_view)).into_response(),
+    let Some(account_view) = view else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let sequence = account_view.sequence();
+    match with_assets(state.asset_query.as_ref(), account_view).await {
+        Ok(response) => (StatusCode::OK, [(header::ETAG, etag(sequence))], Json(response)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Lifetime totals of the account, see `AccountStats`.
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/stats",
+    tag = "account",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+    ),
+    responses(
+        (status = 200, body = AccountStats),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn account_stats_handler(
+    Path(account_id): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    match state.account_stats.load(&account_id).await {
+        Ok(Some(stats)) => (StatusCode::OK, Json(stats)).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Whether the account can cover an amount, for risk checks that cannot afford loading the
+// whole view: answered from the aggregate cache when it is recent enough, see `Coverage`
+// for how stale the answer may be.
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/coverage",
+    tag = "account",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+        CoverageParams,
+    ),
+    responses(
+        (status = 200, body = Coverage),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn account_coverage_handler(
+    Path(account_id): Path<String>,
+    Query(params): Query<CoverageParams>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    match check_coverage(&state.account_cache, &*state.account_query, &account_id, &params).await {
+        Ok(Some(coverage)) => (StatusCode::OK, Json(coverage)).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// What the account would accrue in interest and fees until a date at its current balances,
+// nothing is posted.
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/accruals/preview",
+    tag = "account",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+        AccrualParams,
+    ),
+    responses(
+        (status = 200, body = AccrualPreview),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn account_accrual_preview_handler(
+    Path(account_id): Path<String>,
+    Query(params): Query<AccrualParams>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    match state.account_query.load(&account_id).await {
+        Ok(Some(view)) if view.is_open() => {
+            let preview = preview_accruals(&state.accruals, &account_id, &view, clock::now(), params.until);
+            (StatusCode::OK, Json(preview)).into_response()
+        }
+        Ok(_) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Streams the events of an account as Server-Sent Events while the client stays
+// connected. Each message carries the event type as its name and the event
+// sequence as its id.
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/stream",
+    tag = "account",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+    ),
+    responses(
+        (status = 200, description = "Server-Sent Events, one per account event", content_type = "text/event-stream"),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn account_stream_handler(
+    Path(account_id): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    match state.account_query.load(&account_id).await {
+        Ok(Some(_)) => {},
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        }
+    }
+    let receiver = state.account_stream.subscribe(&account_id);
+    let events = futures::stream::unfold(receiver, |mut receiver| async move {
+        let event = match receiver.recv().await {
+            Ok(streamed) => Event::default()
+                .event(streamed.event_type.clone())
+                .id(streamed.sequence.to_string())
+                .json_data(&*streamed),
+            // The client fell behind and the oldest events were dropped; tell it so it
+            // can reload the account instead of silently missing them.
+            Err(RecvError::Lagged(skipped)) => Ok(Event::default().event("Lagged").data(skipped.to_string())),
+            Err(RecvError::Closed) => return None,
+        };
+        Some((event, receiver))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+// Serves the full transaction history of an account, newest first, in pages of
+// `limit` entries. Follow `next_cursor` until it is absent to read the rest.
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/ledger",
+    tag = "account",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+        StatementFilter,
+    ),
+    responses(
+        (status = 200, body = StatementPage),
+        (status = 400, description = "Invalid cursor", body = String),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn account_ledger_handler(
+    Path(account_id): Path<String>,
+    Query(filter): Query<StatementFilter>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    match state.account_query.load(&account_id).await {
+        Ok(Some(_)) => {},
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        }
+    }
+    let filter = match resolve_value_in(&state, filter).await {
+        Ok(filter) => filter,
+        Err(response) => return response,
+    };
+    match load_statement(&state.pool, &state.counterparty_names, &account_id, &filter).await {
+        Ok(page) => (StatusCode::OK, Json(page)).into_response(),
+        Err(err @ LedgerError::InvalidCursor(_)) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Finds the transactions of an account by their memos, see `search_ledger`, newest
+// first in pages of `limit` matches.
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/ledger/search",
+    tag = "account",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+        SearchParams,
+    ),
+    responses(
+        (status = 200, body = SearchPage),
+        (status = 400, description = "Invalid cursor or empty search", body = String),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn account_ledger_search_handler(
+    Path(account_id): Path<String>,
+    Query(params): Query<SearchParams>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    match state.account_query.load(&account_id).await {
+        Ok(Some(_)) => {},
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        }
+    }
+    match search_ledger(&state.pool, &state.counterparty_names, &account_id, &params).await {
+        Ok(page) => (StatusCode::OK, Json(page)).into_response(),
+        Err(err @ (LedgerError::InvalidCursor(_) | LedgerError::InvalidSearch(_))) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Exports the full transaction history of an account as CSV, newest first. The body is
+// streamed page by page as it is read, so exports of any length use bounded memory.
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/ledger.csv",
+    tag = "account",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+        StatementFilter,
+        ExportParams,
+    ),
+    responses(
+        (status = 200, description = "CSV statement, newest first", body = String, content_type = "text/csv"),
+        (status = 400, description = "Invalid cursor or unknown column", body = String),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn account_ledger_export_handler(
+    Path(account_id): Path<String>,
+    Query(filter): Query<StatementFilter>,
+    Query(params): Query<ExportParams>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    match state.account_query.load(&account_id).await {
+        Ok(Some(_)) => {},
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        }
+    }
+    let filter = match resolve_value_in(&state, filter).await {
+        Ok(filter) => filter,
+        Err(response) => return response,
+    };
+    let disposition = format!("attachment; filename=\"{}-ledger.csv\"", account_id);
+    let chunks = match export_statement(state.pool.clone(), state.counterparty_names.clone(), account_id.clone(), filter, params.columns.as_deref()) {
+        Ok(chunks) => chunks,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+    // Headers are gone by the time a later page fails, the client sees the body cut short.
+    let chunks = chunks.inspect_err(move |err| tracing::error!("Export of the ledger of {} failed: {}", account_id, err));
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()), (header::CONTENT_DISPOSITION, disposition)],
+        Body::from_stream(chunks),
+    )
+        .into_response()
+}
+
+// Valuations in a migrated asset are made in the asset it became, which carries its rates on.
+async fn resolve_value_in(state: &ApplicationState, mut filter: StatementFilter) -> Result<StatementFilter, Response> {
+    if let Some(value_in) = &filter.value_in {
+        filter.value_in = Some(resolve(&state.asset_query, value_in).await.map_err(migration_error_response)?);
+    }
+    Ok(filter)
+}
+
+// Exports the history of an account in one asset as an OFX bank statement, for
+// accounting software. Streamed like the CSV export.
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/ledger.ofx",
+    tag = "account",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+        StatementFilter,
+        OfxParams,
+    ),
+    responses(
+        (status = 200, description = "OFX 2.2 statement, newest first", body = String, content_type = "application/x-ofx"),
+        (status = 400, description = "Invalid cursor or unknown asset", body = String),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn account_ledger_ofx_handler(
+    Path(account_id): Path<String>,
+    Query(filter): Query<StatementFilter>,
+    Query(params): Query<OfxParams>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    match state.account_query.load(&account_id).await {
+        Ok(Some(_)) => {},
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        }
     }
+    // A migrated asset is exported as the asset it became, conversion included.
+    let asset = match resolve(&state.asset_query, &params.asset).await {
+        Ok(asset) => asset,
+        Err(err) => return migration_error_response(err),
+    };
+    let decimals = match state.asset_query.load(&asset).await {
+        Ok(Some(asset)) => asset.decimals,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Unknown asset: {}", asset)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        }
+    };
+    let disposition = format!("attachment; filename=\"{}-{}-ledger.ofx\"", account_id, asset);
+    let chunks = match export_ofx(state.pool.clone(), state.counterparty_names.clone(), account_id.clone(), filter, asset, decimals) {
+        Ok(chunks) => chunks,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+    let chunks = chunks.inspect_err(move |err| tracing::error!("Export of the ledger of {} failed: {}", account_id, err));
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/x-ofx".to_string()), (header::CONTENT_DISPOSITION, disposition)],
+        Body::from_stream(chunks),
+    )
+        .into_response()
 }
 
 // Serves as our command endpoint to make changes in a `BankAccount` aggregate.
+// Lifecycle commands are only accepted by `account_lifecycle_handler`.
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}",
+    tag = "account",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+        ("If-Match" = Option<String>, Header, description = "Only apply the command at this sequence, e.g. `\"42\"`"),
+    ),
+    request_body = AccountCommand,
+    responses(
+        (status = 204, description = "Command accepted", headers(("X-Sequence" = usize, description = "Sequence the account reached, for min_sequence"))),
+        (status = 400, description = "Command rejected, or beyond the limits of the command policy", body = String),
+        (status = 403, description = "Lifecycle commands, quarantine releases, earmarks and value dated commands go to the admin routes, batches to /account/{account_id}/commands", body = String),
+        (status = 409, description = "Txid already used", body = String),
+        (status = 412, description = "The account is past the sequence of If-Match", body = String, headers(("ETag" = String, description = "Sequence the account is at"))),
+        (status = 429, description = "Too many commands to the aggregate type, see the bulkheads", body = String),
+    ),
+)]
 pub async fn account_command_handler(
     Path(account_id): Path<String>,
     State(state): State<ApplicationState>,
-    CommandExtractor(metadata, command): CommandExtractor<AccountCommand>,
+    _bulkhead: Bulkhead<Account>,
+    IfMatch(expected): IfMatch,
+    CommandExtractor(mut metadata, command): CommandExtractor<AccountCommand>,
 ) -> Response {
-    match state
-        .account_cqrs
-        .execute_with_metadata(&account_id, command, metadata)
-        .await
-    {
-        Ok(_) => StatusCode::NO_CONTENT.into_response(),
-        Err(err) =>  {
+    if let Err(rejection) = admit_account_command(&account_id, &command) {
+        return rejection.into_response();
+    }
+    if let Some(expected) = expected {
+        if let Err(response) = check_if_match(&state, &account_id, expected).await {
+            return response;
+        }
+        metadata.insert(EXPECTED_SEQUENCE_KEY.to_string(), expected.to_string());
+    }
+    if let AccountCommand::Transaction { txid, .. } = &command {
+        if let Err(response) = claim_txid(&state, txid, &format!("account:{}", account_id)).await {
+            return response;
+        }
+    }
+    // Losing to a command since the check above is final under If-Match.
+    let retry = match expected {
+        Some(_) => ConflictRetryConfig { attempts: 1, ..state.conflict_retry.clone() },
+        None => state.conflict_retry.clone(),
+    };
+    let execute = || state.account_cqrs.execute_with_metadata(&account_id, command.clone(), metadata.clone());
+    let executed = retry_conflicts(&retry, execute).await;
+    match (executed, expected) {
+        (Ok(_), _) => (StatusCode::NO_CONTENT, sequence_header(&state, &account_id).await).into_response(),
+        // Lost to a command committed since the check above.
+        (Err(AggregateError::AggregateConflict), Some(expected)) => match check_if_match(&state, &account_id, expected).await {
+            Err(response) => response,
+            Ok(()) => (StatusCode::PRECONDITION_FAILED, format!("Account {} moved past sequence {}", account_id, expected)).into_response(),
+        },
+        (Err(err), _) =>  {
+            state.error_metrics.record::<Account>(&err);
+            state.signals.rejected(&account_id, &err).await;
             tracing::error!("Error: {:#?}\n", err);
             (StatusCode::BAD_REQUEST, err.to_string()).into_response()
         },
     }
 }
 
-pub async fn transfer_query_handler(
-    Path(transfer_id): Path<String>,
+// What an account command sent to `/account/{account_id}` is checked for before it runs,
+// also when it waits in the command inbox, see `crate::inbox`.
+pub(crate) fn admit_account_command(account_id: &str, command: &AccountCommand) -> Result<(), (StatusCode, String)> {
+    if let AccountCommand::Lifecycle(_) = command {
+        let message = format!("Lifecycle commands must be sent to /admin/account/{}", account_id);
+        return Err((StatusCode::FORBIDDEN, message));
+    }
+    if let AccountCommand::Batch(_) = command {
+        let message = format!("Batches must be sent to /account/{}/commands", account_id);
+        return Err((StatusCode::FORBIDDEN, message));
+    }
+    if let AccountCommand::Transaction { txid, command: TransactionCommand::ReleaseQuarantine, .. } = command {
+        let message = format!("Quarantined deposits must be released at /admin/account/{}/quarantine/{}", account_id, txid.hex());
+        return Err((StatusCode::FORBIDDEN, message));
+    }
+    if let AccountCommand::Transaction { command: TransactionCommand::Earmark { earmark, .. } | TransactionCommand::ReleaseEarmark { earmark }, .. } = command {
+        let message = format!("Earmarks are managed at /admin/account/{}/earmark/{}", account_id, earmark);
+        return Err((StatusCode::FORBIDDEN, message));
+    }
+    if let AccountCommand::Transaction { command: TransactionCommand::ConvertAsset { .. }, .. } = command {
+        // Only the migration converts balances, at the ratio recorded on the asset.
+        return Err((StatusCode::FORBIDDEN, "Balances are converted by asset migrations under /admin/assets/migrations".to_string()));
+    }
+    if command.value_date().is_some() {
+        let message = format!("Value dated commands must be sent to /admin/account/{}/value-dated", account_id);
+        return Err((StatusCode::FORBIDDEN, message));
+    }
+    Ok(())
+}
+
+// Runs a list of commands against one account in order, loading it once, for imports of
+// historical transactions. Commands are rejected on their own and reported by index,
+// the others are applied regardless.
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/commands",
+    tag = "account",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+        ("If-Match" = Option<String>, Header, description = "Only apply the batch at this sequence, e.g. `\"42\"`; commands then fail as a conflict when another one came in between"),
+    ),
+    request_body = Vec<AccountCommand>,
+    responses(
+        (status = 200, description = "Outcome of every command", body = BatchOutcome, headers(("X-Sequence" = usize, description = "Sequence the account reached, for min_sequence"))),
+        (status = 400, description = "More commands than a batch may carry", body = String),
+        (status = 412, description = "The account is past the sequence of If-Match", body = String, headers(("ETag" = String, description = "Sequence the account is at"))),
+        (status = 429, description = "Too many commands to the aggregate type, see the bulkheads", body = String),
+    ),
+)]
+pub async fn account_batch_handler(
+    Path(account_id): Path<String>,
     State(state): State<ApplicationState>,
+    _bulkhead: Bulkhead<Account>,
+    IfMatch(expected): IfMatch,
+    CommandExtractor(mut metadata, commands): CommandExtractor<Vec<AccountCommand>>,
 ) -> Response {
-    let view = match state.transfer_query.load(&transfer_id).await {
-        Ok(view) => view,
+    if let Some(expected) = expected {
+        if let Err(response) = check_if_match(&state, &account_id, expected).await {
+            return response;
+        }
+        metadata.insert(EXPECTED_SEQUENCE_KEY.to_string(), expected.to_string());
+    }
+    let owner = format!("account:{}", account_id);
+    let mut outcomes = vec![];
+    let mut accepted = Vec::with_capacity(commands.len());
+    for (index, command) in commands.into_iter().enumerate() {
+        // Same checks as `account_command_handler`.
+        match &command {
+            AccountCommand::Lifecycle(_) => {
+                outcomes.push(CommandOutcome::rejected(index, format!("Lifecycle commands must be sent to /admin/account/{}", account_id)));
+                continue;
+            }
+            AccountCommand::Batch(_) => {
+                outcomes.push(CommandOutcome::rejected(index, "Batches cannot be nested"));
+                continue;
+            }
+            AccountCommand::Transaction { txid, command: TransactionCommand::ReleaseQuarantine, .. } => {
+                outcomes.push(CommandOutcome::rejected(index, format!("Quarantined deposits must be released at /admin/account/{}/quarantine/{}", account_id, txid.hex())));
+                continue;
+            }
+            AccountCommand::Transaction { command: TransactionCommand::Earmark { earmark, .. } | TransactionCommand::ReleaseEarmark { earmark }, .. } => {
+                outcomes.push(CommandOutcome::rejected(index, format!("Earmarks are managed at /admin/account/{}/earmark/{}", account_id, earmark)));
+                continue;
+            }
+            AccountCommand::Transaction { command: TransactionCommand::ConvertAsset { .. }, .. } => {
+                outcomes.push(CommandOutcome::rejected(index, "Balances are converted by asset migrations under /admin/assets/migrations"));
+                continue;
+            }
+            AccountCommand::Transaction { .. } if command.value_date().is_some() => {
+                outcomes.push(CommandOutcome::rejected(index, format!("Value dated commands must be sent to /admin/account/{}/value-dated", account_id)));
+                continue;
+            }
+            AccountCommand::Transaction { txid, .. } => {
+                if let Err(err) = state.txid_registry.claim(txid, &owner).await {
+                    if !matches!(err, TxidRegistryError::Conflict(..)) {
+                        tracing::error!("Error: {:#?}\n", err);
+                    }
+                    outcomes.push(CommandOutcome::rejected(index, err));
+                    continue;
+                }
+            }
+        }
+        accepted.push((index, command));
+    }
+    outcomes.extend(execute_batch(&state.account_cqrs, &state.error_metrics, &state.signals, &account_id, accepted, metadata).await);
+    (StatusCode::OK, sequence_header(&state, &account_id).await, Json(BatchOutcome::new(outcomes))).into_response()
+}
+
+// Fails with 412 and the sequence the account is at, to try again from, when it is not
+// at `expected`. The commit checks again, see `crate::consistency::IfMatch`; this spares
+// the command the side effects it would have before, e.g. claiming its txid.
+async fn check_if_match(state: &ApplicationState, account_id: &str, expected: usize) -> Result<(), Response> {
+    match committed_sequence::<Account>(&state.pool, account_id).await {
+        Ok(sequence) if sequence == expected => Ok(()),
+        Ok(sequence) => {
+            let message = format!("Account {} is at sequence {}, not {}", account_id, sequence, expected);
+            Err((StatusCode::PRECONDITION_FAILED, [(header::ETAG, etag(sequence))], message).into_response())
+        }
         Err(err) => {
             tracing::error!("Error: {:#?}\n", err);
-            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+            Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())
         }
-    };
-    match view {
-        None => StatusCode::NOT_FOUND.into_response(),
-        Some(transfer_view) => (StatusCode::OK, Json(transfer_view)).into_response(),
     }
 }
 
-pub async fn transfer_command_handler(
-    Path(transfer_id): Path<String>,
+// The sequence the account reached, for the caller to read its own writes, see
+// `crate::consistency`. Left out when it cannot be read; the command went through.
+async fn sequence_header(state: &ApplicationState, account_id: &str) -> AppendHeaders<Option<(&'static str, String)>> {
+    match committed_sequence::<Account>(&state.pool, account_id).await {
+        Ok(sequence) => AppendHeaders(Some((SEQUENCE_HDR, sequence.to_string()))),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            AppendHeaders(None)
+        }
+    }
+}
+
+// Opens, disables, enables and closes accounts, behind the admin authorization.
+#[utoipa::path(
+    post,
+    path = "/admin/account/{account_id}",
+    tag = "admin",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+    ),
+    request_body = LifecycleCommand,
+    responses(
+        (status = 204, description = "Command accepted", headers(("X-Sequence" = usize, description = "Sequence the account reached, for min_sequence"))),
+        (status = 400, description = "Command rejected", body = String),
+        (status = 403, description = "Needs approval, see /admin/approval", body = String),
+        (status = 429, description = "Too many commands to the aggregate type, see the bulkheads", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn account_lifecycle_handler(
+    Path(account_id): Path<String>,
     State(state): State<ApplicationState>,
-    CommandExtractor(metadata, command): CommandExtractor<TransferCommand>,
+    _bulkhead: Bulkhead<Account>,
+    CommandExtractor(metadata, command): CommandExtractor<LifecycleCommand>,
 ) -> Response {
-    match state
-        .transfer_cqrs
-        .execute_with_metadata(&transfer_id, command, metadata)
-        .await
-    {
-        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+    if state.approvals.required && !matches!(command, LifecycleCommand::Open { .. }) {
+        return needs_approval();
+    }
+    let execute = || state.account_cqrs.execute_with_metadata(&account_id, AccountCommand::Lifecycle(command.clone()), metadata.clone());
+    match retry_conflicts(&state.conflict_retry, execute).await {
+        Ok(_) => (StatusCode::NO_CONTENT, sequence_header(&state, &account_id).await).into_response(),
         Err(err) => {
+            state.error_metrics.record::<Account>(&err);
+            state.signals.rejected(&account_id, &err).await;
             tracing::error!("Error: {:#?}\n", err);
             (StatusCode::BAD_REQUEST, err.to_string()).into_response()
         },
     }
 }
 
-pub async fn order_query_handler(
-    Path(order_id): Path<String>,
+// Credit limits by asset of the account, absent assets cannot be overdrawn.
+#[utoipa::path(
+    get,
+    path = "/admin/account/{account_id}/overdraft",
+    tag = "admin",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+    ),
+    responses(
+        (status = 200, body = BTreeMap<String, u64>),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn overdraft_limits_handler(
+    Path(account_id): Path<String>,
     State(state): State<ApplicationState>,
 ) -> Response {
-    let view = match state.order_query.load(&order_id).await {
-        Ok(view) => view,
+    match state.overdraft_limits.limits(&account_id).await {
+        Ok(limits) => (StatusCode::OK, Json(limits)).into_response(),
         Err(err) => {
             tracing::error!("Error: {:#?}\n", err);
-            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
         }
-    };
-    match view {
-        None => StatusCode::NOT_FOUND.into_response(),
-        Some(order_view) => (StatusCode::OK, Json(order_view)).into_response(),
     }
 }
 
-pub async fn order_command_handler(
-    Path(order_id): Path<String>,
+// Sets how far the account may overdraw an asset, effective from its next withdrawal or debit.
+#[utoipa::path(
+    put,
+    path = "/admin/account/{account_id}/overdraft",
+    tag = "admin",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+    ),
+    request_body = OverdraftLimit,
+    responses(
+        (status = 204, description = "Limit set"),
+        (status = 403, description = "Needs approval, see /admin/approval", body = String),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn overdraft_limit_handler(
+    Path(account_id): Path<String>,
+    State(state): State<ApplicationState>,
+    Json(limit): Json<OverdraftLimit>,
+) -> Response {
+    if state.approvals.required {
+        return needs_approval();
+    }
+    match state.overdraft_limits.set(&account_id, &limit).await {
+        Ok(()) => {
+            tracing::warn!("Overdraft limit of {} in {} set to {}", account_id, limit.asset, limit.limit);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// The configuration changes taken only as proposals while approvals are required.
+fn needs_approval() -> Response {
+    (StatusCode::FORBIDDEN, "this change needs a second operator, propose it under /admin/approval".to_string()).into_response()
+}
+
+// Proposes a configuration change for another operator to approve, see `Approval`. The
+// proposer is the principal the request was authorized for.
+#[utoipa::path(
+    post,
+    path = "/admin/approval",
+    tag = "admin",
+    request_body = ConfigChange,
+    responses(
+        (status = 200, body = ProposalResponse),
+        (status = 400, description = "Change rejected", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn approval_propose_handler(
+    State(state): State<ApplicationState>,
+    Extension(principal): Extension<Principal>,
+    CommandExtractor(metadata, change): CommandExtractor<ConfigChange>,
+) -> Response {
+    let approval_id = hex::encode(rand::random::<[u8; 16]>());
+    let command = ApprovalCommand::Propose { proposer: principal.0, change, timestamp: clock::now() };
+    match state.approval_cqrs.execute_with_metadata(&approval_id, command, metadata).await {
+        Ok(_) => (StatusCode::OK, Json(ProposalResponse { approval_id })).into_response(),
+        Err(err) => {
+            state.error_metrics.record::<Approval>(&err);
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        },
+    }
+}
+
+// The changes waiting for a second operator, oldest first.
+#[utoipa::path(
+    get,
+    path = "/admin/approval",
+    tag = "admin",
+    responses(
+        (status = 200, body = Vec<ApprovalView>),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn pending_approvals_handler(State(state): State<ApplicationState>) -> Response {
+    match pending_approvals(&state.pool, MAX_PENDING_APPROVALS).await {
+        Ok(pending) => (StatusCode::OK, Json(pending)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/approval/{approval_id}",
+    tag = "admin",
+    params(
+        ("approval_id" = String, Path, description = "Approval id"),
+    ),
+    responses(
+        (status = 200, body = ApprovalView),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn approval_query_handler(
+    Path(approval_id): Path<String>,
     State(state): State<ApplicationState>,
-    CommandExtractor(metadata, command): CommandExtractor<OrderCommand>,
 ) -> Response {
-    match state
-        .order_cqrs
-        .execute_with_metadata(&order_id, command, metadata)
-        .await
-    {
+    match state.approval_query.load(&approval_id).await {
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Ok(Some(view)) => (StatusCode::OK, Json(view)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Approves a pending change, which is applied before the approval is recorded. The
+// approver must be another principal than the proposer.
+#[utoipa::path(
+    post,
+    path = "/admin/approval/{approval_id}/approve",
+    tag = "admin",
+    params(
+        ("approval_id" = String, Path, description = "Approval id"),
+    ),
+    responses(
+        (status = 204, description = "Change approved and applied"),
+        (status = 400, description = "Not pending, or the change could not be applied", body = String),
+        (status = 403, description = "Proposed by the same operator", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn approval_approve_handler(
+    Path(approval_id): Path<String>,
+    State(state): State<ApplicationState>,
+    Extension(principal): Extension<Principal>,
+    MetadataExtractor(metadata): MetadataExtractor,
+) -> Response {
+    let command = ApprovalCommand::Approve { approver: principal.0, timestamp: clock::now() };
+    approval_decision(&state, &approval_id, command, metadata).await
+}
+
+// Rejects a pending change, by its proposer to withdraw it or by any other operator.
+#[utoipa::path(
+    post,
+    path = "/admin/approval/{approval_id}/reject",
+    tag = "admin",
+    params(
+        ("approval_id" = String, Path, description = "Approval id"),
+    ),
+    request_body = RejectionRequest,
+    responses(
+        (status = 204, description = "Change rejected"),
+        (status = 400, description = "Not pending", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn approval_reject_handler(
+    Path(approval_id): Path<String>,
+    State(state): State<ApplicationState>,
+    Extension(principal): Extension<Principal>,
+    CommandExtractor(metadata, request): CommandExtractor<RejectionRequest>,
+) -> Response {
+    let command = ApprovalCommand::Reject { approver: principal.0, reason: request.reason, timestamp: clock::now() };
+    approval_decision(&state, &approval_id, command, metadata).await
+}
+
+async fn approval_decision(state: &ApplicationState, approval_id: &str, command: ApprovalCommand, metadata: HashMap<String, String>) -> Response {
+    match state.approval_cqrs.execute_with_metadata(approval_id, command, metadata).await {
         Ok(_) => StatusCode::NO_CONTENT.into_response(),
         Err(err) => {
+            state.error_metrics.record::<Approval>(&err);
+            tracing::error!("Error: {:#?}\n", err);
+            let status = match err {
+                AggregateError::UserError(ApprovalError::SameOperator(_)) => StatusCode::FORBIDDEN,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            (status, err.to_string()).into_response()
+        },
+    }
+}
+
+// Books a deposit or withdrawal on the business day of its value date rather than the one
+// it is posted on, for corrections after the fact. The value date must lie within the
+// backdating window, see `BusinessDayConfig::max_backdate_days`; a closed day it lands on
+// gets its checkpoint taken again.
+#[utoipa::path(
+    post,
+    path = "/admin/account/{account_id}/value-dated",
+    tag = "admin",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+    ),
+    request_body = AccountCommand,
+    responses(
+        (status = 204, description = "Command accepted", headers(("X-Sequence" = usize, description = "Sequence the account reached, for min_sequence"))),
+        (status = 400, description = "Not a value dated deposit or withdrawal, value date outside of the backdating window, or command rejected", body = String),
+        (status = 409, description = "Txid already used", body = String),
+        (status = 429, description = "Too many commands to the aggregate type, see the bulkheads", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn value_dated_handler(
+    Path(account_id): Path<String>,
+    State(state): State<ApplicationState>,
+    _bulkhead: Bulkhead<Account>,
+    Extension(principal): Extension<Principal>,
+    CommandExtractor(metadata, command): CommandExtractor<AccountCommand>,
+) -> Response {
+    let (AccountCommand::Transaction { txid, .. }, Some(value_date)) = (&command, command.value_date()) else {
+        return (StatusCode::BAD_REQUEST, "Only deposits and withdrawals with a value date are taken here").into_response();
+    };
+    if let Err(response) = claim_txid(&state, txid, &format!("account:{}", account_id)).await {
+        return response;
+    }
+    let execute = || state.account_cqrs.execute_with_metadata(&account_id, command.clone(), metadata.clone());
+    match retry_conflicts(&state.conflict_retry, execute).await {
+        Ok(_) => {
+            tracing::warn!("Transaction of {} value dated {} by {}", account_id, value_date, principal.0);
+            if let Some(business_days) = &state.business_days {
+                if let Err(err) = business_days.restate(value_date).await {
+                    tracing::error!("Error: {:#?}\n", err);
+                }
+            }
+            (StatusCode::NO_CONTENT, sequence_header(&state, &account_id).await).into_response()
+        }
+        Err(err) => {
+            state.error_metrics.record::<Account>(&err);
+            state.signals.rejected(&account_id, &err).await;
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        },
+    }
+}
+
+// Lets a quarantined deposit into the balance once its source has been checked.
+#[utoipa::path(
+    post,
+    path = "/admin/account/{account_id}/quarantine/{txid}",
+    tag = "admin",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+        ("txid" = String, Path, description = "Txid of the quarantined deposit"),
+    ),
+    responses(
+        (status = 204, description = "Deposit released"),
+        (status = 400, description = "Nothing quarantined under the txid", body = String),
+        (status = 429, description = "Too many commands to the aggregate type, see the bulkheads", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn quarantine_release_handler(
+    Path((account_id, txid)): Path<(String, ByteArray32)>,
+    State(state): State<ApplicationState>,
+    _bulkhead: Bulkhead<Account>,
+    MetadataExtractor(metadata): MetadataExtractor,
+) -> Response {
+    let execute = || state.account_cqrs.execute_with_metadata(&account_id, AccountCommand::release_quarantine(txid, clock::now()), metadata.clone());
+    match retry_conflicts(&state.conflict_retry, execute).await {
+        Ok(_) => {
+            tracing::warn!("Quarantined deposit {} of {} released", txid.hex(), account_id);
+            (StatusCode::NO_CONTENT, sequence_header(&state, &account_id).await).into_response()
+        }
+        Err(err) => {
+            state.error_metrics.record::<Account>(&err);
+            state.signals.rejected(&account_id, &err).await;
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        },
+    }
+}
+
+// Sets funds of the balance aside until the earmark is released, for compliance holds,
+// term deposits or funds quarantined after the fact.
+#[utoipa::path(
+    post,
+    path = "/admin/account/{account_id}/earmark/{earmark}",
+    tag = "admin",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+        ("earmark" = String, Path, description = "Name of the earmark"),
+    ),
+    request_body = EarmarkRequest,
+    responses(
+        (status = 204, description = "Funds earmarked"),
+        (status = 400, description = "Insufficient funds or earmark already exists", body = String),
+        (status = 429, description = "Too many commands to the aggregate type, see the bulkheads", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn earmark_handler(
+    Path((account_id, earmark)): Path<(String, String)>,
+    State(state): State<ApplicationState>,
+    _bulkhead: Bulkhead<Account>,
+    MetadataExtractor(metadata): MetadataExtractor,
+    Json(request): Json<EarmarkRequest>,
+) -> Response {
+    let txid = ByteArray32(rand::random());
+    if let Err(response) = claim_txid(&state, &txid, &format!("account:{}", account_id)).await {
+        return response;
+    }
+    let command = AccountCommand::earmark(txid, clock::now(), earmark.clone(), request.asset, request.amount, request.purpose);
+    let execute = || state.account_cqrs.execute_with_metadata(&account_id, command.clone(), metadata.clone());
+    match retry_conflicts(&state.conflict_retry, execute).await {
+        Ok(_) => {
+            tracing::warn!("Funds of {} earmarked under {} for {:?}", account_id, earmark, request.purpose);
+            (StatusCode::NO_CONTENT, sequence_header(&state, &account_id).await).into_response()
+        }
+        Err(err) => {
+            state.error_metrics.record::<Account>(&err);
+            state.signals.rejected(&account_id, &err).await;
             tracing::error!("Error: {:#?}\n", err);
             (StatusCode::BAD_REQUEST, err.to_string()).into_response()
         },
     }
 }
+
+// Returns the funds of an earmark to the available balance.
+#[utoipa::path(
+    delete,
+    path = "/admin/account/{account_id}/earmark/{earmark}",
+    tag = "admin",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+        ("earmark" = String, Path, description = "Name of the earmark"),
+    ),
+    responses(
+        (status = 204, description = "Earmark released"),
+        (status = 400, description = "Earmark not found", body = String),
+        (status = 429, description = "Too many commands to the aggregate type, see the bulkheads", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn earmark_release_handler(
+    Path((account_id, earmark)): Path<(String, String)>,
+    State(state): State<ApplicationState>,
+    _bulkhead: Bulkhead<Account>,
+    MetadataExtractor(metadata): MetadataExtractor,
+) -> Response {
+    let txid = ByteArray32(rand::random());
+    if let Err(response) = claim_txid(&state, &txid, &format!("account:{}", account_id)).await {
+        return response;
+    }
+    let command = AccountCommand::release_earmark(txid, clock::now(), earmark.clone());
+    let execute = || state.account_cqrs.execute_with_metadata(&account_id, command.clone(), metadata.clone());
+    match retry_conflicts(&state.conflict_retry, execute).await {
+        Ok(_) => {
+            tracing::warn!("Earmark {} of {} released", earmark, account_id);
+            (StatusCode::NO_CONTENT, sequence_header(&state, &account_id).await).into_response()
+        }
+        Err(err) => {
+            state.error_metrics.record::<Account>(&err);
+            state.signals.rejected(&account_id, &err).await;
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        },
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/account/{account_id}/preferences",
+    tag = "account",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+    ),
+    responses(
+        (status = 200, body = PreferencesView),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn preferences_query_handler(
+    Path(account_id): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    let view = match state.preferences_query.load(&account_id).await {
+        Ok(view) => view,
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        }
+    };
+    match view {
+        None => StatusCode::NOT_FOUND.into_response(),
+        Some(preferences_view) => (StatusCode::OK, Json(preferences_view)).into_response(),
+    }
+}
+
+// Preferences share the id of their account and can only be set on existing accounts.
+// Webhooks and display names are set on `/admin/account/:account_id/preferences`, nothing
+// here tells the owner of the account from anyone else, and statements show the names
+// to its counterparties.
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/preferences",
+    tag = "account",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+    ),
+    request_body = PreferencesCommand,
+    responses(
+        (status = 204, description = "Command accepted"),
+        (status = 400, description = "Command rejected", body = String),
+        (status = 403, description = "Webhooks and display names are set on the admin route", body = String),
+        (status = 404, description = "Not found"),
+        (status = 429, description = "Too many commands to the aggregate type, see the bulkheads", body = String),
+    ),
+)]
+pub async fn preferences_command_handler(
+    Path(account_id): Path<String>,
+    State(state): State<ApplicationState>,
+    _bulkhead: Bulkhead<Preferences>,
+    CommandExtractor(metadata, command): CommandExtractor<PreferencesCommand>,
+) -> Response {
+    if let PreferencesCommand::SetWebhook { .. } = command {
+        let message = format!("Webhooks must be set at /admin/account/{}/preferences", account_id);
+        return (StatusCode::FORBIDDEN, message).into_response();
+    }
+    if let PreferencesCommand::SetDisplayName { .. } = command {
+        let message = format!("Display names must be set at /admin/account/{}/preferences", account_id);
+        return (StatusCode::FORBIDDEN, message).into_response();
+    }
+    execute_preferences(&state, &account_id, command, metadata).await
+}
+
+// Same as `preferences_command_handler`, webhooks and display names included. Their url must resolve to
+// public addresses, deliveries check it again, see `check_public_url`.
+#[utoipa::path(
+    post,
+    path = "/admin/account/{account_id}/preferences",
+    tag = "admin",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+    ),
+    request_body = PreferencesCommand,
+    responses(
+        (status = 204, description = "Command accepted"),
+        (status = 400, description = "Command rejected, or the webhook points into the private network", body = String),
+        (status = 404, description = "Not found"),
+        (status = 429, description = "Too many commands to the aggregate type, see the bulkheads", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn preferences_admin_handler(
+    Path(account_id): Path<String>,
+    State(state): State<ApplicationState>,
+    _bulkhead: Bulkhead<Preferences>,
+    CommandExtractor(metadata, command): CommandExtractor<PreferencesCommand>,
+) -> Response {
+    if let PreferencesCommand::SetWebhook { url: Some(url) } = &command {
+        if let Err(err) = check_public_url(url).await {
+            return (StatusCode::BAD_REQUEST, err).into_response();
+        }
+    }
+    execute_preferences(&state, &account_id, command, metadata).await
+}
+
+async fn execute_preferences(state: &ApplicationState, account_id: &str, command: PreferencesCommand, metadata: HashMap<String, String>) -> Response {
+    match state.account_query.load(account_id).await {
+        Ok(Some(_)) => {},
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        }
+    }
+    let execute = || state.preferences_cqrs.execute_with_metadata(account_id, command.clone(), metadata.clone());
+    match retry_conflicts(&state.conflict_retry, execute).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => {
+            state.error_metrics.record::<Preferences>(&err);
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        },
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/transfer/{transfer_id}",
+    tag = "transfer",
+    params(
+        ("transfer_id" = String, Path, description = "Transfer id, hex"),
+    ),
+    responses(
+        (status = 200, body = TransferView),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn transfer_query_handler(
+    Path(transfer_id): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    let view = match state.transfer_query.load(&transfer_id).await {
+        Ok(view) => view,
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        }
+    };
+    match view {
+        None => StatusCode::NOT_FOUND.into_response(),
+        Some(transfer_view) => (StatusCode::OK, Json(transfer_view)).into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/transfer/{transfer_id}",
+    tag = "transfer",
+    params(
+        ("transfer_id" = String, Path, description = "Transfer id, hex"),
+    ),
+    request_body = TransferCommand,
+    responses(
+        (status = 204, description = "Command accepted"),
+        (status = 400, description = "Command rejected", body = String),
+        (status = 409, description = "Txid already used", body = String),
+        (status = 429, description = "Too many commands to the aggregate type, see the bulkheads", body = String),
+    ),
+)]
+pub async fn transfer_command_handler(
+    Path(transfer_id): Path<String>,
+    State(state): State<ApplicationState>,
+    _bulkhead: Bulkhead<Transfer>,
+    CommandExtractor(metadata, command): CommandExtractor<TransferCommand>,
+) -> Response {
+    if let Err(rejection) = admit_transfer_command(&transfer_id, &command) {
+        return rejection.into_response();
+    }
+    if let Some(txid) = transfer_txid(&command) {
+        if let Err(response) = claim_txid(&state, txid, &format!("transfer:{}", transfer_id)).await {
+            return response;
+        }
+    }
+    match execute_transfer_command(&state, &transfer_id, &command, &metadata).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => {
+            state.error_metrics.record::<Transfer>(&err);
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        },
+    }
+}
+
+// Runs a command sent to `/transfer/{transfer_id}`, also from the command inbox. A cancel
+// only marks the transfer to be reversed, the reversal follows as its next step.
+pub(crate) async fn execute_transfer_command(
+    state: &ApplicationState,
+    transfer_id: &str,
+    command: &TransferCommand,
+    metadata: &HashMap<String, String>,
+) -> Result<(), AggregateError<TransferError>> {
+    let execute = || state.transfer_cqrs.execute_with_metadata(transfer_id, command.clone(), metadata.clone());
+    retry_conflicts(&state.conflict_retry, execute).await?;
+    if let TransferCommand::Cancel { .. } = command {
+        let reverse = || state.transfer_cqrs.execute_with_metadata(transfer_id, TransferCommand::Continue, metadata.clone());
+        retry_conflicts(&state.conflict_retry, reverse).await?;
+    }
+    Ok(())
+}
+
+// What a command sent to `/transfer/{transfer_id}` is checked for before it runs, also
+// when it waits in the command inbox, see `crate::inbox`.
+pub(crate) fn admit_transfer_command(transfer_id: &str, command: &TransferCommand) -> Result<(), (StatusCode, String)> {
+    if let TransferCommand::Open { transfer_id: txid, .. } | TransferCommand::SwapOpen { transfer_id: txid, .. } = command {
+        if txid.hex() != transfer_id {
+            let message = format!("Transfer {} does not match transfer_id {} of the command", transfer_id, txid.hex());
+            return Err((StatusCode::BAD_REQUEST, message));
+        }
+    }
+    // Refunds only go through `/transfer/{transfer_id}/refund`, which derives their id.
+    if let TransferCommand::Refund { .. } = command {
+        let message = format!("Refunds of transfer {} go through /transfer/{}/refund", transfer_id, transfer_id);
+        return Err((StatusCode::BAD_REQUEST, message));
+    }
+    Ok(())
+}
+
+// The txid a transfer command moves funds under, claimed before it runs.
+pub(crate) fn transfer_txid(command: &TransferCommand) -> Option<&ByteArray32> {
+    match command {
+        TransferCommand::Open { transfer_id, .. } | TransferCommand::SwapOpen { transfer_id, .. } => Some(transfer_id),
+        _ => None,
+    }
+}
+
+// Opens a transfer under an id derived from (from, to, client_reference) and returns
+// it. Retrying with the same request opens nothing new.
+#[utoipa::path(
+    post,
+    path = "/transfer",
+    tag = "transfer",
+    request_body = OpenTransferRequest,
+    responses(
+        (status = 200, body = OpenTransferResponse),
+        (status = 400, description = "Command rejected", body = String),
+        (status = 409, description = "Txid already used", body = String),
+        (status = 429, description = "Too many commands to the aggregate type, see the bulkheads", body = String),
+    ),
+)]
+pub async fn transfer_open_handler(
+    State(state): State<ApplicationState>,
+    _bulkhead: Bulkhead<Transfer>,
+    MetadataExtractor(metadata): MetadataExtractor,
+    Json(request): Json<OpenTransferRequest>,
+) -> Response {
+    if request.client_reference.is_empty() {
+        return (StatusCode::BAD_REQUEST, "client_reference must not be empty").into_response();
+    }
+    let command = request.into_command();
+    let TransferCommand::Open { transfer_id: txid, .. } = &command else {
+        unreachable!("OpenTransferRequest builds an Open command");
+    };
+    let transfer_id = txid.hex();
+    if let Err(response) = claim_txid(&state, txid, &format!("transfer:{}", transfer_id)).await {
+        return response;
+    }
+    let execute = || state.transfer_cqrs.execute_with_metadata(&transfer_id, command.clone(), metadata.clone());
+    match retry_conflicts(&state.conflict_retry, execute).await {
+        Ok(_) => (StatusCode::OK, Json(OpenTransferResponse { transfer_id })).into_response(),
+        Err(err) => {
+            state.error_metrics.record::<Transfer>(&err);
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        },
+    }
+}
+
+// Moves funds out of the account in one call: opens a transfer the way `POST /transfer`
+// does, continues it and answers with its view as the transfer ended, `Done` or
+// `Failed`. Resending the request moves nothing again, it answers with the same transfer.
+// A transfer that could not be continued is left `Opened`, for a `Continue` on
+// `/transfer/{transfer_id}` or the expiry sweeper.
+#[utoipa::path(
+    post,
+    path = "/account/{account_id}/transfer",
+    tag = "transfer",
+    params(
+        ("account_id" = String, Path, description = "Account the funds are moved out of"),
+    ),
+    request_body = AccountTransferRequest,
+    responses(
+        (status = 200, body = TransferView),
+        (status = 400, description = "Command rejected", body = String),
+        (status = 409, description = "Txid already used", body = String),
+        (status = 429, description = "Too many commands to the aggregate type, see the bulkheads", body = String),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn account_transfer_handler(
+    Path(account_id): Path<String>,
+    State(state): State<ApplicationState>,
+    _bulkhead: Bulkhead<Transfer>,
+    MetadataExtractor(metadata): MetadataExtractor,
+    Json(request): Json<AccountTransferRequest>,
+) -> Response {
+    if request.client_reference.is_empty() {
+        return (StatusCode::BAD_REQUEST, "client_reference must not be empty").into_response();
+    }
+    let command = request.into_open(account_id.clone()).into_command();
+    let TransferCommand::Open { transfer_id: txid, .. } = &command else {
+        unreachable!("OpenTransferRequest builds an Open command");
+    };
+    let transfer_id = txid.hex();
+    if let Err(response) = claim_txid(&state, txid, &format!("transfer:{}", transfer_id)).await {
+        return response;
+    }
+    let open = || state.transfer_cqrs.execute_with_metadata(&transfer_id, command.clone(), metadata.clone());
+    let continued = match retry_conflicts(&state.conflict_retry, open).await {
+        Ok(()) => {
+            let execute = || state.transfer_cqrs.execute_with_metadata(&transfer_id, TransferCommand::Continue, metadata.clone());
+            match retry_conflicts(&state.conflict_retry, execute).await {
+                // A resent request finds the transfer past `Opened` already, and answers with it.
+                Err(AggregateError::UserError(TransferError::InvalidState(_))) => Ok(()),
+                continued => continued,
+            }
+        }
+        opened => opened,
+    };
+    if let Err(err) = continued {
+        state.error_metrics.record::<Transfer>(&err);
+        tracing::error!("Error: {:#?}\n", err);
+        return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+    }
+    match state.transfer_query.load(&transfer_id).await {
+        Ok(Some(transfer_view)) => (StatusCode::OK, Json(transfer_view)).into_response(),
+        Ok(None) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Transfer {} has no view", transfer_id)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Sends part or all of a done transfer back under a refund id derived from (transfer,
+// client_reference) and returns it. Retrying with the same request refunds once. The
+// refund gets a view of its own, under the refund id, linking back to the transfer.
+#[utoipa::path(
+    post,
+    path = "/transfer/{transfer_id}/refund",
+    tag = "transfer",
+    params(
+        ("transfer_id" = String, Path, description = "Id of the transfer refunded, hex"),
+    ),
+    request_body = RefundTransferRequest,
+    responses(
+        (status = 200, body = RefundTransferResponse),
+        (status = 400, description = "Command rejected, e.g. more than is left to refund", body = String),
+        (status = 404, description = "Not found"),
+        (status = 409, description = "Txid already used", body = String),
+        (status = 429, description = "Too many commands to the aggregate type, see the bulkheads", body = String),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn transfer_refund_handler(
+    Path(transfer_id): Path<String>,
+    State(state): State<ApplicationState>,
+    _bulkhead: Bulkhead<Transfer>,
+    MetadataExtractor(metadata): MetadataExtractor,
+    Json(request): Json<RefundTransferRequest>,
+) -> Response {
+    if request.client_reference.is_empty() {
+        return (StatusCode::BAD_REQUEST, "client_reference must not be empty").into_response();
+    }
+    let Ok(original) = transfer_id.parse::<ByteArray32>() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match state.transfer_query.load(&transfer_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        }
+    };
+    let command = request.into_command(&original);
+    let TransferCommand::Refund { refund_id, .. } = &command else {
+        unreachable!("RefundTransferRequest builds a Refund command");
+    };
+    let refund_id = *refund_id;
+    if let Err(response) = claim_txid(&state, &refund_id, &format!("transfer:{}", transfer_id)).await {
+        return response;
+    }
+    let execute = || state.transfer_cqrs.execute_with_metadata(&transfer_id, command.clone(), metadata.clone());
+    match retry_conflicts(&state.conflict_retry, execute).await {
+        Ok(_) => (StatusCode::OK, Json(RefundTransferResponse { refund_id: refund_id.hex() })).into_response(),
+        Err(err) => {
+            state.error_metrics.record::<Transfer>(&err);
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        },
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/batch-transfer/{batch_id}",
+    tag = "transfer",
+    params(
+        ("batch_id" = String, Path, description = "Batch id, hex"),
+    ),
+    responses(
+        (status = 200, body = BatchTransferView),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn batch_transfer_query_handler(
+    Path(batch_id): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    let view = match state.batch_transfer_query.load(&batch_id).await {
+        Ok(view) => view,
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        }
+    };
+    match view {
+        None => StatusCode::NOT_FOUND.into_response(),
+        Some(batch_transfer_view) => (StatusCode::OK, Json(batch_transfer_view)).into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/batch-transfer/{batch_id}",
+    tag = "transfer",
+    params(
+        ("batch_id" = String, Path, description = "Batch id, hex"),
+    ),
+    request_body = BatchTransferCommand,
+    responses(
+        (status = 204, description = "Command accepted"),
+        (status = 400, description = "Command rejected", body = String),
+        (status = 409, description = "Txid already used", body = String),
+        (status = 429, description = "Too many commands to the aggregate type, see the bulkheads", body = String),
+    ),
+)]
+pub async fn batch_transfer_command_handler(
+    Path(batch_id): Path<String>,
+    State(state): State<ApplicationState>,
+    _bulkhead: Bulkhead<BatchTransfer>,
+    CommandExtractor(metadata, command): CommandExtractor<BatchTransferCommand>,
+) -> Response {
+    if let BatchTransferCommand::Open { batch_id: txid, .. } = &command {
+        if let Err(response) = claim_txid(&state, txid, &format!("batch_transfer:{}", batch_id)).await {
+            return response;
+        }
+    }
+    let execute = || state.batch_transfer_cqrs.execute_with_metadata(&batch_id, command.clone(), metadata.clone());
+    match retry_conflicts(&state.conflict_retry, execute).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => {
+            state.error_metrics.record::<BatchTransfer>(&err);
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        },
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/order/{order_id}",
+    tag = "order",
+    params(
+        ("order_id" = String, Path, description = "Order id"),
+    ),
+    responses(
+        (status = 200, body = WithAssets<OrderView>),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn order_query_handler(
+    Path(order_id): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    let view = match state.order_query.load(&order_id).await {
+        Ok(view) => view,
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        }
+    };
+    let Some(order_view) = view else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match with_assets(state.asset_query.as_ref(), order_view).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct OrderBookParams {
+    depth: Option<u32>,
+}
+
+// Lists the placed orders of a `BASE-QUOTE` pair, best price first, so buyers can
+// pick an order to hit.
+#[utoipa::path(
+    get,
+    path = "/orderbook/{pair}",
+    tag = "order",
+    params(
+        ("pair" = String, Path, description = "`BASE-QUOTE`, e.g. `BTC-USDT`"),
+        OrderBookParams,
+    ),
+    responses(
+        (status = 200, body = WithAssets<OrderBook>),
+        (status = 400, description = "Invalid pair", body = String),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn order_book_handler(
+    Path(pair): Path<String>,
+    Query(params): Query<OrderBookParams>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    let pair: Pair = match pair.parse() {
+        Ok(pair) => pair,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+    let book = match load_order_book(&state.pool, &state.trading_halts, &pair, params.depth).await {
+        Ok(book) => book,
+        Err(err @ OrderBookError::InvalidPair(_)) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        }
+    };
+    match with_assets(state.asset_query.as_ref(), book).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Lists the rates a pair settled at, newest first, in either direction of its trades.
+#[utoipa::path(
+    get,
+    path = "/rates/{pair}/history",
+    tag = "order",
+    params(
+        ("pair" = String, Path, description = "`BASE-QUOTE`, e.g. `BTC-USDT`"),
+        RateFilter,
+    ),
+    responses(
+        (status = 200, body = WithAssets<RateHistory>),
+        (status = 400, description = "Invalid pair or cursor", body = String),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn rate_history_handler(
+    Path(pair): Path<String>,
+    Query(filter): Query<RateFilter>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    let pair: Pair = match pair.parse() {
+        Ok(pair) => pair,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+    let history = match load_rate_history(&state.pool, &pair, &filter).await {
+        Ok(history) => history,
+        Err(err @ RateError::InvalidCursor(_)) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        }
+    };
+    match with_assets(state.asset_query.as_ref(), history).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/order/{order_id}",
+    tag = "order",
+    params(
+        ("order_id" = String, Path, description = "Order id"),
+    ),
+    request_body = OrderCommand,
+    responses(
+        (status = 204, description = "Command accepted"),
+        (status = 400, description = "Command rejected", body = String),
+        (status = 403, description = "`Release` is reserved to the matcher, `Fill` to the auction engine", body = String),
+        (status = 409, description = "Txid already used", body = String),
+        (status = 429, description = "Too many commands to the aggregate type, see the bulkheads", body = String),
+    ),
+)]
+pub async fn order_command_handler(
+    Path(order_id): Path<String>,
+    State(state): State<ApplicationState>,
+    _bulkhead: Bulkhead<Order>,
+    CommandExtractor(metadata, command): CommandExtractor<OrderCommand>,
+) -> Response {
+    if let OrderCommand::Release = command {
+        return (StatusCode::FORBIDDEN, "Orders are released by the matcher".to_string()).into_response();
+    }
+    if let OrderCommand::Fill { .. } = command {
+        return (StatusCode::FORBIDDEN, "Auction orders are filled by the auction engine".to_string()).into_response();
+    }
+    if let OrderCommand::Open { config } = &command {
+        if let Err(response) = claim_txid(&state, &config.order_id, &format!("order:{}", order_id)).await {
+            return response;
+        }
+    }
+    let execute = || state.order_cqrs.execute_with_metadata(&order_id, command.clone(), metadata.clone());
+    match retry_conflicts(&state.conflict_retry, execute).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => {
+            state.error_metrics.record::<Order>(&err);
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        },
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/rfq/{rfq_id}",
+    tag = "rfq",
+    params(
+        ("rfq_id" = String, Path, description = "RFQ id"),
+    ),
+    responses(
+        (status = 200, body = RfqView),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn rfq_query_handler(
+    Path(rfq_id): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    let view = match state.rfq_query.load(&rfq_id).await {
+        Ok(view) => view,
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        }
+    };
+    match view {
+        None => StatusCode::NOT_FOUND.into_response(),
+        Some(rfq_view) => (StatusCode::OK, Json(rfq_view)).into_response(),
+    }
+}
+
+// Lists every RFQ that is still open for quotes.
+#[utoipa::path(
+    get,
+    path = "/rfq",
+    tag = "rfq",
+    responses(
+        (status = 200, body = Vec<RfqView>),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn open_rfqs_handler(State(state): State<ApplicationState>) -> Response {
+    match open_rfqs(&state.pool).await {
+        Ok(views) => (StatusCode::OK, Json(views)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/rfq/{rfq_id}",
+    tag = "rfq",
+    params(
+        ("rfq_id" = String, Path, description = "RFQ id"),
+    ),
+    request_body = RfqCommand,
+    responses(
+        (status = 204, description = "Command accepted"),
+        (status = 400, description = "Command rejected", body = String),
+        (status = 409, description = "Txid already used", body = String),
+        (status = 429, description = "Too many commands to the aggregate type, see the bulkheads", body = String),
+    ),
+)]
+pub async fn rfq_command_handler(
+    Path(rfq_id): Path<String>,
+    State(state): State<ApplicationState>,
+    _bulkhead: Bulkhead<Rfq>,
+    CommandExtractor(metadata, command): CommandExtractor<RfqCommand>,
+) -> Response {
+    if let RfqCommand::Request { config } = &command {
+        if let Err(response) = claim_txid(&state, &config.rfq_id, &format!("rfq:{}", rfq_id)).await {
+            return response;
+        }
+    }
+    let execute = || state.rfq_cqrs.execute_with_metadata(&rfq_id, command.clone(), metadata.clone());
+    match retry_conflicts(&state.conflict_retry, execute).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => {
+            state.error_metrics.record::<Rfq>(&err);
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        },
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/auction/{auction_id}",
+    tag = "auction",
+    params(
+        ("auction_id" = String, Path, description = "Auction id"),
+    ),
+    responses(
+        (status = 200, body = AuctionView),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn auction_query_handler(
+    Path(auction_id): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    let view = match state.auction_query.load(&auction_id).await {
+        Ok(view) => view,
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        }
+    };
+    match view {
+        None => StatusCode::NOT_FOUND.into_response(),
+        Some(auction_view) => (StatusCode::OK, Json(auction_view)).into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/auction/{auction_id}",
+    tag = "auction",
+    params(
+        ("auction_id" = String, Path, description = "Auction id"),
+    ),
+    request_body = AuctionCommand,
+    responses(
+        (status = 204, description = "Command accepted"),
+        (status = 400, description = "Command rejected", body = String),
+        (status = 429, description = "Too many commands to the aggregate type, see the bulkheads", body = String),
+    ),
+)]
+pub async fn auction_command_handler(
+    Path(auction_id): Path<String>,
+    State(state): State<ApplicationState>,
+    _bulkhead: Bulkhead<Auction>,
+    CommandExtractor(metadata, command): CommandExtractor<AuctionCommand>,
+) -> Response {
+    let execute = || state.auction_cqrs.execute_with_metadata(&auction_id, command.clone(), metadata.clone());
+    match retry_conflicts(&state.conflict_retry, execute).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => {
+            state.error_metrics.record::<Auction>(&err);
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        },
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/standing-order/{standing_order_id}",
+    tag = "transfer",
+    params(
+        ("standing_order_id" = String, Path, description = "Standing order id"),
+    ),
+    responses(
+        (status = 200, body = StandingOrderView),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn standing_order_query_handler(
+    Path(standing_order_id): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    let view = match state.standing_order_query.load(&standing_order_id).await {
+        Ok(view) => view,
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        }
+    };
+    match view {
+        None => StatusCode::NOT_FOUND.into_response(),
+        Some(standing_order_view) => (StatusCode::OK, Json(standing_order_view)).into_response(),
+    }
+}
+
+// Creates, pauses, resumes or cancels a standing order, the scheduler opens its
+// transfers. A new order is held to the command policy of the transfers it will open.
+#[utoipa::path(
+    post,
+    path = "/standing-order/{standing_order_id}",
+    tag = "transfer",
+    params(
+        ("standing_order_id" = String, Path, description = "Standing order id"),
+    ),
+    request_body = StandingOrderCommand,
+    responses(
+        (status = 204, description = "Command accepted"),
+        (status = 400, description = "Command rejected", body = String),
+        (status = 403, description = "Trigger, only sent by the scheduler", body = String),
+        (status = 429, description = "Too many commands to the aggregate type, see the bulkheads", body = String),
+    ),
+)]
+pub async fn standing_order_command_handler(
+    Path(standing_order_id): Path<String>,
+    State(state): State<ApplicationState>,
+    _bulkhead: Bulkhead<StandingOrder>,
+    CommandExtractor(metadata, command): CommandExtractor<StandingOrderCommand>,
+) -> Response {
+    if let StandingOrderCommand::Trigger { .. } = command {
+        return (StatusCode::FORBIDDEN, "Occurrences are triggered by the scheduler".to_string()).into_response();
+    }
+    if let StandingOrderCommand::Create { plan, timestamp } = &command {
+        let open = TransferCommand::Open {
+            transfer_id: ByteArray32::default(),
+            from_account: plan.from_account.clone(),
+            to_account: plan.to_account.clone(),
+            asset: plan.asset.clone(),
+            amount: plan.amount,
+            timestamp: *timestamp,
+            description: plan.description.clone(),
+        };
+        // Checked again by the transfers it opens, this spares a plan none could run.
+        if let Err(err) = state.command_policy.check_transfer(&open) {
+            return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+        }
+    }
+    let execute = || state.standing_order_cqrs.execute_with_metadata(&standing_order_id, command.clone(), metadata.clone());
+    match retry_conflicts(&state.conflict_retry, execute).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => {
+            state.error_metrics.record::<StandingOrder>(&err);
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        },
+    }
+}
+
+// Queues a batch of commands for later, in order, answering with the status of each.
+// Resending a batch answers with the statuses of the one stored, see `crate::inbox`.
+#[utoipa::path(
+    post,
+    path = "/inbox/{batch_id}",
+    tag = "inbox",
+    params(
+        ("batch_id" = String, Path, description = "Batch id chosen by the client"),
+    ),
+    request_body = Vec<InboxEntry>,
+    responses(
+        (status = 202, description = "Batch stored", body = Vec<InboxEntryStatus>),
+        (status = 400, description = "Batch rejected, or a command as its route would", body = String),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn inbox_submit_handler(
+    Path(batch_id): Path<String>,
+    State(state): State<ApplicationState>,
+    MetadataExtractor(metadata): MetadataExtractor,
+    Json(entries): Json<Vec<InboxEntry>>,
+) -> Response {
+    match state.inbox.submit(&batch_id, entries, metadata).await {
+        Ok(statuses) => (StatusCode::ACCEPTED, Json(statuses)).into_response(),
+        Err(err @ InboxError::Invalid(_)) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/inbox/{batch_id}",
+    tag = "inbox",
+    params(
+        ("batch_id" = String, Path, description = "Batch id chosen by the client"),
+    ),
+    responses(
+        (status = 200, body = Vec<InboxEntryStatus>),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn inbox_status_handler(
+    Path(batch_id): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    match state.inbox.status(&batch_id).await {
+        Ok(statuses) if statuses.is_empty() => StatusCode::NOT_FOUND.into_response(),
+        Ok(statuses) => (StatusCode::OK, Json(statuses)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Runs a payout batch from a `account_id,asset,amount,reference` CSV body and answers
+// with the per-row result report, which is also kept for later download.
+#[utoipa::path(
+    post,
+    path = "/payout/{batch_id}",
+    tag = "payout",
+    params(
+        ("batch_id" = String, Path, description = "Payout batch id"),
+    ),
+    request_body(content = String, description = "`account_id,asset,amount,reference` rows", content_type = "text/csv"),
+    responses(
+        (status = 200, description = "Result of every row", body = String, content_type = "text/csv"),
+        (status = 400, description = "Malformed CSV", body = String),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn payout_command_handler(
+    Path(batch_id): Path<String>,
+    State(state): State<ApplicationState>,
+    MetadataExtractor(metadata): MetadataExtractor,
+    body: Bytes,
+) -> Response {
+    let rows = match parse_payout_csv(&body) {
+        Ok(rows) => rows,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+    if let Err(err) = check_clawbacks(&rows, &state.payout) {
+        return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+    }
+    let results = execute_payout(state.account_cqrs.clone(), state.txid_registry.clone(), &state.payout, &state.conflict_retry, &batch_id, rows, metadata).await;
+    let report = match payout_report_csv(&results) {
+        Ok(report) => report,
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        }
+    };
+    if let Err(err) = save_payout_report(&state.pool, &batch_id, &report).await {
+        tracing::error!("Failed to save payout report {}: {}", batch_id, err);
+    }
+    payout_report_response(&batch_id, report)
+}
+
+#[utoipa::path(
+    get,
+    path = "/payout/{batch_id}",
+    tag = "payout",
+    params(
+        ("batch_id" = String, Path, description = "Payout batch id"),
+    ),
+    responses(
+        (status = 200, description = "Result of every row", body = String, content_type = "text/csv"),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn payout_report_handler(
+    Path(batch_id): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    match load_payout_report(&state.pool, &batch_id).await {
+        Ok(Some(report)) => payout_report_response(&batch_id, report),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+fn payout_report_response(batch_id: &str, report: String) -> Response {
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, "text/csv".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"payout-{}.csv\"", batch_id)),
+        ],
+        report,
+    )
+        .into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/asset/{symbol}",
+    tag = "asset",
+    params(
+        ("symbol" = String, Path, description = "Asset symbol"),
+    ),
+    responses(
+        (status = 200, body = AssetView),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn asset_query_handler(
+    Path(symbol): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    let view = match state.asset_query.load(&symbol).await {
+        Ok(view) => view,
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+        }
+    };
+    match view {
+        None => StatusCode::NOT_FOUND.into_response(),
+        Some(asset_view) => (StatusCode::OK, Json(asset_view)).into_response(),
+    }
+}
+
+// Registers, enables, disables and otherwise administers assets, for operators only:
+// every account trades and holds them.
+#[utoipa::path(
+    post,
+    path = "/admin/asset/{symbol}",
+    tag = "admin",
+    params(
+        ("symbol" = String, Path, description = "Asset symbol"),
+    ),
+    request_body = AssetCommand,
+    responses(
+        (status = 204, description = "Command accepted"),
+        (status = 400, description = "Command rejected", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn asset_command_handler(
+    Path(symbol): Path<String>,
+    State(state): State<ApplicationState>,
+    CommandExtractor(metadata, command): CommandExtractor<AssetCommand>,
+) -> Response {
+    // Accounts look assets up by symbol, so the registry entry must live under it.
+    match &command {
+        AssetCommand::Register { symbol: registered, .. } if registered != &symbol => {
+            return (StatusCode::BAD_REQUEST, format!("symbol {} does not match path {}", registered, symbol)).into_response();
+        }
+        // Only the migration converts the balances along, see `/admin/assets/migrations`.
+        AssetCommand::Migrate { .. } | AssetCommand::CompleteMigration { .. } => {
+            return (StatusCode::BAD_REQUEST, "asset migrations are started under /admin/assets/migrations".to_string()).into_response();
+        }
+        AssetCommand::SetTradingRules { .. } if state.approvals.required => return needs_approval(),
+        _ => {}
+    }
+    let execute = || state.asset_cqrs.execute_with_metadata(&symbol, command.clone(), metadata.clone());
+    match retry_conflicts(&state.conflict_retry, execute).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => {
+            state.error_metrics.record::<Asset>(&err);
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        },
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StateMachineParams {
+    #[serde(default)]
+    format: GraphFormat,
+}
+
+// Renders the transition table of an aggregate as a Graphviz DOT (default) or Mermaid diagram.
+#[utoipa::path(
+    get,
+    path = "/admin/statemachine/{aggregate}",
+    tag = "admin",
+    params(
+        ("aggregate" = String, Path, description = "Aggregate type, e.g. `account`"),
+        StateMachineParams,
+    ),
+    responses(
+        (status = 200, description = "Graphviz DOT or Mermaid diagram", body = String, content_type = "text/plain"),
+        (status = 404, description = "Not found"),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn statemachine_handler(
+    Path(aggregate): Path<String>,
+    Query(params): Query<StateMachineParams>,
+) -> Response {
+    match transitions_of(&aggregate) {
+        None => StatusCode::NOT_FOUND.into_response(),
+        Some(transitions) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            render(&aggregate, transitions, params.format),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/matching",
+    tag = "admin",
+    responses(
+        (status = 200, body = MatchingStats),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn matching_stats_handler(State(state): State<ApplicationState>) -> Response {
+    (StatusCode::OK, Json(state.matching_metrics.stats(std::time::Instant::now()))).into_response()
+}
+
+// Lists the pairs halted right now, whether by configuration or under `/admin/halts`.
+#[utoipa::path(
+    get,
+    path = "/admin/halts",
+    tag = "admin",
+    responses(
+        (status = 200, body = Vec<TradingHalt>),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn halt_list_handler(State(state): State<ApplicationState>) -> Response {
+    match state.trading_halts.list().await {
+        Ok(halts) => (StatusCode::OK, Json(halts)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Halts trading in a pair, in both directions, until lifted or until `resume_at`.
+// Halting a halted pair replaces its reason and resume time.
+#[utoipa::path(
+    post,
+    path = "/admin/halts/{pair}",
+    tag = "admin",
+    params(
+        ("pair" = String, Path, description = "`BASE-QUOTE`, e.g. `BTC-USDT`"),
+    ),
+    request_body = HaltRequest,
+    responses(
+        (status = 200, body = TradingHalt),
+        (status = 400, description = "Invalid pair or halt", body = String),
+        (status = 409, description = "Halted by configuration", body = String),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn halt_handler(
+    Path(pair): Path<String>,
+    State(state): State<ApplicationState>,
+    Json(request): Json<HaltRequest>,
+) -> Response {
+    let pair: Pair = match pair.parse() {
+        Ok(pair) => pair,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+    match state.trading_halts.halt(&pair, request).await {
+        Ok(halt) => {
+            tracing::warn!("Trading in {} halted: {}", halt.pair, halt.reason);
+            (StatusCode::OK, Json(halt)).into_response()
+        }
+        Err(err) => halt_error_response(err),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/halts/{pair}",
+    tag = "admin",
+    params(
+        ("pair" = String, Path, description = "`BASE-QUOTE`, e.g. `BTC-USDT`"),
+    ),
+    responses(
+        (status = 204, description = "Trading resumed"),
+        (status = 400, description = "Invalid pair", body = String),
+        (status = 404, description = "Not halted"),
+        (status = 409, description = "Halted by configuration", body = String),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn resume_handler(
+    Path(pair): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    let pair: Pair = match pair.parse() {
+        Ok(pair) => pair,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+    match state.trading_halts.resume(&pair).await {
+        Ok(true) => {
+            tracing::warn!("Trading in {} resumed", pair);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => halt_error_response(err),
+    }
+}
+
+// Funds recovered from failed and cancelled orders since startup, see `crate::order::recovery`.
+#[utoipa::path(
+    get,
+    path = "/admin/recovery",
+    tag = "admin",
+    responses(
+        (status = 200, body = RecoveryReport),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn recovery_report_handler(State(state): State<ApplicationState>) -> Response {
+    (StatusCode::OK, Json(state.fund_recovery.report())).into_response()
+}
+
+// Sweeps orphaned order locks now rather than on the next scheduled sweep, and returns
+// the locks it released.
+#[utoipa::path(
+    post,
+    path = "/admin/recovery",
+    tag = "admin",
+    responses(
+        (status = 200, body = Vec<RecoveredLock>),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn recovery_sweep_handler(State(state): State<ApplicationState>) -> Response {
+    match state.fund_recovery.sweep().await {
+        Ok(recovered) => (StatusCode::OK, Json(recovered)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Sagas resumed and given up on since startup, see `crate::watchdog`.
+#[utoipa::path(
+    get,
+    path = "/admin/watchdog",
+    tag = "admin",
+    responses(
+        (status = 200, body = WatchdogReport),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn watchdog_report_handler(State(state): State<ApplicationState>) -> Response {
+    (StatusCode::OK, Json(state.saga_watchdog.report())).into_response()
+}
+
+// Sweeps stalled sagas now rather than on the next scheduled sweep, and returns the
+// ones it sent `Continue`.
+#[utoipa::path(
+    post,
+    path = "/admin/watchdog",
+    tag = "admin",
+    responses(
+        (status = 200, body = Vec<StalledSaga>),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn watchdog_sweep_handler(State(state): State<ApplicationState>) -> Response {
+    match state.saga_watchdog.sweep().await {
+        Ok(resumed) => (StatusCode::OK, Json(resumed)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Account views found to differ from their events by the last view audit, see
+// `crate::view_audit`.
+#[utoipa::path(
+    get,
+    path = "/admin/view-audit",
+    tag = "admin",
+    responses(
+        (status = 200, body = ViewAuditReport),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn view_audit_report_handler(State(state): State<ApplicationState>) -> Response {
+    (StatusCode::OK, Json(state.view_audit.report())).into_response()
+}
+
+// Compares the account views with their events now, repairing them when configured.
+#[utoipa::path(
+    post,
+    path = "/admin/view-audit",
+    tag = "admin",
+    responses(
+        (status = 200, body = ViewAuditReport),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn view_audit_run_handler(State(state): State<ApplicationState>) -> Response {
+    match state.view_audit.audit().await {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Invariant violations found by the last consistency audit, see `crate::audit`.
+#[utoipa::path(
+    get,
+    path = "/admin/audit",
+    tag = "admin",
+    responses(
+        (status = 200, body = AuditReport),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn audit_report_handler(State(state): State<ApplicationState>) -> Response {
+    (StatusCode::OK, Json(state.consistency_audit.report())).into_response()
+}
+
+// Audits the invariants now rather than on the next scheduled audit.
+#[utoipa::path(
+    post,
+    path = "/admin/audit",
+    tag = "admin",
+    responses(
+        (status = 200, body = AuditReport),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn audit_run_handler(State(state): State<ApplicationState>) -> Response {
+    match state.consistency_audit.audit().await {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Who executed which command on an aggregate, newest first, see `AuditLogQuery`.
+#[utoipa::path(
+    get,
+    path = "/admin/audit/{aggregate_id}",
+    tag = "admin",
+    params(
+        ("aggregate_id" = String, Path, description = "Aggregate id"),
+        AuditLogFilter,
+    ),
+    responses(
+        (status = 200, body = Vec<AuditEntry>),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn audit_log_handler(
+    Path(aggregate_id): Path<String>,
+    Query(filter): Query<AuditLogFilter>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    match state.audit_log.list(&aggregate_id, &filter).await {
+        Ok(entries) => (StatusCode::OK, Json(entries)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Orders and locks found out of step by the last integrity check, see
+// `crate::order::integrity`.
+#[utoipa::path(
+    get,
+    path = "/admin/integrity",
+    tag = "admin",
+    responses(
+        (status = 200, body = IntegrityReport),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn integrity_report_handler(State(state): State<ApplicationState>) -> Response {
+    (StatusCode::OK, Json(state.lock_integrity.report())).into_response()
+}
+
+// Checks the orders and locks now rather than on the next scheduled check.
+#[utoipa::path(
+    post,
+    path = "/admin/integrity",
+    tag = "admin",
+    responses(
+        (status = 200, body = IntegrityReport),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn integrity_check_handler(State(state): State<ApplicationState>) -> Response {
+    match state.lock_integrity.check().await {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Remediates the issue with the lock of an order or RFQ on an account, as it stands now,
+// and returns it: unlocks a dangling lock, cancels an order that lost its lock or locks
+// the funds of a bought one again.
+#[utoipa::path(
+    post,
+    path = "/admin/integrity/{account_id}/{txid}",
+    tag = "admin",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+        ("txid" = String, Path, description = "Id of the order or RFQ the lock is held for"),
+    ),
+    responses(
+        (status = 200, body = IntegrityIssue),
+        (status = 400, description = "Remediation rejected by the account or order", body = String),
+        (status = 404, description = "No issue with the lock, or resolved meanwhile", body = String),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn integrity_remediate_handler(
+    Path((account_id, txid)): Path<(String, ByteArray32)>,
+    State(state): State<ApplicationState>,
+    Extension(principal): Extension<Principal>,
+    MetadataExtractor(metadata): MetadataExtractor,
+) -> Response {
+    match state.lock_integrity.remediate(&account_id, txid, metadata).await {
+        Ok(issue) => {
+            tracing::warn!("Integrity issue of {} on {} remediated by {}", txid.hex(), account_id, principal.0);
+            (StatusCode::OK, Json(issue)).into_response()
+        }
+        Err(err @ IntegrityError::NotFound(..)) => (StatusCode::NOT_FOUND, err.to_string()).into_response(),
+        Err(err @ (IntegrityError::Account(_) | IntegrityError::Order(_))) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        }
+        Err(err @ IntegrityError::Storage(_)) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Progress of the projection rebuilds, archiving and compaction verification, running
+// or past, see `crate::replay`.
+#[utoipa::path(
+    get,
+    path = "/admin/replays",
+    tag = "admin",
+    responses(
+        (status = 200, body = Vec<ReplayProgress>),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn replay_list_handler(State(state): State<ApplicationState>) -> Response {
+    match list_replays(&state.pool).await {
+        Ok(replays) => (StatusCode::OK, Json(replays)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Pauses a replay at its next progress report, within a second, until resumed.
+#[utoipa::path(
+    post,
+    path = "/admin/replays/{name}/pause",
+    tag = "admin",
+    params(
+        ("name" = String, Path, description = "e.g. `rebuild:account_query`"),
+    ),
+    responses(
+        (status = 204, description = "Replay paused"),
+        (status = 404, description = "No such replay"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn replay_pause_handler(
+    Path(name): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    replay_pause_response(&state, &name, true).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/replays/{name}/resume",
+    tag = "admin",
+    params(
+        ("name" = String, Path, description = "e.g. `rebuild:account_query`"),
+    ),
+    responses(
+        (status = 204, description = "Replay resumed"),
+        (status = 404, description = "No such replay"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn replay_resume_handler(
+    Path(name): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    replay_pause_response(&state, &name, false).await
+}
+
+async fn replay_pause_response(state: &ApplicationState, name: &str, paused: bool) -> Response {
+    match pause(&state.pool, name, paused).await {
+        Ok(true) => {
+            tracing::warn!("Replay {} {}", name, if paused { "paused" } else { "resumed" });
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Debits and credits of the movements between accounts per asset, which net to zero
+// once every movement has posted both legs, see `crate::account::journal`.
+#[utoipa::path(
+    get,
+    path = "/reports/trial-balance",
+    tag = "admin",
+    responses(
+        (status = 200, body = TrialBalance),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn trial_balance_handler(State(state): State<ApplicationState>) -> Response {
+    match trial_balance(&state.pool).await {
+        Ok(balance) => (StatusCode::OK, Json(balance)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+fn business_days_not_kept() -> Response {
+    (StatusCode::NOT_FOUND, "Business days are not kept, see BUSINESS_DAY").into_response()
+}
+
+// The day postings are booked on and the most recent business days, see `crate::business_day`.
+#[utoipa::path(
+    get,
+    path = "/admin/business-days",
+    tag = "admin",
+    responses(
+        (status = 200, body = BusinessDayReport),
+        (status = 404, description = "Business days are not kept", body = String),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn business_days_handler(State(state): State<ApplicationState>) -> Response {
+    let Some(business_days) = &state.business_days else {
+        return business_days_not_kept();
+    };
+    match business_days.report().await {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Closes the open business day ahead of its cut-off, postings from now on are booked on
+// the next one. Answers with the day closed, its checkpoint follows after the grace period.
+#[utoipa::path(
+    post,
+    path = "/admin/business-days/close",
+    tag = "admin",
+    responses(
+        (status = 200, body = BusinessDay),
+        (status = 404, description = "Business days are not kept", body = String),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn business_day_close_handler(State(state): State<ApplicationState>) -> Response {
+    let Some(business_days) = &state.business_days else {
+        return business_days_not_kept();
+    };
+    match business_days.close().await {
+        Ok(day) => (StatusCode::OK, Json(day)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// The checkpoint of a closed business day: its ledger movements and the trial balance as
+// of the close.
+#[utoipa::path(
+    get,
+    path = "/reports/business-day/{date}",
+    tag = "admin",
+    params(
+        ("date" = String, Path, description = "Business day, e.g. 2026-10-16"),
+    ),
+    responses(
+        (status = 200, body = DayCheckpoint),
+        (status = 400, description = "Invalid date", body = String),
+        (status = 404, description = "Business days are not kept, or the day has no checkpoint yet", body = String),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn business_day_checkpoint_handler(Path(date): Path<String>, State(state): State<ApplicationState>) -> Response {
+    let Some(business_days) = &state.business_days else {
+        return business_days_not_kept();
+    };
+    let Ok(date) = date.parse() else {
+        return (StatusCode::BAD_REQUEST, format!("Invalid date {}, expected YYYY-MM-DD", date)).into_response();
+    };
+    match business_days.load_checkpoint(date).await {
+        Ok(Some(checkpoint)) => (StatusCode::OK, Json(checkpoint)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, format!("No checkpoint of {} yet", date)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+fn halt_error_response(err: HaltError) -> Response {
+    match err {
+        HaltError::Invalid(_) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+        HaltError::Configured(_) => (StatusCode::CONFLICT, err.to_string()).into_response(),
+        err => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Starts disabling or enabling the accounts matching a filter in the background. Answers
+// with the job, whose progress is then read from `/admin/bulk/{job_id}`.
+#[utoipa::path(
+    post,
+    path = "/admin/bulk",
+    tag = "admin",
+    request_body = BulkRequest,
+    responses(
+        (status = 202, body = BulkJob),
+        (status = 400, description = "Invalid filter", body = String),
+        (status = 409, description = "Another job is running", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn bulk_start_handler(
+    State(state): State<ApplicationState>,
+    CommandExtractor(metadata, request): CommandExtractor<BulkRequest>,
+) -> Response {
+    match state.bulk_operations.start(request, metadata) {
+        Ok(job) => (StatusCode::ACCEPTED, Json(job)).into_response(),
+        Err(err @ BulkError::JobRunning(_)) => (StatusCode::CONFLICT, err.to_string()).into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/bulk",
+    tag = "admin",
+    responses(
+        (status = 200, body = Vec<BulkJob>),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn bulk_list_handler(State(state): State<ApplicationState>) -> Response {
+    (StatusCode::OK, Json(state.bulk_operations.jobs())).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/bulk/{job_id}",
+    tag = "admin",
+    params(
+        ("job_id" = String, Path, description = "Bulk job id"),
+    ),
+    responses(
+        (status = 200, body = BulkJob),
+        (status = 404, description = "Not found"),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn bulk_job_handler(
+    Path(job_id): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    match state.bulk_operations.job(&job_id) {
+        Some(job) => (StatusCode::OK, Json(job)).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/bulk/{job_id}/cancel",
+    tag = "admin",
+    params(
+        ("job_id" = String, Path, description = "Bulk job id"),
+    ),
+    responses(
+        (status = 202, body = BulkJob),
+        (status = 404, description = "Not found"),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn bulk_cancel_handler(
+    Path(job_id): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    match state.bulk_operations.cancel(&job_id) {
+        Ok(job) => (StatusCode::ACCEPTED, Json(job)).into_response(),
+        Err(err) => (StatusCode::NOT_FOUND, err.to_string()).into_response(),
+    }
+}
+
+// Starts migrating an asset into another, converting the balances of every account that
+// held it in the background. Answers with the progress, then read from
+// `/admin/assets/migrations/{asset}`. Starting a failed migration again retries it.
+#[utoipa::path(
+    post,
+    path = "/admin/assets/migrations",
+    tag = "admin",
+    request_body = MigrationRequest,
+    responses(
+        (status = 202, body = MigrationProgress),
+        (status = 400, description = "Unknown or disabled asset, or invalid ratio", body = String),
+        (status = 409, description = "Already migrated, or still running", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn asset_migration_start_handler(
+    State(state): State<ApplicationState>,
+    CommandExtractor(metadata, request): CommandExtractor<MigrationRequest>,
+) -> Response {
+    match state.asset_migrations.start(request, metadata).await {
+        Ok(progress) => (StatusCode::ACCEPTED, Json(progress)).into_response(),
+        Err(err) => migration_error_response(err),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/assets/migrations",
+    tag = "admin",
+    responses(
+        (status = 200, body = Vec<MigrationProgress>),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn asset_migration_list_handler(State(state): State<ApplicationState>) -> Response {
+    match state.asset_migrations.list().await {
+        Ok(migrations) => (StatusCode::OK, Json(migrations)).into_response(),
+        Err(err) => migration_error_response(err.into()),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/assets/migrations/{asset}",
+    tag = "admin",
+    params(
+        ("asset" = String, Path, description = "Symbol of the migrated asset"),
+    ),
+    responses(
+        (status = 200, body = MigrationProgress),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn asset_migration_handler(
+    Path(asset): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    match state.asset_migrations.progress(&asset).await {
+        Ok(Some(progress)) => (StatusCode::OK, Json(progress)).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => migration_error_response(err.into()),
+    }
+}
+
+fn migration_error_response(err: MigrationError) -> Response {
+    match err {
+        MigrationError::UnknownAsset(_) | MigrationError::NotEnabled(_) | MigrationError::Rejected(_) => {
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        }
+        MigrationError::AlreadyMigrated(..) | MigrationError::Running(_) => (StatusCode::CONFLICT, err.to_string()).into_response(),
+        err => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Saga steps that failed on an account, newest first.
+#[utoipa::path(
+    get,
+    path = "/admin/dlq",
+    tag = "admin",
+    params(DeadLetterFilter),
+    responses(
+        (status = 200, body = Vec<DeadLetter>),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn dead_letter_list_handler(
+    Query(filter): Query<DeadLetterFilter>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    match state.dead_letters.list(&filter).await {
+        Ok(letters) => (StatusCode::OK, Json(letters)).into_response(),
+        Err(err) => dead_letter_error_response(err.into()),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/dlq/{id}",
+    tag = "admin",
+    params(
+        ("id" = i64, Path, description = "Dead letter id"),
+    ),
+    responses(
+        (status = 200, body = DeadLetter),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn dead_letter_handler(
+    Path(id): Path<i64>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    match state.dead_letters.get(id).await {
+        Ok(Some(letter)) => (StatusCode::OK, Json(letter)).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => dead_letter_error_response(err.into()),
+    }
+}
+
+// Executes the failed account command again.
+#[utoipa::path(
+    post,
+    path = "/admin/dlq/{id}/retry",
+    tag = "admin",
+    params(
+        ("id" = i64, Path, description = "Dead letter id"),
+    ),
+    responses(
+        (status = 200, body = DeadLetter),
+        (status = 404, description = "Not found", body = String),
+        (status = 409, description = "Already retried or discarded", body = String),
+        (status = 502, description = "The command failed again, the letter stays pending", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn dead_letter_retry_handler(
+    Path(id): Path<i64>,
+    State(state): State<ApplicationState>,
+    Extension(principal): Extension<Principal>,
+) -> Response {
+    match state.dead_letters.retry(id, &*state.account_cqrs, &principal.0).await {
+        Ok(letter) => (StatusCode::OK, Json(letter)).into_response(),
+        Err(err) => dead_letter_error_response(err),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/dlq/{id}/discard",
+    tag = "admin",
+    params(
+        ("id" = i64, Path, description = "Dead letter id"),
+    ),
+    responses(
+        (status = 200, body = DeadLetter),
+        (status = 404, description = "Not found", body = String),
+        (status = 409, description = "Already retried or discarded", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn dead_letter_discard_handler(
+    Path(id): Path<i64>,
+    State(state): State<ApplicationState>,
+    Extension(principal): Extension<Principal>,
+) -> Response {
+    match state.dead_letters.discard(id, &principal.0).await {
+        Ok(letter) => (StatusCode::OK, Json(letter)).into_response(),
+        Err(err) => dead_letter_error_response(err),
+    }
+}
+
+fn dead_letter_error_response(err: DeadLetterError) -> Response {
+    match err {
+        DeadLetterError::NotFound(_) => (StatusCode::NOT_FOUND, err.to_string()).into_response(),
+        DeadLetterError::Resolved(..) => (StatusCode::CONFLICT, err.to_string()).into_response(),
+        DeadLetterError::RetryFailed(..) => (StatusCode::BAD_GATEWAY, err.to_string()).into_response(),
+        err => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Command errors by aggregate and variant, and the framework error rate the alert watches.
+#[utoipa::path(
+    get,
+    path = "/admin/errors",
+    tag = "admin",
+    responses(
+        (status = 200, body = ErrorStats),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn error_stats_handler(State(state): State<ApplicationState>) -> Response {
+    (StatusCode::OK, Json(state.error_metrics.stats(std::time::Instant::now()))).into_response()
+}
+
+// Duplicate transactions rejected, reversals and compensations of all accounts per hour,
+// with the accounts that had the most of each.
+#[utoipa::path(
+    get,
+    path = "/admin/signals",
+    tag = "admin",
+    params(SignalFilter),
+    responses(
+        (status = 200, body = SignalReport),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn signal_report_handler(
+    Query(filter): Query<SignalFilter>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    signal_report(&state, None, &filter).await
+}
+
+// The same for one account.
+#[utoipa::path(
+    get,
+    path = "/admin/signals/{account_id}",
+    tag = "admin",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+        SignalFilter,
+    ),
+    responses(
+        (status = 200, body = SignalReport),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn account_signal_report_handler(
+    Path(account_id): Path<String>,
+    Query(filter): Query<SignalFilter>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    signal_report(&state, Some(&account_id), &filter).await
+}
+
+async fn signal_report(state: &ApplicationState, account_id: Option<&str>, filter: &SignalFilter) -> Response {
+    match state.signals.report(account_id, filter).await {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Events committed today and bytes stored by the busiest accounts and by each tenant with a
+// quota, against their caps, see `Quotas`.
+#[utoipa::path(
+    get,
+    path = "/admin/quota",
+    tag = "admin",
+    responses(
+        (status = 200, body = QuotaReport),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn quota_report_handler(State(state): State<ApplicationState>) -> Response {
+    match state.quotas.report().await {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// The same for one account and its tenant.
+#[utoipa::path(
+    get,
+    path = "/admin/quota/{account_id}",
+    tag = "admin",
+    params(
+        ("account_id" = String, Path, description = "Account id"),
+    ),
+    responses(
+        (status = 200, body = AccountQuotaReport),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn account_quota_report_handler(
+    Path(account_id): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    match state.quotas.account_report(&account_id).await {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// The region this node serves and the endpoints of the others, with their latency lately,
+// see `RegionRouter`.
+#[utoipa::path(
+    get,
+    path = "/admin/routing",
+    tag = "admin",
+    responses(
+        (status = 200, body = RoutingReport),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn routing_report_handler(State(state): State<ApplicationState>) -> Response {
+    (StatusCode::OK, Json(state.router.report())).into_response()
+}
+
+// Registered metrics in the Prometheus text format, for scraping with the admin credentials.
+#[utoipa::path(
+    get,
+    path = "/admin/metrics",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Prometheus text exposition", body = String, content_type = "text/plain"),
+        (status = 500, description = "Encoding error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn metrics_handler(State(state): State<ApplicationState>) -> Response {
+    let encoder = TextEncoder::new();
+    match encoder.encode_to_string(&state.metrics_registry.gather()) {
+        Ok(metrics) => (StatusCode::OK, [(header::CONTENT_TYPE, encoder.format_type().to_string())], metrics).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/webhooks",
+    tag = "admin",
+    responses(
+        (status = 200, body = Vec<Subscription>),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn webhook_list_handler(State(state): State<ApplicationState>) -> Response {
+    match state.webhook_registry.list().await {
+        Ok(subscriptions) => (StatusCode::OK, Json(subscriptions)).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/webhooks",
+    tag = "admin",
+    request_body = Subscription,
+    responses(
+        (status = 204, description = "Subscribed"),
+        (status = 400, description = "Invalid subscription", body = String),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn webhook_subscribe_handler(
+    State(state): State<ApplicationState>,
+    Json(subscription): Json<Subscription>,
+) -> Response {
+    match state.webhook_registry.subscribe(&subscription).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(err @ WebhookError::Invalid(_)) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/webhooks/{subscription_id}",
+    tag = "admin",
+    params(
+        ("subscription_id" = String, Path, description = "Subscription id"),
+    ),
+    responses(
+        (status = 204, description = "Unsubscribed"),
+        (status = 404, description = "Not found"),
+        (status = 500, description = "Storage error", body = String),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn webhook_unsubscribe_handler(
+    Path(subscription_id): Path<String>,
+    State(state): State<ApplicationState>,
+) -> Response {
+    match state.webhook_registry.unsubscribe(&subscription_id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+// Sandbox only: mints a deposit of any registered asset into an open account.
+#[utoipa::path(
+    post,
+    path = "/sandbox/faucet",
+    tag = "sandbox",
+    request_body = FaucetRequest,
+    responses(
+        (status = 200, body = FaucetResponse),
+        (status = 400, description = "Amount out of range or deposit rejected", body = String),
+        (status = 409, description = "Txid already used", body = String),
+    ),
+)]
+pub async fn faucet_handler(
+    State(state): State<ApplicationState>,
+    MetadataExtractor(mut metadata): MetadataExtractor,
+    Json(request): Json<FaucetRequest>,
+) -> Response {
+    if request.amount == 0 || request.amount > state.sandbox.faucet_limit {
+        let message = format!("amount must be between 1 and {}", state.sandbox.faucet_limit);
+        return (StatusCode::BAD_REQUEST, message).into_response();
+    }
+    let txid = ByteArray32(rand::random());
+    if let Err(response) = claim_txid(&state, &txid, &format!("account:{}", request.account_id)).await {
+        return response;
+    }
+    let timestamp = clock::now();
+    let command = AccountCommand::Transaction {
+        timestamp,
+        txid,
+        command: TransactionCommand::Deposit { asset: request.asset, amount: request.amount, source: None, value_date: None },
+        labels: TransactionLabels::default(),
+    };
+    metadata.entry(INITIATOR_KEY.to_string()).or_insert("sandbox-faucet".to_string());
+    let execute = || state.account_cqrs.execute_with_metadata(&request.account_id, command.clone(), metadata.clone());
+    match retry_conflicts(&state.conflict_retry, execute).await {
+        Ok(_) => (StatusCode::OK, Json(FaucetResponse { txid: txid.hex(), timestamp })).into_response(),
+        Err(err) => {
+            state.error_metrics.record::<Account>(&err);
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+        },
+    }
+}
+
+// Sandbox only: deletes every account, order, event and view of the sandbox.
+#[utoipa::path(
+    post,
+    path = "/sandbox/reset",
+    tag = "sandbox",
+    responses(
+        (status = 200, body = WipeReport),
+        (status = 500, description = "Storage error", body = String),
+    ),
+)]
+pub async fn sandbox_reset_handler(State(state): State<ApplicationState>) -> Response {
+    match wipe(&state.pool, &state.sandbox.schema).await {
+        Ok(report) => {
+            // The wiped accounts must not be served from memory.
+            state.account_cache.clear();
+            (StatusCode::OK, Json(report)).into_response()
+        },
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/clock",
+    tag = "sandbox",
+    responses(
+        (status = 200, body = ClockResponse),
+    ),
+)]
+pub async fn clock_query_handler() -> Response {
+    (StatusCode::OK, Json(ClockResponse { now: clock::now(), offset: clock::offset() })).into_response()
+}
+
+// Sandbox only: fast-forwards the clock used for expiries, e.g. to let an order expire
+// without waiting for it. The offset is persisted, so a restart keeps the clock ahead.
+#[utoipa::path(
+    post,
+    path = "/admin/clock",
+    tag = "sandbox",
+    request_body = AdvanceClockRequest,
+    responses(
+        (status = 200, body = ClockResponse),
+        (status = 400, description = "The clock would overflow", body = String),
+    ),
+)]
+pub async fn clock_advance_handler(State(state): State<ApplicationState>, Json(request): Json<AdvanceClockRequest>) -> Response {
+    let Some(offset) = clock::advance(request.seconds) else {
+        return (StatusCode::BAD_REQUEST, format!("Advancing the clock by {}s would overflow it", request.seconds)).into_response();
+    };
+    if let Err(err) = save_clock(&state.pool, offset).await {
+        tracing::error!("Error: {:#?}\n", err);
+        return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+    }
+    tracing::info!("Sandbox clock advanced by {}s, now {}s ahead", request.seconds, offset);
+    (StatusCode::OK, Json(ClockResponse { now: clock::now(), offset })).into_response()
+}
+
+// Consults the global txid registry before a command that introduces a new txid.
+async fn claim_txid(state: &ApplicationState, txid: &ByteArray32, owner: &str) -> Result<(), Response> {
+    try_claim_txid(state, txid, owner).await.map_err(IntoResponse::into_response)
+}
+
+pub(crate) async fn try_claim_txid(state: &ApplicationState, txid: &ByteArray32, owner: &str) -> Result<(), (StatusCode, String)> {
+    match state.txid_registry.claim(txid, owner).await {
+        Ok(()) => Ok(()),
+        Err(err @ TxidRegistryError::Conflict(..)) => Err((StatusCode::CONFLICT, err.to_string())),
+        Err(err) => {
+            tracing::error!("Error: {:#?}\n", err);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+        }
+    }
+}
@@ -0,0 +1,482 @@
+use std::collections::{BTreeSet, HashMap};
+use std::iter::once;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use axum::body::{to_bytes, Body, Bytes};
+use axum::extract::{RawPathParams, Request, State};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use prometheus::Registry;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+use crate::config::RoutingConfig;
+use crate::metrics::RoutingMetrics;
+use crate::services::outbound::{HttpTransport, OutboundError, OutboundRequest, OutboundResponse, ReqwestTransport};
+use crate::state::ApplicationState;
+use crate::telemetry::{TRACEPARENT_KEY, TRACESTATE_KEY};
+
+// Set on forwarded requests to the region they come from: they are served where they land,
+// so regions disagreeing on who owns an account cannot bounce a request between them.
+// Only trusted along a valid `ROUTING_SIGNATURE_HDR`, stripped from any other request.
+pub const ROUTED_FROM_HDR: &str = "x-routed-from";
+// HMAC-SHA256 with `RoutingConfig::secret` over the region, the time, the method, the path
+// and the body of a forwarded request, see `RegionRouter::signature`.
+pub const ROUTING_SIGNATURE_HDR: &str = "x-routing-signature";
+pub const ROUTING_TIMESTAMP_HDR: &str = "x-routing-timestamp";
+// Forwarded requests signed longer ago are taken as replayed.
+const SIGNATURE_MAX_AGE_SECS: u64 = 300;
+// Fields of command bodies naming the account a command moves funds of, for routes without
+// the account in their path: transfers, orders, standing orders and inbox batches.
+const BODY_ACCOUNT_KEYS: &[&str] = &["from_account", "seller", "account_id"];
+
+pub(crate) const MAX_BODY: usize = 16 * 1024 * 1024;
+// Weight of the latest request in the latency of an endpoint.
+const SMOOTHING: f64 = 0.2;
+// Headers of one connection, not passed on. The trace context is set anew by the transport,
+// to the span forwarding the request.
+const HOP_BY_HOP: &[&str] = &[
+    "host", "connection", "keep-alive", "content-length", "transfer-encoding", "upgrade", TRACEPARENT_KEY, TRACESTATE_KEY,
+    ROUTED_FROM_HDR, ROUTING_SIGNATURE_HDR, ROUTING_TIMESTAMP_HDR,
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct EndpointReport {
+    pub region: String,
+    pub url: String,
+    // Smoothed over the requests forwarded to it, a failed one counting as the timeout.
+    // None until the first one.
+    pub latency_ms: Option<f64>,
+    pub forwarded: u64,
+    pub failed: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct RoutingReport {
+    pub region: String,
+    pub endpoints: Vec<EndpointReport>,
+}
+
+#[derive(Default)]
+struct Endpoint {
+    latency_secs: Option<f64>,
+    forwarded: u64,
+    failed: u64,
+}
+
+// Maps accounts and assets to the region owning them and forwards requests to the other
+// regions, see `RoutingConfig`. A region with several endpoints is reached at the one
+// answering the fastest lately; endpoints not tried yet go first, so each gets measured.
+// Forwarded requests are attempted once, commands are not safe to resend.
+#[derive(Clone)]
+pub struct RegionRouter {
+    config: Arc<RoutingConfig>,
+    transport: Arc<dyn HttpTransport>,
+    endpoints: Arc<Mutex<HashMap<String, Endpoint>>>,
+    metrics: RoutingMetrics,
+}
+
+impl RegionRouter {
+    pub fn new(config: RoutingConfig) -> Self {
+        Self::with_transport(config, Arc::new(ReqwestTransport::new()))
+    }
+
+    pub fn with_transport(config: RoutingConfig, transport: Arc<dyn HttpTransport>) -> Self {
+        RegionRouter {
+            config: Arc::new(config),
+            transport,
+            endpoints: Arc::new(Mutex::new(HashMap::new())),
+            metrics: RoutingMetrics::default(),
+        }
+    }
+
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        self.metrics.register(registry)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.config.region.is_empty()
+    }
+
+    pub fn region(&self) -> &str {
+        &self.config.region
+    }
+
+    pub fn owner_of_account(&self, account_id: &str) -> &str {
+        self.config
+            .accounts
+            .iter()
+            .filter(|(prefix, _)| account_id.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, region)| region.as_str())
+            .unwrap_or_else(|| self.hashed_owner(account_id))
+    }
+
+    pub fn owner_of_asset(&self, symbol: &str) -> &str {
+        self.config
+            .assets
+            .get(symbol)
+            .map(String::as_str)
+            .unwrap_or_else(|| self.hashed_owner(symbol))
+    }
+
+    // The region scoring highest for the key, so adding or removing a region only moves
+    // the keys it wins or held.
+    fn hashed_owner(&self, key: &str) -> &str {
+        if !self.config.hashed {
+            return &self.config.region;
+        }
+        let regions: BTreeSet<&str> = self.config.regions.keys().map(String::as_str).chain(once(self.region())).collect();
+        regions
+            .into_iter()
+            .max_by_key(|region| Sha256::digest(format!("{}:{}", region, key)))
+            .unwrap_or(&self.config.region)
+    }
+
+    // The region owning the accounts a command body names, see `BODY_ACCOUNT_KEYS`. Fails
+    // when they belong to several regions, nowhere could serve the command whole.
+    pub fn owner_of_body(&self, body: &[u8]) -> Result<Option<&str>, String> {
+        let Ok(body) = serde_json::from_slice::<Value>(body) else {
+            return Ok(None);
+        };
+        let mut accounts = vec![];
+        body_accounts(&body, BODY_ACCOUNT_KEYS, &mut accounts);
+        let owners: BTreeSet<&str> = accounts.iter().map(|account_id| self.owner_of_account(account_id)).collect();
+        match owners.len() {
+            0 | 1 => Ok(owners.into_iter().next()),
+            _ => Err(format!("The command names accounts of regions {}, send them to each separately", owners.into_iter().collect::<Vec<_>>().join(", "))),
+        }
+    }
+
+    fn signature(&self, region: &str, timestamp: u64, method: &Method, path: &str, body: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.config.secret.as_bytes()).expect("HMAC accepts keys of any length");
+        mac.update(format!("{}\n{}\n{}\n{}\n", region, timestamp, method, path).as_bytes());
+        mac.update(body);
+        mac
+    }
+
+    // Whether the request was forwarded by another region, signed with the shared secret
+    // lately. Never without a secret.
+    fn is_forwarded(&self, parts: &Parts, body: &[u8]) -> bool {
+        let header = |name: &str| parts.headers.get(name).and_then(|value| value.to_str().ok());
+        let (Some(region), Some(timestamp), Some(signature)) = (header(ROUTED_FROM_HDR), header(ROUTING_TIMESTAMP_HDR), header(ROUTING_SIGNATURE_HDR)) else {
+            return false;
+        };
+        let (Ok(timestamp), Ok(signature)) = (timestamp.parse::<u64>(), hex::decode(signature)) else {
+            return false;
+        };
+        if self.config.secret.is_empty() || unix_now().abs_diff(timestamp) > SIGNATURE_MAX_AGE_SECS {
+            return false;
+        }
+        self.signature(region, timestamp, &parts.method, path_of(parts), body).verify_slice(&signature).is_ok()
+    }
+
+    fn endpoint(&self, region: &str) -> Option<String> {
+        let urls = self.config.regions.get(region)?;
+        let endpoints = self.endpoints.lock().expect("routing endpoints poisoned");
+        let latency = |url: &String| endpoints.get(url).and_then(|endpoint| endpoint.latency_secs).unwrap_or(0.0);
+        urls.iter().min_by(|a, b| latency(a).total_cmp(&latency(b))).cloned()
+    }
+
+    fn record(&self, url: &str, succeeded: bool, elapsed: Duration) {
+        let mut endpoints = self.endpoints.lock().expect("routing endpoints poisoned");
+        let endpoint = endpoints.entry(url.to_string()).or_default();
+        let sample = match succeeded {
+            true => elapsed.as_secs_f64(),
+            false => Duration::from_millis(self.config.timeout_ms).as_secs_f64(),
+        };
+        endpoint.latency_secs = Some(match endpoint.latency_secs {
+            Some(latency) => latency + SMOOTHING * (sample - latency),
+            None => sample,
+        });
+        endpoint.forwarded += 1;
+        endpoint.failed += u64::from(!succeeded);
+    }
+
+    // Sends the request, its `url` a path, to the region, answered with whatever status the
+    // region answers.
+    pub async fn forward(&self, region: &str, request: OutboundRequest) -> Result<OutboundResponse, OutboundError> {
+        let Some(endpoint) = self.endpoint(region) else {
+            return Err(OutboundError::Transport(format!("no endpoint for region {}", region)));
+        };
+        let timestamp = unix_now();
+        let signature = self.signature(self.region(), timestamp, &request.method, &request.url, &request.body);
+        let request = OutboundRequest { url: format!("{}{}", endpoint.trim_end_matches('/'), request.url), ..request }
+            .header(ROUTED_FROM_HDR, self.region())
+            .header(ROUTING_TIMESTAMP_HDR, &timestamp.to_string())
+            .header(ROUTING_SIGNATURE_HDR, &hex::encode(signature.finalize().into_bytes()));
+        let started = Instant::now();
+        let result = self.transport.send(&request, Duration::from_millis(self.config.timeout_ms)).await;
+        let elapsed = started.elapsed();
+        self.record(&endpoint, result.is_ok(), elapsed);
+        self.metrics.forwarded(region, if result.is_ok() { "ok" } else { "failed" }, elapsed);
+        result
+    }
+
+    pub fn report(&self) -> RoutingReport {
+        let endpoints = self.endpoints.lock().expect("routing endpoints poisoned");
+        let mut regions: Vec<_> = self.config.regions.iter().collect();
+        regions.sort();
+        let endpoints = regions
+            .into_iter()
+            .flat_map(|(region, urls)| urls.iter().map(move |url| (region, url)))
+            .map(|(region, url)| {
+                let endpoint = endpoints.get(url);
+                EndpointReport {
+                    region: region.clone(),
+                    url: url.clone(),
+                    latency_ms: endpoint.and_then(|endpoint| endpoint.latency_secs).map(|secs| secs * 1000.0),
+                    forwarded: endpoint.map_or(0, |endpoint| endpoint.forwarded),
+                    failed: endpoint.map_or(0, |endpoint| endpoint.failed),
+                }
+            })
+            .collect();
+        RoutingReport { region: self.region().to_string(), endpoints }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+fn path_of(parts: &Parts) -> &str {
+    parts.uri.path_and_query().map_or(parts.uri.path(), |path| path.as_str())
+}
+
+// The string fields named one of `keys` anywhere in the body, in the order found.
+pub(crate) fn body_accounts(value: &Value, keys: &[&str], accounts: &mut Vec<String>) {
+    match value {
+        Value::Object(fields) => {
+            for (key, field) in fields {
+                match field {
+                    Value::String(account_id) if keys.contains(&key.as_str()) => accounts.push(account_id.clone()),
+                    field => body_accounts(field, keys, accounts),
+                }
+            }
+        },
+        Value::Array(items) => items.iter().for_each(|item| body_accounts(item, keys, accounts)),
+        _ => {},
+    }
+}
+
+fn strip_forwarding(headers: &mut HeaderMap) {
+    for name in [ROUTED_FROM_HDR, ROUTING_SIGNATURE_HDR, ROUTING_TIMESTAMP_HDR] {
+        headers.remove(name);
+    }
+}
+
+// Forwards requests to an account or an asset of another region there and answers with
+// its response: the account or asset of the path, otherwise the accounts the command body
+// names. Other requests, and those another region signed for forwarding here, are served
+// locally; forwarding headers of any other request are dropped. Event streams are
+// redirected rather than relayed. Answered 502 when the region cannot be reached and 504
+// when it does not answer in time.
+pub async fn routing_layer(
+    State(state): State<ApplicationState>,
+    params: Option<RawPathParams>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let router = &state.router;
+    let (mut parts, body) = request.into_parts();
+    if !router.is_enabled() {
+        strip_forwarding(&mut parts.headers);
+        return next.run(Request::from_parts(parts, body)).await;
+    }
+    let body: Bytes = match to_bytes(body, MAX_BODY).await {
+        Ok(body) => body,
+        Err(err) => return (StatusCode::PAYLOAD_TOO_LARGE, err.to_string()).into_response(),
+    };
+    if router.is_forwarded(&parts, &body) {
+        return next.run(Request::from_parts(parts, Body::from(body))).await;
+    }
+    strip_forwarding(&mut parts.headers);
+    let owner = params.as_ref().and_then(|params| {
+        params.iter().find_map(|(name, value)| match name {
+            "account_id" => Some(router.owner_of_account(value)),
+            "symbol" => Some(router.owner_of_asset(value)),
+            _ => None,
+        })
+    });
+    let owner = match owner {
+        Some(owner) => Some(owner),
+        None => match router.owner_of_body(&body) {
+            Ok(owner) => owner,
+            Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+        },
+    };
+    let Some(owner) = owner.filter(|owner| *owner != router.region()).map(str::to_string) else {
+        return next.run(Request::from_parts(parts, Body::from(body))).await;
+    };
+    let path = path_of(&parts).to_string();
+    let streaming = parts
+        .headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/event-stream"));
+    if streaming {
+        return match router.endpoint(&owner) {
+            Some(endpoint) => {
+                let location = format!("{}{}", endpoint.trim_end_matches('/'), path);
+                (StatusCode::TEMPORARY_REDIRECT, [(header::LOCATION, location)]).into_response()
+            },
+            None => (StatusCode::BAD_GATEWAY, format!("no endpoint for region {}", owner)).into_response(),
+        };
+    }
+    let headers = parts
+        .headers
+        .iter()
+        .filter(|(name, _)| !HOP_BY_HOP.contains(&name.as_str()))
+        .filter_map(|(name, value)| value.to_str().ok().map(|value| (name.to_string(), value.to_string())))
+        .collect();
+    let request = OutboundRequest { method: parts.method, url: path.clone(), headers, body: body.to_vec() };
+    match router.forward(&owner, request).await {
+        Ok(response) => {
+            let mut builder = Response::builder().status(response.status);
+            for (name, value) in response.headers.iter().filter(|(name, _)| !HOP_BY_HOP.contains(&name.as_str())) {
+                builder = builder.header(name, value);
+            }
+            builder
+                .body(Body::from(response.body))
+                .unwrap_or_else(|err| (StatusCode::BAD_GATEWAY, err.to_string()).into_response())
+        },
+        Err(err) => {
+            tracing::warn!("Forwarding {} to region {} failed: {}", path, owner, err);
+            let status = match err {
+                OutboundError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+                _ => StatusCode::BAD_GATEWAY,
+            };
+            (status, err.to_string()).into_response()
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use axum::http::Request;
+    use serde_json::json;
+    use crate::config::RoutingConfig;
+    use crate::routing::{RegionRouter, ROUTED_FROM_HDR, ROUTING_SIGNATURE_HDR, ROUTING_TIMESTAMP_HDR};
+    use crate::services::outbound::{MockTransport, OutboundError, OutboundRequest};
+
+    fn config() -> RoutingConfig {
+        RoutingConfig {
+            region: "eu".to_string(),
+            regions: HashMap::from([
+                ("eu".to_string(), vec!["http://eu-1".to_string()]),
+                ("us".to_string(), vec!["http://us-1".to_string(), "http://us-2/".to_string()]),
+            ]),
+            accounts: HashMap::from([("ACME-".to_string(), "us".to_string()), ("ACME-EU-".to_string(), "eu".to_string())]),
+            assets: HashMap::from([("USD".to_string(), "us".to_string())]),
+            secret: "shared".to_string(),
+            ..RoutingConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_owners() {
+        let router = RegionRouter::new(config());
+        assert_eq!(router.owner_of_account("ACME-0001"), "us");
+        assert_eq!(router.owner_of_account("ACME-EU-0001"), "eu");
+        assert_eq!(router.owner_of_account("ACCT-0001"), "eu");
+        assert_eq!(router.owner_of_asset("USD"), "us");
+        assert_eq!(router.owner_of_asset("BTC"), "eu");
+
+        let hashed = RegionRouter::new(RoutingConfig { hashed: true, ..config() });
+        let owners: Vec<&str> = (0..100).map(|n| hashed.owner_of_account(&format!("ACCT-{:04}", n))).collect();
+        assert!(owners.contains(&"eu") && owners.contains(&"us"));
+        // Listed accounts keep their region.
+        assert_eq!(hashed.owner_of_account("ACME-0001"), "us");
+        // Another region only takes over some of the accounts, from all regions alike.
+        let mut regions = config().regions;
+        regions.insert("ap".to_string(), vec!["http://ap-1".to_string()]);
+        let grown = RegionRouter::new(RoutingConfig { hashed: true, regions, ..config() });
+        for (n, owner) in owners.iter().enumerate() {
+            let moved = grown.owner_of_account(&format!("ACCT-{:04}", n));
+            assert!(moved == *owner || moved == "ap");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward_to_fastest_endpoint() {
+        let transport = Arc::new(MockTransport::default().then(Ok(404)).then(Err(OutboundError::Timeout(Duration::from_millis(5000)))));
+        let router = RegionRouter::with_transport(config(), transport.clone());
+        let request = || OutboundRequest { method: reqwest::Method::GET, url: "/account/ACME-0001".to_string(), headers: vec![], body: vec![] };
+
+        // Whatever the region answers is passed on.
+        assert_eq!(router.forward("us", request()).await.unwrap().status, 404);
+        // The endpoint not tried yet goes next, and fails.
+        assert!(router.forward("us", request()).await.is_err());
+        router.forward("us", request()).await.unwrap();
+        let urls: Vec<String> = transport.requests().into_iter().map(|request| request.url).collect();
+        assert_eq!(urls, ["http://us-1/account/ACME-0001", "http://us-2/account/ACME-0001", "http://us-1/account/ACME-0001"]);
+
+        let report = router.report();
+        let us: Vec<_> = report.endpoints.iter().filter(|endpoint| endpoint.region == "us").collect();
+        assert_eq!((us[0].forwarded, us[0].failed), (2, 0));
+        assert_eq!((us[1].forwarded, us[1].failed), (1, 1));
+        assert_eq!(us[1].latency_ms, Some(5000.0));
+        assert!(router.forward("ap", request()).await.is_err());
+    }
+
+    #[test]
+    fn test_owner_of_body() {
+        let router = RegionRouter::new(config());
+        let open = json!({"Open": {"transfer_id": "00", "from_account": "ACME-0001", "to_account": "ACCT-0001", "asset": "USD", "amount": 1}});
+        assert_eq!(router.owner_of_body(open.to_string().as_bytes()), Ok(Some("us")));
+        let order = json!({"Open": {"config": {"seller": "ACCT-0002", "sell_asset": "BTC"}}});
+        assert_eq!(router.owner_of_body(order.to_string().as_bytes()), Ok(Some("eu")));
+        assert_eq!(router.owner_of_body(br#""Continue""#), Ok(None));
+        assert_eq!(router.owner_of_body(b"not json"), Ok(None));
+        // A batch of the inbox served by no single region.
+        let batch = json!([
+            {"command_id": "1", "command": {"Account": {"account_id": "ACME-0001", "command": {}}}},
+            {"command_id": "2", "command": {"Account": {"account_id": "ACCT-0001", "command": {}}}},
+        ]);
+        assert!(router.owner_of_body(batch.to_string().as_bytes()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_requests_are_signed() {
+        let transport = Arc::new(MockTransport::default());
+        let router = RegionRouter::with_transport(config(), transport.clone());
+        let body = br#"{"Deposit":{}}"#.to_vec();
+        let request = OutboundRequest { method: reqwest::Method::POST, url: "/account/ACME-0001".to_string(), headers: vec![], body: body.clone() };
+        router.forward("us", request).await.unwrap();
+        let sent = transport.requests().remove(0);
+
+        let received = |headers: &[(String, String)], body: &[u8]| {
+            let mut request = Request::builder().method("POST").uri("/account/ACME-0001");
+            for (name, value) in headers {
+                request = request.header(name, value);
+            }
+            let (parts, _) = request.body(()).unwrap().into_parts();
+            router.is_forwarded(&parts, body)
+        };
+        assert!(received(&sent.headers, &body));
+        // Altered on the way, or merely claiming to come from a region.
+        assert!(!received(&sent.headers, br#"{"Withdraw":{}}"#));
+        assert!(!received(&[(ROUTED_FROM_HDR.to_string(), "us".to_string())], &body));
+        let forged: Vec<_> = sent.headers.iter().cloned().map(|(name, value)| match name.as_str() {
+            ROUTING_SIGNATURE_HDR => (name, "00".repeat(32)),
+            _ => (name, value),
+        }).collect();
+        assert!(!received(&forged, &body));
+        let stale: Vec<_> = sent.headers.iter().cloned().map(|(name, value)| match name.as_str() {
+            ROUTING_TIMESTAMP_HDR => (name, "1".to_string()),
+            _ => (name, value),
+        }).collect();
+        assert!(!received(&stale, &body));
+        // Another node sharing no secret.
+        let other = RegionRouter::new(RoutingConfig { secret: String::new(), ..config() });
+        let (parts, _) = Request::builder().method("POST").uri("/account/ACME-0001").body(()).unwrap().into_parts();
+        assert!(!other.is_forwarded(&parts, &body));
+    }
+}
@@ -0,0 +1,374 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+use async_trait::async_trait;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use cqrs_es::persist::{PersistedEventRepository, PersistedEventStore, PersistenceError, ReplayStream, SerializedEvent, SerializedSnapshot, ViewContext, ViewRepository};
+use cqrs_es::{Aggregate, CqrsFramework, Query, View};
+use hmac::{Hmac, Mac};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use sqlx::{Pool, Postgres, Row};
+use crate::aggregate_cache::{AggregateCache, CachedEventStore};
+use crate::commit_hooks::HookedEventRepository;
+use crate::config::{SealingConfig, ShardConfig};
+use crate::consistency::check_expected_sequence;
+use crate::sharding::ShardedCqrs;
+
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SealError {
+    #[error("Invalid {0}: expected 32 hex encoded bytes")]
+    InvalidKey(&'static str),
+    #[error("An encryption key needs an integrity key")]
+    EncryptionWithoutIntegrity,
+    #[error("{0} {1} is not sealed")]
+    Unsealed(String, String),
+    #[error("{0} {1} is encrypted but no encryption key is configured")]
+    NoEncryptionKey(String, String),
+    #[error("Malformed seal on {0} {1}")]
+    Malformed(String, String),
+    #[error("Integrity check of {0} {1} failed, the payload was altered or partially written")]
+    Tampered(String, String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    sealed: u32,
+    // HMAC-SHA256 of the kind, id and version of the payload and of the stored bytes.
+    mac: String,
+    #[serde(flatten)]
+    body: Body,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Body {
+    Encrypted { nonce: String, ciphertext: String },
+    // The JSON text as sealed, so the bytes checked are exactly the ones authenticated
+    // and not whatever jsonb normalized them into.
+    Plain { data: String },
+}
+
+// Seals the snapshots and views of aggregates holding balances: the stored payload
+// carries an HMAC over its bytes, kind, id and version, so edits, payloads moved between
+// ids or rolled back to an older version, and partial writes are caught on load instead
+// of being served. With an encryption key the payload is also AES-256-GCM encrypted.
+#[derive(Clone)]
+pub struct Sealer {
+    integrity_key: Arc<[u8]>,
+    encryption_key: Option<Arc<LessSafeKey>>,
+    accept_unsealed: bool,
+}
+
+impl std::fmt::Debug for Sealer {
+    // Never prints the keys.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sealer")
+            .field("encrypted", &self.encryption_key.is_some())
+            .field("accept_unsealed", &self.accept_unsealed)
+            .finish()
+    }
+}
+
+impl Sealer {
+    // None when no integrity key is configured, payloads are then stored in plain JSON.
+    pub fn new(config: &SealingConfig) -> Result<Option<Self>, SealError> {
+        let decode = |key: &str, name| {
+            hex::decode(key).ok().filter(|bytes| bytes.len() == 32).ok_or(SealError::InvalidKey(name))
+        };
+        if config.integrity_key.is_empty() {
+            return match config.encryption_key.is_empty() {
+                true => Ok(None),
+                false => Err(SealError::EncryptionWithoutIntegrity),
+            };
+        }
+        let integrity_key = decode(&config.integrity_key, "integrity key")?;
+        let encryption_key = match config.encryption_key.is_empty() {
+            true => None,
+            false => {
+                let key = UnboundKey::new(&AES_256_GCM, &decode(&config.encryption_key, "encryption key")?)
+                    .map_err(|_| SealError::InvalidKey("encryption key"))?;
+                Some(Arc::new(LessSafeKey::new(key)))
+            }
+        };
+        Ok(Some(Sealer { integrity_key: integrity_key.into(), encryption_key, accept_unsealed: config.accept_unsealed }))
+    }
+
+    pub fn seal(&self, kind: &str, id: &str, version: i64, payload: &Value) -> Value {
+        let data = serde_json::to_vec(payload).expect("JSON values are always serializable");
+        let aad = associated_data(kind, id, version);
+        let body = match &self.encryption_key {
+            Some(key) => {
+                let nonce: [u8; NONCE_LEN] = rand::random();
+                let mut ciphertext = data;
+                key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(&aad), &mut ciphertext)
+                    .expect("payloads are far below the AES-GCM size limit");
+                Body::Encrypted { nonce: hex::encode(nonce), ciphertext: BASE64_STANDARD.encode(ciphertext) }
+            }
+            None => Body::Plain { data: String::from_utf8(data).expect("serde_json writes UTF-8") },
+        };
+        let mac = self.mac(&aad, &body).finalize().into_bytes();
+        let envelope = Envelope { sealed: FORMAT_VERSION, mac: hex::encode(mac), body };
+        serde_json::to_value(envelope).expect("envelopes are always serializable")
+    }
+
+    pub fn open(&self, kind: &str, id: &str, version: i64, stored: Value) -> Result<Value, SealError> {
+        let malformed = || SealError::Malformed(kind.to_string(), id.to_string());
+        if stored.get("sealed").is_none() {
+            return match self.accept_unsealed {
+                // Written before sealing was enabled, it is sealed on its next update.
+                true => Ok(stored),
+                false => Err(SealError::Unsealed(kind.to_string(), id.to_string())),
+            };
+        }
+        let envelope: Envelope = serde_json::from_value(stored).map_err(|_| malformed())?;
+        if envelope.sealed != FORMAT_VERSION {
+            return Err(malformed());
+        }
+        let aad = associated_data(kind, id, version);
+        let mac = hex::decode(&envelope.mac).map_err(|_| malformed())?;
+        self.mac(&aad, &envelope.body)
+            .verify_slice(&mac)
+            .map_err(|_| SealError::Tampered(kind.to_string(), id.to_string()))?;
+        let data = match envelope.body {
+            Body::Plain { data } => data.into_bytes(),
+            Body::Encrypted { nonce, ciphertext } => {
+                let key = self.encryption_key.as_ref().ok_or_else(|| SealError::NoEncryptionKey(kind.to_string(), id.to_string()))?;
+                let nonce: [u8; NONCE_LEN] = hex::decode(nonce).ok().and_then(|nonce| nonce.try_into().ok()).ok_or_else(malformed)?;
+                let mut data = BASE64_STANDARD.decode(ciphertext).map_err(|_| malformed())?;
+                let plaintext = key.open_in_place(Nonce::assume_unique_for_key(nonce), Aad::from(&aad), &mut data)
+                    .map_err(|_| SealError::Tampered(kind.to_string(), id.to_string()))?;
+                let len = plaintext.len();
+                data.truncate(len);
+                data
+            }
+        };
+        serde_json::from_slice(&data).map_err(|_| malformed())
+    }
+
+    fn mac(&self, aad: &[u8], body: &Body) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.integrity_key).expect("HMAC accepts keys of any length");
+        mac.update(aad);
+        match body {
+            Body::Plain { data } => mac.update(data.as_bytes()),
+            Body::Encrypted { nonce, ciphertext } => {
+                mac.update(nonce.as_bytes());
+                mac.update(b"\n");
+                mac.update(ciphertext.as_bytes());
+            }
+        }
+        mac
+    }
+}
+
+fn associated_data(kind: &str, id: &str, version: i64) -> Vec<u8> {
+    format!("{}\n{}\n{}\n", kind, id, version).into_bytes()
+}
+
+fn snapshot_kind<A: Aggregate>() -> String {
+    format!("snapshot/{}", A::aggregate_type())
+}
+
+// The sealed event store of any backend, behind the aggregate cache.
+pub type SealedEventStore<R, A> = CachedEventStore<PersistedEventStore<SealedEventRepository<R>, A>, A>;
+
+pub type SealedCqrs<A> = ShardedCqrs<A, SealedEventStore<HookedEventRepository, A>>;
+
+// `postgres_es::postgres_snapshot_cqrs` with sealed snapshots, keeping the aggregates in
+// `cache` between their commands and executing them on `shards`.
+pub fn sealed_snapshot_cqrs<A>(
+    events: HookedEventRepository,
+    queries: Vec<Box<dyn Query<A>>>,
+    snapshot_size: usize,
+    services: A::Services,
+    sealer: Option<Sealer>,
+    cache: AggregateCache<A>,
+    shards: &ShardConfig,
+) -> SealedCqrs<A>
+where
+    A: Aggregate + Clone + 'static,
+    A::Command: Send,
+    A::Error: Send,
+{
+    let repo = SealedEventRepository::new(events, sealer);
+    let store = CachedEventStore::new(PersistedEventStore::new_snapshot_store(repo, snapshot_size), cache, Some(snapshot_size));
+    ShardedCqrs::new(CqrsFramework::new(store, queries, services), shards)
+}
+
+// Seals the snapshots written through `inner`; events are passed through untouched, but
+// for the sequence a command expected, see `crate::consistency::check_expected_sequence`.
+pub struct SealedEventRepository<R> {
+    inner: R,
+    sealer: Option<Sealer>,
+}
+
+impl<R> SealedEventRepository<R> {
+    pub fn new(inner: R, sealer: Option<Sealer>) -> Self {
+        SealedEventRepository { inner, sealer }
+    }
+}
+
+#[async_trait]
+impl<R: PersistedEventRepository> PersistedEventRepository for SealedEventRepository<R> {
+    async fn get_events<A: Aggregate>(&self, aggregate_id: &str) -> Result<Vec<SerializedEvent>, PersistenceError> {
+        self.inner.get_events::<A>(aggregate_id).await
+    }
+
+    async fn get_last_events<A: Aggregate>(&self, aggregate_id: &str, last_sequence: usize) -> Result<Vec<SerializedEvent>, PersistenceError> {
+        self.inner.get_last_events::<A>(aggregate_id, last_sequence).await
+    }
+
+    async fn get_snapshot<A: Aggregate>(&self, aggregate_id: &str) -> Result<Option<SerializedSnapshot>, PersistenceError> {
+        let Some(mut snapshot) = self.inner.get_snapshot::<A>(aggregate_id).await? else {
+            return Ok(None);
+        };
+        if let Some(sealer) = &self.sealer {
+            let stored = std::mem::take(&mut snapshot.aggregate);
+            snapshot.aggregate = sealer
+                .open(&snapshot_kind::<A>(), aggregate_id, snapshot.current_snapshot as i64, stored)
+                .map_err(|e| PersistenceError::DeserializationError(Box::new(e)))?;
+        }
+        Ok(Some(snapshot))
+    }
+
+    async fn persist<A: Aggregate>(&self, events: &[SerializedEvent], snapshot_update: Option<(String, Value, usize)>) -> Result<(), PersistenceError> {
+        let snapshot_update = match (&self.sealer, snapshot_update) {
+            (Some(sealer), Some((aggregate_id, aggregate, current_snapshot))) => {
+                let sealed = sealer.seal(&snapshot_kind::<A>(), &aggregate_id, current_snapshot as i64, &aggregate);
+                Some((aggregate_id, sealed, current_snapshot))
+            }
+            (_, snapshot_update) => snapshot_update,
+        };
+        let events = check_expected_sequence(events)?;
+        self.inner.persist::<A>(&events, snapshot_update).await
+    }
+
+    async fn stream_events<A: Aggregate>(&self, aggregate_id: &str) -> Result<ReplayStream, PersistenceError> {
+        self.inner.stream_events::<A>(aggregate_id).await
+    }
+
+    async fn stream_all_events<A: Aggregate>(&self) -> Result<ReplayStream, PersistenceError> {
+        self.inner.stream_all_events::<A>().await
+    }
+}
+
+// `postgres_es::PostgresViewRepository` with sealed payloads.
+pub struct SealedViewRepository<V, A> {
+    view_name: String,
+    pool: Pool<Postgres>,
+    sealer: Option<Sealer>,
+    _phantom: PhantomData<(V, A)>,
+}
+
+impl<V: View<A>, A: Aggregate> SealedViewRepository<V, A> {
+    pub fn new(view_name: &str, pool: Pool<Postgres>, sealer: Option<Sealer>) -> Self {
+        SealedViewRepository { view_name: view_name.to_string(), pool, sealer, _phantom: PhantomData }
+    }
+
+    fn kind(&self) -> String {
+        format!("view/{}", self.view_name)
+    }
+}
+
+#[async_trait]
+impl<V: View<A>, A: Aggregate> ViewRepository<V, A> for SealedViewRepository<V, A> {
+    async fn load(&self, view_id: &str) -> Result<Option<V>, PersistenceError> {
+        Ok(self.load_with_context(view_id).await?.map(|(view, _)| view))
+    }
+
+    async fn load_with_context(&self, view_id: &str) -> Result<Option<(V, ViewContext)>, PersistenceError> {
+        let row = sqlx::query(&format!("SELECT version, payload FROM {} WHERE view_id = $1", self.view_name))
+            .bind(view_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| PersistenceError::ConnectionError(Box::new(e)))?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let version: i64 = row.try_get("version").map_err(|e| PersistenceError::DeserializationError(Box::new(e)))?;
+        let payload: Value = row.try_get("payload").map_err(|e| PersistenceError::DeserializationError(Box::new(e)))?;
+        let payload = match &self.sealer {
+            Some(sealer) => sealer.open(&self.kind(), view_id, version, payload).map_err(|e| PersistenceError::DeserializationError(Box::new(e)))?,
+            None => payload,
+        };
+        let view = serde_json::from_value(payload)?;
+        Ok(Some((view, ViewContext::new(view_id.to_string(), version))))
+    }
+
+    async fn update_view(&self, view: V, context: ViewContext) -> Result<(), PersistenceError> {
+        let sql = match context.version {
+            0 => format!("INSERT INTO {} (payload, version, view_id) VALUES ($1, $2, $3)", self.view_name),
+            _ => format!("UPDATE {} SET payload = $1, version = $2 WHERE view_id = $3", self.view_name),
+        };
+        let version = context.version + 1;
+        let payload = serde_json::to_value(&view)?;
+        let payload = match &self.sealer {
+            Some(sealer) => sealer.seal(&self.kind(), &context.view_instance_id, version, &payload),
+            None => payload,
+        };
+        sqlx::query(&sql)
+            .bind(payload)
+            .bind(version)
+            .bind(&context.view_instance_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| PersistenceError::ConnectionError(Box::new(e)))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use crate::config::SealingConfig;
+    use crate::sealing::{SealError, Sealer};
+
+    const INTEGRITY_KEY: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+    const ENCRYPTION_KEY: &str = "0202020202020202020202020202020202020202020202020202020202020202";
+
+    fn sealer(encryption_key: &str, accept_unsealed: bool) -> Sealer {
+        let config = SealingConfig { integrity_key: INTEGRITY_KEY.to_string(), encryption_key: encryption_key.to_string(), accept_unsealed };
+        Sealer::new(&config).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_seal_round_trip() {
+        let payload = json!({ "InService": { "balance": { "BTC": 100 } } });
+        for sealer in [sealer("", false), sealer(ENCRYPTION_KEY, false)] {
+            let sealed = sealer.seal("snapshot/account", "ACCT-0001", 3, &payload);
+            assert_eq!(sealer.open("snapshot/account", "ACCT-0001", 3, sealed.clone()).unwrap(), payload);
+            // Bound to its id and version.
+            assert!(matches!(sealer.open("snapshot/account", "ACCT-0002", 3, sealed.clone()), Err(SealError::Tampered(..))));
+            assert!(matches!(sealer.open("snapshot/account", "ACCT-0001", 4, sealed), Err(SealError::Tampered(..))));
+        }
+        let encrypted = sealer(ENCRYPTION_KEY, false).seal("view/account_query", "ACCT-0001", 1, &payload);
+        assert!(!encrypted.to_string().contains("balance"));
+        assert!(matches!(sealer("", false).open("view/account_query", "ACCT-0001", 1, encrypted), Err(SealError::NoEncryptionKey(..))));
+    }
+
+    #[test]
+    fn test_detects_tampering() {
+        let sealer = sealer("", false);
+        let mut sealed = sealer.seal("view/account_query", "ACCT-0001", 1, &json!({ "balance": { "BTC": 100 } }));
+        sealed["data"] = json!(r#"{"balance":{"BTC":100000}}"#);
+        assert!(matches!(sealer.open("view/account_query", "ACCT-0001", 1, sealed.clone()), Err(SealError::Tampered(..))));
+        sealed["data"] = json!(r#"{"balance":{"BT"#);
+        assert!(matches!(sealer.open("view/account_query", "ACCT-0001", 1, sealed), Err(SealError::Tampered(..))));
+
+        let plain = json!({ "balance": { "BTC": 100 } });
+        assert!(matches!(sealer.open("view/account_query", "ACCT-0001", 1, plain.clone()), Err(SealError::Unsealed(..))));
+        assert_eq!(self::sealer("", true).open("view/account_query", "ACCT-0001", 1, plain.clone()).unwrap(), plain);
+    }
+
+    #[test]
+    fn test_keys() {
+        assert!(Sealer::new(&SealingConfig::default()).unwrap().is_none());
+        let config = SealingConfig { integrity_key: "00ff".to_string(), ..Default::default() };
+        assert!(matches!(Sealer::new(&config), Err(SealError::InvalidKey(_))));
+        let config = SealingConfig { encryption_key: ENCRYPTION_KEY.to_string(), ..Default::default() };
+        assert!(matches!(Sealer::new(&config), Err(SealError::EncryptionWithoutIntegrity)));
+    }
+}
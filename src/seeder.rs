@@ -0,0 +1,611 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use cqrs_es::persist::{PersistedEventRepository, PersistedEventStore};
+use cqrs_es::{AggregateError, CqrsFramework};
+use futures::{stream, StreamExt};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use reqwest::StatusCode;
+use crate::account::aggregate::Account;
+use crate::account::commands::{AccountCommand, LifecycleCommand, TransactionCommand};
+use crate::asset::aggregate::Asset;
+use crate::asset::commands::AssetCommand;
+use crate::field_encryption::EncryptedEventRepository;
+use crate::order::aggregate::{Order, OrderError};
+use crate::order::commands::OrderCommand;
+use crate::order::events::OrderConfig;
+use crate::sealing::SealedEventStore;
+use crate::sharding::ShardedCqrs;
+use crate::transfer::aggregate::{Transfer, TransferError};
+use crate::transfer::commands::TransferCommand;
+use crate::util::types::ByteArray32;
+
+// Generates realistic populations for performance and UX testing: accounts holding a few
+// assets with heavy-tailed balances, transfers flowing mostly towards a handful of
+// merchant accounts, and order books resting on both sides of the mid price. Generation
+// is deterministic in `SeedConfig::seed` and lazy, so a large scale is streamed to the
+// target rather than held in memory. Targets are the frameworks of either event store,
+// see `FrameworkTarget`, or a running server, see `HttpTarget`.
+
+const MAX_ATTEMPTS: u32 = 5;
+const RETRY_BACKOFF: Duration = Duration::from_millis(20);
+// Draws of an account with something to spend before a phase is cut short.
+const MAX_DRAWS: usize = 100;
+// One account in `HUB_RATIO` is a merchant, receiving `HUB_SHARE` of the transfers.
+const HUB_RATIO: usize = 50;
+const HUB_SHARE: f64 = 0.6;
+// Resting orders are priced between these fractions away from the mid price.
+const MIN_SPREAD: f64 = 0.001;
+const MAX_SPREAD: f64 = 0.05;
+
+struct SeedAsset {
+    symbol: &'static str,
+    decimals: u8,
+    // The mid price in the quote asset.
+    price: f64,
+    // The share of accounts holding the asset.
+    holders: f64,
+}
+
+// The quote asset of every pair comes first.
+const QUOTE: usize = 0;
+const ASSETS: [SeedAsset; 3] = [
+    SeedAsset { symbol: "USDT", decimals: 6, price: 1.0, holders: 0.9 },
+    SeedAsset { symbol: "BTC", decimals: 8, price: 60_000.0, holders: 0.5 },
+    SeedAsset { symbol: "ETH", decimals: 8, price: 3_000.0, holders: 0.4 },
+];
+
+impl SeedAsset {
+    fn units(&self, value: f64) -> u64 {
+        (value * 10f64.powi(self.decimals as i32)) as u64
+    }
+
+    fn value(&self, units: u64) -> f64 {
+        units as f64 / 10f64.powi(self.decimals as i32)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scale {
+    Small,
+    Medium,
+    Large,
+}
+
+impl FromStr for Scale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "small" => Ok(Scale::Small),
+            "medium" => Ok(Scale::Medium),
+            "large" => Ok(Scale::Large),
+            _ => Err(format!("unknown scale {}, expected small, medium or large", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SeedConfig {
+    pub accounts: usize,
+    pub transfers: usize,
+    pub orders: usize,
+    pub seed: u64,
+    // Account ids are the prefix and a sequence number, ids of transfers, orders and
+    // deposits derive from the prefix and the seed. Seeding the same target twice takes
+    // another prefix or seed.
+    pub prefix: String,
+    // Commands are timestamped from here on, one second apart.
+    pub timestamp: u64,
+}
+
+impl SeedConfig {
+    pub fn new(scale: Scale) -> Self {
+        let (accounts, transfers, orders) = match scale {
+            Scale::Small => (100, 300, 100),
+            Scale::Medium => (10_000, 50_000, 5_000),
+            Scale::Large => (100_000, 1_000_000, 50_000),
+        };
+        SeedConfig {
+            accounts,
+            transfers,
+            orders,
+            seed: 0,
+            prefix: "SEED-".to_string(),
+            timestamp: chrono::Utc::now().timestamp() as u64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeedPhase {
+    Assets,
+    Accounts,
+    Transfers,
+    Orders,
+}
+
+impl SeedPhase {
+    fn next(self) -> Option<Self> {
+        match self {
+            SeedPhase::Assets => Some(SeedPhase::Accounts),
+            SeedPhase::Accounts => Some(SeedPhase::Transfers),
+            SeedPhase::Transfers => Some(SeedPhase::Orders),
+            SeedPhase::Orders => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum SeedCommand {
+    Asset { symbol: String, command: AssetCommand },
+    Account { account_id: String, command: AccountCommand },
+    Transfer { transfer_id: String, command: TransferCommand },
+    Order { order_id: String, command: OrderCommand },
+}
+
+// Commands executed in order, e.g. opening an account and its deposits. Units of a phase
+// are independent of each other, a phase only starts once the one before is done.
+#[derive(Debug, Clone)]
+pub struct SeedUnit {
+    pub phase: SeedPhase,
+    pub commands: Vec<SeedCommand>,
+}
+
+pub struct Scenario {
+    config: SeedConfig,
+    rng: StdRng,
+    phase: Option<SeedPhase>,
+    next: usize,
+    // What each account has left of its deposits, by asset. Transfers and orders only
+    // spend deposits, never what was received, so the units of a phase can run in any order.
+    spendable: Vec<[u64; ASSETS.len()]>,
+}
+
+impl Scenario {
+    pub fn new(config: SeedConfig) -> Self {
+        let rng = StdRng::seed_from_u64(config.seed);
+        Scenario { config, rng, phase: Some(SeedPhase::Assets), next: 0, spendable: Vec::new() }
+    }
+
+    fn account_id(&self, index: usize) -> String {
+        format!("{}{:06}", self.config.prefix, index + 1)
+    }
+
+    fn derive_id(&self, kind: &str, key: &str) -> ByteArray32 {
+        ByteArray32::derive("seed", &format!("{}:{}:{}:{}", self.config.prefix, self.config.seed, kind, key))
+    }
+
+    fn timestamp(&self) -> u64 {
+        self.config.timestamp + self.next as u64
+    }
+
+    fn asset_unit(&mut self) -> Option<SeedUnit> {
+        let asset = ASSETS.get(self.next)?;
+        let command = AssetCommand::Register { symbol: asset.symbol.to_string(), decimals: asset.decimals, timestamp: self.timestamp() };
+        Some(SeedUnit { phase: SeedPhase::Assets, commands: vec![SeedCommand::Asset { symbol: asset.symbol.to_string(), command }] })
+    }
+
+    fn account_unit(&mut self) -> Option<SeedUnit> {
+        if self.next >= self.config.accounts {
+            return None;
+        }
+        let account_id = self.account_id(self.next);
+        let mut balances = [0; ASSETS.len()];
+        for (balance, asset) in balances.iter_mut().zip(&ASSETS) {
+            if self.rng.gen_bool(asset.holders) {
+                // Log-uniform between 10 and a million in the quote asset.
+                let value = 10f64.powf(self.rng.gen_range(1.0..6.0));
+                *balance = asset.units(value / asset.price);
+            }
+        }
+        if balances.iter().all(|balance| *balance == 0) {
+            balances[QUOTE] = ASSETS[QUOTE].units(10.0);
+        }
+        let open = AccountCommand::Lifecycle(LifecycleCommand::Open { account_id: account_id.clone() });
+        let mut commands = vec![SeedCommand::Account { account_id: account_id.clone(), command: open }];
+        for (asset, amount) in ASSETS.iter().zip(balances).filter(|(_, amount)| *amount > 0) {
+            let command = AccountCommand::Transaction {
+                timestamp: self.timestamp(),
+                txid: self.derive_id("deposit", &format!("{}:{}", account_id, asset.symbol)),
+                command: TransactionCommand::Deposit { asset: asset.symbol.to_string(), amount, source: None, value_date: None },
+                labels: Default::default(),
+            };
+            commands.push(SeedCommand::Account { account_id: account_id.clone(), command });
+        }
+        self.spendable.push(balances);
+        Some(SeedUnit { phase: SeedPhase::Accounts, commands })
+    }
+
+    // Takes a fraction of what the account has left of the asset, at least one unit.
+    fn draw(&mut self, account: usize, asset: usize, min: f64, max: f64) -> u64 {
+        let spendable = self.spendable[account][asset];
+        ((spendable as f64 * self.rng.gen_range(min..max)) as u64).clamp(1, spendable)
+    }
+
+    fn counterparty(&mut self, from: usize) -> usize {
+        let hubs = (self.spendable.len() / HUB_RATIO).max(1);
+        loop {
+            let to = if self.rng.gen_bool(HUB_SHARE) {
+                self.rng.gen_range(0..hubs)
+            } else {
+                self.rng.gen_range(0..self.spendable.len())
+            };
+            if to != from {
+                return to;
+            }
+        }
+    }
+
+    fn transfer_unit(&mut self) -> Option<SeedUnit> {
+        if self.next >= self.config.transfers || self.spendable.len() < 2 {
+            return None;
+        }
+        for _ in 0..MAX_DRAWS {
+            let from = self.rng.gen_range(0..self.spendable.len());
+            let held: Vec<usize> = (0..ASSETS.len()).filter(|asset| self.spendable[from][*asset] > 0).collect();
+            let Some(&asset) = held.choose(&mut self.rng) else {
+                continue;
+            };
+            let amount = self.draw(from, asset, 0.01, 0.2);
+            self.spendable[from][asset] -= amount;
+            let to = self.counterparty(from);
+            let transfer_id = self.derive_id("transfer", &self.next.to_string());
+            let open = TransferCommand::Open {
+                transfer_id,
+                from_account: self.account_id(from),
+                to_account: self.account_id(to),
+                asset: ASSETS[asset].symbol.to_string(),
+                amount,
+                timestamp: self.timestamp(),
+                description: String::new(),
+            };
+            let commands = [open, TransferCommand::Continue]
+                .into_iter()
+                .map(|command| SeedCommand::Transfer { transfer_id: transfer_id.hex(), command })
+                .collect();
+            return Some(SeedUnit { phase: SeedPhase::Transfers, commands });
+        }
+        None
+    }
+
+    fn order_unit(&mut self) -> Option<SeedUnit> {
+        if self.next >= self.config.orders || self.spendable.is_empty() {
+            return None;
+        }
+        for _ in 0..MAX_DRAWS {
+            let seller = self.rng.gen_range(0..self.spendable.len());
+            let base = self.rng.gen_range(QUOTE + 1..ASSETS.len());
+            let ask = self.rng.gen_bool(0.5);
+            let (sell, buy) = if ask { (base, QUOTE) } else { (QUOTE, base) };
+            if self.spendable[seller][sell] == 0 {
+                continue;
+            }
+            let sell_amount = self.draw(seller, sell, 0.01, 0.1);
+            let spread = self.rng.gen_range(MIN_SPREAD..MAX_SPREAD);
+            let buy_amount = if ask {
+                ASSETS[QUOTE].units(ASSETS[base].value(sell_amount) * ASSETS[base].price * (1.0 + spread))
+            } else {
+                ASSETS[base].units(ASSETS[QUOTE].value(sell_amount) / (ASSETS[base].price * (1.0 - spread)))
+            };
+            if buy_amount == 0 {
+                continue;
+            }
+            self.spendable[seller][sell] -= sell_amount;
+            let order_id = self.derive_id("order", &self.next.to_string());
+            let config = OrderConfig {
+                order_id,
+                seller: self.account_id(seller),
+                sell_asset: ASSETS[sell].symbol.to_string(),
+                sell_amount,
+                buy_asset: ASSETS[buy].symbol.to_string(),
+                buy_amount,
+                timestamp: self.timestamp(),
+                auction: false,
+                expires_at: None,
+            };
+            let commands = [OrderCommand::Open { config }, OrderCommand::Continue]
+                .into_iter()
+                .map(|command| SeedCommand::Order { order_id: order_id.hex(), command })
+                .collect();
+            return Some(SeedUnit { phase: SeedPhase::Orders, commands });
+        }
+        None
+    }
+}
+
+impl Iterator for Scenario {
+    type Item = SeedUnit;
+
+    fn next(&mut self) -> Option<SeedUnit> {
+        loop {
+            let unit = match self.phase? {
+                SeedPhase::Assets => self.asset_unit(),
+                SeedPhase::Accounts => self.account_unit(),
+                SeedPhase::Transfers => self.transfer_unit(),
+                SeedPhase::Orders => self.order_unit(),
+            };
+            match unit {
+                Some(unit) => {
+                    self.next += 1;
+                    return Some(unit);
+                },
+                None => {
+                    self.phase = self.phase.and_then(SeedPhase::next);
+                    self.next = 0;
+                },
+            }
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SeedError {
+    // Worth another attempt, e.g. a concurrency conflict or a full bulkhead.
+    #[error("Try again: {0}")]
+    Transient(String),
+    #[error("Rejected: {0}")]
+    Rejected(String),
+}
+
+#[async_trait]
+pub trait SeedTarget: Sync + Send {
+    async fn execute(&self, command: SeedCommand) -> Result<(), SeedError>;
+}
+
+fn seed_error<E: std::error::Error>(err: AggregateError<E>) -> SeedError {
+    match err {
+        AggregateError::AggregateConflict | AggregateError::DatabaseConnectionError(_) => SeedError::Transient(err.to_string()),
+        err => SeedError::Rejected(err.to_string()),
+    }
+}
+
+// Executes the commands on the frameworks directly, those of `ApplicationState` or of
+// `crate::sqlite`, skipping the checks of the routes. The accounts may be kept in a
+// repository of their own, see `crate::commit_hooks`.
+pub struct FrameworkTarget<R: PersistedEventRepository, AR: PersistedEventRepository = R> {
+    asset: Arc<CqrsFramework<Asset, PersistedEventStore<R, Asset>>>,
+    account: Arc<ShardedCqrs<Account, SealedEventStore<AR, Account>>>,
+    transfer: Arc<CqrsFramework<Transfer, PersistedEventStore<EncryptedEventRepository<R>, Transfer>>>,
+    order: Arc<CqrsFramework<Order, PersistedEventStore<AR, Order>>>,
+}
+
+impl<R: PersistedEventRepository, AR: PersistedEventRepository> FrameworkTarget<R, AR> {
+    pub fn new(
+        asset: Arc<CqrsFramework<Asset, PersistedEventStore<R, Asset>>>,
+        account: Arc<ShardedCqrs<Account, SealedEventStore<AR, Account>>>,
+        transfer: Arc<CqrsFramework<Transfer, PersistedEventStore<EncryptedEventRepository<R>, Transfer>>>,
+        order: Arc<CqrsFramework<Order, PersistedEventStore<AR, Order>>>,
+    ) -> Self {
+        FrameworkTarget { asset, account, transfer, order }
+    }
+}
+
+#[async_trait]
+impl<R: PersistedEventRepository + 'static, AR: PersistedEventRepository + 'static> SeedTarget for FrameworkTarget<R, AR> {
+    async fn execute(&self, command: SeedCommand) -> Result<(), SeedError> {
+        match command {
+            SeedCommand::Asset { symbol, command } => self.asset.execute(&symbol, command).await.map_err(seed_error),
+            SeedCommand::Account { account_id, command } => self.account.execute(&account_id, command).await.map_err(seed_error),
+            // The sagas pass on conflicts on the accounts they move funds of.
+            SeedCommand::Transfer { transfer_id, command } => self.transfer.execute(&transfer_id, command).await.map_err(|err| match err {
+                AggregateError::UserError(TransferError::AggregateError(AggregateError::AggregateConflict)) => SeedError::Transient(err.to_string()),
+                err => seed_error(err),
+            }),
+            SeedCommand::Order { order_id, command } => self.order.execute(&order_id, command).await.map_err(|err| match err {
+                AggregateError::UserError(OrderError::AggregateError(AggregateError::AggregateConflict)) => SeedError::Transient(err.to_string()),
+                err => seed_error(err),
+            }),
+        }
+    }
+}
+
+// Sends the commands to the routes of a running server, through its checks and bulkheads.
+// Asset and lifecycle commands go to the admin routes, the client is to carry the credentials.
+pub struct HttpTarget {
+    client: reqwest::Client,
+    base_url: String,
+}
+
+impl HttpTarget {
+    pub fn new(client: reqwest::Client, base_url: &str) -> Self {
+        HttpTarget { client, base_url: base_url.trim_end_matches('/').to_string() }
+    }
+}
+
+#[async_trait]
+impl SeedTarget for HttpTarget {
+    async fn execute(&self, command: SeedCommand) -> Result<(), SeedError> {
+        let (path, body) = match command {
+            SeedCommand::Asset { symbol, command } => (format!("/admin/asset/{}", symbol), serde_json::to_value(command)),
+            SeedCommand::Account { account_id, command: AccountCommand::Lifecycle(command) } => (format!("/admin/account/{}", account_id), serde_json::to_value(command)),
+            SeedCommand::Account { account_id, command } => (format!("/account/{}", account_id), serde_json::to_value(command)),
+            SeedCommand::Transfer { transfer_id, command } => (format!("/transfer/{}", transfer_id), serde_json::to_value(command)),
+            SeedCommand::Order { order_id, command } => (format!("/order/{}", order_id), serde_json::to_value(command)),
+        };
+        let body = body.map_err(|err| SeedError::Rejected(err.to_string()))?;
+        let response = self.client
+            .post(format!("{}{}", self.base_url, path))
+            .json(&body)
+            .send()
+            .await
+            .map_err(|err| SeedError::Transient(err.to_string()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let message = format!("{} {}: {}", status, path, response.text().await.unwrap_or_default());
+        match status {
+            StatusCode::CONFLICT | StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => Err(SeedError::Transient(message)),
+            _ if message.contains("aggregate conflict") => Err(SeedError::Transient(message)),
+            _ => Err(SeedError::Rejected(message)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PhaseReport {
+    pub phase: SeedPhase,
+    pub units: u64,
+    pub commands: u64,
+    pub failed: u64,
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct SeedReport {
+    pub phases: Vec<PhaseReport>,
+}
+
+impl SeedReport {
+    pub fn failed(&self) -> u64 {
+        self.phases.iter().map(|phase| phase.failed).sum()
+    }
+}
+
+async fn execute_with_retry(target: &dyn SeedTarget, command: &SeedCommand) -> Result<(), SeedError> {
+    let mut attempt = 1;
+    loop {
+        match target.execute(command.clone()).await {
+            Err(SeedError::Transient(_)) if attempt < MAX_ATTEMPTS => {
+                tokio::time::sleep(RETRY_BACKOFF * 2u32.pow(attempt)).await;
+                attempt += 1;
+            },
+            result => return result,
+        }
+    }
+}
+
+// Runs the commands of a unit until one fails, returns how many ran.
+async fn run_unit(target: &dyn SeedTarget, unit: SeedUnit) -> Result<u64, SeedError> {
+    for command in &unit.commands {
+        execute_with_retry(target, command).await?;
+    }
+    Ok(unit.commands.len() as u64)
+}
+
+// Runs the scenario against the target phase by phase, up to `concurrency` units at a
+// time. A failed unit is reported and skipped, later units that depend on it fail in turn.
+pub async fn seed(target: &dyn SeedTarget, scenario: Scenario, concurrency: usize) -> SeedReport {
+    let mut report = SeedReport::default();
+    let mut units = scenario.peekable();
+    while let Some(phase) = units.peek().map(|unit| unit.phase) {
+        let started = Instant::now();
+        let mut phase_report = PhaseReport { phase, units: 0, commands: 0, failed: 0, elapsed: Duration::ZERO };
+        let mut results = stream::iter(std::iter::from_fn(|| units.next_if(|unit| unit.phase == phase)))
+            .map(|unit| run_unit(target, unit))
+            .buffer_unordered(concurrency.max(1));
+        while let Some(result) = results.next().await {
+            phase_report.units += 1;
+            match result {
+                Ok(commands) => phase_report.commands += commands,
+                Err(err) => {
+                    phase_report.failed += 1;
+                    tracing::warn!("Seeding {:?} failed: {}", phase, err);
+                },
+            }
+            if phase_report.units.is_multiple_of(10_000) {
+                tracing::info!("Seeded {} {:?} units", phase_report.units, phase);
+            }
+        }
+        phase_report.elapsed = started.elapsed();
+        tracing::info!("Seeded {:?}: {} units, {} failed", phase, phase_report.units, phase_report.failed);
+        report.phases.push(phase_report);
+    }
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use async_trait::async_trait;
+    use crate::account::commands::{AccountCommand, TransactionCommand};
+    use crate::order::commands::OrderCommand;
+    use crate::seeder::{seed, Scale, Scenario, SeedCommand, SeedConfig, SeedError, SeedPhase, SeedTarget};
+    use crate::transfer::commands::TransferCommand;
+
+    fn small(seed: u64) -> SeedConfig {
+        SeedConfig { seed, timestamp: 1_700_000_000, ..SeedConfig::new(Scale::Small) }
+    }
+
+    #[test]
+    fn test_scenario_is_deterministic_and_feasible() {
+        let units: Vec<_> = Scenario::new(small(7)).collect();
+        assert_eq!(format!("{:?}", units), format!("{:?}", Scenario::new(small(7)).collect::<Vec<_>>()));
+        assert_ne!(format!("{:?}", units), format!("{:?}", Scenario::new(small(8)).collect::<Vec<_>>()));
+
+        let count = |phase| units.iter().filter(|unit| unit.phase == phase).count();
+        assert_eq!((count(SeedPhase::Assets), count(SeedPhase::Accounts), count(SeedPhase::Transfers), count(SeedPhase::Orders)), (3, 100, 300, 100));
+        let phases: Vec<_> = units.iter().map(|unit| unit.phase as u8).collect();
+        assert!(phases.is_sorted());
+
+        // Whatever order the transfers and orders run in, no account spends more than it
+        // deposited.
+        let mut deposited: HashMap<(String, String), u64> = HashMap::new();
+        let mut spent: HashMap<(String, String), u64> = HashMap::new();
+        for command in units.iter().flat_map(|unit| &unit.commands) {
+            match command {
+                SeedCommand::Account { account_id, command: AccountCommand::Transaction { command: TransactionCommand::Deposit { asset, amount, .. }, .. } } => {
+                    *deposited.entry((account_id.clone(), asset.clone())).or_default() += amount;
+                },
+                SeedCommand::Transfer { command: TransferCommand::Open { from_account, to_account, asset, amount, .. }, .. } => {
+                    assert_ne!(from_account, to_account);
+                    *spent.entry((from_account.clone(), asset.clone())).or_default() += amount;
+                },
+                SeedCommand::Order { command: OrderCommand::Open { config }, .. } => {
+                    assert!(config.buy_amount > 0 && config.sell_asset != config.buy_asset);
+                    *spent.entry((config.seller.clone(), config.sell_asset.clone())).or_default() += config.sell_amount;
+                },
+                _ => {},
+            }
+        }
+        for (key, amount) in spent {
+            assert!(amount <= deposited.get(&key).copied().unwrap_or_default(), "{:?} overspent", key);
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingTarget {
+        attempts: Mutex<HashMap<String, u32>>,
+        executed: Mutex<Vec<SeedPhase>>,
+    }
+
+    #[async_trait]
+    impl SeedTarget for RecordingTarget {
+        async fn execute(&self, command: SeedCommand) -> Result<(), SeedError> {
+            let (phase, key) = match &command {
+                SeedCommand::Asset { symbol, .. } => (SeedPhase::Assets, symbol.clone()),
+                SeedCommand::Account { account_id, command } => (SeedPhase::Accounts, format!("{}{:?}", account_id, command)),
+                SeedCommand::Transfer { transfer_id, command } => (SeedPhase::Transfers, format!("{}{:?}", transfer_id, command)),
+                SeedCommand::Order { order_id, .. } => return Err(SeedError::Rejected(order_id.clone())),
+            };
+            // Every command conflicts once.
+            let mut attempts = self.attempts.lock().unwrap();
+            let attempt = attempts.entry(key).or_default();
+            *attempt += 1;
+            if *attempt == 1 {
+                return Err(SeedError::Transient("aggregate conflict".to_string()));
+            }
+            self.executed.lock().unwrap().push(phase);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_seed_retries_phase_by_phase() {
+        let config = SeedConfig { accounts: 20, transfers: 30, orders: 5, ..small(1) };
+        let target = RecordingTarget::default();
+        let report = seed(&target, Scenario::new(config), 8).await;
+
+        let units: Vec<_> = report.phases.iter().map(|phase| (phase.phase, phase.units, phase.failed)).collect();
+        assert_eq!(units, vec![(SeedPhase::Assets, 3, 0), (SeedPhase::Accounts, 20, 0), (SeedPhase::Transfers, 30, 0), (SeedPhase::Orders, 5, 5)]);
+        assert_eq!(report.failed(), 5);
+        assert_eq!(report.phases[2].commands, 60);
+        let executed: Vec<_> = target.executed.lock().unwrap().iter().map(|phase| *phase as u8).collect();
+        assert!(executed.is_sorted());
+    }
+}
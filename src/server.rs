@@ -0,0 +1,163 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::GracefulShutdown;
+use hyper_util::service::TowerToHyperService;
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use crate::config::ServerConfig;
+use crate::metrics::ConnectionMetrics;
+
+// Before accepting again after a failure, e.g. when out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+// Serves `router` on the connections of `listener` as `axum::serve` does, but with the
+// connection handling of `config` and counting the connections in `metrics`. Once
+// `shutdown` completes no more connections are accepted, idle ones are closed and the
+// requests in flight are given `shutdown_timeout_secs` to finish.
+pub async fn serve(listener: TcpListener, router: Router, config: &ServerConfig, metrics: ConnectionMetrics, shutdown: impl Future<Output = ()>) {
+    let builder = builder(config);
+    let limit = (config.max_connections > 0).then(|| Arc::new(Semaphore::new(config.max_connections)));
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+    loop {
+        let permit = match &limit {
+            Some(limit) => tokio::select! {
+                permit = limit.clone().acquire_owned() => Some(permit.expect("connection limit is never closed")),
+                _ = &mut shutdown => break,
+            },
+            None => None,
+        };
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+        let (stream, remote) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                tracing::error!("Failed to accept a connection: {}", err);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        if let Err(err) = stream.set_nodelay(config.tcp_nodelay) {
+            tracing::warn!("Failed to set TCP_NODELAY on the connection from {}: {}", remote, err);
+        }
+        let connection = metrics.accepted();
+        let service = TowerToHyperService::new(router.clone());
+        let served = graceful.watch(builder.serve_connection_with_upgrades(TokioIo::new(stream), service).into_owned());
+        tokio::spawn(async move {
+            if let Err(err) = served.await {
+                tracing::debug!("Connection from {} closed: {}", remote, err);
+            }
+            drop((connection, permit));
+        });
+    }
+
+    drop(listener);
+    tracing::info!("Shutting down, draining the requests in flight");
+    let timeout = Duration::from_secs(config.shutdown_timeout_secs);
+    if tokio::time::timeout(timeout, graceful.shutdown()).await.is_err() {
+        tracing::warn!("Requests still in flight after {:?}, closing their connections", timeout);
+    }
+}
+
+// Completes on SIGTERM, as sent by an orchestrator stopping the server, or Ctrl-C.
+pub async fn shutdown_signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c().await.expect("unable to listen for Ctrl-C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("unable to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+}
+
+fn builder(config: &ServerConfig) -> Builder<TokioExecutor> {
+    let mut builder = Builder::new(TokioExecutor::new());
+    if !config.http2 {
+        builder = builder.http1_only();
+    }
+    // With a timer, HTTP/1.1 connections that do not send their headers within 30 seconds
+    // are closed.
+    builder.http1()
+        .keep_alive(config.keep_alive)
+        .timer(TokioTimer::new());
+    let interval = config.http2_keep_alive_interval_secs;
+    builder.http2()
+        .max_concurrent_streams(config.max_concurrent_streams)
+        .keep_alive_interval((interval > 0).then(|| Duration::from_secs(interval)))
+        .keep_alive_timeout(Duration::from_secs(config.http2_keep_alive_timeout_secs))
+        .timer(TokioTimer::new());
+    builder
+}
+
+#[cfg(test)]
+mod test {
+    use axum::routing::get;
+    use axum::Router;
+    use prometheus::{Registry, TextEncoder};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+    use crate::config::ServerConfig;
+    use crate::metrics::ConnectionMetrics;
+    use crate::server::serve;
+
+    #[tokio::test]
+    async fn test_serve_http1_and_http2() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let registry = Registry::new();
+        let metrics = ConnectionMetrics::default();
+        metrics.register(&registry).unwrap();
+        let router = Router::new().route("/", get(|| async { "ok" }));
+        tokio::spawn(async move { serve(listener, router, &ServerConfig::default(), metrics, std::future::pending()).await });
+
+        let http1 = reqwest::Client::builder().http1_only().build().unwrap();
+        let http2 = reqwest::Client::builder().http2_prior_knowledge().build().unwrap();
+        for client in [&http1, &http1, &http2] {
+            let response = client.get(&url).send().await.unwrap();
+            assert_eq!(response.text().await.unwrap(), "ok");
+        }
+
+        // Both clients kept their connection open.
+        let metrics = TextEncoder::new().encode_to_string(&registry.gather()).unwrap();
+        assert!(metrics.contains("http_connections_accepted_total 2"), "{}", metrics);
+        assert!(metrics.contains("http_connections_active 2"), "{}", metrics);
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let router = Router::new().route("/", get(|| async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            "ok"
+        }));
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            serve(listener, router, &ServerConfig::default(), ConnectionMetrics::default(), async { stopped.await.unwrap() }).await
+        });
+
+        let in_flight = tokio::spawn(reqwest::get(url.clone()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        stop.send(()).unwrap();
+        // The request in flight is answered, then the server returns.
+        assert_eq!(in_flight.await.unwrap().unwrap().text().await.unwrap(), "ok");
+        tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+        assert!(reqwest::get(&url).await.is_err());
+    }
+}
@@ -0,0 +1,281 @@
+pub mod outbound;
+
+use std::sync::Arc;
+use async_trait::async_trait;
+use tracing::Instrument;
+use cqrs_es::persist::ViewRepository;
+use cqrs_es::{AggregateError, CqrsFramework, EventStore};
+use crate::account::aggregate::Account;
+use crate::account::canary::Canary;
+use crate::account::commands::{AccountCommand, TransactionCommand};
+use crate::account::events::AccountError;
+use crate::asset::aggregate::{Asset, AssetMigration};
+use crate::asset::queries::AssetView;
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use crate::business_day::PostingDate;
+use crate::config::{CommandPolicyConfig, DuplicateDetectionConfig, FeeConfig, QuarantineConfig};
+use crate::sharding::ShardedCqrs;
+use crate::util::clock;
+use crate::telemetry;
+use crate::util::types::ByteArray32;
+
+pub struct BankAccountServices {
+    pub services: Box<dyn BankAccountApi>,
+    pub duplicate_detection: DuplicateDetectionConfig,
+    pub fees: FeeConfig,
+    pub overdraft: Box<dyn OverdraftPolicy>,
+    pub quarantine: QuarantineConfig,
+    pub command_policy: CommandPolicyConfig,
+    // Transactions are value dated when business days are kept.
+    pub posting_date: Option<PostingDate>,
+    // Days a value date given on a command may lie before the posting date.
+    pub max_backdate_days: u32,
+    // How long an account asked to be closed stays open for withdrawals.
+    pub closure_grace_secs: u64,
+    // Routes some accounts to another implementation of their logic.
+    pub canary: Option<Canary>,
+    // Txids past the recent ones kept in the accounts, when stored outside of them.
+    pub dedup_store: Option<Arc<dyn DedupStore>>,
+}
+
+impl BankAccountServices {
+    pub fn new(services: Box<dyn BankAccountApi>) -> Self {
+        Self {
+            services,
+            duplicate_detection: DuplicateDetectionConfig::default(),
+            fees: FeeConfig::default(),
+            overdraft: Box::new(NoOverdraft),
+            quarantine: QuarantineConfig::default(),
+            command_policy: CommandPolicyConfig::default(),
+            posting_date: None,
+            max_backdate_days: 0,
+            closure_grace_secs: 0,
+            canary: None,
+            dedup_store: None,
+        }
+    }
+
+    pub fn with_duplicate_detection(mut self, duplicate_detection: DuplicateDetectionConfig) -> Self {
+        self.duplicate_detection = duplicate_detection;
+        self
+    }
+
+    pub fn with_fees(mut self, fees: FeeConfig) -> Self {
+        self.fees = fees;
+        self
+    }
+
+    pub fn with_overdraft(mut self, overdraft: Box<dyn OverdraftPolicy>) -> Self {
+        self.overdraft = overdraft;
+        self
+    }
+
+    pub fn with_quarantine(mut self, quarantine: QuarantineConfig) -> Self {
+        self.quarantine = quarantine;
+        self
+    }
+
+    pub fn with_command_policy(mut self, command_policy: CommandPolicyConfig) -> Self {
+        self.command_policy = command_policy;
+        self
+    }
+
+    pub fn with_posting_date(mut self, posting_date: Option<PostingDate>) -> Self {
+        self.posting_date = posting_date;
+        self
+    }
+
+    pub fn with_max_backdate_days(mut self, max_backdate_days: u32) -> Self {
+        self.max_backdate_days = max_backdate_days;
+        self
+    }
+
+    pub fn with_canary(mut self, canary: Option<Canary>) -> Self {
+        self.canary = canary;
+        self
+    }
+
+    pub fn with_dedup_store(mut self, dedup_store: Option<Arc<dyn DedupStore>>) -> Self {
+        self.dedup_store = dedup_store;
+        self
+    }
+
+    // Nothing is posted to a day frozen at its cut-off, until the next one is open, but
+    // what finishes a saga, see `TransactionCommand::finishes_saga`, which stays on the
+    // frozen day along with what it unwinds or settles.
+    pub fn value_date(&self, command: &TransactionCommand) -> Result<Option<NaiveDate>, AccountError> {
+        match &self.posting_date {
+            Some(posting_date) => match posting_date.open_at(clock::now()) {
+                Ok(date) => Ok(Some(date)),
+                Err(date) if command.finishes_saga() => Ok(Some(date)),
+                Err(date) => Err(AccountError::BusinessDayClosed(date)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    // A value date asked for on a command may go back up to `max_backdate_days` from the
+    // posting date, or today while business days are not kept, and never forward.
+    pub fn check_value_date(&self, date: NaiveDate) -> Result<NaiveDate, AccountError> {
+        let today = self.posting_date.as_ref().map(PostingDate::get).unwrap_or_else(|| {
+            NaiveDateTime::from_timestamp_opt(clock::now() as i64, 0).expect("the clock is in range").date()
+        });
+        let earliest = today - Duration::days(self.max_backdate_days.into());
+        if date > today || date < earliest {
+            return Err(AccountError::ValueDateOutOfWindow(date));
+        }
+        Ok(date)
+    }
+
+    pub fn with_closure_grace_secs(mut self, closure_grace_secs: u64) -> Self {
+        self.closure_grace_secs = closure_grace_secs;
+        self
+    }
+}
+
+// External services must be called during the processing of the command.
+#[async_trait]
+pub trait BankAccountApi: Sync + Send {
+    async fn atm_withdrawal(&self, atm_id: &str, amount: f64) -> Result<(), AtmError>;
+    async fn validate_check(&self, account_id: &str, check: &str) -> Result<(), CheckingError>;
+    async fn validate_asset(&self, asset: &str) -> Result<(), AssetValidationError>;
+    // What the asset is recorded to migrate into, balances are only converted along it.
+    async fn asset_migration(&self, asset: &str) -> Option<AssetMigration>;
+}
+// Txids processed by the accounts, kept outside of their state, see `crate::account::dedup`.
+#[async_trait]
+pub trait DedupStore: Sync + Send {
+    // When the account processed the txid, if it did at or after `since`.
+    async fn processed_at(&self, account_id: &str, txid: &ByteArray32, since: u64) -> Result<Option<u64>, String>;
+}
+
+// How far an account may overdraw an asset, asked when a withdrawal or debit exceeds
+// the balance.
+#[async_trait]
+pub trait OverdraftPolicy: Sync + Send {
+    async fn credit_limit(&self, account_id: &str, asset: &str) -> u64;
+}
+
+// Never lets a balance go below zero.
+pub struct NoOverdraft;
+
+#[async_trait]
+impl OverdraftPolicy for NoOverdraft {
+    async fn credit_limit(&self, _account_id: &str, _asset: &str) -> u64 {
+        0
+    }
+}
+
+pub struct AtmError;
+pub struct CheckingError;
+pub enum AssetValidationError {
+    NotRegistered,
+    Disabled,
+}
+
+// The account commands issued by the transfer, batch transfer and order sagas, whichever
+// event store the accounts are kept in. Each runs in a span of its own, its events carry
+// the trace context on in their metadata.
+#[async_trait]
+pub trait AccountExecutor: Sync + Send {
+    async fn execute(&self, account_id: &str, command: AccountCommand) -> Result<(), AggregateError<AccountError>>;
+}
+
+#[async_trait]
+impl<ES> AccountExecutor for CqrsFramework<Account, ES>
+where
+    ES: EventStore<Account>,
+    ES::AC: Send,
+{
+    async fn execute(&self, account_id: &str, command: AccountCommand) -> Result<(), AggregateError<AccountError>> {
+        let span = tracing::info_span!("account.execute", account_id);
+        async { CqrsFramework::execute_with_metadata(self, account_id, command, telemetry::current_context()).await }
+            .instrument(span)
+            .await
+    }
+}
+
+#[async_trait]
+impl<ES> AccountExecutor for ShardedCqrs<Account, ES>
+where
+    ES: EventStore<Account> + 'static,
+    ES::AC: Send,
+{
+    async fn execute(&self, account_id: &str, command: AccountCommand) -> Result<(), AggregateError<AccountError>> {
+        let span = tracing::info_span!("account.execute", account_id);
+        async { ShardedCqrs::execute_with_metadata(self, account_id, command, telemetry::current_context()).await }
+            .instrument(span)
+            .await
+    }
+}
+
+// A very simple "happy path" set of services that always succeed.
+pub struct HappyPathBankAccountServices;
+
+#[async_trait]
+impl BankAccountApi for HappyPathBankAccountServices {
+    async fn atm_withdrawal(&self, _atm_id: &str, _amount: f64) -> Result<(), AtmError> {
+        Ok(())
+    }
+
+    async fn validate_check(
+        &self,
+        _account_id: &str,
+        _check_number: &str,
+    ) -> Result<(), CheckingError> {
+        Ok(())
+    }
+
+    async fn validate_asset(&self, _asset: &str) -> Result<(), AssetValidationError> {
+        Ok(())
+    }
+
+    async fn asset_migration(&self, _asset: &str) -> Option<AssetMigration> {
+        None
+    }
+}
+
+// Checks assets against the asset registry, everything else is on the happy path.
+pub struct RegistryBankAccountServices {
+    asset_query: Arc<dyn ViewRepository<AssetView, Asset>>,
+}
+
+impl RegistryBankAccountServices {
+    pub fn new(asset_query: Arc<dyn ViewRepository<AssetView, Asset>>) -> Self {
+        Self { asset_query }
+    }
+}
+
+#[async_trait]
+impl BankAccountApi for RegistryBankAccountServices {
+    async fn atm_withdrawal(&self, atm_id: &str, amount: f64) -> Result<(), AtmError> {
+        HappyPathBankAccountServices.atm_withdrawal(atm_id, amount).await
+    }
+
+    async fn validate_check(&self, account_id: &str, check_number: &str) -> Result<(), CheckingError> {
+        HappyPathBankAccountServices.validate_check(account_id, check_number).await
+    }
+
+    async fn validate_asset(&self, asset: &str) -> Result<(), AssetValidationError> {
+        match self.asset_query.load(asset).await {
+            Ok(Some(view)) if view.enabled => Ok(()),
+            Ok(Some(_)) => Err(AssetValidationError::Disabled),
+            Ok(None) => Err(AssetValidationError::NotRegistered),
+            Err(e) => {
+                // Fail closed, an unreadable registry must not let unknown assets through.
+                tracing::error!("Failed to load asset {}: {}", asset, e);
+                Err(AssetValidationError::NotRegistered)
+            }
+        }
+    }
+
+    async fn asset_migration(&self, asset: &str) -> Option<AssetMigration> {
+        match self.asset_query.load(asset).await {
+            Ok(view) => view.and_then(|view| view.migration),
+            Err(e) => {
+                tracing::error!("Failed to load asset {}: {}", asset, e);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use rand::random;
+use serde::Serialize;
+use crate::config::{OutboundConfig, OutboundPolicy};
+use crate::idempotency::IDEMPOTENCY_KEY_HDR;
+use crate::metrics::OutboundMetrics;
+use crate::telemetry;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboundRequest {
+    pub method: reqwest::Method,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl OutboundRequest {
+    pub fn post_json<T: Serialize>(url: &str, payload: &T) -> serde_json::Result<Self> {
+        Ok(OutboundRequest {
+            method: reqwest::Method::POST,
+            url: url.to_string(),
+            headers: vec![(reqwest::header::CONTENT_TYPE.to_string(), "application/json".to_string())],
+            body: serde_json::to_vec(payload)?,
+        })
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    fn header_value(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboundResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum OutboundError {
+    #[error("Calls to {0} are cut off after failing in a row")]
+    CircuitOpen(String),
+    #[error("No response within {0:?}")]
+    Timeout(Duration),
+    #[error("Request failed: {0}")]
+    Transport(String),
+    #[error("Answered {0}")]
+    Status(u16),
+}
+
+impl OutboundError {
+    // Whether another attempt may succeed where this one failed.
+    fn is_transient(&self) -> bool {
+        match self {
+            OutboundError::Timeout(_) | OutboundError::Transport(_) => true,
+            OutboundError::Status(status) => *status == 408 || *status == 429 || *status >= 500,
+            OutboundError::CircuitOpen(_) => false,
+        }
+    }
+}
+
+// Sends a request and waits for the response, once. `ReqwestTransport` over the network,
+// `MockTransport` in tests.
+#[async_trait]
+pub trait HttpTransport: Send + Sync {
+    async fn send(&self, request: &OutboundRequest, timeout: Duration) -> Result<OutboundResponse, OutboundError>;
+}
+
+pub struct ReqwestTransport {
+    client: reqwest::Client,
+}
+
+impl ReqwestTransport {
+    pub fn new() -> Self {
+        let client = reqwest::Client::builder().build().expect("cannot build the outbound HTTP client");
+        ReqwestTransport { client }
+    }
+}
+
+impl Default for ReqwestTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl HttpTransport for ReqwestTransport {
+    async fn send(&self, request: &OutboundRequest, timeout: Duration) -> Result<OutboundResponse, OutboundError> {
+        let mut builder = self.client.request(request.method.clone(), &request.url).timeout(timeout).body(request.body.clone());
+        for (name, value) in &request.headers {
+            builder = builder.header(name, value);
+        }
+        // The receiver continues the trace of the caller, see `crate::telemetry`.
+        for (name, value) in telemetry::current_context() {
+            builder = builder.header(name, value);
+        }
+        let response = builder.send().await.map_err(|e| match e.is_timeout() {
+            true => OutboundError::Timeout(timeout),
+            false => OutboundError::Transport(e.to_string()),
+        })?;
+        let status = response.status().as_u16();
+        let headers = response
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| value.to_str().ok().map(|value| (name.to_string(), value.to_string())))
+            .collect();
+        let body = response.bytes().await.map_err(|e| OutboundError::Transport(e.to_string()))?;
+        Ok(OutboundResponse { status, headers, body: body.to_vec() })
+    }
+}
+
+// Answers requests from a script, in order, and 200 once it runs out. Keeps the requests
+// it was sent.
+#[derive(Default)]
+pub struct MockTransport {
+    script: Mutex<VecDeque<Result<OutboundResponse, OutboundError>>>,
+    requests: Mutex<Vec<OutboundRequest>>,
+}
+
+impl MockTransport {
+    pub fn then(self, result: Result<u16, OutboundError>) -> Self {
+        let result = result.map(|status| OutboundResponse { status, headers: vec![], body: vec![] });
+        self.script.lock().expect("mock transport poisoned").push_back(result);
+        self
+    }
+
+    pub fn requests(&self) -> Vec<OutboundRequest> {
+        self.requests.lock().expect("mock transport poisoned").clone()
+    }
+}
+
+#[async_trait]
+impl HttpTransport for MockTransport {
+    async fn send(&self, request: &OutboundRequest, _timeout: Duration) -> Result<OutboundResponse, OutboundError> {
+        self.requests.lock().expect("mock transport poisoned").push(request.clone());
+        let next = self.script.lock().expect("mock transport poisoned").pop_front();
+        next.unwrap_or(Ok(OutboundResponse { status: 200, headers: vec![], body: vec![] }))
+    }
+}
+
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+    // A call let through to a destination that was cut off, to try it again.
+    trial: bool,
+}
+
+impl Breaker {
+    fn admit(&mut self, now: Instant) -> bool {
+        match self.open_until {
+            None => true,
+            Some(until) if now < until || self.trial => false,
+            Some(_) => {
+                self.trial = true;
+                true
+            }
+        }
+    }
+
+    fn record(&mut self, policy: &OutboundPolicy, succeeded: bool, now: Instant) {
+        if succeeded {
+            *self = Breaker::default();
+            return;
+        }
+        self.failures += 1;
+        if self.trial || (policy.breaker_threshold > 0 && self.failures >= policy.breaker_threshold) {
+            self.open_until = Some(now + Duration::from_secs(policy.breaker_open_secs));
+            self.trial = false;
+        }
+    }
+}
+
+struct Shared {
+    transport: Arc<dyn HttpTransport>,
+    config: OutboundConfig,
+    // By destination and endpoint, the endpoint empty for breakers of a whole destination.
+    breakers: Mutex<HashMap<(String, String), Breaker>>,
+    metrics: OutboundMetrics,
+}
+
+// The HTTP calls out of the system, e.g. to webhooks, by destination: each attempt is
+// timed out, transient failures are retried with a backoff and a destination failing
+// call after call is cut off for a while, so callers do not pile up behind it, see
+// `OutboundPolicy`. Every attempt of a call carries the same `Idempotency-Key`, so the
+// receiver can tell a retry from a new call. Destinations made of endpoints run by
+// different parties, e.g. webhooks, are cut off endpoint by endpoint instead, see
+// `send_to_endpoint`. Reported by `OutboundMetrics`.
+#[derive(Clone)]
+pub struct OutboundClient {
+    shared: Arc<Shared>,
+}
+
+impl OutboundClient {
+    pub fn new(config: &OutboundConfig, metrics: OutboundMetrics) -> Self {
+        Self::with_transport(config, Arc::new(ReqwestTransport::new()), metrics)
+    }
+
+    pub fn with_transport(config: &OutboundConfig, transport: Arc<dyn HttpTransport>, metrics: OutboundMetrics) -> Self {
+        let shared = Shared { transport, config: config.clone(), breakers: Mutex::new(HashMap::new()), metrics };
+        OutboundClient { shared: Arc::new(shared) }
+    }
+
+    // Calls the destination until it answers with a success, or the call failed for good.
+    pub async fn send(&self, destination: &str, request: OutboundRequest) -> Result<OutboundResponse, OutboundError> {
+        self.call(destination, String::new(), request).await
+    }
+
+    // Like `send`, but only the url called is cut off when it keeps failing, not the
+    // other endpoints of the destination.
+    pub async fn send_to_endpoint(&self, destination: &str, request: OutboundRequest) -> Result<OutboundResponse, OutboundError> {
+        let endpoint = request.url.clone();
+        self.call(destination, endpoint, request).await
+    }
+
+    async fn call(&self, destination: &str, endpoint: String, request: OutboundRequest) -> Result<OutboundResponse, OutboundError> {
+        let policy = self.shared.config.policy(destination);
+        let metrics = &self.shared.metrics;
+        let key = (destination.to_string(), endpoint);
+        if !self.breaker(&key, |breaker| breaker.admit(Instant::now())) {
+            metrics.called(destination, "cut_off", Duration::ZERO);
+            return Err(OutboundError::CircuitOpen(destination.to_string()));
+        }
+        let request = match request.header_value(IDEMPOTENCY_KEY_HDR) {
+            Some(_) => request,
+            None => request.header(IDEMPOTENCY_KEY_HDR, &hex::encode(random::<[u8; 16]>())),
+        };
+        let started = Instant::now();
+        let timeout = Duration::from_millis(policy.timeout_ms);
+        let mut backoff = Duration::from_millis(policy.backoff_ms);
+        let mut attempt = 1;
+        let result = loop {
+            let result = match self.shared.transport.send(&request, timeout).await {
+                Ok(response) if (200..300).contains(&response.status) => Ok(response),
+                Ok(response) => Err(OutboundError::Status(response.status)),
+                Err(e) => Err(e),
+            };
+            match result {
+                Err(e) if e.is_transient() && attempt < policy.attempts => {
+                    tracing::warn!("Calling {} at {} failed (attempt {}): {}", destination, request.url, attempt, e);
+                    metrics.retried(destination);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(Duration::from_millis(policy.max_backoff_ms));
+                    attempt += 1;
+                }
+                result => break result,
+            }
+        };
+        // Only failures of the destination count towards cutting it off, not requests it
+        // turned down.
+        let succeeded = !result.as_ref().is_err_and(OutboundError::is_transient);
+        self.breaker(&key, |breaker| breaker.record(policy, succeeded, Instant::now()));
+        metrics.circuit(destination, self.cut_off(destination));
+        metrics.called(destination, if result.is_ok() { "ok" } else { "failed" }, started.elapsed());
+        result
+    }
+
+    // Breakers back to closed are dropped, so endpoints that come and go do not pile up.
+    fn breaker<T>(&self, key: &(String, String), f: impl FnOnce(&mut Breaker) -> T) -> T {
+        let mut breakers = self.shared.breakers.lock().expect("outbound breakers poisoned");
+        let breaker = breakers.entry(key.clone()).or_default();
+        let result = f(breaker);
+        if breaker.failures == 0 && breaker.open_until.is_none() {
+            breakers.remove(key);
+        }
+        result
+    }
+
+    // How many endpoints of the destination are cut off.
+    fn cut_off(&self, destination: &str) -> usize {
+        let breakers = self.shared.breakers.lock().expect("outbound breakers poisoned");
+        breakers.iter().filter(|((cut_off, _), breaker)| cut_off == destination && breaker.open_until.is_some()).count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use prometheus::{Registry, TextEncoder};
+    use crate::config::{OutboundConfig, OutboundPolicy};
+    use crate::idempotency::IDEMPOTENCY_KEY_HDR;
+    use crate::metrics::OutboundMetrics;
+    use crate::services::outbound::{MockTransport, OutboundClient, OutboundError, OutboundRequest};
+
+    #[tokio::test]
+    async fn test_outbound_calls() {
+        let policy = OutboundPolicy { attempts: 3, backoff_ms: 0, breaker_threshold: 2, breaker_open_secs: 60, ..OutboundPolicy::default() };
+        let config = OutboundConfig { default: policy.clone(), destinations: HashMap::from([("once".to_string(), OutboundPolicy { attempts: 1, ..policy })]) };
+        let transport = Arc::new(
+            MockTransport::default()
+                .then(Err(OutboundError::Transport("connection reset".to_string())))
+                .then(Ok(503))
+                .then(Ok(204))
+                .then(Ok(404))
+                .then(Ok(500))
+                .then(Ok(500))
+                .then(Ok(500)),
+        );
+        let registry = Registry::new();
+        let metrics = OutboundMetrics::default();
+        metrics.register(&registry).unwrap();
+        let client = OutboundClient::with_transport(&config, transport.clone(), metrics);
+        let request = || OutboundRequest::post_json("http://hooks.example/alert", &serde_json::json!({ "threshold": 2 })).unwrap();
+
+        // Transient failures are retried, every attempt with the same key.
+        assert_eq!(client.send("webhooks", request()).await.unwrap().status, 204);
+        let keys: Vec<_> = transport.requests().iter().map(|request| request.header_value(IDEMPOTENCY_KEY_HDR).unwrap().to_string()).collect();
+        assert_eq!(keys.len(), 3);
+        assert!(keys.iter().all(|key| key == &keys[0]));
+        // Requests turned down are not.
+        assert_eq!(client.send("webhooks", request()).await, Err(OutboundError::Status(404)));
+        assert_eq!(transport.requests().len(), 4);
+
+        // Destinations have their own policy and breaker.
+        assert_eq!(client.send("once", request()).await, Err(OutboundError::Status(500)));
+        assert_eq!(client.send("once", request()).await, Err(OutboundError::Status(500)));
+        assert_eq!(client.send("once", request()).await, Err(OutboundError::CircuitOpen("once".to_string())));
+        assert_eq!(transport.requests().len(), 6);
+        // The last 500 is retried, and the script run out answers 200.
+        assert_eq!(client.send("webhooks", request()).await.unwrap().status, 200);
+        assert_eq!(transport.requests().len(), 8);
+
+        let metrics = TextEncoder::new().encode_to_string(&registry.gather()).unwrap();
+        for line in [
+            "outbound_calls_total{destination=\"webhooks\",outcome=\"ok\"} 2",
+            "outbound_calls_total{destination=\"webhooks\",outcome=\"failed\"} 1",
+            "outbound_calls_total{destination=\"once\",outcome=\"failed\"} 2",
+            "outbound_calls_total{destination=\"once\",outcome=\"cut_off\"} 1",
+            "outbound_retries_total{destination=\"webhooks\"} 3",
+            "outbound_circuit_open{destination=\"once\"} 1",
+            "outbound_circuit_open{destination=\"webhooks\"} 0",
+        ] {
+            assert!(metrics.contains(line), "{} missing from\n{}", line, metrics);
+        }
+
+        // Endpoints of a destination such as webhooks are cut off one by one.
+        let transport = Arc::new(MockTransport::default().then(Ok(500)).then(Ok(500)));
+        let registry = Registry::new();
+        let metrics = OutboundMetrics::default();
+        metrics.register(&registry).unwrap();
+        let client = OutboundClient::with_transport(&config, transport.clone(), metrics);
+        let hook = |url| OutboundRequest::post_json(url, &serde_json::json!({ "threshold": 2 })).unwrap();
+        assert_eq!(client.send_to_endpoint("once", hook("http://down.example/hook")).await, Err(OutboundError::Status(500)));
+        assert_eq!(client.send_to_endpoint("once", hook("http://down.example/hook")).await, Err(OutboundError::Status(500)));
+        assert_eq!(client.send_to_endpoint("once", hook("http://down.example/hook")).await, Err(OutboundError::CircuitOpen("once".to_string())));
+        assert_eq!(client.send_to_endpoint("once", hook("http://up.example/hook")).await.unwrap().status, 200);
+        assert_eq!(transport.requests().len(), 3);
+        let metrics = TextEncoder::new().encode_to_string(&registry.gather()).unwrap();
+        assert!(metrics.contains("outbound_circuit_open{destination=\"once\"} 1"), "{}", metrics);
+    }
+}
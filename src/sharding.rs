@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Arc;
+use cqrs_es::{Aggregate, AggregateError, CqrsFramework, EventStore};
+use tokio::sync::{mpsc, oneshot};
+use crate::config::ShardConfig;
+
+type Reply<A> = oneshot::Sender<Result<(), AggregateError<<A as Aggregate>::Error>>>;
+
+struct Job<A: Aggregate> {
+    aggregate_id: String,
+    command: A::Command,
+    metadata: HashMap<String, String>,
+    reply: Reply<A>,
+}
+
+// Executes the commands of an aggregate type on a fixed set of workers, each aggregate on
+// the worker its id hashes to: the commands to one aggregate run one after the other in
+// the order they arrive instead of racing each other to the optimistic lock of the event
+// store, and concurrent commands to overlapping aggregates no longer fail over to retries.
+// Commands of another node can still conflict. A command whose caller went away is carried
+// out anyway, its result dropped. Without workers the commands run on the caller's task.
+pub struct ShardedCqrs<A: Aggregate, ES: EventStore<A>> {
+    cqrs: Arc<CqrsFramework<A, ES>>,
+    shards: Vec<mpsc::Sender<Job<A>>>,
+}
+
+impl<A, ES> ShardedCqrs<A, ES>
+where
+    A: Aggregate + 'static,
+    A::Command: Send,
+    A::Error: Send,
+    ES: EventStore<A> + 'static,
+    ES::AC: Send,
+{
+    pub fn new(cqrs: CqrsFramework<A, ES>, config: &ShardConfig) -> Self {
+        let cqrs = Arc::new(cqrs);
+        let shards = (0..config.workers)
+            .map(|_| {
+                let (sender, receiver) = mpsc::channel(config.queue_depth.max(1));
+                tokio::spawn(Self::work(cqrs.clone(), receiver));
+                sender
+            })
+            .collect();
+        ShardedCqrs { cqrs, shards }
+    }
+
+    pub async fn execute(&self, aggregate_id: &str, command: A::Command) -> Result<(), AggregateError<A::Error>> {
+        self.execute_with_metadata(aggregate_id, command, HashMap::new()).await
+    }
+
+    pub async fn execute_with_metadata(
+        &self,
+        aggregate_id: &str,
+        command: A::Command,
+        metadata: HashMap<String, String>,
+    ) -> Result<(), AggregateError<A::Error>> {
+        if self.shards.is_empty() {
+            return self.cqrs.execute_with_metadata(aggregate_id, command, metadata).await;
+        }
+        let (reply, result) = oneshot::channel();
+        let job = Job { aggregate_id: aggregate_id.to_string(), command, metadata, reply };
+        let shard = &self.shards[shard_of(aggregate_id, self.shards.len())];
+        if shard.send(job).await.is_err() {
+            return Err(AggregateError::UnexpectedError("the shard worker is gone".into()));
+        }
+        // A command panicking drops its reply, the worker carries on with the next one.
+        result.await.unwrap_or_else(|_| Err(AggregateError::UnexpectedError("the command panicked".into())))
+    }
+
+    async fn work(cqrs: Arc<CqrsFramework<A, ES>>, mut jobs: mpsc::Receiver<Job<A>>) {
+        while let Some(job) = jobs.recv().await {
+            let cqrs = cqrs.clone();
+            // Spawned, so a panicking command does not take the worker down with it.
+            let execution = tokio::spawn(async move {
+                let result = cqrs.execute_with_metadata(&job.aggregate_id, job.command, job.metadata).await;
+                let _ = job.reply.send(result);
+            });
+            if let Err(e) = execution.await {
+                tracing::error!("Command to a {} panicked: {}", A::aggregate_type(), e);
+            }
+        }
+    }
+}
+
+fn shard_of(aggregate_id: &str, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    aggregate_id.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use cqrs_es::persist::PersistedEventStore;
+    use cqrs_es::CqrsFramework;
+    use futures::future::join_all;
+    use postgres_es::PostgresEventRepository;
+    use rand::random;
+    use crate::account::aggregate::Account;
+    use crate::account::commands::AccountCommand;
+    use crate::config::ShardConfig;
+    use crate::services::{BankAccountServices, HappyPathBankAccountServices};
+    use crate::sharding::{shard_of, ShardedCqrs};
+    use crate::util::test_db::{random_suffix, test_pool};
+    use crate::util::types::ByteArray32;
+
+    #[test]
+    fn test_shard_of() {
+        let ids: Vec<String> = (0..1000).map(|n| format!("ACCT-{:04}", n)).collect();
+        assert!(ids.iter().all(|id| shard_of(id, 8) == shard_of(id, 8)));
+        let used: HashSet<usize> = ids.iter().map(|id| shard_of(id, 8)).collect();
+        assert_eq!(used.len(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_sharded_commands() {
+        let pool = test_pool();
+        // Without the aggregate cache every command loads its account from the event store.
+        let store = PersistedEventStore::<_, Account>::new_event_store(PostgresEventRepository::new(pool));
+        let services = BankAccountServices::new(Box::new(HappyPathBankAccountServices));
+        let cqrs = CqrsFramework::new(store, vec![], services);
+        let cqrs = Arc::new(ShardedCqrs::new(cqrs, &ShardConfig { workers: 4, queue_depth: 8 }));
+
+        let prefix = format!("SHARD-{}-", random_suffix());
+        let ids: Vec<String> = (0..4).map(|n| format!("{}{}", prefix, n)).collect();
+        for id in &ids {
+            cqrs.execute(id, AccountCommand::account_opened(id.clone())).await.unwrap();
+        }
+        // 128 tasks depositing to overlapping accounts, none of them conflicts.
+        let deposit = |amount| AccountCommand::deposited(ByteArray32(random()), 1, "BTC".to_string(), amount);
+        let withdraw = |amount| AccountCommand::withdrew(ByteArray32(random()), 1, "BTC".to_string(), amount);
+        let deposits = (0..128).map(|n| {
+            let cqrs = cqrs.clone();
+            let id = ids[n % ids.len()].clone();
+            let command = deposit(1);
+            tokio::spawn(async move { cqrs.execute(&id, command).await })
+        });
+        for result in join_all(deposits).await {
+            result.unwrap().unwrap();
+        }
+        for id in &ids {
+            cqrs.execute(id, withdraw(32)).await.unwrap();
+            assert!(cqrs.execute(id, withdraw(1)).await.is_err());
+        }
+    }
+}
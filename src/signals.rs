@@ -0,0 +1,261 @@
+use std::collections::BTreeMap;
+use async_trait::async_trait;
+use cqrs_es::{AggregateError, EventEnvelope, Query};
+use prometheus::{IntCounterVec, Opts, Registry};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres, Row};
+use utoipa::{IntoParams, ToSchema};
+use crate::account::aggregate::Account;
+use crate::account::events::{AccountError, AccountEvent, TransactionEvent};
+use crate::util::clock;
+
+const BUCKET_SECS: u64 = 3600;
+const DEFAULT_WINDOW_SECS: u64 = 24 * 3600;
+const TOP_ACCOUNTS: i64 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+pub enum SignalKind {
+    // A command rejected to a client for a transaction id the account already processed.
+    DuplicateTransaction,
+    // A debit or credit taken back, `DebitReversed` and `CreditReversed`.
+    Reversal,
+    // The undo of a saga step executed, whether by its guard or by the recovery sweep.
+    Compensation,
+}
+
+impl SignalKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            SignalKind::DuplicateTransaction => "DuplicateTransaction",
+            SignalKind::Reversal => "Reversal",
+            SignalKind::Compensation => "Compensation",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "DuplicateTransaction" => Some(SignalKind::DuplicateTransaction),
+            "Reversal" => Some(SignalKind::Reversal),
+            "Compensation" => Some(SignalKind::Compensation),
+            _ => None,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            SignalKind::DuplicateTransaction => "duplicate_transaction",
+            SignalKind::Reversal => "reversal",
+            SignalKind::Compensation => "compensation",
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SignalFilter {
+    // Unix seconds, the last 24 hours by default.
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub kind: Option<SignalKind>,
+}
+
+// Signals counted in the hour starting at `bucket`, in unix seconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct SignalBucket {
+    pub bucket: u64,
+    pub kind: SignalKind,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct AccountSignals {
+    pub account_id: String,
+    pub kind: SignalKind,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct SignalReport {
+    // Start of the first and end of the last hour covered.
+    pub from: u64,
+    pub to: u64,
+    pub totals: BTreeMap<SignalKind, u64>,
+    pub buckets: Vec<SignalBucket>,
+    // The accounts with the most signals of each kind, none in the report of an account.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub top_accounts: Vec<AccountSignals>,
+}
+
+// Counts the duplicate transactions rejected, the reversals and the compensations of
+// each account per hour in `account_signals`, and all of them since the start of the
+// process in `account_signals_total`. Reversals are counted from the events as they are
+// committed, so a projection replay would count them again.
+#[derive(Clone)]
+pub struct Signals {
+    pool: Pool<Postgres>,
+    counter: IntCounterVec,
+}
+
+impl Signals {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        let counter = IntCounterVec::new(
+            Opts::new("account_signals_total", "Duplicate transactions rejected, reversals and compensations"),
+            &["kind"],
+        ).expect("invalid account signal counter");
+        Signals { pool, counter }
+    }
+
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.counter.clone()))
+    }
+
+    pub async fn record(&self, account_id: &str, kind: SignalKind, count: u64) {
+        if count == 0 {
+            return;
+        }
+        self.counter.with_label_values(&[kind.label()]).inc_by(count);
+        let recorded = sqlx::query(
+            "INSERT INTO account_signals (account_id, bucket, kind, count) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (account_id, bucket, kind) DO UPDATE SET count = account_signals.count + EXCLUDED.count",
+        )
+            .bind(account_id)
+            .bind((clock::now() / BUCKET_SECS * BUCKET_SECS) as i64)
+            .bind(kind.as_str())
+            .bind(count as i64)
+            .execute(&self.pool)
+            .await;
+        if let Err(e) = recorded {
+            tracing::error!("Failed to record {} signals of {}: {}", kind.as_str(), account_id, e);
+        }
+    }
+
+    // Counts the duplicate transactions among the reasons a command on the account was
+    // rejected, each batched command on its own.
+    pub async fn rejected(&self, account_id: &str, err: &AggregateError<AccountError>) {
+        let duplicates = match err {
+            AggregateError::UserError(AccountError::DuplicateTransaction(_)) => 1,
+            AggregateError::UserError(AccountError::BatchRejected(rejected)) => rejected
+                .iter()
+                .filter(|(_, error)| matches!(error, AccountError::DuplicateTransaction(_)))
+                .count() as u64,
+            _ => 0,
+        };
+        self.record(account_id, SignalKind::DuplicateTransaction, duplicates).await;
+    }
+
+    // Buckets of all accounts, or of one, within the filter.
+    pub async fn report(&self, account_id: Option<&str>, filter: &SignalFilter) -> Result<SignalReport, sqlx::Error> {
+        let to = filter.to.unwrap_or_else(clock::now);
+        let from = filter.from.unwrap_or(to.saturating_sub(DEFAULT_WINDOW_SECS)) / BUCKET_SECS * BUCKET_SECS;
+        let kind = filter.kind.map(SignalKind::as_str);
+        let rows = sqlx::query(
+            "SELECT bucket, kind, sum(count)::bigint AS count FROM account_signals
+             WHERE bucket >= $1 AND bucket <= $2 AND ($3::text IS NULL OR kind = $3) AND ($4::text IS NULL OR account_id = $4)
+             GROUP BY bucket, kind ORDER BY bucket, kind",
+        )
+            .bind(from as i64)
+            .bind(to as i64)
+            .bind(kind)
+            .bind(account_id)
+            .fetch_all(&self.pool)
+            .await?;
+        let mut buckets = Vec::with_capacity(rows.len());
+        let mut totals = BTreeMap::new();
+        for row in rows {
+            let Some(kind) = SignalKind::parse(row.try_get("kind")?) else {
+                continue;
+            };
+            let count = row.try_get::<i64, _>("count")? as u64;
+            *totals.entry(kind).or_default() += count;
+            buckets.push(SignalBucket { bucket: row.try_get::<i64, _>("bucket")? as u64, kind, count });
+        }
+        let top_accounts = match account_id {
+            Some(_) => Vec::new(),
+            None => self.top_accounts(from, to, kind).await?,
+        };
+        Ok(SignalReport { from, to, totals, buckets, top_accounts })
+    }
+
+    async fn top_accounts(&self, from: u64, to: u64, kind: Option<&str>) -> Result<Vec<AccountSignals>, sqlx::Error> {
+        let rows = sqlx::query(
+            "SELECT account_id, kind, count FROM (
+                 SELECT account_id, kind, sum(count)::bigint AS count,
+                        row_number() OVER (PARTITION BY kind ORDER BY sum(count) DESC, account_id) AS rank
+                 FROM account_signals
+                 WHERE bucket >= $1 AND bucket <= $2 AND ($3::text IS NULL OR kind = $3)
+                 GROUP BY account_id, kind
+             ) ranked WHERE rank <= $4 ORDER BY kind, rank",
+        )
+            .bind(from as i64)
+            .bind(to as i64)
+            .bind(kind)
+            .bind(TOP_ACCOUNTS)
+            .fetch_all(&self.pool)
+            .await?;
+        let mut accounts = Vec::with_capacity(rows.len());
+        for row in rows {
+            let Some(kind) = SignalKind::parse(row.try_get("kind")?) else {
+                continue;
+            };
+            accounts.push(AccountSignals {
+                account_id: row.try_get("account_id")?,
+                kind,
+                count: row.try_get::<i64, _>("count")? as u64,
+            });
+        }
+        Ok(accounts)
+    }
+}
+
+#[async_trait]
+impl Query<Account> for Signals {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<Account>]) {
+        let reversals = events
+            .iter()
+            .filter(|event| matches!(
+                &event.payload,
+                AccountEvent::Transaction { event: TransactionEvent::DebitReversed { .. } | TransactionEvent::CreditReversed { .. }, .. },
+            ))
+            .count() as u64;
+        self.record(aggregate_id, SignalKind::Reversal, reversals).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::AggregateError;
+    use crate::account::events::AccountError;
+    use crate::signals::{SignalFilter, SignalKind, Signals};
+    use crate::util::test_db::{delete_prefixed, random_suffix, test_pool};
+
+    #[tokio::test]
+    async fn test_signal_report() {
+        let pool = test_pool();
+        let signals = Signals::new(pool.clone());
+        let prefix = format!("SIGNAL-{}", random_suffix());
+        let (noisy, quiet) = (format!("{}-NOISY", prefix), format!("{}-QUIET", prefix));
+
+        signals.rejected(&noisy, &AggregateError::UserError(AccountError::DuplicateTransaction(1))).await;
+        signals.rejected(&noisy, &AggregateError::UserError(AccountError::BatchRejected(vec![
+            (0, AccountError::DuplicateTransaction(1)),
+            (1, AccountError::InsufficientFunds),
+            (2, AccountError::DuplicateTransaction(1)),
+        ]))).await;
+        signals.rejected(&quiet, &AggregateError::UserError(AccountError::InsufficientFunds)).await;
+        signals.record(&quiet, SignalKind::Compensation, 1).await;
+
+        let report = signals.report(Some(&noisy), &SignalFilter::default()).await.unwrap();
+        assert_eq!(report.totals.into_iter().collect::<Vec<_>>(), vec![(SignalKind::DuplicateTransaction, 3)]);
+        assert_eq!(report.buckets.len(), 1);
+        assert!(report.top_accounts.is_empty());
+        let report = signals.report(Some(&quiet), &SignalFilter { kind: Some(SignalKind::DuplicateTransaction), ..Default::default() }).await.unwrap();
+        assert!(report.buckets.is_empty());
+
+        let report = signals.report(None, &SignalFilter::default()).await.unwrap();
+        assert!(report.totals[&SignalKind::DuplicateTransaction] >= 3);
+        assert!(report.top_accounts.iter().any(|top| top.account_id == quiet && top.kind == SignalKind::Compensation));
+        assert_eq!(signals.counter.with_label_values(&["duplicate_transaction"]).get(), 3);
+
+        delete_prefixed(&pool, "account_signals", "account_id", &prefix).await;
+    }
+}
@@ -8,9 +8,11 @@ use futures::{Stream, StreamExt, TryFutureExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use sqlx::{query, Pool, Postgres};
 use stm::TVar;
-use tokio::sync::oneshot;
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinHandle;
 use tokio::time::sleep;
 use tokio_stream::wrappers::ReceiverStream;
+use crate::config::BatchingConfig;
 use crate::util::types::ByteArray32;
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
@@ -42,13 +44,6 @@ impl Default for Balance {
     }
 }
 
-impl Balance {
-    fn new(value: u64) -> Self {
-        Balance(TVar::new(value))
-    }
-}
-
-
 #[derive(Default)]
 pub struct Account {
     pub assets: StdMutex<BTreeMap<AssetID, Balance>>,
@@ -122,7 +117,7 @@ impl Account {
 }
 
 #[derive(Serialize, Deserialize, PartialEq, PartialOrd, Eq, Ord, Clone)]
-pub struct AccountID(String);
+pub struct AccountID(pub String);
 
 pub struct AccountBook {
     pub accounts: StdMutex<BTreeMap<AccountID, Arc<Account>>>,
@@ -147,12 +142,17 @@ impl AccountBook {
             .clone()
     }
 
+    // Persists the transactions still queued, see `PostgresStore::close`.
+    pub async fn close(&self) {
+        self.store.close().await;
+    }
+
     pub async fn deposit(&self,
                          txid: ByteArray32,
                          account_id: &AccountID,
                          asset: AssetID,
                          amount: u64) {
-        let account = self.get(&account_id);
+        let account = self.get(account_id);
         let tx = Transaction {
             id: txid,
             data: TransactionData::Deposit {
@@ -276,6 +276,10 @@ pub enum TransactionData {
     },
 }
 
+pub type ItemStream<'a, T, E> = Pin<Box<dyn Stream<Item = Result<T, E>> + Send + 'a>>;
+
+type Promise = oneshot::Sender<Result<(), Arc<sqlx::Error>>>;
+
 pub trait Store {
     type Item: Send;
     type Error;
@@ -286,30 +290,55 @@ pub trait Store {
     }
 
     fn persist_all<I: IntoIterator<Item = Self::Item> + Send>(&self, items: I) -> impl Future<Output = Result<u64, Self::Error>> + Send;
-    fn load_all(&self) -> Pin<Box<dyn Stream<Item = Result<Self::Item, Self::Error>> + Send + '_>>;
+    fn load_all(&self) -> ItemStream<'_, Self::Item, Self::Error>;
 }
 
 #[derive(Clone)]
 pub struct PostgresStore {
     pool: Pool<Postgres>,
-    tx: tokio::sync::mpsc::Sender<(Transaction, tokio::sync::oneshot::Sender<Result<(), Arc<sqlx::Error>>>)>,
+    tx: tokio::sync::mpsc::Sender<(Transaction, Promise)>,
+    // Tells the flusher to drain the queue and stop, see `close`.
+    closing: Arc<Notify>,
+    flusher: Arc<StdMutex<Option<JoinHandle<()>>>>,
+    max_batch: usize,
 }
 
 impl PostgresStore {
     pub fn new(pool: Pool<Postgres>) -> Self {
-        let (tx, rx) = tokio::sync::mpsc::channel(1024);
+        Self::with_batching(pool, &BatchingConfig::default())
+    }
+
+    pub fn with_batching(pool: Pool<Postgres>, config: &BatchingConfig) -> Self {
+        let (tx, rx) = tokio::sync::mpsc::channel(config.queue_capacity.max(1));
         let this = Self {
             pool,
-            tx
+            tx,
+            max_batch: config.max_batch.max(1),
+            closing: Arc::new(Notify::new()),
+            flusher: Default::default(),
         };
 
         let bind = this.clone();
-        tokio::spawn(async move {
+        let flusher = tokio::spawn(async move {
             bind.background(rx).await;
         });
+        *this.flusher.lock().expect("Failed to lock flusher") = Some(flusher);
         this
     }
 
+    // Flushes the transactions already queued, then closes the pool. Transactions
+    // persisted afterwards fail.
+    pub async fn close(&self) {
+        self.closing.notify_one();
+        let flusher = self.flusher.lock().expect("Failed to lock flusher").take();
+        if let Some(flusher) = flusher {
+            if let Err(e) = flusher.await {
+                tracing::error!("Transaction flusher failed: {:?}", e);
+            }
+        }
+        self.pool.close().await;
+    }
+
     async fn flush<I: IntoIterator<Item=Transaction>>(&self, items: I) -> Result<u64, sqlx::Error> {
         let (ids, data): (Vec<String>, Vec<Vec<u8>>) = items
             .into_iter()
@@ -335,16 +364,27 @@ impl PostgresStore {
 
     async fn enqueue(&self, item: Transaction) -> Result<(), Arc<sqlx::Error>> {
         let (tx, rx) = oneshot::channel();
-        let _ = self.tx.send((item, tx)).await.expect("Failed to send transaction to queue");
+        self.tx.send((item, tx)).await.map_err(|_| Arc::new(sqlx::Error::PoolClosed))?;
         rx.await.expect("Failed to receive transaction response")
     }
 
-    async fn background(&self, rx: tokio::sync::mpsc::Receiver<(Transaction, tokio::sync::oneshot::Sender<Result<(), Arc<sqlx::Error>>>)>) {
+    async fn background(&self, rx: tokio::sync::mpsc::Receiver<(Transaction, Promise)>) {
         let stream: ReceiverStream<_> = rx.into();
-        let mut chunked = stream.ready_chunks(1024);
-
-        while let Some(chunks) = chunked.next().await {
-            let (items, promises): (Vec<Transaction>, Vec<oneshot::Sender<Result<(), Arc<sqlx::Error>>>>) = chunks.into_iter().unzip();
+        let mut chunked = stream.ready_chunks(self.max_batch);
+
+        loop {
+            let chunks = tokio::select! {
+                chunks = chunked.next() => chunks,
+                _ = self.closing.notified() => {
+                    // No more transactions are taken, those queued are still flushed.
+                    chunked.get_mut().close();
+                    chunked.next().await
+                },
+            };
+            let Some(chunks) = chunks else {
+                break;
+            };
+            let (items, promises): (Vec<Transaction>, Vec<Promise>) = chunks.into_iter().unzip();
             let res = self.flush(items).await.map(|_| ()).map_err(Arc::new);
             for p in promises {
                 let _ = p.send(res.clone());
@@ -362,10 +402,10 @@ impl Store for PostgresStore {
     }
 
     async fn persist_all<I: IntoIterator<Item=Self::Item>>(&self, items: I) -> Result<u64, Self::Error> {
-        todo!()
+        self.flush(items).await.map_err(Arc::new)
     }
 
-    fn load_all(&self) -> Pin<Box<dyn Stream<Item = Result<Self::Item, Self::Error>> + Send + '_>> {
+    fn load_all(&self) -> ItemStream<'_, Self::Item, Self::Error> {
         let stream = query!("SELECT id, data FROM transactions")
             .fetch(&self.pool)
             .map_ok(|row| {
@@ -390,10 +430,42 @@ mod test {
     use rand::{random, Rng};
     use sqlx::postgres::PgPoolOptions;
 
-    use crate::{simple::{AccountBook, AccountID, PostgresStore}, util::types::ByteArray32};
+    use crate::{simple::{AccountBook, AccountID, PostgresStore, Store, Transaction, TransactionData}, util::test_db::test_pool, util::types::ByteArray32};
 
     use super::Error;
 
+    #[tokio::test]
+    async fn test_close_drains_queue() {
+        let store = PostgresStore::new(test_pool());
+        let ids: Vec<ByteArray32> = (0..100).map(|_| ByteArray32(random())).collect();
+        let mut promises = vec![];
+        for id in &ids {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            let data = TransactionData::Unlock { id: *id };
+            store.tx.send((Transaction { id: *id, data }, tx)).await.unwrap();
+            promises.push(rx);
+        }
+        store.close().await;
+        for promise in promises {
+            promise.await.unwrap().unwrap();
+        }
+        assert!(store.persist(Transaction { id: ids[0], data: TransactionData::Unlock { id: ids[0] } }).await.is_err());
+
+        let pool = test_pool();
+        let hex_ids: Vec<String> = ids.iter().map(|id| hex::encode(id.0)).collect();
+        let persisted: i64 = sqlx::query_scalar("SELECT count(*) FROM transactions WHERE id = ANY($1)")
+            .bind(&hex_ids)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(persisted, 100);
+        sqlx::query("DELETE FROM transactions WHERE id = ANY($1)")
+            .bind(&hex_ids)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
 
     #[tokio::test]
     async fn test() {
@@ -409,16 +481,16 @@ mod test {
             store: PostgresStore::new(pool)
         });
 
-        let BTC = "BTC".parse().expect("Failed to parse asset");
-        let ETH = "ETH".parse().expect("Failed to parse asset");
+        let btc = "BTC".parse().expect("Failed to parse asset");
+        let eth = "ETH".parse().expect("Failed to parse asset");
 
         for i in 0..1000 {
             let account_id = AccountID(format!("ACCT-{:04}", i));
             let txid = ByteArray32(random());
             let amount = rand::thread_rng().gen_range(10_000u64..1_000_000u64);
-            book.deposit(txid, &account_id, BTC, amount).await;
+            book.deposit(txid, &account_id, btc, amount).await;
             let amount = rand::thread_rng().gen_range(10_000u64..1_000_000u64);
-            book.deposit(txid, &account_id, ETH, amount).await;
+            book.deposit(txid, &account_id, eth, amount).await;
         }
     
         let start = Instant::now();
@@ -451,13 +523,13 @@ mod test {
     async fn order(book: &AccountBook, 
                    seller: &AccountID, 
                    buyer: &AccountID) -> Result<(), Error> {
-        let BTC = "BTC".parse().expect("Failed to parse asset");
-        let ETH = "ETH".parse().expect("Failed to parse asset");
+        let btc = "BTC".parse().expect("Failed to parse asset");
+        let eth = "ETH".parse().expect("Failed to parse asset");
         let txid = ByteArray32(random());
         let sell_amount = rand::thread_rng().gen_range(1u64..100u64);
         let buy_amount = rand::thread_rng().gen_range(1u64..100u64);
-        book.transfer(txid, seller, buyer, BTC, sell_amount).await?;
+        book.transfer(txid, seller, buyer, btc, sell_amount).await?;
         let txid = ByteArray32(random());
-        book.transfer(txid, buyer, seller, ETH, buy_amount).await
+        book.transfer(txid, buyer, seller, eth, buy_amount).await
     }
 }
\ No newline at end of file
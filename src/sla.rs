@@ -0,0 +1,240 @@
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use crate::state::ApplicationState;
+
+// p99 is not meaningful on a handful of requests.
+const MIN_SAMPLES: usize = 20;
+// Bounds the memory and the cost of a p99 evaluation on busy routes.
+const MAX_SAMPLES: usize = 2000;
+const EVALUATION_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RoutePriority {
+    Low = 0,
+    Normal = 1,
+    Critical = 2,
+}
+
+impl FromStr for RoutePriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(RoutePriority::Low),
+            "normal" => Ok(RoutePriority::Normal),
+            "critical" => Ok(RoutePriority::Critical),
+            other => Err(format!("unknown route priority {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoutePolicy {
+    pub priority: RoutePriority,
+    // Routes without a budget are never measured, e.g. long lived streams.
+    pub budget: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SlaConfig {
+    pub window: Duration,
+    // Keyed by `<METHOD> <route>`, with the route as registered, e.g. `POST /account/:account_id`.
+    pub routes: HashMap<String, RoutePolicy>,
+}
+
+impl SlaConfig {
+    fn policy(&self, route: &str) -> RoutePolicy {
+        self.routes.get(route).copied().unwrap_or(RoutePolicy { priority: RoutePriority::Normal, budget: None })
+    }
+
+    // Parses `POST /account/:account_id=critical:250;GET /rfq=low` (budget in milliseconds,
+    // optional) on top of the current policies.
+    pub fn apply_overrides(&mut self, overrides: &str) -> Result<(), String> {
+        for entry in overrides.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (route, policy) = entry.rsplit_once('=').ok_or(format!("missing '=' in {}", entry))?;
+            let (priority, budget) = match policy.split_once(':') {
+                Some((priority, budget)) => {
+                    let budget = budget.parse::<u64>().map_err(|_| format!("invalid budget in {}", entry))?;
+                    (priority, Some(Duration::from_millis(budget)))
+                },
+                None => (policy, None),
+            };
+            self.routes.insert(route.trim().to_string(), RoutePolicy { priority: priority.parse()?, budget });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct RouteSamples {
+    samples: VecDeque<(Instant, Duration)>,
+}
+
+impl RouteSamples {
+    fn record(&mut self, now: Instant, latency: Duration) {
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((now, latency));
+    }
+
+    fn p99(&mut self, now: Instant, window: Duration) -> Option<Duration> {
+        while self.samples.front().is_some_and(|(at, _)| now.duration_since(*at) > window) {
+            self.samples.pop_front();
+        }
+        if self.samples.len() < MIN_SAMPLES {
+            return None;
+        }
+        let mut latencies: Vec<Duration> = self.samples.iter().map(|(_, latency)| *latency).collect();
+        latencies.sort_unstable();
+        Some(latencies[(latencies.len() * 99).div_ceil(100) - 1])
+    }
+}
+
+struct Measurements {
+    routes: HashMap<String, RouteSamples>,
+    evaluated_at: Instant,
+}
+
+// Sheds lower priority routes while a higher priority one is over its latency budget:
+// when the p99 of a route over the rolling window exceeds its budget, every route with
+// a strictly lower priority is answered with 503 until the p99 recovers. Critical
+// routes (deposits, withdrawals) are therefore never shed.
+#[derive(Clone)]
+pub struct LoadShedder {
+    config: Arc<SlaConfig>,
+    measurements: Arc<Mutex<Measurements>>,
+    // Routes with a priority below this level are shed, `Low` sheds nothing.
+    shed_below: Arc<AtomicU8>,
+}
+
+impl LoadShedder {
+    pub fn new(config: SlaConfig) -> Self {
+        LoadShedder {
+            config: Arc::new(config),
+            measurements: Arc::new(Mutex::new(Measurements { routes: HashMap::new(), evaluated_at: Instant::now() })),
+            shed_below: Arc::new(AtomicU8::new(RoutePriority::Low as u8)),
+        }
+    }
+
+    pub fn should_shed(&self, route: &str) -> bool {
+        (self.config.policy(route).priority as u8) < self.shed_below.load(Ordering::Relaxed)
+    }
+
+    pub fn record(&self, route: &str, latency: Duration) {
+        self.record_at(route, latency, Instant::now());
+    }
+
+    fn record_at(&self, route: &str, latency: Duration, now: Instant) {
+        if self.config.policy(route).budget.is_none() {
+            return;
+        }
+        let mut measurements = self.measurements.lock().expect("latency measurements poisoned");
+        measurements.routes.entry(route.to_string()).or_default().record(now, latency);
+        if now.duration_since(measurements.evaluated_at) >= EVALUATION_INTERVAL {
+            measurements.evaluated_at = now;
+            self.evaluate(&mut measurements, now);
+        }
+    }
+
+    fn evaluate(&self, measurements: &mut Measurements, now: Instant) {
+        let mut shed_below = RoutePriority::Low;
+        let mut reason = String::new();
+        for (route, samples) in measurements.routes.iter_mut() {
+            let policy = self.config.policy(route);
+            let (Some(budget), Some(p99)) = (policy.budget, samples.p99(now, self.config.window)) else {
+                continue;
+            };
+            if p99 > budget && policy.priority > shed_below {
+                shed_below = policy.priority;
+                reason = format!("{} p99 {:?} is over its budget of {:?}", route, p99, budget);
+            }
+        }
+        let previous = self.shed_below.swap(shed_below as u8, Ordering::Relaxed);
+        if previous == shed_below as u8 {
+            return;
+        }
+        if shed_below == RoutePriority::Low {
+            tracing::info!("All routes are within their latency budgets, stopped shedding");
+        } else {
+            tracing::warn!("{}, shedding routes below {:?}", reason, shed_below);
+        }
+    }
+}
+
+pub async fn sla_layer(State(state): State<ApplicationState>, request: Request, next: Next) -> Response {
+    let Some(path) = request.extensions().get::<MatchedPath>() else {
+        return next.run(request).await;
+    };
+    let route = format!("{} {}", request.method(), path.as_str());
+    if state.load_shedder.should_shed(&route) {
+        return (StatusCode::SERVICE_UNAVAILABLE, [(header::RETRY_AFTER, "1")], "Temporarily shedding low priority requests").into_response();
+    }
+    let started = Instant::now();
+    let response = next.run(request).await;
+    state.load_shedder.record(&route, started.elapsed());
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+    use crate::sla::{LoadShedder, RoutePolicy, RoutePriority, SlaConfig};
+
+    fn shedder() -> LoadShedder {
+        let mut config = SlaConfig { window: Duration::from_secs(10), routes: HashMap::new() };
+        config.apply_overrides("POST /account/:account_id=critical:100; GET /rfq=low; GET /order/:order_id=normal:50").unwrap();
+        LoadShedder::new(config)
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        let shedder = shedder();
+        assert_eq!(
+            shedder.config.routes["POST /account/:account_id"],
+            RoutePolicy { priority: RoutePriority::Critical, budget: Some(Duration::from_millis(100)) }
+        );
+        assert_eq!(shedder.config.routes["GET /rfq"].budget, None);
+        let mut config = SlaConfig { window: Duration::from_secs(10), routes: HashMap::new() };
+        assert!(config.apply_overrides("GET /rfq=urgent").is_err());
+    }
+
+    #[test]
+    fn test_sheds_lower_priorities_while_over_budget() {
+        let shedder = shedder();
+        let start = Instant::now();
+        for i in 0..50 {
+            shedder.record_at("POST /account/:account_id", Duration::from_millis(150), start + Duration::from_millis(i * 30));
+        }
+        assert!(shedder.should_shed("GET /rfq"));
+        assert!(shedder.should_shed("GET /order/:order_id"));
+        assert!(shedder.should_shed("GET /asset/:symbol"));
+        assert!(!shedder.should_shed("POST /account/:account_id"));
+
+        // Once the slow samples leave the window the routes are served again.
+        let later = start + Duration::from_secs(30);
+        for i in 0..50 {
+            shedder.record_at("POST /account/:account_id", Duration::from_millis(10), later + Duration::from_millis(i * 30));
+        }
+        assert!(!shedder.should_shed("GET /rfq"));
+    }
+
+    #[test]
+    fn test_normal_route_over_budget_only_sheds_low() {
+        let shedder = shedder();
+        let start = Instant::now();
+        for i in 0..50 {
+            shedder.record_at("GET /order/:order_id", Duration::from_millis(80), start + Duration::from_millis(i * 30));
+        }
+        assert!(shedder.should_shed("GET /rfq"));
+        assert!(!shedder.should_shed("GET /asset/:symbol"));
+    }
+}
@@ -0,0 +1,222 @@
+use std::sync::Arc;
+use cqrs_es::persist::{GenericQuery, PersistedEventStore};
+use cqrs_es::{CqrsFramework, Query};
+use sqlite_es::{SqliteCqrs, SqliteEventRepository, SqliteViewRepository};
+use sqlx::{Pool, Sqlite};
+use crate::account::aggregate::Account;
+use crate::account::queries::AccountView;
+use crate::account::stats::AccountStats;
+use crate::aggregate_cache::{AggregateCache, CachedEventStore};
+use crate::asset::aggregate::Asset;
+use crate::asset::queries::AssetView;
+use crate::config::{outbox_config, AppConfig, ConfigError, DuplicateStore, EventStoreConfig};
+use crate::field_encryption::{EncryptedEventRepository, FieldCipher};
+use crate::metrics::{AggregateCacheMetrics, SagaMetrics};
+use crate::order::aggregate::{Order, OrderServices};
+use crate::order::queries::OrderView;
+use crate::retry::RetryingExecutor;
+use crate::sealing::{SealedEventRepository, SealedEventStore, Sealer};
+use crate::services::{BankAccountServices, RegistryBankAccountServices};
+use crate::sharding::ShardedCqrs;
+use crate::transfer::aggregate::{Transfer, TransferServices};
+use crate::transfer::queries::TransferView;
+
+// The SQLite counterparts of the constructors in `crate::config`, for running the account,
+// transfer and order aggregates embedded, without a Postgres server. Only the queries that
+// need no more than a view table come along: there is no ledger, journal, fee collection,
+// outbox, order book or matching here, and accounts never overdraw. Snapshots are sealed
+// and personal data in transfer events encrypted as in Postgres, views are not sealed.
+// Settings of what is left out are turned down, see `check_config`, rather than ignored.
+
+pub type SqliteSealedCqrs<A> = ShardedCqrs<A, SealedEventStore<SqliteEventRepository, A>>;
+pub type SqliteEncryptedCqrs<A> = CqrsFramework<A, PersistedEventStore<EncryptedEventRepository<SqliteEventRepository>, A>>;
+pub type SqliteAccountViewRepository = SqliteViewRepository<AccountView, Account>;
+pub type SqliteAccountStatsRepository = SqliteViewRepository<AccountStats, Account>;
+pub type SqliteAccountFramework = (Arc<SqliteSealedCqrs<Account>>, Arc<SqliteAccountViewRepository>, Arc<SqliteAccountStatsRepository>);
+
+// Opens the database, creating it when missing, and applies `db/sqlite.sql`.
+pub async fn connect(config: &EventStoreConfig) -> Result<Pool<Sqlite>, sqlx::Error> {
+    let pool = sqlite_es::default_sqlite_pool(&config.sqlite_url).await;
+    sqlx::raw_sql(include_str!("../db/sqlite.sql")).execute(&pool).await?;
+    Ok(pool)
+}
+
+// Fails on the settings of features needing the tables or the notifications of Postgres,
+// their projections are not run here: fees would be debited without ever being collected,
+// txids past the window not remembered and events never published.
+pub fn check_config(config: &AppConfig) -> Result<(), ConfigError> {
+    let unsupported = [
+        (!config.fees.collection_account.is_empty(), "fees.collection_account"),
+        (config.duplicate_detection.store == DuplicateStore::Table, "duplicate_detection.store = \"table\""),
+        (config.cache_invalidation.enabled, "cache_invalidation.enabled"),
+        (config.canary.percent > 0, "canary.percent"),
+        (config.business_day.enabled, "business_day.enabled"),
+        (config.batching.enabled, "batching.enabled"),
+        (outbox_config().is_some(), "KAFKA_BROKERS"),
+    ];
+    match unsupported.iter().filter(|(set, _)| *set).map(|(_, setting)| *setting).collect::<Vec<_>>() {
+        settings if settings.is_empty() => Ok(()),
+        settings => Err(ConfigError::Invalid(format!("the sqlite event store does not support {}", settings.join(", ")))),
+    }
+}
+
+pub fn asset_cqrs_framework(pool: Pool<Sqlite>, config: &AppConfig) -> (Arc<SqliteCqrs<Asset>>, Arc<SqliteViewRepository<AssetView, Asset>>) {
+    let simple_query = crate::asset::queries::SimpleLoggingQuery {};
+
+    let asset_view_repo = Arc::new(SqliteViewRepository::new("asset_query", pool.clone()));
+    let mut asset_query = GenericQuery::new(asset_view_repo.clone());
+    asset_query.use_error_handler(Box::new(|e| println!("{}", e)));
+
+    let queries: Vec<Box<dyn Query<Asset>>> = vec![Box::new(simple_query), Box::new(asset_query)];
+
+    (
+        Arc::new(sqlite_es::sqlite_snapshot_cqrs(
+            pool, queries, config.snapshots.interval("asset"), (),
+        )),
+        asset_view_repo,
+    )
+}
+
+pub fn account_cqrs_framework(
+    pool: Pool<Sqlite>,
+    config: &AppConfig,
+    asset_query: Arc<SqliteViewRepository<AssetView, Asset>>,
+) -> Result<SqliteAccountFramework, ConfigError> {
+    check_config(config)?;
+    let simple_query = crate::account::queries::SimpleLoggingQuery {};
+
+    let sealer = Sealer::new(&config.sealing).expect("invalid sealing keys");
+    let account_view_repo = Arc::new(SqliteViewRepository::new("account_query", pool.clone()));
+    let mut account_query = GenericQuery::new(account_view_repo.clone());
+    account_query.use_error_handler(Box::new(|e| println!("{}", e)));
+
+    let stats_view_repo = Arc::new(SqliteViewRepository::new("account_stats_query", pool.clone()));
+    let mut stats_query = GenericQuery::new(stats_view_repo.clone());
+    stats_query.use_error_handler(Box::new(|e| println!("{}", e)));
+
+    let queries: Vec<Box<dyn Query<Account>>> = vec![Box::new(simple_query), Box::new(account_query), Box::new(stats_query)];
+    let services = BankAccountServices::new(Box::new(RegistryBankAccountServices::new(asset_query)))
+        .with_duplicate_detection(config.duplicate_detection.clone())
+        .with_fees(config.fees.clone())
+        .with_quarantine(config.quarantine.clone())
+        .with_command_policy(config.command_policy.clone())
+        .with_max_backdate_days(config.business_day.max_backdate_days)
+        .with_closure_grace_secs(config.account_closure.grace_secs);
+    let repo = SealedEventRepository::new(SqliteEventRepository::new(pool), sealer);
+    let snapshot_size = config.snapshots.interval("account");
+    // Only this process writes to the database, the cache is never stale.
+    let cache = AggregateCache::new(&config.aggregate_cache, AggregateCacheMetrics::default());
+    let store = CachedEventStore::new(PersistedEventStore::new_snapshot_store(repo, snapshot_size), cache, Some(snapshot_size));
+
+    Ok((
+        Arc::new(ShardedCqrs::new(CqrsFramework::new(store, queries, services), &config.shards)),
+        account_view_repo,
+        stats_view_repo,
+    ))
+}
+
+pub fn transfer_cqrs_framework(pool: Pool<Sqlite>, config: &AppConfig, account_cqrs: Arc<SqliteSealedCqrs<Account>>) -> (Arc<SqliteEncryptedCqrs<Transfer>>, Arc<SqliteViewRepository<TransferView, Transfer>>) {
+    let simple_query = crate::transfer::queries::SimpleLoggingQuery {};
+
+    let transfer_view_repo = Arc::new(SqliteViewRepository::new("transfer_query", pool.clone()));
+    let mut transfer_query = GenericQuery::new(transfer_view_repo.clone());
+    transfer_query.use_error_handler(Box::new(|e| println!("{}", e)));
+
+    let queries: Vec<Box<dyn Query<Transfer>>> = vec![Box::new(simple_query), Box::new(transfer_query)];
+    let services = TransferServices::new(Arc::new(RetryingExecutor::new(account_cqrs, config.conflict_retry.clone())), config.transfer.timeout_secs)
+        .with_command_policy(config.command_policy.clone());
+
+    let cipher = FieldCipher::new(&config.field_encryption).expect("invalid field encryption keys");
+    let repo = EncryptedEventRepository::new(SqliteEventRepository::new(pool), cipher);
+    let store = PersistedEventStore::new_snapshot_store(repo, config.snapshots.interval("transfer"));
+
+    (
+        Arc::new(CqrsFramework::new(store, queries, services)),
+        transfer_view_repo,
+    )
+}
+
+pub fn order_cqrs_framework(pool: Pool<Sqlite>, config: &AppConfig, account_cqrs: Arc<SqliteSealedCqrs<Account>>, saga_metrics: SagaMetrics) -> (Arc<SqliteCqrs<Order>>, Arc<SqliteViewRepository<OrderView, Order>>) {
+    let simple_query = crate::order::queries::SimpleLoggingQuery {};
+
+    let order_view_repo = Arc::new(SqliteViewRepository::new("order_query", pool.clone()));
+    let mut order_query = GenericQuery::new(order_view_repo.clone());
+    order_query.use_error_handler(Box::new(|e| println!("{}", e)));
+
+    let queries: Vec<Box<dyn Query<Order>>> = vec![Box::new(simple_query), Box::new(order_query)];
+    let services = OrderServices::new(Arc::new(RetryingExecutor::new(account_cqrs, config.conflict_retry.clone()))).with_metrics("order", saga_metrics);
+
+    (
+        Arc::new(sqlite_es::sqlite_snapshot_cqrs(
+            pool, queries, config.snapshots.interval("order"), services,
+        )),
+        order_view_repo,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use cqrs_es::persist::ViewRepository;
+    use rand::random;
+    use crate::account::commands::{AccountCommand, LifecycleCommand, TransactionCommand};
+    use crate::asset::commands::AssetCommand;
+    use crate::config::{AppConfig, ConfigError, EventStoreConfig};
+    use crate::metrics::SagaMetrics;
+    use crate::sqlite::{account_cqrs_framework, asset_cqrs_framework, connect, order_cqrs_framework, transfer_cqrs_framework};
+    use crate::transfer::commands::TransferCommand;
+    use crate::util::types::ByteArray32;
+
+    #[tokio::test]
+    async fn test_transfer_on_sqlite() {
+        let path = std::env::temp_dir().join(format!("cqrs-account-{}.db", hex::encode(random::<[u8; 8]>())));
+        let store = EventStoreConfig { sqlite_url: format!("sqlite://{}", path.display()), ..EventStoreConfig::default() };
+        let pool = connect(&store).await.unwrap();
+        // The schema is applied on every connect.
+        connect(&store).await.unwrap();
+        let config = AppConfig::default();
+
+        let (asset_cqrs, asset_query) = asset_cqrs_framework(pool.clone(), &config);
+        let (account_cqrs, account_query, _) = account_cqrs_framework(pool.clone(), &config, asset_query).unwrap();
+        let (transfer_cqrs, transfer_query) = transfer_cqrs_framework(pool.clone(), &config, account_cqrs.clone());
+        let _ = order_cqrs_framework(pool.clone(), &config, account_cqrs.clone(), SagaMetrics::default());
+
+        asset_cqrs.execute("USDT", AssetCommand::Register { symbol: "USDT".to_string(), decimals: 6, timestamp: 1 }).await.unwrap();
+        for account_id in ["A", "B"] {
+            account_cqrs.execute(account_id, AccountCommand::Lifecycle(LifecycleCommand::Open { account_id: account_id.to_string() })).await.unwrap();
+        }
+        let deposit = TransactionCommand::Deposit { asset: "USDT".to_string(), amount: 100, source: None, value_date: None };
+        let txid = ByteArray32::derive("test", "deposit");
+        account_cqrs.execute("A", AccountCommand::Transaction { timestamp: 1, txid, command: deposit, labels: Default::default() }).await.unwrap();
+
+        let transfer_id = ByteArray32::derive("test", "transfer");
+        let open = TransferCommand::Open {
+            transfer_id,
+            from_account: "A".to_string(),
+            to_account: "B".to_string(),
+            asset: "USDT".to_string(),
+            amount: 40,
+            timestamp: 2,
+            description: String::new(),
+        };
+        transfer_cqrs.execute(&transfer_id.hex(), open).await.unwrap();
+        transfer_cqrs.execute(&transfer_id.hex(), TransferCommand::Continue).await.unwrap();
+
+        let transfer = serde_json::to_value(transfer_query.load(&transfer_id.hex()).await.unwrap().unwrap()).unwrap();
+        assert_eq!(transfer["is_done"], true);
+        for (account_id, balance) in [("A", 60), ("B", 40)] {
+            let account = serde_json::to_value(account_query.load(account_id).await.unwrap().unwrap()).unwrap();
+            assert_eq!(account["balance"]["USDT"], balance);
+        }
+
+        // Fees would never be collected, no ledger sweeps them.
+        let (_, asset_query) = asset_cqrs_framework(pool.clone(), &config);
+        let mut with_fees = AppConfig::default();
+        with_fees.fees.collection_account = "FEES".to_string();
+        assert!(matches!(account_cqrs_framework(pool.clone(), &with_fees, asset_query), Err(ConfigError::Invalid(_))));
+
+        pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use std::mem::swap;
+use async_trait::async_trait;
+use cqrs_es::Aggregate;
+use serde::{Deserialize, Serialize};
+use crate::metrics::ErrorVariant;
+use crate::standing_order::commands::StandingOrderCommand;
+use crate::standing_order::events::{StandingOrderEvent, StandingOrderPlan};
+use crate::standing_order::schedule::Schedule;
+use crate::statemachine::{StateMachine, Transition};
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub enum StandingOrder {
+    #[default]
+    Uninitialized,
+    // Due at `next_at`, none once the schedule ran out.
+    Active {
+        plan: StandingOrderPlan,
+        next_at: Option<u64>,
+    },
+    Paused {
+        plan: StandingOrderPlan,
+    },
+    Cancelled {
+        plan: StandingOrderPlan,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StandingOrderError {
+    #[error("Invalid state: {0}")]
+    InvalidState(String),
+    #[error("Invalid plan: {0}")]
+    InvalidPlan(String),
+    #[error("Occurrence {0} is not due")]
+    NotDue(u64),
+}
+
+impl ErrorVariant for StandingOrderError {
+    fn variant(&self) -> &'static str {
+        match self {
+            StandingOrderError::InvalidState(_) => "InvalidState",
+            StandingOrderError::InvalidPlan(_) => "InvalidPlan",
+            StandingOrderError::NotDue(_) => "NotDue",
+        }
+    }
+}
+
+fn schedule_of(plan: &StandingOrderPlan) -> Result<Schedule, StandingOrderError> {
+    plan.schedule.parse().map_err(StandingOrderError::InvalidPlan)
+}
+
+#[async_trait]
+impl Aggregate for StandingOrder {
+    type Command = StandingOrderCommand;
+    type Event = StandingOrderEvent;
+    type Error = StandingOrderError;
+    type Services = ();
+
+    fn aggregate_type() -> String {
+        "standing_order".to_string()
+    }
+
+    async fn handle(
+        &self,
+        command: Self::Command,
+        _services: &Self::Services,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        match (self, command) {
+            (StandingOrder::Uninitialized, StandingOrderCommand::Create { plan, timestamp }) => {
+                if plan.amount == 0 {
+                    return Err(StandingOrderError::InvalidPlan("amount must be positive".to_string()));
+                }
+                if plan.from_account.is_empty() || plan.to_account.is_empty() || plan.asset.is_empty() {
+                    return Err(StandingOrderError::InvalidPlan("accounts and asset must not be empty".to_string()));
+                }
+                if plan.from_account == plan.to_account {
+                    return Err(StandingOrderError::InvalidPlan("cannot transfer to the same account".to_string()));
+                }
+                let Some(next_at) = schedule_of(&plan)?.next_after(timestamp) else {
+                    return Err(StandingOrderError::InvalidPlan(format!("{:?} never occurs", plan.schedule)));
+                };
+                Ok(vec![StandingOrderEvent::Created { plan, next_at, timestamp }])
+            },
+            (StandingOrder::Active { .. }, StandingOrderCommand::Pause { timestamp }) => {
+                Ok(vec![StandingOrderEvent::Paused { timestamp }])
+            },
+            (StandingOrder::Paused { plan }, StandingOrderCommand::Resume { timestamp }) => {
+                let Some(next_at) = schedule_of(plan)?.next_after(timestamp) else {
+                    return Err(StandingOrderError::InvalidPlan(format!("{:?} has no occurrence left", plan.schedule)));
+                };
+                Ok(vec![StandingOrderEvent::Resumed { next_at, timestamp }])
+            },
+            (StandingOrder::Active { .. } | StandingOrder::Paused { .. }, StandingOrderCommand::Cancel { reason, timestamp }) => {
+                Ok(vec![StandingOrderEvent::Cancelled { reason, timestamp }])
+            },
+            (StandingOrder::Active { plan, next_at }, StandingOrderCommand::Trigger { occurrence, transfer_id, timestamp }) => {
+                if *next_at != Some(occurrence) || timestamp < occurrence {
+                    return Err(StandingOrderError::NotDue(occurrence));
+                }
+                // Occurrences missed while the scheduler was down are not made up for.
+                let next_at = schedule_of(plan)?.next_after(timestamp.max(occurrence));
+                Ok(vec![StandingOrderEvent::Triggered { occurrence, transfer_id, next_at, timestamp }])
+            },
+            (state, cmd) => {
+                Err(StandingOrderError::InvalidState(format!("Standing order current at {:?} state, cannot accept {:?} command", state, cmd)))
+            }
+        }
+    }
+
+    fn apply(&mut self, event: Self::Event) {
+        let mut prev = Default::default();
+        swap(&mut prev, self);
+        *self = match (prev, event) {
+            (StandingOrder::Uninitialized, StandingOrderEvent::Created { plan, next_at, .. }) => StandingOrder::Active { plan, next_at: Some(next_at) },
+            (StandingOrder::Active { plan, .. }, StandingOrderEvent::Paused { .. }) => StandingOrder::Paused { plan },
+            (StandingOrder::Paused { plan }, StandingOrderEvent::Resumed { next_at, .. }) => StandingOrder::Active { plan, next_at: Some(next_at) },
+            (StandingOrder::Active { plan, .. } | StandingOrder::Paused { plan }, StandingOrderEvent::Cancelled { .. }) => StandingOrder::Cancelled { plan },
+            (StandingOrder::Active { plan, .. }, StandingOrderEvent::Triggered { next_at, .. }) => StandingOrder::Active { plan, next_at },
+            (state, event) => unreachable!("Invalid state transition: {:?} -> {:?}", state, event),
+        };
+    }
+}
+
+impl StateMachine for StandingOrder {
+    const TRANSITIONS: &'static [Transition] = &[
+        Transition { from: "Uninitialized", command: "Create", guard: Some("schedule occurs"), events: &["Created"], to: "Active" },
+        Transition { from: "Active", command: "Pause", guard: None, events: &["Paused"], to: "Paused" },
+        Transition { from: "Paused", command: "Resume", guard: Some("schedule occurs"), events: &["Resumed"], to: "Active" },
+        Transition { from: "Active", command: "Cancel", guard: None, events: &["Cancelled"], to: "Cancelled" },
+        Transition { from: "Paused", command: "Cancel", guard: None, events: &["Cancelled"], to: "Cancelled" },
+        Transition { from: "Active", command: "Trigger", guard: Some("occurrence due"), events: &["Triggered"], to: "Active" },
+    ];
+}
+
+#[cfg(test)]
+mod aggregate_tests {
+    use cqrs_es::test::TestFramework;
+
+    use crate::standing_order::aggregate::{StandingOrder, StandingOrderError};
+    use crate::standing_order::commands::StandingOrderCommand;
+    use crate::standing_order::events::{StandingOrderEvent, StandingOrderPlan};
+    use crate::util::types::ByteArray32;
+
+    // 2024-01-01T00:00:00Z.
+    const NEW_YEAR: u64 = 1704067200;
+    const DAY: u64 = 86400;
+
+    fn plan() -> StandingOrderPlan {
+        StandingOrderPlan {
+            from_account: "ACCT-0001".to_string(),
+            to_account: "ACCT-0002".to_string(),
+            asset: "USDT".to_string(),
+            amount: 100,
+            schedule: "0 0 * * *".to_string(),
+            description: "rent".to_string(),
+        }
+    }
+
+    fn created() -> StandingOrderEvent {
+        StandingOrderEvent::Created { plan: plan(), next_at: NEW_YEAR + DAY, timestamp: NEW_YEAR }
+    }
+
+    #[test]
+    fn test_create() {
+        TestFramework::<StandingOrder>::with(())
+            .given_no_previous_events()
+            .when(StandingOrderCommand::Create { plan: plan(), timestamp: NEW_YEAR })
+            .then_expect_events(vec![created()]);
+        let never = StandingOrderPlan { schedule: "0 0 31 4 *".to_string(), ..plan() };
+        TestFramework::<StandingOrder>::with(())
+            .given_no_previous_events()
+            .when(StandingOrderCommand::Create { plan: never, timestamp: NEW_YEAR })
+            .then_expect_error_message("Invalid plan: \"0 0 31 4 *\" never occurs");
+    }
+
+    #[test]
+    fn test_trigger_skips_missed_occurrences() {
+        let transfer_id = ByteArray32([1; 32]);
+        TestFramework::<StandingOrder>::with(())
+            .given(vec![created()])
+            .when(StandingOrderCommand::Trigger { occurrence: NEW_YEAR + DAY, transfer_id, timestamp: NEW_YEAR + DAY - 1 })
+            .then_expect_error_message(&format!("Occurrence {} is not due", NEW_YEAR + DAY));
+        // Three days late, the transfer of the missed occurrence is opened once.
+        TestFramework::<StandingOrder>::with(())
+            .given(vec![created()])
+            .when(StandingOrderCommand::Trigger { occurrence: NEW_YEAR + DAY, transfer_id, timestamp: NEW_YEAR + 3 * DAY + 10 })
+            .then_expect_events(vec![StandingOrderEvent::Triggered {
+                occurrence: NEW_YEAR + DAY,
+                transfer_id,
+                next_at: Some(NEW_YEAR + 4 * DAY),
+                timestamp: NEW_YEAR + 3 * DAY + 10,
+            }]);
+    }
+
+    #[test]
+    fn test_pause_resume_cancel() {
+        let paused = StandingOrderEvent::Paused { timestamp: NEW_YEAR + 10 };
+        let result = TestFramework::<StandingOrder>::with(())
+            .given(vec![created(), paused.clone()])
+            .when(StandingOrderCommand::Trigger { occurrence: NEW_YEAR + DAY, transfer_id: ByteArray32([1; 32]), timestamp: NEW_YEAR + DAY })
+            .inspect_result();
+        assert!(matches!(result, Err(StandingOrderError::InvalidState(_))));
+        TestFramework::<StandingOrder>::with(())
+            .given(vec![created(), paused.clone()])
+            .when(StandingOrderCommand::Resume { timestamp: NEW_YEAR + 2 * DAY + 1 })
+            .then_expect_events(vec![StandingOrderEvent::Resumed { next_at: NEW_YEAR + 3 * DAY, timestamp: NEW_YEAR + 2 * DAY + 1 }]);
+        TestFramework::<StandingOrder>::with(())
+            .given(vec![created(), paused])
+            .when(StandingOrderCommand::Cancel { reason: "moved out".to_string(), timestamp: NEW_YEAR + 20 })
+            .then_expect_events(vec![StandingOrderEvent::Cancelled { reason: "moved out".to_string(), timestamp: NEW_YEAR + 20 }]);
+    }
+}
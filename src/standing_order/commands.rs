@@ -0,0 +1,32 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::standing_order::events::StandingOrderPlan;
+use crate::util::types::ByteArray32;
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub enum StandingOrderCommand {
+    // Sets the order up, its first transfer follows the first occurrence after `timestamp`.
+    Create {
+        plan: StandingOrderPlan,
+        timestamp: u64,
+    },
+    // Skips the occurrences until resumed.
+    Pause {
+        timestamp: u64,
+    },
+    // Resumes with the first occurrence after `timestamp`, the ones missed are skipped.
+    Resume {
+        timestamp: u64,
+    },
+    Cancel {
+        reason: String,
+        timestamp: u64,
+    },
+    // Issued by the scheduler to reserve the `occurrence` due before it opens its transfer,
+    // never accepted on the public route.
+    Trigger {
+        occurrence: u64,
+        transfer_id: ByteArray32,
+        timestamp: u64,
+    },
+}
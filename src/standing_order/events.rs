@@ -0,0 +1,60 @@
+use cqrs_es::DomainEvent;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::util::types::ByteArray32;
+
+// Moves `amount` of `asset` from `from_account` to `to_account` at every occurrence of
+// `schedule`, a cron rule, see `crate::standing_order::schedule::Schedule`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub struct StandingOrderPlan {
+    pub from_account: String,
+    pub to_account: String,
+    pub asset: String,
+    pub amount: u64,
+    pub schedule: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum StandingOrderEvent {
+    Created {
+        plan: StandingOrderPlan,
+        next_at: u64,
+        timestamp: u64,
+    },
+    Paused {
+        timestamp: u64,
+    },
+    Resumed {
+        next_at: u64,
+        timestamp: u64,
+    },
+    Cancelled {
+        reason: String,
+        timestamp: u64,
+    },
+    // `next_at` is absent once the schedule has no occurrence left.
+    Triggered {
+        occurrence: u64,
+        transfer_id: ByteArray32,
+        next_at: Option<u64>,
+        timestamp: u64,
+    },
+}
+
+impl DomainEvent for StandingOrderEvent {
+    fn event_type(&self) -> String {
+        match self {
+            StandingOrderEvent::Created { .. } => "Created".to_string(),
+            StandingOrderEvent::Paused { .. } => "Paused".to_string(),
+            StandingOrderEvent::Resumed { .. } => "Resumed".to_string(),
+            StandingOrderEvent::Cancelled { .. } => "Cancelled".to_string(),
+            StandingOrderEvent::Triggered { .. } => "Triggered".to_string(),
+        }
+    }
+
+    fn event_version(&self) -> String {
+        "1.0".to_string()
+    }
+}
@@ -0,0 +1,6 @@
+pub mod aggregate;
+pub mod commands;
+pub mod events;
+pub mod queries;
+pub mod schedule;
+pub mod scheduler;
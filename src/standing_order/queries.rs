@@ -0,0 +1,84 @@
+use async_trait::async_trait;
+use cqrs_es::{EventEnvelope, Query, View};
+use cqrs_es::persist::GenericQuery;
+use postgres_es::PostgresViewRepository;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::standing_order::aggregate::StandingOrder;
+use crate::standing_order::events::{StandingOrderEvent, StandingOrderPlan};
+
+pub struct SimpleLoggingQuery {}
+
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, ToSchema)]
+pub enum StandingOrderStatus {
+    #[default]
+    Active,
+    Paused,
+    Cancelled,
+}
+
+// The transfer opened for an occurrence.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ScheduledTransfer {
+    pub occurrence: u64,
+    pub transfer_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, ToSchema)]
+pub struct StandingOrderView {
+    pub id: String,
+    pub plan: Option<StandingOrderPlan>,
+    pub status: StandingOrderStatus,
+    // The next occurrence while active, the scheduler picks the order up from then on.
+    pub next_at: Option<u64>,
+    pub runs: u64,
+    pub last_run: Option<ScheduledTransfer>,
+    pub cancel_reason: Option<String>,
+}
+
+#[async_trait]
+impl Query<StandingOrder> for SimpleLoggingQuery {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<StandingOrder>]) {
+        for event in events {
+            let payload = serde_json::to_string_pretty(&event.payload).unwrap();
+            tracing::debug!("{}-{}\n{}", aggregate_id, event.sequence, payload);
+        }
+    }
+}
+
+pub type StandingOrderQuery = GenericQuery<
+    PostgresViewRepository<StandingOrderView, StandingOrder>,
+    StandingOrderView,
+    StandingOrder,
+>;
+
+impl View<StandingOrder> for StandingOrderView {
+    fn update(&mut self, event: &EventEnvelope<StandingOrder>) {
+        match &event.payload {
+            StandingOrderEvent::Created { plan, next_at, .. } => {
+                self.id = event.aggregate_id.clone();
+                self.plan = Some(plan.clone());
+                self.status = StandingOrderStatus::Active;
+                self.next_at = Some(*next_at);
+            }
+            StandingOrderEvent::Paused { .. } => {
+                self.status = StandingOrderStatus::Paused;
+                self.next_at = None;
+            }
+            StandingOrderEvent::Resumed { next_at, .. } => {
+                self.status = StandingOrderStatus::Active;
+                self.next_at = Some(*next_at);
+            }
+            StandingOrderEvent::Cancelled { reason, .. } => {
+                self.status = StandingOrderStatus::Cancelled;
+                self.next_at = None;
+                self.cancel_reason = Some(reason.clone());
+            }
+            StandingOrderEvent::Triggered { occurrence, transfer_id, next_at, .. } => {
+                self.runs += 1;
+                self.last_run = Some(ScheduledTransfer { occurrence: *occurrence, transfer_id: transfer_id.hex() });
+                self.next_at = *next_at;
+            }
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::str::FromStr;
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Timelike};
+
+// Occurrences are looked for this far ahead, a rule like `0 0 30 2 *` never occurs.
+const HORIZON_SECS: u64 = 5 * 366 * 86400;
+
+// A recurrence in the five fields of cron, evaluated in UTC: minute, hour, day of the
+// month, month and day of the week, where 0 and 7 are Sunday. A field is `*`, a value,
+// a range `a-b`, either with a step such as `*/15`, or a comma separated list of those.
+// As in cron, when both day fields are restricted a day matching either one occurs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = rule.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("{:?} does not have the five fields of a cron rule", rule));
+        };
+        let mut weekday_bits = field(weekdays, 0, 7)?;
+        // Sunday is both 0 and 7.
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits |= 1;
+        }
+        Ok(Schedule {
+            minutes: field(minutes, 0, 59)?,
+            hours: field(hours, 0, 23)?,
+            days: field(days, 1, 31)?,
+            months: field(months, 1, 12)?,
+            weekdays: weekday_bits,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+}
+
+impl Schedule {
+    // The first occurrence after `timestamp`, occurrences fall on whole minutes.
+    pub fn next_after(&self, timestamp: u64) -> Option<u64> {
+        let start = (timestamp / 60 + 1) * 60;
+        let mut time = NaiveDateTime::from_timestamp_opt(start as i64, 0)?;
+        let horizon = start.saturating_add(HORIZON_SECS) as i64;
+        while time.timestamp() <= horizon {
+            if !has(self.months, time.month()) {
+                let (year, month) = if time.month() == 12 { (time.year() + 1, 1) } else { (time.year(), time.month() + 1) };
+                time = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.day_matches(time.date()) {
+                time = time.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !has(self.hours, time.hour()) {
+                time = time.date().and_hms_opt(time.hour(), 0, 0)? + Duration::hours(1);
+            } else if !has(self.minutes, time.minute()) {
+                time += Duration::minutes(1);
+            } else {
+                return Some(time.timestamp() as u64);
+            }
+        }
+        None
+    }
+
+    fn day_matches(&self, date: NaiveDate) -> bool {
+        let day = has(self.days, date.day());
+        let weekday = has(self.weekdays, date.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+}
+
+fn has(bits: u64, value: u32) -> bool {
+    bits & (1 << value) != 0
+}
+
+fn field(spec: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in spec.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|step| *step > 0).ok_or(format!("invalid step in {:?}", part))?),
+            None => (part, 1),
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((from, to)) => (value(from, min, max)?, value(to, min, max)?),
+                // `5/15` runs from 5 to the end of the range.
+                None if step > 1 => (value(range, min, max)?, max),
+                None => (value(range, min, max)?, value(range, min, max)?),
+            },
+        };
+        if from > to {
+            return Err(format!("empty range {:?}", part));
+        }
+        for value in (from..=to).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+fn value(value: &str, min: u32, max: u32) -> Result<u32, String> {
+    value.parse::<u32>().ok().filter(|value| (min..=max).contains(value)).ok_or(format!("{:?} is not within {}-{}", value, min, max))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::standing_order::schedule::Schedule;
+
+    // 2024-01-01T00:00:00Z, a Monday.
+    const NEW_YEAR: u64 = 1704067200;
+
+    fn next(rule: &str, timestamp: u64) -> Option<u64> {
+        rule.parse::<Schedule>().unwrap().next_after(timestamp)
+    }
+
+    #[test]
+    fn test_next_occurrence() {
+        // Strictly after, on the next whole minute.
+        assert_eq!(next("* * * * *", NEW_YEAR), Some(NEW_YEAR + 60));
+        assert_eq!(next("*/15 * * * *", NEW_YEAR + 61), Some(NEW_YEAR + 15 * 60));
+        assert_eq!(next("30 9 * * *", NEW_YEAR), Some(NEW_YEAR + 9 * 3600 + 30 * 60));
+        // The first of each month, at midnight.
+        assert_eq!(next("0 0 1 * *", NEW_YEAR), Some(NEW_YEAR + 31 * 86400));
+        // Fridays, or Sundays given as 7.
+        assert_eq!(next("0 12 * * 5", NEW_YEAR), Some(NEW_YEAR + 4 * 86400 + 12 * 3600));
+        assert_eq!(next("0 0 * * 7", NEW_YEAR), Some(NEW_YEAR + 6 * 86400));
+        // Either day field matches when both are set: the 15th comes after Wednesday the 3rd.
+        assert_eq!(next("0 0 15 * 3", NEW_YEAR), Some(NEW_YEAR + 2 * 86400));
+        // February the 29th, a leap day away.
+        assert_eq!(next("0 0 29 2 *", NEW_YEAR + 60 * 86400), Some(1835395200));
+        assert_eq!(next("0 0 30 2 *", NEW_YEAR), None);
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        for rule in ["* * * *", "60 * * * *", "* 24 * * *", "* * 0 * *", "*/0 * * * *", "5-1 * * * *", "* * * jan *"] {
+            assert!(rule.parse::<Schedule>().is_err(), "{}", rule);
+        }
+    }
+}
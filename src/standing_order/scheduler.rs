@@ -0,0 +1,152 @@
+use std::sync::Arc;
+use std::time::Duration;
+use cqrs_es::AggregateError;
+use postgres_es::PostgresCqrs;
+use sqlx::postgres::PgRow;
+use sqlx::{Pool, Postgres, Row};
+use crate::background::{Background, Shutdown};
+use crate::config::StandingOrderConfig;
+use crate::field_encryption::EncryptedCqrs;
+use crate::standing_order::aggregate::StandingOrder;
+use crate::standing_order::commands::StandingOrderCommand;
+use crate::standing_order::events::StandingOrderPlan;
+use crate::standing_order::queries::StandingOrderView;
+use crate::transfer::aggregate::{Transfer, TransferError};
+use crate::transfer::commands::{derive_transfer_id, TransferCommand};
+use crate::util::clock;
+use crate::util::types::ByteArray32;
+
+// Triggers every standing order that came due, then opens and continues the transfer of
+// the occurrence. Triggering first reserves the occurrence, so a Pause or Cancel committed
+// in between leaves no transfer behind. The transfer id is derived from the order and the
+// occurrence: a sweep that stopped after the trigger finds the last run without its
+// transfer and opens it, before the order comes due again. A transfer the accounts reject
+// fails on its own.
+#[derive(Clone)]
+pub struct StandingOrderScheduler {
+    standing_order_cqrs: Arc<PostgresCqrs<StandingOrder>>,
+    transfer_cqrs: Arc<EncryptedCqrs<Transfer>>,
+    pool: Pool<Postgres>,
+    config: StandingOrderConfig,
+}
+
+impl StandingOrderScheduler {
+    pub fn new(standing_order_cqrs: Arc<PostgresCqrs<StandingOrder>>, transfer_cqrs: Arc<EncryptedCqrs<Transfer>>, pool: Pool<Postgres>, config: StandingOrderConfig) -> Self {
+        StandingOrderScheduler { standing_order_cqrs, transfer_cqrs, pool, config }
+    }
+
+    pub fn spawn(self, background: &Background) {
+        background.spawn(|shutdown| async move { self.run(shutdown).await });
+    }
+
+    async fn run(&self, mut shutdown: Shutdown) {
+        loop {
+            if let Err(e) = self.sweep().await {
+                tracing::error!("Failed to sweep due standing orders: {}", e);
+            }
+            if !shutdown.pause(Duration::from_secs(self.config.sweep_interval_secs.max(1))).await {
+                break;
+            }
+        }
+    }
+
+    async fn sweep(&self) -> Result<(), sqlx::Error> {
+        let now = clock::now();
+        for (standing_order_id, plan, occurrence) in untransferred_runs(&self.pool).await? {
+            let transfer_id = occurrence_transfer_id(&standing_order_id, &plan, occurrence);
+            self.open(&standing_order_id, &transfer_id, plan, occurrence, now).await;
+        }
+        for (standing_order_id, plan, occurrence) in due_standing_orders(&self.pool, now).await? {
+            let transfer_id = occurrence_transfer_id(&standing_order_id, &plan, occurrence);
+            let trigger = StandingOrderCommand::Trigger { occurrence, transfer_id, timestamp: now };
+            if let Err(e) = self.standing_order_cqrs.execute(&standing_order_id, trigger).await {
+                tracing::error!("Failed to trigger standing order {}: {}", standing_order_id, e);
+                continue;
+            }
+            self.open(&standing_order_id, &transfer_id, plan, occurrence, now).await;
+        }
+        Ok(())
+    }
+
+    async fn open(&self, standing_order_id: &str, transfer_id: &ByteArray32, plan: StandingOrderPlan, occurrence: u64, now: u64) {
+        match self.transfer(transfer_id, plan, now).await {
+            Ok(()) => tracing::info!("Standing order {} opened transfer {}", standing_order_id, transfer_id.hex()),
+            Err(e) => tracing::error!("Failed to open the transfer of standing order {} due at {}: {}", standing_order_id, occurrence, e),
+        }
+    }
+
+    async fn transfer(&self, transfer_id: &ByteArray32, plan: StandingOrderPlan, now: u64) -> Result<(), AggregateError<TransferError>> {
+        let open = TransferCommand::Open {
+            transfer_id: *transfer_id,
+            from_account: plan.from_account,
+            to_account: plan.to_account,
+            asset: plan.asset,
+            amount: plan.amount,
+            timestamp: now,
+            description: plan.description,
+        };
+        let id = transfer_id.hex();
+        // Either step finds the transfer past it when an earlier sweep got that far.
+        for command in [open, TransferCommand::Continue] {
+            match self.transfer_cqrs.execute(&id, command).await {
+                Ok(()) | Err(AggregateError::UserError(TransferError::InvalidState(_))) => {},
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+// The transfer an occurrence of a standing order opens.
+fn occurrence_transfer_id(standing_order_id: &str, plan: &StandingOrderPlan, occurrence: u64) -> ByteArray32 {
+    derive_transfer_id(&plan.from_account, &plan.to_account, &format!("standing-order:{}:{}", standing_order_id, occurrence))
+}
+
+// Active orders come due again only once the transfer of their last run is opened, see
+// `untransferred_runs`.
+async fn due_standing_orders(pool: &Pool<Postgres>, now: u64) -> Result<Vec<(String, StandingOrderPlan, u64)>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT view_id, payload FROM standing_order_query o
+         WHERE payload->>'status' = 'Active' AND (payload->>'next_at')::bigint <= $1
+           AND (json_typeof(payload->'last_run') IS DISTINCT FROM 'object'
+                OR EXISTS (SELECT 1 FROM transfer_query t WHERE t.view_id = o.payload->'last_run'->>'transfer_id'))",
+    )
+        .bind(now as i64)
+        .fetch_all(pool)
+        .await?;
+    let mut due = vec![];
+    for (standing_order_id, view) in decode_views(rows)? {
+        if let (Some(plan), Some(next_at)) = (view.plan, view.next_at) {
+            due.push((standing_order_id, plan, next_at));
+        }
+    }
+    Ok(due)
+}
+
+// Runs triggered without their transfer opened, whatever became of the order since.
+async fn untransferred_runs(pool: &Pool<Postgres>) -> Result<Vec<(String, StandingOrderPlan, u64)>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT view_id, payload FROM standing_order_query o
+         WHERE json_typeof(payload->'last_run') = 'object'
+           AND NOT EXISTS (SELECT 1 FROM transfer_query t WHERE t.view_id = o.payload->'last_run'->>'transfer_id')",
+    )
+        .fetch_all(pool)
+        .await?;
+    let mut runs = vec![];
+    for (standing_order_id, view) in decode_views(rows)? {
+        if let (Some(plan), Some(last_run)) = (view.plan, view.last_run) {
+            runs.push((standing_order_id, plan, last_run.occurrence));
+        }
+    }
+    Ok(runs)
+}
+
+fn decode_views(rows: Vec<PgRow>) -> Result<Vec<(String, StandingOrderView)>, sqlx::Error> {
+    rows.into_iter()
+        .map(|row| {
+            let standing_order_id: String = row.try_get("view_id")?;
+            let view = serde_json::from_value(row.try_get("payload")?).map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+            Ok((standing_order_id, view))
+        })
+        .collect()
+}
@@ -1,40 +1,366 @@
 use crate::account::aggregate::Account;
-use crate::config::{account_cqrs_framework, transfer_cqrs_framework, order_cqrs_framework};
-use postgres_es::{default_postgress_pool, PostgresCqrs, PostgresViewRepository};
+use crate::account::bulk::BulkOperations;
+use crate::account::canary::next_logic;
+use crate::account::counterparty::CounterpartyNames;
+use crate::auth::Authenticator;
+use crate::audit::ConsistencyAudit;
+use crate::background::Background;
+use crate::inbox::CommandInbox;
+use crate::view_audit::ViewAudit;
+use crate::account::fees::{FeeCollector, FeeQuery};
+use crate::config::{AppConfig, ApprovalConfig, ConfigError, AccrualConfig, CommandPolicyConfig, ConflictRetryConfig, EventStoreBackend, PayoutConfig, SandboxConfig, ServerConfig, account_cqrs_framework, asset_cqrs_framework, transfer_cqrs_framework, batch_transfer_cqrs_framework, order_cqrs_framework, rfq_cqrs_framework, auction_cqrs_framework, standing_order_cqrs_framework, approval_cqrs_framework, auction_schedule, preferences_cqrs_framework, notification_queries, global_txid_registry_enabled, traffic_recording_enabled, outbox_config, sla_config};
+use postgres_es::{PostgresCqrs, PostgresViewRepository};
+use crate::commit_hooks::HookedCqrs;
+use sqlx::postgres::PgPoolOptions;
+use prometheus::Registry;
 use std::sync::Arc;
+use std::time::Duration;
+use sqlx::{Pool, Postgres};
 use crate::account::queries::AccountView;
+use crate::account::stats::AccountStatsRepository;
+use crate::account::overdraft::OverdraftLimits;
+use crate::account::stream::AccountEventStream;
+use crate::asset::aggregate::Asset;
+use crate::asset::migration::AssetMigrations;
+use crate::compensation::CompensationJournal;
+use crate::signals::Signals;
+use crate::retry::RetryingExecutor;
+use crate::dlq::DeadLetters;
+use crate::asset::queries::AssetView;
+use crate::auction::aggregate::Auction;
+use crate::auction::engine::AuctionEngine;
+use crate::transfer::expiry::TransferExpiry;
+use crate::auction::queries::AuctionView;
+use crate::standing_order::aggregate::StandingOrder;
+use crate::standing_order::queries::StandingOrderView;
+use crate::standing_order::scheduler::StandingOrderScheduler;
+use crate::batch_transfer::aggregate::BatchTransfer;
+use crate::batch_transfer::queries::BatchTransferView;
+use crate::idempotency::IdempotencyStore;
+use crate::invalidation::InvalidationBus;
+use crate::aggregate_cache::AggregateCache;
+use crate::bulkhead::Bulkheads;
+use crate::business_day::BusinessDays;
+use crate::metrics::{AuditMetrics, BulkheadMetrics, ConnectionMetrics, ErrorMetrics, OutboundMetrics, RateLimitMetrics, SagaMetrics};
+use crate::notification::WebhookNotifier;
+use crate::services::outbound::OutboundClient;
 use crate::order::aggregate::Order;
+use crate::order::halts::TradingHalts;
+use crate::order::matching::{MatchingMetrics, MatchingQuery, OrderMatcher};
 use crate::order::queries::OrderView;
+use crate::account::closure::AccountClosures;
+use crate::order::integrity::LockIntegrity;
+use crate::order::expiry::OrderExpiry;
+use crate::order::recovery::FundRecovery;
+use crate::watchdog::SagaWatchdog;
+use crate::audit_log::AuditLog;
+use crate::outbox::OutboxPublisher;
+use crate::field_encryption::{EncryptedCqrs, FieldCipher};
+use crate::sealing::{SealedCqrs, SealedViewRepository, Sealer};
+use crate::preferences::aggregate::Preferences;
+use crate::preferences::queries::PreferencesView;
+use crate::rfq::aggregate::Rfq;
+use crate::recording::TrafficRecorder;
+use crate::rfq::queries::RfqView;
+use crate::sandbox::{load_clock, sandbox_pool};
+use crate::util::clock;
+use crate::rate_limit::RateLimiter;
+use crate::quota::Quotas;
+use crate::routing::RegionRouter;
+use crate::approval::aggregate::Approval;
+use crate::approval::queries::ApprovalView;
+use crate::sla::LoadShedder;
 use crate::transfer::aggregate::Transfer;
 use crate::transfer::queries::TransferView;
+use crate::txid_registry::TxidRegistry;
+use crate::webhooks::WebhookRegistry;
 
 #[derive(Clone)]
 pub struct ApplicationState {
-    pub account_cqrs: Arc<PostgresCqrs<Account>>,
-    pub account_query: Arc<PostgresViewRepository<AccountView, Account>>,
-    pub transfer_cqrs: Arc<PostgresCqrs<Transfer>>,
+    pub account_cqrs: Arc<SealedCqrs<Account>>,
+    // Accounts hydrated between their commands, behind `account_cqrs`.
+    pub account_cache: AggregateCache<Account>,
+    pub account_query: Arc<SealedViewRepository<AccountView, Account>>,
+    pub account_stats: Arc<AccountStatsRepository>,
+    pub overdraft_limits: OverdraftLimits,
+    pub account_stream: AccountEventStream,
+    pub preferences_cqrs: Arc<PostgresCqrs<Preferences>>,
+    pub preferences_query: Arc<PostgresViewRepository<PreferencesView, Preferences>>,
+    // Display names shown next to the counterparties of the ledger.
+    pub counterparty_names: CounterpartyNames,
+    pub asset_cqrs: Arc<PostgresCqrs<Asset>>,
+    pub asset_query: Arc<PostgresViewRepository<AssetView, Asset>>,
+    pub asset_migrations: AssetMigrations,
+    // Configuration changes proposed by one operator for another to approve.
+    pub approval_cqrs: Arc<PostgresCqrs<Approval>>,
+    pub approval_query: Arc<PostgresViewRepository<ApprovalView, Approval>>,
+    pub approvals: ApprovalConfig,
+    // Saga steps that failed on an account, under `/admin/dlq`.
+    pub dead_letters: DeadLetters,
+    pub transfer_cqrs: Arc<EncryptedCqrs<Transfer>>,
     pub transfer_query: Arc<PostgresViewRepository<TransferView, Transfer>>,
-    pub order_cqrs: Arc<PostgresCqrs<Order>>,
+    pub batch_transfer_cqrs: Arc<EncryptedCqrs<BatchTransfer>>,
+    pub batch_transfer_query: Arc<PostgresViewRepository<BatchTransferView, BatchTransfer>>,
+    pub order_cqrs: Arc<HookedCqrs<Order>>,
     pub order_query: Arc<PostgresViewRepository<OrderView, Order>>,
+    pub matching_metrics: MatchingMetrics,
+    pub trading_halts: TradingHalts,
+    pub fund_recovery: FundRecovery,
+    pub saga_watchdog: SagaWatchdog,
+    // Who executed which command, see `AuditLogQuery`.
+    pub audit_log: AuditLog,
+    pub consistency_audit: ConsistencyAudit,
+    pub lock_integrity: LockIntegrity,
+    // Absent unless business days are kept.
+    pub business_days: Option<BusinessDays>,
+    // Compares the account views with their events.
+    pub view_audit: ViewAudit,
+    pub error_metrics: ErrorMetrics,
+    // Duplicate transactions, reversals and compensations per account.
+    pub signals: Signals,
+    // Metrics exposed in the Prometheus format.
+    pub metrics_registry: Registry,
+    pub connection_metrics: ConnectionMetrics,
+    pub bulk_operations: BulkOperations,
+    pub rfq_cqrs: Arc<PostgresCqrs<Rfq>>,
+    pub rfq_query: Arc<PostgresViewRepository<RfqView, Rfq>>,
+    pub auction_cqrs: Arc<PostgresCqrs<Auction>>,
+    pub auction_query: Arc<PostgresViewRepository<AuctionView, Auction>>,
+    pub standing_order_cqrs: Arc<PostgresCqrs<StandingOrder>>,
+    pub standing_order_query: Arc<PostgresViewRepository<StandingOrderView, StandingOrder>>,
+    // Commands queued by clients that are offline, see `crate::inbox`.
+    pub inbox: CommandInbox,
+    pub txid_registry: TxidRegistry,
+    pub idempotency: IdempotencyStore,
+    pub load_shedder: LoadShedder,
+    pub bulkheads: Bulkheads,
+    pub rate_limiter: RateLimiter,
+    pub quotas: Quotas,
+    // Forwards requests to the region owning their account or asset.
+    pub router: RegionRouter,
+    pub authenticator: Authenticator,
+    pub recorder: TrafficRecorder,
+    pub webhook_registry: WebhookRegistry,
+    // Present when cache invalidation is enabled, caches subscribe to it.
+    pub invalidation_bus: Option<InvalidationBus>,
+    pub sandbox: SandboxConfig,
+    pub command_policy: CommandPolicyConfig,
+    pub payout: PayoutConfig,
+    pub accruals: AccrualConfig,
+    // Of the commands the routes execute, see `crate::retry`.
+    pub conflict_retry: ConflictRetryConfig,
+    pub server: ServerConfig,
+    // Joined on shutdown before the pool is closed.
+    pub background: Background,
+    pub pool: Pool<Postgres>,
 }
 
-pub async fn new_application_state(connection_string: &str) -> ApplicationState {
+pub async fn new_application_state(connection_string: &str) -> Result<ApplicationState, ConfigError> {
     // Configure the CQRS framework, backed by a Postgres database, along with two queries:
     // - a simply-query prints events to stdout as they are published
     // - `account_query` stores the current state of the account in a ViewRepository that we can access
     //
     // The needed database tables are automatically configured with `docker-compose up -d`,
     // see init file at `/db/init.sql` for more.
-    let pool = default_postgress_pool(connection_string).await;
-    let (account_cqrs, account_query) = account_cqrs_framework(pool.clone());
-    let (transfer_cqrs, transfer_query) = transfer_cqrs_framework(pool.clone(), account_cqrs.clone());
-    let (order_cqrs, order_query) = order_cqrs_framework(pool, account_cqrs.clone());
-    ApplicationState {
+    let mut config = AppConfig::load()?;
+    // Embedded deployments build their frameworks with `crate::sqlite` instead.
+    if config.event_store.backend != EventStoreBackend::Postgres {
+        return Err(ConfigError::Invalid("the server keeps its events in Postgres, the sqlite event store is for embedding the aggregates".to_string()));
+    }
+    // Keep sandbox invalidations away from the caches of live nodes.
+    if config.sandbox.enabled {
+        config.cache_invalidation.channel = format!("{}_{}", config.sandbox.schema, config.cache_invalidation.channel);
+    }
+    let pool = if config.sandbox.enabled {
+        tracing::warn!("Running in sandbox mode on schema {}", config.sandbox.schema);
+        let pool = sandbox_pool(connection_string, &config.sandbox, config.database.max_connections).await.expect("unable to prepare the sandbox schema");
+        // Before anything reads the time, so nothing is stamped behind the events already stored.
+        clock::restore(load_clock(&pool).await.expect("unable to load the sandbox clock"));
+        pool
+    } else {
+        PgPoolOptions::new()
+            .max_connections(config.database.max_connections)
+            .connect(connection_string)
+            .await
+            .expect("unable to connect to the database")
+    };
+    let (asset_cqrs, asset_query) = asset_cqrs_framework(pool.clone(), &config);
+    let account_stream = AccountEventStream::new();
+    let counterparty_names = CounterpartyNames::new(pool.clone());
+    let (preferences_cqrs, preferences_query) = preferences_cqrs_framework(pool.clone(), &config, counterparty_names.clone());
+    let (fee_query, fees_charged) = FeeQuery::channel();
+    let business_days = config
+        .business_day
+        .enabled
+        .then(|| BusinessDays::new(pool.clone(), &config.business_day).expect("invalid business day configuration"));
+    let posting_date = business_days.as_ref().map(BusinessDays::posting_date);
+    let invalidation_bus = config
+        .cache_invalidation
+        .enabled
+        .then(|| InvalidationBus::listen(pool.clone(), config.cache_invalidation.channel.clone()));
+    let background = Background::default();
+    let metrics_registry = Registry::new();
+    let outbound_metrics = OutboundMetrics::default();
+    outbound_metrics.register(&metrics_registry).expect("unable to register the outbound metrics");
+    let outbound = OutboundClient::new(&config.outbound, outbound_metrics);
+    let signals = Signals::new(pool.clone());
+    signals.register(&metrics_registry).expect("unable to register the account signal metrics");
+    let mut notifications = notification_queries(&pool, preferences_query.clone(), WebhookNotifier::new(outbound.clone(), "webhooks"));
+    // Reversals per account and hour.
+    notifications.push(Box::new(signals.clone()));
+    let quotas = Quotas::new(pool.clone(), config.quotas.clone());
+    quotas.register(&metrics_registry).expect("unable to register the quota metrics");
+    let router = RegionRouter::new(config.routing.clone());
+    router.register(&metrics_registry).expect("unable to register the routing metrics");
+    // Events and bytes committed per account and day.
+    notifications.push(Box::new(quotas.clone()));
+    let (account_cqrs, account_query, account_stats, account_cache, canary_metrics) = account_cqrs_framework(pool.clone(), &config, asset_query.clone(), account_stream.clone(), notifications, fee_query, posting_date);
+    account_cache.metrics().register(&metrics_registry).expect("unable to register the aggregate cache metrics");
+    canary_metrics.register(&metrics_registry).expect("unable to register the canary metrics");
+    if config.canary.percent > 0 {
+        tracing::info!("{}% of the accounts routed to account logic {} in {:?} mode", config.canary.percent, next_logic().version(), config.canary.mode);
+    }
+    match &invalidation_bus {
+        Some(bus) => account_cache.listen(bus),
+        // Nothing tells the cache about commits of other nodes, they are caught on commit.
+        None if account_cache.is_enabled() => tracing::info!("Aggregate cache on without cache invalidation, accounts changed by other nodes are reloaded on conflict"),
+        None => {},
+    }
+    FeeCollector::new(account_cqrs.clone(), pool.clone(), fees_charged).spawn(&background);
+    let compensations = CompensationJournal::new(pool.clone()).with_signals(signals.clone());
+    let (transfer_cqrs, transfer_query) = transfer_cqrs_framework(pool.clone(), &config, account_cqrs.clone(), compensations.clone());
+    let (batch_transfer_cqrs, batch_transfer_query) = batch_transfer_cqrs_framework(pool.clone(), &config, account_cqrs.clone(), compensations.clone());
+    let (matching_query, placed_orders) = MatchingQuery::channel();
+    let trading_halts = TradingHalts::new(pool.clone(), &config.trading_halts).expect("invalid trading halt configuration");
+    let saga_metrics = SagaMetrics::default();
+    saga_metrics.register(&metrics_registry).expect("unable to register the saga metrics");
+    let connection_metrics = ConnectionMetrics::default();
+    connection_metrics.register(&metrics_registry).expect("unable to register the connection metrics");
+    let bulkhead_metrics = BulkheadMetrics::default();
+    bulkhead_metrics.register(&metrics_registry).expect("unable to register the bulkhead metrics");
+    let rate_limit_metrics = RateLimitMetrics::default();
+    rate_limit_metrics.register(&metrics_registry).expect("unable to register the rate limit metrics");
+    let (order_cqrs, order_query) = order_cqrs_framework(pool.clone(), &config, account_cqrs.clone(), matching_query, trading_halts.clone(), saga_metrics.clone(), compensations.clone());
+    let (rfq_cqrs, rfq_query) = rfq_cqrs_framework(pool.clone(), &config, account_cqrs.clone(), saga_metrics, compensations.clone());
+    let (auction_cqrs, auction_query) = auction_cqrs_framework(pool.clone(), &config, account_cqrs.clone());
+    let auction_accounts = Arc::new(RetryingExecutor::new(account_cqrs.clone(), config.conflict_retry.clone()));
+    AuctionEngine::new(auction_cqrs.clone(), auction_query.clone(), order_cqrs.clone(), auction_accounts, pool.clone())
+        .spawn(&background, auction_schedule());
+    let matching_metrics = MatchingMetrics::new();
+    let recheck = Duration::from_secs(config.trading_halts.recheck_secs.max(1));
+    let stranded = Duration::from_secs(config.order_recovery.stranded_match_secs);
+    OrderMatcher::new(order_cqrs.clone(), pool.clone(), matching_metrics.clone(), trading_halts.clone(), recheck, stranded).spawn(&background, placed_orders);
+    TransferExpiry::new(transfer_cqrs.clone(), pool.clone(), config.transfer.clone()).spawn(&background);
+    let (standing_order_cqrs, standing_order_query) = standing_order_cqrs_framework(pool.clone(), &config);
+    StandingOrderScheduler::new(standing_order_cqrs.clone(), transfer_cqrs.clone(), pool.clone(), config.standing_orders.clone()).spawn(&background);
+    OrderExpiry::new(order_cqrs.clone(), pool.clone(), config.order_expiry.clone()).spawn(&background);
+    AccountClosures::new(account_cqrs.clone(), pool.clone(), config.account_closure.clone()).spawn(&background);
+    let fund_recovery = FundRecovery::new(account_cqrs.clone(), pool.clone(), config.order_recovery.clone());
+    fund_recovery.spawn(&background);
+    let saga_watchdog = SagaWatchdog::new(order_cqrs.clone(), transfer_cqrs.clone(), pool.clone(), config.saga_watchdog.clone())
+        .with_notifier(WebhookNotifier::new(outbound.clone(), "alerts"));
+    saga_watchdog.spawn(&background);
+    // Undoes the saga steps a crash of this or another node left uncompensated.
+    compensations
+        .spawn(&background, Arc::new(RetryingExecutor::new(account_cqrs.clone(), config.conflict_retry.clone())), config.compensations.clone());
+    let audit_metrics = AuditMetrics::default();
+    audit_metrics.register(&metrics_registry).expect("unable to register the consistency audit metrics");
+    let consistency_audit = ConsistencyAudit::new(pool.clone(), config.audit.clone(), audit_metrics);
+    consistency_audit.spawn(&background);
+    let lock_integrity = LockIntegrity::new(account_cqrs.clone(), order_cqrs.clone(), pool.clone(), config.integrity.clone());
+    lock_integrity.spawn(&background);
+    if let Some(business_days) = &business_days {
+        business_days.spawn(&background);
+    }
+    let sealer = Sealer::new(&config.sealing).expect("invalid sealing keys");
+    let cipher = FieldCipher::new(&config.field_encryption).expect("invalid field encryption keys");
+    let view_audit = ViewAudit::new(pool.clone(), sealer, cipher, config.view_audit.clone());
+    view_audit.spawn(&background);
+    if let Some(mut outbox) = outbox_config() {
+        // Keep sandbox events off the topics live consumers read.
+        if config.sandbox.enabled {
+            outbox.topic_prefix = format!("{}.{}", config.sandbox.schema, outbox.topic_prefix);
+        }
+        OutboxPublisher::new(pool.clone(), outbox)
+            .expect("invalid Kafka configuration")
+            .spawn(&background);
+    }
+    let error_metrics = ErrorMetrics::new(config.error_alerts.clone()).with_notifier(WebhookNotifier::new(outbound, "alerts"));
+    error_metrics.register(&metrics_registry).expect("unable to register the command error metrics");
+    let bulk_operations = BulkOperations::new(account_cqrs.clone(), account_query.clone(), pool.clone(), error_metrics.clone(), config.bulk.clone());
+    let asset_migrations = AssetMigrations::new(asset_cqrs.clone(), asset_query.clone(), account_cqrs.clone(), pool.clone(), error_metrics.clone(), config.bulk.clone());
+    asset_migrations.spawn();
+    let (approval_cqrs, approval_query) = approval_cqrs_framework(pool.clone(), &config, account_cqrs.clone(), asset_cqrs.clone());
+    let authenticator = Authenticator::new(&config.auth);
+    if !authenticator.is_configured() {
+        tracing::warn!("No admin credentials configured, admin routes refuse every request");
+    }
+    let inbox = CommandInbox::new(pool.clone(), config.inbox.clone());
+    let state = ApplicationState {
         account_cqrs,
+        account_cache,
         account_query,
+        account_stats,
+        overdraft_limits: OverdraftLimits::new(pool.clone()),
+        account_stream,
+        preferences_cqrs,
+        preferences_query,
+        counterparty_names,
+        asset_cqrs,
+        asset_query,
+        asset_migrations,
+        approval_cqrs,
+        approval_query,
+        approvals: config.approvals.clone(),
+        dead_letters: DeadLetters::new(pool.clone()),
         transfer_cqrs,
         transfer_query,
+        batch_transfer_cqrs,
+        batch_transfer_query,
         order_cqrs,
         order_query,
-    }
+        matching_metrics,
+        trading_halts,
+        fund_recovery,
+        saga_watchdog,
+        audit_log: AuditLog::new(pool.clone()),
+        consistency_audit,
+        lock_integrity,
+        business_days,
+        view_audit,
+        error_metrics,
+        signals,
+        metrics_registry,
+        connection_metrics,
+        bulk_operations,
+        rfq_cqrs,
+        rfq_query,
+        auction_cqrs,
+        auction_query,
+        standing_order_cqrs,
+        standing_order_query,
+        inbox,
+        txid_registry: TxidRegistry::new(pool.clone(), global_txid_registry_enabled()),
+        idempotency: IdempotencyStore::new(pool.clone()),
+        load_shedder: LoadShedder::new(sla_config()),
+        bulkheads: Bulkheads::new(&config.bulkheads, bulkhead_metrics),
+        rate_limiter: RateLimiter::new(&config.rate_limits, rate_limit_metrics),
+        quotas,
+        router,
+        authenticator,
+        recorder: TrafficRecorder::new(pool.clone(), traffic_recording_enabled()),
+        webhook_registry: WebhookRegistry::new(pool.clone()),
+        invalidation_bus,
+        sandbox: config.sandbox,
+        command_policy: config.command_policy,
+        payout: config.payout,
+        accruals: config.accruals,
+        conflict_retry: config.conflict_retry,
+        server: config.server,
+        background,
+        pool,
+    };
+    // Runs the queued commands the way their routes would, with the whole state at hand.
+    state.inbox.spawn(&state.background, state.clone());
+    Ok(state)
 }
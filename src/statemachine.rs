@@ -0,0 +1,201 @@
+use std::fmt::Write;
+use cqrs_es::Aggregate;
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+// One edge of an aggregate's state machine: in state `from`, `command` emits `events`
+// and leaves the aggregate in `to`. `guard` tells apart the outcomes of a command
+// that can end up in more than one state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition {
+    pub from: &'static str,
+    pub command: &'static str,
+    pub guard: Option<&'static str>,
+    pub events: &'static [&'static str],
+    pub to: &'static str,
+}
+
+// Declarative description of the transitions an aggregate's `handle` and `apply` implement.
+pub trait StateMachine {
+    const TRANSITIONS: &'static [Transition];
+}
+
+// Aggregates whose `handle` is gated by their transition table, so a command is only
+// dispatched in a state that has a row for it. State and command names are the enum
+// variant names, event names are `DomainEvent::event_type`.
+pub trait TableDriven: Aggregate + StateMachine {
+    fn state_name(&self) -> &'static str;
+
+    fn command_name(command: &Self::Command) -> &'static str;
+
+    fn accepts(&self, command: &Self::Command) -> bool {
+        let (state, command) = (self.state_name(), Self::command_name(command));
+        Self::TRANSITIONS.iter().any(|t| t.from == state && t.command == command)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum GraphFormat {
+    #[default]
+    Dot,
+    Mermaid,
+}
+
+// Looks a transition table up by `Aggregate::aggregate_type`.
+pub fn transitions_of(aggregate_type: &str) -> Option<&'static [Transition]> {
+    use crate::account::aggregate::Account;
+    use crate::approval::aggregate::Approval;
+    use crate::asset::aggregate::Asset;
+    use crate::auction::aggregate::Auction;
+    use crate::batch_transfer::aggregate::BatchTransfer;
+    use crate::order::aggregate::Order;
+    use crate::preferences::aggregate::Preferences;
+    use crate::rfq::aggregate::Rfq;
+    use crate::standing_order::aggregate::StandingOrder;
+    use crate::transfer::aggregate::Transfer;
+
+    match aggregate_type {
+        "account" => Some(Account::TRANSITIONS),
+        "approval" => Some(Approval::TRANSITIONS),
+        "asset" => Some(Asset::TRANSITIONS),
+        "auction" => Some(Auction::TRANSITIONS),
+        "batch_transfer" => Some(BatchTransfer::TRANSITIONS),
+        "order" => Some(Order::TRANSITIONS),
+        "preferences" => Some(Preferences::TRANSITIONS),
+        "rfq" => Some(Rfq::TRANSITIONS),
+        "standing_order" => Some(StandingOrder::TRANSITIONS),
+        "transfer" => Some(Transfer::TRANSITIONS),
+        _ => None,
+    }
+}
+
+pub fn render(name: &str, transitions: &[Transition], format: GraphFormat) -> String {
+    match format {
+        GraphFormat::Dot => to_dot(name, transitions),
+        GraphFormat::Mermaid => to_mermaid(transitions),
+    }
+}
+
+fn label(transition: &Transition) -> String {
+    let mut label = transition.command.to_string();
+    if let Some(guard) = transition.guard {
+        let _ = write!(label, " [{}]", guard);
+    }
+    if !transition.events.is_empty() {
+        let _ = write!(label, " / {}", transition.events.join(", "));
+    }
+    label
+}
+
+fn to_dot(name: &str, transitions: &[Transition]) -> String {
+    let mut out = format!("digraph {} {{\n    rankdir=LR;\n", name);
+    for transition in transitions {
+        let _ = writeln!(
+            out,
+            "    \"{}\" -> \"{}\" [label=\"{}\"];",
+            transition.from,
+            transition.to,
+            label(transition).replace('"', "\\\""),
+        );
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn to_mermaid(transitions: &[Transition]) -> String {
+    let mut out = "stateDiagram-v2\n".to_string();
+    for transition in transitions {
+        let from = if transition.from == "Uninitialized" { "[*]" } else { transition.from };
+        // Mermaid treats ':' in a label as the start of the label, so strip it.
+        let _ = writeln!(out, "    {} --> {} : {}", from, transition.to, label(transition).replace(':', ""));
+    }
+    out
+}
+
+// Checks an aggregate against its transition table using one sample value of each
+// state, command and event:
+// - every state, command and event named by the table has a sample,
+// - a state accepts exactly the commands the table lists for it,
+// - `apply` succeeds for exactly the (state, event) pairs of the table and ends up in
+//   the row's target state; any other pair must be rejected by `apply`.
+#[cfg(test)]
+pub(crate) fn verify_table<A>(states: &[A], commands: &[A::Command], events: &[A::Event])
+where
+    A: TableDriven + Clone + std::fmt::Debug,
+    A::Event: Clone,
+{
+    use std::collections::HashSet;
+    use cqrs_es::DomainEvent;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let state_names: HashSet<&str> = states.iter().map(|s| s.state_name()).collect();
+    let command_names: HashSet<&str> = commands.iter().map(|c| A::command_name(c)).collect();
+    let event_names: HashSet<String> = events.iter().map(|e| e.event_type()).collect();
+    for t in A::TRANSITIONS {
+        assert!(state_names.contains(t.from), "no sample for state {}", t.from);
+        assert!(state_names.contains(t.to), "no sample for state {}", t.to);
+        assert!(command_names.contains(t.command), "no sample for command {}", t.command);
+        for event in t.events {
+            assert!(event_names.contains(*event), "no sample for event {}", event);
+        }
+    }
+
+    for state in states {
+        for command in commands {
+            let listed = A::TRANSITIONS.iter().any(|t| t.from == state.state_name() && t.command == A::command_name(command));
+            assert_eq!(state.accepts(command), listed, "{} accepting {}", state.state_name(), A::command_name(command));
+        }
+        for event in events {
+            let expected = A::TRANSITIONS
+                .iter()
+                .find(|t| t.from == state.state_name() && t.events.contains(&event.event_type().as_str()))
+                .map(|t| t.to);
+            let applied = catch_unwind(AssertUnwindSafe(|| {
+                let mut next = state.clone();
+                next.apply(event.clone());
+                next.state_name()
+            }));
+            match (expected, applied) {
+                (Some(to), Ok(actual)) => assert_eq!(actual, to, "{} + {}", state.state_name(), event.event_type()),
+                (Some(_), Err(_)) => panic!("apply panics on {} + {}, which the table allows", state.state_name(), event.event_type()),
+                (None, Ok(actual)) => panic!("apply accepts {} + {} -> {}, which the table does not list", state.state_name(), event.event_type(), actual),
+                (None, Err(_)) => {},
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::statemachine::{render, transitions_of, GraphFormat, Transition};
+
+    const TABLE: &[Transition] = &[
+        Transition { from: "Uninitialized", command: "Open", guard: None, events: &["Opened"], to: "Open" },
+        Transition { from: "Open", command: "Close", guard: Some("empty"), events: &["Closed"], to: "Closed" },
+    ];
+
+    #[test]
+    fn test_render_dot() {
+        assert_eq!(
+            render("demo", TABLE, GraphFormat::Dot),
+            "digraph demo {\n    rankdir=LR;\n    \"Uninitialized\" -> \"Open\" [label=\"Open / Opened\"];\n    \"Open\" -> \"Closed\" [label=\"Close [empty] / Closed\"];\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_render_mermaid() {
+        assert_eq!(
+            render("demo", TABLE, GraphFormat::Mermaid),
+            "stateDiagram-v2\n    [*] --> Open : Open / Opened\n    Open --> Closed : Close [empty] / Closed\n"
+        );
+    }
+
+    #[test]
+    fn test_known_aggregates() {
+        for aggregate in ["account", "approval", "asset", "auction", "order", "rfq", "transfer"] {
+            assert!(!transitions_of(aggregate).unwrap().is_empty(), "{} has no transitions", aggregate);
+        }
+        assert!(transitions_of("ledger").is_none());
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use axum::extract::Request;
+use axum::http::HeaderMap;
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::runtime::Tokio;
+use opentelemetry_sdk::trace::{Sampler, TracerProvider};
+use opentelemetry_sdk::Resource;
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use crate::config::TelemetryConfig;
+
+// W3C trace context, as carried in request headers and in command and event metadata.
+pub const TRACEPARENT_KEY: &str = "traceparent";
+pub const TRACESTATE_KEY: &str = "tracestate";
+
+// Logs to stdout like `tracing_subscriber::fmt::init`, and with an `otlp_endpoint` also
+// exports the spans, so one trace follows a command from the request through the
+// account commands of its saga to the views. The provider returned flushes the spans
+// still buffered when shut down.
+pub fn init(config: &TelemetryConfig) -> Option<TracerProvider> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let provider = config.otlp_endpoint.as_ref().and_then(|endpoint| {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .with_timeout(Duration::from_millis(config.timeout_ms))
+            .build();
+        match exporter {
+            Ok(exporter) => Some(
+                TracerProvider::builder()
+                    .with_batch_exporter(exporter, Tokio)
+                    .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio))))
+                    .with_resource(Resource::new([KeyValue::new("service.name", config.service_name.clone())]))
+                    .build(),
+            ),
+            Err(err) => {
+                eprintln!("Not exporting traces to {}: {}", endpoint, err);
+                None
+            }
+        }
+    });
+    let otel = provider.as_ref().map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("cqrs-account")));
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(tracing_subscriber::fmt::layer())
+        .with(otel)
+        .init();
+    if let Some(provider) = &provider {
+        global::set_tracer_provider(provider.clone());
+    }
+    provider
+}
+
+// The trace context of the current span, to hand on with a command or a request. Empty
+// when traces are not exported.
+pub fn current_context() -> HashMap<String, String> {
+    let mut carrier = HashMap::new();
+    let context = tracing::Span::current().context();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut carrier));
+    carrier
+}
+
+// Adds the trace context of the current span to command metadata, leaving a context the
+// caller already put there.
+pub fn inject(metadata: &mut HashMap<String, String>) {
+    for (key, value) in current_context() {
+        metadata.entry(key).or_insert(value);
+    }
+}
+
+// Continues the trace of `carrier` in `span`, e.g. from the metadata of an event.
+pub fn set_parent(span: &tracing::Span, carrier: &impl Extractor) {
+    let context = global::get_text_map_propagator(|propagator| propagator.extract(carrier));
+    span.set_parent(context);
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+// Serves each request in a span continuing the trace of its `traceparent` header, the
+// commands it executes carry it on, see `CommandExtractor`.
+pub async fn trace_layer(request: Request, next: Next) -> Response {
+    let span = tracing::info_span!("request", method = %request.method(), path = %request.uri().path(), status = tracing::field::Empty);
+    set_parent(&span, &HeaderExtractor(request.headers()));
+    let response = next.run(request).instrument(span.clone()).await;
+    span.record("status", response.status().as_u16());
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+    use opentelemetry_sdk::trace::TracerProvider;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+    use crate::telemetry::{current_context, inject, set_parent, TRACEPARENT_KEY};
+
+    #[test]
+    fn test_context_carried_through_metadata() {
+        opentelemetry::global::set_text_map_propagator(opentelemetry_sdk::propagation::TraceContextPropagator::new());
+        let provider = TracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+            let parent = HashMap::from([(TRACEPARENT_KEY.to_string(), traceparent.to_string())]);
+            let span = tracing::info_span!("command");
+            set_parent(&span, &parent);
+            let _entered = span.enter();
+
+            // The span continues the trace, as a child of the span that sent it.
+            let context = current_context();
+            assert!(context[TRACEPARENT_KEY].starts_with("00-0af7651916cd43dd8448eb211c80319c-"));
+            assert_ne!(context[TRACEPARENT_KEY], traceparent);
+            assert!(span.context().span().span_context().is_sampled());
+
+            let mut metadata = HashMap::from([("initiator".to_string(), "ops".to_string())]);
+            inject(&mut metadata);
+            assert_eq!(metadata[TRACEPARENT_KEY], context[TRACEPARENT_KEY]);
+            assert_eq!(metadata["initiator"], "ops");
+        });
+        // Nothing to carry outside of a trace.
+        assert!(current_context().is_empty());
+    }
+}
@@ -1,26 +1,45 @@
 #![deny(arithmetic_overflow)]
 
 use std::mem::swap;
-use futures::future::BoxFuture;
 use std::sync::Arc;
 
 use async_trait::async_trait;
+use tracing::Instrument;
 use cqrs_es::{Aggregate, AggregateError};
-use postgres_es::PostgresCqrs;
+use crate::compensation::{guard, journaled_undo, CompensationJournal, Journaled, StepGuard};
+use crate::config::CommandPolicyConfig;
+use crate::dlq::DeadLetters;
+use crate::services::AccountExecutor;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-use crate::{
-    account::{
-        aggregate::Account,
-        commands::AccountCommand,
-        events::AccountError,
-    },
-    util::transaction_guard::TransactionGuard,
+use crate::account::{
+    commands::AccountCommand,
+    events::AccountError,
 };
+use crate::statemachine::{StateMachine, TableDriven, Transition};
+use crate::util::clock::{Clock, SystemClock};
 use crate::util::types::ByteArray32;
 use super::{commands::TransferCommand, events::TransferEvent};
+use crate::metrics::ErrorVariant;
+
+// What `to_account` sends back to `from_account` in a swap.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct SwapLeg {
+    pub asset: String,
+    pub amount: u64,
+}
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+// A refund a done transfer sent back, see `TransferCommand::Refund`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct Refund {
+    pub refund_id: ByteArray32,
+    pub amount: u64,
+    pub reason: String,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Config {
     pub transfer_id: ByteArray32,
     pub from_account: String,
@@ -29,28 +48,123 @@ pub struct Config {
     pub amount: u64,
     pub timestamp: u64,
     pub description: String,
+    #[serde(default)]
+    pub opened_at: u64,
+    // How often the transfer was retried after failing.
+    #[serde(default)]
+    pub attempt: u32,
+    // The counter leg when the transfer is a swap.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub swap: Option<SwapLeg>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+impl Config {
+    // Whether `command` is the `Open` or `SwapOpen` this transfer was created by, resent
+    // by a client retry.
+    fn opened_by(&self, command: &TransferCommand) -> bool {
+        let (transfer_id, from_account, to_account, asset, amount, timestamp, description, swap) = match command {
+            TransferCommand::Open { transfer_id, from_account, to_account, asset, amount, timestamp, description } => {
+                (transfer_id, from_account, to_account, asset, amount, timestamp, description, None)
+            }
+            TransferCommand::SwapOpen { transfer_id, from_account, to_account, asset, amount, counter_asset, counter_amount, timestamp, description } => {
+                let swap = SwapLeg { asset: counter_asset.clone(), amount: *counter_amount };
+                (transfer_id, from_account, to_account, asset, amount, timestamp, description, Some(swap))
+            }
+            _ => return false,
+        };
+        *transfer_id == self.transfer_id
+            && *from_account == self.from_account
+            && *to_account == self.to_account
+            && *asset == self.asset
+            && *amount == self.amount
+            && *timestamp == self.timestamp
+            && *description == self.description
+            && swap == self.swap
+    }
+
+    // The txid the legs of the current attempt run under. Each retry takes its own, so
+    // reversals of an earlier attempt still on their way, or recovered from the
+    // compensation journal, cannot undo the legs of a later one.
+    pub fn txid(&self) -> ByteArray32 {
+        match self.attempt {
+            0 => self.transfer_id,
+            attempt => ByteArray32::derive("transfer-retry", &format!("{}\0{}", self.transfer_id.hex(), attempt)),
+        }
+    }
+
+    // The txid the legs of a refund run under. Derived here rather than taken from the
+    // refund id, so a refund id a client picked cannot collide with a txid already used
+    // on one of the accounts, where that leg would be taken as done.
+    pub fn refund_txid(&self, refund_id: &ByteArray32) -> ByteArray32 {
+        ByteArray32::derive("transfer-refund", &format!("{}\0{}", self.transfer_id.hex(), refund_id.hex()))
+    }
+
+    // Transfers opened before `opened_at` was recorded fall back to the client timestamp.
+    pub fn expires_at(&self, timeout_secs: u64) -> u64 {
+        let opened_at = if self.opened_at == 0 { self.timestamp } else { self.opened_at };
+        opened_at.saturating_add(timeout_secs)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub enum Transfer {
     #[default]
     Uninitialized,
     Opened {
         config: Config,
     },
+    // A swap with both legs locked, only settling them is left.
+    Locked {
+        config: Config,
+    },
     Done {
         config: Config,
         timestamp: u64,
+        #[serde(default)]
+        refunds: Vec<Refund>,
     },
     Failed {
         config: Config,
         reason: String,
         timestamp: u64,
     },
+    // Canceled, or expired without a cancel reason, and reversed by the next `Continue`,
+    // so a `Continue` still moving funds cannot run alongside the reversal.
+    Reversing {
+        config: Config,
+        cancel_reason: Option<String>,
+    },
+    ReversalFailed {
+        config: Config,
+        reason: String,
+        timestamp: u64,
+    },
     Canceled {
         config: Config,
         reason: String,
+        #[serde(default)]
+        timestamp: u64,
     },
+    Expired {
+        config: Config,
+        timestamp: u64,
+    },
+}
+
+impl Transfer {
+    pub fn config(&self) -> Option<&Config> {
+        match self {
+            Transfer::Uninitialized => None,
+            Transfer::Opened { config }
+            | Transfer::Locked { config }
+            | Transfer::Done { config, .. }
+            | Transfer::Failed { config, .. }
+            | Transfer::Reversing { config, .. }
+            | Transfer::ReversalFailed { config, .. }
+            | Transfer::Canceled { config, .. }
+            | Transfer::Expired { config, .. } => Some(config),
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -61,50 +175,190 @@ pub enum TransferError {
     AccountError(#[from] AccountError),
     #[error("Aggregate error: {0}")]
     AggregateError(#[from] AggregateError<AccountError>),
+    #[error("Policy violation: {0}")]
+    PolicyViolation(String),
+}
+
+impl ErrorVariant for TransferError {
+    fn variant(&self) -> &'static str {
+        match self {
+            TransferError::InvalidState(_) => "InvalidState",
+            TransferError::PolicyViolation(_) => "PolicyViolation",
+            TransferError::AccountError(e) => e.variant(),
+            TransferError::AggregateError(e) => e.variant(),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct TransferServices {
-    account_service: Arc<PostgresCqrs<Account>>,
+    account_service: Arc<dyn AccountExecutor>,
+    // Seconds an opened transfer may wait for `Continue` before it can be expired.
+    timeout_secs: u64,
+    // Where the reversals that failed are left for an operator.
+    dead_letters: Option<DeadLetters>,
+    // Where the reversals wait while their step runs, see `crate::compensation`.
+    compensations: Option<CompensationJournal>,
+    // What the transfer stamps its steps with.
+    clock: Arc<dyn Clock>,
+    command_policy: CommandPolicyConfig,
 }
 
 impl TransferServices {
-    pub fn new(account_service: Arc<PostgresCqrs<Account>>) -> Self {
-        Self { account_service }
+    pub fn new(account_service: Arc<dyn AccountExecutor>, timeout_secs: u64) -> Self {
+        Self { account_service, timeout_secs, dead_letters: None, compensations: None, clock: Arc::new(SystemClock), command_policy: CommandPolicyConfig::default() }
+    }
+
+    pub fn with_dead_letters(mut self, dead_letters: DeadLetters) -> Self {
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+
+    pub fn with_compensations(mut self, compensations: CompensationJournal) -> Self {
+        self.compensations = Some(compensations);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_command_policy(mut self, command_policy: CommandPolicyConfig) -> Self {
+        self.command_policy = command_policy;
+        self
+    }
+
+    pub(crate) fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    async fn journal(&self, transfer_id: ByteArray32, account_id: &str, undo: &AccountCommand) -> Result<Option<Journaled>, TransferError> {
+        match &self.compensations {
+            Some(journal) => Ok(Some(journal.record("transfer", &transfer_id.hex(), account_id, undo).await?)),
+            None => Ok(None),
+        }
+    }
+
+    // Undoes whatever part of the transfer reached the accounts. `Continue` reverses its
+    // own partial work, but a crash between the account commands and the transfer
+    // events can leave the debit, or both legs, applied to a transfer still `Opened`.
+    // Of a swap that is one or both of its locks, nothing is settled before `Locked`.
+    async fn reverse(&self, config: &Config, timestamp: u64) -> Result<(), TransferError> {
+        if config.swap.is_some() {
+            for account_id in [&config.to_account, &config.from_account] {
+                match self.account_service.execute(account_id, AccountCommand::unlock_funds(config.txid(), timestamp)).await {
+                    Ok(_)
+                    | Err(AggregateError::UserError(AccountError::LockNotFound | AccountError::AccountNotFound)) => {}
+                    Err(e) => return Err(TransferError::AggregateError(e)),
+                }
+            }
+            return Ok(());
+        }
+        let reverse_credit = AccountCommand::reverse_credit(
+            config.txid(),
+            timestamp,
+            config.from_account.clone(),
+            config.asset.clone(),
+            config.amount,
+        );
+        match self.account_service.execute(&config.to_account, reverse_credit).await {
+            Ok(_)
+            | Err(AggregateError::UserError(AccountError::TransactionNotFound | AccountError::AccountNotFound)) => {}
+            Err(e) => return Err(TransferError::AggregateError(e)),
+        }
+        let reverse_debit = AccountCommand::reverse_debit(
+            config.txid(),
+            timestamp,
+            config.to_account.clone(),
+            config.asset.clone(),
+            config.amount,
+        );
+        match self.account_service.execute(&config.from_account, reverse_debit).await {
+            Ok(_)
+            | Err(AggregateError::UserError(AccountError::TransactionNotFound | AccountError::AccountNotFound)) => Ok(()),
+            Err(e) => Err(TransferError::AggregateError(e)),
+        }
     }
 
     async fn debit(
         &self,
+        config: &Config,
         txid: ByteArray32,
         from_account: String,
         to_account: String,
-        asset: String,
         amount: u64,
         timestamp: u64,
-    ) -> Result<TransactionGuard<BoxFuture<'static, ()>>, TransferError> {
+    ) -> Result<StepGuard, TransferError> {
         let account_service = self.account_service.clone();
-        let undo = {
+        let asset = config.asset.clone();
+        let command = AccountCommand::reverse_debit(txid, timestamp, to_account.clone(), asset.clone(), amount);
+        let journaled = self.journal(config.transfer_id, &from_account, &command).await?;
+        let undo = journaled_undo(journaled.clone(), {
             let from_account = from_account.clone();
-            let to_account = to_account.clone();
-            let asset = asset.clone();
+            let dead_letters = self.dead_letters.clone();
             async move {
-                let command =
-                    AccountCommand::reverse_debit(txid, timestamp, to_account.clone(), asset, amount);
-                match account_service.execute(&from_account, command).await {
+                match account_service.execute(&from_account, command.clone()).await {
                     Ok(_) => {}
                     Err(AggregateError::UserError(AccountError::TransactionNotFound)) => {}
                     Err(e) => {
                         tracing::error!("Error undoing debit: {:?}", e);
+                        if let Some(dead_letters) = dead_letters {
+                            dead_letters.record("transfer", "reverse_debit", &txid.hex(), &from_account, &command, &e).await;
+                        }
                     }
                 }
             }
-        };
+        });
 
         let command = AccountCommand::debit(txid, timestamp, to_account, asset, amount);
 
         match self.account_service.execute(&from_account, command).await {
             Ok(_) | Err(AggregateError::UserError(AccountError::DuplicateTransaction(_))) => {
-                Ok(TransactionGuard::new(Box::pin(undo)))
+                Ok(guard(journaled, undo))
+            }
+            Err(agg_err) => {
+                undo.await;
+                Err(TransferError::AggregateError(agg_err))
+            }
+        }
+    }
+
+    // Locks one leg of a swap, unlocked again when the guard is dropped.
+    async fn lock(
+        &self,
+        config: &Config,
+        account_id: String,
+        asset: String,
+        amount: u64,
+        timestamp: u64,
+    ) -> Result<StepGuard, TransferError> {
+        let account_service = self.account_service.clone();
+        let txid = config.txid();
+        let command = AccountCommand::unlock_funds(txid, timestamp);
+        let journaled = self.journal(config.transfer_id, &account_id, &command).await?;
+        let undo = journaled_undo(journaled.clone(), {
+            let account_id = account_id.clone();
+            let dead_letters = self.dead_letters.clone();
+            async move {
+                match account_service.execute(&account_id, command.clone()).await {
+                    Ok(_) | Err(AggregateError::UserError(AccountError::LockNotFound)) => {}
+                    Err(e) => {
+                        tracing::error!("Error undoing lock: {:?}", e);
+                        if let Some(dead_letters) = dead_letters {
+                            dead_letters.record("transfer", "unlock", &txid.hex(), &account_id, &command, &e).await;
+                        }
+                    }
+                }
+            }
+        });
+
+        let command = AccountCommand::lock_funds(txid, timestamp, asset, amount);
+
+        match self.account_service.execute(&account_id, command).await {
+            // Locked by an earlier `Continue` that did not get to record it.
+            Ok(_) | Err(AggregateError::UserError(AccountError::DuplicateLock)) => {
+                Ok(guard(journaled, undo))
             }
             Err(agg_err) => {
                 undo.await;
@@ -113,43 +367,82 @@ impl TransferServices {
         }
     }
 
+    // Settles the lock of one leg of a swap against the other leg, which `account_id`
+    // receives from `counterparty`.
+    async fn settle(
+        &self,
+        txid: ByteArray32,
+        account_id: &str,
+        counterparty: String,
+        receive_asset: String,
+        receive_amount: u64,
+        timestamp: u64,
+    ) -> Result<(), TransferError> {
+        let command = AccountCommand::settle(txid, timestamp, counterparty, receive_asset, receive_amount);
+        match self.account_service.execute(account_id, command).await {
+            // Settled by an earlier `Continue` that did not get to settle the other leg.
+            Ok(_) | Err(AggregateError::UserError(AccountError::DuplicateTransaction(_))) => Ok(()),
+            Err(e) => Err(TransferError::AggregateError(e)),
+        }
+    }
+
+    // Gives the first leg of a swap back what it settled, after the counter leg could not
+    // settle: takes the counter asset it received and returns its asset, then releases the
+    // lock of the counter leg. Under txids of their own derived from the transfer, so a
+    // `Continue` repeating it finds it done.
+    async fn unwind(&self, config: &Config, counter: &SwapLeg, timestamp: u64) -> Result<(), TransferError> {
+        let txid = config.txid();
+        let take_back = AccountCommand::debit(
+            ByteArray32::derive("swap-unwind-debit", &txid.hex()), timestamp, config.to_account.clone(), counter.asset.clone(), counter.amount,
+        );
+        let give_back = AccountCommand::credit(
+            ByteArray32::derive("swap-unwind-credit", &txid.hex()), timestamp, config.to_account.clone(), config.asset.clone(), config.amount,
+        );
+        let unlock = AccountCommand::unlock_funds(txid, timestamp);
+        for (account_id, command) in [(&config.from_account, take_back), (&config.from_account, give_back), (&config.to_account, unlock)] {
+            match self.account_service.execute(account_id, command).await {
+                Ok(_)
+                | Err(AggregateError::UserError(AccountError::DuplicateTransaction(_) | AccountError::LockNotFound)) => {}
+                Err(e) => return Err(TransferError::AggregateError(e)),
+            }
+        }
+        Ok(())
+    }
+
     async fn credit(
         &self,
+        config: &Config,
         txid: ByteArray32,
         from_account: String,
         to_account: String,
-        asset: String,
         amount: u64,
         timestamp: u64,
-    ) -> Result<TransactionGuard<BoxFuture<'static, ()>>, TransferError> {
+    ) -> Result<StepGuard, TransferError> {
         let account_service = self.account_service.clone();
-        let undo = {
-            let from_account = from_account.clone();
+        let asset = config.asset.clone();
+        let command = AccountCommand::reverse_credit(txid, timestamp, from_account.clone(), asset.clone(), amount);
+        let journaled = self.journal(config.transfer_id, &to_account, &command).await?;
+        let undo = journaled_undo(journaled.clone(), {
             let to_account = to_account.clone();
-            let asset = asset.clone();
+            let dead_letters = self.dead_letters.clone();
             async move {
-                let command = AccountCommand::reverse_credit(
-                    txid,
-                    timestamp,
-                    from_account,
-                    asset,
-                    amount,
-                );
-
-                match account_service.execute(&to_account, command).await {
+                match account_service.execute(&to_account, command.clone()).await {
                     Ok(_) | Err(AggregateError::UserError(AccountError::TransactionNotFound)) => {}
                     Err(e) => {
                         tracing::error!("Error undoing credit: {:?}", e);
+                        if let Some(dead_letters) = dead_letters {
+                            dead_letters.record("transfer", "reverse_credit", &txid.hex(), &to_account, &command, &e).await;
+                        }
                     }
                 }
             }
-        };
+        });
 
         let command = AccountCommand::credit(txid, timestamp, from_account, asset, amount);
 
         match self.account_service.execute(&to_account, command).await {
             Ok(_) | Err(AggregateError::UserError(AccountError::DuplicateTransaction(_))) => {
-                Ok(TransactionGuard::new(Box::pin(undo)))
+                Ok(guard(journaled, undo))
             }
             Err(agg_err) => {
                 undo.await;
@@ -159,24 +452,16 @@ impl TransferServices {
     }
 }
 
-#[async_trait]
-impl Aggregate for Transfer {
-    type Command = TransferCommand;
-    type Event = TransferEvent;
-    type Error = TransferError;
-    type Services = TransferServices;
-
-    fn aggregate_type() -> String {
-        "transfer".to_string()
-    }
-
-    async fn handle(
-        &self,
-        command: Self::Command,
-        service: &Self::Services,
-    ) -> Result<Vec<Self::Event>, Self::Error> {
-        match command {
-            TransferCommand::Open {
+impl Transfer {
+    pub(crate) async fn decide(&self, command: TransferCommand, service: &TransferServices) -> Result<Vec<TransferEvent>, TransferError> {
+        if !self.accepts(&command) {
+            return Err(TransferError::InvalidState(format!("Transfer current at {} state, cannot accept {} command", self.state_name(), Transfer::command_name(&command))));
+        }
+        if let Transfer::Uninitialized = self {
+            service.command_policy.check_transfer(&command).map_err(|err| TransferError::PolicyViolation(err.to_string()))?;
+        }
+        match (self, command) {
+            (Transfer::Uninitialized, TransferCommand::Open {
                 transfer_id,
                 from_account,
                 to_account,
@@ -184,60 +469,249 @@ impl Aggregate for Transfer {
                 amount,
                 timestamp,
                 description,
-            } => {
-                if let Transfer::Uninitialized = self {
-                    Ok(vec![TransferEvent::Opened {
-                        transfer_id,
-                        from_account,
-                        to_account,
-                        asset,
-                        amount,
-                        timestamp,
-                        description,
-                    }])
-                } else {
-                    Err(TransferError::InvalidState(
-                        "Transfer is already opened".to_string(),
-                    ))
+            }) => {
+                Ok(vec![TransferEvent::Opened {
+                    transfer_id,
+                    from_account,
+                    to_account,
+                    asset,
+                    amount,
+                    timestamp,
+                    description,
+                    opened_at: service.now(),
+                }])
+            },
+            (Transfer::Uninitialized, TransferCommand::SwapOpen {
+                transfer_id,
+                from_account,
+                to_account,
+                asset,
+                amount,
+                counter_asset,
+                counter_amount,
+                timestamp,
+                description,
+            }) => {
+                // Both legs would lock under the same txid.
+                if from_account == to_account {
+                    return Err(TransferError::InvalidState("A swap takes two accounts".to_string()));
+                }
+                Ok(vec![TransferEvent::SwapOpened {
+                    transfer_id,
+                    from_account,
+                    to_account,
+                    asset,
+                    amount,
+                    counter_asset,
+                    counter_amount,
+                    timestamp,
+                    description,
+                    opened_at: service.now(),
+                }])
+            },
+            (state, command @ (TransferCommand::Open { .. } | TransferCommand::SwapOpen { .. })) => {
+                match state.config() {
+                    Some(config) if config.opened_by(&command) => Ok(vec![]),
+                    _ => Err(TransferError::InvalidState("Transfer already opened with different parameters".to_string())),
                 }
             },
-            TransferCommand::Continue => {
-                let Transfer::Opened { config } = self else {
-                    return Err(TransferError::InvalidState(
-                        "State is not Opened".to_string(),
-                    ));
+            (Transfer::Opened { config: config @ Config { swap: Some(counter), .. } }, TransferCommand::Continue) => {
+                let timestamp = service.now();
+                let from_lock = match service
+                    .lock(config, config.from_account.clone(), config.asset.clone(), config.amount, timestamp)
+                    .await
+                {
+                    Ok(guard) => guard,
+                    Err(TransferError::AggregateError(AggregateError::UserError(ae))) => {
+                        return Ok(vec![TransferEvent::Failed { reason: format!("Failed to lock the leg of {}: {:?}", config.from_account, ae), timestamp }]);
+                    },
+                    Err(e) => return Err(e),
                 };
-                let timestamp = 0;
-                let debit_undo_guard = service
+                // Dropping the first lock guard on any of the early returns below unlocks it.
+                let to_lock = match service
+                    .lock(config, config.to_account.clone(), counter.asset.clone(), counter.amount, timestamp)
+                    .await
+                {
+                    Ok(guard) => guard,
+                    Err(TransferError::AggregateError(AggregateError::UserError(ae))) => {
+                        return Ok(vec![TransferEvent::Failed { reason: format!("Failed to lock the leg of {}: {:?}", config.to_account, ae), timestamp }]);
+                    },
+                    Err(e) => return Err(e),
+                };
+                to_lock.commit().await?;
+                from_lock.commit().await?;
+                Ok(vec![TransferEvent::SwapLocked { timestamp }])
+            },
+            // Both settlements are idempotent, a `Continue` failing half way is sent again,
+            // by the expiry sweep at the latest. A leg an account rejects fails the swap:
+            // both locks are released, or the first leg unwound when it settled already.
+            (Transfer::Locked { config: config @ Config { swap: Some(counter), .. } }, TransferCommand::Continue) => {
+                let timestamp = service.now();
+                let settled = service
+                    .settle(config.txid(), &config.from_account, config.to_account.clone(), counter.asset.clone(), counter.amount, timestamp)
+                    .await;
+                if let Err(TransferError::AggregateError(AggregateError::UserError(ae))) = settled {
+                    service.reverse(config, timestamp).await?;
+                    return Ok(vec![TransferEvent::Failed { reason: format!("Failed to settle the leg of {}: {:?}", config.from_account, ae), timestamp }]);
+                }
+                settled?;
+                let settled = service
+                    .settle(config.txid(), &config.to_account, config.from_account.clone(), config.asset.clone(), config.amount, timestamp)
+                    .await;
+                if let Err(TransferError::AggregateError(AggregateError::UserError(ae))) = settled {
+                    service.unwind(config, counter, timestamp).await?;
+                    return Ok(vec![TransferEvent::Failed { reason: format!("Failed to settle the leg of {}: {:?}", config.to_account, ae), timestamp }]);
+                }
+                settled?;
+                Ok(vec![TransferEvent::Done { timestamp }])
+            },
+            (Transfer::Opened { config }, TransferCommand::Continue) => {
+                let timestamp = service.now();
+                let debit_undo_guard = match service
                     .debit(
-                        config.transfer_id,
+                        config,
+                        config.txid(),
                         config.from_account.to_string(),
                         config.to_account.to_string(),
-                        config.asset.to_string(),
                         config.amount,
                         timestamp,
                     )
-                    .await?;
-                let credit_undo_guard = service
+                    .await
+                {
+                    Ok(guard) => guard,
+                    Err(TransferError::AggregateError(AggregateError::UserError(ae))) => {
+                        return Ok(vec![TransferEvent::Failed { reason: format!("Failed to debit: {:?}", ae), timestamp }]);
+                    },
+                    Err(e) => return Err(e),
+                };
+                // Dropping the debit guard on any of the early returns below reverses the debit.
+                let credit_undo_guard = match service
                     .credit(
-                        config.transfer_id,
+                        config,
+                        config.txid(),
                         config.from_account.to_string(),
                         config.to_account.to_string(),
-                        config.asset.to_string(),
                         config.amount,
                         timestamp,
                     )
-                    .await?;
-                credit_undo_guard.commit();
-                debit_undo_guard.commit();
+                    .await
+                {
+                    Ok(guard) => guard,
+                    Err(TransferError::AggregateError(AggregateError::UserError(ae))) => {
+                        return Ok(vec![TransferEvent::Failed { reason: format!("Failed to credit: {:?}", ae), timestamp }]);
+                    },
+                    Err(e) => return Err(e),
+                };
+                credit_undo_guard.commit().await?;
+                debit_undo_guard.commit().await?;
                 Ok(vec![TransferEvent::Done { timestamp }])
+            },
+            (Transfer::Opened { .. }, TransferCommand::Cancel { reason }) => {
+                Ok(vec![TransferEvent::Reversing { reason: Some(reason), timestamp: service.now() }])
+            },
+            (Transfer::Opened { config }, TransferCommand::Expire) => {
+                let timestamp = service.now();
+                let expires_at = config.expires_at(service.timeout_secs);
+                if timestamp < expires_at {
+                    return Err(TransferError::InvalidState(format!("Transfer does not expire before {}", expires_at)));
+                }
+                Ok(vec![TransferEvent::Reversing { reason: None, timestamp }])
+            },
+            // A reversal failing on the way is sent again, by the saga watchdog at the latest.
+            // One an account rejects, e.g. the credit spent since, is not going to succeed.
+            (Transfer::Reversing { config, cancel_reason }, TransferCommand::Continue) => {
+                let timestamp = service.now();
+                match service.reverse(config, timestamp).await {
+                    Ok(()) => Ok(vec![match cancel_reason {
+                        Some(reason) => TransferEvent::Canceled { reason: reason.clone(), timestamp },
+                        None => TransferEvent::Expired { timestamp },
+                    }]),
+                    Err(TransferError::AggregateError(AggregateError::UserError(ae))) => {
+                        Ok(vec![TransferEvent::ReversalFailed { reason: format!("Failed to reverse: {:?}", ae), timestamp }])
+                    },
+                    Err(e) => Err(e),
+                }
+            },
+            // A `Continue` that started before the transfer was canceled loses the commit
+            // and is sent again, reversing what it moved after the reversal ran.
+            (Transfer::Canceled { config, .. } | Transfer::Expired { config, .. }, TransferCommand::Continue) => {
+                service.reverse(config, service.now()).await?;
+                Ok(vec![])
+            },
+            (Transfer::Done { config, refunds, .. }, TransferCommand::Refund { refund_id, amount, reason }) => {
+                if let Some(refund) = refunds.iter().find(|refund| refund.refund_id == refund_id) {
+                    return match refund.amount == amount {
+                        true => Ok(vec![]),
+                        false => Err(TransferError::InvalidState("Refund already made with a different amount".to_string())),
+                    };
+                }
+                if config.swap.is_some() {
+                    return Err(TransferError::InvalidState("A swap cannot be refunded".to_string()));
+                }
+                service.command_policy
+                    .policy(&config.to_account)
+                    .check_memo(reason.len())
+                    .map_err(|err| TransferError::PolicyViolation(err.to_string()))?;
+                let refundable = config.amount - refunds.iter().map(|refund| refund.amount).sum::<u64>();
+                if amount == 0 || amount > refundable {
+                    return Err(TransferError::InvalidState(format!("Refund of {} exceeds the {} left to refund", amount, refundable)));
+                }
+                let timestamp = service.now();
+                let txid = config.refund_txid(&refund_id);
+                // Dropping the debit guard when the credit fails takes the refund back.
+                let debit_undo_guard = service
+                    .debit(config, txid, config.to_account.clone(), config.from_account.clone(), amount, timestamp)
+                    .await?;
+                let credit_undo_guard = service
+                    .credit(config, txid, config.to_account.clone(), config.from_account.clone(), amount, timestamp)
+                    .await?;
+                credit_undo_guard.commit().await?;
+                debit_undo_guard.commit().await?;
+                Ok(vec![TransferEvent::Refunded {
+                    refund_id,
+                    from_account: config.to_account.clone(),
+                    to_account: config.from_account.clone(),
+                    asset: config.asset.clone(),
+                    amount,
+                    reason,
+                    timestamp,
+                }])
+            },
+            (Transfer::Failed { config, .. }, TransferCommand::Retry) => {
+                Ok(vec![TransferEvent::Retried { attempt: config.attempt + 1, timestamp: service.now() }])
+            },
+            (state, cmd) => {
+                Err(TransferError::InvalidState(format!("Transfer current at {:?} state, cannot accept {:?} command", state, cmd)))
             }
         }
     }
+}
+
+#[async_trait]
+impl Aggregate for Transfer {
+    type Command = TransferCommand;
+    type Event = TransferEvent;
+    type Error = TransferError;
+    type Services = TransferServices;
+
+    fn aggregate_type() -> String {
+        "transfer".to_string()
+    }
+
+    async fn handle(
+        &self,
+        command: Self::Command,
+        service: &Self::Services,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        let span = tracing::info_span!("Transfer::handle", transfer_id = self.config().map(|config| config.transfer_id.hex()), command = Transfer::command_name(&command));
+        self.decide(command, service).instrument(span).await
+    }
 
     fn apply(&mut self, event: Self::Event) {
-        match event {
-            TransferEvent::Opened {
+        let mut prev = Default::default();
+        swap(&mut prev, self);
+        *self = match (prev, event) {
+            (Transfer::Uninitialized, TransferEvent::Opened {
                 transfer_id,
                 from_account,
                 to_account,
@@ -245,40 +719,543 @@ impl Aggregate for Transfer {
                 amount,
                 timestamp,
                 description,
-            } => {
-                *self = Transfer::Opened {
-                    config: Config {
-                        transfer_id,
-                        from_account,
-                        to_account,
-                        asset,
-                        amount,
-                        timestamp,
-                        description,
-                    },
-                }
-            }
-            TransferEvent::Failed { reason, timestamp } => {
-                let mut temp = Default::default();
-                if let Transfer::Opened { config } = self {
-                    swap(&mut temp, config);
-                }
-                *self = Transfer::Failed {
-                    config: temp,
-                    reason,
-                    timestamp
-                }
-            }
-            TransferEvent::Done { timestamp } => {
-                let mut temp = Default::default();
-                if let Transfer::Opened { config } = self {
-                    swap(&mut temp, config);
-                }
-                *self = Transfer::Done {
-                    config: temp,
-                    timestamp
-                }
+                opened_at,
+            }) => Transfer::Opened {
+                config: Config {
+                    transfer_id,
+                    from_account,
+                    to_account,
+                    asset,
+                    amount,
+                    timestamp,
+                    description,
+                    opened_at,
+                    attempt: 0,
+                    swap: None,
+                },
+            },
+            (Transfer::Uninitialized, TransferEvent::SwapOpened {
+                transfer_id,
+                from_account,
+                to_account,
+                asset,
+                amount,
+                counter_asset,
+                counter_amount,
+                timestamp,
+                description,
+                opened_at,
+            }) => Transfer::Opened {
+                config: Config {
+                    transfer_id,
+                    from_account,
+                    to_account,
+                    asset,
+                    amount,
+                    timestamp,
+                    description,
+                    opened_at,
+                    attempt: 0,
+                    swap: Some(SwapLeg { asset: counter_asset, amount: counter_amount }),
+                },
+            },
+            (Transfer::Opened { config }, TransferEvent::SwapLocked { .. }) => Transfer::Locked { config },
+            (Transfer::Opened { config } | Transfer::Locked { config }, TransferEvent::Done { timestamp }) => Transfer::Done {
+                config,
+                timestamp,
+                refunds: Vec::new(),
+            },
+            (Transfer::Done { config, timestamp, mut refunds }, TransferEvent::Refunded { refund_id, amount, reason, timestamp: refunded_at, .. }) => {
+                refunds.push(Refund { refund_id, amount, reason, timestamp: refunded_at });
+                Transfer::Done { config, timestamp, refunds }
+            },
+            (Transfer::Opened { config } | Transfer::Locked { config }, TransferEvent::Failed { reason, timestamp }) => Transfer::Failed {
+                config,
+                reason,
+                timestamp,
+            },
+            (Transfer::Opened { config }, TransferEvent::Reversing { reason, .. }) => Transfer::Reversing {
+                config,
+                cancel_reason: reason,
+            },
+            (Transfer::Reversing { config, .. }, TransferEvent::ReversalFailed { reason, timestamp }) => Transfer::ReversalFailed {
+                config,
+                reason,
+                timestamp,
+            },
+            (Transfer::Opened { config } | Transfer::Reversing { config, .. }, TransferEvent::Canceled { reason, timestamp }) => Transfer::Canceled {
+                config,
+                reason,
+                timestamp,
+            },
+            (Transfer::Opened { config } | Transfer::Reversing { config, .. }, TransferEvent::Expired { timestamp }) => Transfer::Expired {
+                config,
+                timestamp,
+            },
+            (Transfer::Failed { config, .. }, TransferEvent::Retried { attempt, timestamp }) => Transfer::Opened {
+                config: Config { attempt, opened_at: timestamp, ..config },
+            },
+            (state, event) => unreachable!("Invalid state transition: {:?} -> {:?}", state, event),
+        };
+    }
+}
+
+impl StateMachine for Transfer {
+    const TRANSITIONS: &'static [Transition] = &[
+        Transition { from: "Uninitialized", command: "Open", guard: None, events: &["Opened"], to: "Opened" },
+        Transition { from: "Opened", command: "Open", guard: Some("same Open resent"), events: &[], to: "Opened" },
+        Transition { from: "Done", command: "Open", guard: Some("same Open resent"), events: &[], to: "Done" },
+        Transition { from: "Failed", command: "Open", guard: Some("same Open resent"), events: &[], to: "Failed" },
+        Transition { from: "Canceled", command: "Open", guard: Some("same Open resent"), events: &[], to: "Canceled" },
+        Transition { from: "Expired", command: "Open", guard: Some("same Open resent"), events: &[], to: "Expired" },
+        Transition { from: "Locked", command: "Open", guard: Some("same Open resent"), events: &[], to: "Locked" },
+        Transition { from: "Uninitialized", command: "SwapOpen", guard: None, events: &["SwapOpened"], to: "Opened" },
+        Transition { from: "Opened", command: "SwapOpen", guard: Some("same SwapOpen resent"), events: &[], to: "Opened" },
+        Transition { from: "Locked", command: "SwapOpen", guard: Some("same SwapOpen resent"), events: &[], to: "Locked" },
+        Transition { from: "Done", command: "SwapOpen", guard: Some("same SwapOpen resent"), events: &[], to: "Done" },
+        Transition { from: "Failed", command: "SwapOpen", guard: Some("same SwapOpen resent"), events: &[], to: "Failed" },
+        Transition { from: "Canceled", command: "SwapOpen", guard: Some("same SwapOpen resent"), events: &[], to: "Canceled" },
+        Transition { from: "Expired", command: "SwapOpen", guard: Some("same SwapOpen resent"), events: &[], to: "Expired" },
+        Transition { from: "Opened", command: "Continue", guard: Some("debit and credit succeed"), events: &["Done"], to: "Done" },
+        Transition { from: "Opened", command: "Continue", guard: Some("both legs of a swap locked"), events: &["SwapLocked"], to: "Locked" },
+        Transition { from: "Opened", command: "Continue", guard: Some("an account rejects the transaction"), events: &["Failed"], to: "Failed" },
+        Transition { from: "Locked", command: "Continue", guard: Some("both legs of the swap settled"), events: &["Done"], to: "Done" },
+        Transition { from: "Locked", command: "Continue", guard: Some("an account rejects its leg, the swap unwound"), events: &["Failed"], to: "Failed" },
+        Transition { from: "Opened", command: "Cancel", guard: None, events: &["Reversing"], to: "Reversing" },
+        Transition { from: "Opened", command: "Expire", guard: Some("timeout elapsed"), events: &["Reversing"], to: "Reversing" },
+        // Reversed in the same step, as recorded before reversals ran as a step of their own.
+        Transition { from: "Opened", command: "Cancel", guard: Some("recorded before Reversing"), events: &["Canceled"], to: "Canceled" },
+        Transition { from: "Opened", command: "Expire", guard: Some("recorded before Reversing"), events: &["Expired"], to: "Expired" },
+        Transition { from: "Reversing", command: "Continue", guard: Some("canceled, partial transfer reversed"), events: &["Canceled"], to: "Canceled" },
+        Transition { from: "Reversing", command: "Continue", guard: Some("expired, partial transfer reversed"), events: &["Expired"], to: "Expired" },
+        Transition { from: "Reversing", command: "Continue", guard: Some("an account rejects the reversal"), events: &["ReversalFailed"], to: "ReversalFailed" },
+        Transition { from: "Canceled", command: "Continue", guard: Some("late legs reversed"), events: &[], to: "Canceled" },
+        Transition { from: "Expired", command: "Continue", guard: Some("late legs reversed"), events: &[], to: "Expired" },
+        Transition { from: "Failed", command: "Retry", guard: None, events: &["Retried"], to: "Opened" },
+        Transition { from: "Done", command: "Refund", guard: Some("within what is left to refund"), events: &["Refunded"], to: "Done" },
+        Transition { from: "Done", command: "Refund", guard: Some("same Refund resent"), events: &[], to: "Done" },
+    ];
+}
+
+impl TableDriven for Transfer {
+    fn state_name(&self) -> &'static str {
+        match self {
+            Transfer::Uninitialized => "Uninitialized",
+            Transfer::Opened { .. } => "Opened",
+            Transfer::Locked { .. } => "Locked",
+            Transfer::Done { .. } => "Done",
+            Transfer::Failed { .. } => "Failed",
+            Transfer::Reversing { .. } => "Reversing",
+            Transfer::ReversalFailed { .. } => "ReversalFailed",
+            Transfer::Canceled { .. } => "Canceled",
+            Transfer::Expired { .. } => "Expired",
+        }
+    }
+
+    fn command_name(command: &TransferCommand) -> &'static str {
+        match command {
+            TransferCommand::Open { .. } => "Open",
+            TransferCommand::SwapOpen { .. } => "SwapOpen",
+            TransferCommand::Continue => "Continue",
+            TransferCommand::Cancel { .. } => "Cancel",
+            TransferCommand::Expire => "Expire",
+            TransferCommand::Retry => "Retry",
+            TransferCommand::Refund { .. } => "Refund",
+        }
+    }
+}
+
+#[cfg(test)]
+mod aggregate_tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use async_trait::async_trait;
+    use cqrs_es::test::TestFramework;
+    use cqrs_es::{Aggregate, AggregateError};
+    use crate::account::commands::{AccountCommand, TransactionCommand};
+    use crate::account::events::AccountError;
+    use crate::services::AccountExecutor;
+    use crate::statemachine::verify_table;
+    use crate::transfer::aggregate::{Config, Refund, SwapLeg, Transfer, TransferServices};
+    use crate::transfer::commands::{derive_transfer_id, TransferCommand};
+    use crate::transfer::events::TransferEvent;
+    use crate::util::clock::FixedClock;
+    use crate::util::types::ByteArray32;
+
+    #[test]
+    fn test_transition_table() {
+        let config = Config::default;
+        let states = vec![
+            Transfer::Uninitialized,
+            Transfer::Opened { config: config() },
+            Transfer::Locked { config: config() },
+            Transfer::Done { config: config(), timestamp: 2, refunds: vec![] },
+            Transfer::Failed { config: config(), reason: "no funds".to_string(), timestamp: 2 },
+            Transfer::Reversing { config: config(), cancel_reason: Some("user".to_string()) },
+            Transfer::ReversalFailed { config: config(), reason: "no funds".to_string(), timestamp: 2 },
+            Transfer::Canceled { config: config(), reason: "user".to_string(), timestamp: 2 },
+            Transfer::Expired { config: config(), timestamp: 2 },
+        ];
+        let commands = vec![
+            TransferCommand::Open {
+                transfer_id: ByteArray32::default(),
+                from_account: "ACCT-0001".to_string(),
+                to_account: "ACCT-0002".to_string(),
+                asset: "BTC".to_string(),
+                amount: 10,
+                timestamp: 1,
+                description: String::new(),
+            },
+            TransferCommand::SwapOpen {
+                transfer_id: ByteArray32::default(),
+                from_account: "ACCT-0001".to_string(),
+                to_account: "ACCT-0002".to_string(),
+                asset: "BTC".to_string(),
+                amount: 10,
+                counter_asset: "ETH".to_string(),
+                counter_amount: 200,
+                timestamp: 1,
+                description: String::new(),
+            },
+            TransferCommand::Continue,
+            TransferCommand::Cancel { reason: "user".to_string() },
+            TransferCommand::Expire,
+            TransferCommand::Retry,
+            TransferCommand::Refund { refund_id: ByteArray32::default(), amount: 5, reason: "returned".to_string() },
+        ];
+        let events = vec![
+            TransferEvent::Opened {
+                transfer_id: ByteArray32::default(),
+                from_account: "ACCT-0001".to_string(),
+                to_account: "ACCT-0002".to_string(),
+                asset: "BTC".to_string(),
+                amount: 10,
+                timestamp: 1,
+                description: String::new(),
+                opened_at: 1,
+            },
+            TransferEvent::SwapOpened {
+                transfer_id: ByteArray32::default(),
+                from_account: "ACCT-0001".to_string(),
+                to_account: "ACCT-0002".to_string(),
+                asset: "BTC".to_string(),
+                amount: 10,
+                counter_asset: "ETH".to_string(),
+                counter_amount: 200,
+                timestamp: 1,
+                description: String::new(),
+                opened_at: 1,
+            },
+            TransferEvent::SwapLocked { timestamp: 2 },
+            TransferEvent::Done { timestamp: 2 },
+            TransferEvent::Failed { reason: "no funds".to_string(), timestamp: 2 },
+            TransferEvent::Reversing { reason: Some("user".to_string()), timestamp: 2 },
+            TransferEvent::ReversalFailed { reason: "no funds".to_string(), timestamp: 2 },
+            TransferEvent::Canceled { reason: "user".to_string(), timestamp: 2 },
+            TransferEvent::Expired { timestamp: 2 },
+            TransferEvent::Retried { attempt: 1, timestamp: 3 },
+            TransferEvent::Refunded {
+                refund_id: ByteArray32::default(),
+                from_account: "ACCT-0002".to_string(),
+                to_account: "ACCT-0001".to_string(),
+                asset: "BTC".to_string(),
+                amount: 5,
+                reason: "returned".to_string(),
+                timestamp: 3,
+            },
+        ];
+        verify_table(&states, &commands, &events);
+    }
+
+    #[test]
+    fn test_expires_at() {
+        let config = Config { timestamp: 100, opened_at: 200, ..Default::default() };
+        assert_eq!(config.expires_at(900), 1100);
+        // Transfers opened before `opened_at` existed use the client timestamp.
+        let legacy = Config { timestamp: 100, ..Default::default() };
+        assert_eq!(legacy.expires_at(900), 1000);
+    }
+
+    #[test]
+    fn test_retry_takes_new_txid() {
+        let config = Config { transfer_id: derive_transfer_id("ACCT-0001", "ACCT-0002", "invoice-1"), opened_at: 5, ..Default::default() };
+        let mut transfer = Transfer::Failed { config: config.clone(), reason: "no funds".to_string(), timestamp: 6 };
+        transfer.apply(TransferEvent::Retried { attempt: 1, timestamp: 100 });
+        let Transfer::Opened { config: retried } = transfer else {
+            panic!("a retried transfer is opened again");
+        };
+        assert_eq!((retried.attempt, retried.expires_at(900)), (1, 1000));
+        assert_eq!(config.txid(), config.transfer_id);
+        assert_ne!(retried.txid(), config.txid());
+    }
+
+    #[test]
+    fn test_opened_by() {
+        let open = |amount| TransferCommand::Open {
+            transfer_id: derive_transfer_id("ACCT-0001", "ACCT-0002", "invoice-1"),
+            from_account: "ACCT-0001".to_string(),
+            to_account: "ACCT-0002".to_string(),
+            asset: "BTC".to_string(),
+            amount,
+            timestamp: 1,
+            description: String::new(),
+        };
+        let config = Config {
+            transfer_id: derive_transfer_id("ACCT-0001", "ACCT-0002", "invoice-1"),
+            from_account: "ACCT-0001".to_string(),
+            to_account: "ACCT-0002".to_string(),
+            asset: "BTC".to_string(),
+            amount: 10,
+            timestamp: 1,
+            description: String::new(),
+            opened_at: 5,
+            attempt: 0,
+            swap: None,
+        };
+        assert!(config.opened_by(&open(10)));
+        assert!(!config.opened_by(&open(11)));
+        assert!(!config.opened_by(&TransferCommand::Continue));
+        let swap_open = TransferCommand::SwapOpen {
+            transfer_id: config.transfer_id,
+            from_account: "ACCT-0001".to_string(),
+            to_account: "ACCT-0002".to_string(),
+            asset: "BTC".to_string(),
+            amount: 10,
+            counter_asset: "ETH".to_string(),
+            counter_amount: 200,
+            timestamp: 1,
+            description: String::new(),
+        };
+        assert!(!config.opened_by(&swap_open));
+        let swap = Config { swap: Some(SwapLeg { asset: "ETH".to_string(), amount: 200 }), ..config.clone() };
+        assert!(swap.opened_by(&swap_open));
+        assert!(!swap.opened_by(&open(10)));
+        assert_ne!(derive_transfer_id("ACCT-0001", "ACCT-0002", "invoice-1"), derive_transfer_id("ACCT-0002", "ACCT-0001", "invoice-1"));
+    }
+
+    // Records the transactions executed on each account, rejecting locks on `broke`,
+    // settlements on `closing`, reversed credits on `spent` and the txid `used` on its
+    // account.
+    #[derive(Default)]
+    struct Accounts {
+        broke: String,
+        closing: String,
+        spent: String,
+        used: Option<(String, ByteArray32)>,
+        executed: Mutex<Vec<(String, &'static str)>>,
+    }
+
+    #[async_trait]
+    impl AccountExecutor for Accounts {
+        async fn execute(&self, account_id: &str, command: AccountCommand) -> Result<(), AggregateError<AccountError>> {
+            let AccountCommand::Transaction { command, txid, timestamp, .. } = command else {
+                panic!("swaps only run transactions");
+            };
+            if self.used == Some((account_id.to_string(), txid)) {
+                return Err(AggregateError::UserError(AccountError::DuplicateTransaction(timestamp)));
             }
+            let name = match command {
+                TransactionCommand::LockFunds { .. } if account_id == self.broke => return Err(AggregateError::UserError(AccountError::InsufficientFunds)),
+                TransactionCommand::LockFunds { .. } => "LockFunds",
+                TransactionCommand::UnlockFunds => "UnlockFunds",
+                TransactionCommand::Settle { .. } if account_id == self.closing => return Err(AggregateError::UserError(AccountError::AccountClosing)),
+                TransactionCommand::Settle { .. } => "Settle",
+                TransactionCommand::Debit { .. } => "Debit",
+                TransactionCommand::Credit { .. } => "Credit",
+                TransactionCommand::ReverseCredit { .. } if account_id == self.spent => return Err(AggregateError::UserError(AccountError::InsufficientFunds)),
+                TransactionCommand::ReverseCredit { .. } => "ReverseCredit",
+                TransactionCommand::ReverseDebit { .. } => "ReverseDebit",
+                _ => "Other",
+            };
+            self.executed.lock().unwrap().push((account_id.to_string(), name));
+            Ok(())
         }
     }
+
+    #[tokio::test]
+    async fn test_swap_locks_then_settles() {
+        let config = Config {
+            from_account: "ACCT-0001".to_string(),
+            to_account: "ACCT-0002".to_string(),
+            asset: "BTC".to_string(),
+            amount: 10,
+            swap: Some(SwapLeg { asset: "ETH".to_string(), amount: 200 }),
+            ..Default::default()
+        };
+        let accounts = Arc::new(Accounts::default());
+        let services = TransferServices::new(accounts.clone(), 900);
+        let executed = |accounts: &Accounts| accounts.executed.lock().unwrap().drain(..).collect::<Vec<_>>();
+
+        let transfer = Transfer::Opened { config: config.clone() };
+        let events = transfer.handle(TransferCommand::Continue, &services).await.unwrap();
+        assert!(matches!(events[..], [TransferEvent::SwapLocked { .. }]));
+        assert_eq!(executed(&accounts), vec![("ACCT-0001".to_string(), "LockFunds"), ("ACCT-0002".to_string(), "LockFunds")]);
+        // Locked swaps only go forward.
+        let transfer = Transfer::Locked { config: config.clone() };
+        assert!(transfer.handle(TransferCommand::Cancel { reason: "user".to_string() }, &services).await.is_err());
+        let events = transfer.handle(TransferCommand::Continue, &services).await.unwrap();
+        assert!(matches!(events[..], [TransferEvent::Done { .. }]));
+        assert_eq!(executed(&accounts), vec![("ACCT-0001".to_string(), "Settle"), ("ACCT-0002".to_string(), "Settle")]);
+
+        // The counter leg cannot settle, the first one is given back what it settled and the
+        // counter leg unlocked.
+        let accounts = Arc::new(Accounts { closing: "ACCT-0002".to_string(), ..Default::default() });
+        let services = TransferServices::new(accounts.clone(), 900);
+        let events = transfer.handle(TransferCommand::Continue, &services).await.unwrap();
+        assert!(matches!(events[..], [TransferEvent::Failed { .. }]));
+        assert_eq!(executed(&accounts), vec![
+            ("ACCT-0001".to_string(), "Settle"),
+            ("ACCT-0001".to_string(), "Debit"),
+            ("ACCT-0001".to_string(), "Credit"),
+            ("ACCT-0002".to_string(), "UnlockFunds"),
+        ]);
+        // The first leg cannot settle, both are unlocked.
+        let accounts = Arc::new(Accounts { closing: "ACCT-0001".to_string(), ..Default::default() });
+        let services = TransferServices::new(accounts.clone(), 900);
+        let events = transfer.handle(TransferCommand::Continue, &services).await.unwrap();
+        assert!(matches!(events[..], [TransferEvent::Failed { .. }]));
+        assert_eq!(executed(&accounts), vec![("ACCT-0002".to_string(), "UnlockFunds"), ("ACCT-0001".to_string(), "UnlockFunds")]);
+
+        // The counter leg cannot be locked, the first one is unlocked again; unlocking the
+        // counter leg as well finds no lock there.
+        let accounts = Arc::new(Accounts { broke: "ACCT-0002".to_string(), ..Default::default() });
+        let services = TransferServices::new(accounts.clone(), 900);
+        let events = Transfer::Opened { config }.handle(TransferCommand::Continue, &services).await.unwrap();
+        assert!(matches!(events[..], [TransferEvent::Failed { .. }]));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(executed(&accounts), vec![
+            ("ACCT-0001".to_string(), "LockFunds"),
+            ("ACCT-0002".to_string(), "UnlockFunds"),
+            ("ACCT-0001".to_string(), "UnlockFunds"),
+        ]);
+    }
+
+    #[test]
+    fn test_expire_follows_clock() {
+        let clock = Arc::new(FixedClock::new(1_000));
+        let services = || TransferServices::new(Arc::new(Accounts::default()), 900).with_clock(clock.clone());
+        let opened = || vec![TransferEvent::Opened {
+            transfer_id: ByteArray32::default(),
+            from_account: "ACCT-0001".to_string(),
+            to_account: "ACCT-0002".to_string(),
+            asset: "BTC".to_string(),
+            amount: 10,
+            timestamp: 1,
+            description: String::new(),
+            opened_at: 1_000,
+        }];
+
+        clock.set(1_899);
+        TestFramework::<Transfer>::with(services())
+            .given(opened())
+            .when(TransferCommand::Expire)
+            .then_expect_error_message("Invalid state: Transfer does not expire before 1900");
+        clock.set(1_900);
+        TestFramework::<Transfer>::with(services())
+            .given(opened())
+            .when(TransferCommand::Expire)
+            .then_expect_events(vec![TransferEvent::Reversing { reason: None, timestamp: 1_900 }]);
+    }
+
+    #[tokio::test]
+    async fn test_reversal_runs_as_a_step() {
+        let config = Config {
+            from_account: "ACCT-0001".to_string(),
+            to_account: "ACCT-0002".to_string(),
+            asset: "BTC".to_string(),
+            amount: 10,
+            ..Default::default()
+        };
+        let accounts = Arc::new(Accounts::default());
+        let services = TransferServices::new(accounts.clone(), 900).with_clock(Arc::new(FixedClock::new(1_000)));
+        let executed = |accounts: &Accounts| accounts.executed.lock().unwrap().drain(..).collect::<Vec<_>>();
+
+        // Canceling touches no account, the next `Continue` reverses.
+        let mut transfer = Transfer::Opened { config: config.clone() };
+        let events = transfer.handle(TransferCommand::Cancel { reason: "user".to_string() }, &services).await.unwrap();
+        assert_eq!(events, vec![TransferEvent::Reversing { reason: Some("user".to_string()), timestamp: 1_000 }]);
+        assert!(executed(&accounts).is_empty());
+        transfer.apply(events[0].clone());
+        assert!(transfer.handle(TransferCommand::Cancel { reason: "again".to_string() }, &services).await.is_err());
+        let events = transfer.handle(TransferCommand::Continue, &services).await.unwrap();
+        assert_eq!(events, vec![TransferEvent::Canceled { reason: "user".to_string(), timestamp: 1_000 }]);
+        assert_eq!(executed(&accounts), vec![("ACCT-0002".to_string(), "ReverseCredit"), ("ACCT-0001".to_string(), "ReverseDebit")]);
+        // Legs a `Continue` moved after the reversal are reversed by its resend.
+        transfer.apply(events[0].clone());
+        assert_eq!(transfer.handle(TransferCommand::Continue, &services).await.unwrap(), vec![]);
+        assert_eq!(executed(&accounts), vec![("ACCT-0002".to_string(), "ReverseCredit"), ("ACCT-0001".to_string(), "ReverseDebit")]);
+
+        // The credit was spent since, the transfer is left to an operator.
+        let accounts = Arc::new(Accounts { spent: "ACCT-0002".to_string(), ..Default::default() });
+        let services = TransferServices::new(accounts.clone(), 900).with_clock(Arc::new(FixedClock::new(1_000)));
+        let transfer = Transfer::Reversing { config, cancel_reason: None };
+        let events = transfer.handle(TransferCommand::Continue, &services).await.unwrap();
+        assert!(matches!(events[..], [TransferEvent::ReversalFailed { .. }]));
+    }
+
+    #[tokio::test]
+    async fn test_refunds_capped_at_transfer_amount() {
+        let config = Config {
+            from_account: "ACCT-0001".to_string(),
+            to_account: "ACCT-0002".to_string(),
+            asset: "BTC".to_string(),
+            amount: 10,
+            ..Default::default()
+        };
+        let accounts = Arc::new(Accounts::default());
+        let services = TransferServices::new(accounts.clone(), 900).with_clock(Arc::new(FixedClock::new(1_000)));
+        let refund = |refund_id: u8, amount| TransferCommand::Refund { refund_id: ByteArray32([refund_id; 32]), amount, reason: "returned".to_string() };
+        let refunded = |refund_id: u8, amount| Refund { refund_id: ByteArray32([refund_id; 32]), amount, reason: "returned".to_string(), timestamp: 1_000 };
+
+        let transfer = Transfer::Done { config: config.clone(), timestamp: 2, refunds: vec![refunded(1, 6)] };
+        let events = transfer.handle(refund(2, 4), &services).await.unwrap();
+        assert_eq!(events, vec![TransferEvent::Refunded {
+            refund_id: ByteArray32([2; 32]),
+            from_account: "ACCT-0002".to_string(),
+            to_account: "ACCT-0001".to_string(),
+            asset: "BTC".to_string(),
+            amount: 4,
+            reason: "returned".to_string(),
+            timestamp: 1_000,
+        }]);
+        // The funds go back the way they came.
+        assert_eq!(accounts.executed.lock().unwrap().drain(..).collect::<Vec<_>>(), vec![
+            ("ACCT-0002".to_string(), "Debit"),
+            ("ACCT-0001".to_string(), "Credit"),
+        ]);
+        assert!(transfer.handle(refund(2, 5), &services).await.is_err());
+        assert!(transfer.handle(refund(1, 6), &services).await.unwrap().is_empty());
+        assert!(transfer.handle(refund(1, 5), &services).await.is_err());
+        assert!(transfer.handle(refund(2, 0), &services).await.is_err());
+        assert!(accounts.executed.lock().unwrap().is_empty());
+
+        let swap = Transfer::Done { config: Config { swap: Some(SwapLeg { asset: "ETH".to_string(), amount: 200 }), ..config }, timestamp: 2, refunds: vec![] };
+        assert!(swap.handle(refund(2, 4), &services).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_refund_id_cannot_reuse_a_txid() {
+        let config = Config {
+            transfer_id: ByteArray32([7; 32]),
+            from_account: "ACCT-0001".to_string(),
+            to_account: "ACCT-0002".to_string(),
+            asset: "BTC".to_string(),
+            amount: 10,
+            ..Default::default()
+        };
+        // The refund id is a txid already used on the account the refund debits, the legs
+        // run under one of their own and both post.
+        let used = ByteArray32([9; 32]);
+        let accounts = Arc::new(Accounts { used: Some(("ACCT-0002".to_string(), used)), ..Default::default() });
+        let services = TransferServices::new(accounts.clone(), 900);
+        let transfer = Transfer::Done { config: config.clone(), timestamp: 2, refunds: vec![] };
+        let refund = TransferCommand::Refund { refund_id: used, amount: 4, reason: "returned".to_string() };
+        assert!(matches!(transfer.handle(refund, &services).await.unwrap()[..], [TransferEvent::Refunded { .. }]));
+        assert_eq!(accounts.executed.lock().unwrap().drain(..).collect::<Vec<_>>(), vec![
+            ("ACCT-0002".to_string(), "Debit"),
+            ("ACCT-0001".to_string(), "Credit"),
+        ]);
+        assert_ne!(config.refund_txid(&used), used);
+        assert_ne!(config.refund_txid(&config.transfer_id), config.txid());
+    }
 }
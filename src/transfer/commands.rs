@@ -1,8 +1,106 @@
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 use crate::util::types::ByteArray32;
 
-#[derive(Debug, Serialize, Deserialize)]
+// The transfer id a server derived Open gets, so a client retrying with the same
+// reference lands on the same transfer.
+pub fn derive_transfer_id(from_account: &str, to_account: &str, client_reference: &str) -> ByteArray32 {
+    ByteArray32::derive("transfer", &format!("{}\0{}\0{}", from_account, to_account, client_reference))
+}
+
+// The refund id a refund of `transfer_id` gets, see `Config::refund_txid` for the txid it
+// moves the funds back under. A client retrying with the same reference refunds once.
+pub fn derive_refund_id(transfer_id: &ByteArray32, client_reference: &str) -> ByteArray32 {
+    ByteArray32::derive("refund", &format!("{}\0{}", transfer_id.hex(), client_reference))
+}
+
+// Body of `POST /transfer`, which opens a transfer under a derived id.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct OpenTransferRequest {
+    pub from_account: String,
+    pub to_account: String,
+    pub asset: String,
+    pub amount: u64,
+    pub timestamp: u64,
+    #[serde(default)]
+    pub description: String,
+    pub client_reference: String,
+}
+
+// Body of `POST /account/{account_id}/transfer`, which opens and continues a transfer
+// out of the account in one call.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct AccountTransferRequest {
+    pub to_account: String,
+    pub asset: String,
+    pub amount: u64,
+    pub timestamp: u64,
+    #[serde(default)]
+    pub description: String,
+    pub client_reference: String,
+}
+
+impl AccountTransferRequest {
+    pub fn into_open(self, from_account: String) -> OpenTransferRequest {
+        OpenTransferRequest {
+            from_account,
+            to_account: self.to_account,
+            asset: self.asset,
+            amount: self.amount,
+            timestamp: self.timestamp,
+            description: self.description,
+            client_reference: self.client_reference,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct OpenTransferResponse {
+    pub transfer_id: String,
+}
+
+// Body of `POST /transfer/{transfer_id}/refund`, which sends part or all of a done
+// transfer back under a derived refund id.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RefundTransferRequest {
+    pub amount: u64,
+    #[serde(default)]
+    pub reason: String,
+    pub client_reference: String,
+}
+
+impl RefundTransferRequest {
+    pub fn into_command(self, transfer_id: &ByteArray32) -> TransferCommand {
+        TransferCommand::Refund {
+            refund_id: derive_refund_id(transfer_id, &self.client_reference),
+            amount: self.amount,
+            reason: self.reason,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RefundTransferResponse {
+    pub refund_id: String,
+}
+
+impl OpenTransferRequest {
+    pub fn into_command(self) -> TransferCommand {
+        TransferCommand::Open {
+            transfer_id: derive_transfer_id(&self.from_account, &self.to_account, &self.client_reference),
+            from_account: self.from_account,
+            to_account: self.to_account,
+            asset: self.asset,
+            amount: self.amount,
+            timestamp: self.timestamp,
+            description: self.description,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub enum TransferCommand {
+    // The transfer is stored under the hex encoding of `transfer_id`.
     Open {
         transfer_id: ByteArray32,
         from_account: String,
@@ -12,5 +110,35 @@ pub enum TransferCommand {
         timestamp: u64,
         description: String,
     },
+    // Opens a swap: `from_account` sends `amount` of `asset` to `to_account`, which sends
+    // `counter_amount` of `counter_asset` back. Its first `Continue` locks both legs, the
+    // second settles them; once locked it can no longer be cancelled or expire.
+    SwapOpen {
+        transfer_id: ByteArray32,
+        from_account: String,
+        to_account: String,
+        asset: String,
+        amount: u64,
+        counter_asset: String,
+        counter_amount: u64,
+        timestamp: u64,
+        description: String,
+    },
     Continue,
+    Cancel {
+        reason: String,
+    },
+    // Issued by the expiry sweeper once the transfer timeout has passed.
+    Expire,
+    // Opens a failed transfer again, for a `Continue` to run its legs once more, e.g.
+    // after the rejecting account was topped up. The timeout counts from the retry.
+    Retry,
+    // Sends `amount` of a done transfer back from `to_account` to `from_account`, with
+    // `refund_id` as the txid. Sent to the transfer refunded, which keeps what all its
+    // refunds send back within the amount it moved.
+    Refund {
+        refund_id: ByteArray32,
+        amount: u64,
+        reason: String,
+    },
 }
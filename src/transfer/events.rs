@@ -12,6 +12,27 @@ pub enum TransferEvent {
         amount: u64,
         timestamp: u64,
         description: String,
+        // Server time the transfer was opened at, the timeout counts from here.
+        // Zero on transfers opened before it was recorded.
+        #[serde(default)]
+        opened_at: u64,
+    },
+    // Opened as a swap, `to_account` sends the counter leg back.
+    SwapOpened {
+        transfer_id: ByteArray32,
+        from_account: String,
+        to_account: String,
+        asset: String,
+        amount: u64,
+        counter_asset: String,
+        counter_amount: u64,
+        timestamp: u64,
+        description: String,
+        opened_at: u64,
+    },
+    // Both legs of a swap locked, settling them is all that is left.
+    SwapLocked {
+        timestamp: u64,
     },
     Done {
         timestamp: u64,
@@ -20,14 +41,57 @@ pub enum TransferEvent {
         reason: String,
         timestamp: u64,
     },
+    // Canceled, or expired without a reason, once the part of the transfer that reached
+    // the accounts is reversed by the next `Continue`.
+    Reversing {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+        timestamp: u64,
+    },
+    // An account rejected the reversal, the transfer is left to an operator.
+    ReversalFailed {
+        reason: String,
+        timestamp: u64,
+    },
+    Canceled {
+        reason: String,
+        timestamp: u64,
+    },
+    Expired {
+        timestamp: u64,
+    },
+    // Opened again after failing, as the `attempt`-th retry.
+    Retried {
+        attempt: u32,
+        timestamp: u64,
+    },
+    // Part or all of a done transfer sent back, `from_account` being the account that
+    // received the transfer.
+    Refunded {
+        refund_id: ByteArray32,
+        from_account: String,
+        to_account: String,
+        asset: String,
+        amount: u64,
+        reason: String,
+        timestamp: u64,
+    },
 }
 
 impl DomainEvent for TransferEvent {
     fn event_type(&self) -> String {
         match self {
             TransferEvent::Opened { .. } => "Opened".to_string(),
+            TransferEvent::SwapOpened { .. } => "SwapOpened".to_string(),
+            TransferEvent::SwapLocked { .. } => "SwapLocked".to_string(),
             TransferEvent::Done { .. } => "Done".to_string(),
             TransferEvent::Failed { .. } => "Failed".to_string(),
+            TransferEvent::Reversing { .. } => "Reversing".to_string(),
+            TransferEvent::ReversalFailed { .. } => "ReversalFailed".to_string(),
+            TransferEvent::Canceled { .. } => "Canceled".to_string(),
+            TransferEvent::Expired { .. } => "Expired".to_string(),
+            TransferEvent::Retried { .. } => "Retried".to_string(),
+            TransferEvent::Refunded { .. } => "Refunded".to_string(),
         }
     }
 
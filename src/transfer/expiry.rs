@@ -0,0 +1,75 @@
+use std::sync::Arc;
+use std::time::Duration;
+use sqlx::{Pool, Postgres, Row};
+use crate::background::{Background, Shutdown};
+use crate::config::TransferConfig;
+use crate::field_encryption::EncryptedCqrs;
+use crate::transfer::aggregate::Transfer;
+use crate::transfer::commands::TransferCommand;
+use crate::util::clock;
+
+// Expires transfers that are still open once `TransferConfig::timeout_secs` has
+// passed, then reverses whatever part of them reached the accounts. A transfer whose
+// reversal fails stays `Reversing` and is taken up again by the saga watchdog. Swaps
+// locked by then can no longer expire, the sweep settles them instead, or fails and
+// unwinds them when an account rejects its leg.
+#[derive(Clone)]
+pub struct TransferExpiry {
+    transfer_cqrs: Arc<EncryptedCqrs<Transfer>>,
+    pool: Pool<Postgres>,
+    config: TransferConfig,
+}
+
+impl TransferExpiry {
+    pub fn new(transfer_cqrs: Arc<EncryptedCqrs<Transfer>>, pool: Pool<Postgres>, config: TransferConfig) -> Self {
+        TransferExpiry { transfer_cqrs, pool, config }
+    }
+
+    pub fn spawn(self, background: &Background) {
+        background.spawn(|shutdown| async move { self.run(shutdown).await });
+    }
+
+    async fn run(&self, mut shutdown: Shutdown) {
+        loop {
+            if let Err(e) = self.sweep().await {
+                tracing::error!("Failed to sweep expired transfers: {}", e);
+            }
+            if !shutdown.pause(Duration::from_secs(self.config.sweep_interval_secs.max(1))).await {
+                break;
+            }
+        }
+    }
+
+    async fn sweep(&self) -> Result<(), sqlx::Error> {
+        let cutoff = clock::now().saturating_sub(self.config.timeout_secs);
+        for (transfer_id, status) in expired_transfers(&self.pool, cutoff).await? {
+            let locked = status == "Locked";
+            // Expiring marks the transfer to be reversed, which `Continue` does.
+            let swept = async {
+                if !locked {
+                    self.transfer_cqrs.execute(&transfer_id, TransferCommand::Expire).await?;
+                }
+                self.transfer_cqrs.execute(&transfer_id, TransferCommand::Continue).await
+            }.await;
+            let done = if locked { "settled" } else { "expired" };
+            match swept {
+                Ok(_) => tracing::info!("Transfer {} {}", transfer_id, done),
+                Err(e) => tracing::error!("Failed to sweep transfer {}: {}", transfer_id, e),
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn expired_transfers(pool: &Pool<Postgres>, cutoff: u64) -> Result<Vec<(String, String)>, sqlx::Error> {
+    sqlx::query(
+        "SELECT view_id, payload->>'status' AS status FROM transfer_query
+         WHERE payload->>'status' IN ('Opened', 'Locked') AND (payload->>'opened_at')::bigint <= $1",
+    )
+        .bind(cutoff as i64)
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| Ok((row.try_get("view_id")?, row.try_get("status")?)))
+        .collect()
+}
@@ -1,4 +1,5 @@
 pub mod aggregate;
 pub mod commands;
 pub mod events;
+pub mod expiry;
 pub mod queries;
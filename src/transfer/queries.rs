@@ -1,10 +1,12 @@
+use std::sync::Arc;
 use async_trait::async_trait;
-use cqrs_es::persist::GenericQuery;
+use cqrs_es::persist::{GenericQuery, ViewContext, ViewRepository};
 use cqrs_es::{EventEnvelope, Query, View};
 use postgres_es::PostgresViewRepository;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 use crate::util::types::ByteArray32;
-use super::aggregate::Transfer;
+use super::aggregate::{Refund, SwapLeg, Transfer};
 use super::events::TransferEvent;
 
 pub struct SimpleLoggingQuery {}
@@ -30,9 +32,25 @@ pub type TransferQuery = GenericQuery<
     Transfer,
 >;
 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum TransferStatus {
+    #[default]
+    Opened,
+    // A swap with both legs locked.
+    Locked,
+    Done,
+    Failed,
+    // Canceled or expired, being reversed.
+    Reversing,
+    // Left to an operator, see `TransferEvent::ReversalFailed`.
+    ReversalFailed,
+    Canceled,
+    Expired,
+}
+
 // The view for a Transfer query, for a standard http application this should
 // be designed to reflect the response dto that will be returned to a user.
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
 pub struct TransferView {
     transfer_id: Option<ByteArray32>,
     from_account: String,
@@ -44,6 +62,33 @@ pub struct TransferView {
     description: String,
     is_done: bool,
     failed_reason: Option<String>,
+    cancel_reason: Option<String>,
+    // Indexed by the expiry sweeper, together with `opened_at`.
+    #[serde(default)]
+    status: TransferStatus,
+    #[serde(default)]
+    opened_at: u64,
+    // How often the transfer was retried after failing.
+    #[serde(default)]
+    retries: u32,
+    // What `to_account` sends back, on swaps.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    swap: Option<SwapLeg>,
+    // What was sent back so far, and by which refunds.
+    #[serde(default)]
+    refunded: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    refunds: Vec<Refund>,
+    // On the view of a refund, the transfer it sent funds back for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    refund_of: Option<ByteArray32>,
+}
+
+impl TransferView {
+    // The account a refund of the transfer sends funds back from.
+    pub fn to_account(&self) -> &str {
+        &self.to_account
+    }
 }
 
 // This updates the view with events as they are committed.
@@ -52,7 +97,7 @@ pub struct TransferView {
 impl View<Transfer> for TransferView {
     fn update(&mut self, event: &EventEnvelope<Transfer>) {
         match &event.payload {
-            TransferEvent::Opened { transfer_id, from_account, to_account, amount, asset, timestamp, description } => {
+            TransferEvent::Opened { transfer_id, from_account, to_account, amount, asset, timestamp, description, opened_at } => {
                 self.transfer_id = Some(*transfer_id);
                 self.from_account = from_account.clone();
                 self.to_account = to_account.clone();
@@ -61,14 +106,123 @@ impl View<Transfer> for TransferView {
                 self.create_timestamp = *timestamp;
                 self.description = description.clone();
                 self.is_done = false;
+                self.status = TransferStatus::Opened;
+                self.opened_at = if *opened_at == 0 { *timestamp } else { *opened_at };
+            }
+            TransferEvent::SwapOpened { transfer_id, from_account, to_account, asset, amount, counter_asset, counter_amount, timestamp, description, opened_at } => {
+                self.transfer_id = Some(*transfer_id);
+                self.from_account = from_account.clone();
+                self.to_account = to_account.clone();
+                self.amount = *amount;
+                self.asset = asset.clone();
+                self.swap = Some(SwapLeg { asset: counter_asset.clone(), amount: *counter_amount });
+                self.create_timestamp = *timestamp;
+                self.description = description.clone();
+                self.is_done = false;
+                self.status = TransferStatus::Opened;
+                self.opened_at = *opened_at;
+            }
+            TransferEvent::SwapLocked { timestamp } => {
+                self.update_timestamp = *timestamp;
+                self.status = TransferStatus::Locked;
             }
             TransferEvent::Done { timestamp } => {
                 self.update_timestamp = *timestamp;
                 self.is_done = true;
+                self.status = TransferStatus::Done;
             },
             TransferEvent::Failed { reason, timestamp } => {
                 self.update_timestamp = *timestamp;
-                self.failed_reason = Some(reason.clone())
+                self.failed_reason = Some(reason.clone());
+                self.status = TransferStatus::Failed;
+            },
+            TransferEvent::Reversing { reason, timestamp } => {
+                self.update_timestamp = *timestamp;
+                self.cancel_reason = reason.clone();
+                self.status = TransferStatus::Reversing;
+            },
+            TransferEvent::ReversalFailed { reason, timestamp } => {
+                self.update_timestamp = *timestamp;
+                self.failed_reason = Some(reason.clone());
+                self.status = TransferStatus::ReversalFailed;
+            },
+            TransferEvent::Canceled { reason, timestamp } => {
+                self.update_timestamp = *timestamp;
+                self.cancel_reason = Some(reason.clone());
+                self.status = TransferStatus::Canceled;
+            },
+            TransferEvent::Expired { timestamp } => {
+                self.update_timestamp = *timestamp;
+                self.status = TransferStatus::Expired;
+            }
+            // The expiry sweeper finds it again, opened as of the retry.
+            TransferEvent::Retried { attempt, timestamp } => {
+                self.update_timestamp = *timestamp;
+                self.failed_reason = None;
+                self.status = TransferStatus::Opened;
+                self.opened_at = *timestamp;
+                self.retries = *attempt;
+            }
+            TransferEvent::Refunded { refund_id, amount, reason, timestamp, .. } => {
+                self.update_timestamp = *timestamp;
+                self.refunded += *amount;
+                self.refunds.push(Refund { refund_id: *refund_id, amount: *amount, reason: reason.clone(), timestamp: *timestamp });
+            }
+        }
+    }
+}
+
+// The view a `Refunded` event gives its refund, stored under the refund id next to the
+// transfer it refunds.
+pub fn refund_view(transfer_id: &str, event: &TransferEvent) -> Option<(String, TransferView)> {
+    let TransferEvent::Refunded { refund_id, from_account, to_account, asset, amount, reason, timestamp } = event else {
+        return None;
+    };
+    let view = TransferView {
+        transfer_id: Some(*refund_id),
+        from_account: from_account.clone(),
+        to_account: to_account.clone(),
+        amount: *amount,
+        asset: asset.clone(),
+        create_timestamp: *timestamp,
+        update_timestamp: *timestamp,
+        description: reason.clone(),
+        is_done: true,
+        status: TransferStatus::Done,
+        opened_at: *timestamp,
+        refund_of: transfer_id.parse().ok(),
+        ..Default::default()
+    };
+    Some((refund_id.hex(), view))
+}
+
+// Writes the views of refunds, the transfers refunded list theirs in `refunds`.
+pub struct RefundLinkQuery {
+    view_repository: Arc<PostgresViewRepository<TransferView, Transfer>>,
+}
+
+impl RefundLinkQuery {
+    pub fn new(view_repository: Arc<PostgresViewRepository<TransferView, Transfer>>) -> Self {
+        RefundLinkQuery { view_repository }
+    }
+}
+
+#[async_trait]
+impl Query<Transfer> for RefundLinkQuery {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<Transfer>]) {
+        for (refund_id, view) in events.iter().filter_map(|event| refund_view(aggregate_id, &event.payload)) {
+            // Written once, a refund does not change after.
+            let linked = match self.view_repository.load_with_context(&refund_id).await {
+                Ok(linked) => linked,
+                Err(err) => {
+                    tracing::error!("Failed to load the view of refund {}: {}", refund_id, err);
+                    continue;
+                }
+            };
+            if linked.is_none() {
+                if let Err(err) = self.view_repository.update_view(view, ViewContext::new(refund_id.clone(), 0)).await {
+                    tracing::error!("Failed to write the view of refund {}: {}", refund_id, err);
+                }
             }
         }
     }
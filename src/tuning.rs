@@ -0,0 +1,249 @@
+use std::time::{Duration, Instant};
+use futures::{stream, StreamExt};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{Pool, Postgres};
+use crate::account::aggregate::Account;
+use crate::account::commands::AccountCommand;
+use crate::aggregate_cache::AggregateCache;
+use crate::commit_hooks::HookedEventRepository;
+use crate::config::{AppConfig, BatchingConfig};
+use crate::metrics::AggregateCacheMetrics;
+use crate::retry::retry_conflicts;
+use crate::sealing::{sealed_snapshot_cqrs, Sealer};
+use crate::services::{BankAccountServices, HappyPathBankAccountServices};
+use crate::util::clock;
+use crate::util::types::ByteArray32;
+
+// Measures the knobs that were constants before they were configurable: the snapshot
+// interval, the pool size and the commit batch size behind the account aggregate. Every
+// trial runs the account store the server runs, sealed, cached and sharded as
+// configured, and replays the same burst of deposits, generated from `Workload::seed`,
+// on accounts of its own, so trials compare and runs reproduce. Trials leave their
+// accounts behind, run them against a database set aside for it.
+
+// One account in `HOT_RATIO` is hot, receiving `HOT_SHARE` of the deposits: their long
+// streams are what snapshots are for.
+const HOT_RATIO: usize = 20;
+const HOT_SHARE: f64 = 0.5;
+const ASSET: &str = "BTC";
+
+#[derive(Debug, Clone)]
+pub struct Workload {
+    pub accounts: usize,
+    pub deposits: usize,
+    // Deposits in flight at once, the burst.
+    pub concurrency: usize,
+    pub seed: u64,
+    // Account ids and transaction ids derive from it, a run against a database tuned
+    // before takes another.
+    pub prefix: String,
+}
+
+impl Workload {
+    // The account index and amount of each deposit.
+    pub fn plan(&self) -> Vec<(usize, u64)> {
+        let accounts = self.accounts.max(1);
+        let hot = (accounts / HOT_RATIO).max(1);
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        (0..self.deposits)
+            .map(|_| {
+                let account = match rng.gen_bool(HOT_SHARE) {
+                    true => rng.gen_range(0..hot),
+                    false => rng.gen_range(0..accounts),
+                };
+                (account, rng.gen_range(1..1_000_000))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Grid {
+    pub snapshot_intervals: Vec<usize>,
+    pub max_connections: Vec<u32>,
+    pub max_batches: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Knob {
+    // With the configured commit batching.
+    SnapshotInterval(usize),
+    // With batched commits and the configured account snapshot interval.
+    MaxBatch(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct Trial {
+    pub knob: Knob,
+    pub max_connections: u32,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub elapsed: Duration,
+    pub p50: Duration,
+    pub p99: Duration,
+}
+
+impl Trial {
+    fn new(knob: Knob, max_connections: u32, results: Vec<(bool, Duration)>, elapsed: Duration) -> Self {
+        let mut latencies: Vec<Duration> = results.iter().map(|(_, latency)| *latency).collect();
+        latencies.sort();
+        let percentile = |p: f64| latencies.get(((latencies.len() as f64 * p) as usize).min(latencies.len().saturating_sub(1))).copied().unwrap_or_default();
+        let succeeded = results.iter().filter(|(ok, _)| *ok).count();
+        Trial {
+            knob,
+            max_connections,
+            succeeded,
+            failed: results.len() - succeeded,
+            elapsed,
+            p50: percentile(0.5),
+            p99: percentile(0.99),
+        }
+    }
+
+    // Deposits that went through per second.
+    pub fn per_sec(&self) -> f64 {
+        self.succeeded as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TuningReport {
+    pub trials: Vec<Trial>,
+}
+
+impl TuningReport {
+    // The trial that got the most deposits through per second, for snapshot intervals or
+    // for batch sizes. Deposits losing their conflicts on the hot accounts count against it.
+    pub fn best(&self, batching: bool) -> Option<&Trial> {
+        self.trials
+            .iter()
+            .filter(|trial| matches!(trial.knob, Knob::MaxBatch(_)) == batching)
+            .max_by(|a, b| a.per_sec().total_cmp(&b.per_sec()))
+    }
+}
+
+// Runs every snapshot interval and every batch size of the grid with every pool size.
+pub async fn sweep(connection_string: &str, workload: &Workload, grid: &Grid, config: &AppConfig) -> Result<TuningReport, sqlx::Error> {
+    let plan = workload.plan();
+    let mut report = TuningReport::default();
+    for &max_connections in &grid.max_connections {
+        let knobs = grid.snapshot_intervals.iter().map(|&interval| Knob::SnapshotInterval(interval))
+            .chain(grid.max_batches.iter().map(|&max_batch| Knob::MaxBatch(max_batch)));
+        for knob in knobs {
+            let pool = PgPoolOptions::new().max_connections(max_connections.max(1)).connect(connection_string).await?;
+            let prefix = format!("{}{:?}-{}-", workload.prefix, knob, max_connections);
+            let (interval, batching) = match knob {
+                Knob::SnapshotInterval(interval) => (interval, config.batching.clone()),
+                Knob::MaxBatch(max_batch) => {
+                    (config.snapshots.interval("account"), BatchingConfig { enabled: true, max_batch, ..config.batching.clone() })
+                },
+            };
+            let (results, elapsed) = deposit_on_accounts(pool, &prefix, interval, &batching, config, workload, &plan).await;
+            let trial = Trial::new(knob, max_connections, results, elapsed);
+            tracing::info!("{:?} on {} connections: {:.0} deposits/s", knob, max_connections, trial.per_sec());
+            report.trials.push(trial);
+        }
+    }
+    Ok(report)
+}
+
+async fn deposit_on_accounts(
+    pool: Pool<Postgres>,
+    prefix: &str,
+    interval: usize,
+    batching: &BatchingConfig,
+    config: &AppConfig,
+    workload: &Workload,
+    plan: &[(usize, u64)],
+) -> Measured {
+    let services = BankAccountServices::new(Box::new(HappyPathBankAccountServices));
+    let sealer = Sealer::new(&config.sealing).expect("invalid sealing keys");
+    let cache = AggregateCache::new(&config.aggregate_cache, AggregateCacheMetrics::default());
+    let events = HookedEventRepository::new(pool.clone(), vec![]).with_batching(batching);
+    let cqrs = sealed_snapshot_cqrs::<Account>(events, vec![], interval, services, sealer, cache, &config.shards);
+    let account_id = |account: usize| format!("{}{:05}", prefix, account);
+    // Opening the accounts is not part of the burst.
+    stream::iter(0..workload.accounts.max(1))
+        .for_each_concurrent(workload.concurrency.max(1), |account| {
+            let cqrs = &cqrs;
+            async move {
+                if let Err(e) = cqrs.execute(&account_id(account), AccountCommand::account_opened(account_id(account))).await {
+                    tracing::error!("Failed to open {}: {}", account_id(account), e);
+                }
+            }
+        })
+        .await;
+    let measured = measure(workload.concurrency, plan.iter().enumerate(), |(i, &(account, amount))| {
+        let cqrs = &cqrs;
+        async move {
+            let (aggregate_id, txid) = (account_id(account), ByteArray32::derive(prefix, &i.to_string()));
+            let execute = || cqrs.execute(&aggregate_id, AccountCommand::deposited(txid, clock::now(), ASSET.to_string(), amount));
+            retry_conflicts(&config.conflict_retry, execute).await.is_ok()
+        }
+    }).await;
+    // Stops the task writing the batches before the pool goes.
+    drop(cqrs);
+    pool.close().await;
+    measured
+}
+
+// Whether each deposit went through and how long it took, and the time all of them took.
+type Measured = (Vec<(bool, Duration)>, Duration);
+
+// Runs the deposits `concurrency` at a time, timing each of them.
+async fn measure<T, F>(concurrency: usize, deposits: impl Iterator<Item = T>, deposit: impl Fn(T) -> F) -> Measured
+where
+    F: std::future::Future<Output = bool>,
+{
+    let started = Instant::now();
+    let results = stream::iter(deposits)
+        .map(|item| {
+            let deposit = deposit(item);
+            async move {
+                let started = Instant::now();
+                (deposit.await, started.elapsed())
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+    (results, started.elapsed())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::tuning::{Knob, Trial, TuningReport, Workload};
+
+    #[test]
+    fn test_workload_plan() {
+        let workload = Workload { accounts: 100, deposits: 10_000, concurrency: 8, seed: 7, prefix: "TUNE-".to_string() };
+        let plan = workload.plan();
+        assert_eq!(plan, workload.plan());
+        assert_ne!(plan, Workload { seed: 8, ..workload.clone() }.plan());
+        assert!(plan.iter().all(|&(account, amount)| account < 100 && amount > 0));
+        // Half of the deposits, and some of the rest, go to the 5 hot accounts.
+        let hot = plan.iter().filter(|&&(account, _)| account < 5).count();
+        assert!((5_000..6_000).contains(&hot), "{} hot deposits", hot);
+
+        let trial = |knob, secs, failed| Trial {
+            knob,
+            max_connections: 10,
+            succeeded: 1_000 - failed,
+            failed,
+            elapsed: Duration::from_secs(secs),
+            p50: Duration::ZERO,
+            p99: Duration::ZERO,
+        };
+        let report = TuningReport { trials: vec![
+            trial(Knob::SnapshotInterval(10), 4, 0),
+            trial(Knob::SnapshotInterval(100), 2, 0),
+            trial(Knob::SnapshotInterval(1000), 1, 600),
+            trial(Knob::MaxBatch(64), 1, 0),
+        ] };
+        assert_eq!(report.best(false).unwrap().knob, Knob::SnapshotInterval(100));
+        assert_eq!(report.best(true).unwrap().knob, Knob::MaxBatch(64));
+    }
+}
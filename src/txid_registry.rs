@@ -0,0 +1,78 @@
+use sqlx::{Pool, Postgres, Row};
+use crate::util::types::ByteArray32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TxidRegistryError {
+    #[error("Transaction id {0} is already used by {1}")]
+    Conflict(String, String),
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+// Accounts only reject a txid they have seen themselves, so nothing stops the same
+// txid from being reused on another account for something unrelated. When enabled,
+// the registry records which operation first used each txid (e.g. `account:ACCT-1`,
+// `transfer`, `order`) and rejects any other operation that presents it later.
+// Sagas claim their id once and then reuse it across accounts as before.
+#[derive(Clone)]
+pub struct TxidRegistry {
+    pool: Pool<Postgres>,
+    enabled: bool,
+}
+
+impl TxidRegistry {
+    pub fn new(pool: Pool<Postgres>, enabled: bool) -> Self {
+        TxidRegistry { pool, enabled }
+    }
+
+    pub async fn claim(&self, txid: &ByteArray32, owner: &str) -> Result<(), TxidRegistryError> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let inserted = sqlx::query(
+            "INSERT INTO txid_registry (txid, owner, created_at) VALUES ($1, $2, now())
+             ON CONFLICT (txid) DO NOTHING",
+        )
+            .bind(txid.hex())
+            .bind(owner)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        if inserted == 1 {
+            return Ok(());
+        }
+        let existing: String = sqlx::query("SELECT owner FROM txid_registry WHERE txid = $1")
+            .bind(txid.hex())
+            .fetch_one(&self.pool)
+            .await?
+            .try_get("owner")?;
+        if existing == owner {
+            Ok(())
+        } else {
+            Err(TxidRegistryError::Conflict(txid.hex(), existing))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::random;
+    use crate::txid_registry::{TxidRegistry, TxidRegistryError};
+    use crate::util::test_db::test_pool;
+    use crate::util::types::ByteArray32;
+
+    #[tokio::test]
+    async fn test_claim_is_scoped_to_owner() {
+        let pool = test_pool();
+        let registry = TxidRegistry::new(pool.clone(), true);
+        let txid = ByteArray32(random());
+
+        registry.claim(&txid, "account:ACCT-0001").await.unwrap();
+        registry.claim(&txid, "account:ACCT-0001").await.unwrap();
+        assert!(matches!(
+            registry.claim(&txid, "account:ACCT-0002").await,
+            Err(TxidRegistryError::Conflict(_, owner)) if owner == "account:ACCT-0001"
+        ));
+        TxidRegistry::new(pool, false).claim(&txid, "account:ACCT-0002").await.unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Seconds the clock has been fast-forwarded by. Only the sandbox moves it, so outside
+// of sandbox mode `now` is plain wall clock time.
+static OFFSET: AtomicU64 = AtomicU64::new(0);
+
+fn wall_clock() -> u64 {
+    chrono::Utc::now().timestamp() as u64
+}
+
+// Current unix time in seconds, as seen by aggregates checking expiries.
+pub fn now() -> u64 {
+    wall_clock().saturating_add(OFFSET.load(Ordering::Relaxed))
+}
+
+pub fn offset() -> u64 {
+    OFFSET.load(Ordering::Relaxed)
+}
+
+// Time only moves forward: events already stamped with the fast-forwarded time
+// must not end up in the future again. `None`, and the clock left alone, when the
+// clock would no longer fit the signed seconds timestamps are stored as.
+pub fn advance(seconds: u64) -> Option<u64> {
+    let fits = |offset: &u64| wall_clock().checked_add(*offset).is_some_and(|now| now <= i64::MAX as u64);
+    OFFSET
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |offset| offset.checked_add(seconds).filter(fits))
+        .ok()
+        .map(|previous| previous + seconds)
+}
+
+// Picks up an offset persisted before a restart, never moving the clock back.
+pub fn restore(offset: u64) {
+    OFFSET.fetch_max(offset, Ordering::Relaxed);
+}
+
+// Where the sagas read the time they stamp their events with, handed to them with their
+// services so tests can hold it still. `apply` only reads timestamps from the events.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+// `now`, fast-forwarded along with the sandbox.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        now()
+    }
+}
+
+// A clock that only moves when told to.
+#[derive(Debug, Default)]
+pub struct FixedClock(AtomicU64);
+
+impl FixedClock {
+    pub fn new(now: u64) -> Self {
+        FixedClock(AtomicU64::new(now))
+    }
+
+    pub fn set(&self, now: u64) {
+        self.0.store(now, Ordering::Relaxed);
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
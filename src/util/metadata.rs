@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+// Who the request was authenticated as, absent on routes without authentication.
+pub const INITIATOR_KEY: &str = "initiator";
+// Who the caller says it acts for, from `X-Initiator`. Never checked, not an identity.
+pub const CLAIMED_INITIATOR_KEY: &str = "claimed_initiator";
+pub const CORRELATION_ID_KEY: &str = "correlation_id";
+// The command variant executed, recorded in the audit log.
+pub const COMMAND_KEY: &str = "command";
+
+// Who or what triggered an event, lifted out of the command metadata so views
+// and downstream consumers don't have to dig through the raw envelope. Only the
+// authenticated initiator is lifted, never the unverified one the caller claimed.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default, ToSchema)]
+pub struct EventOrigin {
+    pub initiator: Option<String>,
+    pub correlation_id: Option<String>,
+}
+
+impl EventOrigin {
+    pub fn from_metadata(metadata: &HashMap<String, String>) -> Option<Self> {
+        let origin = EventOrigin {
+            initiator: metadata.get(INITIATOR_KEY).cloned(),
+            correlation_id: metadata.get(CORRELATION_ID_KEY).cloned(),
+        };
+        if origin.initiator.is_none() && origin.correlation_id.is_none() {
+            None
+        } else {
+            Some(origin)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use crate::util::metadata::{EventOrigin, CLAIMED_INITIATOR_KEY, CORRELATION_ID_KEY};
+
+    #[test]
+    fn test_origin_from_metadata() {
+        assert_eq!(EventOrigin::from_metadata(&HashMap::new()), None);
+
+        let metadata = HashMap::from([
+            (CORRELATION_ID_KEY.to_string(), "abc".to_string()),
+            (CLAIMED_INITIATOR_KEY.to_string(), "ops@example.com".to_string()),
+        ]);
+        assert_eq!(EventOrigin::from_metadata(&metadata), Some(EventOrigin {
+            initiator: None,
+            correlation_id: Some("abc".to_string()),
+        }));
+    }
+}
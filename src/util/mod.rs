@@ -1,2 +1,7 @@
+pub mod clock;
+pub mod metadata;
+#[cfg(all(test, feature = "server"))]
+pub mod test_db;
+#[cfg(feature = "server")]
 pub mod transaction_guard;
 pub mod types;
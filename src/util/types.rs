@@ -1,62 +1,156 @@
-use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use base64::prelude::{Engine, BASE64_STANDARD, BASE64_URL_SAFE};
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use utoipa::openapi::schema::{ObjectBuilder, Schema, Type};
+use utoipa::openapi::RefOr;
+use utoipa::{PartialSchema, ToSchema};
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy, Default)]
-#[serde(transparent)]
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy, Default)]
 pub struct ByteArray32(pub [u8; 32]);
 
-// impl <'de> Deserialize<'de> for ByteArray32 {
-//     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-//     where
-//         D: Deserializer<'de>
-//     {
-//         // 1. hex str
-//         // 2. base64 str
-//         // 3. base58 str
-//         // 4. number array
-//
-//         // visitor
-//         struct ByteArray32Visitor;
-//
-//         impl<'de> Visitor<'de> for ByteArray32Visitor {
-//             type Value = ByteArray32;
-//
-//             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-//                 formatter.write_str("a 32-byte array")
-//             }
-//
-//             fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
-//             where
-//                 E: de::Error,
-//             {
-//                 if value.len() == 64 {
-//                     let mut bytes = [0u8; 32];
-//                     hex::decode_to_slice(value, &mut bytes).map_err(de::Error::custom)?;
-//                     Ok(ByteArray32(bytes))
-//                 } else {
-//                     Err(de::Error::custom("invalid length"))
-//                 }
-//             }
-//
-//             fn visit_bytes<E>(self, value: &[u8]) -> Result<Self::Value, E>
-//             where
-//                 E: de::Error,
-//             {
-//                 if value.len() == 32 {
-//                     let mut bytes = [0u8; 32];
-//                     bytes.copy_from_slice(value);
-//                     Ok(ByteArray32(bytes))
-//                 } else {
-//                     Err(de::Error::custom("invalid length"))
-//                 }
-//             }
-//         }
-//
-//         deserializer.deserialize_str(ByteArray32Visitor)
-//     }
-// }
-//
+// In JSON ids are written as hex. Clients may also send them as base64 (padded), base58
+// or a plain array of 32 numbers, which is how they used to be written and how older
+// events are stored. Binary formats keep the raw 32 bytes.
+impl Serialize for ByteArray32 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.hex())
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteArray32 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(ByteArray32Visitor)
+        } else {
+            <[u8; 32]>::deserialize(deserializer).map(ByteArray32)
+        }
+    }
+}
+
+// Documented as the hex string it is written as.
+impl PartialSchema for ByteArray32 {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .schema_type(Type::String)
+            .description(Some("32 bytes as 64 hex digits, base64, base58 or an array of 32 numbers are read as well"))
+            .min_length(Some(64))
+            .max_length(Some(64))
+            .into()
+    }
+}
+
+impl ToSchema for ByteArray32 {}
+
+struct ByteArray32Visitor;
+
+impl<'de> Visitor<'de> for ByteArray32Visitor {
+    type Value = ByteArray32;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("32 bytes as a hex, base64 or base58 string or as an array of numbers")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        value.parse().map_err(E::custom)
+    }
+
+    fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Self::Value, E> {
+        let bytes = value.try_into().map_err(|_| E::invalid_length(value.len(), &self))?;
+        Ok(ByteArray32(bytes))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(i, &self))?;
+        }
+        if seq.next_element::<de::IgnoredAny>()?.is_some() {
+            return Err(de::Error::invalid_length(33, &self));
+        }
+        Ok(ByteArray32(bytes))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{0} is not 32 bytes in hex, base64 or base58")]
+pub struct InvalidByteArray32(String);
+
+// 64 hex digits (optionally `0x` prefixed), base64 which always ends with `=` for 32
+// bytes, or otherwise base58. The alphabets overlap, the length and padding tell them apart.
+impl FromStr for ByteArray32 {
+    type Err = InvalidByteArray32;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidByteArray32(s.to_string());
+        let digits = s.strip_prefix("0x").unwrap_or(s);
+        let mut bytes = [0u8; 32];
+        if digits.len() == 64 {
+            hex::decode_to_slice(digits, &mut bytes).map_err(|_| invalid())?;
+        } else if s.ends_with('=') {
+            let decoded = BASE64_STANDARD.decode(s).or_else(|_| BASE64_URL_SAFE.decode(s)).map_err(|_| invalid())?;
+            bytes = decoded.try_into().map_err(|_| invalid())?;
+        } else {
+            let written = bs58::decode(s).onto(&mut bytes).map_err(|_| invalid())?;
+            if written != 32 {
+                return Err(invalid());
+            }
+        }
+        Ok(ByteArray32(bytes))
+    }
+}
+
 impl ByteArray32 {
     pub fn hex(&self) -> String {
         hex::encode(self.0)
     }
-}
\ No newline at end of file
+
+    // Deterministically derives an id from a namespace and a caller supplied key, so
+    // retrying the same request maps onto the same transaction id.
+    pub fn derive(namespace: &str, key: &str) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(namespace.as_bytes());
+        hasher.update([0u8]);
+        hasher.update(key.as_bytes());
+        ByteArray32(hasher.finalize().into())
+    }
+}
+#[cfg(test)]
+mod test {
+    use base64::prelude::{Engine, BASE64_STANDARD};
+    use crate::util::types::ByteArray32;
+
+    #[test]
+    fn test_json_encodings() {
+        let id = ByteArray32::derive("test", "id");
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, format!("\"{}\"", id.hex()));
+
+        let encodings = [
+            json,
+            format!("\"0x{}\"", id.hex()),
+            format!("\"{}\"", BASE64_STANDARD.encode(id.0)),
+            format!("\"{}\"", bs58::encode(id.0).into_string()),
+            serde_json::to_string(&id.0).unwrap(),
+        ];
+        for encoded in encodings {
+            assert_eq!(serde_json::from_str::<ByteArray32>(&encoded).unwrap(), id, "{}", encoded);
+        }
+        assert!(serde_json::from_str::<ByteArray32>("\"abcd\"").is_err());
+        assert!(serde_json::from_str::<ByteArray32>(&serde_json::to_string(&vec![1u8; 33]).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_binary_encoding_unchanged() {
+        let id = ByteArray32([7; 32]);
+        let encoded = bincode::serialize(&id).unwrap();
+        assert_eq!(encoded, vec![7; 32]);
+        assert_eq!(bincode::deserialize::<ByteArray32>(&encoded).unwrap(), id);
+    }
+}
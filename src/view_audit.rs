@@ -0,0 +1,270 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use cqrs_es::persist::{PersistenceError, ViewRepository};
+use serde::Serialize;
+use utoipa::ToSchema;
+use sqlx::{Pool, Postgres, Row};
+use crate::background::Background;
+use crate::account::aggregate::Account;
+use crate::account::queries::AccountView;
+use crate::compaction::archive::ALL_EVENTS;
+use crate::config::ViewAuditConfig;
+use crate::field_encryption::FieldCipher;
+use crate::rebuild::{replay, write_view, RebuildError};
+use crate::sealing::{SealedViewRepository, Sealer};
+use crate::util::clock;
+
+const VIEW_TABLE: &str = "account_query";
+
+// Divergences reported per audit, the count is not capped.
+const MAX_REPORTED: usize = 100;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ViewAuditError {
+    #[error(transparent)]
+    Replay(#[from] RebuildError),
+    #[error("View error: {0}")]
+    View(#[from] PersistenceError),
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(tag = "divergence")]
+pub enum Divergence {
+    // The account has events but no view.
+    Missing {
+        account_id: String,
+        sequence: i64,
+    },
+    // The view was last updated from another event than the last one stored.
+    Stale {
+        account_id: String,
+        view_sequence: i64,
+        sequence: i64,
+    },
+    // Replaying the events gives another balance than the view shows.
+    Balance {
+        account_id: String,
+        field: String,
+        asset: String,
+        view: u64,
+        replayed: u64,
+    },
+}
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct ViewAuditReport {
+    pub audits: u64,
+    pub last_audit_at: Option<u64>,
+    pub failures: u64,
+    // Accounts compared by the last audit.
+    pub checked: u64,
+    // Of those, the ones whose view differs from their events.
+    pub divergent: u64,
+    // Up to 100 of the differences.
+    pub divergences: Vec<Divergence>,
+    // Views rewritten from the events, when repairs are on.
+    pub repaired: u64,
+}
+
+// Compares the account views with the balances their events replay to, at boot when
+// configured and on request. The events are the truth: a view missing, left behind or
+// showing other balances is logged, and with `repair` rewritten from the replay.
+// An account found to differ is checked again once the others are done, so an event
+// that was only on its way to the view is not reported. A repair racing a live update
+// of the same view can be undone by it, the next audit shows it again.
+#[derive(Clone)]
+pub struct ViewAudit {
+    pool: Pool<Postgres>,
+    views: Arc<SealedViewRepository<AccountView, Account>>,
+    sealer: Option<Sealer>,
+    cipher: Option<FieldCipher>,
+    config: ViewAuditConfig,
+    report: Arc<Mutex<ViewAuditReport>>,
+}
+
+impl ViewAudit {
+    pub fn new(pool: Pool<Postgres>, sealer: Option<Sealer>, cipher: Option<FieldCipher>, config: ViewAuditConfig) -> Self {
+        let views = Arc::new(SealedViewRepository::new(VIEW_TABLE, pool.clone(), sealer.clone()));
+        ViewAudit { pool, views, sealer, cipher, config, report: Default::default() }
+    }
+
+    // Audits once in the background when `on_startup` is set.
+    pub fn spawn(&self, background: &Background) {
+        if !self.config.on_startup {
+            return;
+        }
+        let audit = self.clone();
+        background.spawn(|_| async move {
+            if let Err(e) = audit.audit().await {
+                tracing::error!("Failed to audit the account views: {}", e);
+            }
+        });
+    }
+
+    pub async fn audit(&self) -> Result<ViewAuditReport, ViewAuditError> {
+        let checked = self.check().await;
+        let mut report = self.report.lock().expect("view audit report poisoned");
+        report.audits += 1;
+        report.last_audit_at = Some(clock::now());
+        let (checked, divergences, repaired) = match checked {
+            Ok(checked) => checked,
+            Err(e) => {
+                report.failures += 1;
+                return Err(e);
+            }
+        };
+        report.checked = checked;
+        report.divergent = divergences.iter().map(Divergence::account_id).collect::<BTreeSet<_>>().len() as u64;
+        report.divergences = divergences.into_iter().take(MAX_REPORTED).collect();
+        report.repaired = repaired;
+        Ok(report.clone())
+    }
+
+    async fn check(&self) -> Result<(u64, Vec<Divergence>, u64), ViewAuditError> {
+        let sample = match self.config.sample {
+            0 => None,
+            sample => Some(sample as i64),
+        };
+        // LIMIT NULL is no limit at all.
+        let accounts: Vec<String> = sqlx::query(&format!(
+            "SELECT aggregate_id FROM (SELECT DISTINCT aggregate_id FROM {} WHERE aggregate_type = $1) accounts ORDER BY random() LIMIT $2",
+            ALL_EVENTS
+        ))
+            .bind("account")
+            .bind(sample)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|row| row.try_get("aggregate_id"))
+            .collect::<Result<_, _>>()?;
+        let mut suspects = vec![];
+        for account_id in &accounts {
+            if !self.compare(account_id).await?.0.is_empty() {
+                suspects.push(account_id);
+            }
+        }
+        let mut divergences = vec![];
+        let mut repaired = 0;
+        for account_id in suspects {
+            let (found, replayed, sequence) = self.compare(account_id).await?;
+            if found.is_empty() {
+                continue;
+            }
+            for divergence in &found {
+                tracing::error!("Account view diverges from its events: {:?}", divergence);
+            }
+            divergences.extend(found);
+            if self.config.repair {
+                let seal = self.sealer.as_ref().map(|sealer| (sealer, format!("view/{}", VIEW_TABLE)));
+                let mut conn = self.pool.acquire().await?;
+                write_view(&mut conn, VIEW_TABLE, account_id, &replayed, sequence, seal.as_ref().map(|(sealer, kind)| (*sealer, kind.as_str()))).await?;
+                tracing::warn!("Rewrote the view of account {} from its {} events", account_id, sequence);
+                repaired += 1;
+            }
+        }
+        Ok((accounts.len() as u64, divergences, repaired))
+    }
+
+    // The differences between the stored view of the account and its replayed events,
+    // with the replayed view and the sequence it is at.
+    async fn compare(&self, account_id: &str) -> Result<(Vec<Divergence>, AccountView, i64), ViewAuditError> {
+        let stored = self.views.load_with_context(account_id).await?;
+        let mut conn = self.pool.acquire().await?;
+        let (replayed, sequence) = replay::<Account, AccountView>(&mut conn, account_id, self.cipher.as_ref()).await?;
+        let Some((view, context)) = stored else {
+            return Ok((vec![Divergence::Missing { account_id: account_id.to_string(), sequence }], replayed, sequence));
+        };
+        if context.version != sequence {
+            let stale = Divergence::Stale { account_id: account_id.to_string(), view_sequence: context.version, sequence };
+            return Ok((vec![stale], replayed, sequence));
+        }
+        Ok((balance_divergences(account_id, &view, &replayed), replayed, sequence))
+    }
+
+    pub fn report(&self) -> ViewAuditReport {
+        self.report.lock().expect("view audit report poisoned").clone()
+    }
+}
+
+impl Divergence {
+    fn account_id(&self) -> &str {
+        match self {
+            Divergence::Missing { account_id, .. } | Divergence::Stale { account_id, .. } | Divergence::Balance { account_id, .. } => account_id,
+        }
+    }
+}
+
+fn balance_divergences(account_id: &str, view: &AccountView, replayed: &AccountView) -> Vec<Divergence> {
+    let mut divergences = vec![];
+    for ((field, shown), (_, expected)) in view.balances().into_iter().zip(replayed.balances()) {
+        let assets: BTreeSet<&String> = shown.keys().chain(expected.keys()).collect();
+        for asset in assets {
+            let view = shown.get(asset).copied().unwrap_or(0);
+            let replayed = expected.get(asset).copied().unwrap_or(0);
+            if view != replayed {
+                divergences.push(Divergence::Balance { account_id: account_id.to_string(), field: field.to_string(), asset: asset.clone(), view, replayed });
+            }
+        }
+    }
+    divergences
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use cqrs_es::{DomainEvent, EventEnvelope, View};
+    use rand::random;
+    use sqlx::{Pool, Postgres};
+    use crate::account::aggregate::Account;
+    use crate::account::events::AccountEvent;
+    use crate::account::queries::AccountView;
+    use crate::config::ViewAuditConfig;
+    use crate::rebuild::write_view;
+    use crate::util::test_db::{random_suffix, test_pool};
+    use crate::util::types::ByteArray32;
+    use crate::view_audit::{Divergence, ViewAudit, VIEW_TABLE};
+
+    async fn store(pool: &Pool<Postgres>, account_id: &str, sequence: i64, event: &AccountEvent) {
+        sqlx::query("INSERT INTO events (aggregate_type, aggregate_id, sequence, event_type, event_version, payload, metadata) VALUES ('account', $1, $2, $3, $4, $5, '{}')")
+            .bind(account_id)
+            .bind(sequence)
+            .bind(event.event_type())
+            .bind(event.event_version())
+            .bind(serde_json::to_value(event).unwrap())
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_divergent_views() {
+        let pool = test_pool();
+        let suffix = random_suffix().to_uppercase();
+        let (alice, bob) = (format!("ALICE-{}", suffix), format!("BOB-{}", suffix));
+        let audit = ViewAudit::new(pool.clone(), None, None, ViewAuditConfig::default());
+        let deposit = AccountEvent::deposited(ByteArray32(random()), 10, "USDT".to_string(), 100);
+        store(&pool, &alice, 1, &deposit).await;
+        store(&pool, &bob, 1, &deposit).await;
+
+        // The view applied a deposit the events do not have.
+        let mut view = AccountView::default();
+        let envelope = EventEnvelope::<Account> { aggregate_id: alice.clone(), sequence: 1, payload: AccountEvent::deposited(ByteArray32(random()), 10, "USDT".to_string(), 60), metadata: HashMap::new() };
+        view.update(&envelope);
+        let mut conn = pool.acquire().await.unwrap();
+        write_view(&mut conn, VIEW_TABLE, &alice, &view, 1, None).await.unwrap();
+        let (found, replayed, sequence) = audit.compare(&alice).await.unwrap();
+        let balance = Divergence::Balance { account_id: alice.clone(), field: "balance".to_string(), asset: "USDT".to_string(), view: 60, replayed: 100 };
+        assert_eq!((found, sequence), (vec![balance], 1));
+
+        // Rewritten from the events it matches them, until it misses the next one.
+        write_view(&mut conn, VIEW_TABLE, &alice, &replayed, sequence, None).await.unwrap();
+        assert_eq!(audit.compare(&alice).await.unwrap().0, vec![]);
+        store(&pool, &alice, 2, &AccountEvent::deposited(ByteArray32(random()), 20, "USDT".to_string(), 5)).await;
+        let stale = Divergence::Stale { account_id: alice.clone(), view_sequence: 1, sequence: 2 };
+        assert_eq!(audit.compare(&alice).await.unwrap().0, vec![stale]);
+
+        assert_eq!(audit.compare(&bob).await.unwrap().0, vec![Divergence::Missing { account_id: bob.clone(), sequence: 1 }]);
+    }
+}
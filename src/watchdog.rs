@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::background::{Background, Shutdown};
+use crate::commit_hooks::HookedCqrs;
+use serde::Serialize;
+use utoipa::ToSchema;
+use sqlx::{Pool, Postgres, Row};
+use crate::config::SagaWatchdogConfig;
+use crate::field_encryption::EncryptedCqrs;
+use crate::notification::WebhookNotifier;
+use crate::order::aggregate::Order;
+use crate::order::commands::OrderCommand;
+use crate::transfer::aggregate::Transfer;
+use crate::transfer::commands::TransferCommand;
+use crate::util::clock;
+
+const ALERT_KIND: &str = "stalled_saga";
+// Stalled sagas read per query, a sweep pages through all of them.
+const SWEEP_BATCH: i64 = 500;
+
+// A saga found between two of its steps for longer than it should take.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct StalledSaga {
+    // `order` or `transfer`.
+    pub aggregate_type: String,
+    pub id: String,
+    pub status: String,
+    // When it last moved.
+    pub since: u64,
+    // Sweeps that found it stalled and sent it `Continue`.
+    pub attempts: u32,
+}
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct WatchdogReport {
+    pub sweeps: u64,
+    pub last_sweep_at: Option<u64>,
+    // `Continue`s sent since startup, and those that failed.
+    pub resumed: u64,
+    pub failures: u64,
+    // Sagas still stalled after `max_attempts` sweeps, left to an operator.
+    pub exhausted: Vec<StalledSaga>,
+}
+
+#[derive(Default)]
+struct WatchdogState {
+    // Sweeps in a row each stalled saga was found by, by aggregate type and id.
+    attempts: HashMap<(String, String), u32>,
+    report: WatchdogReport,
+}
+
+// Resumes the order and transfer sagas a crash left between their steps: an order
+// opened but not placed, or being bought, settled or cancelled, and a transfer opened,
+// with both legs locked or being reversed, still so `stall_secs` after it last moved. Each sweep sends them
+// `Continue`, which every step takes up again where it stopped. A saga still stalled
+// after `max_attempts` sweeps is alerted on once and left alone until it moves.
+#[derive(Clone)]
+pub struct SagaWatchdog {
+    order_cqrs: Arc<HookedCqrs<Order>>,
+    transfer_cqrs: Arc<EncryptedCqrs<Transfer>>,
+    pool: Pool<Postgres>,
+    config: SagaWatchdogConfig,
+    notifier: WebhookNotifier,
+    state: Arc<Mutex<WatchdogState>>,
+}
+
+impl SagaWatchdog {
+    pub fn new(order_cqrs: Arc<HookedCqrs<Order>>, transfer_cqrs: Arc<EncryptedCqrs<Transfer>>, pool: Pool<Postgres>, config: SagaWatchdogConfig) -> Self {
+        SagaWatchdog { order_cqrs, transfer_cqrs, pool, config, notifier: WebhookNotifier::default(), state: Default::default() }
+    }
+
+    pub fn with_notifier(mut self, notifier: WebhookNotifier) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    pub fn spawn(&self, background: &Background) {
+        if self.config.stall_secs == 0 {
+            return;
+        }
+        let watchdog = self.clone();
+        background.spawn(|shutdown| async move { watchdog.run(shutdown).await });
+    }
+
+    async fn run(&self, mut shutdown: Shutdown) {
+        loop {
+            if let Err(e) = self.sweep().await {
+                tracing::error!("Failed to sweep stalled sagas: {}", e);
+            }
+            if !shutdown.pause(Duration::from_secs(self.config.sweep_interval_secs.max(1))).await {
+                break;
+            }
+        }
+    }
+
+    // Sends `Continue` to the stalled sagas found and returns the ones it was sent to.
+    // Every stalled saga is read, so those out of attempts never hide newer ones.
+    pub async fn sweep(&self) -> Result<Vec<StalledSaga>, sqlx::Error> {
+        let cutoff = clock::now().saturating_sub(self.config.stall_secs);
+        let mut stalled = vec![];
+        loop {
+            let page = stalled_sagas(&self.pool, cutoff, stalled.last(), SWEEP_BATCH).await?;
+            let last_page = (page.len() as i64) < SWEEP_BATCH;
+            stalled.extend(page);
+            if last_page {
+                break;
+            }
+        }
+        let (resume, exhausted) = self.count_attempts(stalled);
+        for saga in &exhausted {
+            self.alert(saga);
+        }
+        let mut failures = 0;
+        for saga in &resume {
+            let resumed = match saga.aggregate_type.as_str() {
+                "order" => self.order_cqrs.execute(&saga.id, OrderCommand::Continue).await.map_err(|e| e.to_string()),
+                _ => self.transfer_cqrs.execute(&saga.id, TransferCommand::Continue).await.map_err(|e| e.to_string()),
+            };
+            match resumed {
+                Ok(_) => tracing::warn!("Resumed {} {} stalled {} since {}", saga.aggregate_type, saga.id, saga.status, saga.since),
+                Err(e) => {
+                    failures += 1;
+                    tracing::error!("Failed to resume {} {} stalled {}: {}", saga.aggregate_type, saga.id, saga.status, e);
+                }
+            }
+        }
+        let mut state = self.state.lock().expect("watchdog state poisoned");
+        state.report.sweeps += 1;
+        state.report.last_sweep_at = Some(clock::now());
+        state.report.resumed += resume.len() as u64;
+        state.report.failures += failures;
+        Ok(resume)
+    }
+
+    // Splits the stalled sagas into those to resume and those that just ran out of
+    // attempts, forgetting the ones that moved since the last sweep.
+    fn count_attempts(&self, stalled: Vec<StalledSaga>) -> (Vec<StalledSaga>, Vec<StalledSaga>) {
+        let mut state = self.state.lock().expect("watchdog state poisoned");
+        let mut attempts = HashMap::with_capacity(stalled.len());
+        let (mut resume, mut exhausted, mut given_up) = (vec![], vec![], vec![]);
+        for mut saga in stalled {
+            let key = (saga.aggregate_type.clone(), saga.id.clone());
+            saga.attempts = state.attempts.get(&key).copied().unwrap_or(0) + 1;
+            attempts.insert(key, saga.attempts);
+            match saga.attempts {
+                n if n <= self.config.max_attempts => resume.push(saga),
+                n => {
+                    saga.attempts = self.config.max_attempts;
+                    if n == self.config.max_attempts + 1 {
+                        exhausted.push(saga.clone());
+                    }
+                    given_up.push(saga);
+                }
+            }
+        }
+        state.attempts = attempts;
+        state.report.exhausted = given_up;
+        (resume, exhausted)
+    }
+
+    fn alert(&self, saga: &StalledSaga) {
+        tracing::error!(
+            "{} {} is still {} since {} after {} attempts to resume it, it needs an operator",
+            saga.aggregate_type, saga.id, saga.status, saga.since, saga.attempts,
+        );
+        if !self.config.webhook_url.is_empty() {
+            self.notifier.notify(&self.config.webhook_url, ALERT_KIND, saga);
+        }
+    }
+
+    pub fn report(&self) -> WatchdogReport {
+        self.state.lock().expect("watchdog state poisoned").report.clone()
+    }
+}
+
+// Orders and transfers in an intermediate state that last moved at or before `cutoff`,
+// longest stalled first, from the one after `after` on.
+pub async fn stalled_sagas(pool: &Pool<Postgres>, cutoff: u64, after: Option<&StalledSaga>, limit: i64) -> Result<Vec<StalledSaga>, sqlx::Error> {
+    let (after_since, after_type, after_id) = match after {
+        Some(saga) => (saga.since as i64, saga.aggregate_type.as_str(), saga.id.as_str()),
+        None => (-1, "", ""),
+    };
+    sqlx::query(
+        "SELECT * FROM (
+             SELECT 'order' AS aggregate_type, view_id, payload->>'status' AS status,
+                    (payload->>'update_time')::bigint AS since
+             FROM order_query
+             WHERE payload->>'status' IN ('Initial', 'Buying', 'Bought', 'Cancelling')
+             UNION ALL
+             SELECT 'transfer', view_id, payload->>'status',
+                    GREATEST((payload->>'create_timestamp')::bigint, (payload->>'update_timestamp')::bigint, (payload->>'opened_at')::bigint)
+             FROM transfer_query
+             WHERE payload->>'status' IN ('Opened', 'Locked', 'Reversing')
+         ) AS sagas
+         WHERE since <= $1 AND (since, aggregate_type, view_id) > ($3, $4, $5)
+         ORDER BY since, aggregate_type, view_id
+         LIMIT $2",
+    )
+        .bind(cutoff as i64)
+        .bind(limit)
+        .bind(after_since)
+        .bind(after_type)
+        .bind(after_id)
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| {
+            Ok(StalledSaga {
+                aggregate_type: row.try_get("aggregate_type")?,
+                id: row.try_get("view_id")?,
+                status: row.try_get("status")?,
+                since: row.try_get::<i64, _>("since")? as u64,
+                attempts: 0,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use crate::util::test_db::{delete_prefixed, random_suffix, test_pool};
+    use crate::watchdog::stalled_sagas;
+
+    #[tokio::test]
+    async fn test_stalled_sagas() {
+        let pool = test_pool();
+        let prefix = format!("STALL-{}-", random_suffix());
+        // Far in the past, so nothing else in the tables sorts before them.
+        let orders = [("buying", "Buying", 5), ("placed", "Placed", 5), ("recent", "Buying", 500)];
+        for (id, status, update_time) in orders {
+            sqlx::query("INSERT INTO order_query (view_id, version, payload) VALUES ($1, 1, $2)")
+                .bind(format!("{}{}", prefix, id))
+                .bind(json!({ "status": status, "update_time": update_time }))
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        let transfer = json!({ "status": "Locked", "create_timestamp": 1, "update_timestamp": 6, "opened_at": 1 });
+        sqlx::query("INSERT INTO transfer_query (view_id, version, payload) VALUES ($1, 2, $2)")
+            .bind(format!("{}locked", prefix))
+            .bind(transfer)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let stalled = stalled_sagas(&pool, 100, None, 1000).await.unwrap();
+        let ours: Vec<(&str, &str, u64)> = stalled
+            .iter()
+            .filter_map(|saga| saga.id.strip_prefix(&prefix).map(|id| (saga.aggregate_type.as_str(), id, saga.since)))
+            .collect();
+        assert_eq!(ours, vec![("order", "buying", 5), ("transfer", "locked", 6)]);
+
+        // Paging on from a saga leaves it and those before it out.
+        let buying = stalled.iter().find(|saga| saga.id == format!("{}buying", prefix)).unwrap();
+        let after: Vec<String> = stalled_sagas(&pool, 100, Some(buying), 1000).await.unwrap().into_iter().map(|saga| saga.id).collect();
+        assert!(!after.contains(&buying.id));
+        assert!(after.contains(&format!("{}locked", prefix)));
+
+        delete_prefixed(&pool, "order_query", "view_id", &prefix).await;
+        delete_prefixed(&pool, "transfer_query", "view_id", &prefix).await;
+    }
+}
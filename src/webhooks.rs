@@ -0,0 +1,211 @@
+use async_trait::async_trait;
+use cqrs_es::{EventEnvelope, Query};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use sqlx::{Pool, Postgres, Row};
+use crate::account::aggregate::Account;
+use crate::account::events::{AccountEvent, LifecycleEvent};
+use crate::notification::WebhookNotifier;
+use crate::util::clock;
+
+const NOTIFICATION_KIND: &str = "account_lifecycle";
+
+#[derive(Debug, thiserror::Error)]
+pub enum WebhookError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Invalid subscription: {0}")]
+    Invalid(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum LifecycleEventType {
+    #[serde(rename = "AccountOpened")]
+    Opened,
+    #[serde(rename = "AccountDisabled")]
+    Disabled,
+    #[serde(rename = "AccountEnabled")]
+    Enabled,
+    #[serde(rename = "AccountClosed")]
+    Closed,
+    #[serde(rename = "AccountTagged")]
+    Tagged,
+    #[serde(rename = "AccountCloseRequested")]
+    CloseRequested,
+    #[serde(rename = "AccountClosureAborted")]
+    ClosureAborted,
+}
+
+impl LifecycleEventType {
+    // Bookkeeping of the account itself, such as evicted txids, is not published.
+    pub fn of(event: &LifecycleEvent) -> Option<Self> {
+        match event {
+            LifecycleEvent::Opened { .. } => Some(LifecycleEventType::Opened),
+            LifecycleEvent::Disabled => Some(LifecycleEventType::Disabled),
+            LifecycleEvent::Enabled => Some(LifecycleEventType::Enabled),
+            LifecycleEvent::Closed => Some(LifecycleEventType::Closed),
+            LifecycleEvent::Tagged { .. } => Some(LifecycleEventType::Tagged),
+            LifecycleEvent::CloseRequested { .. } => Some(LifecycleEventType::CloseRequested),
+            LifecycleEvent::ClosureAborted { .. } => Some(LifecycleEventType::ClosureAborted),
+            LifecycleEvent::TxidsEvicted { .. } => None,
+        }
+    }
+}
+
+// A downstream system (CRM, card issuing, ...) listening to account lifecycle events.
+// An empty `event_types` subscribes to all of them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Subscription {
+    pub subscription_id: String,
+    pub url: String,
+    #[serde(default)]
+    pub event_types: Vec<LifecycleEventType>,
+}
+
+impl Subscription {
+    fn validate(&self) -> Result<(), WebhookError> {
+        if self.subscription_id.is_empty() {
+            return Err(WebhookError::Invalid("subscription id is required".to_string()));
+        }
+        if !self.url.starts_with("https://") && !self.url.starts_with("http://") {
+            return Err(WebhookError::Invalid(format!("{} is not an http(s) url", self.url)));
+        }
+        Ok(())
+    }
+
+    pub fn wants(&self, event_type: LifecycleEventType) -> bool {
+        self.event_types.is_empty() || self.event_types.contains(&event_type)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LifecycleNotification {
+    pub event_type: LifecycleEventType,
+    pub account_id: String,
+    // Sequence of the event in the account, for ordering and deduplication downstream.
+    pub sequence: usize,
+    pub timestamp: u64,
+}
+
+// Webhook subscriptions of downstream systems, managed under `/admin/webhooks`.
+#[derive(Clone)]
+pub struct WebhookRegistry {
+    pool: Pool<Postgres>,
+}
+
+impl WebhookRegistry {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        WebhookRegistry { pool }
+    }
+
+    // Creates the subscription, or replaces the one with the same id.
+    pub async fn subscribe(&self, subscription: &Subscription) -> Result<(), WebhookError> {
+        subscription.validate()?;
+        let event_types = serde_json::to_value(&subscription.event_types).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+        sqlx::query(
+            "INSERT INTO webhook_subscription (subscription_id, url, event_types, created_at) VALUES ($1, $2, $3, now())
+             ON CONFLICT (subscription_id) DO UPDATE SET url = EXCLUDED.url, event_types = EXCLUDED.event_types",
+        )
+            .bind(&subscription.subscription_id)
+            .bind(&subscription.url)
+            .bind(event_types)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // Returns whether the subscription existed.
+    pub async fn unsubscribe(&self, subscription_id: &str) -> Result<bool, WebhookError> {
+        let deleted = sqlx::query("DELETE FROM webhook_subscription WHERE subscription_id = $1")
+            .bind(subscription_id)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        Ok(deleted > 0)
+    }
+
+    pub async fn list(&self) -> Result<Vec<Subscription>, WebhookError> {
+        let rows = sqlx::query("SELECT subscription_id, url, event_types FROM webhook_subscription ORDER BY subscription_id")
+            .fetch_all(&self.pool)
+            .await?;
+        let mut subscriptions = Vec::with_capacity(rows.len());
+        for row in rows {
+            let event_types: serde_json::Value = row.try_get("event_types")?;
+            subscriptions.push(Subscription {
+                subscription_id: row.try_get("subscription_id")?,
+                url: row.try_get("url")?,
+                event_types: serde_json::from_value(event_types).map_err(|e| sqlx::Error::Decode(Box::new(e)))?,
+            });
+        }
+        Ok(subscriptions)
+    }
+}
+
+// Notifies every matching subscription of account lifecycle events, so downstream
+// systems can provision or deprovision. Delivery is best effort like the balance
+// alerts; consumers that cannot miss an event should read the outbox topic instead.
+pub struct LifecycleWebhookQuery {
+    registry: WebhookRegistry,
+    notifier: WebhookNotifier,
+}
+
+impl LifecycleWebhookQuery {
+    pub fn new(registry: WebhookRegistry, notifier: WebhookNotifier) -> Self {
+        LifecycleWebhookQuery { registry, notifier }
+    }
+}
+
+#[async_trait]
+impl Query<Account> for LifecycleWebhookQuery {
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<Account>]) {
+        let lifecycle: Vec<(LifecycleEventType, usize)> = events
+            .iter()
+            .filter_map(|envelope| match &envelope.payload {
+                AccountEvent::Lifecycle(event) => LifecycleEventType::of(event).map(|event_type| (event_type, envelope.sequence)),
+                AccountEvent::Transaction { .. } => None,
+            })
+            .collect();
+        if lifecycle.is_empty() {
+            return;
+        }
+        let subscriptions = match self.registry.list().await {
+            Ok(subscriptions) => subscriptions,
+            Err(e) => {
+                tracing::error!("Failed to load webhook subscriptions for {}: {}", aggregate_id, e);
+                return;
+            }
+        };
+        for (event_type, sequence) in lifecycle {
+            let notification = LifecycleNotification {
+                event_type,
+                account_id: aggregate_id.to_string(),
+                sequence,
+                timestamp: clock::now(),
+            };
+            for subscription in subscriptions.iter().filter(|s| s.wants(event_type)) {
+                self.notifier.notify(&subscription.url, NOTIFICATION_KIND, &notification);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::webhooks::{LifecycleEventType, Subscription};
+
+    #[test]
+    fn test_subscription_filter() {
+        let subscription = |event_types| Subscription {
+            subscription_id: "crm".to_string(),
+            url: "https://crm.example.com/hooks".to_string(),
+            event_types,
+        };
+        assert!(subscription(vec![]).wants(LifecycleEventType::Closed));
+        let card_issuing = subscription(vec![LifecycleEventType::Opened, LifecycleEventType::Closed]);
+        assert!(card_issuing.wants(LifecycleEventType::Opened));
+        assert!(!card_issuing.wants(LifecycleEventType::Disabled));
+
+        assert!(subscription(vec![]).validate().is_ok());
+        assert!(Subscription { url: "ftp://crm.example.com".to_string(), ..subscription(vec![]) }.validate().is_err());
+    }
+}